@@ -21,6 +21,7 @@ struct RegAttributes {
     rcb: bool,
     readonly: bool,
     writeonly: bool,
+    fields: String,
 
     bank: usize,
     offset: u32,
@@ -81,6 +82,12 @@ fn parse_reg_attributes(varname: &str, attrs: &proc_macro2::TokenStream) -> RegA
                 }
                 ra.writeonly = true;
             }
+            "fields" => {
+                if kv.len() != 2 {
+                    panic!(format!("{}: no argument for fields", varname))
+                }
+                ra.fields = kv[1].trim().trim_matches('"').to_string();
+            }
             "bank" => {
                 if kv.len() != 2 {
                     panic!(format!("{}: no argument for bank", varname))
@@ -230,6 +237,7 @@ fn expand_reg_devinit(
     let rwmask = ra.rwmask.parse::<u32>().unwrap();
     let read = !ra.writeonly;
     let write = !ra.readonly;
+    let fields = &ra.fields;
     quote! {
         #initbody
         *#fi = Reg::new(
@@ -239,7 +247,7 @@ fn expand_reg_devinit(
             RegFlags::new(#read, #write),
             #qwcb,
             #qrcb,
-        );
+        ).with_fields(#fields);
     }
 }
 
@@ -299,7 +307,7 @@ fn expand_mem_devmap(
     let varname = Ident::new(varname, Span::call_site());
     quote!{
         if bank == #bank {
-            bus.map_mem(base + #off, base + #off + #vsize - 1, &self. #varname)?;
+            bus.map_mem_named(base + #off, base + #off + #vsize - 1, &self. #varname, stringify!(#varname))?;
         }
     }
 }
@@ -352,6 +360,7 @@ fn derive_device(mut s: synstructure::Structure, bigendian: bool) -> proc_macro2
         }
     });
 
+    let devname = s.ast().ident.to_string();
     let endian = Ident::new(
         if bigendian {
             "BigEndian"
@@ -381,6 +390,10 @@ fn derive_device(mut s: synstructure::Structure, bigendian: bool) -> proc_macro2
                 }
             }
 
+            fn dev_name(&self) -> &'static str {
+                #devname
+            }
+
             fn dev_map(&self, bus: &mut Bus<Self::Order>, bank: usize, base: u32,) -> Result<(), &'static str> {
                 #dev_map
                 Ok(())
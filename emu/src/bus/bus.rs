@@ -3,17 +3,22 @@ extern crate slog;
 
 use self::byteorder::ByteOrder;
 use super::device::{DevPtr, Device};
-use super::mem::Mem;
+use super::mem::{Mem, MemFlags};
 use super::memint::{AccessSize, ByteOrderCombiner, MemInt};
+use super::memmap::{MapEntry, MapKind, MemoryMap};
 use super::radix::RadixTree;
-use super::regs::Reg;
+use super::regs::{Reg, RegField, RegFlags};
+use alloc::boxed::Box;
+use alloc::collections::BTreeSet;
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::marker::PhantomData;
+use core::mem;
+use core::slice;
 use enum_map::EnumMap;
-use std::cell::RefCell;
+#[cfg(feature = "io")]
 use std::io;
-use std::marker::PhantomData;
-use std::mem;
-use std::rc::Rc;
-use std::slice;
 
 #[derive(Clone)]
 pub enum HwIoR {
@@ -73,7 +78,7 @@ pub struct MemIoR<O: ByteOrder, U: MemInt> {
     phantom: PhantomData<(O, U)>,
 }
 
-use std::iter;
+use core::iter;
 pub type MemIoRIterator<'a, U> = iter::Map<slice::ExactChunks<'a, u8>, for<'r> fn(&'r [u8]) -> U>;
 
 impl<O: ByteOrder, U: MemInt> MemIoR<O, U> {
@@ -109,6 +114,12 @@ impl<O: ByteOrder, U: MemInt> MemIoR<O, U> {
     // that yields consecutive elements of type U.
     // Otherwise, returns None.
     pub fn iter<'s, 'r: 's>(&'s self) -> Option<impl Iterator<Item = U>> {
+        self.iter_at(0)
+    }
+
+    // Like iter(), but starting at the specified offset (in bytes) from
+    // the address of the MemIoR, within the same memory area.
+    pub fn iter_at<'s, 'r: 's>(&'s self, offset: u32) -> Option<impl Iterator<Item = U>> {
         match self.hwio {
             HwIoR::Mem(ref buf, mask) => {
                 // Use unsafe here for performance: we don't want
@@ -120,7 +131,7 @@ impl<O: ByteOrder, U: MemInt> MemIoR<O, U> {
                     unsafe { slice::from_raw_parts(raw, len) }
                 };
                 Some(
-                    slice[(self.addr & mask) as usize..]
+                    slice[(self.addr.wrapping_add(offset) & mask) as usize..]
                         .exact_chunks(U::SIZE)
                         .map(U::endian_read_from::<O>),
                 )
@@ -159,6 +170,7 @@ impl<O: ByteOrder, U: MemInt> MemIoW<O, U> {
     }
 }
 
+#[cfg(feature = "io")]
 impl<O: ByteOrder, U: MemInt> io::Read for MemIoR<O, U> {
     fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
         match self.hwio.clone() {
@@ -177,22 +189,16 @@ impl<O: ByteOrder, U: MemInt> io::Read for MemIoR<O, U> {
 }
 
 pub fn unmapped_area_r() -> HwIoR {
-    thread_local!(
-        static FN: Rc<Fn(u32)->u64> = Rc::new(|_| {
-            // FIXME: log
-            return 0xffffffffffffffff;
-        })
-    );
-    HwIoR::Func(FN.with(|c| c.clone()))
+    HwIoR::Func(Rc::new(|_| {
+        // FIXME: log
+        return 0xffffffffffffffff;
+    }))
 }
 
 pub fn unmapped_area_w() -> HwIoW {
-    thread_local!(
-        static FN: Rc<Fn(u32,u64)> = Rc::new(|_,_| {
-            // FIXME: log
-        })
-    );
-    HwIoW::Func(FN.with(|c| c.clone()))
+    HwIoW::Func(Rc::new(|_, _| {
+        // FIXME: log
+    }))
 }
 
 pub struct Bus<Order: ByteOrderCombiner> {
@@ -202,6 +208,13 @@ pub struct Bus<Order: ByteOrderCombiner> {
     unmap_r: HwIoR,
     unmap_w: HwIoW,
 
+    // Addresses of accesses to unmapped areas (with a flag for writes)
+    unmapped: RefCell<BTreeSet<(u32, bool)>>,
+
+    // Memory map, and the device (with bank) currently being mapped
+    map: Vec<MapEntry>,
+    map_owner: Option<(&'static str, usize)>,
+
     logger: slog::Logger,
 
     phantom: PhantomData<Order>,
@@ -230,6 +243,9 @@ where
             },
             unmap_r: unmapped_area_r(),
             unmap_w: unmapped_area_w(),
+            unmapped: RefCell::new(BTreeSet::new()),
+            map: Vec::new(),
+            map_owner: None,
             logger: logger,
             phantom: PhantomData,
         })
@@ -272,6 +288,7 @@ where
             .or_else(|| {
                 if unmapped_log {
                     error!(self.logger, "unmapped bus read"; o!("addr" => format!("0x{:x}", addr), "size" => U::SIZE));
+                    self.unmapped.borrow_mut().insert((addr, false));
                 }
                 Some(&self.unmap_r)
             })
@@ -285,12 +302,52 @@ where
             .or_else(|| {
                 if unmapped_log {
                     error!(self.logger, "unmapped bus write"; o!("addr" => format!("0x{:x}", addr), "size" => U::SIZE));
+                    self.unmapped.borrow_mut().insert((addr, true));
                 }
                 Some(&self.unmap_w)
             })
             .unwrap()
     }
 
+    /// Return the list of unmapped addresses that were accessed so far, each one
+    /// with a flag which is true for writes.
+    pub fn unmapped_accesses(&self) -> Vec<(u32, bool)> {
+        self.unmapped.borrow().iter().cloned().collect()
+    }
+
+    /// Return the current memory map of the bus.
+    pub fn memory_map(&self) -> MemoryMap {
+        let mut entries = self.map.clone();
+        entries.sort_by_key(|e| e.begin);
+        MemoryMap { entries }
+    }
+
+    fn add_map_entry(
+        &mut self,
+        begin: u32,
+        end: u32,
+        kind: MapKind,
+        name: &str,
+        (read, write): (bool, bool),
+        (rwmask, callbacks): (u64, bool),
+        fields: &[RegField],
+    ) {
+        let (device, bank) = self.map_owner.unwrap_or(("", 0));
+        self.map.push(MapEntry {
+            begin,
+            end,
+            device: device.into(),
+            bank,
+            kind,
+            name: name.into(),
+            read,
+            write,
+            rwmask,
+            callbacks,
+            fields: fields.to_vec(),
+        });
+    }
+
     fn mapreg_partial<U: 'static, S>(
         &mut self,
         addr: u32,
@@ -320,10 +377,36 @@ where
         U: MemInt,
         Reg<Order, U>: MappedReg<Order = Order>,
     {
-        reg.map_into(self, addr)
+        reg.map_into(self, addr)?;
+        let flags = reg.flags();
+        self.add_map_entry(
+            addr,
+            addr + U::SIZE as u32 - 1,
+            MapKind::Reg,
+            reg.name(),
+            (
+                flags.contains(RegFlags::READACCESS),
+                flags.contains(RegFlags::WRITEACCESS),
+            ),
+            (reg.rwmask().into(), reg.has_callbacks()),
+            reg.fields(),
+        );
+        Ok(())
     }
 
     pub fn map_mem(&'b mut self, begin: u32, end: u32, mem: &'b Mem) -> Result<(), &'s str> {
+        self.map_mem_named(begin, end, mem, "")
+    }
+
+    // Like map_mem(), but also specify a name for the memory area, to be
+    // shown in the memory map.
+    pub fn map_mem_named(
+        &'b mut self,
+        begin: u32,
+        end: u32,
+        mem: &'b Mem,
+        name: &str,
+    ) -> Result<(), &'s str> {
         self.reads[AccessSize::Size8].insert_range(begin, end, mem.hwio_r::<u8>(), false)?;
         self.reads[AccessSize::Size16].insert_range(begin, end, mem.hwio_r::<u16>(), false)?;
         self.reads[AccessSize::Size32].insert_range(begin, end, mem.hwio_r::<u32>(), false)?;
@@ -334,9 +417,67 @@ where
         self.writes[AccessSize::Size32].insert_range(begin, end, mem.hwio_w::<u32>(), false)?;
         self.writes[AccessSize::Size64].insert_range(begin, end, mem.hwio_w::<u64>(), false)?;
 
+        let flags = mem.flags();
+        self.add_map_entry(
+            begin,
+            end,
+            MapKind::Mem,
+            name,
+            (
+                flags.contains(MemFlags::READACCESS),
+                flags.contains(MemFlags::WRITEACCESS),
+            ),
+            (!0, false),
+            &[],
+        );
         return Ok(());
     }
 
+    // Map an I/O area, whose accesses of any size are handled by functions
+    // (like a register with callbacks, but covering a whole range). The
+    // functions receive the address and the size in bytes of the access.
+    pub fn map_io_named(
+        &mut self,
+        begin: u32,
+        end: u32,
+        name: &str,
+        read: Rc<Fn(u32, usize) -> u64>,
+        write: Rc<Fn(u32, usize, u64)>,
+    ) -> Result<(), &'static str> {
+        let sizes = [
+            (AccessSize::Size8, 1),
+            (AccessSize::Size16, 2),
+            (AccessSize::Size32, 4),
+            (AccessSize::Size64, 8),
+        ];
+        for &(acc, size) in sizes.iter() {
+            let read = read.clone();
+            let write = write.clone();
+            self.reads[acc].insert_range(
+                begin,
+                end,
+                HwIoR::Func(Rc::new(move |addr| read(addr, size))),
+                false,
+            )?;
+            self.writes[acc].insert_range(
+                begin,
+                end,
+                HwIoW::Func(Rc::new(move |addr, val| write(addr, size, val))),
+                false,
+            )?;
+        }
+        self.add_map_entry(
+            begin,
+            end,
+            MapKind::Reg,
+            name,
+            (true, true),
+            (!0, true),
+            &[],
+        );
+        Ok(())
+    }
+
     pub fn map_device<T>(
         &'b mut self,
         base: u32,
@@ -346,7 +487,11 @@ where
     where
         T: Device<Order = Order>,
     {
-        device.borrow().dev_map(self, bank, base)
+        let dev = device.borrow();
+        self.map_owner = Some((dev.dev_name(), bank));
+        let res = dev.dev_map(self, bank, base);
+        self.map_owner = None;
+        res
     }
 
     // Add a memory map for a "combiner": that is, an internal function that combines two
@@ -474,6 +619,29 @@ mod tests {
         assert_eq!(bus.read::<u8>(0x04bbb125), 0xbb);
     }
 
+    #[test]
+    fn io_area() {
+        let last = Rc::new(RefCell::new((0, 0, 0)));
+        let mut bus = Bus::<BigEndian>::new(logger());
+        let wlast = last.clone();
+        bus.map_io_named(
+            0x1000_0000,
+            0x1FFF_FFFF,
+            "io",
+            Rc::new(|addr: u32, size: usize| (addr as u64) << 8 | size as u64),
+            Rc::new(move |addr: u32, size: usize, val: u64| {
+                *wlast.borrow_mut() = (addr, size, val)
+            }),
+        )
+        .unwrap();
+
+        assert_eq!(bus.read::<u32>(0x1000_0004), 0x0000_0404);
+        assert_eq!(bus.read::<u8>(0x1234_5679), 0x01);
+        assert_eq!(bus.read::<u64>(0x1000_0008), 0x10_0000_0808);
+        bus.write::<u16>(0x1000_0002, 0xABCD);
+        assert_eq!(*last.borrow(), (0x1000_0002, 2, 0xABCD));
+    }
+
     #[test]
     fn basic_reg() {
         let reg1 = Reg32::default();
@@ -508,6 +676,32 @@ mod tests {
         assert_eq!(bus.read::<u32>(0x04000124), 0x000056f8);
     }
 
+    #[test]
+    fn memory_map() {
+        let ram = Mem::new(1024, MemFlags::new(true, false));
+        let reg = Reg32::new("status", 0x25, 0xFF, RegFlags::new(true, false), None, None)
+            .with_fields("mode:0-1 busy:5");
+
+        let mut bus = Bus::<LittleEndian>::new(logger());
+        assert_eq!(bus.map_reg(0x04001000, &reg).is_ok(), true);
+        assert_eq!(bus.map_mem_named(0x04000000, 0x040003FF, &ram, "rom").is_ok(), true);
+
+        let map = bus.memory_map();
+        assert_eq!(map.entries.len(), 2);
+        assert_eq!(map.entries[0].name, "rom");
+        assert_eq!(map.entries[0].kind, MapKind::Mem);
+        assert_eq!(map.entries[1].end, 0x04001003);
+        assert_eq!((map.entries[1].rwmask, map.entries[1].callbacks), (0xFF, false));
+        assert_eq!(
+            map.to_text(),
+            "04000000-040003FF -        mem r- rom\n04001000-04001003 -        reg r- status\n"
+        );
+        assert!(map.to_json().contains(r#""name": "status", "read": true, "write": false"#));
+        assert_eq!(reg.decode(), "mode=1 busy=1");
+        assert_eq!(map.entries[1].decode(0xE2), "mode=2 busy=1");
+        assert_eq!(map.entries[0].decode(0xE2), "0xe2");
+    }
+
     #[test]
     fn combiner_le() {
         let reg1 = Reg32::default();
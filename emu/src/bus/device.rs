@@ -1,12 +1,18 @@
 use super::bus::Bus;
 use super::memint::ByteOrderCombiner;
-use std::cell::{Ref, RefCell, RefMut};
-use std::rc::Rc;
+use alloc::rc::Rc;
+use core::cell::{Ref, RefCell, RefMut};
 
 pub trait Device {
     type Order: ByteOrderCombiner;
 
     fn dev_init(&mut self, wself: Rc<RefCell<Self>>);
+
+    // Name of the device, as shown in the bus memory map.
+    fn dev_name(&self) -> &'static str {
+        "device"
+    }
+
     fn dev_map(
         &self,
         bus: &mut Bus<Self::Order>,
@@ -3,8 +3,10 @@ extern crate byteorder;
 use self::byteorder::ByteOrder;
 use super::bus::{unmapped_area_r, unmapped_area_w, HwIoR, HwIoW};
 use super::memint::MemInt;
-use std::cell::{RefCell, RefMut};
-use std::rc::Rc;
+use alloc::boxed::Box;
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::cell::{RefCell, RefMut};
 
 bitflags! {
    pub struct MemFlags: u8 {
@@ -66,6 +68,10 @@ impl Mem {
         self.psize
     }
 
+    pub fn flags(&self) -> MemFlags {
+        self.flags
+    }
+
     pub fn buf<'a>(&'a self) -> RefMut<'a, Box<[u8]>> {
         self.buf.borrow_mut()
     }
@@ -94,6 +100,82 @@ impl Mem {
     }
 }
 
+/// Size of the pages used to track changes between memory snapshots.
+pub const SNAPSHOT_PAGE_SIZE: usize = 4096;
+
+/// MemDelta is the list of pages of a memory area that changed between two
+/// snapshots, with their new contents.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MemDelta {
+    pages: Vec<(usize, Box<[u8]>)>,
+}
+
+impl MemDelta {
+    /// Indices of the changed pages.
+    pub fn pages(&self) -> Vec<usize> {
+        self.pages.iter().map(|&(idx, _)| idx).collect()
+    }
+
+    /// Size in bytes of the page contents held by the delta.
+    pub fn size(&self) -> usize {
+        self.pages.len() * SNAPSHOT_PAGE_SIZE
+    }
+
+    /// Copy the changed pages into the memory area.
+    pub fn apply(&self, mem: &Mem) {
+        let mut buf = mem.buf();
+        for &(idx, ref page) in &self.pages {
+            let off = idx * SNAPSHOT_PAGE_SIZE;
+            buf[off..off + page.len()].copy_from_slice(page);
+        }
+    }
+}
+
+/// MemSnapshot tracks the pages of a memory area that were written since the
+/// last snapshot, so that incremental snapshots only need to store those.
+/// Memory is accessed through raw slices by devices (eg: DMA) and by the CPU
+/// fast paths, so dirty pages are found by comparing with a copy of the last
+/// snapshot rather than by intercepting writes.
+pub struct MemSnapshot {
+    base: Box<[u8]>,
+}
+
+impl MemSnapshot {
+    pub fn new(mem: &Mem) -> MemSnapshot {
+        MemSnapshot {
+            base: mem.buf().clone(),
+        }
+    }
+
+    /// Pages written since the last snapshot.
+    pub fn dirty_pages(&self, mem: &Mem) -> Vec<usize> {
+        let buf = mem.buf();
+        buf.chunks(SNAPSHOT_PAGE_SIZE)
+            .zip(self.base.chunks(SNAPSHOT_PAGE_SIZE))
+            .enumerate()
+            .filter(|&(_, (cur, old))| cur != old)
+            .map(|(idx, _)| idx)
+            .collect()
+    }
+
+    /// Take a new snapshot, returning the pages changed since the previous one.
+    pub fn take_delta(&mut self, mem: &Mem) -> MemDelta {
+        let buf = mem.buf();
+        let mut pages = Vec::new();
+        for (idx, (cur, old)) in buf
+            .chunks(SNAPSHOT_PAGE_SIZE)
+            .zip(self.base.chunks_mut(SNAPSHOT_PAGE_SIZE))
+            .enumerate()
+        {
+            if cur != &old[..] {
+                old.copy_from_slice(cur);
+                pages.push((idx, cur.to_vec().into_boxed_slice()));
+            }
+        }
+        MemDelta { pages }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -108,4 +190,24 @@ mod tests {
 
         assert_eq!(ram.buf()[128], 5);
     }
+    #[test]
+    fn mem_snapshot() {
+        let ram = Mem::new(4 * SNAPSHOT_PAGE_SIZE, MemFlags::default());
+        let mut snap = MemSnapshot::new(&ram);
+        assert!(snap.dirty_pages(&ram).is_empty());
+
+        ram.buf()[SNAPSHOT_PAGE_SIZE + 10] = 1;
+        ram.buf()[3 * SNAPSHOT_PAGE_SIZE] = 2;
+        assert_eq!(snap.dirty_pages(&ram), vec![1, 3]);
+
+        let delta = snap.take_delta(&ram);
+        assert_eq!(delta.pages(), vec![1, 3]);
+        assert_eq!(delta.size(), 2 * SNAPSHOT_PAGE_SIZE);
+        assert!(snap.take_delta(&ram).pages().is_empty());
+
+        // Applying the delta on top of the previous snapshot (all zeros)
+        let ram2 = Mem::new(4 * SNAPSHOT_PAGE_SIZE, MemFlags::default());
+        delta.apply(&ram2);
+        assert_eq!(&ram2.buf()[..], &ram.buf()[..]);
+    }
 }
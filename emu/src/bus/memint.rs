@@ -1,8 +1,8 @@
 extern crate byteorder;
-extern crate num;
+extern crate num_traits;
 
 use self::byteorder::{BigEndian, ByteOrder, LittleEndian};
-use self::num::PrimInt;
+use self::num_traits::PrimInt;
 
 #[derive(Debug, Enum, Copy, Clone)]
 pub enum AccessSize {
@@ -14,7 +14,7 @@ pub enum AccessSize {
 
 pub trait MemInt: PrimInt + Into<u64> + Default {
     type Half: MemInt + Into<Self>;
-    const SIZE: usize = ::std::mem::size_of::<Self>();
+    const SIZE: usize = ::core::mem::size_of::<Self>();
     const ACCESS_SIZE: AccessSize;
     fn truncate_from(v: u64) -> Self;
     fn endian_read_from<O: ByteOrder>(buf: &[u8]) -> Self;
@@ -0,0 +1,113 @@
+use super::regs::RegField;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Kind of area mapped on the bus.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MapKind {
+    Mem,
+    Reg,
+}
+
+/// An entry of the memory map of a bus: an address range, the device (and
+/// bank) that mapped it, and the access permissions.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MapEntry {
+    pub begin: u32,
+    pub end: u32,
+    pub device: String, // empty if not mapped through a device
+    pub bank: usize,
+    pub kind: MapKind,
+    pub name: String,
+    pub read: bool,
+    pub write: bool,
+    pub rwmask: u64,           // writable bits (registers only)
+    pub callbacks: bool,       // true if accesses trigger callbacks (registers only)
+    pub fields: Vec<RegField>, // documented bitfields (registers only)
+}
+
+impl MapEntry {
+    fn access(&self) -> &'static str {
+        match (self.read, self.write) {
+            (true, true) => "rw",
+            (true, false) => "r-",
+            (false, true) => "-w",
+            (false, false) => "--",
+        }
+    }
+
+    /// Decode a value of the register symbolically, using its documented
+    /// bitfields. Values are shown in hex if there are none.
+    pub fn decode(&self, val: u64) -> String {
+        if self.fields.is_empty() {
+            format!("{:#x}", val)
+        } else {
+            RegField::decode(&self.fields, val)
+        }
+    }
+
+    fn owner(&self) -> String {
+        if self.device.is_empty() {
+            "-".into()
+        } else {
+            format!("{}[{}]", self.device, self.bank)
+        }
+    }
+}
+
+impl fmt::Display for MapEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            MapKind::Mem => "mem",
+            MapKind::Reg => "reg",
+        };
+        write!(
+            f,
+            "{:08X}-{:08X} {:<8} {} {} {}",
+            self.begin,
+            self.end,
+            self.owner(),
+            kind,
+            self.access(),
+            self.name
+        )
+    }
+}
+
+/// MemoryMap is a snapshot of all the areas mapped on a bus, sorted by
+/// address.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MemoryMap {
+    pub entries: Vec<MapEntry>,
+}
+
+impl MemoryMap {
+    pub fn to_text(&self) -> String {
+        self.entries
+            .iter()
+            .map(|e| format!("{}\n", e))
+            .collect()
+    }
+
+    pub fn to_json(&self) -> String {
+        let entries: Vec<String> = self
+            .entries
+            .iter()
+            .map(|e| {
+                format!(
+                    r#"  {{"begin": {}, "end": {}, "device": {:?}, "bank": {}, "kind": "{}", "name": {:?}, "read": {}, "write": {}}}"#,
+                    e.begin,
+                    e.end,
+                    e.device,
+                    e.bank,
+                    if e.kind == MapKind::Mem { "mem" } else { "reg" },
+                    e.name,
+                    e.read,
+                    e.write
+                )
+            })
+            .collect();
+        format!("[\n{}\n]\n", entries.join(",\n"))
+    }
+}
@@ -4,14 +4,16 @@ mod bus;
 mod device;
 mod mem;
 mod memint;
+mod memmap;
 mod radix;
 mod regs;
 
 pub use self::bus::{Bus, MemIoR, MemIoRIterator, MemIoW};
 pub use self::device::{DevPtr, Device};
-pub use self::mem::{Mem, MemFlags};
+pub use self::mem::{Mem, MemDelta, MemFlags, MemSnapshot, SNAPSHOT_PAGE_SIZE};
 pub use self::memint::MemInt;
-pub use self::regs::{Reg, RegDeref, RegFlags, RegRef};
+pub use self::memmap::{MapEntry, MapKind, MemoryMap};
+pub use self::regs::{Reg, RegDeref, RegField, RegFlags, RegRef};
 
 pub mod le {
     use super::byteorder::LittleEndian;
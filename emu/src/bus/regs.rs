@@ -2,11 +2,14 @@ extern crate byteorder;
 
 use super::bus::{unmapped_area_r, unmapped_area_w, HwIoR, HwIoW, MemIoR, MemIoW};
 use super::memint::{ByteOrderCombiner, MemInt};
-use std::cell::RefCell;
-use std::fmt;
-use std::marker::PhantomData;
-use std::ops::{Deref, DerefMut};
-use std::rc::Rc;
+use alloc::boxed::Box;
+use alloc::rc::Rc;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::fmt;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
 
 bitflags! {
    pub struct RegFlags: u8 {
@@ -34,6 +37,57 @@ impl Default for RegFlags {
     }
 }
 
+/// A bitfield of a register, as documented in the device declaration. It
+/// spans bits `lo` to `hi` (inclusive).
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RegField {
+    pub name: &'static str,
+    pub lo: u32,
+    pub hi: u32,
+}
+
+impl RegField {
+    /// Parse a list of bitfields, separated by whitespace. Each bitfield is
+    /// either "name:bit" or "name:lo-hi".
+    pub fn parse(desc: &'static str) -> Vec<RegField> {
+        desc.split_whitespace()
+            .map(|f| {
+                let (name, bits) = match f.find(':') {
+                    Some(idx) => (&f[..idx], &f[idx + 1..]),
+                    None => panic!("invalid register field: {}", f),
+                };
+                let mut range = bits.split('-').map(|b| {
+                    b.parse::<u32>()
+                        .unwrap_or_else(|_| panic!("invalid register field: {}", f))
+                });
+                let lo = range.next().unwrap();
+                let hi = range.next().unwrap_or(lo);
+                if hi < lo || hi >= 64 {
+                    panic!("invalid register field: {}", f);
+                }
+                RegField { name, lo, hi }
+            })
+            .collect()
+    }
+
+    /// Extract the value of the bitfield from a register value.
+    pub fn get(&self, val: u64) -> u64 {
+        let width = self.hi - self.lo + 1;
+        let mask = if width == 64 { !0 } else { (1 << width) - 1 };
+        (val >> self.lo) & mask
+    }
+
+    /// Decode a register value as a list of "name=value" pairs, one per
+    /// bitfield.
+    pub fn decode(fields: &[RegField], val: u64) -> String {
+        fields
+            .iter()
+            .map(|f| format!("{}={}", f.name, f.get(val)))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
 type Wcb<U> = Option<Rc<Box<Fn(U, U)>>>;
 type Rcb<U> = Option<Rc<Box<Fn(U) -> U>>>;
 
@@ -48,6 +102,7 @@ where
     flags: RegFlags,
     wcb: Wcb<U>,
     rcb: Rcb<U>,
+    fields: Vec<RegField>,
     phantom: PhantomData<O>,
 }
 
@@ -64,6 +119,7 @@ where
             flags: RegFlags::default(),
             wcb: None,
             rcb: None,
+            fields: Vec::new(),
             phantom: PhantomData,
         }
     }
@@ -95,10 +151,48 @@ where
         return reg;
     }
 
+    /// Attach the documentation of the register bitfields (see
+    /// `RegField::parse` for the format).
+    pub fn with_fields(mut self, desc: &'static str) -> Self {
+        self.fields = RegField::parse(desc);
+        self
+    }
+
+    pub fn fields(&self) -> &[RegField] {
+        &self.fields
+    }
+
+    /// Decode the current value of the register symbolically, using its
+    /// bitfields. Registers without documented bitfields are shown in hex.
+    pub fn decode(&self) -> String {
+        let val: u64 = self.get().into();
+        if self.fields.is_empty() {
+            format!("{:#x}", val)
+        } else {
+            RegField::decode(&self.fields, val)
+        }
+    }
+
     pub fn as_ref<R: RegDeref<Type = U>>(&self) -> RegRef<O, R> {
         RegRef::new(self)
     }
 
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn flags(&self) -> RegFlags {
+        self.flags
+    }
+
+    pub fn rwmask(&self) -> U {
+        !self.romask
+    }
+
+    pub fn has_callbacks(&self) -> bool {
+        self.wcb.is_some() || self.rcb.is_some()
+    }
+
     /// Get the current value of the register in memory, bypassing any callback.
     pub fn get(&self) -> U {
         let val: U = Self::refcell_get(&self.raw);
@@ -263,8 +357,8 @@ mod tests {
     use super::super::memint::{ByteOrderCombiner, MemInt};
     use super::super::{be, le};
     use super::{Reg, RegFlags};
-    use std::marker::PhantomData;
-    use std::rc::Rc;
+    use alloc::rc::Rc;
+    use core::marker::PhantomData;
 
     #[derive(Default)]
     struct FakeBus<O: ByteOrderCombiner, U: MemInt + 'static> {
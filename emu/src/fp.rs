@@ -1,12 +1,14 @@
-extern crate num;
+extern crate num_traits;
 extern crate typenum;
-use self::num::cast::NumCast;
-use self::num::{PrimInt, ToPrimitive, Zero};
+#[cfg(not(feature = "std"))]
+use self::num_traits::float::FloatCore;
+use self::num_traits::cast::NumCast;
+use self::num_traits::{PrimInt, ToPrimitive, Zero};
 use self::typenum::{U0, U128, U16, U32, U64, U8, Unsigned};
-use std::fmt;
-use std::iter;
-use std::marker::PhantomData;
-use std::ops;
+use core::fmt;
+use core::iter;
+use core::marker::PhantomData;
+use core::ops;
 
 pub trait FixedPointInt: PrimInt + ToPrimitive + iter::Step {
     type DoubleInt: FixedPointInt;
@@ -9,7 +9,10 @@ use self::typenum::{
 };
 use super::super::bus::MemInt;
 use super::{Color, ColorConverter, ColorFormat};
-use std::marker::PhantomData;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use core::slice::{Chunks, ChunksMut};
 
 pub struct GfxBuffer<'a, CF: ColorFormat + Sized, O: ByteOrder> {
     mem: &'a [u8],
@@ -37,6 +40,21 @@ pub struct GfxLineMut<'a, CF: ColorFormat + Sized, O: ByteOrder> {
     phantom: PhantomData<(CF, O)>,
 }
 
+/// Iterator over the lines of a GfxBuffer, top to bottom.
+pub struct GfxLines<'a, CF: ColorFormat + Sized, O: ByteOrder> {
+    chunks: Chunks<'a, u8>,
+    len: usize,
+    phantom: PhantomData<(CF, O)>,
+}
+
+/// Iterator over the lines of a GfxBufferMut, top to bottom. Lines are
+/// disjoint, so they can be kept and written at the same time.
+pub struct GfxLinesMut<'a, CF: ColorFormat + Sized, O: ByteOrder> {
+    chunks: ChunksMut<'a, u8>,
+    len: usize,
+    phantom: PhantomData<(CF, O)>,
+}
+
 pub struct OwnedGfxBuffer<CF: ColorFormat + Sized, O: ByteOrder> {
     mem: Vec<u8>,
     width: usize,
@@ -76,6 +94,14 @@ impl<'a: 's, 's, CF: ColorFormat + Sized, O: ByteOrder> GfxBuffer<'a, CF, O> {
         })
     }
 
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
     pub fn raw(&'s self) -> (&'a [u8], usize) {
         (self.mem, self.pitch)
     }
@@ -86,6 +112,14 @@ impl<'a: 's, 's, CF: ColorFormat + Sized, O: ByteOrder> GfxBuffer<'a, CF, O> {
             phantom: PhantomData,
         }
     }
+
+    pub fn iter_lines(&'s self) -> GfxLines<'a, CF, O> {
+        GfxLines {
+            chunks: self.mem.chunks(self.pitch.max(1)),
+            len: self.width * CF::BITS::to_usize() / 8,
+            phantom: PhantomData,
+        }
+    }
 }
 
 impl<'a: 's, 's, CF: ColorFormat + Sized, O: ByteOrder> GfxBufferMut<'a, CF, O> {
@@ -120,6 +154,14 @@ impl<'a: 's, 's, CF: ColorFormat + Sized, O: ByteOrder> GfxBufferMut<'a, CF, O>
         })
     }
 
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
     pub fn raw(&'s mut self) -> (&'s mut [u8], usize) {
         (self.mem, self.pitch)
     }
@@ -149,6 +191,116 @@ impl<'a: 's, 's, CF: ColorFormat + Sized, O: ByteOrder> GfxBufferMut<'a, CF, O>
             },
         )
     }
+
+    pub fn iter_lines_mut(&'s mut self) -> GfxLinesMut<'s, CF, O> {
+        GfxLinesMut {
+            chunks: self.mem.chunks_mut(self.pitch.max(1)),
+            len: self.width * CF::BITS::to_usize() / 8,
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn fill(&'s mut self, c: Color<CF>) {
+        for mut line in self.iter_lines_mut() {
+            line.fill(c);
+        }
+    }
+}
+
+impl<'a, CF: ColorFormat + Sized, O: ByteOrder> Iterator for GfxLines<'a, CF, O> {
+    type Item = GfxLine<'a, CF, O>;
+
+    fn next(&mut self) -> Option<GfxLine<'a, CF, O>> {
+        let len = self.len;
+        self.chunks.next().map(|mem| GfxLine {
+            mem: &mem[..len],
+            phantom: PhantomData,
+        })
+    }
+}
+
+impl<'a, CF: ColorFormat + Sized, O: ByteOrder> Iterator for GfxLinesMut<'a, CF, O> {
+    type Item = GfxLineMut<'a, CF, O>;
+
+    fn next(&mut self) -> Option<GfxLineMut<'a, CF, O>> {
+        let len = self.len;
+        self.chunks.next().map(|mem| GfxLineMut {
+            mem: &mut mem[..len],
+            phantom: PhantomData,
+        })
+    }
+}
+
+impl<'a, CF: ColorFormat + Sized, O: ByteOrder> GfxLine<'a, CF, O> {
+    pub fn width(&self) -> usize {
+        self.mem.len() * 8 / CF::BITS::to_usize()
+    }
+
+    pub fn raw(&self) -> &'a [u8] {
+        self.mem
+    }
+}
+
+impl<'a, CF: ColorFormat + Sized, O: ByteOrder> GfxLineMut<'a, CF, O> {
+    pub fn width(&self) -> usize {
+        self.mem.len() * 8 / CF::BITS::to_usize()
+    }
+
+    pub fn as_line<'s>(&'s self) -> GfxLine<'s, CF, O> {
+        GfxLine {
+            mem: self.mem,
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn fill(&mut self, c: Color<CF>) {
+        for x in 0..self.width() {
+            self.set(x, c);
+        }
+    }
+
+    /// Copy a line of the same format; the shortest of the two lines
+    /// determines the number of copied pixels.
+    pub fn copy_from(&mut self, src: &GfxLine<CF, O>) {
+        let n = self.mem.len().min(src.mem.len());
+        self.mem[..n].copy_from_slice(&src.mem[..n]);
+    }
+
+    /// Convert a whole line from a different format.
+    pub fn convert_from<CF2: ColorFormat + Sized, O2: ByteOrder>(&mut self, src: &GfxLine<CF2, O2>) {
+        self.convert_from_scaled(src, 1);
+    }
+
+    /// Convert a whole line from a different format, replicating each
+    /// source pixel `scale` times horizontally.
+    pub fn convert_from_scaled<CF2: ColorFormat + Sized, O2: ByteOrder>(
+        &mut self,
+        src: &GfxLine<CF2, O2>,
+        scale: usize,
+    ) {
+        let w = src.width().min(self.width() / scale);
+        for x in 0..w {
+            let px = src.get(x).cconv();
+            for i in 0..scale {
+                self.set(x * scale + i, px);
+            }
+        }
+    }
+
+    /// Convert a whole line from a different format, resampling it (nearest
+    /// neighbour) so that it covers the whole destination line.
+    pub fn convert_from_stretched<CF2: ColorFormat + Sized, O2: ByteOrder>(
+        &mut self,
+        src: &GfxLine<CF2, O2>,
+    ) {
+        let (sw, dw) = (src.width(), self.width());
+        if sw == 0 {
+            return;
+        }
+        for x in 0..dw {
+            self.set(x, src.get(x * sw / dw).cconv());
+        }
+    }
 }
 
 pub trait BufferLineGetter<CF: ColorFormat> {
@@ -322,12 +474,10 @@ impl<CF: ColorFormat + Sized, O: ByteOrder> OwnedGfxBuffer<CF, O> {
     ) -> OwnedGfxBuffer<CF, O> {
         let (w, h) = (buf.width, buf.height);
         let mut dst = OwnedGfxBuffer::<CF, O>::new(w, h);
-        for y in 0..h {
-            let src = buf.line(y);
+        {
             let mut dstbuf = dst.buf_mut();
-            let mut dst = dstbuf.line(y);
-            for x in 0..w {
-                dst.set(x, src.get(x).cconv());
+            for (mut dst, src) in dstbuf.iter_lines_mut().zip(buf.iter_lines()) {
+                dst.convert_from(&src);
             }
         }
         dst
@@ -380,7 +530,7 @@ pub type OwnedGfxBufferBE<CF> = OwnedGfxBuffer<CF, BigEndian>;
 
 #[cfg(test)]
 mod tests {
-    use super::super::{Abgr8888, ColorConverter, I4, Rgb565, Rgb888, Rgba8888};
+    use super::super::{Abgr8888, ColorConverter, I4, Rgb555, Rgb565, Rgb888, Rgba8888};
     use super::byteorder::ByteOrder;
     use super::*;
 
@@ -433,6 +583,69 @@ mod tests {
         );
     }
 
+    #[test]
+    fn line_iter() {
+        let mut v1 = Vec::<u8>::new();
+        let mut v2 = Vec::<u8>::new();
+        v1.resize(4 * 4 * 2, 0);
+        v2.resize(8 * 4 * 4, 0);
+
+        let c1 = Color::<Rgb555>::new_clamped(0x13, 0x08, 0x1F, 0);
+        {
+            let mut buf1 = GfxBufferMut::<Rgb555, LittleEndian>::new(&mut v1, 3, 4, 8).unwrap();
+            buf1.fill(c1);
+            assert_eq!(buf1.iter_lines_mut().count(), 4);
+        }
+        // Padding bytes at the end of each line are untouched
+        assert_eq!(&v1[6..8], &[0, 0]);
+        {
+            let buf1 = GfxBuffer::<Rgb555, LittleEndian>::new(&v1, 3, 4, 8).unwrap();
+            let mut buf2 =
+                GfxBufferMut::<Rgb888, LittleEndian>::new(&mut v2, 8, 4, 8 * 4).unwrap();
+            for (mut dst, src) in buf2.iter_lines_mut().zip(buf1.iter_lines()) {
+                assert_eq!(src.width(), 3);
+                dst.convert_from_scaled(&src, 2);
+            }
+        }
+        {
+            let buf2 = GfxBuffer::<Rgb888, LittleEndian>::new(&v2, 8, 4, 8 * 4).unwrap();
+            let c2: Color<Rgb888> = c1.cconv();
+            for line in buf2.iter_lines() {
+                for x in 0..6 {
+                    assert_eq!(line.get(x), c2);
+                }
+                assert_eq!(line.get(6), Color::new_clamped(0, 0, 0, 0));
+            }
+        }
+    }
+
+    #[test]
+    fn line_stretch() {
+        let mut v1 = Vec::<u8>::new();
+        let mut v2 = Vec::<u8>::new();
+        v1.resize(2 * 4, 0);
+        v2.resize(5 * 4, 0);
+
+        let c0 = Color::<Rgb888>::new_clamped(0x10, 0x20, 0x30, 0);
+        let c1 = Color::<Rgb888>::new_clamped(0x40, 0x50, 0x60, 0);
+        {
+            let mut buf1 = GfxBufferMut::<Rgb888, LittleEndian>::new(&mut v1, 2, 1, 8).unwrap();
+            buf1.line(0).set2(0, c0, c1);
+        }
+        {
+            let buf1 = GfxBuffer::<Rgb888, LittleEndian>::new(&v1, 2, 1, 8).unwrap();
+            let mut buf2 = GfxBufferMut::<Rgb888, LittleEndian>::new(&mut v2, 5, 1, 20).unwrap();
+            assert_eq!((buf2.width(), buf2.height()), (5, 1));
+            buf2.line(0).convert_from_stretched(&buf1.line(0));
+        }
+        let buf2 = GfxBuffer::<Rgb888, LittleEndian>::new(&v2, 5, 1, 20).unwrap();
+        let line = buf2.line(0);
+        assert_eq!(
+            (0..5).map(|x| line.get(x)).collect::<Vec<_>>(),
+            vec![c0, c0, c0, c1, c1]
+        );
+    }
+
     #[test]
     fn bpp4() {
         let mut v1 = Vec::<u8>::new();
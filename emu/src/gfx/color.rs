@@ -1,6 +1,9 @@
 extern crate byteorder;
+extern crate num_traits;
 extern crate typenum;
 
+#[cfg(not(feature = "std"))]
+use self::num_traits::float::FloatCore;
 #[allow(unused_imports)]
 use self::typenum::{
     IsEqual, IsGreaterOrEqual, True, U0, U1, U10, U11, U12, U13, U14, U15, U16, U17, U18, U19, U2,
@@ -8,8 +11,8 @@ use self::typenum::{
     Unsigned,
 };
 use super::super::bus::MemInt;
-use std::fmt;
-use std::marker::PhantomData;
+use core::fmt;
+use core::marker::PhantomData;
 
 trait Component {
     type U: MemInt;
@@ -97,9 +100,20 @@ impl<U: MemInt, N: Unsigned, S: Unsigned> Value<U, N, S> {
                 (f, t) if f == t => v.val,
                 (f, t) if f >= t => (v.val >> (f - t)),
                 (f, t) if f < t => {
-                    // TODO: this formula doesn't work for all bit sizes, but only for common one.
-                    // We're basically doing (v<<3)|(v>>2) when converting 5 bits to 8 bits.
-                    (v.val << (t - f)) | (v.val >> (f - (t - f)))
+                    // Replicate the source bits until all destination bits are
+                    // filled. For instance, this is (v<<3)|(v>>2) when converting
+                    // 5 bits to 8 bits, and (v<<5)|(v<<2)|(v>>1) for 3 bits to 8 bits.
+                    let (f, mut shift) = (f as isize, t as isize - f as isize);
+                    let mut val = 0;
+                    while shift > -f {
+                        val |= if shift >= 0 {
+                            v.val << shift
+                        } else {
+                            v.val >> -shift
+                        };
+                        shift -= f;
+                    }
+                    val
                 }
                 (_, _) => unimplemented!(),
             },
@@ -252,6 +266,17 @@ impl<CF: ColorFormat> Color<CF> {
     pub fn components(&self) -> (i32, i32, i32, i32) {
         (self.r.val, self.g.val, self.b.val, self.a.val)
     }
+
+    /// Convert a YUV color (ITU-R BT.601, as used by the RDP and by the
+    /// video microcodes) into an opaque RGB color.
+    pub fn from_yuv(y: u8, u: u8, v: u8) -> Self {
+        let (y, u, v) = (y as f32, u as f32 - 128.0, v as f32 - 128.0);
+        let r = y + 1.402 * v;
+        let g = y - 0.344 * u - 0.714 * v;
+        let b = y + 1.772 * u;
+        Color::<Rgba8888>::new_clamped(r.round() as i32, g.round() as i32, b.round() as i32, 0xFF)
+            .cconv()
+    }
 }
 
 impl<CF: ColorFormat> fmt::Debug for Color<CF> {
@@ -272,6 +297,9 @@ impl<CF: ColorFormat> fmt::Debug for Color<CF> {
 
 pub type I4 = cf<u8, U4, U4, U0, U0, U0, U0, U0, U0, U0>;
 pub type I8 = cf<u8, U8, U8, U0, U0, U0, U0, U0, U0, U0>;
+pub type Ia31 = cf<u8, U4, U3, U1, U0, U0, U0, U0, U1, U0>;
+pub type Ia44 = cf<u8, U8, U4, U4, U0, U0, U0, U0, U4, U0>;
+pub type Ia88 = cf<u16, U16, U8, U8, U0, U0, U0, U0, U8, U0>;
 pub type Rgb555 = cf<u16, U16, U5, U0, U5, U5, U5, U10, U0, U0>;
 pub type Rgb565 = cf<u16, U16, U5, U0, U6, U5, U5, U11, U0, U0>;
 pub type Rgb888 = cf<u32, U32, U8, U0, U8, U8, U8, U16, U0, U0>;
@@ -279,6 +307,60 @@ pub type Rgba5551 = cf<u16, U16, U5, U0, U5, U5, U5, U10, U1, U15>;
 pub type Rgba8888 = cf<u32, U32, U8, U0, U8, U8, U8, U16, U8, U24>;
 pub type Abgr8888 = cf<u32, U32, U8, U24, U8, U16, U8, U8, U8, U0>;
 
+/// Color-indexed formats: the index is stored in the red component. They are
+/// distinct types from intensity formats with the same layout, so that they
+/// are not accidentally converted as greyscale; use Color::lookup instead.
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub struct ci<U, BITS> {
+    phantom: PhantomData<(U, BITS)>,
+}
+
+impl<U: MemInt, BITS: Unsigned + Copy> ColorFormat for ci<U, BITS> {
+    type U = U;
+    type BITS = BITS;
+    type RN = BITS;
+    type RS = U0;
+    type GN = U0;
+    type GS = U0;
+    type BN = U0;
+    type BS = U0;
+    type AN = U0;
+    type AS = U0;
+}
+
+pub type Ci4 = ci<u8, U4>;
+pub type Ci8 = ci<u8, U8>;
+
+impl<U: MemInt, BITS: Unsigned + Copy> Color<ci<U, BITS>> {
+    pub fn index(&self) -> usize {
+        self.r.val as usize
+    }
+
+    /// Convert the color through a palette (TLUT). Out of range indices
+    /// are mapped to transparent black.
+    pub fn lookup<CF2: ColorFormat>(&self, palette: &[Color<CF2>]) -> Color<CF2> {
+        match palette.get(self.index()) {
+            Some(c) => *c,
+            None => Color::new_clamped(0, 0, 0, 0),
+        }
+    }
+}
+
+/// Coverage plane: the RDP keeps 3 bits of coverage for each pixel of a
+/// 16-bit framebuffer (the alpha bit of the pixel, plus 2 hidden RDRAM bits).
+/// A coverage plane stores one byte per pixel, with coverage in the alpha
+/// component so that it converts naturally to an alpha channel.
+pub type Cvg3 = cf<u8, U8, U0, U0, U0, U0, U0, U0, U3, U0>;
+
+impl Color<Rgba5551> {
+    /// Build the full 3-bit coverage value of a pixel, given its hidden bits.
+    pub fn coverage(&self, hidden: u8) -> Color<Cvg3> {
+        let (_, _, _, a) = self.components();
+        Color::new_clamped(0, 0, 0, (a << 2) | (hidden as i32 & 3))
+    }
+}
+
 pub trait ColorConverter<CF2: ColorFormat>: Sized {
     #[inline(always)]
     fn cconv(self) -> Color<CF2>;
@@ -324,6 +406,36 @@ macro_rules! define_greyscale_conversions {
 define_greyscale_conversions!(I4, Rgba8888, Rgba5551, Rgb888, Rgb565, Rgb555);
 define_greyscale_conversions!(I8, Rgba8888, Rgba5551, Rgb888, Rgb565, Rgb555);
 
+// Intensity+alpha formats: same as greyscale, but alpha is preserved.
+macro_rules! define_greyscale_alpha_conversions {
+    ($cfgrey:ident, $($cf2:ident),+) => {
+        $(
+            impl ColorConverter<$cf2> for Color<$cfgrey> {
+                #[inline(always)]
+                fn cconv(self) -> Color<$cf2> {
+                    Color {
+                        r: self.r.into(),
+                        g: self.r.into(),
+                        b: self.r.into(),
+                        a: self.a.into(),
+                    }
+                }
+            }
+            impl ColorConverter<$cfgrey> for Color<$cf2> {
+                #[inline(always)]
+                fn cconv(self) -> Color<$cfgrey> {
+                    let i = self.r.as_f32() * 0.30 + self.g.as_f32() * 0.59 + self.b.as_f32() * 0.11;
+                    Color::from_f32_clamped(i, 0.0, 0.0, self.a.as_f32())
+                }
+            }
+        )+
+    };
+}
+
+define_greyscale_alpha_conversions!(Ia31, Rgba8888, Rgba5551, Rgb888, Rgb565, Rgb555);
+define_greyscale_alpha_conversions!(Ia44, Rgba8888, Rgba5551, Rgb888, Rgb565, Rgb555);
+define_greyscale_alpha_conversions!(Ia88, Rgba8888, Rgba5551, Rgb888, Rgb565, Rgb555);
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -361,6 +473,22 @@ mod tests {
         assert_eq!(Color::<Rgba8888>::new_clamped(0x44, 0x44, 0x44, 0xff), c2);
     }
 
+    #[test]
+    fn yuv() {
+        assert_eq!(
+            Color::<Rgb888>::from_yuv(0x80, 0x80, 0x80),
+            Color::<Rgb888>::new_clamped(0x80, 0x80, 0x80, 0)
+        );
+        assert_eq!(
+            Color::<Rgb888>::from_yuv(76, 84, 255),
+            Color::<Rgb888>::new_clamped(254, 0, 0, 0)
+        );
+        assert_eq!(
+            Color::<Rgba5551>::from_yuv(235, 128, 128),
+            Color::<Rgba5551>::new_clamped(29, 29, 29, 1)
+        );
+    }
+
     #[test]
     fn alpha() {
         assert_eq!(Color::<Rgb555>::new(0x10, 0x10, 0x10, 0).is_some(), true);
@@ -471,4 +599,50 @@ mod tests {
         let v2: Color<Rgb888> = v1.cconv();
         assert_eq!(v2, Color::<Rgb888>::new_clamped(0x88, 0x88, 0x88, 0));
     }
+
+    #[test]
+    fn intensity_alpha() {
+        let v1 = Color::<Ia88>::from_bits(0x80C0);
+        assert_eq!(v1.components(), (0x80, 0, 0, 0xC0));
+        let v2: Color<Rgba8888> = v1.cconv();
+        assert_eq!(v2, Color::<Rgba8888>::new_clamped(0x80, 0x80, 0x80, 0xC0));
+
+        let v1 = Color::<Ia44>::from_bits(0x9C);
+        let v2: Color<Rgba8888> = v1.cconv();
+        assert_eq!(v2, Color::<Rgba8888>::new_clamped(0x99, 0x99, 0x99, 0xCC));
+
+        let v1 = Color::<Ia31>::from_bits(0xB);
+        assert_eq!(v1.components(), (0x5, 0, 0, 1));
+        let v2: Color<Rgba5551> = v1.cconv();
+        assert_eq!(v2.components().3, 1);
+
+        let v3: Color<Ia88> = Color::<Rgba8888>::new_clamped(0, 0, 0, 0xFF).cconv();
+        assert_eq!(v3.to_bits(), 0x00FF);
+    }
+
+    #[test]
+    fn color_index() {
+        let pal = [
+            Color::<Rgba5551>::new_clamped(0x1F, 0, 0, 1),
+            Color::<Rgba5551>::new_clamped(0, 0x1F, 0, 1),
+        ];
+        let c = Color::<Ci8>::from_bits(1);
+        assert_eq!(c.index(), 1);
+        assert_eq!(c.lookup(&pal), pal[1]);
+        assert_eq!(
+            Color::<Ci4>::from_bits(7).lookup(&pal),
+            Color::new_clamped(0, 0, 0, 0)
+        );
+    }
+
+    #[test]
+    fn coverage() {
+        let c = Color::<Rgba5551>::from_bits(0x8000);
+        assert_eq!(c.coverage(3).components().3, 7);
+        assert_eq!(c.coverage(0).components().3, 4);
+        let c = Color::<Rgba5551>::from_bits(0x0000);
+        assert_eq!(c.coverage(2).components().3, 2);
+        let a: Color<Rgba8888> = c.coverage(2).cconv();
+        assert_eq!(a.components().3, (2 << 5) | (2 << 2) | (2 >> 1));
+    }
 }
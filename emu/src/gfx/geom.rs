@@ -1,8 +1,8 @@
-extern crate num;
-use self::num::PrimInt;
-use super::super::fp::{FixedPoint, Q};
-use std::fmt;
-use std::ops;
+extern crate num_traits;
+use self::num_traits::PrimInt;
+use super::super::fp::{FixedPoint, FixedPointInt, Q};
+use core::fmt;
+use core::ops;
 
 #[derive(Copy, Clone, Default, Eq, PartialEq)]
 pub struct Point<FP: FixedPoint> {
@@ -31,6 +31,10 @@ impl<FP: FixedPoint> Point<FP> {
     pub fn truncate(self) -> Point<impl FixedPoint> {
         Point::new(self.x.truncate(), self.y.truncate())
     }
+    #[inline(always)]
+    pub fn scale<N: FixedPointInt>(self, n: N) -> Self {
+        Self::new(self.x * n, self.y * n)
+    }
 }
 
 impl<FP: FixedPoint> ops::Add for Point<FP> {
@@ -93,6 +97,10 @@ impl<FP: FixedPoint> Rect<FP> {
         Rect::new(self.c0.truncate(), self.c1.truncate())
     }
     #[inline(always)]
+    pub fn scale<N: FixedPointInt>(self, n: N) -> Self {
+        Self::new(self.c0.scale(n), self.c1.scale(n))
+    }
+    #[inline(always)]
     pub fn set_width(&mut self, w: Q<FP>) {
         self.c1.x = self.c0.x + w;
     }
@@ -0,0 +1,397 @@
+extern crate byteorder;
+#[cfg(feature = "cpal")]
+extern crate cpal;
+extern crate sdl2;
+
+use self::byteorder::{LittleEndian, WriteBytesExt};
+use self::sdl2::audio::{AudioQueue, AudioSpecDesired};
+use super::{Error, Result};
+#[cfg(feature = "cpal")]
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+#[cfg(feature = "cpal")]
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "cpal")]
+use std::thread;
+use std::time::Duration;
+
+/// An AudioBackend is the final destination of the audio samples produced
+/// by the emulator. Samples are signed 16-bit, interleaved stereo; the
+/// sample rate is specified with each batch, as emulated hardware can change
+/// it at any time.
+pub trait AudioBackend {
+    fn queue_samples(&mut self, freq: u32, samples: &[i16]) -> Result<()>;
+
+    // Duration of the samples queued and not played yet, for backends that
+    // play them in real time (see SyncMode::Audio).
+    fn queued(&self) -> Option<Duration> {
+        None
+    }
+}
+
+// Duration of the specified number of stereo frames.
+fn frames_duration(frames: u64, freq: u32) -> Duration {
+    let freq = freq as u64;
+    let nanos = (frames % freq) * 1_000_000_000 / freq;
+    Duration::new(frames / freq, nanos as u32)
+}
+
+/// Linear resampler for interleaved stereo samples, for backends that play or
+/// store samples at a fixed rate. It keeps its position across batches, so
+/// that they join seamlessly.
+struct Resampler {
+    // Position of the next output frame in the input, where -1 is the last
+    // frame of the previous batch
+    pos: f64,
+    last: (i16, i16),
+}
+
+impl Resampler {
+    fn new() -> Resampler {
+        Resampler {
+            pos: 0.0,
+            last: (0, 0),
+        }
+    }
+
+    fn resample(&mut self, from: u32, to: u32, samples: &[i16], out: &mut Vec<i16>) {
+        let frames = samples.len() / 2;
+        if frames == 0 {
+            return;
+        }
+        if from == to {
+            out.extend_from_slice(&samples[..frames * 2]);
+        } else {
+            let step = from as f64 / to as f64;
+            let frame = |idx: isize, last: (i16, i16)| match idx {
+                -1 => last,
+                idx => (samples[idx as usize * 2], samples[idx as usize * 2 + 1]),
+            };
+            while self.pos.floor() as isize + 1 < frames as isize {
+                let idx = self.pos.floor() as isize;
+                let (t, (l0, r0), (l1, r1)) = (
+                    self.pos - idx as f64,
+                    frame(idx, self.last),
+                    frame(idx + 1, self.last),
+                );
+                out.push((l0 as f64 + (l1 as f64 - l0 as f64) * t) as i16);
+                out.push((r0 as f64 + (r1 as f64 - r0 as f64) * t) as i16);
+                self.pos += step;
+            }
+        }
+        self.pos = if from == to { 0.0 } else { self.pos - frames as f64 };
+        self.last = (samples[frames * 2 - 2], samples[frames * 2 - 1]);
+    }
+}
+
+/// Play audio through a SDL audio queue.
+pub struct SdlAudio {
+    sub: sdl2::AudioSubsystem,
+    queue: Option<(u32, AudioQueue<i16>)>,
+}
+
+impl SdlAudio {
+    pub fn new(context: &sdl2::Sdl) -> Result<SdlAudio> {
+        let sub = context
+            .audio()
+            .map_err(|e| Error::Sdl(format!("error creating audio subsystem: {:?}", e)))?;
+        Ok(SdlAudio { sub, queue: None })
+    }
+}
+
+impl AudioBackend for SdlAudio {
+    fn queue_samples(&mut self, freq: u32, samples: &[i16]) -> Result<()> {
+        // (Re)open the audio device whenever the sample rate changes.
+        if self.queue.as_ref().map(|&(f, _)| f) != Some(freq) {
+            let spec = AudioSpecDesired {
+                freq: Some(freq as i32),
+                channels: Some(2),
+                samples: None,
+            };
+            let queue = self
+                .sub
+                .open_queue::<i16, _>(None, &spec)
+                .map_err(Error::Sdl)?;
+            queue.resume();
+            self.queue = Some((freq, queue));
+        }
+
+        let queue = &self.queue.as_ref().unwrap().1;
+        if !queue.queue(samples) {
+            return Err(Error::Sdl(sdl2::get_error()));
+        }
+        Ok(())
+    }
+
+    fn queued(&self) -> Option<Duration> {
+        // 4 bytes per stereo sample
+        self.queue
+            .as_ref()
+            .map(|&(freq, ref queue)| frames_duration(queue.size() as u64 / 4, freq))
+    }
+}
+
+/// Play audio through the default output device of the system with cpal,
+/// for frontends that do not use SDL. Samples are resampled to the rate of
+/// the device, and queued for the audio thread run by cpal.
+#[cfg(feature = "cpal")]
+pub struct CpalAudio {
+    event_loop: Arc<cpal::EventLoop>,
+    stream: cpal::StreamId,
+    rate: u32,
+    queue: Arc<Mutex<VecDeque<i16>>>,
+    resampler: Resampler,
+    buf: Vec<i16>,
+}
+
+#[cfg(feature = "cpal")]
+impl CpalAudio {
+    pub fn new() -> Result<CpalAudio> {
+        let device = cpal::default_output_device()
+            .ok_or_else(|| Error::Audio("no audio output device".into()))?;
+        let format = device
+            .default_output_format()
+            .map_err(|e| Error::Audio(format!("error querying audio device: {:?}", e)))?;
+        let event_loop = Arc::new(cpal::EventLoop::new());
+        let stream = event_loop
+            .build_output_stream(&device, &format)
+            .map_err(|e| Error::Audio(format!("error opening audio device: {:?}", e)))?;
+
+        let queue = Arc::new(Mutex::new(VecDeque::new()));
+        let (evloop, evqueue) = (event_loop.clone(), queue.clone());
+        let channels = format.channels as usize;
+        thread::spawn(move || {
+            evloop.run(move |_, data| {
+                if let cpal::StreamData::Output { buffer } = data {
+                    match buffer {
+                        cpal::UnknownTypeOutputBuffer::U16(mut buf) => {
+                            CpalAudio::fill(&mut buf, channels, &evqueue)
+                        }
+                        cpal::UnknownTypeOutputBuffer::I16(mut buf) => {
+                            CpalAudio::fill(&mut buf, channels, &evqueue)
+                        }
+                        cpal::UnknownTypeOutputBuffer::F32(mut buf) => {
+                            CpalAudio::fill(&mut buf, channels, &evqueue)
+                        }
+                    }
+                }
+            });
+        });
+        event_loop.play_stream(stream.clone());
+
+        Ok(CpalAudio {
+            event_loop,
+            stream,
+            rate: format.sample_rate.0,
+            queue,
+            resampler: Resampler::new(),
+            buf: Vec::new(),
+        })
+    }
+
+    // Fill a buffer of the device with the queued samples, and silence when
+    // there are not enough. Stereo is mixed down for mono devices.
+    fn fill<S: cpal::Sample>(out: &mut [S], channels: usize, queue: &Mutex<VecDeque<i16>>) {
+        let mut queue = queue.lock().unwrap();
+        for frame in out.chunks_mut(channels) {
+            let (l, r) = match (queue.pop_front(), queue.pop_front()) {
+                (Some(l), Some(r)) => (l, r),
+                _ => (0, 0),
+            };
+            for (ch, out) in frame.iter_mut().enumerate() {
+                let sample = match (channels, ch) {
+                    (1, _) => ((l as i32 + r as i32) / 2) as i16,
+                    (_, 0) => l,
+                    (_, 1) => r,
+                    _ => 0,
+                };
+                *out = S::from(&sample);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "cpal")]
+impl AudioBackend for CpalAudio {
+    fn queue_samples(&mut self, freq: u32, samples: &[i16]) -> Result<()> {
+        self.buf.clear();
+        self.resampler
+            .resample(freq, self.rate, samples, &mut self.buf);
+        self.queue.lock().unwrap().extend(&self.buf);
+        Ok(())
+    }
+
+    fn queued(&self) -> Option<Duration> {
+        let frames = self.queue.lock().unwrap().len() as u64 / 2;
+        Some(frames_duration(frames, self.rate))
+    }
+}
+
+#[cfg(feature = "cpal")]
+impl Drop for CpalAudio {
+    fn drop(&mut self) {
+        self.event_loop.destroy_stream(self.stream.clone());
+    }
+}
+
+/// Discard all samples (headless runs).
+pub struct NullAudio;
+
+impl AudioBackend for NullAudio {
+    fn queue_samples(&mut self, _freq: u32, _samples: &[i16]) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Write all samples into a WAV file. The sample rate of the file is the one
+/// of the first batch of samples; later batches at a different rate are
+/// resampled to it.
+pub struct WavAudio {
+    path: PathBuf,
+    out: BufWriter<File>,
+    freq: Option<u32>,
+    nbytes: u32,
+    resampler: Resampler,
+    buf: Vec<i16>,
+}
+
+impl WavAudio {
+    pub fn new(path: &Path) -> Result<WavAudio> {
+        let file = File::create(path).map_err(|e| Error::File(path.into(), e))?;
+        Ok(WavAudio {
+            path: path.into(),
+            out: BufWriter::new(file),
+            freq: None,
+            nbytes: 0,
+            resampler: Resampler::new(),
+            buf: Vec::new(),
+        })
+    }
+
+    fn write_header(&mut self, freq: u32) -> ::std::io::Result<()> {
+        self.out.write_all(b"RIFF")?;
+        self.out.write_u32::<LittleEndian>(36 + self.nbytes)?;
+        self.out.write_all(b"WAVEfmt ")?;
+        self.out.write_u32::<LittleEndian>(16)?; // fmt chunk size
+        self.out.write_u16::<LittleEndian>(1)?; // PCM
+        self.out.write_u16::<LittleEndian>(2)?; // channels
+        self.out.write_u32::<LittleEndian>(freq)?;
+        self.out.write_u32::<LittleEndian>(freq * 4)?; // byte rate
+        self.out.write_u16::<LittleEndian>(4)?; // block align
+        self.out.write_u16::<LittleEndian>(16)?; // bits per sample
+        self.out.write_all(b"data")?;
+        self.out.write_u32::<LittleEndian>(self.nbytes)?;
+        Ok(())
+    }
+
+    fn write_samples(&mut self, freq: u32, samples: &[i16]) -> ::std::io::Result<()> {
+        let rate = match self.freq {
+            Some(rate) => rate,
+            None => {
+                self.freq = Some(freq);
+                self.write_header(freq)?;
+                freq
+            }
+        };
+        self.buf.clear();
+        self.resampler.resample(freq, rate, samples, &mut self.buf);
+        for s in &self.buf {
+            self.out.write_i16::<LittleEndian>(*s)?;
+        }
+        self.nbytes += self.buf.len() as u32 * 2;
+        Ok(())
+    }
+}
+
+impl AudioBackend for WavAudio {
+    fn queue_samples(&mut self, freq: u32, samples: &[i16]) -> Result<()> {
+        self.write_samples(freq, samples)
+            .map_err(|e| Error::File(self.path.clone(), e))
+    }
+}
+
+impl Drop for WavAudio {
+    fn drop(&mut self) {
+        // Rewrite the header now that the final size is known.
+        if let Some(freq) = self.freq {
+            let _ = self
+                .out
+                .seek(SeekFrom::Start(0))
+                .and_then(|_| self.write_header(freq))
+                .and_then(|_| self.out.flush());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn wav_header() {
+        let path = env::temp_dir().join("emu_wav_header_test.wav");
+        {
+            let mut wav = WavAudio::new(&path).unwrap();
+            wav.queue_samples(32000, &[1, -1, 2, -2]).unwrap();
+            wav.queue_samples(32000, &[3, -3]).unwrap();
+        }
+
+        let data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(data.len(), 44 + 12);
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(&data[4..8], &[48, 0, 0, 0]);
+        assert_eq!(&data[24..28], &[0x00, 0x7D, 0, 0]); // 32000 Hz
+        assert_eq!(&data[40..44], &[12, 0, 0, 0]);
+        assert_eq!(&data[44..48], &[1, 0, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn resample() {
+        let mut rs = Resampler::new();
+        let mut out = Vec::new();
+
+        // Same rate: samples are passed through
+        rs.resample(32000, 32000, &[10, -10, 20, -20], &mut out);
+        assert_eq!(out, vec![10, -10, 20, -20]);
+
+        // Downsampling by 2: every other frame, continuing from the
+        // previous batch
+        out.clear();
+        rs.resample(64000, 32000, &[30, -30, 40, -40, 50, -50, 60, -60], &mut out);
+        assert_eq!(out, vec![30, -30, 50, -50]);
+
+        // Upsampling by 2: frames are interpolated, including between the
+        // last frame of the previous batch and the first one of the next
+        out.clear();
+        rs.resample(16000, 32000, &[80, -80, 100, -100], &mut out);
+        assert_eq!(out, vec![80, -80, 90, -90]);
+        out.clear();
+        rs.resample(16000, 32000, &[120, -120], &mut out);
+        assert_eq!(out, vec![100, -100, 110, -110]);
+    }
+
+    #[test]
+    fn wav_rate_change() {
+        let path = env::temp_dir().join("emu_wav_rate_change_test.wav");
+        {
+            let mut wav = WavAudio::new(&path).unwrap();
+            wav.queue_samples(32000, &[1, -1]).unwrap();
+            wav.queue_samples(64000, &[2, -2, 3, -3, 4, -4, 5, -5, 6, -6]).unwrap();
+        }
+
+        // The second batch is resampled to the rate of the file
+        let data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(&data[24..28], &[0x00, 0x7D, 0, 0]); // 32000 Hz
+        assert_eq!(&data[40..44], &[12, 0, 0, 0]);
+        assert_eq!(
+            &data[44..],
+            &[1, 0, 0xFF, 0xFF, 2, 0, 0xFE, 0xFF, 4, 0, 0xFC, 0xFF]
+        );
+    }
+}
@@ -0,0 +1,71 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::result;
+
+/// Errors returned by the host frontend: the backends, the helpers that
+/// read and write host files, and the parsers of the frontend options.
+#[derive(Debug)]
+pub enum Error {
+    /// SDL failed to initialize a subsystem, or to present a frame.
+    Sdl(String),
+    /// The audio device failed to open, or to play the samples.
+    Audio(String),
+    /// A host file could not be opened, created, read or written.
+    File(PathBuf, io::Error),
+    /// A PNG file could not be encoded or decoded.
+    Png(PathBuf, String),
+    /// An I/O error on a stream that is not tied to a file.
+    Io(io::Error),
+    /// An option (frame skip, hotkeys, pad profiles, ...) has an invalid value.
+    Parse(String),
+    /// A scheduling hint could not be applied to a thread.
+    Thread(String),
+    /// The producer could not be created in the emulation thread.
+    Producer(String),
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::File(ref path, ref err) => write!(f, "{}: {}", path.display(), err),
+            Error::Png(ref path, ref err) => write!(f, "{}: {}", path.display(), err),
+            Error::Io(ref err) => write!(f, "{}", err),
+            Error::Sdl(ref err)
+            | Error::Audio(ref err)
+            | Error::Parse(ref err)
+            | Error::Thread(ref err)
+            | Error::Producer(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Sdl(_) => "SDL error",
+            Error::Audio(_) => "audio error",
+            Error::File(_, _) | Error::Io(_) => "I/O error",
+            Error::Png(_, _) => "PNG error",
+            Error::Parse(_) => "invalid option",
+            Error::Thread(_) => "cannot apply thread hint",
+            Error::Producer(_) => "cannot create producer",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            Error::File(_, ref err) | Error::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
@@ -0,0 +1,368 @@
+use super::{Error, Result};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Frame skipping policy. A skipped frame is fully emulated, but it is
+/// neither scanned out by the VI nor presented, which saves most of the
+/// video work on slow hosts.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FrameSkip {
+    Off,
+    // Always skip N frames out of every M.
+    Manual(u32, u32),
+    // Skip frames only while emulation is behind real time, but never more
+    // than the specified number of consecutive frames.
+    Auto(u32),
+}
+
+impl Default for FrameSkip {
+    fn default() -> FrameSkip {
+        FrameSkip::Off
+    }
+}
+
+impl FrameSkip {
+    /// Parse a frame skip policy: "off", "auto", "auto:<max>" or "<n>/<m>".
+    pub fn parse(s: &str) -> Result<FrameSkip> {
+        let err = || Error::Parse(format!("invalid frame skip: {}", s));
+        match s {
+            "off" => Ok(FrameSkip::Off),
+            "auto" => Ok(FrameSkip::Auto(3)),
+            s if s.starts_with("auto:") => match s["auto:".len()..].parse::<u32>() {
+                Ok(max) if max > 0 => Ok(FrameSkip::Auto(max)),
+                _ => Err(err()),
+            },
+            s => {
+                let mut parts = s.splitn(2, '/');
+                let n = parts.next().unwrap().parse::<u32>();
+                let m = parts.next().unwrap_or("").parse::<u32>();
+                match (n, m) {
+                    // At least one frame out of M must be presented
+                    (Ok(n), Ok(m)) if n < m => Ok(FrameSkip::Manual(n, m)),
+                    _ => Err(err()),
+                }
+            }
+        }
+    }
+}
+
+/// When emulated frames are presented to the host.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PresentMode {
+    // Present every emulated frame, at a fixed cadence.
+    Fixed,
+    // Present a frame only when the game swaps framebuffers, which follows
+    // its real frame rate and never shows a buffer while it is being drawn.
+    // Games that do not swap for a while (eg: single-buffered) fall back to
+    // the fixed cadence.
+    OnSwap,
+}
+
+impl Default for PresentMode {
+    fn default() -> PresentMode {
+        PresentMode::Fixed
+    }
+}
+
+impl PresentMode {
+    /// Parse a presentation mode: "fixed" or "swap".
+    pub fn parse(s: &str) -> Result<PresentMode> {
+        match s {
+            "fixed" => Ok(PresentMode::Fixed),
+            "swap" => Ok(PresentMode::OnSwap),
+            _ => Err(Error::Parse(format!("invalid presentation mode: {}", s))),
+        }
+    }
+}
+
+/// What governs the emulation speed.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SyncMode {
+    // Emulate frames at the refresh rate of the emulated machine, measured
+    // with the host timer.
+    Video,
+    // Emulate frames as fast as the audio output plays them, keeping its
+    // buffer at a constant level: sound never crackles, even if the refresh
+    // rate of the host display does not match the emulated one. Frames
+    // without audio fall back to the video timer.
+    Audio,
+}
+
+impl Default for SyncMode {
+    fn default() -> SyncMode {
+        SyncMode::Video
+    }
+}
+
+impl SyncMode {
+    /// Parse a synchronization mode: "video" or "audio".
+    pub fn parse(s: &str) -> Result<SyncMode> {
+        match s {
+            "video" => Ok(SyncMode::Video),
+            "audio" => Ok(SyncMode::Audio),
+            _ => Err(Error::Parse(format!("invalid sync mode: {}", s))),
+        }
+    }
+}
+
+/// Emulation speed, relative to the real hardware.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Speed {
+    // Percentage of the real speed (25-400).
+    Percent(u32),
+    // As fast as possible.
+    Unlimited,
+}
+
+impl Default for Speed {
+    fn default() -> Speed {
+        Speed::Percent(100)
+    }
+}
+
+// Speeds selected by the speed hotkeys, in percent.
+const SPEED_STEPS: [u32; 8] = [25, 50, 75, 100, 150, 200, 300, 400];
+
+impl Speed {
+    /// Parse a speed: a percentage between 25 and 400 (eg: "50%"), or
+    /// "unlimited".
+    pub fn parse(s: &str) -> Result<Speed> {
+        if s == "unlimited" {
+            return Ok(Speed::Unlimited);
+        }
+        match s.trim_right_matches('%').parse::<u32>() {
+            Ok(p) if p >= 25 && p <= 400 => Ok(Speed::Percent(p)),
+            _ => Err(Error::Parse(format!("invalid speed: {}", s))),
+        }
+    }
+
+    /// Next faster speed step; above the highest step, speed is unlimited.
+    pub fn faster(self) -> Speed {
+        match self {
+            Speed::Percent(p) => match SPEED_STEPS.iter().find(|&&s| s > p) {
+                Some(&s) => Speed::Percent(s),
+                None => Speed::Unlimited,
+            },
+            Speed::Unlimited => Speed::Unlimited,
+        }
+    }
+
+    /// Next slower speed step.
+    pub fn slower(self) -> Speed {
+        let p = match self {
+            Speed::Percent(p) => p,
+            Speed::Unlimited => u32::max_value(),
+        };
+        match SPEED_STEPS.iter().rev().find(|&&s| s < p) {
+            Some(&s) => Speed::Percent(s),
+            None => self,
+        }
+    }
+
+    /// Sample rate at which audio generated at the specified rate must be
+    /// played to follow the emulation speed, with a pitch change. No audio
+    /// is played at unlimited speed (returns 0).
+    pub fn scale_freq(self, freq: u32) -> u32 {
+        match self {
+            Speed::Percent(p) => (freq as u64 * p as u64 / 100) as u32,
+            Speed::Unlimited => 0,
+        }
+    }
+}
+
+impl fmt::Display for Speed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Speed::Percent(p) => write!(f, "{}%", p),
+            Speed::Unlimited => write!(f, "unlimited"),
+        }
+    }
+}
+
+// In OnSwap mode, number of frames without a swap after which every frame is
+// presented again.
+const SWAP_TIMEOUT_FRAMES: u32 = 30;
+
+// If emulation gets behind by more than this number of frames (eg: after a
+// pause), give up catching up and restart pacing from the current time.
+const MAX_LATE_FRAMES: u32 = 8;
+
+/// FramePacer keeps track of the emulation speed compared to real time. It
+/// decides which frames to skip, and how long to wait before emulating the
+/// next frame, according to the emulation speed.
+pub struct FramePacer {
+    skip: FrameSkip,
+    speed: Speed,
+    // Duration of a frame at real speed, and at the current speed
+    base_frame_time: Duration,
+    frame_time: Duration,
+    deadline: Option<Instant>,
+    nframes: u64,
+    skipped: u32, // consecutive skipped frames
+    present: PresentMode,
+    swapped: bool,   // swap not presented yet
+    since_swap: u32, // consecutive frames without a swap
+}
+
+impl FramePacer {
+    pub fn new(fps: isize, skip: FrameSkip) -> FramePacer {
+        let frame_time = Duration::from_secs(1) / fps.max(1) as u32;
+        FramePacer {
+            skip,
+            speed: Speed::default(),
+            base_frame_time: frame_time,
+            frame_time,
+            deadline: None,
+            nframes: 0,
+            skipped: 0,
+            present: PresentMode::Fixed,
+            swapped: false,
+            since_swap: 0,
+        }
+    }
+
+    pub fn set_present_mode(&mut self, present: PresentMode) {
+        self.present = present;
+    }
+
+    /// Change the emulation speed. At unlimited speed, frames are still
+    /// accounted at real speed, to decide which ones to skip.
+    pub fn set_speed(&mut self, speed: Speed) {
+        self.speed = speed;
+        self.frame_time = match speed {
+            Speed::Percent(p) => self.base_frame_time * 100 / p,
+            Speed::Unlimited => self.base_frame_time,
+        };
+    }
+
+    /// Account for a new frame about to be emulated at the specified time,
+    /// and return true if it must be skipped.
+    pub fn next_frame(&mut self, now: Instant) -> bool {
+        let mut deadline = self.deadline.unwrap_or(now) + self.frame_time;
+        if now > deadline + self.frame_time * MAX_LATE_FRAMES {
+            deadline = now + self.frame_time;
+        }
+        // The frame is late if it starts after the time it should have been
+        // completed.
+        let late = now > deadline - self.frame_time;
+        self.deadline = Some(deadline);
+
+        let skip = match self.skip {
+            FrameSkip::Off => false,
+            FrameSkip::Manual(n, m) => (self.nframes % m as u64) < n as u64,
+            FrameSkip::Auto(max) => late && self.skipped < max,
+        };
+        self.nframes += 1;
+        self.skipped = if skip { self.skipped + 1 } else { 0 };
+        skip
+    }
+
+    /// Account for the frame that was just emulated, and return true if it
+    /// must be presented. Skipped frames are never presented, but their
+    /// swaps are carried over to the next frame.
+    pub fn present(&mut self, skipped: bool, swapped: bool) -> bool {
+        self.swapped |= swapped;
+        self.since_swap = if swapped { 0 } else { self.since_swap + 1 };
+        if skipped {
+            return false;
+        }
+        let present = match self.present {
+            PresentMode::Fixed => true,
+            PresentMode::OnSwap => self.swapped || self.since_swap >= SWAP_TIMEOUT_FRAMES,
+        };
+        if present {
+            self.swapped = false;
+        }
+        present
+    }
+
+    /// Time to wait, after the current frame was emulated, so that emulation
+    /// does not run faster than the selected speed.
+    pub fn delay(&self, now: Instant) -> Duration {
+        match self.deadline {
+            Some(deadline) if deadline > now && self.speed != Speed::Unlimited => deadline - now,
+            _ => Duration::from_secs(0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_skip() {
+        assert_eq!(FrameSkip::parse("off").unwrap(), FrameSkip::Off);
+        assert_eq!(FrameSkip::parse("auto:5").unwrap(), FrameSkip::Auto(5));
+        assert_eq!(FrameSkip::parse("1/3").unwrap(), FrameSkip::Manual(1, 3));
+        assert!(FrameSkip::parse("3/3").is_err());
+        assert!(FrameSkip::parse("auto:0").is_err());
+
+        let t0 = Instant::now();
+        let frame = Duration::from_millis(20);
+        let mut p = FramePacer::new(50, FrameSkip::Manual(1, 3));
+        let skips: Vec<bool> = (0..6).map(|i| p.next_frame(t0 + frame * i)).collect();
+        assert_eq!(skips, vec![true, false, false, true, false, false]);
+
+        // On time: nothing is skipped, and pacing waits for the deadline
+        let mut p = FramePacer::new(50, FrameSkip::Auto(2));
+        assert!(!p.next_frame(t0));
+        assert_eq!(p.delay(t0 + frame / 2), frame / 2);
+        assert!(!p.next_frame(t0 + frame));
+
+        // Falling behind: at most 2 consecutive frames are skipped
+        let skips: Vec<bool> = (0..4).map(|_| p.next_frame(t0 + frame * 5)).collect();
+        assert_eq!(skips, vec![true, true, false, false]);
+        assert_eq!(p.delay(t0 + frame * 5), frame);
+    }
+
+    #[test]
+    fn speed() {
+        assert_eq!(Speed::parse("50%").unwrap(), Speed::Percent(50));
+        assert_eq!(Speed::parse("unlimited").unwrap(), Speed::Unlimited);
+        assert!(Speed::parse("10%").is_err());
+        assert_eq!(Speed::Percent(100).faster(), Speed::Percent(150));
+        assert_eq!(Speed::Percent(400).faster(), Speed::Unlimited);
+        assert_eq!(Speed::Unlimited.slower(), Speed::Percent(400));
+        assert_eq!(Speed::Percent(60).slower(), Speed::Percent(50));
+        assert_eq!(Speed::Percent(25).slower(), Speed::Percent(25));
+        assert_eq!(Speed::Percent(50).scale_freq(44100), 22050);
+
+        // Half speed: frames last twice as long
+        let t0 = Instant::now();
+        let frame = Duration::from_millis(20);
+        let mut p = FramePacer::new(50, FrameSkip::Off);
+        p.set_speed(Speed::Percent(50));
+        p.next_frame(t0);
+        assert_eq!(p.delay(t0), frame * 2);
+        p.set_speed(Speed::Unlimited);
+        p.next_frame(t0);
+        assert_eq!(p.delay(t0), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn present_on_swap() {
+        assert_eq!(PresentMode::parse("swap").unwrap(), PresentMode::OnSwap);
+        assert!(PresentMode::parse("vsync").is_err());
+        assert_eq!(SyncMode::parse("audio").unwrap(), SyncMode::Audio);
+        assert!(SyncMode::parse("swap").is_err());
+
+        let mut p = FramePacer::new(60, FrameSkip::Off);
+        assert!(p.present(false, false));
+        p.set_present_mode(PresentMode::OnSwap);
+        assert!(!p.present(false, false));
+        assert!(p.present(false, true));
+
+        // A swap in a skipped frame is presented with the next frame
+        assert!(!p.present(true, true));
+        assert!(p.present(false, false));
+        assert!(!p.present(false, false));
+
+        // Without swaps, every frame is presented after a while
+        for _ in 2..SWAP_TIMEOUT_FRAMES - 1 {
+            assert!(!p.present(false, false));
+        }
+        assert!(p.present(false, false));
+        assert!(p.present(false, false));
+    }
+}
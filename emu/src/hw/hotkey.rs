@@ -0,0 +1,148 @@
+extern crate sdl2;
+
+use self::sdl2::keyboard::Keycode;
+use super::{Error, Result};
+use std::collections::HashMap;
+
+/// Actions that can be triggered through a keyboard shortcut while the
+/// emulator is running.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Hotkey {
+    Quit,
+    SaveState,
+    LoadState,
+    Screenshot,
+    Pause,
+    FastForward,
+    Fullscreen,
+    Reset,
+    SpeedUp,
+    SpeedDown,
+}
+
+impl Hotkey {
+    pub fn from_name(name: &str) -> Option<Hotkey> {
+        Some(match name {
+            "quit" => Hotkey::Quit,
+            "savestate" => Hotkey::SaveState,
+            "loadstate" => Hotkey::LoadState,
+            "screenshot" => Hotkey::Screenshot,
+            "pause" => Hotkey::Pause,
+            "fastforward" => Hotkey::FastForward,
+            "fullscreen" => Hotkey::Fullscreen,
+            "reset" => Hotkey::Reset,
+            "speedup" => Hotkey::SpeedUp,
+            "speeddown" => Hotkey::SpeedDown,
+            _ => return None,
+        })
+    }
+}
+
+/// HotkeyTable maps keys to hotkeys. A key can be bound to a single hotkey;
+/// binding it twice is reported as a conflict.
+#[derive(Clone, Debug)]
+pub struct HotkeyTable {
+    keys: HashMap<Keycode, Hotkey>,
+}
+
+impl Default for HotkeyTable {
+    fn default() -> HotkeyTable {
+        let mut t = HotkeyTable::empty();
+        for &(key, hk) in [
+            (Keycode::Escape, Hotkey::Quit),
+            (Keycode::F5, Hotkey::SaveState),
+            (Keycode::F7, Hotkey::LoadState),
+            (Keycode::F12, Hotkey::Screenshot),
+            (Keycode::P, Hotkey::Pause),
+            (Keycode::Tab, Hotkey::FastForward),
+            (Keycode::F11, Hotkey::Fullscreen),
+            (Keycode::F9, Hotkey::Reset),
+            (Keycode::Equals, Hotkey::SpeedUp),
+            (Keycode::Minus, Hotkey::SpeedDown),
+        ]
+        .iter()
+        {
+            t.keys.insert(key, hk);
+        }
+        t
+    }
+}
+
+impl HotkeyTable {
+    pub fn empty() -> HotkeyTable {
+        HotkeyTable {
+            keys: HashMap::new(),
+        }
+    }
+
+    /// Bind a key to the specified hotkey, replacing any other key previously
+    /// bound to it. Fails if the key is already bound to a different hotkey.
+    pub fn bind(&mut self, key: Keycode, hk: Hotkey) -> Result<()> {
+        match self.keys.get(&key) {
+            Some(&other) if other != hk => return Err(conflict(key, other, hk)),
+            _ => {}
+        }
+        self.keys.retain(|_, v| *v != hk);
+        self.keys.insert(key, hk);
+        Ok(())
+    }
+
+    /// Parse a list of bindings in the form "hotkey=key,hotkey=key", where
+    /// keys use SDL key names (eg: "pause=P,fullscreen=F11"), and apply them
+    /// on top of the current ones. Bindings in the list take precedence over
+    /// the current ones, but cannot conflict with each other.
+    pub fn parse(&mut self, bindings: &str) -> Result<()> {
+        let mut seen = HashMap::new();
+        for b in bindings.split(',').filter(|b| !b.is_empty()) {
+            let mut parts = b.splitn(2, '=');
+            let (name, key) = (parts.next().unwrap(), parts.next().unwrap_or(""));
+            let hk = Hotkey::from_name(name.trim())
+                .ok_or_else(|| Error::Parse(format!("unknown hotkey: {}", name)))?;
+            let key = Keycode::from_name(key.trim())
+                .ok_or_else(|| Error::Parse(format!("unknown key name: {}", key)))?;
+
+            match seen.insert(key, hk) {
+                Some(other) if other != hk => return Err(conflict(key, other, hk)),
+                _ => {}
+            }
+            self.keys.remove(&key);
+            self.bind(key, hk)?;
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, key: Keycode) -> Option<Hotkey> {
+        self.keys.get(&key).cloned()
+    }
+}
+
+fn conflict(key: Keycode, hk1: Hotkey, hk2: Hotkey) -> Error {
+    Error::Parse(format!(
+        "hotkey conflict: {} is bound to both {:?} and {:?}",
+        key.name(),
+        hk1,
+        hk2
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conflicts() {
+        let mut t = HotkeyTable::default();
+        assert_eq!(t.get(Keycode::P), Some(Hotkey::Pause));
+
+        // Overriding a default binding is fine
+        t.parse("pause=Space,screenshot=P").unwrap();
+        assert_eq!(t.get(Keycode::Space), Some(Hotkey::Pause));
+        assert_eq!(t.get(Keycode::P), Some(Hotkey::Screenshot));
+        assert_eq!(t.get(Keycode::F12), None);
+
+        // Binding the same key twice in the configuration is not
+        assert!(t.parse("reset=R,fullscreen=R").is_err());
+        assert!(t.parse("pause=Foo").is_err());
+        assert!(t.parse("foo=P").is_err());
+    }
+}
@@ -0,0 +1,359 @@
+extern crate byteorder;
+extern crate sdl2;
+
+use self::byteorder::{BigEndian, ByteOrder};
+use self::sdl2::keyboard::{KeyboardState, Scancode};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+pub const MAX_PADS: usize = 4;
+
+bitflags! {
+    #[derive(Default)]
+    pub struct PadButtons: u16 {
+        const A = 0x8000;
+        const B = 0x4000;
+        const Z = 0x2000;
+        const START = 0x1000;
+        const DUP = 0x0800;
+        const DDOWN = 0x0400;
+        const DLEFT = 0x0200;
+        const DRIGHT = 0x0100;
+        const L = 0x0020;
+        const R = 0x0010;
+        const CUP = 0x0008;
+        const CDOWN = 0x0004;
+        const CLEFT = 0x0002;
+        const CRIGHT = 0x0001;
+    }
+}
+
+/// State of a controller, in the same format returned by the hardware
+/// to the "read buttons" joybus command.
+#[derive(Copy, Clone, Default, Debug, PartialEq, Eq)]
+pub struct PadState {
+    pub buttons: PadButtons,
+    pub x: i8,
+    pub y: i8,
+}
+
+impl PadState {
+    pub fn to_bytes(&self) -> [u8; 4] {
+        let mut buf = [0u8; 4];
+        BigEndian::write_u16(&mut buf[0..2], self.buttons.bits());
+        buf[2] = self.x as u8;
+        buf[3] = self.y as u8;
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> PadState {
+        PadState {
+            buttons: PadButtons::from_bits_truncate(BigEndian::read_u16(&buf[0..2])),
+            x: buf[2] as i8,
+            y: buf[3] as i8,
+        }
+    }
+}
+
+/// State of all the controller ports; None means that no controller is
+/// connected to the port.
+pub type PadPorts = [Option<PadState>; MAX_PADS];
+
+/// An InputSource provides the state of the controllers, once per frame.
+/// All sources (live devices, movies, network) are consumed through the
+/// same path, so that they cannot diverge.
+pub trait InputSource: Send {
+    fn poll(&mut self, frame: u64) -> PadPorts;
+}
+
+impl<S: InputSource + ?Sized> InputSource for Box<S> {
+    fn poll(&mut self, frame: u64) -> PadPorts {
+        (**self).poll(frame)
+    }
+}
+
+/// Live input from the host devices. The state is updated by Output
+/// (which owns the SDL event loop) and read by the emulation thread.
+#[derive(Clone, Default)]
+pub struct LiveInput {
+    state: Arc<Mutex<PadPorts>>,
+}
+
+static KEYMAP: [(Scancode, PadButtons); 14] = [
+    (Scancode::X, PadButtons::A),
+    (Scancode::C, PadButtons::B),
+    (Scancode::Z, PadButtons::Z),
+    (Scancode::Return, PadButtons::START),
+    (Scancode::T, PadButtons::DUP),
+    (Scancode::G, PadButtons::DDOWN),
+    (Scancode::F, PadButtons::DLEFT),
+    (Scancode::H, PadButtons::DRIGHT),
+    (Scancode::A, PadButtons::L),
+    (Scancode::S, PadButtons::R),
+    (Scancode::I, PadButtons::CUP),
+    (Scancode::K, PadButtons::CDOWN),
+    (Scancode::J, PadButtons::CLEFT),
+    (Scancode::L, PadButtons::CRIGHT),
+];
+
+impl LiveInput {
+    pub fn new() -> LiveInput {
+        LiveInput::default()
+    }
+
+    pub fn set(&self, ports: PadPorts) {
+        *self.state.lock().unwrap() = ports;
+    }
+
+    // Update the state from the host game controllers (one per port, in
+    // order of connection) and the keyboard, which is merged into the
+    // first port.
+    pub fn update(&self, pads: &[PadState], kbd: &KeyboardState) {
+        let mut ports = PadPorts::default();
+        for (port, pad) in ports.iter_mut().zip(pads.iter()) {
+            *port = Some(*pad);
+        }
+
+        let mut pad = ports[0].unwrap_or_default();
+        for &(sc, button) in KEYMAP.iter() {
+            if kbd.is_scancode_pressed(sc) {
+                pad.buttons |= button;
+            }
+        }
+        let axis = |neg, pos| match (kbd.is_scancode_pressed(neg), kbd.is_scancode_pressed(pos)) {
+            (true, false) => -80,
+            (false, true) => 80,
+            _ => 0,
+        };
+        pad.x = pad.x.saturating_add(axis(Scancode::Left, Scancode::Right));
+        pad.y = pad.y.saturating_add(axis(Scancode::Down, Scancode::Up));
+        ports[0] = Some(pad);
+
+        self.set(ports);
+    }
+}
+
+impl InputSource for LiveInput {
+    fn poll(&mut self, _frame: u64) -> PadPorts {
+        *self.state.lock().unwrap()
+    }
+}
+
+/// Scripted input: a list of state changes, each applied at a specific frame.
+/// Mostly useful for tests and automated runs.
+#[derive(Default)]
+pub struct ScriptedInput {
+    changes: BTreeMap<u64, Vec<(usize, Option<PadState>)>>,
+    state: PadPorts,
+}
+
+impl ScriptedInput {
+    pub fn new() -> ScriptedInput {
+        ScriptedInput::default()
+    }
+
+    pub fn at(mut self, frame: u64, port: usize, pad: Option<PadState>) -> ScriptedInput {
+        self.changes
+            .entry(frame)
+            .or_insert(Vec::new())
+            .push((port, pad));
+        self
+    }
+}
+
+impl InputSource for ScriptedInput {
+    fn poll(&mut self, frame: u64) -> PadPorts {
+        if let Some(changes) = self.changes.get(&frame) {
+            for &(port, pad) in changes {
+                self.state[port] = pad;
+            }
+        }
+        self.state
+    }
+}
+
+// Movie files are a sequence of frame records. Each record contains, for
+// each port, a presence byte followed by the 4-byte pad state.
+const MOVIE_MAGIC: &[u8; 4] = b"R64M";
+const MOVIE_RECORD_SIZE: usize = MAX_PADS * 5;
+
+fn encode_ports(ports: &PadPorts) -> [u8; MOVIE_RECORD_SIZE] {
+    let mut rec = [0u8; MOVIE_RECORD_SIZE];
+    for (idx, port) in ports.iter().enumerate() {
+        if let Some(pad) = port {
+            rec[idx * 5] = 1;
+            rec[idx * 5 + 1..idx * 5 + 5].copy_from_slice(&pad.to_bytes());
+        }
+    }
+    rec
+}
+
+fn decode_ports(rec: &[u8]) -> PadPorts {
+    let mut ports = PadPorts::default();
+    for (idx, port) in ports.iter_mut().enumerate() {
+        if rec[idx * 5] != 0 {
+            *port = Some(PadState::from_bytes(&rec[idx * 5 + 1..idx * 5 + 5]));
+        }
+    }
+    ports
+}
+
+/// Play back a movie previously recorded with MovieRecorder. Once the movie
+/// is over, the last state is kept.
+pub struct MoviePlayer {
+    records: Vec<PadPorts>,
+}
+
+impl MoviePlayer {
+    pub fn new(path: &Path) -> io::Result<MoviePlayer> {
+        let mut data = Vec::new();
+        BufReader::new(File::open(path)?).read_to_end(&mut data)?;
+        if data.len() < 4 || &data[0..4] != MOVIE_MAGIC {
+            return Err(io::Error::new(ErrorKind::InvalidData, "not a movie file"));
+        }
+        Ok(MoviePlayer {
+            records: data[4..]
+                .chunks(MOVIE_RECORD_SIZE)
+                .filter(|rec| rec.len() == MOVIE_RECORD_SIZE)
+                .map(decode_ports)
+                .collect(),
+        })
+    }
+}
+
+impl InputSource for MoviePlayer {
+    fn poll(&mut self, frame: u64) -> PadPorts {
+        match self.records.len() {
+            0 => PadPorts::default(),
+            n => self.records[(frame as usize).min(n - 1)],
+        }
+    }
+}
+
+/// Record the input provided by another source into a movie file.
+pub struct MovieRecorder<S: InputSource> {
+    source: S,
+    out: BufWriter<File>,
+}
+
+impl<S: InputSource> MovieRecorder<S> {
+    pub fn new(source: S, path: &Path) -> io::Result<MovieRecorder<S>> {
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(MOVIE_MAGIC)?;
+        Ok(MovieRecorder { source, out })
+    }
+}
+
+impl<S: InputSource> InputSource for MovieRecorder<S> {
+    fn poll(&mut self, frame: u64) -> PadPorts {
+        let ports = self.source.poll(frame);
+        let _ = self.out.write_all(&encode_ports(&ports));
+        ports
+    }
+}
+
+/// Input injected through a TCP connection. Each message is a movie record
+/// (see MovieRecorder) that replaces the current state of all ports.
+pub struct TcpInput {
+    listener: TcpListener,
+    conn: Option<TcpStream>,
+    buf: Vec<u8>,
+    state: PadPorts,
+}
+
+impl TcpInput {
+    pub fn new(addr: &str) -> io::Result<TcpInput> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(TcpInput {
+            listener,
+            conn: None,
+            buf: Vec::new(),
+            state: PadPorts::default(),
+        })
+    }
+
+    fn receive(&mut self) -> io::Result<()> {
+        if self.conn.is_none() {
+            let (conn, _) = self.listener.accept()?;
+            conn.set_nonblocking(true)?;
+            self.conn = Some(conn);
+        }
+
+        let mut data = [0u8; 256];
+        loop {
+            match self.conn.as_mut().unwrap().read(&mut data)? {
+                0 => {
+                    self.conn = None;
+                    return Ok(());
+                }
+                n => self.buf.extend_from_slice(&data[..n]),
+            }
+        }
+    }
+}
+
+impl InputSource for TcpInput {
+    fn poll(&mut self, _frame: u64) -> PadPorts {
+        match self.receive() {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(_) => self.conn = None,
+            Ok(_) => {}
+        }
+
+        while self.buf.len() >= MOVIE_RECORD_SIZE {
+            self.state = decode_ports(&self.buf[..MOVIE_RECORD_SIZE]);
+            self.buf.drain(..MOVIE_RECORD_SIZE);
+        }
+        self.state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn movie_roundtrip() {
+        let pad1 = PadState {
+            buttons: PadButtons::A | PadButtons::START,
+            x: -20,
+            y: 100,
+        };
+        let pad2 = PadState {
+            buttons: PadButtons::Z,
+            x: 0,
+            y: -128,
+        };
+        let script = ScriptedInput::new()
+            .at(1, 0, Some(pad1))
+            .at(3, 0, Some(pad2))
+            .at(3, 2, Some(pad1));
+
+        let path = env::temp_dir().join("emu_movie_roundtrip_test.r64m");
+        let mut recorded = Vec::new();
+        {
+            let mut rec = MovieRecorder::new(script, &path).unwrap();
+            for frame in 0..5 {
+                recorded.push(rec.poll(frame));
+            }
+        }
+        assert_eq!(recorded[0], PadPorts::default());
+        assert_eq!(recorded[2][0], Some(pad1));
+        assert_eq!(recorded[4][0], Some(pad2));
+        assert_eq!(recorded[4][2], Some(pad1));
+
+        let mut player = MoviePlayer::new(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        for frame in 0..5 {
+            assert_eq!(player.poll(frame), recorded[frame as usize]);
+        }
+        assert_eq!(player.poll(100), recorded[4]);
+    }
+}
@@ -0,0 +1,452 @@
+extern crate byteorder;
+extern crate sdl2;
+
+mod audio;
+mod error;
+mod frameskip;
+mod hotkey;
+mod input;
+mod osd;
+mod passthrough;
+mod profile;
+mod shutdown;
+mod threads;
+mod video;
+
+#[cfg(feature = "cpal")]
+pub use self::audio::CpalAudio;
+pub use self::audio::{AudioBackend, NullAudio, SdlAudio, WavAudio};
+pub use self::error::{Error, Result};
+pub use self::frameskip::{FramePacer, FrameSkip, PresentMode, Speed, SyncMode};
+pub use self::hotkey::{Hotkey, HotkeyTable};
+pub use self::input::{
+    InputSource, LiveInput, MoviePlayer, MovieRecorder, PadButtons, PadPorts, PadState,
+    ScriptedInput, TcpInput, MAX_PADS,
+};
+pub use self::osd::{Corner, InputDisplay};
+pub use self::passthrough::{JoybusDevice, RaphnetAdapter};
+pub use self::profile::{PadInput, PadProfile, PadProfiles};
+pub use self::shutdown::{install_signal_handlers, request_shutdown, shutdown_requested};
+pub use self::threads::ThreadHints;
+pub use self::video::{
+    load_png_rgba, save_png, save_png_rgba, NullVideo, PngVideo, SdlVideo, VideoBackend,
+};
+
+use self::sdl2::controller::GameController;
+use self::sdl2::event::Event;
+use self::sdl2::keyboard::Keycode;
+use super::gfx::{GfxBufferLE, GfxBufferMutLE, OwnedGfxBufferLE, Rgb888};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub struct OutputConfig {
+    pub window_title: String,
+    pub width: isize,
+    pub height: isize,
+    pub fps: isize,
+    pub enforce_speed: bool,
+    // Initial emulation speed, when enforced
+    pub speed: Speed,
+    pub frame_skip: FrameSkip,
+    pub present: PresentMode,
+    pub sync: SyncMode,
+    pub hotkeys: HotkeyTable,
+    pub pad_profiles: PadProfiles,
+    pub threads: ThreadHints,
+}
+
+pub trait OutputProducer {
+    fn render_frame(&mut self, screen: &mut GfxBufferMutLE<Rgb888>);
+    fn finish(&mut self);
+
+    // Emulate a frame that will not be presented (frame skipping). Producers
+    // can override it to avoid scanning out the frame.
+    fn skip_frame(&mut self, screen: &mut GfxBufferMutLE<Rgb888>) {
+        self.render_frame(screen);
+    }
+
+    // Return true if the emulated machine swapped framebuffers while
+    // emulating the last frame. It drives presentation in PresentMode::OnSwap;
+    // producers that cannot tell can rely on the default implementation.
+    fn swapped(&mut self) -> bool {
+        true
+    }
+
+    // Collect the audio samples generated while rendering the last frame
+    // (signed 16-bit, interleaved stereo), and return their sample rate.
+    // Producers without audio can rely on the default implementation.
+    fn render_audio(&mut self, _samples: &mut Vec<i16>) -> u32 {
+        0
+    }
+
+    // Handle a hotkey that affects the emulated machine (eg: reset, savestates).
+    // Hotkeys that only affect the output (eg: pause) are handled by Output.
+    fn hotkey(&mut self, _hk: Hotkey) {}
+
+    // Load a file dropped on the window while the machine runs (eg: a
+    // controller pak image).
+    fn load_file(&mut self, _path: &Path) {}
+}
+
+// In SyncMode::Audio, level of the audio queue (in time) that the output
+// keeps: it is a trade-off between latency and resilience to hiccups.
+const AUDIO_SYNC_LEVEL_MS: u64 = 50;
+
+pub struct Output {
+    cfg: Rc<OutputConfig>,
+    context: Option<sdl2::Sdl>,
+    video: Option<Box<VideoBackend>>,
+    audio: Option<Box<AudioBackend>>,
+    input: LiveInput,
+    controllers: Vec<(String, GameController)>, // GUID and opened controller
+}
+
+impl Output {
+    pub fn new(cfg: OutputConfig) -> Result<Output> {
+        Ok(Output {
+            cfg: Rc::new(cfg),
+            context: None,
+            video: None,
+            audio: None,
+            input: LiveInput::new(),
+            controllers: Vec::new(),
+        })
+    }
+
+    pub fn config(&self) -> Rc<OutputConfig> {
+        self.cfg.clone()
+    }
+
+    // SDL is initialized lazily, so that headless backends don't require it.
+    fn sdl(&mut self) -> Result<sdl2::Sdl> {
+        if self.context.is_none() {
+            self.context = Some(sdl2::init().map_err(Error::Sdl)?);
+        }
+        Ok(self.context.clone().unwrap())
+    }
+
+    /// Enable video output to a SDL window.
+    pub fn enable_video(&mut self) -> Result<()> {
+        let context = self.sdl()?;
+        let video = SdlVideo::new(self.cfg.clone(), &context)?;
+        self.set_video(Box::new(video));
+        Ok(())
+    }
+
+    /// Select the video backend that will receive the emulated frames.
+    pub fn set_video(&mut self, video: Box<VideoBackend>) {
+        self.video = Some(video);
+    }
+
+    /// Enable audio output through SDL.
+    pub fn enable_audio(&mut self) -> Result<()> {
+        let context = self.sdl()?;
+        let audio = SdlAudio::new(&context)?;
+        self.set_audio(Box::new(audio));
+        Ok(())
+    }
+
+    /// Select the audio backend that will receive the emulated samples.
+    pub fn set_audio(&mut self, audio: Box<AudioBackend>) {
+        self.audio = Some(audio);
+    }
+
+    /// Return the input source fed with the state of the host devices
+    /// (updated while Output is running).
+    pub fn live_input(&self) -> LiveInput {
+        self.input.clone()
+    }
+
+    /// Run the producer returned by create in a worker thread, and present
+    /// its output until the output is closed. Errors creating the producer,
+    /// or initializing and feeding the backends, are returned.
+    pub fn run<F: 'static + Send + FnOnce() -> Result<Box<OutputProducer>>>(
+        &mut self,
+        create: F,
+    ) -> Result<()> {
+        let width = self.cfg.width as usize;
+        let height = self.cfg.height as usize;
+        let threads = self.cfg.threads;
+        let sync = self.cfg.sync;
+        let mut speed = if self.cfg.enforce_speed {
+            self.cfg.speed
+        } else {
+            Speed::Unlimited
+        };
+        let mut pacer = FramePacer::new(self.cfg.fps, self.cfg.frame_skip);
+        pacer.set_present_mode(self.cfg.present);
+        pacer.set_speed(speed);
+        let (tx, rx) = mpsc::sync_channel(3);
+        let (hktx, hkrx) = mpsc::channel();
+        let (speedtx, speedrx) = mpsc::channel();
+        let (filetx, filerx) = mpsc::channel::<PathBuf>();
+
+        let worker = thread::spawn(move || -> Result<()> {
+            if let Err(err) = threads.apply_emu() {
+                eprintln!("{}", err);
+            }
+            let mut producer = create()?;
+
+            // The producer is finished even if emulation panics, so that it
+            // can flush its state before the panic is propagated.
+            let res = panic::catch_unwind(AssertUnwindSafe(|| loop {
+                for hk in hkrx.try_iter() {
+                    producer.hotkey(hk);
+                }
+                for speed in speedrx.try_iter() {
+                    pacer.set_speed(speed);
+                }
+                for path in filerx.try_iter() {
+                    producer.load_file(&path);
+                }
+
+                // Skipped frames are emulated, but not sent for presentation,
+                // as well as frames without a buffer swap when presenting
+                // on swaps.
+                let mut screen = OwnedGfxBufferLE::<Rgb888>::new(width, height);
+                let skipped = pacer.next_frame(Instant::now());
+                if skipped {
+                    producer.skip_frame(&mut screen.buf_mut());
+                } else {
+                    producer.render_frame(&mut screen.buf_mut());
+                }
+                let swapped = producer.swapped();
+                let screen = if pacer.present(skipped, swapped) {
+                    Some(screen)
+                } else {
+                    None
+                };
+
+                let mut samples = Vec::new();
+                let freq = producer.render_audio(&mut samples);
+                let audio_sync = sync == SyncMode::Audio && freq != 0 && !samples.is_empty();
+
+                // The receiver is gone when the output is closed
+                if tx.send((screen, freq, samples)).is_err() {
+                    break;
+                }
+
+                // Frames with audio are paced by the output, in audio sync
+                // mode: it stops receiving them while its audio queue is
+                // full, which blocks the producer once the channel is full.
+                if !audio_sync {
+                    thread::sleep(pacer.delay(Instant::now()));
+                }
+            }));
+            producer.finish();
+            if let Err(err) = res {
+                panic::resume_unwind(err);
+            }
+            Ok(())
+        });
+
+        if let Err(err) = threads.apply_ui() {
+            eprintln!("{}", err);
+        }
+        self.show_speed(speed);
+
+        let mut paused = false;
+        let mut fastforward = false;
+        let mut nframes = 0u64;
+        let mut last: Option<OwnedGfxBufferLE<Rgb888>> = None;
+        let mut error = None;
+        let gcsub = self.context.as_ref().and_then(|c| c.game_controller().ok());
+        let joysub = self.context.as_ref().and_then(|c| c.joystick().ok());
+        'main: loop {
+            if shutdown_requested() {
+                break 'main;
+            }
+            if let Some(context) = self.context.clone() {
+                let mut pump = match context.event_pump() {
+                    Ok(pump) => pump,
+                    Err(err) => {
+                        error = Some(Error::Sdl(err));
+                        break 'main;
+                    }
+                };
+                for event in pump.poll_iter() {
+                    match event {
+                        Event::Quit { .. } => break 'main,
+                        Event::KeyDown {
+                            keycode: Some(key),
+                            repeat: false,
+                            ..
+                        } => match self.cfg.hotkeys.get(key) {
+                            Some(Hotkey::Quit) => break 'main,
+                            Some(Hotkey::Pause) => paused = !paused,
+                            Some(Hotkey::FastForward) => fastforward = true,
+                            Some(hk @ Hotkey::SpeedUp) | Some(hk @ Hotkey::SpeedDown) => {
+                                speed = if hk == Hotkey::SpeedUp {
+                                    speed.faster()
+                                } else {
+                                    speed.slower()
+                                };
+                                speedtx.send(speed).unwrap_or(());
+                                self.show_speed(speed);
+                            }
+                            Some(Hotkey::Fullscreen) => {
+                                self.video.as_mut().map(|v| v.toggle_fullscreen());
+                            }
+                            Some(Hotkey::Screenshot) => {
+                                if let Some(ref screen) = last {
+                                    self.screenshot(screen);
+                                }
+                            }
+                            // The producer might be gone, which is noticed
+                            // when receiving the next frame.
+                            Some(hk) => hktx.send(hk).unwrap_or(()),
+                            None => {}
+                        },
+                        Event::DropFile { filename, .. } => {
+                            filetx.send(PathBuf::from(filename)).unwrap_or(());
+                        }
+                        Event::ControllerDeviceAdded { which, .. } => {
+                            if let (Some(gcsub), Some(joysub)) = (gcsub.as_ref(), joysub.as_ref()) {
+                                self.open_controller(gcsub, joysub, which);
+                            }
+                        }
+                        Event::ControllerDeviceRemoved { which, .. } => {
+                            self.controllers.retain(|c| c.1.instance_id() != which);
+                        }
+                        Event::KeyUp {
+                            keycode: Some(key), ..
+                        } => {
+                            if self.cfg.hotkeys.get(key) == Some(Hotkey::FastForward) {
+                                fastforward = false;
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                let pads: Vec<PadState> = self
+                    .controllers
+                    .iter()
+                    .map(|&(ref guid, ref ctrl)| self.cfg.pad_profiles.get(guid).read(ctrl))
+                    .collect();
+                self.input.update(&pads, &pump.keyboard_state());
+            }
+
+            // While paused, the producer is blocked as soon as the channel
+            // is full.
+            if paused {
+                thread::sleep(Duration::from_millis(16));
+                continue;
+            }
+
+            // The sender is gone if the producer panicked or failed
+            let (screen, freq, samples) = match rx.recv() {
+                Ok(frame) => frame,
+                Err(_) => break 'main,
+            };
+            nframes += 1;
+            let mut res = Ok(());
+            // Frames skipped by the producer carry no screen. While
+            // fast-forwarding, only present one frame out of four.
+            if let Some(screen) = screen {
+                if !fastforward || nframes % 4 == 0 {
+                    res = self.render_frame(&screen.buf());
+                }
+                last = Some(screen);
+            }
+            // Audio is dropped while fast-forwarding. Otherwise, it is
+            // played faster or slower following the speed (and dropped at
+            // unlimited speed).
+            if !fastforward && res.is_ok() {
+                res = self.render_audio(speed.scale_freq(freq), &samples);
+                if sync == SyncMode::Audio {
+                    self.wait_audio();
+                }
+            }
+            if let Err(err) = res {
+                error = Some(err);
+                break 'main;
+            }
+        }
+
+        // Let the producer complete the current frame and finish, then
+        // close the backends, finalizing any capture file.
+        drop(rx);
+        let res = worker.join();
+        self.video = None;
+        self.audio = None;
+        match res {
+            Ok(Ok(())) => error.map_or(Ok(()), Err),
+            Ok(Err(err)) => Err(err),
+            Err(err) => panic::resume_unwind(err),
+        }
+    }
+
+    fn open_controller(
+        &mut self,
+        gcsub: &sdl2::GameControllerSubsystem,
+        joysub: &sdl2::JoystickSubsystem,
+        index: u32,
+    ) {
+        if self.controllers.len() >= MAX_PADS {
+            return;
+        }
+        if let (Ok(ctrl), Ok(guid)) = (gcsub.open(index), joysub.device_guid(index)) {
+            let guid = guid.string();
+            if self
+                .controllers
+                .iter()
+                .all(|c| c.1.instance_id() != ctrl.instance_id())
+            {
+                self.controllers.push((guid, ctrl));
+            }
+        }
+    }
+
+    // Wait until the audio queue drains to its target level (see
+    // SyncMode::Audio). Fast-forwarding does not queue audio, so it never
+    // waits.
+    fn wait_audio(&self) {
+        let target = Duration::from_millis(AUDIO_SYNC_LEVEL_MS);
+        match self.audio.as_ref().and_then(|a| a.queued()) {
+            Some(queued) if queued > target => thread::sleep(queued - target),
+            _ => {}
+        }
+    }
+
+    fn show_speed(&mut self, speed: Speed) {
+        let status = match speed {
+            Speed::Percent(100) => String::new(),
+            _ => format!("Speed: {}", speed),
+        };
+        self.video.as_mut().map(|v| v.set_status(&status));
+    }
+
+    fn screenshot(&self, screen: &OwnedGfxBufferLE<Rgb888>) {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let path = format!("screenshot-{}.png", secs);
+        if let Err(e) = save_png(
+            Path::new(&path),
+            &screen.buf(),
+            self.cfg.width as usize,
+            self.cfg.height as usize,
+        ) {
+            eprintln!("{}", e);
+        }
+    }
+
+    pub fn render_frame(&mut self, video: &GfxBufferLE<Rgb888>) -> Result<()> {
+        match self.video {
+            Some(ref mut v) => v.render_frame(video),
+            None => Ok(()),
+        }
+    }
+
+    pub fn render_audio(&mut self, freq: u32, samples: &[i16]) -> Result<()> {
+        match self.audio {
+            Some(ref mut a) if freq != 0 && !samples.is_empty() => a.queue_samples(freq, samples),
+            _ => Ok(()),
+        }
+    }
+}
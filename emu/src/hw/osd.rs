@@ -0,0 +1,225 @@
+use super::super::gfx::{BufferLineSetter, Color, GfxBufferMutLE, Rgb888};
+use super::input::{PadButtons, PadPorts, PadState};
+use super::{Error, Result};
+
+/// Corner of the screen in which an overlay is drawn.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Corner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+// Size of the panel of a controller, and distance between panels and from
+// the edges of the screen, in overlay pixels (before scaling).
+const PANEL_WIDTH: usize = 33;
+const PANEL_HEIGHT: usize = 15;
+const PANEL_SPACING: usize = 2;
+const MARGIN: usize = 4;
+
+// Analog stick box: position, size, and the range of the stick (the
+// diagonal of the real stick reaches about 85 in each axis).
+const STICK_X: usize = 1;
+const STICK_Y: usize = 1;
+const STICK_SIZE: usize = 13;
+const STICK_RANGE: i32 = 85;
+
+// Buttons of the panel, as (button, x, y, width, height, color).
+const BUTTONS: [(PadButtons, usize, usize, usize, usize, (u8, u8, u8)); 14] = [
+    (PadButtons::L, 15, 1, 4, 2, GRAY),
+    (PadButtons::Z, 20, 1, 3, 2, GRAY),
+    (PadButtons::START, 24, 1, 3, 2, (0xE0, 0x20, 0x20)),
+    (PadButtons::R, 28, 1, 4, 2, GRAY),
+    (PadButtons::DUP, 17, 5, 2, 2, GRAY),
+    (PadButtons::DLEFT, 15, 7, 2, 2, GRAY),
+    (PadButtons::DRIGHT, 19, 7, 2, 2, GRAY),
+    (PadButtons::DDOWN, 17, 9, 2, 2, GRAY),
+    (PadButtons::B, 22, 8, 3, 3, (0x20, 0xC0, 0x20)),
+    (PadButtons::A, 25, 11, 3, 3, (0x30, 0x50, 0xF0)),
+    (PadButtons::CUP, 28, 5, 2, 2, YELLOW),
+    (PadButtons::CLEFT, 26, 7, 2, 2, YELLOW),
+    (PadButtons::CRIGHT, 30, 7, 2, 2, YELLOW),
+    (PadButtons::CDOWN, 28, 9, 2, 2, YELLOW),
+];
+const GRAY: (u8, u8, u8) = (0xC0, 0xC0, 0xC0);
+const YELLOW: (u8, u8, u8) = (0xF0, 0xD0, 0x20);
+const BACKGROUND: (u8, u8, u8) = (0x18, 0x18, 0x18);
+
+/// InputDisplay draws the state of the controllers on the screen (one panel
+/// per connected controller, with its buttons and the position of its
+/// stick), for streaming and TAS work. Released buttons are drawn dimmed.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct InputDisplay {
+    pub corner: Corner,
+    // Size of an overlay pixel, in screen pixels
+    pub scale: usize,
+}
+
+impl Default for InputDisplay {
+    fn default() -> InputDisplay {
+        InputDisplay {
+            corner: Corner::BottomLeft,
+            scale: 1,
+        }
+    }
+}
+
+impl InputDisplay {
+    /// Parse the configuration of the display, as a comma-separated list
+    /// of options: the corner (top-left, top-right, bottom-left,
+    /// bottom-right) and scale=<n>. An empty string selects the defaults.
+    pub fn parse(s: &str) -> Result<InputDisplay> {
+        let mut disp = InputDisplay::default();
+        for opt in s.split(',').filter(|opt| !opt.is_empty()) {
+            match opt {
+                "top-left" => disp.corner = Corner::TopLeft,
+                "top-right" => disp.corner = Corner::TopRight,
+                "bottom-left" => disp.corner = Corner::BottomLeft,
+                "bottom-right" => disp.corner = Corner::BottomRight,
+                opt if opt.starts_with("scale=") => match opt["scale=".len()..].parse() {
+                    Ok(scale @ 1...4) => disp.scale = scale,
+                    _ => {
+                        return Err(Error::Parse(format!(
+                            "invalid input display scale: {}",
+                            opt
+                        )))
+                    }
+                },
+                _ => {
+                    return Err(Error::Parse(format!(
+                        "invalid input display option: {}",
+                        opt
+                    )))
+                }
+            }
+        }
+        Ok(disp)
+    }
+
+    /// Draw the panels of the connected controllers, in port order, from
+    /// the selected corner. Panels that do not fit are clipped.
+    pub fn draw(&self, screen: &mut GfxBufferMutLE<Rgb888>, pads: &PadPorts) {
+        let pads = pads.iter().filter_map(|pad| *pad);
+        for (idx, pad) in pads.enumerate() {
+            let x = MARGIN + idx * (PANEL_WIDTH + PANEL_SPACING);
+            let (x, y) = match self.corner {
+                Corner::TopLeft => (Some(x), Some(MARGIN)),
+                Corner::TopRight => (
+                    (screen.width() / self.scale).checked_sub(x + PANEL_WIDTH),
+                    Some(MARGIN),
+                ),
+                Corner::BottomLeft => (
+                    Some(x),
+                    (screen.height() / self.scale).checked_sub(MARGIN + PANEL_HEIGHT),
+                ),
+                Corner::BottomRight => (
+                    (screen.width() / self.scale).checked_sub(x + PANEL_WIDTH),
+                    (screen.height() / self.scale).checked_sub(MARGIN + PANEL_HEIGHT),
+                ),
+            };
+            if let (Some(x), Some(y)) = (x, y) {
+                self.draw_panel(screen, x, y, &pad);
+            }
+        }
+    }
+
+    fn draw_panel(&self, screen: &mut GfxBufferMutLE<Rgb888>, x: usize, y: usize, pad: &PadState) {
+        self.fill(screen, x, y, PANEL_WIDTH, PANEL_HEIGHT, BACKGROUND);
+        for &(button, bx, by, w, h, color) in BUTTONS.iter() {
+            let color = if pad.buttons.contains(button) {
+                color
+            } else {
+                dim(color)
+            };
+            self.fill(screen, x + bx, y + by, w, h, color);
+        }
+
+        // Stick box, with a dot at the position of the stick (Y grows up)
+        let (sx, sy) = (x + STICK_X, y + STICK_Y);
+        self.fill(screen, sx, sy, STICK_SIZE, STICK_SIZE, dim(GRAY));
+        self.fill(
+            screen,
+            sx + 1,
+            sy + 1,
+            STICK_SIZE - 2,
+            STICK_SIZE - 2,
+            BACKGROUND,
+        );
+        let half = STICK_SIZE as i32 / 2;
+        let axis = |v: i32| {
+            let pos = half + v * (half - 1) / STICK_RANGE;
+            pos.max(2).min(STICK_SIZE as i32 - 3) as usize
+        };
+        let (dx, dy) = (axis(pad.x as i32), axis(-(pad.y as i32)));
+        self.fill(screen, sx + dx - 1, sy + dy - 1, 3, 3, GRAY);
+    }
+
+    // Fill a rectangle of overlay pixels, clipped to the screen.
+    fn fill(
+        &self,
+        screen: &mut GfxBufferMutLE<Rgb888>,
+        x: usize,
+        y: usize,
+        w: usize,
+        h: usize,
+        (r, g, b): (u8, u8, u8),
+    ) {
+        let color = Color::<Rgb888>::new_clamped(r, g, b, 0xFF);
+        let x2 = ((x + w) * self.scale).min(screen.width());
+        let y2 = ((y + h) * self.scale).min(screen.height());
+        for sy in y * self.scale..y2 {
+            let mut line = screen.line(sy);
+            for sx in x * self.scale..x2 {
+                line.set(sx, color);
+            }
+        }
+    }
+}
+
+fn dim((r, g, b): (u8, u8, u8)) -> (u8, u8, u8) {
+    (r / 3, g / 3, b / 3)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::gfx::{BufferLineGetter, OwnedGfxBufferLE};
+    use super::*;
+
+    #[test]
+    fn input_display() {
+        assert_eq!(InputDisplay::parse("").unwrap(), InputDisplay::default());
+        let disp = InputDisplay::parse("top-right,scale=2").unwrap();
+        assert_eq!(disp.corner, Corner::TopRight);
+        assert_eq!(disp.scale, 2);
+        assert!(InputDisplay::parse("scale=0").is_err());
+        assert!(InputDisplay::parse("middle").is_err());
+
+        let mut screen = OwnedGfxBufferLE::<Rgb888>::new(80, 40);
+        let pad = PadState {
+            buttons: PadButtons::A,
+            x: 85,
+            y: 0,
+        };
+        let disp = InputDisplay {
+            corner: Corner::TopLeft,
+            scale: 1,
+        };
+        disp.draw(&mut screen.buf_mut(), &[None, Some(pad), None, None]);
+
+        let pixel = |screen: &OwnedGfxBufferLE<Rgb888>, x: usize, y: usize| {
+            let (r, g, b, _) = screen.buf().line(y).get(x).components();
+            (r as u8, g as u8, b as u8)
+        };
+        // The controller on port 2 is the first panel: A is pressed, B is not
+        assert_eq!(pixel(&screen, MARGIN + 26, MARGIN + 12), (0x30, 0x50, 0xF0));
+        assert_eq!(
+            pixel(&screen, MARGIN + 23, MARGIN + 9),
+            dim((0x20, 0xC0, 0x20))
+        );
+        // The stick is pushed right
+        assert_eq!(pixel(&screen, MARGIN + 1 + 11, MARGIN + 1 + 6), GRAY);
+        assert_eq!(pixel(&screen, MARGIN + 1 + 6, MARGIN + 1 + 6), BACKGROUND);
+        assert_eq!(pixel(&screen, 0, 0), (0, 0, 0));
+    }
+}
@@ -0,0 +1,124 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::Path;
+
+/// A JoybusDevice executes raw joybus commands on real hardware, so that
+/// real controllers (and their paks) can be used in place of the emulated
+/// ones.
+pub trait JoybusDevice: Send {
+    // Number of controller ports exposed by the device.
+    fn channels(&self) -> usize;
+
+    // Execute a joybus command on the specified channel, writing the reply
+    // into resp. Returns false if no controller answered.
+    fn command(&mut self, chan: usize, req: &[u8], resp: &mut [u8]) -> io::Result<bool>;
+}
+
+// Raphnet adapters accept raw SI commands through their vendor HID
+// interface: [0x80, channel, txlen, tx...], and reply with
+// [0x80, channel, rxlen, rx...] (rxlen is 0 if no controller answered).
+const RNT_RAW_SI_COMMAND: u8 = 0x80;
+const RNT_REPORT_SIZE: usize = 64;
+
+/// A raphnet-tech N64-to-USB adapter (or any adapter implementing the same
+/// raw protocol), accessed through its HID device node (eg: /dev/hidrawN).
+pub struct RaphnetAdapter<T: Read + Write + Send> {
+    dev: T,
+    channels: usize,
+}
+
+impl RaphnetAdapter<File> {
+    pub fn open(path: &Path, channels: usize) -> io::Result<RaphnetAdapter<File>> {
+        let dev = OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(RaphnetAdapter::new(dev, channels))
+    }
+}
+
+impl<T: Read + Write + Send> RaphnetAdapter<T> {
+    pub fn new(dev: T, channels: usize) -> RaphnetAdapter<T> {
+        RaphnetAdapter { dev, channels }
+    }
+}
+
+impl<T: Read + Write + Send> JoybusDevice for RaphnetAdapter<T> {
+    fn channels(&self) -> usize {
+        self.channels
+    }
+
+    fn command(&mut self, chan: usize, req: &[u8], resp: &mut [u8]) -> io::Result<bool> {
+        if req.len() > RNT_REPORT_SIZE - 3 {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "joybus command too long"));
+        }
+        let mut rep = [0u8; RNT_REPORT_SIZE];
+        rep[0] = RNT_RAW_SI_COMMAND;
+        rep[1] = chan as u8;
+        rep[2] = req.len() as u8;
+        rep[3..3 + req.len()].copy_from_slice(req);
+        self.dev.write_all(&rep)?;
+
+        let n = self.dev.read(&mut rep)?;
+        if n < 3 || rep[0] != RNT_RAW_SI_COMMAND || rep[1] != chan as u8 {
+            return Err(io::Error::new(ErrorKind::InvalidData, "invalid adapter reply"));
+        }
+        let rx = (rep[2] as usize).min(n - 3).min(resp.len());
+        if rx == 0 {
+            return Ok(false);
+        }
+        resp[..rx].copy_from_slice(&rep[3..3 + rx]);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // Fake adapter: records the request and returns a canned reply.
+    struct FakeDev {
+        written: Vec<u8>,
+        reply: Cursor<Vec<u8>>,
+    }
+
+    impl Read for FakeDev {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.reply.read(buf)
+        }
+    }
+
+    impl Write for FakeDev {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn adapter(reply: &[u8]) -> RaphnetAdapter<FakeDev> {
+        RaphnetAdapter::new(
+            FakeDev {
+                written: Vec::new(),
+                reply: Cursor::new(reply.to_vec()),
+            },
+            2,
+        )
+    }
+
+    #[test]
+    fn raphnet_raw_command() {
+        let mut ad = adapter(&[0x80, 1, 3, 0x05, 0x00, 0x01]);
+        let mut resp = [0u8; 3];
+        assert!(ad.command(1, &[0x00], &mut resp).unwrap());
+        assert_eq!(resp, [0x05, 0x00, 0x01]);
+        assert_eq!(&ad.dev.written[..4], &[0x80, 1, 1, 0x00]);
+        assert_eq!(ad.dev.written.len(), RNT_REPORT_SIZE);
+
+        let mut ad = adapter(&[0x80, 0, 0]);
+        assert!(!ad.command(0, &[0x01], &mut resp).unwrap());
+
+        let mut ad = adapter(&[0x81, 0, 0]);
+        assert!(ad.command(0, &[0x01], &mut resp).is_err());
+    }
+}
@@ -0,0 +1,335 @@
+extern crate sdl2;
+
+use self::sdl2::controller::{Axis, Button, GameController};
+use super::input::{PadButtons, PadState};
+use super::{Error, Result};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+// Names used for buttons and axes, matching those of SDL mapping strings.
+static BUTTON_NAMES: [(Button, &'static str); 15] = [
+    (Button::A, "a"),
+    (Button::B, "b"),
+    (Button::X, "x"),
+    (Button::Y, "y"),
+    (Button::Back, "back"),
+    (Button::Guide, "guide"),
+    (Button::Start, "start"),
+    (Button::LeftStick, "leftstick"),
+    (Button::RightStick, "rightstick"),
+    (Button::LeftShoulder, "leftshoulder"),
+    (Button::RightShoulder, "rightshoulder"),
+    (Button::DPadUp, "dpup"),
+    (Button::DPadDown, "dpdown"),
+    (Button::DPadLeft, "dpleft"),
+    (Button::DPadRight, "dpright"),
+];
+
+static AXIS_NAMES: [(Axis, &'static str); 6] = [
+    (Axis::LeftX, "leftx"),
+    (Axis::LeftY, "lefty"),
+    (Axis::RightX, "rightx"),
+    (Axis::RightY, "righty"),
+    (Axis::TriggerLeft, "lefttrigger"),
+    (Axis::TriggerRight, "righttrigger"),
+];
+
+static PAD_NAMES: [(PadButtons, &'static str); 14] = [
+    (PadButtons::A, "a"),
+    (PadButtons::B, "b"),
+    (PadButtons::Z, "z"),
+    (PadButtons::START, "start"),
+    (PadButtons::DUP, "dup"),
+    (PadButtons::DDOWN, "ddown"),
+    (PadButtons::DLEFT, "dleft"),
+    (PadButtons::DRIGHT, "dright"),
+    (PadButtons::L, "l"),
+    (PadButtons::R, "r"),
+    (PadButtons::CUP, "cup"),
+    (PadButtons::CDOWN, "cdown"),
+    (PadButtons::CLEFT, "cleft"),
+    (PadButtons::CRIGHT, "cright"),
+];
+
+// Default profiles, in the same format used by profile files. GUIDs are
+// matched on vendor and product ID only, so that the same profile applies
+// regardless of the bus and driver version.
+static DEFAULT_PROFILES: [&'static str; 6] = [
+    // Microsoft Xbox 360
+    "030000005e0400008e02000000000000,Xbox 360 Controller,a:a,b:x,z:lefttrigger,start:start,\
+     l:leftshoulder,r:rightshoulder,dup:dpup,ddown:dpdown,dleft:dpleft,dright:dpright,\
+     cup:-righty,cdown:+righty,cleft:-rightx,cright:+rightx,x:leftx,y:lefty",
+    // Microsoft Xbox One
+    "030000005e040000ea02000000000000,Xbox One Controller,a:a,b:x,z:lefttrigger,start:start,\
+     l:leftshoulder,r:rightshoulder,dup:dpup,ddown:dpdown,dleft:dpleft,dright:dpright,\
+     cup:-righty,cdown:+righty,cleft:-rightx,cright:+rightx,x:leftx,y:lefty",
+    // Sony DualShock 4
+    "030000004c050000c405000000000000,DualShock 4,a:a,b:x,z:lefttrigger,start:start,\
+     l:leftshoulder,r:rightshoulder,dup:dpup,ddown:dpdown,dleft:dpleft,dright:dpright,\
+     cup:-righty,cdown:+righty,cleft:-rightx,cright:+rightx,x:leftx,y:lefty",
+    // Nintendo Switch Pro Controller: SDL names buttons by position, so the
+    // Nintendo "A" button is reported as "b".
+    "030000007e0500000920000000000000,Switch Pro Controller,a:b,b:a,z:lefttrigger,start:start,\
+     l:leftshoulder,r:rightshoulder,dup:dpup,ddown:dpdown,dleft:dpleft,dright:dpright,\
+     cup:-righty,cdown:+righty,cleft:-rightx,cright:+rightx,x:leftx,y:lefty",
+    // raphnet-tech N64 to USB adapter
+    "030000009b2800006000000000000000,raphnet N64 adapter,a:a,b:b,z:lefttrigger,start:start,\
+     l:leftshoulder,r:rightshoulder,dup:dpup,ddown:dpdown,dleft:dpleft,dright:dpright,\
+     cup:-righty,cdown:+righty,cleft:-rightx,cright:+rightx,x:leftx,y:lefty",
+    // Mayflash N64 controller adapter
+    "03000000d620000010a7000000000000,Mayflash N64 adapter,a:a,b:b,z:lefttrigger,start:start,\
+     l:leftshoulder,r:rightshoulder,dup:dpup,ddown:dpdown,dleft:dpleft,dright:dpright,\
+     cup:-righty,cdown:+righty,cleft:-rightx,cright:+rightx,x:leftx,y:lefty",
+];
+
+fn axis_name(axis: Axis) -> &'static str {
+    AXIS_NAMES.iter().find(|e| e.0 == axis).unwrap().1
+}
+
+// Threshold above which an axis bound to a digital button is considered pressed.
+const AXIS_THRESHOLD: i16 = 16384;
+
+// Maximum deflection of the N64 analog stick.
+const STICK_RANGE: i32 = 80;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PadInput {
+    Button(Button),
+    Axis(Axis, bool), // axis, positive direction
+}
+
+impl PadInput {
+    fn parse(s: &str) -> Option<PadInput> {
+        let (dir, name) = match s.chars().next() {
+            Some('+') => (Some(true), &s[1..]),
+            Some('-') => (Some(false), &s[1..]),
+            _ => (None, s),
+        };
+        if let Some(&(axis, _)) = AXIS_NAMES.iter().find(|&&(_, n)| n == name) {
+            return Some(PadInput::Axis(axis, dir.unwrap_or(true)));
+        }
+        match dir {
+            None => BUTTON_NAMES
+                .iter()
+                .find(|&&(_, n)| n == name)
+                .map(|&(b, _)| PadInput::Button(b)),
+            Some(_) => None,
+        }
+    }
+
+    fn name(&self) -> String {
+        match *self {
+            PadInput::Button(b) => BUTTON_NAMES.iter().find(|e| e.0 == b).unwrap().1.into(),
+            PadInput::Axis(a, pos) => format!("{}{}", if pos { "+" } else { "-" }, axis_name(a)),
+        }
+    }
+
+    fn pressed(&self, ctrl: &GameController) -> bool {
+        match *self {
+            PadInput::Button(b) => ctrl.button(b),
+            PadInput::Axis(a, true) => ctrl.axis(a) > AXIS_THRESHOLD,
+            PadInput::Axis(a, false) => ctrl.axis(a) < -AXIS_THRESHOLD,
+        }
+    }
+}
+
+/// PadProfile describes how the inputs of a host game controller are
+/// mapped to the buttons and stick of a N64 controller.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PadProfile {
+    pub name: String,
+    pub buttons: Vec<(PadButtons, PadInput)>,
+    pub stick: (Axis, Axis),
+}
+
+impl PadProfile {
+    /// Parse a profile line: "guid,name,n64button:input,...,x:axis,y:axis".
+    /// Returns the GUID and the profile.
+    pub fn parse(line: &str) -> Result<(String, PadProfile)> {
+        let mut fields = line.split(',').map(|f| f.trim());
+        let guid = fields.next().unwrap_or("");
+        if guid.len() != 32 || !guid.chars().all(|c| c.is_digit(16)) {
+            return Err(Error::Parse(format!("invalid GUID: {:?}", guid)));
+        }
+        let mut prof = PadProfile {
+            name: fields.next().unwrap_or("").into(),
+            buttons: Vec::new(),
+            stick: (Axis::LeftX, Axis::LeftY),
+        };
+
+        for f in fields.filter(|f| !f.is_empty()) {
+            let mut kv = f.splitn(2, ':');
+            let (key, val) = (kv.next().unwrap(), kv.next().unwrap_or(""));
+            let input = PadInput::parse(val)
+                .ok_or_else(|| Error::Parse(format!("invalid input: {:?}", val)))?;
+            match (key, input) {
+                ("x", PadInput::Axis(axis, _)) => prof.stick.0 = axis,
+                ("y", PadInput::Axis(axis, _)) => prof.stick.1 = axis,
+                _ => {
+                    let button = PAD_NAMES
+                        .iter()
+                        .find(|&&(_, n)| n == key)
+                        .ok_or_else(|| Error::Parse(format!("invalid binding: {:?}", f)))?
+                        .0;
+                    prof.buttons.push((button, input));
+                }
+            }
+        }
+        Ok((guid.to_lowercase(), prof))
+    }
+
+    pub fn to_line(&self, guid: &str) -> String {
+        let mut line = format!("{},{}", guid, self.name);
+        for &(button, input) in self.buttons.iter() {
+            let name = PAD_NAMES.iter().find(|e| e.0 == button).unwrap().1;
+            line += &format!(",{}:{}", name, input.name());
+        }
+        line += &format!(
+            ",x:{},y:{}",
+            axis_name(self.stick.0),
+            axis_name(self.stick.1)
+        );
+        line
+    }
+
+    /// Read the current state of a game controller.
+    pub fn read(&self, ctrl: &GameController) -> PadState {
+        let mut pad = PadState::default();
+        for &(button, input) in self.buttons.iter() {
+            if input.pressed(ctrl) {
+                pad.buttons |= button;
+            }
+        }
+        let scale = |v: i16| (v as i32 * STICK_RANGE / 32767).max(-STICK_RANGE) as i8;
+        pad.x = scale(ctrl.axis(self.stick.0));
+        pad.y = -scale(ctrl.axis(self.stick.1)); // SDL Y axis points down
+        pad
+    }
+}
+
+// Extract vendor and product ID from a SDL joystick GUID.
+fn vendor_product(guid: &str) -> Option<(&str, &str)> {
+    if guid.len() == 32 {
+        Some((&guid[8..12], &guid[16..20]))
+    } else {
+        None
+    }
+}
+
+/// PadProfiles stores the profiles of all known game controllers, keyed by
+/// their SDL GUID, so that each controller keeps its own bindings.
+#[derive(Clone, Debug)]
+pub struct PadProfiles {
+    profiles: HashMap<String, PadProfile>,
+    fallback: PadProfile,
+}
+
+impl Default for PadProfiles {
+    fn default() -> PadProfiles {
+        let mut profiles = HashMap::new();
+        for line in DEFAULT_PROFILES.iter() {
+            let (guid, prof) = PadProfile::parse(line).unwrap();
+            profiles.insert(guid, prof);
+        }
+        let mut fallback = profiles["030000005e0400008e02000000000000"].clone();
+        fallback.name = "Generic Controller".into();
+        PadProfiles { profiles, fallback }
+    }
+}
+
+impl PadProfiles {
+    /// Return the profile of the controller with the specified GUID. If the
+    /// exact GUID is not known, a profile for the same vendor/product is
+    /// used, falling back to a generic mapping.
+    pub fn get(&self, guid: &str) -> &PadProfile {
+        let guid = guid.to_lowercase();
+        if let Some(prof) = self.profiles.get(&guid) {
+            return prof;
+        }
+        let vp = vendor_product(&guid);
+        self.profiles
+            .iter()
+            .find(|&(g, _)| vp.is_some() && vendor_product(g) == vp)
+            .map(|(_, p)| p)
+            .unwrap_or(&self.fallback)
+    }
+
+    pub fn set(&mut self, guid: &str, prof: PadProfile) {
+        self.profiles.insert(guid.to_lowercase(), prof);
+    }
+
+    /// Parse a list of profiles (one per line; empty lines and lines starting
+    /// with '#' are ignored), adding them to the known profiles.
+    pub fn parse(&mut self, text: &str) -> Result<()> {
+        for line in text.lines().map(|l| l.trim()) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (guid, prof) = PadProfile::parse(line)?;
+            self.profiles.insert(guid, prof);
+        }
+        Ok(())
+    }
+
+    pub fn load(&mut self, path: &Path) -> Result<()> {
+        let mut text = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut text))
+            .map_err(|e| Error::File(path.into(), e))?;
+        self.parse(&text)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut guids: Vec<&String> = self.profiles.keys().collect();
+        guids.sort();
+        let mut f = File::create(path)?;
+        for guid in guids {
+            writeln!(f, "{}", self.profiles[guid].to_line(guid))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profile_roundtrip() {
+        for line in DEFAULT_PROFILES.iter() {
+            let (guid, prof) = PadProfile::parse(line).unwrap();
+            let line2 = prof.to_line(&guid);
+            assert_eq!(PadProfile::parse(&line2).unwrap(), (guid, prof));
+        }
+        assert!(PadProfile::parse("1234,foo,a:a").is_err());
+        assert!(PadProfile::parse("030000005e0400008e02000000000000,foo,a:nope").is_err());
+        assert!(PadProfile::parse("030000005e0400008e02000000000000,foo,q:a").is_err());
+    }
+
+    #[test]
+    fn profile_lookup() {
+        let mut profs = PadProfiles::default();
+        // Same vendor/product, different bus and version
+        assert_eq!(
+            profs.get("050000004c050000c405000011810000").name,
+            "DualShock 4"
+        );
+        assert_eq!(
+            profs.get("03000000ffff0000ffff000000000000").name,
+            "Generic Controller"
+        );
+
+        profs
+            .parse("# custom\n03000000ffff0000ffff000000000000,My Pad,a:y,x:rightx,y:righty\n")
+            .unwrap();
+        let prof = profs.get("03000000FFFF0000FFFF000000000000");
+        assert_eq!(prof.name, "My Pad");
+        assert_eq!(
+            prof.buttons,
+            vec![(PadButtons::A, PadInput::Button(Button::Y))]
+        );
+        assert_eq!(prof.stick, (Axis::RightX, Axis::RightY));
+    }
+}
@@ -0,0 +1,41 @@
+extern crate libc;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+/// Ask the running Output to shut down, as if its window was closed: the
+/// producer completes the current frame and finishes, then the backends are
+/// closed. It is safe to call from a signal handler.
+pub fn request_shutdown() {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN.load(Ordering::SeqCst)
+}
+
+#[cfg(unix)]
+extern "C" fn on_signal(sig: libc::c_int) {
+    // A second signal kills the process, in case the shutdown hangs.
+    if SHUTDOWN.swap(true, Ordering::SeqCst) {
+        unsafe {
+            libc::signal(sig, libc::SIG_DFL);
+            libc::raise(sig);
+        }
+    }
+}
+
+/// Turn SIGINT and SIGTERM into a shutdown request. SDL does the same when
+/// it is initialized, but headless backends (eg: WAV capture) would otherwise
+/// be killed without finalizing their output.
+#[cfg(unix)]
+pub fn install_signal_handlers() {
+    unsafe {
+        libc::signal(libc::SIGINT, on_signal as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, on_signal as libc::sighandler_t);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn install_signal_handlers() {}
@@ -0,0 +1,129 @@
+extern crate libc;
+
+use super::{Error, Result};
+
+/// Scheduling hints for the threads run by Output: the emulation thread
+/// (which runs the whole machine, RSP and RDP included) and the UI thread
+/// (events, input and presentation). Audio is played by SDL from a thread
+/// it owns, which cannot be configured.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct ThreadHints {
+    /// Raise the priority of the emulation thread, to reduce frame-time
+    /// jitter on busy hosts. It usually requires privileges.
+    pub high_priority: bool,
+    /// Pin the emulation thread to a core.
+    pub emu_core: Option<usize>,
+    /// Pin the UI thread to a core.
+    pub ui_core: Option<usize>,
+}
+
+impl ThreadHints {
+    /// Parse a list of hints separated by ',': "high-priority", "emu-core=<n>"
+    /// and "ui-core=<n>".
+    pub fn parse(s: &str) -> Result<ThreadHints> {
+        let core = |s: &str| {
+            s.parse::<usize>()
+                .map_err(|_| Error::Parse(format!("invalid core number: {}", s)))
+        };
+        let mut hints = ThreadHints::default();
+        for hint in s.split(',').filter(|h| !h.is_empty()) {
+            match hint {
+                "high-priority" => hints.high_priority = true,
+                h if h.starts_with("emu-core=") => {
+                    hints.emu_core = Some(core(&h["emu-core=".len()..])?)
+                }
+                h if h.starts_with("ui-core=") => {
+                    hints.ui_core = Some(core(&h["ui-core=".len()..])?)
+                }
+                _ => return Err(Error::Parse(format!("invalid thread hint: {}", hint))),
+            }
+        }
+        Ok(hints)
+    }
+
+    /// Apply the hints to the calling thread, which runs the emulation.
+    pub fn apply_emu(&self) -> Result<()> {
+        if self.high_priority {
+            raise_priority()?;
+        }
+        match self.emu_core {
+            Some(core) => set_affinity(core),
+            None => Ok(()),
+        }
+    }
+
+    /// Apply the hints to the calling thread, which runs the UI.
+    pub fn apply_ui(&self) -> Result<()> {
+        match self.ui_core {
+            Some(core) => set_affinity(core),
+            None => Ok(()),
+        }
+    }
+}
+
+// Nice value of the emulation thread in high priority mode
+#[cfg(target_os = "linux")]
+const HIGH_PRIORITY_NICE: libc::c_int = -10;
+
+// On Linux, the nice value and the affinity are per-thread attributes: a
+// zero pid refers to the calling thread.
+#[cfg(target_os = "linux")]
+fn raise_priority() -> Result<()> {
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, HIGH_PRIORITY_NICE) } != 0 {
+        return Err(Error::Thread(format!(
+            "cannot raise thread priority: {}",
+            ::std::io::Error::last_os_error()
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn set_affinity(core: usize) -> Result<()> {
+    unsafe {
+        let mut set: libc::cpu_set_t = ::std::mem::zeroed();
+        libc::CPU_SET(core, &mut set);
+        if libc::sched_setaffinity(0, ::std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(Error::Thread(format!(
+                "cannot pin thread to core {}: {}",
+                core,
+                ::std::io::Error::last_os_error()
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn raise_priority() -> Result<()> {
+    Err(Error::Thread(
+        "thread priority is not supported on this platform".into(),
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_affinity(_core: usize) -> Result<()> {
+    Err(Error::Thread(
+        "thread affinity is not supported on this platform".into(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_thread_hints() {
+        assert_eq!(ThreadHints::parse("").unwrap(), ThreadHints::default());
+        assert_eq!(
+            ThreadHints::parse("high-priority,emu-core=2,ui-core=0").unwrap(),
+            ThreadHints {
+                high_priority: true,
+                emu_core: Some(2),
+                ui_core: Some(0),
+            }
+        );
+        assert!(ThreadHints::parse("emu-core=x").is_err());
+        assert!(ThreadHints::parse("realtime").is_err());
+    }
+}
@@ -0,0 +1,254 @@
+extern crate png;
+extern crate sdl2;
+
+use self::png::HasParameters;
+use self::sdl2::pixels::PixelFormatEnum;
+use self::sdl2::render::{TextureCreator, WindowCanvas};
+use self::sdl2::video::{FullscreenType, WindowContext};
+use super::super::gfx::{
+    BufferLineGetter, BufferLineSetter, Color, GfxBufferLE, OwnedGfxBufferLE, Rgb888, Rgba8888,
+};
+use super::{Error, OutputConfig, Result};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, SystemTime};
+
+/// A VideoBackend is the final destination of the frames produced by the
+/// emulator. Output drives the backend, so that the same orchestration code
+/// can be shared by the frontend, the tests and the headless runners.
+pub trait VideoBackend {
+    fn render_frame(&mut self, frame: &GfxBufferLE<Rgb888>) -> Result<()>;
+
+    // Switch between windowed and fullscreen mode, if supported.
+    fn toggle_fullscreen(&mut self) {}
+
+    // Display a short status message (eg: the emulation speed) along with
+    // the frames, if supported. An empty message clears it.
+    fn set_status(&mut self, _status: &str) {}
+}
+
+/// Display frames in a SDL window.
+pub struct SdlVideo {
+    canvas: WindowCanvas,
+    creator: TextureCreator<WindowContext>,
+
+    cfg: Rc<OutputConfig>,
+    fps_clock: SystemTime,
+    fps_counter: isize,
+    fps: isize,
+    status: String,
+}
+
+impl SdlVideo {
+    pub fn new(cfg: Rc<OutputConfig>, context: &sdl2::Sdl) -> Result<SdlVideo> {
+        let sub = context
+            .video()
+            .map_err(|e| Error::Sdl(format!("error creating video subsystem: {:?}", e)))?;
+        let window = sub
+            .window(&cfg.window_title, 800, 600)
+            .resizable()
+            .position_centered()
+            .opengl()
+            .build()
+            .map_err(|e| Error::Sdl(format!("error creating window: {:?}", e)))?;
+        let mut canvas = window
+            .into_canvas()
+            .software()
+            .build()
+            .map_err(|e| Error::Sdl(format!("error creating canvas: {:?}", e)))?;
+        let creator = canvas.texture_creator();
+
+        canvas.set_logical_size(cfg.width as u32, cfg.height as u32);
+
+        Ok(SdlVideo {
+            cfg,
+            canvas,
+            creator,
+            fps_clock: SystemTime::now(),
+            fps_counter: 0,
+            fps: 0,
+            status: String::new(),
+        })
+    }
+
+    fn draw(&mut self, frame: &GfxBufferLE<Rgb888>) -> Result<()> {
+        let mut tex = self
+            .creator
+            .create_texture_target(
+                PixelFormatEnum::ABGR8888,
+                self.cfg.width as u32,
+                self.cfg.height as u32,
+            )
+            .map_err(|e| Error::Sdl(format!("error creating texture: {:?}", e)))?;
+        let (mem, pitch) = frame.raw();
+        tex.update(None, mem, pitch)
+            .map_err(|e| Error::Sdl(format!("error updating texture: {:?}", e)))?;
+        self.canvas.copy(&tex, None, None).map_err(Error::Sdl)?;
+        self.canvas.present();
+        Ok(())
+    }
+
+    fn update_fps(&mut self) {
+        self.fps_counter += 1;
+        let one_second = Duration::new(1, 0);
+        match self.fps_clock.elapsed() {
+            Ok(elapsed) if elapsed >= one_second => {
+                self.fps = self.fps_counter;
+                self.fps_counter = 0;
+                self.fps_clock += one_second;
+                self.update_title();
+            }
+            _ => {}
+        }
+    }
+
+    // The window title shows the FPS, and the status message if any.
+    fn update_title(&mut self) {
+        let mut title = format!("{} - {} FPS", &self.cfg.window_title, self.fps);
+        if !self.status.is_empty() {
+            title = format!("{} - {}", title, self.status);
+        }
+        let _ = self.canvas.window_mut().set_title(&title);
+    }
+}
+
+impl VideoBackend for SdlVideo {
+    fn render_frame(&mut self, frame: &GfxBufferLE<Rgb888>) -> Result<()> {
+        self.draw(frame)?;
+        self.update_fps();
+        Ok(())
+    }
+
+    fn toggle_fullscreen(&mut self) {
+        let window = self.canvas.window_mut();
+        let mode = match window.fullscreen_state() {
+            FullscreenType::Off => FullscreenType::Desktop,
+            _ => FullscreenType::Off,
+        };
+        let _ = window.set_fullscreen(mode);
+    }
+
+    fn set_status(&mut self, status: &str) {
+        self.status = status.to_string();
+        self.update_title();
+    }
+}
+
+/// Discard all frames (headless runs).
+pub struct NullVideo;
+
+impl VideoBackend for NullVideo {
+    fn render_frame(&mut self, _frame: &GfxBufferLE<Rgb888>) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Save each frame as a numbered PNG file within a directory.
+pub struct PngVideo {
+    cfg: Rc<OutputConfig>,
+    dir: PathBuf,
+    frame: usize,
+}
+
+impl PngVideo {
+    pub fn new(cfg: Rc<OutputConfig>, dir: PathBuf) -> PngVideo {
+        PngVideo { cfg, dir, frame: 0 }
+    }
+}
+
+impl VideoBackend for PngVideo {
+    fn render_frame(&mut self, frame: &GfxBufferLE<Rgb888>) -> Result<()> {
+        let path = self.dir.join(format!("frame{:06}.png", self.frame));
+        save_png(
+            &path,
+            frame,
+            self.cfg.width as usize,
+            self.cfg.height as usize,
+        )?;
+        self.frame += 1;
+        Ok(())
+    }
+}
+
+/// Save a frame as a PNG file.
+pub fn save_png(
+    path: &Path,
+    frame: &GfxBufferLE<Rgb888>,
+    width: usize,
+    height: usize,
+) -> Result<()> {
+    let mut data = Vec::with_capacity(width * height * 3);
+    for y in 0..height {
+        let line = frame.line(y);
+        for x in 0..width {
+            let (r, g, b, _) = line.get(x).components();
+            data.extend_from_slice(&[r as u8, g as u8, b as u8]);
+        }
+    }
+
+    let file = File::create(path).map_err(|e| Error::File(path.into(), e))?;
+    let mut enc = png::Encoder::new(BufWriter::new(file), width as u32, height as u32);
+    enc.set(png::ColorType::RGB).set(png::BitDepth::Eight);
+    enc.write_header()
+        .and_then(|mut w| w.write_image_data(&data))
+        .map_err(|e| Error::Png(path.into(), e.to_string()))
+}
+
+/// Save an image with alpha channel as a PNG file.
+pub fn save_png_rgba(path: &Path, image: &GfxBufferLE<Rgba8888>) -> Result<()> {
+    let mut data = Vec::new();
+    let mut width = 0;
+    let mut height = 0;
+    for line in image.iter_lines() {
+        width = line.width();
+        height += 1;
+        for x in 0..width {
+            let (r, g, b, a) = line.get(x).components();
+            data.extend_from_slice(&[r as u8, g as u8, b as u8, a as u8]);
+        }
+    }
+
+    let file = File::create(path).map_err(|e| Error::File(path.into(), e))?;
+    let mut enc = png::Encoder::new(BufWriter::new(file), width as u32, height as u32);
+    enc.set(png::ColorType::RGBA).set(png::BitDepth::Eight);
+    enc.write_header()
+        .and_then(|mut w| w.write_image_data(&data))
+        .map_err(|e| Error::Png(path.into(), e.to_string()))
+}
+
+/// Load a 8-bit RGB or RGBA PNG file.
+pub fn load_png_rgba(path: &Path) -> Result<OwnedGfxBufferLE<Rgba8888>> {
+    let file = File::open(path).map_err(|e| Error::File(path.into(), e))?;
+    let (info, mut reader) = png::Decoder::new(file)
+        .read_info()
+        .map_err(|e| Error::Png(path.into(), e.to_string()))?;
+    let mut data = vec![0u8; info.buffer_size()];
+    reader
+        .next_frame(&mut data)
+        .map_err(|e| Error::Png(path.into(), e.to_string()))?;
+
+    let bpp = match (info.color_type, info.bit_depth) {
+        (png::ColorType::RGB, png::BitDepth::Eight) => 3,
+        (png::ColorType::RGBA, png::BitDepth::Eight) => 4,
+        (ct, bd) => {
+            return Err(Error::Png(
+                path.into(),
+                format!("unsupported format: {:?}/{:?}", ct, bd),
+            ))
+        }
+    };
+
+    let mut image = OwnedGfxBufferLE::<Rgba8888>::new(info.width as usize, info.height as usize);
+    {
+        let mut buf = image.buf_mut();
+        for (mut dst, src) in buf.iter_lines_mut().zip(data.chunks(info.line_size)) {
+            for (x, px) in src.chunks(bpp).enumerate().take(info.width as usize) {
+                let a = if bpp == 4 { px[3] } else { 0xFF };
+                dst.set(x, Color::new_clamped(px[0], px[1], px[2], a));
+            }
+        }
+    }
+    Ok(image)
+}
@@ -1,3 +1,5 @@
+use alloc::string::String;
+
 pub trait Numerics: Sized {
     type Unsigned: Numerics;
 
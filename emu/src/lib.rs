@@ -1,8 +1,14 @@
+#![cfg_attr(not(feature = "std"), no_std)]
 #![feature(box_syntax)]
 #![feature(exact_chunks)]
 #![feature(step_trait)]
 #![feature(specialization)]
 
+#[cfg(feature = "std")]
+extern crate core;
+#[macro_use]
+extern crate alloc;
+
 #[macro_use]
 extern crate enum_map;
 
@@ -21,6 +27,7 @@ extern crate slog;
 pub mod bus;
 pub mod fp;
 pub mod gfx;
+#[cfg(feature = "sdl")]
 pub mod hw;
 pub mod int;
 pub mod sync;
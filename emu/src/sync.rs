@@ -1,10 +1,16 @@
-use std::cell::RefCell;
-use std::rc::Rc;
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::fmt;
+#[cfg(feature = "io")]
+use std::io::{self, BufRead, Write};
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Event {
     HSync(usize, usize),
     VSync(usize, usize),
+    // A timer scheduled through Timers expired
+    Timer(usize),
 }
 
 pub struct Config {
@@ -28,40 +34,256 @@ pub trait Subsystem {
 
 type SubPtr = Rc<RefCell<Subsystem>>;
 
+struct TimerQueue {
+    main_clock: i64,
+    cycles: i64,
+    // Subsystem being run (with its frequency), whose own position is the
+    // current one
+    current: Option<(*const Subsystem, i64)>,
+    // Pending timers, as (expiry in main clock cycles, id)
+    pending: Vec<(i64, usize)>,
+}
+
+/// Timers lets devices schedule events in the future (eg: the completion of
+/// a DMA transfer), in the clock domain they run in. Sync stops all the
+/// subsystems when a timer expires, and delivers it to the run_frame()
+/// callback as Event::Timer. Timers is a shared handle: devices get a clone
+/// of the one returned by Sync::timers().
+#[derive(Clone)]
+pub struct Timers(Rc<RefCell<TimerQueue>>);
+
+impl Timers {
+    fn new(main_clock: i64) -> Timers {
+        Timers(Rc::new(RefCell::new(TimerQueue {
+            main_clock,
+            cycles: 0,
+            current: None,
+            pending: Vec::new(),
+        })))
+    }
+
+    /// Current position in the main clock timeline (see Sync::cycles()).
+    pub fn now(&self) -> i64 {
+        let q = self.0.borrow();
+        match q.current {
+            Some((sub, freq)) => convert_cycles(unsafe { &*sub }.cycles(), freq, q.main_clock),
+            None => q.cycles,
+        }
+    }
+
+    /// Schedule the timer `id` to expire after the specified number of
+    /// cycles of a clock running at `freq` Hz. If the timer is already
+    /// pending, it is rescheduled.
+    pub fn schedule(&self, id: usize, cycles: i64, freq: i64) {
+        let now = self.now();
+        let mut q = self.0.borrow_mut();
+        let expiry = now + convert_cycles(cycles, freq, q.main_clock);
+        q.pending.retain(|&(_, tid)| tid != id);
+        q.pending.push((expiry, id));
+    }
+
+    pub fn cancel(&self, id: usize) {
+        self.0.borrow_mut().pending.retain(|&(_, tid)| tid != id);
+    }
+
+    pub fn pending(&self, id: usize) -> bool {
+        self.0.borrow().pending.iter().any(|&(_, tid)| tid == id)
+    }
+
+    fn next_expiry(&self) -> Option<i64> {
+        self.0.borrow().pending.iter().map(|&(exp, _)| exp).min()
+    }
+
+    // Remove the first timer that expired by the current position. Timers
+    // expiring on the same cycle are returned in scheduling order.
+    fn pop_expired(&self) -> Option<(i64, usize)> {
+        let mut q = self.0.borrow_mut();
+        let cycles = q.cycles;
+        let pos = q
+            .pending
+            .iter()
+            .enumerate()
+            .filter(|&(_, &(exp, _))| exp <= cycles)
+            .min_by_key(|&(_, &(exp, _))| exp)
+            .map(|(pos, _)| pos);
+        pos.map(|pos| q.pending.remove(pos))
+    }
+}
+
+// A handle not connected to any Sync: its timers never expire.
+impl Default for Timers {
+    fn default() -> Timers {
+        Timers::new(0)
+    }
+}
+
+/// Convert a number of cycles from a clock frequency to another, rounding
+/// down. The computation is exact (no floating point), so that a subsystem
+/// reaches exactly the same cycles on every run.
+pub fn convert_cycles(cycles: i64, from_freq: i64, to_freq: i64) -> i64 {
+    // Split the multiplication to avoid overflowing on long runs
+    cycles / from_freq * to_freq + cycles % from_freq * to_freq / from_freq
+}
+
+/// An entry of the schedule trace: either a sync event, or a slice of
+/// execution of a subsystem (with the cycle it was asked to reach, and the
+/// cycle it actually reached).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TraceEntry {
+    Event(i64, Event),
+    Run(usize, i64, i64),
+}
+
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TraceEntry::Event(cyc, Event::HSync(x, y)) => write!(f, "H {} {} {}", cyc, x, y),
+            TraceEntry::Event(cyc, Event::VSync(x, y)) => write!(f, "V {} {} {}", cyc, x, y),
+            TraceEntry::Event(cyc, Event::Timer(id)) => write!(f, "T {} {} 0", cyc, id),
+            TraceEntry::Run(sub, target, reached) => write!(f, "R {} {} {}", sub, target, reached),
+        }
+    }
+}
+
+#[cfg(feature = "io")]
+impl TraceEntry {
+    fn parse(line: &str) -> Option<TraceEntry> {
+        let mut fields = line.split_whitespace();
+        let kind = fields.next()?;
+        let a = fields.next()?.parse::<i64>().ok()?;
+        let b = fields.next()?.parse::<i64>().ok()?;
+        let c = fields.next()?.parse::<i64>().ok()?;
+        match kind {
+            "H" => Some(TraceEntry::Event(a, Event::HSync(b as usize, c as usize))),
+            "V" => Some(TraceEntry::Event(a, Event::VSync(b as usize, c as usize))),
+            "T" => Some(TraceEntry::Event(a, Event::Timer(b as usize))),
+            "R" => Some(TraceEntry::Run(a as usize, b, c)),
+            _ => None,
+        }
+    }
+}
+
+/// EventTrace is the exact sequence of events scheduled during a run. Two
+/// runs of the same machine with the same inputs must produce identical
+/// traces: this is the determinism contract that netplay and TAS movies
+/// rely on.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EventTrace {
+    pub entries: Vec<TraceEntry>,
+}
+
+#[cfg(feature = "io")]
+impl EventTrace {
+    /// Write the trace as text, one entry per line.
+    pub fn write<W: Write>(&self, mut w: W) -> io::Result<()> {
+        for e in &self.entries {
+            writeln!(w, "{}", e)?;
+        }
+        Ok(())
+    }
+
+    pub fn read<R: BufRead>(r: R) -> io::Result<EventTrace> {
+        let mut entries = Vec::new();
+        for line in r.lines() {
+            let line = line?;
+            match TraceEntry::parse(&line) {
+                Some(e) => entries.push(e),
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("invalid trace entry: {}", line),
+                    ))
+                }
+            }
+        }
+        Ok(EventTrace { entries })
+    }
+}
+
+/// First difference found while verifying a replay against a trace.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Divergence {
+    pub index: usize,
+    pub expected: Option<TraceEntry>, // None if the replay ran longer
+    pub found: TraceEntry,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.expected {
+            Some(ref e) => write!(f, "entry {}: expected [{}], found [{}]", self.index, e, self.found),
+            None => write!(f, "entry {}: unexpected [{}] past the end of the trace", self.index, self.found),
+        }
+    }
+}
+
+enum Audit {
+    Off,
+    Record(EventTrace),
+    Verify(EventTrace, usize, Option<Divergence>),
+}
+
 pub struct Sync {
     pub cfg: Config,
     subs: Vec<SubPtr>,
-    sub_scaler: Vec<f64>,
-    current_sub: Option<*const Subsystem>,
+    sub_freq: Vec<i64>,
+    timers: Timers,
 
     frames: i64,
     cycles: i64,
     line_cycles: i64,
     frame_cycles: i64,
     frame_syncs: Vec<(i64, Event)>,
+
+    audit: Audit,
 }
 
 impl Sync {
     pub fn new(cfg: Config) -> Sync {
+        let timers = Timers::new(cfg.main_clock);
         let mut s = Sync {
             cfg,
             subs: vec![],
-            sub_scaler: vec![],
+            sub_freq: vec![],
             frames: 0,
             cycles: 0,
             line_cycles: 0,
             frame_cycles: 0,
             frame_syncs: vec![],
-            current_sub: None,
+            timers,
+            audit: Audit::Off,
         };
         s.calc();
         s
     }
 
-    pub fn register(&mut self, sub: SubPtr, freq: i64) {
+    /// Register a subsystem running at the specified frequency. Returns its
+    /// index, which identifies it in the schedule trace.
+    pub fn register(&mut self, sub: SubPtr, freq: i64) -> usize {
         self.subs.push(sub);
-        self.sub_scaler
-            .push(self.cfg.main_clock as f64 / freq as f64);
+        self.sub_freq.push(freq);
+        self.subs.len() - 1
+    }
+
+    /// Change the frequency of a registered subsystem. This is meant to be
+    /// done before running the first frame: afterwards, the subsystem would
+    /// be scheduled from a cycle count that does not match its own.
+    pub fn set_frequency(&mut self, idx: usize, freq: i64) {
+        self.sub_freq[idx] = freq;
+    }
+
+    pub fn frequency(&self, idx: usize) -> i64 {
+        self.sub_freq[idx]
+    }
+
+    /// Convert cycles of the main clock into cycles of a subsystem.
+    pub fn to_sub_cycles(&self, idx: usize, cycles: i64) -> i64 {
+        convert_cycles(cycles, self.cfg.main_clock, self.sub_freq[idx])
+    }
+
+    /// Convert cycles of a subsystem into cycles of the main clock.
+    pub fn to_main_cycles(&self, idx: usize, cycles: i64) -> i64 {
+        convert_cycles(cycles, self.sub_freq[idx], self.cfg.main_clock)
     }
 
     fn calc(&mut self) {
@@ -89,11 +311,15 @@ impl Sync {
         self.frame_syncs.sort_by_key(|k| k.0);
     }
 
+    /// Current position in the main clock timeline. While a subsystem is
+    /// running, this is its own position, converted to the main clock.
     pub fn cycles(&self) -> i64 {
-        match self.current_sub {
-            Some(sub) => unsafe { &*sub }.cycles(),
-            None => self.cycles,
-        }
+        self.timers.now()
+    }
+
+    /// Return a handle to the timers, to be shared with the devices.
+    pub fn timers(&self) -> Timers {
+        self.timers.clone()
     }
 
     // Return the (x,y) dot position of the emulation in the current frame.
@@ -104,6 +330,52 @@ impl Sync {
         (x as usize, y as usize)
     }
 
+    /// Start recording the trace of all scheduled events (determinism audit).
+    pub fn record_trace(&mut self) {
+        self.audit = Audit::Record(EventTrace::default());
+    }
+
+    /// Start verifying that the scheduled events match a previously
+    /// recorded trace. The first divergence is available through divergence().
+    pub fn verify_trace(&mut self, trace: EventTrace) {
+        self.audit = Audit::Verify(trace, 0, None);
+    }
+
+    /// Stop the audit, returning the trace recorded so far (if recording).
+    pub fn take_trace(&mut self) -> Option<EventTrace> {
+        match ::core::mem::replace(&mut self.audit, Audit::Off) {
+            Audit::Record(trace) => Some(trace),
+            _ => None,
+        }
+    }
+
+    pub fn divergence(&self) -> Option<&Divergence> {
+        match self.audit {
+            Audit::Verify(_, _, ref div) => div.as_ref(),
+            _ => None,
+        }
+    }
+
+    fn trace(&mut self, entry: TraceEntry) {
+        match self.audit {
+            Audit::Off => {}
+            Audit::Record(ref mut trace) => trace.entries.push(entry),
+            Audit::Verify(ref trace, ref mut pos, ref mut div) => {
+                if div.is_none() {
+                    let expected = trace.entries.get(*pos).cloned();
+                    if expected != Some(entry) {
+                        *div = Some(Divergence {
+                            index: *pos,
+                            expected,
+                            found: entry,
+                        });
+                    }
+                }
+                *pos += 1;
+            }
+        }
+    }
+
     pub fn run_frame<F: FnMut(Event)>(&mut self, mut cb: F) {
         let frame_start = self.cycles;
         let frame_end = frame_start + self.frame_cycles;
@@ -111,22 +383,50 @@ impl Sync {
 
         for idx in 0..self.frame_syncs.len() {
             let (cyc, evt) = self.frame_syncs[idx];
-            self.run_until(frame_start + cyc);
+            self.run_until(frame_start + cyc, &mut cb);
+            self.trace(TraceEntry::Event(frame_start + cyc, evt));
             cb(evt);
         }
 
-        self.run_until(frame_end);
+        self.run_until(frame_end, &mut cb);
         self.frames = self.frames + 1;
     }
 
-    fn run_until(&mut self, target: i64) {
-        for (sub, scaler) in self.subs.iter().zip(self.sub_scaler.iter()) {
+    // Run all the subsystems until the target, stopping at each timer that
+    // expires on the way. A timer scheduled to expire within the slice being
+    // run is delivered at the end of it.
+    fn run_until<F: FnMut(Event)>(&mut self, target: i64, cb: &mut F) {
+        loop {
+            self.fire_timers(cb);
+            match self.timers.next_expiry() {
+                Some(expiry) if expiry < target => self.run_subs(expiry),
+                _ => break,
+            }
+        }
+        self.run_subs(target);
+        self.fire_timers(cb);
+    }
+
+    fn fire_timers<F: FnMut(Event)>(&mut self, cb: &mut F) {
+        while let Some((cyc, id)) = self.timers.pop_expired() {
+            self.trace(TraceEntry::Event(cyc, Event::Timer(id)));
+            cb(Event::Timer(id));
+        }
+    }
+
+    fn run_subs(&mut self, target: i64) {
+        for idx in 0..self.subs.len() {
+            let sub = self.subs[idx].clone();
             let mut sub = sub.borrow_mut();
-            self.current_sub = Some(&*sub);
-            sub.run((target as f64 / scaler) as i64);
-            self.current_sub = None;
+            let sub_target = self.to_sub_cycles(idx, target);
+            self.timers.0.borrow_mut().current =
+                Some((&*sub as *const Subsystem, self.sub_freq[idx]));
+            sub.run(sub_target);
+            self.timers.0.borrow_mut().current = None;
+            self.trace(TraceEntry::Run(idx, sub_target, sub.cycles()));
         }
         self.cycles = target;
+        self.timers.0.borrow_mut().cycles = target;
     }
 }
 
@@ -168,4 +468,132 @@ mod tests {
             events.iter().map(|(_, evt)| *evt).collect::<Vec<_>>()
         );
     }
+
+    struct Counter {
+        cycles: i64,
+        step: i64,
+    }
+
+    impl Subsystem for Counter {
+        fn run(&mut self, target: i64) {
+            while self.cycles < target {
+                self.cycles += self.step;
+            }
+        }
+        fn cycles(&self) -> i64 {
+            self.cycles
+        }
+    }
+
+    #[test]
+    fn frequency() {
+        let mut sync = Sync::new(Config {
+            main_clock: 128,
+            dot_clock_divider: 2,
+            hdots: 4,
+            vdots: 2,
+            hsyncs: vec![0],
+            vsyncs: vec![],
+        });
+        let counter = Rc::new(RefCell::new(Counter { cycles: 0, step: 1 }));
+        let idx = sync.register(counter.clone(), 64);
+        assert_eq!(idx, 0);
+
+        // A frame is 16 cycles of the main clock
+        sync.set_frequency(idx, 32);
+        sync.run_frame(|_| {});
+        assert_eq!(counter.borrow().cycles, 4);
+
+        // Conversions between clock domains
+        sync.set_frequency(idx, 48);
+        assert_eq!(sync.frequency(idx), 48);
+        assert_eq!(sync.to_sub_cycles(idx, 16), 6);
+        assert_eq!(sync.to_main_cycles(idx, 6), 16);
+        assert_eq!(sync.to_sub_cycles(idx, 17), 6);
+        assert_eq!(convert_cycles(1 << 60, 3, 2), (1 << 61) / 3);
+    }
+
+    #[test]
+    fn timers() {
+        let mut sync = Sync::new(Config {
+            main_clock: 128,
+            dot_clock_divider: 2,
+            hdots: 4,
+            vdots: 2,
+            hsyncs: vec![0],
+            vsyncs: vec![],
+        });
+        let counter = Rc::new(RefCell::new(Counter { cycles: 0, step: 1 }));
+        sync.register(counter.clone(), 64);
+        let timers = sync.timers();
+
+        // 3 cycles at 32 Hz are 12 cycles of the main clock
+        timers.schedule(7, 3, 32);
+        assert!(timers.pending(7));
+        let mut record = Vec::new();
+        sync.run_frame(|evt| {
+            record.push((evt, counter.borrow().cycles));
+            if evt == Event::HSync(0, 1) {
+                timers.schedule(8, 1, 64);
+                timers.schedule(9, 1, 64);
+                timers.cancel(9);
+            }
+        });
+        assert_eq!(
+            record,
+            vec![
+                (Event::HSync(0, 0), 0),
+                (Event::HSync(0, 1), 4),
+                (Event::Timer(8), 5),
+                (Event::Timer(7), 6),
+            ]
+        );
+        assert!(!timers.pending(7));
+    }
+
+    fn audit_run(step: i64, trace: Option<EventTrace>) -> Sync {
+        let mut sync = Sync::new(Config {
+            main_clock: 128,
+            dot_clock_divider: 2,
+            hdots: 4,
+            vdots: 2,
+            hsyncs: vec![0],
+            vsyncs: vec![],
+        });
+        sync.register(Rc::new(RefCell::new(Counter { cycles: 0, step })), 64);
+        match trace {
+            Some(trace) => sync.verify_trace(trace),
+            None => sync.record_trace(),
+        }
+        sync.run_frame(|_| {});
+        sync
+    }
+
+    #[test]
+    fn audit() {
+        let trace = audit_run(3, None).take_trace().unwrap();
+        assert_eq!(
+            &trace.entries[..3],
+            &[
+                TraceEntry::Run(0, 0, 0),
+                TraceEntry::Event(0, Event::HSync(0, 0)),
+                TraceEntry::Run(0, 4, 6),
+            ]
+        );
+
+        // Text roundtrip
+        let mut buf = Vec::new();
+        trace.write(&mut buf).unwrap();
+        let trace2 = EventTrace::read(&buf[..]).unwrap();
+        assert_eq!(trace2, trace);
+
+        // Identical replay
+        assert_eq!(audit_run(3, Some(trace.clone())).divergence(), None);
+
+        // A subsystem with different timing diverges at the first slice
+        let sync = audit_run(2, Some(trace));
+        let div = sync.divergence().unwrap();
+        assert_eq!(div.index, 2);
+        assert_eq!(div.found, TraceEntry::Run(0, 4, 4));
+    }
 }
@@ -101,4 +101,31 @@ mod tests {
         bus.write::<u32>(0x0400000C, 0xaaaaaaaa);
         assert_eq!(bus.read::<u32>(0x0400000C), 0xaaaa0081);
     }
+
+    #[derive(Default, DeviceLE)]
+    struct Timer {
+        #[reg(bank = 0, offset = 0x0, fields = "enable:0-0 mode:1-2 count:16-31")]
+        control: Reg<LittleEndian, u32>,
+
+        #[reg(bank = 0, offset = 0x4)]
+        reload: Reg<LittleEndian, u32>,
+    }
+
+    #[test]
+    fn register_fields() {
+        let mut timer = DevPtr::new(Timer::default());
+
+        let mut bus = Bus::<LittleEndian>::new(logger());
+        bus.map_device(0x04000000, &mut timer, 0).expect("map error");
+
+        let map = bus.memory_map();
+        let control = map.entries.iter().find(|e| e.name == "control").unwrap();
+        assert_eq!(control.fields.len(), 3);
+        assert_eq!(control.decode(0x0123_0005), "enable=1 mode=2 count=291");
+
+        // Registers without documented fields are shown in hex
+        let reload = map.entries.iter().find(|e| e.name == "reload").unwrap();
+        assert!(reload.fields.is_empty());
+        assert_eq!(reload.decode(0x1234), "0x1234");
+    }
 }
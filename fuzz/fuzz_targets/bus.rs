@@ -0,0 +1,116 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate slog;
+extern crate emu;
+extern crate r64emu;
+
+use emu::bus::be::Bus;
+use emu::bus::{MapEntry, MapKind};
+use r64emu::N64;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+// Bus fuzzing: perform random reads and writes over the whole address space
+// of an assembled machine. Any panic in a device callback is a crash. Writes
+// to registers without callbacks must also respect the register rwmask
+// (read-only bits never change).
+//
+// Run from the repository root (the BIOS is loaded from bios/pifdata.bin):
+//     cargo fuzz run bus
+
+lazy_static! {
+    // A minimal ROM image: just a valid header, so that the cartridge loads.
+    static ref ROM: PathBuf = {
+        let path = env::temp_dir().join("r64emu-fuzz.z64");
+        let mut rom = vec![0u8; 1 << 20];
+        rom[0..4].copy_from_slice(&[0x80, 0x37, 0x12, 0x40]);
+        fs::write(&path, rom).unwrap();
+        path
+    };
+}
+
+fn read(bus: &Bus, addr: u32, size: usize) -> u64 {
+    match size {
+        1 => bus.read::<u8>(addr) as u64,
+        2 => bus.read::<u16>(addr) as u64,
+        4 => bus.read::<u32>(addr) as u64,
+        _ => bus.read::<u64>(addr),
+    }
+}
+
+fn write(bus: &Bus, addr: u32, size: usize, val: u64) {
+    match size {
+        1 => bus.write::<u8>(addr, val as u8),
+        2 => bus.write::<u16>(addr, val as u16),
+        4 => bus.write::<u32>(addr, val as u32),
+        _ => bus.write::<u64>(addr, val),
+    }
+}
+
+// Return the register mapped exactly at the specified address and size,
+// if its write behavior can be checked against its rwmask.
+fn plain_reg(map: &[MapEntry], addr: u32, size: usize) -> Option<&MapEntry> {
+    map.iter().find(|e| {
+        e.kind == MapKind::Reg
+            && e.begin == addr
+            && (e.end - e.begin + 1) as usize == size
+            && e.read
+            && e.write
+            && !e.callbacks
+    })
+}
+
+fuzz_target!(|data: &[u8]| {
+    let logger = slog::Logger::root(slog::Discard, o!());
+    let n64 = match N64::new(logger, ROM.to_str().unwrap()) {
+        Ok(n64) => n64,
+        Err(_) => return,
+    };
+    let map = n64.memory_map().entries;
+
+    // Each operation is 13 bytes: flags, address (4 bytes), value (8 bytes).
+    //   flags[1:0]: access size (8/16/32/64 bits)
+    //   flags[2]: write
+    //   flags[3]: target a mapped area (address is an index + offset)
+    for op in data.chunks(13).filter(|op| op.len() == 13) {
+        let size = 1usize << (op[0] & 3);
+        let is_write = op[0] & 4 != 0;
+        let mut addr = (op[1] as u32) << 24 | (op[2] as u32) << 16 | (op[3] as u32) << 8 | op[4] as u32;
+        let val = op[5..13].iter().fold(0u64, |v, &b| v << 8 | b as u64);
+
+        if op[0] & 8 != 0 && !map.is_empty() {
+            let e = &map[op[1] as usize % map.len()];
+            let span = (e.end - e.begin) as u64 + 1;
+            addr = e.begin + ((addr & 0xFFFF) as u64 % span) as u32;
+        }
+        addr &= !(size as u32 - 1);
+
+        let bus = n64.bus().borrow();
+        if !is_write {
+            read(&bus, addr, size);
+            continue;
+        }
+        match plain_reg(&map, addr, size) {
+            Some(reg) => {
+                let before = read(&bus, addr, size);
+                write(&bus, addr, size, val);
+                let after = read(&bus, addr, size);
+                assert_eq!(
+                    (before ^ after) & !reg.rwmask,
+                    0,
+                    "read-only bits changed in {} (before={:x} val={:x} after={:x})",
+                    reg.name,
+                    before,
+                    val,
+                    after
+                );
+            }
+            None => write(&bus, addr, size, val),
+        }
+    }
+});
@@ -0,0 +1,57 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+#[macro_use]
+extern crate slog;
+extern crate byteorder;
+extern crate emu;
+extern crate r64emu;
+
+use byteorder::BigEndian;
+use emu::bus::be::{Bus, Mem};
+use r64emu::mips64::{Cop, Cp0, Cpu, Fpu};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// Interpreter fuzzing: execute random opcode streams on a sandboxed CPU,
+// attached to a bus with only RAM mapped. In lenient mode, unimplemented
+// opcodes are skipped, so the interpreter must never panic whatever the
+// input (eg: because of unwraps in the decoding paths).
+//
+//     cargo fuzz run cpu
+
+const RAM_SIZE: usize = 1024 * 1024;
+const START_PC: u32 = 0x8000_0000;
+
+fuzz_target!(|data: &[u8]| {
+    let logger = slog::Logger::root(slog::Discard, o!());
+    let bus = Rc::new(RefCell::new(Bus::new(logger.new(o!()))));
+    let ram = Mem::new(RAM_SIZE, Default::default());
+    bus.borrow_mut()
+        .map_mem(0x0000_0000, RAM_SIZE as u32 - 1, &ram)
+        .unwrap();
+
+    let mut cpu = Cpu::new(logger.new(o!()), bus.clone());
+    // Start with the FPU usable (Status.CU1), as after boot
+    let mut cop0 = Cp0::new(logger.new(o!()));
+    cop0.set_reg(12, 0x2000_0000);
+    cpu.set_cop0(cop0);
+    cpu.set_cop1(Fpu::new(logger.new(o!())));
+    cpu.set_lenient(true);
+
+    let ops: Vec<u32> = data
+        .chunks(4)
+        .filter(|c| c.len() == 4)
+        .take(RAM_SIZE / 4)
+        .map(|c| (c[0] as u32) << 24 | (c[1] as u32) << 16 | (c[2] as u32) << 8 | c[3] as u32)
+        .collect();
+    for (idx, op) in ops.iter().enumerate() {
+        ram.write::<BigEndian, u32>(idx as u32 * 4, *op);
+    }
+
+    // Give each opcode a few cycles, so that loops and branches are also
+    // exercised (but always terminate).
+    cpu.ctx_mut().set_pc(START_PC);
+    let until = cpu.ctx().clock + ops.len() as i64 * 4;
+    cpu.run(until);
+});
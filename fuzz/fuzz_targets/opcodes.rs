@@ -0,0 +1,118 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+#[macro_use]
+extern crate slog;
+extern crate byteorder;
+extern crate emu;
+extern crate r64emu;
+
+use byteorder::BigEndian;
+use emu::bus::be::{Bus, Mem};
+use r64emu::mips64::{Cop, Cp0, Cpu, Fpu};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// Opcode-level fuzzing: unlike the cpu target, the input is turned into
+// structurally valid instructions (defined primary opcodes, SPECIAL
+// functions and REGIMM conditions, with random operands), and the core is
+// single-stepped so that invariants can be checked after each instruction:
+// r0 stays zero, and memory accesses are aligned and either fall within the
+// RAM or are reported as unmapped by the bus.
+//
+//     cargo fuzz run opcodes
+
+const RAM_SIZE: usize = 1024 * 1024;
+const START_PC: u32 = 0x8000_0000;
+const MAX_STEPS: usize = 4096;
+
+// Defined primary opcodes, excluding COP2 ones (there is no COP2).
+const PRIMARY: [u32; 52] = [
+    0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E, 0x0F,
+    0x10, 0x11, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1A, 0x1B, 0x20, 0x21, 0x22, 0x23, 0x24, 0x25,
+    0x26, 0x27, 0x28, 0x29, 0x2A, 0x2B, 0x2C, 0x2D, 0x2E, 0x2F, 0x30, 0x31, 0x34, 0x35, 0x37, 0x38,
+    0x39, 0x3C, 0x3D, 0x3F,
+];
+
+const SPECIAL: [u32; 52] = [
+    0x00, 0x02, 0x03, 0x04, 0x06, 0x07, 0x08, 0x09, 0x0C, 0x0D, 0x0F, 0x10, 0x11, 0x12, 0x13, 0x14,
+    0x16, 0x17, 0x18, 0x19, 0x1A, 0x1B, 0x1C, 0x1D, 0x1E, 0x1F, 0x20, 0x21, 0x22, 0x23, 0x24, 0x25,
+    0x26, 0x27, 0x2A, 0x2B, 0x2C, 0x2D, 0x2E, 0x2F, 0x30, 0x31, 0x32, 0x33, 0x34, 0x36, 0x38, 0x3A,
+    0x3B, 0x3C, 0x3E, 0x3F,
+];
+
+const REGIMM: [u32; 14] = [
+    0x00, 0x01, 0x02, 0x03, 0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0E, 0x10, 0x11, 0x12, 0x13,
+];
+
+// Turn a random word into a valid instruction, keeping its operand bits.
+fn opcode(word: u32) -> u32 {
+    let op = PRIMARY[(word >> 26) as usize % PRIMARY.len()];
+    let word = (word & 0x03FF_FFFF) | (op << 26);
+    match op {
+        0x00 => (word & !0x3F) | SPECIAL[(word & 0x3F) as usize % SPECIAL.len()],
+        0x01 => {
+            let rt = REGIMM[((word >> 16) & 0x1F) as usize % REGIMM.len()];
+            (word & !(0x1F << 16)) | (rt << 16)
+        }
+        _ => word,
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let logger = slog::Logger::root(slog::Discard, o!());
+    let bus = Rc::new(RefCell::new(Bus::new(logger.new(o!()))));
+    let ram = Mem::new(RAM_SIZE, Default::default());
+    bus.borrow_mut()
+        .map_mem(0x0000_0000, RAM_SIZE as u32 - 1, &ram)
+        .unwrap();
+
+    let mut cpu = Cpu::new(logger.new(o!()), bus.clone());
+    // Start with the FPU usable (Status.CU1), as after boot
+    let mut cop0 = Cp0::new(logger.new(o!()));
+    cop0.set_reg(12, 0x2000_0000);
+    cpu.set_cop0(cop0);
+    cpu.set_cop1(Fpu::new(logger.new(o!())));
+    cpu.set_lenient(true);
+
+    let ops: Vec<u32> = data
+        .chunks(4)
+        .filter(|c| c.len() == 4)
+        .take(RAM_SIZE / 4)
+        .map(|c| (c[0] as u32) << 24 | (c[1] as u32) << 16 | (c[2] as u32) << 8 | c[3] as u32)
+        .map(opcode)
+        .collect();
+    for (idx, op) in ops.iter().enumerate() {
+        ram.write::<BigEndian, u32>(idx as u32 * 4, *op);
+    }
+
+    // Point the GPRs within the RAM (through KSEG0), so that loads and
+    // stores mostly hit it rather than faulting.
+    for idx in 1..32 {
+        cpu.ctx_mut().regs[idx] = 0xFFFF_FFFF_8000_0000 | (idx as u64 * 0x8000);
+    }
+    cpu.ctx_mut().set_pc(START_PC);
+
+    for _ in 0..MAX_STEPS.min(ops.len() * 4) {
+        let step = cpu.step();
+        assert_eq!(cpu.ctx().regs[0], 0, "r0 written: {:?}", step);
+
+        let unmapped = bus.borrow().unmapped_accesses();
+        for acc in &step.accesses {
+            assert_eq!(acc.paddr as usize % acc.size, 0, "misaligned: {:?}", acc);
+            assert!(
+                acc.paddr as usize + acc.size <= RAM_SIZE
+                    || unmapped.iter().any(|&(addr, _)| addr == acc.paddr),
+                "out of bounds: {:?}",
+                acc
+            );
+        }
+        for &(addr, _) in &unmapped {
+            assert!(
+                addr as usize >= RAM_SIZE,
+                "RAM access reported as unmapped: {:x}",
+                addr
+            );
+        }
+    }
+});
@@ -0,0 +1,89 @@
+// Without std there is no HashMap: use the ordered map of alloc instead.
+#[cfg(not(feature = "std"))]
+use alloc::collections::BTreeMap as HashMap;
+use alloc::rc::Rc;
+#[cfg(feature = "std")]
+use std::collections::HashMap;
+
+// Blocks are grouped by 4KB page, the granularity at which CodeWatch
+// tracks the fetched code.
+const PAGE_SHIFT: u32 = 12;
+
+/// BlockCache holds the basic blocks decoded by the cached execution engine,
+/// keyed by the physical address of their first instruction. A block is only
+/// valid as long as the code it was decoded from is not written: the whole
+/// cache is flushed when the CodeWatch generation changes, and the blocks of
+/// a page are dropped when the page starts being watched again (as it might
+/// have been written while it was not).
+pub(crate) struct BlockCache<T> {
+    pages: HashMap<u32, HashMap<u32, Rc<T>>>,
+    generation: u64,
+}
+
+impl<T> BlockCache<T> {
+    pub fn new() -> BlockCache<T> {
+        BlockCache {
+            pages: HashMap::new(),
+            generation: 0,
+        }
+    }
+
+    /// Lookup the block starting at the specified physical address, given
+    /// the current CodeWatch generation.
+    pub fn get(&mut self, paddr: u32, generation: u64) -> Option<Rc<T>> {
+        if self.generation != generation {
+            self.pages.clear();
+            self.generation = generation;
+            return None;
+        }
+        self.pages
+            .get(&(paddr >> PAGE_SHIFT))
+            .and_then(|blocks| blocks.get(&paddr))
+            .cloned()
+    }
+
+    /// Insert the block decoded at the specified physical address.
+    pub fn insert(&mut self, paddr: u32, block: T) -> Rc<T> {
+        let block = Rc::new(block);
+        self.pages
+            .entry(paddr >> PAGE_SHIFT)
+            .or_insert_with(HashMap::new)
+            .insert(paddr, block.clone());
+        block
+    }
+
+    /// Drop all the blocks decoded from the page holding the specified
+    /// physical address.
+    pub fn drop_page(&mut self, paddr: u32) {
+        self.pages.remove(&(paddr >> PAGE_SHIFT));
+    }
+
+    /// Number of blocks in the cache.
+    pub fn len(&self) -> usize {
+        self.pages.values().map(|blocks| blocks.len()).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalidate_blocks() {
+        let mut cache = BlockCache::new();
+        cache.insert(0x0000_1000, vec![1u32, 2]);
+        cache.insert(0x0000_1010, vec![3]);
+        cache.insert(0x0000_2000, vec![4]);
+        assert_eq!(cache.len(), 3);
+        assert_eq!(*cache.get(0x0000_1010, 0).unwrap(), vec![3]);
+        assert!(cache.get(0x0000_1004, 0).is_none());
+
+        cache.drop_page(0x0000_1FFC);
+        assert!(cache.get(0x0000_1000, 0).is_none());
+        assert_eq!(*cache.get(0x0000_2000, 0).unwrap(), vec![4]);
+
+        // A new generation flushes everything
+        assert!(cache.get(0x0000_2000, 1).is_none());
+        assert_eq!(cache.len(), 0);
+    }
+}
@@ -0,0 +1,123 @@
+use alloc::vec::Vec;
+/// State of a cache line: the physical address it holds (bits 31-12, the
+/// tag), and whether it is valid and dirty.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CacheLine {
+    pub ptag: u32,
+    pub valid: bool,
+    pub dirty: bool,
+}
+
+impl CacheLine {
+    /// Encode the line into the TagLo format: PTagLo (bits 27-8) and
+    /// PState (bits 7-6: valid, dirty).
+    pub fn tag_lo(&self) -> u64 {
+        ((self.ptag >> 4) as u64 & 0x0FFF_FF00)
+            | (self.valid as u64) << 7
+            | (self.dirty as u64) << 6
+    }
+
+    /// Decode a line from the TagLo format.
+    pub fn from_tag_lo(tag_lo: u64) -> CacheLine {
+        CacheLine {
+            ptag: ((tag_lo & 0x0FFF_FF00) << 4) as u32,
+            valid: tag_lo & (1 << 7) != 0,
+            dirty: tag_lo & (1 << 6) != 0,
+        }
+    }
+}
+
+/// Cache is a direct-mapped, virtually indexed and physically tagged cache,
+/// like the VR4300 instruction and data caches. Only the state of the lines
+/// is modeled, not their contents: memory is always kept up to date, so
+/// writing back a line only updates its state. This is enough for the CACHE
+/// instruction to behave like on the real hardware, as observed through
+/// the tags.
+pub struct Cache {
+    lines: Vec<CacheLine>,
+    line_shift: u32,
+}
+
+impl Cache {
+    /// Create a cache of the specified size, with the specified line size
+    /// (in bytes; both must be powers of two).
+    pub fn new(size: usize, line_size: usize) -> Cache {
+        assert!(size.is_power_of_two() && line_size.is_power_of_two() && line_size <= size);
+        Cache {
+            lines: vec![CacheLine::default(); size / line_size],
+            line_shift: line_size.trailing_zeros(),
+        }
+    }
+
+    fn index(&self, vaddr: u64) -> usize {
+        (vaddr >> self.line_shift) as usize & (self.lines.len() - 1)
+    }
+
+    /// Line selected by the index bits of a virtual address.
+    pub fn line(&self, vaddr: u64) -> &CacheLine {
+        &self.lines[self.index(vaddr)]
+    }
+
+    pub fn line_mut(&mut self, vaddr: u64) -> &mut CacheLine {
+        let idx = self.index(vaddr);
+        &mut self.lines[idx]
+    }
+
+    /// Line holding the specified address, if it is in the cache.
+    pub fn hit(&mut self, vaddr: u64, paddr: u32) -> Option<&mut CacheLine> {
+        let line = self.line_mut(vaddr);
+        if line.valid && line.ptag == paddr & !0xFFF {
+            Some(line)
+        } else {
+            None
+        }
+    }
+
+    /// Load the line holding the specified address, replacing the one in
+    /// the same index, without marking it dirty.
+    pub fn fill(&mut self, vaddr: u64, paddr: u32) -> &mut CacheLine {
+        let line = self.line_mut(vaddr);
+        *line = CacheLine {
+            ptag: paddr & !0xFFF,
+            valid: true,
+            dirty: false,
+        };
+        line
+    }
+
+    /// Update the cache for a load or store through a cached segment.
+    pub fn access(&mut self, vaddr: u64, paddr: u32, write: bool) {
+        let line = self.line_mut(vaddr);
+        if !line.valid || line.ptag != paddr & !0xFFF {
+            *line = CacheLine {
+                ptag: paddr & !0xFFF,
+                valid: true,
+                dirty: false,
+            };
+        }
+        line.dirty |= write;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache_lines() {
+        let mut dcache = Cache::new(8 * 1024, 16);
+        dcache.access(0x8000_1230, 0x0000_1230, false);
+        assert_eq!(dcache.line(0x8000_1234).tag_lo(), 0x0000_0180);
+        assert!(dcache.hit(0x8000_123C, 0x0000_123C).is_some());
+
+        // Same index, different tag: the line is replaced
+        dcache.access(0x8000_3230, 0x0000_3230, true);
+        assert!(dcache.hit(0x8000_1230, 0x0000_1230).is_none());
+        assert_eq!(dcache.line(0x8000_1230).tag_lo(), 0x0000_03C0);
+
+        let line = CacheLine::from_tag_lo(0x0001_2380);
+        assert_eq!(line.ptag, 0x0012_3000);
+        assert!(line.valid && !line.dirty);
+        assert_eq!(line.tag_lo(), 0x0001_2380);
+    }
+}
@@ -0,0 +1,115 @@
+use alloc::rc::Rc;
+use core::cell::RefCell;
+
+// Code pages are tracked with a 4KB granularity.
+const PAGE_SHIFT: u32 = 12;
+
+// Number of pages from which code was recently fetched that are tracked.
+// Hot code rarely spans more than a few pages between two invalidations.
+const NUM_PAGES: usize = 8;
+
+const NO_PAGE: u32 = 0xFFFF_FFFF;
+
+#[derive(Debug)]
+struct CodePages {
+    pages: [u32; NUM_PAGES],
+    next: usize,
+    generation: u64,
+}
+
+impl Default for CodePages {
+    fn default() -> CodePages {
+        CodePages {
+            pages: [NO_PAGE; NUM_PAGES],
+            next: 0,
+            generation: 0,
+        }
+    }
+}
+
+/// CodeWatch is a shared handle that tracks the physical memory pages from
+/// which a CPU recently fetched code. It is held by the CPU itself and by
+/// the devices that write into memory through DMA: when one of these pages
+/// is written, the generation is bumped, so that the CPU discards any state
+/// it cached about the fetched code (and reloads it from memory).
+#[derive(Clone, Debug, Default)]
+pub struct CodeWatch(Rc<RefCell<CodePages>>);
+
+impl CodeWatch {
+    pub fn new() -> CodeWatch {
+        CodeWatch::default()
+    }
+
+    /// Record that code was fetched from the specified physical address.
+    /// Returns true if its page was not tracked yet: writes into it might
+    /// have been missed until now.
+    pub fn fetched(&self, paddr: u32) -> bool {
+        let page = paddr >> PAGE_SHIFT;
+        let mut cp = self.0.borrow_mut();
+        if cp.pages.contains(&page) {
+            return false;
+        }
+        let next = cp.next;
+        cp.pages[next] = page;
+        cp.next = (next + 1) % NUM_PAGES;
+        true
+    }
+
+    /// Notify a write of len bytes at the specified physical address.
+    /// Returns true if it hit a page from which code was fetched; in that
+    /// case, all the tracked pages are forgotten until they are fetched
+    /// again.
+    pub fn invalidate(&self, paddr: u32, len: u32) -> bool {
+        if len == 0 {
+            return false;
+        }
+        let first = paddr >> PAGE_SHIFT;
+        let last = paddr.saturating_add(len - 1) >> PAGE_SHIFT;
+        let mut cp = self.0.borrow_mut();
+        if !cp.pages.iter().any(|&p| p >= first && p <= last) {
+            return false;
+        }
+        *cp = CodePages {
+            generation: cp.generation + 1,
+            ..CodePages::default()
+        };
+        true
+    }
+
+    /// Number of invalidations so far; cached fetch state is valid as long
+    /// as this does not change.
+    pub fn generation(&self) -> u64 {
+        self.0.borrow().generation
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalidate_pages() {
+        let cpu = CodeWatch::new();
+        let dma = cpu.clone();
+        assert!(cpu.fetched(0x0000_1234));
+        assert!(cpu.fetched(0x0040_0000));
+        assert!(!cpu.fetched(0x0000_1FFC));
+
+        assert!(!dma.invalidate(0x0000_2000, 0x1000));
+        assert!(!dma.invalidate(0x0000_1000, 0));
+        assert_eq!(cpu.generation(), 0);
+
+        // Range overlapping the end of a code page
+        assert!(dma.invalidate(0x0000_0F00, 0x104));
+        assert_eq!(cpu.generation(), 1);
+        assert!(!dma.invalidate(0x0040_0000, 4));
+
+        // Only the most recent pages are tracked
+        for page in 0..NUM_PAGES as u32 + 1 {
+            cpu.fetched(page << PAGE_SHIFT);
+        }
+        assert!(!dma.invalidate(0, 4));
+        assert!(dma.invalidate(0x0000_8FFC, 4));
+        assert_eq!(cpu.generation(), 2);
+    }
+}
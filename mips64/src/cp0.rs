@@ -0,0 +1,713 @@
+use super::cache::{Cache, CacheLine};
+use super::cpu::{Cop, Cop0, CpuBus, CpuContext, Exception, MemAccess};
+use super::segment::{AddrSpace, Mode, Segment};
+use super::tlb::{Tlb, TlbEntry, TlbError, TLB_ENTRIES};
+use alloc::boxed::Box;
+use alloc::collections::BTreeSet;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use slog;
+
+const STATUS_IE: u64 = 1 << 0;
+const STATUS_EXL: u64 = 1 << 1;
+const STATUS_ERL: u64 = 1 << 2;
+const STATUS_KSU_SHIFT: u64 = 3;
+const STATUS_UX: u64 = 1 << 5;
+const STATUS_SX: u64 = 1 << 6;
+const STATUS_KX: u64 = 1 << 7;
+const STATUS_SR: u64 = 1 << 20;
+const STATUS_TS: u64 = 1 << 21;
+const STATUS_BEV: u64 = 1 << 22;
+const STATUS_FR: u64 = 1 << 26;
+const STATUS_CU0: u64 = 1 << 28;
+const STATUS_CU1: u64 = 1 << 29;
+
+const CAUSE_EXCCODE_MASK: u64 = 0x1F << 2;
+const CAUSE_IP_MASK: u64 = 0xFF << 8;
+const CAUSE_CE_SHIFT: u64 = 28;
+const CAUSE_CE_MASK: u64 = 3 << CAUSE_CE_SHIFT;
+const CAUSE_BD: u64 = 1 << 31;
+
+const INDEX_PROBE_FAILURE: u64 = 1 << 31;
+const CONTEXT_BADVPN2_MASK: u64 = 0x7F_FFF0;
+const ENTRYHI_RWMASK: u64 = 0xC000_00FF_FFFF_E0FF;
+const ENTRYLO_RWMASK: u64 = 0x3FFF_FFFF;
+const PAGEMASK_RWMASK: u64 = 0x01FF_E000;
+
+// Processor revision: NEC VR4300
+const PRID: u64 = 0x0B22;
+
+// Config at reset: big-endian, 32-bit bus, 16-byte cache lines. Only the
+// writeback pattern (EP), endianness (BE) and KSEG0 coherency (K0, CU) can be
+// changed by software.
+const CONFIG_RESET: u64 = 0x7006_E463;
+const CONFIG_RWMASK: u64 = 0x0F00_800F;
+
+// WatchLo: physical address (bits 31-3) and access kinds to watch
+const WATCHLO_PADDR_MASK: u64 = 0xFFFF_FFF8;
+const WATCHLO_R: u64 = 1 << 1;
+const WATCHLO_W: u64 = 1 << 0;
+
+const TAGLO_RWMASK: u64 = 0x0FFF_FFC0;
+
+// Registers shown in register dumps, with their index
+static DUMP_REGS: [(&'static str, usize); 20] = [
+    ("Index", 0),
+    ("EntryLo0", 2),
+    ("EntryLo1", 3),
+    ("Context", 4),
+    ("PageMask", 5),
+    ("Wired", 6),
+    ("BadVAddr", 8),
+    ("Count", 9),
+    ("EntryHi", 10),
+    ("Compare", 11),
+    ("Status", 12),
+    ("Cause", 13),
+    ("EPC", 14),
+    ("PRId", 15),
+    ("Config", 16),
+    ("LLAddr", 17),
+    ("WatchLo", 18),
+    ("WatchHi", 19),
+    ("TagLo", 28),
+    ("ErrorEPC", 30),
+];
+
+// VR4300 primary caches: 16KB instruction cache with 32-byte lines, 8KB data
+// cache with 16-byte lines.
+const ICACHE_SIZE: usize = 16 * 1024;
+const ICACHE_LINE_SIZE: usize = 32;
+const DCACHE_SIZE: usize = 8 * 1024;
+const DCACHE_LINE_SIZE: usize = 16;
+
+// The timer interrupt is connected to IP7.
+const TIMER_INT_LINE: usize = 7;
+
+pub struct Cp0 {
+    reg_index: u64,
+    reg_entry_lo0: u64,
+    reg_entry_lo1: u64,
+    reg_context: u64,
+    reg_page_mask: u64,
+    reg_wired: u64,
+    reg_entry_hi: u64,
+    reg_status: u64,
+    reg_cause: u64,
+    reg_epc: u64,
+    reg_error_epc: u64,
+    reg_badvaddr: u64,
+    reg_count: u32,
+    reg_compare: u32,
+    reg_config: u64,
+    reg_lladdr: u64,
+    reg_watch_lo: u64,
+    reg_watch_hi: u64,
+    reg_tag_lo: u64,
+
+    // CPU clock at which reg_count was last updated. Count is incremented
+    // every other CPU cycle.
+    count_clock: i64,
+
+    tlb: Tlb,
+    // Set when the last translation missed the TLB, so that the exception
+    // is taken through the TLB refill vector.
+    tlb_refill: bool,
+
+    icache: Cache,
+    dcache: Cache,
+
+    // Unimplemented registers accessed so far, as (reg, write)
+    unimpl_seen: RefCell<BTreeSet<(usize, bool)>>,
+
+    logger: slog::Logger,
+}
+
+impl Cp0 {
+    pub fn new(logger: slog::Logger) -> Box<Cp0> {
+        Box::new(Cp0 {
+            reg_index: 0,
+            reg_entry_lo0: 0,
+            reg_entry_lo1: 0,
+            reg_context: 0,
+            reg_page_mask: 0,
+            reg_wired: 0,
+            reg_entry_hi: 0,
+            reg_status: 0,
+            reg_cause: 0,
+            reg_epc: 0,
+            reg_error_epc: 0,
+            reg_badvaddr: 0,
+            reg_count: 0,
+            reg_compare: 0,
+            reg_config: CONFIG_RESET,
+            reg_lladdr: 0,
+            reg_watch_lo: 0,
+            reg_watch_hi: 0,
+            reg_tag_lo: 0,
+            count_clock: 0,
+            tlb: Tlb::new(),
+            tlb_refill: false,
+            icache: Cache::new(ICACHE_SIZE, ICACHE_LINE_SIZE),
+            dcache: Cache::new(DCACHE_SIZE, DCACHE_LINE_SIZE),
+            unimpl_seen: RefCell::new(BTreeSet::new()),
+            logger: logger,
+        })
+    }
+
+    // Bring Count up to date with the CPU clock, raising the timer interrupt
+    // if it reached Compare in the meantime.
+    fn tick(&mut self, clock: i64) {
+        let ticks = (clock - self.count_clock) / 2;
+        if ticks <= 0 {
+            return;
+        }
+        let old = self.reg_count;
+        self.reg_count = old.wrapping_add(ticks as u32);
+        self.count_clock += ticks * 2;
+
+        // Compare is in (old, new], handling wrap-around
+        let distance = self.reg_compare.wrapping_sub(old).wrapping_sub(1) as i64;
+        if distance < ticks {
+            self.set_int_line(TIMER_INT_LINE, true);
+        }
+    }
+
+    // Random decrements every cycle, from 31 down to Wired.
+    fn random(&self, clock: i64) -> u64 {
+        let wired = self.reg_wired.min(TLB_ENTRIES as u64 - 1);
+        TLB_ENTRIES as u64 - 1 - (clock as u64 % (TLB_ENTRIES as u64 - wired))
+    }
+
+    // Log accesses to an unimplemented register only once, as programs
+    // tend to poll them.
+    fn warn_unimpl(&self, idx: usize, write: bool) {
+        if self.unimpl_seen.borrow_mut().insert((idx, write)) {
+            warn!(self.logger, "unimplemented COP0 register"; "reg" => idx, "write" => write);
+        }
+    }
+
+    // Value of a register as of the given clock, without side effects: Count
+    // is as of its last update.
+    fn peek_reg(&self, idx: usize, clock: i64) -> Option<u64> {
+        Some(match idx {
+            0 => self.reg_index,
+            1 => self.random(clock),
+            2 => self.reg_entry_lo0,
+            3 => self.reg_entry_lo1,
+            4 => self.reg_context,
+            5 => self.reg_page_mask,
+            6 => self.reg_wired,
+            8 => self.reg_badvaddr,
+            9 => self.reg_count as u64,
+            10 => self.reg_entry_hi,
+            11 => self.reg_compare as u64,
+            12 => self.reg_status,
+            13 => self.reg_cause,
+            14 => self.reg_epc,
+            15 => PRID,
+            16 => self.reg_config,
+            17 => self.reg_lladdr,
+            18 => self.reg_watch_lo,
+            19 => self.reg_watch_hi,
+            28 => self.reg_tag_lo,
+            29 => 0, // TagHi is unused (no secondary cache)
+            30 => self.reg_error_epc,
+            _ => return None,
+        })
+    }
+
+    fn read_reg(&mut self, idx: usize, clock: i64) -> Option<u64> {
+        if idx == 9 {
+            self.tick(clock);
+        }
+        self.peek_reg(idx, clock)
+    }
+
+    fn write_reg(&mut self, idx: usize, val: u64, clock: i64) -> bool {
+        match idx {
+            0 => self.reg_index = (self.reg_index & INDEX_PROBE_FAILURE) | (val & 0x3F),
+            1 => {} // Random is read-only
+            2 => self.reg_entry_lo0 = val & ENTRYLO_RWMASK,
+            3 => self.reg_entry_lo1 = val & ENTRYLO_RWMASK,
+            4 => {
+                // Only PTEBase is writable
+                self.reg_context = (self.reg_context & CONTEXT_BADVPN2_MASK)
+                    | (val & !(CONTEXT_BADVPN2_MASK | 0xF))
+            }
+            5 => self.reg_page_mask = val & PAGEMASK_RWMASK,
+            6 => self.reg_wired = val & 0x3F,
+            8 => self.reg_badvaddr = val,
+            9 => {
+                self.tick(clock);
+                self.reg_count = val as u32;
+            }
+            10 => self.reg_entry_hi = val & ENTRYHI_RWMASK,
+            11 => {
+                // Writing Compare acknowledges the timer interrupt
+                self.tick(clock);
+                self.reg_compare = val as u32;
+                self.set_int_line(TIMER_INT_LINE, false);
+            }
+            12 => self.reg_status = val,
+            13 => {
+                // Only the software interrupt bits (IP0/IP1) are writable
+                self.set_int_line(0, val & (1 << 8) != 0);
+                self.set_int_line(1, val & (1 << 9) != 0);
+            }
+            14 => self.reg_epc = val,
+            15 => {} // PRId is read-only
+            16 => self.reg_config = (self.reg_config & !CONFIG_RWMASK) | (val & CONFIG_RWMASK),
+            17 => self.reg_lladdr = val & 0xFFFF_FFFF,
+            18 => self.reg_watch_lo = val & (WATCHLO_PADDR_MASK | WATCHLO_R | WATCHLO_W),
+            19 => self.reg_watch_hi = val & 0xF,
+            28 => self.reg_tag_lo = val & TAGLO_RWMASK,
+            29 => {}
+            30 => self.reg_error_epc = val,
+            _ => return false,
+        }
+        true
+    }
+
+    fn tlb_read(&mut self, idx: usize) {
+        let e = self.tlb.entry(idx);
+        self.reg_page_mask = e.page_mask as u64;
+        self.reg_entry_hi = e.entry_hi;
+        self.reg_entry_lo0 = e.entry_lo[0] as u64;
+        self.reg_entry_lo1 = e.entry_lo[1] as u64;
+    }
+
+    fn tlb_write(&mut self, idx: usize) {
+        self.tlb.set_entry(
+            idx,
+            TlbEntry {
+                page_mask: self.reg_page_mask as u32,
+                entry_hi: self.reg_entry_hi,
+                entry_lo: [self.reg_entry_lo0 as u32, self.reg_entry_lo1 as u32],
+            },
+        );
+    }
+
+    fn tlb_probe(&mut self) {
+        self.reg_index = match self.tlb.probe(self.reg_entry_hi) {
+            Some(idx) => idx as u64,
+            None => INDEX_PROBE_FAILURE,
+        };
+    }
+
+    // Operating mode and addressing mode, as selected by Status. Exception
+    // handlers always run in kernel mode.
+    fn addr_space(&self) -> AddrSpace {
+        let mode = if self.reg_status & (STATUS_EXL | STATUS_ERL) != 0 {
+            Mode::Kernel
+        } else {
+            match (self.reg_status >> STATUS_KSU_SHIFT) & 3 {
+                0 => Mode::Kernel,
+                1 => Mode::Supervisor,
+                _ => Mode::User,
+            }
+        };
+        let wide = match mode {
+            Mode::Kernel => STATUS_KX,
+            Mode::Supervisor => STATUS_SX,
+            Mode::User => STATUS_UX,
+        };
+        AddrSpace {
+            mode,
+            wide: self.reg_status & wide != 0,
+            erl: self.reg_status & STATUS_ERL != 0,
+        }
+    }
+
+    fn bad_vaddr(&mut self, vaddr: u64, acc: MemAccess) -> Exception {
+        self.reg_badvaddr = vaddr;
+        match acc {
+            MemAccess::Write => Exception::ADES,
+            _ => Exception::ADEL,
+        }
+    }
+
+    // Record the faulting address of a failed TLB translation in BadVAddr,
+    // Context and EntryHi, as expected by the exception handler.
+    fn tlb_exception(&mut self, vaddr: u64, acc: MemAccess, err: TlbError) -> Exception {
+        let vpn2 = vaddr & !0x1FFF;
+        self.reg_badvaddr = vaddr;
+        self.reg_context =
+            (self.reg_context & !CONTEXT_BADVPN2_MASK) | ((vpn2 >> 9) & CONTEXT_BADVPN2_MASK);
+        self.reg_entry_hi = (vpn2 & ENTRYHI_RWMASK) | (self.reg_entry_hi & 0xFF);
+        self.tlb_refill = err == TlbError::Miss;
+        match (err, acc) {
+            (TlbError::Modified, _) => Exception::MOD,
+            (_, MemAccess::Write) => Exception::TLBS,
+            _ => Exception::TLBL,
+        }
+    }
+
+    // Check if a load or store hits the doubleword watched by WatchLo/WatchHi.
+    // Physical addresses are 32-bit, so WatchHi must be zero to match. Watch
+    // exceptions are not raised while handling another exception or an error
+    // (EXL or ERL set).
+    fn watch_hit(&self, paddr: u32, acc: MemAccess) -> bool {
+        let kind = match acc {
+            MemAccess::Read => WATCHLO_R,
+            MemAccess::Write => WATCHLO_W,
+            MemAccess::Fetch => return false,
+        };
+        self.reg_watch_lo & kind != 0
+            && self.reg_watch_hi == 0
+            && self.reg_status & (STATUS_EXL | STATUS_ERL) == 0
+            && paddr as u64 & WATCHLO_PADDR_MASK == self.reg_watch_lo & WATCHLO_PADDR_MASK
+    }
+
+    // Translate an effective virtual address into a physical address, and
+    // tell whether it is accessed through the caches. The TLB only matches
+    // the low 32 bits of addresses in the 64-bit mapped segments; mapped
+    // pages are assumed to be cached.
+    fn translate(&mut self, vaddr: u64, acc: MemAccess) -> Result<(u32, bool), Exception> {
+        match self.addr_space().decode(vaddr) {
+            Some(Segment::Unmapped { paddr, cached }) => Ok((paddr, cached)),
+            Some(Segment::Mapped) => {
+                let asid = self.reg_entry_hi as u8;
+                let write = acc == MemAccess::Write;
+                self.tlb
+                    .translate(vaddr as u32, asid, write)
+                    .map(|paddr| (paddr, true))
+                    .map_err(|err| self.tlb_exception(vaddr, acc, err))
+            }
+            None => Err(self.bad_vaddr(vaddr, acc)),
+        }
+    }
+
+    // Execute a CACHE operation on a primary cache (the VR4300 has no
+    // secondary cache). Index operations select the line with the virtual
+    // address; hit operations also check that it holds the physical address.
+    fn cache_line_op(&mut self, op: u32, vaddr: u64, paddr: u32) {
+        let cache = match op & 3 {
+            0 => &mut self.icache,
+            1 => &mut self.dcache,
+            _ => return,
+        };
+        match op {
+            // Index Invalidate, Index Write Back Invalidate
+            0x00 | 0x01 => *cache.line_mut(vaddr) = CacheLine::default(),
+            // Index Load Tag
+            0x04 | 0x05 => self.reg_tag_lo = cache.line(vaddr).tag_lo(),
+            // Index Store Tag
+            0x08 | 0x09 => *cache.line_mut(vaddr) = CacheLine::from_tag_lo(self.reg_tag_lo),
+            // Create Dirty Exclusive
+            0x0D => cache.fill(vaddr, paddr).dirty = true,
+            // Hit Invalidate, Hit Write Back Invalidate
+            0x10 | 0x11 | 0x15 => {
+                if let Some(line) = cache.hit(vaddr, paddr) {
+                    *line = CacheLine::default();
+                }
+            }
+            // Fill
+            0x14 => {
+                cache.fill(vaddr, paddr);
+            }
+            // Hit Write Back
+            0x18 | 0x19 => {
+                if let Some(line) = cache.hit(vaddr, paddr) {
+                    line.dirty = false;
+                }
+            }
+            _ => warn!(self.logger, "unimplemented CACHE operation"; "op" => op),
+        }
+    }
+}
+
+impl Cop0 for Cp0 {
+    fn update(&mut self, ctx: &CpuContext) {
+        self.tick(ctx.clock);
+    }
+
+    // Count reaches Compare after this many ticks (a full wrap-around, if
+    // it is already there).
+    fn next_interrupt(&self, _ctx: &CpuContext) -> Option<i64> {
+        let ticks = match self.reg_compare.wrapping_sub(self.reg_count) {
+            0 => 1 << 32,
+            n => n as i64,
+        };
+        Some(self.count_clock + ticks * 2)
+    }
+
+    // The interrupt lines are reflected in the IP bits of the Cause register.
+    // Lines 0 and 1 are the software interrupts, set by writing to Cause;
+    // lines 2-7 are connected to external hardware.
+    fn set_int_line(&mut self, line: usize, stat: bool) {
+        let bit = 1 << (8 + line);
+        if stat {
+            self.reg_cause |= bit;
+        } else {
+            self.reg_cause &= !bit;
+        }
+    }
+
+    fn pending_int(&self) -> bool {
+        // Interrupts are enabled only when IE=1 and outside exception handlers;
+        // each line is then masked by the corresponding IM bit in Status.
+        self.reg_status & (STATUS_IE | STATUS_EXL | STATUS_ERL) == STATUS_IE
+            && self.reg_cause & self.reg_status & CAUSE_IP_MASK != 0
+    }
+
+    // Accesses through cached segments update the state of the cache lines.
+    fn translate_addr(&mut self, vaddr: u64, acc: MemAccess) -> Result<u32, Exception> {
+        let vaddr = self.addr_space().effective(vaddr);
+        if acc == MemAccess::Fetch && vaddr & 3 != 0 {
+            return Err(self.bad_vaddr(vaddr, acc));
+        }
+        let (paddr, cached) = self.translate(vaddr, acc)?;
+        if self.watch_hit(paddr, acc) {
+            return Err(Exception::WATCH);
+        }
+        if cached {
+            match acc {
+                MemAccess::Fetch => self.icache.access(vaddr, paddr, false),
+                MemAccess::Read => self.dcache.access(vaddr, paddr, false),
+                MemAccess::Write => self.dcache.access(vaddr, paddr, true),
+            }
+        }
+        Ok(paddr)
+    }
+
+    // Index operations do not translate the address, so they never raise
+    // TLB exceptions.
+    fn cache_op(&mut self, op: u32, vaddr: u64) -> Result<(), Exception> {
+        let vaddr = self.addr_space().effective(vaddr);
+        let paddr = match op >> 2 {
+            0...2 => 0,
+            _ => self.translate(vaddr, MemAccess::Read)?.0,
+        };
+        self.cache_line_op(op, vaddr, paddr);
+        Ok(())
+    }
+
+    fn address_error(&mut self, vaddr: u32, acc: MemAccess) -> Exception {
+        self.bad_vaddr(vaddr as i32 as i64 as u64, acc)
+    }
+
+    // COP0 is always usable in kernel mode, and in the other modes only if
+    // CU0 is set. COP1 (the FPU) is usable only if CU1 is set, in any mode.
+    // The VR4300 has no COP2 and COP3, so CU2-CU3 are not checked.
+    fn check_cop(&mut self, cop: usize) -> Result<(), Exception> {
+        let usable = match cop {
+            0 => self.reg_status & STATUS_CU0 != 0 || self.addr_space().mode == Mode::Kernel,
+            1 => self.reg_status & STATUS_CU1 != 0,
+            _ => true,
+        };
+        if usable {
+            return Ok(());
+        }
+        self.reg_cause &= !CAUSE_CE_MASK;
+        self.reg_cause |= (cop as u64) << CAUSE_CE_SHIFT;
+        Err(Exception::CPU)
+    }
+
+    fn load_linked(&mut self, paddr: u32) {
+        self.reg_lladdr = (paddr >> 4) as u64;
+    }
+
+    fn exception(&mut self, ctx: &mut CpuContext, exc: Exception) {
+        match exc {
+            Exception::RESET | Exception::SOFTRESET | Exception::NMI => {
+                // Resets and NMIs jump to the reset vector at error level:
+                // ErrorEPC holds the interrupted PC, where ERET returns.
+                // Status.SR tells a soft reset (or NMI) from a cold reset, and
+                // the TLB shutdown bit (TS) is cleared.
+                self.reg_error_epc = ctx.get_pc() as i32 as i64 as u64;
+                self.reg_status |= STATUS_ERL | STATUS_BEV;
+                self.reg_status &= !STATUS_TS;
+                if exc == Exception::RESET {
+                    self.reg_status &= !STATUS_SR;
+                } else {
+                    self.reg_status |= STATUS_SR;
+                }
+                ctx.set_pc(0xBFC0_0000);
+                ctx.tight_exit = true;
+            }
+            _ => {
+                // TLB misses outside of exception handlers use the
+                // dedicated refill vector.
+                let refill = self.tlb_refill && self.reg_status & STATUS_EXL == 0;
+                self.tlb_refill = false;
+
+                // The core moves the PC past the faulting instruction before
+                // raising the exception, while interrupts are taken before
+                // executing the instruction at PC. Exceptions in a delay slot
+                // point EPC to the branch, and set Cause.BD. If we are already
+                // handling an exception, EPC and BD are not updated.
+                if self.reg_status & STATUS_EXL == 0 {
+                    let pc = match (exc, ctx.delay_slot) {
+                        (Exception::INT, _) => ctx.get_pc(),
+                        (_, Some(branch)) => branch,
+                        _ => ctx.get_pc().wrapping_sub(4),
+                    };
+                    self.reg_epc = pc as i32 as i64 as u64;
+                    if ctx.delay_slot.is_some() && exc != Exception::INT {
+                        self.reg_cause |= CAUSE_BD;
+                    } else {
+                        self.reg_cause &= !CAUSE_BD;
+                    }
+                    self.reg_status |= STATUS_EXL;
+                }
+                self.reg_cause &= !CAUSE_EXCCODE_MASK;
+                self.reg_cause |= ((exc as u64) << 2) & CAUSE_EXCCODE_MASK;
+
+                let base = if self.reg_status & STATUS_BEV != 0 {
+                    0xBFC0_0200
+                } else {
+                    0x8000_0000
+                };
+                ctx.set_pc(base + if refill { 0 } else { 0x180 });
+                ctx.tight_exit = true;
+            }
+        }
+    }
+
+    fn unimplemented_regs(&self) -> Vec<(usize, bool)> {
+        self.unimpl_seen.borrow().iter().cloned().collect()
+    }
+
+    // Random depends on the clock, so it is not dumped; Count is as of the
+    // last update by the core.
+    fn dump_regs(&self) -> Vec<(&'static str, u64)> {
+        DUMP_REGS
+            .iter()
+            .map(|&(name, idx)| (name, self.reg(idx) as u64))
+            .collect()
+    }
+}
+
+struct C0op<'a> {
+    opcode: u32,
+    cop0: &'a mut Cp0,
+    cpu: &'a mut CpuContext,
+}
+
+impl<'a> C0op<'a> {
+    fn func(&self) -> usize {
+        ((self.opcode >> 21) & 0x1f) as usize
+    }
+    fn sel(&self) -> u32 {
+        self.opcode & 7
+    }
+    fn rt(&self) -> usize {
+        ((self.opcode >> 16) & 0x1f) as usize
+    }
+    fn rd(&self) -> usize {
+        ((self.opcode >> 11) & 0x1f) as usize
+    }
+    fn rt64(&self) -> u64 {
+        self.cpu.regs[self.rt()]
+    }
+    fn rt32(&self) -> u32 {
+        self.rt64() as u32
+    }
+}
+
+impl Cop for Cp0 {
+    fn reg(&self, idx: usize) -> u128 {
+        match self.peek_reg(idx, self.count_clock) {
+            Some(val) => val as u128,
+            None => {
+                self.warn_unimpl(idx, false);
+                0
+            }
+        }
+    }
+
+    fn set_reg(&mut self, idx: usize, val: u128) {
+        match idx {
+            0 => self.reg_index = val as u64,
+            2 => self.reg_entry_lo0 = val as u64,
+            3 => self.reg_entry_lo1 = val as u64,
+            4 => self.reg_context = val as u64,
+            5 => self.reg_page_mask = val as u64,
+            6 => self.reg_wired = val as u64,
+            8 => self.reg_badvaddr = val as u64,
+            9 => self.reg_count = val as u32,
+            10 => self.reg_entry_hi = val as u64,
+            11 => self.reg_compare = val as u32,
+            12 => self.reg_status = val as u64,
+            13 => self.reg_cause = val as u64,
+            14 => self.reg_epc = val as u64,
+            16 => self.reg_config = val as u64,
+            17 => self.reg_lladdr = val as u64,
+            18 => self.reg_watch_lo = val as u64,
+            19 => self.reg_watch_hi = val as u64,
+            28 => self.reg_tag_lo = val as u64,
+            30 => self.reg_error_epc = val as u64,
+            _ => self.warn_unimpl(idx, true),
+        }
+    }
+
+    fn op(&mut self, cpu: &mut CpuContext, opcode: u32, _bus: &mut CpuBus) {
+        let op = C0op {
+            opcode,
+            cpu,
+            cop0: self,
+        };
+        match op.func() {
+            0x00 | 0x01 => {
+                // MFC0 (sign-extended 32-bit) / DMFC0
+                let clock = op.cpu.clock;
+                match op.cop0.read_reg(op.rd(), clock) {
+                    Some(val) => {
+                        op.cpu.regs[op.rt()] = if op.func() == 0x00 {
+                            val as u32 as i32 as i64 as u64
+                        } else {
+                            val
+                        };
+                    }
+                    None => op.cop0.warn_unimpl(op.rd(), false),
+                }
+            }
+            0x04 | 0x05 => {
+                // MTC0 (sign-extended 32-bit) / DMTC0
+                let clock = op.cpu.clock;
+                let val = if op.func() == 0x04 {
+                    op.rt32() as i32 as i64 as u64
+                } else {
+                    op.rt64()
+                };
+                if op.sel() != 0 || !op.cop0.write_reg(op.rd(), val, clock) {
+                    op.cop0.warn_unimpl(op.rd(), true);
+                }
+                // Status and Cause may unmask a pending interrupt
+                op.cpu.tight_exit = true;
+                op.cpu.fr = op.cop0.reg_status & STATUS_FR != 0;
+            }
+            0x10 if op.opcode & 0x3F == 0x01 => {
+                // TLBR
+                let idx = op.cop0.reg_index as usize;
+                op.cop0.tlb_read(idx);
+            }
+            0x10 if op.opcode & 0x3F == 0x02 => {
+                // TLBWI
+                let idx = op.cop0.reg_index as usize;
+                op.cop0.tlb_write(idx);
+            }
+            0x10 if op.opcode & 0x3F == 0x06 => {
+                // TLBWR
+                let idx = op.cop0.random(op.cpu.clock) as usize;
+                op.cop0.tlb_write(idx);
+            }
+            0x10 if op.opcode & 0x3F == 0x08 => op.cop0.tlb_probe(), // TLBP
+            0x10 if op.opcode & 0x3F == 0x18 => {
+                // ERET: return from exception (no delay slot)
+                let pc = if op.cop0.reg_status & STATUS_ERL != 0 {
+                    op.cop0.reg_status &= !STATUS_ERL;
+                    op.cop0.reg_error_epc
+                } else {
+                    op.cop0.reg_status &= !STATUS_EXL;
+                    op.cop0.reg_epc
+                };
+                op.cpu.set_pc(pc as u32);
+                op.cpu.llbit = false;
+                op.cpu.tight_exit = true;
+            }
+            _ => op.cpu.unimplemented(&op.cop0.logger, op.opcode),
+        }
+    }
+}
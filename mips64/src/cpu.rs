@@ -0,0 +1,2322 @@
+extern crate emu;
+
+use self::emu::bus::be::{Bus, MemIoR};
+use self::emu::bus::MemInt;
+use self::emu::int::Numerics;
+use self::emu::sync;
+use super::blocks::BlockCache;
+use super::codewatch::CodeWatch;
+use super::disasm::{branch_target, disasm, reg_index};
+use super::idle::{self, IdleLoop};
+#[cfg(feature = "jit")]
+use super::jit::{BlockJit, Jit};
+use super::opstats::{opcode_kind, OpcodeStats};
+use super::stall::BusStall;
+use super::state::CpuState;
+use super::timing::{self, Timing};
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::rc::Rc;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::{Cell, RefCell};
+use slog;
+
+/// Cop is a MIPS64 coprocessor that can be installed within the core.
+pub trait Cop {
+    fn reg(&self, idx: usize) -> u128;
+    fn set_reg(&mut self, idx: usize, val: u128);
+
+    fn op(&mut self, cpu: &mut CpuContext, opcode: u32, bus: &mut CpuBus);
+
+    // Coprocessor loads and stores. The core computes the effective address
+    // (base + offset) and translates it into paddr before calling these.
+
+    fn lwc(&mut self, op: u32, _ctx: &CpuContext, paddr: u32, bus: &CpuBus) {
+        let rt = ((op >> 16) & 0x1f) as usize;
+        let val = bus.read::<u32>(paddr & !3) as u64;
+        self.set_reg(rt, val as u128);
+    }
+
+    fn ldc(&mut self, op: u32, _ctx: &CpuContext, paddr: u32, bus: &CpuBus) {
+        let rt = ((op >> 16) & 0x1f) as usize;
+        let val = bus.read::<u64>(paddr & !3) as u64;
+        self.set_reg(rt, val as u128);
+    }
+
+    fn swc(&mut self, op: u32, _ctx: &CpuContext, paddr: u32, bus: &CpuBus) {
+        let rt = ((op >> 16) & 0x1f) as usize;
+        let val = self.reg(rt) as u32;
+        bus.write::<u32>(paddr & !3, val);
+    }
+
+    fn sdc(&mut self, op: u32, _ctx: &CpuContext, paddr: u32, bus: &CpuBus) {
+        let rt = ((op >> 16) & 0x1f) as usize;
+        let val = self.reg(rt) as u64;
+        bus.write::<u64>(paddr & !3, val);
+    }
+}
+
+/// CpuBus gives coprocessor instructions controlled access to the bus of the
+/// core. Accesses use physical addresses: virtual addresses can be translated
+/// with translate_addr(), which goes through COP0 exactly like the core.
+pub struct CpuBus<'a> {
+    bus: &'a Rc<RefCell<Box<Bus>>>,
+    cop0: Option<&'a mut Box<dyn Cop0>>,
+    code_watch: &'a CodeWatch,
+    // Set when a write hit memory holding fetched code
+    code_written: Cell<bool>,
+}
+
+impl<'a> CpuBus<'a> {
+    pub(crate) fn new(
+        bus: &'a Rc<RefCell<Box<Bus>>>,
+        cop0: Option<&'a mut Box<dyn Cop0>>,
+        code_watch: &'a CodeWatch,
+    ) -> CpuBus<'a> {
+        CpuBus {
+            bus,
+            cop0,
+            code_watch,
+            code_written: Cell::new(false),
+        }
+    }
+
+    pub fn read<U: MemInt>(&self, paddr: u32) -> U {
+        self.bus.borrow().read::<U>(paddr)
+    }
+
+    /// Write into memory. Like the stores of the core, writes into memory
+    /// holding fetched code invalidate it.
+    pub fn write<U: MemInt>(&self, paddr: u32, val: U) {
+        self.bus.borrow().write::<U>(paddr, val);
+        if self.code_watch.invalidate(paddr, U::SIZE as u32) {
+            self.code_written.set(true);
+        }
+    }
+
+    /// Translate a virtual address into a physical address. COP0 is not
+    /// available to its own instructions, which only get the direct mapping
+    /// of the unmapped segments.
+    pub fn translate_addr(&mut self, vaddr: u64, acc: MemAccess) -> Result<u32, Exception> {
+        match self.cop0 {
+            Some(ref mut cop0) => cop0.translate_addr(vaddr, acc),
+            None => Ok(vaddr as u32 & 0x1FFF_FFFF),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Exception {
+    INT = 0x00,   // Interrupt
+    MOD = 0x01,   // TLB modification exception
+    TLBL = 0x02,  // TLB load/fetch
+    TLBS = 0x03,  // TLB store
+    ADEL = 0x04,  // Address error (load/fetch)
+    ADES = 0x05,  // Address error (store)
+    SYS = 0x08,   // Syscall
+    BP = 0x09,    // Breakpoint
+    RI = 0x0A,    // Reserved instruction
+    CPU = 0x0B,   // Coprocessor unusable
+    OV = 0x0C,    // Arithmetic overflow
+    TR = 0x0D,    // Trap
+    FPE = 0x0F,   // Floating-point exception
+    WATCH = 0x17, // Reference to the WatchLo/WatchHi address
+
+    // Special exceptions that are not specified in the Cause register
+    RESET = 0x100,
+    SOFTRESET = 0x101,
+    NMI = 0x102,
+}
+
+/// Kind of memory access being performed, used during address translation.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MemAccess {
+    Fetch,
+    Read,
+    Write,
+}
+
+/// A memory access performed by a load or store of the core, as reported
+/// by Cpu::step(). The physical address is aligned to the access size.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct StepAccess {
+    pub kind: MemAccess,
+    pub vaddr: u32,
+    pub paddr: u32,
+    pub size: usize,
+    pub val: u64,
+}
+
+/// Result of Cpu::step().
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StepResult {
+    /// Address of the executed instruction (or of the next instruction, if
+    /// none was executed).
+    pub pc: u32,
+    /// Executed opcode; None if the core is halted, an interrupt was taken,
+    /// or the fetch failed.
+    pub opcode: Option<u32>,
+    /// Whether the instruction was in a branch delay slot.
+    pub delay_slot: bool,
+    /// Exception raised by the instruction (or the interrupt taken).
+    pub exception: Option<Exception>,
+    /// Accesses performed by the core loads and stores (coprocessor loads
+    /// and stores are not included).
+    pub accesses: Vec<StepAccess>,
+    /// GPRs whose value changed, as (index, new value).
+    pub regs: Vec<(usize, u64)>,
+    /// New values of HI and LO, if either changed.
+    pub hi_lo: Option<(u64, u64)>,
+}
+
+struct Lines {
+    halt: bool,
+    single_step: bool,
+}
+
+/// Cop0 is a MIPS64 coprocessor #0, which (in addition to being a normal coprocessor)
+/// it is able to control execution of the core by triggering exceptions.
+pub trait Cop0: Cop {
+    /// Called by the core before checking for pending interrupts, to let the
+    /// coprocessor update its timers.
+    fn update(&mut self, _ctx: &CpuContext) {}
+
+    /// Set the status of an external interrupt line (IP0-IP7).
+    fn set_int_line(&mut self, _line: usize, _stat: bool) {}
+
+    /// Return the clock at which the coprocessor raises an interrupt by
+    /// itself (eg: a timer), if any. The core does not skip idle loops
+    /// past it.
+    fn next_interrupt(&self, _ctx: &CpuContext) -> Option<i64> {
+        None
+    }
+
+    /// Check if there's a pending interrupt. It is expected that if this
+    /// function returns true, Cop0::exception() is immediately called with
+    /// exc == Exception::Int.
+    fn pending_int(&self) -> bool;
+
+    /// Trigger the specified excepion.
+    fn exception(&mut self, ctx: &mut CpuContext, exc: Exception);
+
+    /// Translate a virtual address into a physical address. If the translation
+    /// fails, the coprocessor updates its internal state (eg: BadVAddr) and
+    /// returns the exception that must be raised by the core.
+    fn translate_addr(&mut self, vaddr: u64, _acc: MemAccess) -> Result<u32, Exception> {
+        Ok(vaddr as u32 & 0x1FFF_FFFF)
+    }
+
+    /// Notify an address error detected by the core (eg: fetching from an
+    /// address which is not backed by memory). Returns the exception to raise.
+    fn address_error(&mut self, _vaddr: u32, acc: MemAccess) -> Exception {
+        match acc {
+            MemAccess::Write => Exception::ADES,
+            _ => Exception::ADEL,
+        }
+    }
+
+    /// Check whether instructions of the specified coprocessor can be
+    /// executed in the current operating mode. Returns the exception to
+    /// raise if not.
+    fn check_cop(&mut self, _cop: usize) -> Result<(), Exception> {
+        Ok(())
+    }
+
+    /// Execute a CACHE instruction, with the operation (rt field) and the
+    /// virtual address. Returns the exception to raise if the address cannot
+    /// be translated.
+    fn cache_op(&mut self, _op: u32, _vaddr: u64) -> Result<(), Exception> {
+        Ok(())
+    }
+
+    /// Called by the core on LL/LLD with the physical address of the load,
+    /// which is recorded in LLAddr.
+    fn load_linked(&mut self, _paddr: u32) {}
+
+    /// Called after each step executed in single-step mode. The default
+    /// implementation halts the core, until the halt line is released.
+    fn single_step(&mut self, ctx: &mut CpuContext) {
+        ctx.set_halt_line(true);
+    }
+
+    /// Return the registers of the coprocessor as (name, value), for
+    /// register dumps. Unlike reading them through the core, this must not
+    /// have side effects.
+    fn dump_regs(&self) -> Vec<(&'static str, u64)> {
+        Vec::new()
+    }
+
+    /// Return the registers that were accessed but are not implemented by
+    /// the coprocessor, as (register, write).
+    fn unimplemented_regs(&self) -> Vec<(usize, bool)> {
+        Vec::new()
+    }
+}
+
+pub struct CpuContext {
+    pub regs: [u64; 32],
+    pub hi: u64,
+    pub lo: u64,
+    pub(crate) pc: u32,
+    // Target of the taken branch whose delay slot is executed next (any
+    // address, including 0).
+    pub(crate) branch_pc: Option<u32>,
+    // Set when a branch likely is not taken: its delay slot (still pending
+    // in branch_pc) is annulled, rather than executed.
+    annul: bool,
+    pub clock: i64,
+    pub tight_exit: bool,
+    lines: Lines,
+
+    // FPU register mode (Status.FR), mirrored here by COP0 so that the FPU
+    // can lay out its registers accordingly.
+    pub(crate) fr: bool,
+
+    // Set by LL/LLD, and cleared by exceptions (and ERET): SC/SCD only
+    // store if it is still set.
+    pub(crate) llbit: bool,
+
+    // Address of the branch whose delay slot is being executed, if any:
+    // exceptions raised there restart from the branch.
+    pub(crate) delay_slot: Option<u32>,
+
+    // Exception requested by a coprocessor instruction, raised by the core
+    // once the instruction completes.
+    pending_exc: Option<Exception>,
+
+    // In lenient mode, unimplemented opcodes are logged and executed as NOPs.
+    lenient: bool,
+    unimpl_seen: BTreeSet<u32>,
+    unimpl_ops: Vec<(u32, u32)>,
+
+    // Optional per-opcode execution statistics
+    stats: Option<OpcodeStats>,
+
+    // Timing profile, and GPR loaded by the last instruction (0 if none),
+    // for load-use interlocks
+    timing: Timing,
+    load_reg: usize,
+    // Multiplier of the cycles taken by each instruction
+    counter_factor: i64,
+}
+
+// Code is looked up on the bus once per 4KB page, the smallest TLB page:
+// consecutive instructions are fetched without translating their address
+// only up to the end of the page.
+const FETCH_PAGE_SIZE: u32 = 0x1000;
+
+pub struct Cpu {
+    ctx: CpuContext,
+
+    cop0: Option<Box<Cop0>>,
+    cop1: Option<Box<Cop>>,
+    cop2: Option<Box<Cop>>,
+    cop3: Option<Box<Cop>>,
+
+    logger: slog::Logger,
+    bus: Rc<RefCell<Box<Bus>>>,
+    until: i64,
+    exc_stats: BTreeMap<Exception, u64>,
+
+    // Last memory area code was fetched from, and its physical address:
+    // the base of the page, if the area covers it entirely.
+    last_fetch_addr: u32,
+    last_fetch_mem: MemIoR<u32>,
+    last_fetch_page: bool,
+
+    // Pages of code recently fetched, invalidated by writes into them; the
+    // fetch state above is reloaded when its generation changes.
+    code_watch: CodeWatch,
+    code_generation: u64,
+
+    // Stalls of the bus accesses made by the instruction being executed,
+    // if devices are allowed to stall the CPU
+    bus_stall: Option<BusStall>,
+
+    // Result of the instruction being executed by step()
+    step: Option<StepResult>,
+
+    decode: DecodeTable,
+
+    // Decoded blocks, when run() uses the cached engine
+    blocks: Option<BlockCache<Block>>,
+
+    // Compiler of hot blocks to native code, if enabled
+    #[cfg(feature = "jit")]
+    jit: Option<Jit>,
+
+    // Detection of idle loops, if they are skipped
+    idle: Option<IdleLoop>,
+}
+
+struct Mipsop<'a> {
+    opcode: u32,
+    cpu: &'a mut Cpu,
+}
+
+impl<'a> Mipsop<'a> {
+    fn op(&self) -> u32 {
+        self.opcode >> 26
+    }
+    fn special(&self) -> u32 {
+        self.opcode & 0x3f
+    }
+    // Effective address, as a 64-bit virtual address (COP0 decides how many
+    // bits are significant, according to the addressing mode).
+    fn ea(&self) -> u64 {
+        self.rs64().wrapping_add(self.sximm64() as u64)
+    }
+    fn sa(&self) -> usize {
+        ((self.opcode >> 6) & 0x1f) as usize
+    }
+    // PC is incremented before executing the opcode, so branch and jump
+    // targets are computed from the address of the delay slot.
+    fn btgt(&self) -> u32 {
+        self.cpu.ctx.pc.wrapping_add((self.sximm32() << 2) as u32)
+    }
+    fn jtgt(&self) -> u32 {
+        (self.cpu.ctx.pc & 0xF000_0000) | ((self.opcode & 0x03FF_FFFF) << 2)
+    }
+    fn rs(&self) -> usize {
+        ((self.opcode >> 21) & 0x1f) as usize
+    }
+    fn rt(&self) -> usize {
+        ((self.opcode >> 16) & 0x1f) as usize
+    }
+    fn rd(&self) -> usize {
+        ((self.opcode >> 11) & 0x1f) as usize
+    }
+    fn sximm32(&self) -> i32 {
+        (self.opcode & 0xffff) as i16 as i32
+    }
+    fn sximm64(&self) -> i64 {
+        (self.opcode & 0xffff) as i16 as i64
+    }
+    fn imm64(&self) -> u64 {
+        (self.opcode & 0xffff) as u64
+    }
+    fn rs64(&self) -> u64 {
+        self.cpu.ctx.regs[self.rs()]
+    }
+    fn rt64(&self) -> u64 {
+        self.cpu.ctx.regs[self.rt()]
+    }
+    fn irs64(&self) -> i64 {
+        self.rs64() as i64
+    }
+    fn irt64(&self) -> i64 {
+        self.rt64() as i64
+    }
+    fn rs32(&self) -> u32 {
+        self.rs64() as u32
+    }
+    fn rt32(&self) -> u32 {
+        self.rt64() as u32
+    }
+    fn irs32(&self) -> i32 {
+        self.rs64() as i32
+    }
+    fn irt32(&self) -> i32 {
+        self.rt64() as i32
+    }
+    fn load<U: MemInt>(&mut self) -> Result<U, Exception> {
+        let ea = self.ea();
+        self.cpu.read::<U>(ea)
+    }
+    fn store<U: MemInt>(&mut self, val: U) -> Result<(), Exception> {
+        let ea = self.ea();
+        self.cpu.write::<U>(ea, val)
+    }
+    fn load_linked<U: MemInt>(&mut self) -> Result<U, Exception> {
+        let ea = self.ea();
+        let paddr = self.cpu.translate_addr(ea, MemAccess::Read)?;
+        if let Some(ref mut cop0) = self.cpu.cop0 {
+            cop0.load_linked(paddr);
+        }
+        self.cpu.ctx.llbit = true;
+        let paddr = paddr & !(U::SIZE as u32 - 1);
+        let val = self.cpu.bus.borrow().read::<U>(paddr);
+        self.cpu.trace_access(MemAccess::Read, ea, paddr, val);
+        Ok(val)
+    }
+    // SC/SCD store only if the LLbit is set, and return whether they did
+    // (written to rt).
+    fn store_conditional<U: MemInt>(&mut self, val: U) -> Result<u64, Exception> {
+        let ea = self.ea();
+        let paddr = self.cpu.translate_addr(ea, MemAccess::Write)?;
+        if self.cpu.ctx.llbit {
+            let paddr = paddr & !(U::SIZE as u32 - 1);
+            self.cpu.write_paddr(ea, paddr, val);
+        }
+        Ok(self.cpu.ctx.llbit as u64)
+    }
+    // Unaligned accesses operate on the aligned word (or doubleword) containing
+    // the effective address. Memory is big-endian: the byte at the address is
+    // the most significant byte merged by LWL/SWL, and the least significant
+    // one merged by LWR/SWR. SWL/SWR are translated as stores, as they might
+    // fault on a write-protected page.
+    fn lwl(&mut self) -> Result<u32, Exception> {
+        let (vaddr, reg) = (self.ea(), self.rt32());
+        let addr = vaddr as u32;
+        let mem = self.cpu.read::<u32>(vaddr)?;
+        let shift = (addr & 3) * 8;
+        let mask = (1 << shift) - 1;
+        Ok((reg & mask) | ((mem << shift) & !mask))
+    }
+    fn lwr(&mut self) -> Result<u32, Exception> {
+        let (vaddr, reg) = (self.ea(), self.rt32());
+        let addr = vaddr as u32;
+        let mem = self.cpu.read::<u32>(vaddr)?;
+        let shift = (!addr & 3) * 8;
+        let mask = ((1u64 << (32 - shift)) - 1) as u32;
+        Ok((reg & !mask) | ((mem >> shift) & mask))
+    }
+    fn swl(&mut self) -> Result<(), Exception> {
+        let (vaddr, reg) = (self.ea(), self.rt32());
+        let addr = vaddr as u32;
+        let paddr = self.cpu.translate_addr(vaddr, MemAccess::Write)? & !3;
+        let mem = self.cpu.bus.borrow().read::<u32>(paddr);
+        let shift = (addr & 3) * 8;
+        let mask = ((1u64 << (32 - shift)) - 1) as u32;
+        let val = (mem & !mask) | ((reg >> shift) & mask);
+        self.cpu.write_paddr(vaddr, paddr, val);
+        Ok(())
+    }
+    fn swr(&mut self) -> Result<(), Exception> {
+        let (vaddr, reg) = (self.ea(), self.rt32());
+        let addr = vaddr as u32;
+        let paddr = self.cpu.translate_addr(vaddr, MemAccess::Write)? & !3;
+        let mem = self.cpu.bus.borrow().read::<u32>(paddr);
+        let shift = (!addr & 3) * 8;
+        let mask = (1 << shift) - 1;
+        let val = (mem & mask) | ((reg << shift) & !mask);
+        self.cpu.write_paddr(vaddr, paddr, val);
+        Ok(())
+    }
+    fn ldl(&mut self) -> Result<u64, Exception> {
+        let (vaddr, reg) = (self.ea(), self.rt64());
+        let addr = vaddr as u32;
+        let mem = self.cpu.read::<u64>(vaddr)?;
+        let shift = (addr & 7) * 8;
+        let mask = (1 << shift) - 1;
+        Ok((reg & mask) | ((mem << shift) & !mask))
+    }
+    fn ldr(&mut self) -> Result<u64, Exception> {
+        let (vaddr, reg) = (self.ea(), self.rt64());
+        let addr = vaddr as u32;
+        let mem = self.cpu.read::<u64>(vaddr)?;
+        let shift = (!addr & 7) * 8;
+        let mask = !0u64 >> shift;
+        Ok((reg & !mask) | ((mem >> shift) & mask))
+    }
+    fn sdl(&mut self) -> Result<(), Exception> {
+        let (vaddr, reg) = (self.ea(), self.rt64());
+        let addr = vaddr as u32;
+        let paddr = self.cpu.translate_addr(vaddr, MemAccess::Write)? & !7;
+        let mem = self.cpu.bus.borrow().read::<u64>(paddr);
+        let shift = (addr & 7) * 8;
+        let mask = !0u64 >> shift;
+        let val = (mem & !mask) | ((reg >> shift) & mask);
+        self.cpu.write_paddr(vaddr, paddr, val);
+        Ok(())
+    }
+    fn sdr(&mut self) -> Result<(), Exception> {
+        let (vaddr, reg) = (self.ea(), self.rt64());
+        let addr = vaddr as u32;
+        let paddr = self.cpu.translate_addr(vaddr, MemAccess::Write)? & !7;
+        let mem = self.cpu.bus.borrow().read::<u64>(paddr);
+        let shift = (!addr & 7) * 8;
+        let mask = (1 << shift) - 1;
+        let val = (mem & mask) | ((reg << shift) & !mask);
+        self.cpu.write_paddr(vaddr, paddr, val);
+        Ok(())
+    }
+    fn mrt64(&'a mut self) -> &'a mut u64 {
+        &mut self.cpu.ctx.regs[self.rt()]
+    }
+    fn mrd64(&'a mut self) -> &'a mut u64 {
+        &mut self.cpu.ctx.regs[self.rd()]
+    }
+    fn hex(&self) -> String {
+        format!("{:x}", self.opcode)
+    }
+}
+
+impl CpuContext {
+    #[inline]
+    pub fn branch(&mut self, cond: bool, tgt: u32, likely: bool) {
+        if cond {
+            self.branch_pc = Some(tgt);
+            self.tight_exit = true;
+        } else if likely {
+            // Branch likely not taken: the delay slot is annulled. The core
+            // skips it like a delay slot, without fetching it.
+            self.branch_pc = Some(self.pc.wrapping_add(4));
+            self.annul = true;
+            self.tight_exit = true;
+        }
+    }
+
+    /// While the halt line is asserted, the core does not execute any
+    /// instruction (but time still flows).
+    pub fn set_halt_line(&mut self, stat: bool) {
+        self.lines.halt = stat;
+        self.tight_exit = true;
+    }
+
+    /// While the single-step line is asserted, the core executes a single
+    /// instruction (or a branch with its delay slot) and then notifies COP0,
+    /// which normally halts it.
+    pub fn set_single_step_line(&mut self, stat: bool) {
+        self.lines.single_step = stat;
+        self.tight_exit = true;
+    }
+
+    /// Raise an exception from a coprocessor instruction (eg: a floating-point
+    /// exception). The core triggers it after the instruction completes.
+    pub fn trigger_exception(&mut self, exc: Exception) {
+        self.pending_exc = Some(exc);
+    }
+
+    pub fn halted(&self) -> bool {
+        self.lines.halt
+    }
+
+    pub fn set_pc(&mut self, pc: u32) {
+        self.pc = pc;
+        self.branch_pc = None;
+        self.annul = false;
+    }
+
+    pub fn get_pc(&self) -> u32 {
+        self.pc
+    }
+
+    /// Value of a GPR by name (eg: "a0", "sp", "r31"; see reg_index()).
+    pub fn reg_by_name(&self, name: &str) -> Option<u64> {
+        reg_index(name).map(|idx| self.regs[idx])
+    }
+
+    /// Target of the pending branch, if the next instruction is in its delay
+    /// slot (an annulled delay slot is skipped, so it is not reported).
+    pub fn pending_branch(&self) -> Option<u32> {
+        if self.annul {
+            None
+        } else {
+            self.branch_pc
+        }
+    }
+
+    // Cycles taken by an instruction that is about to be executed, including
+    // the stall caused by using the result of a load right after it.
+    fn cycles(&mut self, opcode: u32) -> i64 {
+        if self.timing == Timing::Simple {
+            return self.counter_factor;
+        }
+        let interlock = timing::reads_reg(opcode, self.load_reg);
+        self.load_reg = timing::load_target(opcode);
+        (self.timing.cycles(opcode) + interlock as i64) * self.counter_factor
+    }
+
+    /// Return the unimplemented opcodes found so far in lenient mode, as a list
+    /// of (pc, opcode), recording the first occurrence of each kind of instruction.
+    pub fn unimplemented_ops(&self) -> &[(u32, u32)] {
+        &self.unimpl_ops
+    }
+
+    /// Return the opcode statistics, if enabled.
+    pub fn opcode_stats(&self) -> Option<&OpcodeStats> {
+        self.stats.as_ref()
+    }
+
+    /// Report an unimplemented opcode. In lenient mode, the opcode is logged
+    /// (only the first time each kind of instruction is found) and execution
+    /// continues as if it was a NOP; otherwise, emulation is aborted.
+    pub fn unimplemented(&mut self, logger: &slog::Logger, opcode: u32) {
+        let pc = self.pc.wrapping_sub(4);
+        if !self.lenient {
+            panic!(
+                "unimplemented opcode: {} (op={}, pc={})",
+                disasm(opcode, pc),
+                opcode.hex(),
+                pc.hex()
+            );
+        }
+
+        if let Some(ref mut stats) = self.stats {
+            stats.record_unimplemented(opcode);
+        }
+
+        // Log each instruction kind only once
+        if self.unimpl_seen.insert(opcode_kind(opcode)) {
+            self.unimpl_ops.push((pc, opcode));
+            error!(logger, "unimplemented opcode, executed as NOP"; o!(
+                "pc" => pc.hex(),
+                "op" => opcode.hex(),
+                "disasm" => disasm(opcode, pc)));
+        }
+    }
+}
+
+macro_rules! branch {
+    ($op:ident, $cond:expr, $tgt:expr) => {{
+        branch!($op, $cond, $tgt, link(false), likely(false));
+    }};
+    ($op:ident, $cond:expr, $tgt:expr,link($link:expr)) => {{
+        branch!($op, $cond, $tgt, link($link), likely(true));
+    }};
+    ($op:ident, $cond:expr, $tgt:expr,likely($lkl:expr)) => {{
+        branch!($op, $cond, $tgt, link(false), likely($lkl));
+    }};
+    ($op:ident, $cond:expr, $tgt:expr,link($link:expr),likely($lkl:expr)) => {{
+        if $link {
+            $op.cpu.ctx.regs[31] = $op.cpu.ctx.pc.wrapping_add(4) as u64;
+        }
+        let (cond, tgt) = ($cond, $tgt);
+        $op.cpu.ctx.branch(cond, tgt, $lkl);
+    }};
+}
+
+macro_rules! check_overflow_add {
+    ($op:ident, $dest:expr, $reg1:expr, $reg2:expr) => {{
+        match $reg1.checked_add($reg2) {
+            Some(res) => $dest = res.sx64(),
+            None => $op.cpu.trap_overflow(),
+        }
+    }};
+}
+
+macro_rules! check_overflow_sub {
+    ($op:ident, $dest:expr, $reg1:expr, $reg2:expr) => {{
+        match $reg1.checked_sub($reg2) {
+            Some(res) => $dest = res.sx64(),
+            None => $op.cpu.trap_overflow(),
+        }
+    }};
+}
+
+macro_rules! trap {
+    ($op:ident, $cond:expr) => {{
+        if $cond {
+            $op.cpu.exception(Exception::TR);
+        }
+    }};
+}
+
+// Memory accesses can fail address translation: the exception is raised and
+// the destination register is left unmodified.
+macro_rules! load {
+    ($op:ident, $val:expr) => {{
+        match $val {
+            Ok(val) => *$op.mrt64() = val,
+            Err(exc) => $op.cpu.exception(exc),
+        }
+    }};
+}
+
+macro_rules! store {
+    ($op:ident, $res:expr) => {{
+        if let Err(exc) = $res {
+            $op.cpu.exception(exc);
+        }
+    }};
+    ($op:ident, $ty:ty, $val:expr) => {{
+        let val: $ty = $val;
+        store!($op, $op.store::<$ty>(val));
+    }};
+}
+
+macro_rules! if_cop {
+    ($op:ident, $cop:ident, $do:expr) => {{
+        match $op.cpu.$cop {
+            Some(ref mut $cop) => $do,
+            None => {
+                let pc = $op.cpu.ctx.pc;
+                let opcode = $op.opcode;
+                warn!($op.cpu.logger, "COP opcode without COP"; o!("pc" => pc.hex(), "op" => opcode.hex()));
+            }
+        }
+        $op.cpu.cop_exception();
+    }};
+}
+
+macro_rules! cop_index {
+    (cop0) => {
+        0
+    };
+    (cop1) => {
+        1
+    };
+    (cop2) => {
+        2
+    };
+    (cop3) => {
+        3
+    };
+}
+
+// Run a coprocessor instruction. COP0 instructions get a CpuBus without
+// address translation, as COP0 itself is busy executing them.
+macro_rules! cop_op {
+    ($op:ident, cop0) => {{
+        let opcode = $op.opcode;
+        if $op.cpu.cop_usable(0) {
+            if_cop!($op, cop0, {
+                let mut bus = CpuBus::new(&$op.cpu.bus, None, &$op.cpu.code_watch);
+                cop0.op(&mut $op.cpu.ctx, opcode, &mut bus)
+            })
+        }
+    }};
+    ($op:ident, $cop:ident) => {{
+        let opcode = $op.opcode;
+        if $op.cpu.cop_usable(cop_index!($cop)) {
+            if_cop!($op, $cop, {
+                let mut bus = CpuBus::new(&$op.cpu.bus, $op.cpu.cop0.as_mut(), &$op.cpu.code_watch);
+                $cop.op(&mut $op.cpu.ctx, opcode, &mut bus)
+            })
+        }
+    }};
+}
+
+// Coprocessor loads and stores check that the coprocessor is usable before
+// translating the address.
+macro_rules! cop_loadstore {
+    ($op:ident, $cop:ident, $func:ident, $acc:expr) => {{
+        if $op.cpu.cop_usable(cop_index!($cop)) {
+            let ea = $op.ea();
+            match $op.cpu.translate_addr(ea, $acc) {
+                Ok(paddr) => if_cop!($op, $cop, {
+                    let code_written = {
+                        let bus =
+                            CpuBus::new(&$op.cpu.bus, $op.cpu.cop0.as_mut(), &$op.cpu.code_watch);
+                        $cop.$func($op.opcode, &$op.cpu.ctx, paddr, &bus);
+                        bus.code_written.get()
+                    };
+                    // Self-modifying code (see Cpu::write_paddr)
+                    if code_written {
+                        $op.cpu.ctx.tight_exit = true;
+                    }
+                }),
+                Err(exc) => $op.cpu.exception(exc),
+            }
+        }
+    }};
+}
+
+// Handler of an instruction, as found in the decode tables.
+type OpFn = fn(&mut Mipsop);
+
+// Decode tables of the core: instructions are dispatched on the primary
+// opcode, and then on the function field (SPECIAL) or on the rt field
+// (REGIMM). Opcodes without a handler are unimplemented.
+struct DecodeTable {
+    op: [OpFn; 64],
+    special: [OpFn; 64],
+    regimm: [OpFn; 32],
+}
+
+impl DecodeTable {
+    fn new() -> DecodeTable {
+        let unimpl = insn::unimplemented as OpFn;
+        let mut t = DecodeTable {
+            op: [unimpl; 64],
+            special: [unimpl; 64],
+            regimm: [unimpl; 32],
+        };
+        t.op[0x00] = insn::special;
+        t.op[0x01] = insn::regimm;
+        t.op[0x02] = insn::j;
+        t.op[0x03] = insn::jal;
+        t.op[0x04] = insn::beq;
+        t.op[0x05] = insn::bne;
+        t.op[0x06] = insn::blez;
+        t.op[0x07] = insn::bgtz;
+        t.op[0x08] = insn::addi;
+        t.op[0x09] = insn::addiu;
+        t.op[0x0A] = insn::slti;
+        t.op[0x0B] = insn::sltiu;
+        t.op[0x0C] = insn::andi;
+        t.op[0x0D] = insn::ori;
+        t.op[0x0E] = insn::xori;
+        t.op[0x0F] = insn::lui;
+        t.op[0x10] = insn::cop0;
+        t.op[0x11] = insn::cop1;
+        t.op[0x12] = insn::cop2;
+        t.op[0x13] = insn::cop3;
+        t.op[0x14] = insn::beql;
+        t.op[0x15] = insn::bnel;
+        t.op[0x16] = insn::blezl;
+        t.op[0x17] = insn::bgtzl;
+        t.op[0x18] = insn::daddi;
+        t.op[0x19] = insn::daddiu;
+        t.op[0x1A] = insn::ldl;
+        t.op[0x1B] = insn::ldr;
+        t.op[0x20] = insn::lb;
+        t.op[0x21] = insn::lh;
+        t.op[0x22] = insn::lwl;
+        t.op[0x23] = insn::lw;
+        t.op[0x24] = insn::lbu;
+        t.op[0x25] = insn::lhu;
+        t.op[0x26] = insn::lwr;
+        t.op[0x27] = insn::lwu;
+        t.op[0x28] = insn::sb;
+        t.op[0x29] = insn::sh;
+        t.op[0x2A] = insn::swl;
+        t.op[0x2B] = insn::sw;
+        t.op[0x2C] = insn::sdl;
+        t.op[0x2D] = insn::sdr;
+        t.op[0x2E] = insn::swr;
+        t.op[0x2F] = insn::cache;
+        t.op[0x30] = insn::ll;
+        t.op[0x31] = insn::lwc1;
+        t.op[0x32] = insn::lwc2;
+        t.op[0x34] = insn::lld;
+        t.op[0x35] = insn::ldc1;
+        t.op[0x36] = insn::ldc2;
+        t.op[0x37] = insn::ld;
+        t.op[0x38] = insn::sc;
+        t.op[0x39] = insn::swc1;
+        t.op[0x3A] = insn::swc2;
+        t.op[0x3C] = insn::scd;
+        t.op[0x3D] = insn::sdc1;
+        t.op[0x3E] = insn::sdc2;
+        t.op[0x3F] = insn::sd;
+
+        t.special[0x00] = insn::sll;
+        t.special[0x02] = insn::srl;
+        t.special[0x03] = insn::sra;
+        t.special[0x04] = insn::sllv;
+        t.special[0x06] = insn::srlv;
+        t.special[0x07] = insn::srav;
+        t.special[0x08] = insn::jr;
+        t.special[0x09] = insn::jalr;
+        t.special[0x0C] = insn::syscall;
+        t.special[0x0D] = insn::brk;
+        t.special[0x0F] = insn::sync;
+        t.special[0x10] = insn::mfhi;
+        t.special[0x11] = insn::mthi;
+        t.special[0x12] = insn::mflo;
+        t.special[0x13] = insn::mtlo;
+        t.special[0x14] = insn::dsllv;
+        t.special[0x16] = insn::dsrlv;
+        t.special[0x17] = insn::dsrav;
+        t.special[0x18] = insn::mult;
+        t.special[0x19] = insn::multu;
+        t.special[0x1A] = insn::div;
+        t.special[0x1B] = insn::divu;
+        t.special[0x1C] = insn::dmult;
+        t.special[0x1D] = insn::dmultu;
+        t.special[0x1E] = insn::ddiv;
+        t.special[0x1F] = insn::ddivu;
+        t.special[0x20] = insn::add;
+        t.special[0x21] = insn::addu;
+        t.special[0x22] = insn::sub;
+        t.special[0x23] = insn::subu;
+        t.special[0x24] = insn::and;
+        t.special[0x25] = insn::or;
+        t.special[0x26] = insn::xor;
+        t.special[0x27] = insn::nor;
+        t.special[0x2A] = insn::slt;
+        t.special[0x2B] = insn::sltu;
+        t.special[0x2C] = insn::dadd;
+        t.special[0x2D] = insn::daddu;
+        t.special[0x2E] = insn::dsub;
+        t.special[0x2F] = insn::dsubu;
+        t.special[0x30] = insn::tge;
+        t.special[0x31] = insn::tgeu;
+        t.special[0x32] = insn::tlt;
+        t.special[0x33] = insn::tltu;
+        t.special[0x34] = insn::teq;
+        t.special[0x36] = insn::tne;
+        t.special[0x38] = insn::dsll;
+        t.special[0x3A] = insn::dsrl;
+        t.special[0x3B] = insn::dsra;
+        t.special[0x3C] = insn::dsll32;
+        t.special[0x3E] = insn::dsrl32;
+        t.special[0x3F] = insn::dsra32;
+
+        t.regimm[0x00] = insn::bltz;
+        t.regimm[0x01] = insn::bgez;
+        t.regimm[0x02] = insn::bltzl;
+        t.regimm[0x03] = insn::bgezl;
+        t.regimm[0x08] = insn::tgei;
+        t.regimm[0x09] = insn::tgeiu;
+        t.regimm[0x0A] = insn::tlti;
+        t.regimm[0x0B] = insn::tltiu;
+        t.regimm[0x0C] = insn::teqi;
+        t.regimm[0x0E] = insn::tnei;
+        t.regimm[0x10] = insn::bltzal;
+        t.regimm[0x11] = insn::bgezal;
+        t.regimm[0x12] = insn::bltzall;
+        t.regimm[0x13] = insn::bgezall;
+
+        t
+    }
+
+    // Find the final handler of an opcode, going through the SPECIAL and
+    // REGIMM tables.
+    fn resolve(&self, opcode: u32) -> OpFn {
+        match opcode >> 26 {
+            0x00 => self.special[(opcode & 0x3f) as usize],
+            0x01 => self.regimm[((opcode >> 16) & 0x1f) as usize],
+            op => self.op[op as usize],
+        }
+    }
+}
+
+// Basic block decoded by the cached engine: its instructions, with their
+// handlers, and the state of its compilation to native code.
+struct Block {
+    ops: Vec<(u32, OpFn)>,
+    #[cfg(feature = "jit")]
+    jit: BlockJit,
+}
+
+// Decoded blocks end after the first branch or jump (and its delay slot):
+// JR and JALR are the only ones without a static target.
+fn ends_block(opcode: u32) -> bool {
+    branch_target(opcode, 0).is_some() || (opcode >> 26 == 0 && opcode & 0x3e == 0x08)
+}
+
+// Instruction handlers, named after their mnemonic.
+mod insn {
+    use super::*;
+
+    pub(super) fn unimplemented(op: &mut Mipsop) {
+        op.cpu.ctx.unimplemented(&op.cpu.logger, op.opcode);
+    }
+
+    pub(super) fn special(op: &mut Mipsop) {
+        let handler = op.cpu.decode.special[op.special() as usize];
+        handler(op);
+    }
+
+    pub(super) fn regimm(op: &mut Mipsop) {
+        let handler = op.cpu.decode.regimm[op.rt()];
+        handler(op);
+    }
+
+    pub(super) fn j(op: &mut Mipsop) {
+        branch!(op, true, op.jtgt(), link(false));
+    }
+
+    pub(super) fn jal(op: &mut Mipsop) {
+        branch!(op, true, op.jtgt(), link(true));
+    }
+
+    pub(super) fn beq(op: &mut Mipsop) {
+        branch!(op, op.rs64() == op.rt64(), op.btgt());
+    }
+
+    pub(super) fn bne(op: &mut Mipsop) {
+        branch!(op, op.rs64() != op.rt64(), op.btgt());
+    }
+
+    pub(super) fn blez(op: &mut Mipsop) {
+        branch!(op, op.irs64() <= 0, op.btgt());
+    }
+
+    pub(super) fn bgtz(op: &mut Mipsop) {
+        branch!(op, op.irs64() > 0, op.btgt());
+    }
+
+    pub(super) fn addi(op: &mut Mipsop) {
+        check_overflow_add!(op, *op.mrt64(), op.irs32(), op.sximm32());
+    }
+
+    pub(super) fn addiu(op: &mut Mipsop) {
+        *op.mrt64() = op.irs32().wrapping_add(op.sximm32()).sx64();
+    }
+
+    pub(super) fn slti(op: &mut Mipsop) {
+        *op.mrt64() = (op.irs64() < op.sximm64()) as u64;
+    }
+
+    pub(super) fn sltiu(op: &mut Mipsop) {
+        *op.mrt64() = (op.rs64() < op.sximm64() as u64) as u64;
+    }
+
+    pub(super) fn andi(op: &mut Mipsop) {
+        *op.mrt64() = op.rs64() & op.imm64();
+    }
+
+    pub(super) fn ori(op: &mut Mipsop) {
+        *op.mrt64() = op.rs64() | op.imm64();
+    }
+
+    pub(super) fn xori(op: &mut Mipsop) {
+        *op.mrt64() = op.rs64() ^ op.imm64();
+    }
+
+    pub(super) fn lui(op: &mut Mipsop) {
+        *op.mrt64() = (op.sximm32() << 16).sx64();
+    }
+
+    pub(super) fn cop0(op: &mut Mipsop) {
+        cop_op!(op, cop0);
+    }
+
+    pub(super) fn cop1(op: &mut Mipsop) {
+        cop_op!(op, cop1);
+    }
+
+    pub(super) fn cop2(op: &mut Mipsop) {
+        cop_op!(op, cop2);
+    }
+
+    pub(super) fn cop3(op: &mut Mipsop) {
+        cop_op!(op, cop3);
+    }
+
+    pub(super) fn beql(op: &mut Mipsop) {
+        branch!(op, op.rs64() == op.rt64(), op.btgt(), likely(true));
+    }
+
+    pub(super) fn bnel(op: &mut Mipsop) {
+        branch!(op, op.rs64() != op.rt64(), op.btgt(), likely(true));
+    }
+
+    pub(super) fn blezl(op: &mut Mipsop) {
+        branch!(op, op.irs64() <= 0, op.btgt(), likely(true));
+    }
+
+    pub(super) fn bgtzl(op: &mut Mipsop) {
+        branch!(op, op.irs64() > 0, op.btgt(), likely(true));
+    }
+
+    pub(super) fn daddi(op: &mut Mipsop) {
+        check_overflow_add!(op, *op.mrt64(), op.irs64(), op.sximm64());
+    }
+
+    pub(super) fn daddiu(op: &mut Mipsop) {
+        *op.mrt64() = op.irs64().wrapping_add(op.sximm64()) as u64;
+    }
+
+    pub(super) fn ldl(op: &mut Mipsop) {
+        load!(op, op.ldl());
+    }
+
+    pub(super) fn ldr(op: &mut Mipsop) {
+        load!(op, op.ldr());
+    }
+
+    pub(super) fn lb(op: &mut Mipsop) {
+        load!(op, op.load::<u8>().map(|v| v.sx64()));
+    }
+
+    pub(super) fn lh(op: &mut Mipsop) {
+        load!(op, op.load::<u16>().map(|v| v.sx64()));
+    }
+
+    pub(super) fn lwl(op: &mut Mipsop) {
+        load!(op, op.lwl().map(|v| v.sx64()));
+    }
+
+    pub(super) fn lw(op: &mut Mipsop) {
+        load!(op, op.load::<u32>().map(|v| v.sx64()));
+    }
+
+    pub(super) fn lbu(op: &mut Mipsop) {
+        load!(op, op.load::<u8>().map(|v| v as u64));
+    }
+
+    pub(super) fn lhu(op: &mut Mipsop) {
+        load!(op, op.load::<u16>().map(|v| v as u64));
+    }
+
+    pub(super) fn lwr(op: &mut Mipsop) {
+        load!(op, op.lwr().map(|v| v.sx64()));
+    }
+
+    pub(super) fn lwu(op: &mut Mipsop) {
+        load!(op, op.load::<u32>().map(|v| v as u64));
+    }
+
+    pub(super) fn sb(op: &mut Mipsop) {
+        store!(op, u8, op.rt32() as u8);
+    }
+
+    pub(super) fn sh(op: &mut Mipsop) {
+        store!(op, u16, op.rt32() as u16);
+    }
+
+    pub(super) fn swl(op: &mut Mipsop) {
+        store!(op, op.swl());
+    }
+
+    pub(super) fn sw(op: &mut Mipsop) {
+        store!(op, u32, op.rt32());
+    }
+
+    pub(super) fn sdl(op: &mut Mipsop) {
+        store!(op, op.sdl());
+    }
+
+    pub(super) fn sdr(op: &mut Mipsop) {
+        store!(op, op.sdr());
+    }
+
+    pub(super) fn swr(op: &mut Mipsop) {
+        store!(op, op.swr());
+    }
+
+    pub(super) fn cache(op: &mut Mipsop) {
+        if op.cpu.cop_usable(0) {
+            let (func, vaddr) = (op.rt() as u32, op.ea());
+            let res = match op.cpu.cop0 {
+                Some(ref mut cop0) => cop0.cache_op(func, vaddr),
+                None => Ok(()),
+            };
+            if let Err(exc) = res {
+                op.cpu.exception(exc);
+            }
+        }
+    }
+
+    pub(super) fn ll(op: &mut Mipsop) {
+        load!(op, op.load_linked::<u32>().map(|v| v.sx64()))
+    }
+
+    pub(super) fn lwc1(op: &mut Mipsop) {
+        cop_loadstore!(op, cop1, lwc, MemAccess::Read);
+    }
+
+    pub(super) fn lwc2(op: &mut Mipsop) {
+        cop_loadstore!(op, cop2, lwc, MemAccess::Read);
+    }
+
+    pub(super) fn lld(op: &mut Mipsop) {
+        load!(op, op.load_linked::<u64>());
+    }
+
+    pub(super) fn ldc1(op: &mut Mipsop) {
+        cop_loadstore!(op, cop1, ldc, MemAccess::Read);
+    }
+
+    pub(super) fn ldc2(op: &mut Mipsop) {
+        cop_loadstore!(op, cop2, ldc, MemAccess::Read);
+    }
+
+    pub(super) fn ld(op: &mut Mipsop) {
+        load!(op, op.load::<u64>());
+    }
+
+    pub(super) fn sc(op: &mut Mipsop) {
+        let val = op.rt32();
+        load!(op, op.store_conditional::<u32>(val))
+    }
+
+    pub(super) fn swc1(op: &mut Mipsop) {
+        cop_loadstore!(op, cop1, swc, MemAccess::Write);
+    }
+
+    pub(super) fn swc2(op: &mut Mipsop) {
+        cop_loadstore!(op, cop2, swc, MemAccess::Write);
+    }
+
+    pub(super) fn scd(op: &mut Mipsop) {
+        let val = op.rt64();
+        load!(op, op.store_conditional::<u64>(val))
+    }
+
+    pub(super) fn sdc1(op: &mut Mipsop) {
+        cop_loadstore!(op, cop1, sdc, MemAccess::Write);
+    }
+
+    pub(super) fn sdc2(op: &mut Mipsop) {
+        cop_loadstore!(op, cop2, sdc, MemAccess::Write);
+    }
+
+    pub(super) fn sd(op: &mut Mipsop) {
+        store!(op, u64, op.rt64());
+    }
+
+    pub(super) fn sll(op: &mut Mipsop) {
+        *op.mrd64() = (op.rt32() << op.sa()).sx64();
+    }
+
+    pub(super) fn srl(op: &mut Mipsop) {
+        *op.mrd64() = (op.rt32() >> op.sa()).sx64();
+    }
+
+    pub(super) fn sra(op: &mut Mipsop) {
+        *op.mrd64() = (op.irt32() >> op.sa()).sx64();
+    }
+
+    pub(super) fn sllv(op: &mut Mipsop) {
+        *op.mrd64() = (op.rt32() << (op.rs32() & 0x1F)).sx64();
+    }
+
+    pub(super) fn srlv(op: &mut Mipsop) {
+        *op.mrd64() = (op.rt32() >> (op.rs32() & 0x1F)).sx64();
+    }
+
+    pub(super) fn srav(op: &mut Mipsop) {
+        *op.mrd64() = (op.irt32() >> (op.rs32() & 0x1F)).sx64();
+    }
+
+    pub(super) fn jr(op: &mut Mipsop) {
+        branch!(op, true, op.rs32(), link(false));
+    }
+
+    pub(super) fn jalr(op: &mut Mipsop) {
+        branch!(op, true, op.rs32(), link(true));
+    }
+
+    pub(super) fn syscall(op: &mut Mipsop) {
+        op.cpu.exception(Exception::SYS);
+    }
+
+    pub(super) fn brk(op: &mut Mipsop) {
+        op.cpu.exception(Exception::BP);
+    }
+
+    pub(super) fn sync(_op: &mut Mipsop) {}
+
+    pub(super) fn mfhi(op: &mut Mipsop) {
+        *op.mrd64() = op.cpu.ctx.hi;
+    }
+
+    pub(super) fn mthi(op: &mut Mipsop) {
+        op.cpu.ctx.hi = op.rs64();
+    }
+
+    pub(super) fn mflo(op: &mut Mipsop) {
+        *op.mrd64() = op.cpu.ctx.lo;
+    }
+
+    pub(super) fn mtlo(op: &mut Mipsop) {
+        op.cpu.ctx.lo = op.rs64();
+    }
+
+    pub(super) fn dsllv(op: &mut Mipsop) {
+        *op.mrd64() = op.rt64() << (op.rs32() & 0x3F);
+    }
+
+    pub(super) fn dsrlv(op: &mut Mipsop) {
+        *op.mrd64() = op.rt64() >> (op.rs32() & 0x3F);
+    }
+
+    pub(super) fn dsrav(op: &mut Mipsop) {
+        *op.mrd64() = (op.irt64() >> (op.rs32() & 0x3F)) as u64;
+    }
+
+    pub(super) fn mult(op: &mut Mipsop) {
+        let (hi, lo) = (i64::wrapping_mul(op.rt32().isx64(), op.rs32().isx64()) as u64).hi_lo();
+        op.cpu.ctx.lo = lo;
+        op.cpu.ctx.hi = hi;
+    }
+
+    pub(super) fn multu(op: &mut Mipsop) {
+        let (hi, lo) = u64::wrapping_mul(op.rt32() as u64, op.rs32() as u64).hi_lo();
+        op.cpu.ctx.lo = lo;
+        op.cpu.ctx.hi = hi;
+    }
+
+    pub(super) fn div(op: &mut Mipsop) {
+        if op.irt32() == 0 {
+            // Division by zero doesn't trap: LO is -1 or 1
+            // depending on the sign of the dividend, HI is the
+            // dividend.
+            op.cpu.ctx.lo = if op.irs32() < 0 { 1 } else { -1i64 as u64 };
+            op.cpu.ctx.hi = op.rs32().sx64();
+        } else {
+            op.cpu.ctx.lo = op.irs32().wrapping_div(op.irt32()).sx64();
+            op.cpu.ctx.hi = op.irs32().wrapping_rem(op.irt32()).sx64();
+        }
+    }
+
+    pub(super) fn divu(op: &mut Mipsop) {
+        if op.rt32() == 0 {
+            op.cpu.ctx.lo = -1i64 as u64;
+            op.cpu.ctx.hi = op.rs32().sx64();
+        } else {
+            op.cpu.ctx.lo = op.rs32().wrapping_div(op.rt32()).sx64();
+            op.cpu.ctx.hi = op.rs32().wrapping_rem(op.rt32()).sx64();
+        }
+    }
+
+    pub(super) fn dmult(op: &mut Mipsop) {
+        let (hi, lo) = i128::wrapping_mul(op.irt64() as i128, op.irs64() as i128).hi_lo();
+        op.cpu.ctx.lo = lo as u64;
+        op.cpu.ctx.hi = hi as u64;
+    }
+
+    pub(super) fn dmultu(op: &mut Mipsop) {
+        let (hi, lo) = u128::wrapping_mul(op.rt64() as u128, op.rs64() as u128).hi_lo();
+        op.cpu.ctx.lo = lo as u64;
+        op.cpu.ctx.hi = hi as u64;
+    }
+
+    pub(super) fn ddiv(op: &mut Mipsop) {
+        if op.irt64() == 0 {
+            op.cpu.ctx.lo = if op.irs64() < 0 { 1 } else { -1i64 as u64 };
+            op.cpu.ctx.hi = op.rs64();
+        } else {
+            op.cpu.ctx.lo = op.irs64().wrapping_div(op.irt64()) as u64;
+            op.cpu.ctx.hi = op.irs64().wrapping_rem(op.irt64()) as u64;
+        }
+    }
+
+    pub(super) fn ddivu(op: &mut Mipsop) {
+        if op.rt64() == 0 {
+            op.cpu.ctx.lo = -1i64 as u64;
+            op.cpu.ctx.hi = op.rs64();
+        } else {
+            op.cpu.ctx.lo = op.rs64().wrapping_div(op.rt64());
+            op.cpu.ctx.hi = op.rs64().wrapping_rem(op.rt64());
+        }
+    }
+
+    pub(super) fn add(op: &mut Mipsop) {
+        check_overflow_add!(op, *op.mrd64(), op.irs32(), op.irt32());
+    }
+
+    pub(super) fn addu(op: &mut Mipsop) {
+        *op.mrd64() = op.rs32().wrapping_add(op.rt32()).sx64();
+    }
+
+    pub(super) fn sub(op: &mut Mipsop) {
+        check_overflow_sub!(op, *op.mrd64(), op.irs32(), op.irt32());
+    }
+
+    pub(super) fn subu(op: &mut Mipsop) {
+        *op.mrd64() = op.rs32().wrapping_sub(op.rt32()).sx64();
+    }
+
+    pub(super) fn and(op: &mut Mipsop) {
+        *op.mrd64() = op.rs64() & op.rt64();
+    }
+
+    pub(super) fn or(op: &mut Mipsop) {
+        *op.mrd64() = op.rs64() | op.rt64();
+    }
+
+    pub(super) fn xor(op: &mut Mipsop) {
+        *op.mrd64() = op.rs64() ^ op.rt64();
+    }
+
+    pub(super) fn nor(op: &mut Mipsop) {
+        *op.mrd64() = !(op.rs64() | op.rt64());
+    }
+
+    pub(super) fn slt(op: &mut Mipsop) {
+        *op.mrd64() = (op.irs64() < op.irt64()) as u64;
+    }
+
+    pub(super) fn sltu(op: &mut Mipsop) {
+        *op.mrd64() = (op.rs64() < op.rt64()) as u64;
+    }
+
+    pub(super) fn dadd(op: &mut Mipsop) {
+        check_overflow_add!(op, *op.mrd64(), op.irs64(), op.irt64());
+    }
+
+    pub(super) fn daddu(op: &mut Mipsop) {
+        *op.mrd64() = op.rs64().wrapping_add(op.rt64());
+    }
+
+    pub(super) fn dsub(op: &mut Mipsop) {
+        check_overflow_sub!(op, *op.mrd64(), op.irs64(), op.irt64());
+    }
+
+    pub(super) fn dsubu(op: &mut Mipsop) {
+        *op.mrd64() = op.rs64().wrapping_sub(op.rt64());
+    }
+
+    pub(super) fn tge(op: &mut Mipsop) {
+        trap!(op, op.irs64() >= op.irt64());
+    }
+
+    pub(super) fn tgeu(op: &mut Mipsop) {
+        trap!(op, op.rs64() >= op.rt64());
+    }
+
+    pub(super) fn tlt(op: &mut Mipsop) {
+        trap!(op, op.irs64() < op.irt64());
+    }
+
+    pub(super) fn tltu(op: &mut Mipsop) {
+        trap!(op, op.rs64() < op.rt64());
+    }
+
+    pub(super) fn teq(op: &mut Mipsop) {
+        trap!(op, op.rs64() == op.rt64());
+    }
+
+    pub(super) fn tne(op: &mut Mipsop) {
+        trap!(op, op.rs64() != op.rt64());
+    }
+
+    pub(super) fn dsll(op: &mut Mipsop) {
+        *op.mrd64() = op.rt64() << op.sa();
+    }
+
+    pub(super) fn dsrl(op: &mut Mipsop) {
+        *op.mrd64() = op.rt64() >> op.sa();
+    }
+
+    pub(super) fn dsra(op: &mut Mipsop) {
+        *op.mrd64() = (op.irt64() >> op.sa()) as u64;
+    }
+
+    pub(super) fn dsll32(op: &mut Mipsop) {
+        *op.mrd64() = op.rt64() << (op.sa() + 32);
+    }
+
+    pub(super) fn dsrl32(op: &mut Mipsop) {
+        *op.mrd64() = op.rt64() >> (op.sa() + 32);
+    }
+
+    pub(super) fn dsra32(op: &mut Mipsop) {
+        *op.mrd64() = (op.irt64() >> (op.sa() + 32)) as u64;
+    }
+
+    pub(super) fn bltz(op: &mut Mipsop) {
+        branch!(op, op.irs64() < 0, op.btgt(), link(false), likely(false));
+    }
+
+    pub(super) fn bgez(op: &mut Mipsop) {
+        branch!(op, op.irs64() >= 0, op.btgt(), link(false), likely(false));
+    }
+
+    pub(super) fn bltzl(op: &mut Mipsop) {
+        branch!(op, op.irs64() < 0, op.btgt(), link(false), likely(true));
+    }
+
+    pub(super) fn bgezl(op: &mut Mipsop) {
+        branch!(op, op.irs64() >= 0, op.btgt(), link(false), likely(true));
+    }
+
+    pub(super) fn tgei(op: &mut Mipsop) {
+        trap!(op, op.irs64() >= op.sximm64());
+    }
+
+    pub(super) fn tgeiu(op: &mut Mipsop) {
+        trap!(op, op.rs64() >= op.sximm64() as u64);
+    }
+
+    pub(super) fn tlti(op: &mut Mipsop) {
+        trap!(op, op.irs64() < op.sximm64());
+    }
+
+    pub(super) fn tltiu(op: &mut Mipsop) {
+        trap!(op, op.rs64() < op.sximm64() as u64);
+    }
+
+    pub(super) fn teqi(op: &mut Mipsop) {
+        trap!(op, op.irs64() == op.sximm64());
+    }
+
+    pub(super) fn tnei(op: &mut Mipsop) {
+        trap!(op, op.irs64() != op.sximm64());
+    }
+
+    pub(super) fn bltzal(op: &mut Mipsop) {
+        branch!(op, op.irs64() < 0, op.btgt(), link(true), likely(false));
+    }
+
+    pub(super) fn bgezal(op: &mut Mipsop) {
+        branch!(op, op.irs64() >= 0, op.btgt(), link(true), likely(false));
+    }
+
+    pub(super) fn bltzall(op: &mut Mipsop) {
+        branch!(op, op.irs64() < 0, op.btgt(), link(true), likely(true));
+    }
+
+    pub(super) fn bgezall(op: &mut Mipsop) {
+        branch!(op, op.irs64() >= 0, op.btgt(), link(true), likely(true));
+    }
+}
+
+impl Cpu {
+    pub fn new(logger: slog::Logger, bus: Rc<RefCell<Box<Bus>>>) -> Cpu {
+        return Cpu {
+            ctx: CpuContext {
+                regs: [0u64; 32],
+                hi: 0,
+                lo: 0,
+                pc: 0x1FC0_0000, // FIXME
+                branch_pc: None,
+                annul: false,
+                clock: 0,
+                tight_exit: false,
+                lines: Lines {
+                    halt: false,
+                    single_step: false,
+                },
+                pending_exc: None,
+                fr: false,
+                llbit: false,
+                delay_slot: None,
+                lenient: false,
+                unimpl_seen: BTreeSet::new(),
+                unimpl_ops: Vec::new(),
+                stats: None,
+                timing: Timing::Simple,
+                load_reg: 0,
+                counter_factor: 1,
+            },
+            bus: bus,
+            cop0: None,
+            cop1: None,
+            cop2: None,
+            cop3: None,
+            logger: logger,
+            until: 0,
+            exc_stats: BTreeMap::new(),
+            last_fetch_addr: 0xFFFF_FFFF,
+            last_fetch_mem: MemIoR::default(),
+            last_fetch_page: false,
+            code_watch: CodeWatch::new(),
+            code_generation: 0,
+            bus_stall: None,
+            step: None,
+            decode: DecodeTable::new(),
+            blocks: None,
+            #[cfg(feature = "jit")]
+            jit: None,
+            idle: None,
+        };
+    }
+
+    pub fn ctx(&self) -> &CpuContext {
+        &self.ctx
+    }
+
+    pub fn ctx_mut(&mut self) -> &mut CpuContext {
+        &mut self.ctx
+    }
+
+    pub fn set_cop0(&mut self, cop0: Box<dyn Cop0>) {
+        self.cop0 = Some(cop0);
+    }
+    pub fn set_cop1(&mut self, cop1: Box<dyn Cop>) {
+        self.cop1 = Some(cop1);
+    }
+    pub fn set_cop2(&mut self, cop2: Box<dyn Cop>) {
+        self.cop2 = Some(cop2);
+    }
+    pub fn set_cop3(&mut self, cop3: Box<dyn Cop>) {
+        self.cop3 = Some(cop3);
+    }
+    pub fn cop2(&mut self) -> Option<&mut Box<dyn Cop>> {
+        self.cop2.as_mut()
+    }
+
+    /// Share the tracking of the fetched code with the devices that write
+    /// into memory through DMA, so that they can invalidate it.
+    pub fn set_code_watch(&mut self, watch: CodeWatch) {
+        self.code_watch = watch;
+        self.last_fetch_addr = 0xFFFF_FFFF;
+    }
+
+    /// Let the devices on the bus stall the CPU while it waits for their
+    /// accesses to complete.
+    pub fn set_bus_stall(&mut self, stall: BusStall) {
+        self.bus_stall = Some(stall);
+    }
+
+    /// Enable or disable lenient mode, in which unimplemented opcodes are
+    /// logged and skipped instead of aborting emulation.
+    pub fn set_lenient(&mut self, lenient: bool) {
+        self.ctx.lenient = lenient;
+    }
+
+    /// Select how many cycles each instruction takes.
+    pub fn set_timing(&mut self, timing: Timing) {
+        self.ctx.timing = timing;
+        self.ctx.load_reg = 0;
+    }
+
+    /// Multiply the cycles taken by each instruction (the "counter factor"
+    /// of other emulators): fewer instructions are run in the same time,
+    /// while COP0 Count still follows the clock.
+    pub fn set_counter_factor(&mut self, factor: u32) {
+        self.ctx.counter_factor = factor.max(1) as i64;
+    }
+
+    /// Select the execution engine of run(): the interpreter decodes each
+    /// instruction every time it is executed, while the cached engine
+    /// decodes basic blocks once, and then executes their decoded form until
+    /// the memory they were fetched from is written. Disabling it discards
+    /// the decoded blocks.
+    pub fn set_block_cache(&mut self, enable: bool) {
+        self.blocks = if enable {
+            Some(BlockCache::new())
+        } else {
+            None
+        };
+    }
+
+    /// Compile the hot blocks of the cached engine to native code, which is
+    /// then run instead of their decoded form (see Jit). Toggling it also
+    /// (re)enables the cached engine, discarding the decoded blocks.
+    #[cfg(feature = "jit")]
+    pub fn set_jit(&mut self, enable: bool) {
+        // Blocks point into the code generated by the previous compiler
+        self.set_block_cache(true);
+        self.jit = if enable { Some(Jit::new()) } else { None };
+    }
+
+    /// Skip idle loops (see IdleLoop): once the core is found spinning in
+    /// one, run() advances the clock to the next event (or COP0 interrupt)
+    /// without executing it.
+    pub fn set_idle_skip(&mut self, enable: bool) {
+        self.idle = if enable { Some(IdleLoop::new()) } else { None };
+    }
+
+    /// Number of cycles skipped in idle loops.
+    pub fn idle_cycles(&self) -> u64 {
+        self.idle.as_ref().map_or(0, |idle| idle.skipped())
+    }
+
+    /// Number of blocks decoded by the cached engine, and still valid.
+    pub fn cached_blocks(&self) -> usize {
+        self.blocks.as_ref().map_or(0, |blocks| blocks.len())
+    }
+
+    /// Enable or disable counting the executed opcodes (see OpcodeStats).
+    /// Disabling it discards the statistics collected so far.
+    pub fn set_opcode_stats(&mut self, enable: bool) {
+        self.ctx.stats = if enable {
+            Some(OpcodeStats::default())
+        } else {
+            None
+        };
+    }
+
+    pub fn reset(&mut self) {
+        self.exception(Exception::RESET);
+    }
+
+    /// Raise a non-maskable interrupt, as done by the reset button: the core
+    /// restarts from the boot vector, in soft reset state.
+    pub fn nmi(&mut self) {
+        self.exception(Exception::NMI);
+    }
+
+    /// Set the status of an interrupt line of COP0 (eg: the RCP interrupt
+    /// on IP2, or the pre-NMI on IP4).
+    pub fn set_int_line(&mut self, line: usize, stat: bool) {
+        if let Some(ref mut cop0) = self.cop0 {
+            cop0.set_int_line(line, stat);
+        }
+        self.ctx.tight_exit = true;
+    }
+
+    /// Take a snapshot of the state of the core and of COP0.
+    pub fn state(&self) -> CpuState {
+        CpuState {
+            pc: self.ctx.pc,
+            branch_pc: self.ctx.pending_branch(),
+            regs: self.ctx.regs,
+            hi: self.ctx.hi,
+            lo: self.ctx.lo,
+            clock: self.ctx.clock,
+            halt: self.ctx.lines.halt,
+            single_step: self.ctx.lines.single_step,
+            cop0: match self.cop0 {
+                Some(ref cop0) => cop0.dump_regs(),
+                None => Vec::new(),
+            },
+        }
+    }
+
+    /// Return the COP0 registers that were accessed but are not implemented,
+    /// as (register, write).
+    pub fn unimplemented_cop0_regs(&self) -> Vec<(usize, bool)> {
+        match self.cop0 {
+            Some(ref cop0) => cop0.unimplemented_regs(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Return the number of exceptions raised so far, for each kind.
+    pub fn exception_stats(&self) -> &BTreeMap<Exception, u64> {
+        &self.exc_stats
+    }
+
+    fn exception(&mut self, exc: Exception) {
+        *self.exc_stats.entry(exc).or_insert(0) += 1;
+        if let Some(ref mut step) = self.step {
+            step.exception = Some(exc);
+        }
+        self.ctx.llbit = false;
+        if let Some(ref mut cop0) = self.cop0 {
+            cop0.exception(&mut self.ctx, exc);
+        }
+    }
+
+    // Raise the exception requested by the last coprocessor instruction, if any.
+    // Raise a coprocessor unusable exception if the coprocessor is not
+    // enabled in the current operating mode.
+    fn cop_usable(&mut self, cop: usize) -> bool {
+        let res = match self.cop0 {
+            Some(ref mut cop0) => cop0.check_cop(cop),
+            None => Ok(()),
+        };
+        match res {
+            Ok(()) => true,
+            Err(exc) => {
+                self.exception(exc);
+                false
+            }
+        }
+    }
+
+    fn cop_exception(&mut self) {
+        if let Some(exc) = self.ctx.pending_exc.take() {
+            self.exception(exc);
+        }
+    }
+
+    // Signed overflow in ADD, ADDI, SUB and their 64-bit versions: the
+    // destination register is left unmodified.
+    fn trap_overflow(&mut self) {
+        self.exception(Exception::OV);
+    }
+
+    fn op(&mut self, opcode: u32) {
+        let handler = self.decode.op[(opcode >> 26) as usize];
+        self.exec(opcode, handler);
+    }
+
+    // Execute an opcode through its handler, as found in the decode tables.
+    fn exec(&mut self, opcode: u32, handler: OpFn) {
+        self.ctx.clock += self.ctx.cycles(opcode);
+        if let Some(ref mut stats) = self.ctx.stats {
+            stats.record(opcode);
+        }
+        handler(&mut Mipsop { opcode, cpu: self });
+        if let Some(ref stall) = self.bus_stall {
+            self.ctx.clock += stall.take();
+        }
+        // r0 is hardwired to zero: instructions that target it discard
+        // their result.
+        self.ctx.regs[0] = 0;
+    }
+
+    fn translate_addr(&mut self, vaddr: u64, acc: MemAccess) -> Result<u32, Exception> {
+        match self.cop0 {
+            Some(ref mut cop0) => cop0.translate_addr(vaddr, acc),
+            None => Ok(vaddr as u32 & 0x1FFF_FFFF),
+        }
+    }
+
+    // Translate the address of an instruction, and return an iterator over
+    // the code that follows it, up to the end of its page (which is never
+    // empty). Branches within the page reuse the memory area found by the
+    // last lookup, to speed up hot loops.
+    fn fetch(&mut self, addr: u32) -> Result<impl Iterator<Item = u32>, Exception> {
+        let paddr = self.fetch_addr(addr)?;
+        Ok(self.fetch_iter(paddr))
+    }
+
+    // Translate the address of an instruction, and make sure that the
+    // memory area holding it is loaded into the fetch state.
+    fn fetch_addr(&mut self, addr: u32) -> Result<u32, Exception> {
+        let vaddr = addr as i32 as i64 as u64;
+        let paddr = self.translate_addr(vaddr, MemAccess::Fetch)? & !3;
+        let page = paddr & !(FETCH_PAGE_SIZE - 1);
+
+        // The memory area is looked up again if memory holding fetched code
+        // was written in the meantime.
+        let generation = self.code_watch.generation();
+        let base = if self.last_fetch_page { page } else { paddr };
+        if self.last_fetch_addr != base || self.code_generation != generation {
+            let bus = self.bus.borrow();
+            let mem = bus.fetch_read::<u32>(page);
+            // Pages not entirely backed by a single memory area (eg: PIF ROM
+            // and RAM) are looked up at each fetch.
+            self.last_fetch_page = mem
+                .mem()
+                .map_or(false, |m| m.len() >= FETCH_PAGE_SIZE as usize);
+            if self.last_fetch_page {
+                self.last_fetch_addr = page;
+                self.last_fetch_mem = mem;
+            } else {
+                self.last_fetch_addr = paddr;
+                self.last_fetch_mem = bus.fetch_read::<u32>(paddr);
+            }
+            self.code_generation = generation;
+            if self.code_watch.fetched(paddr) {
+                if let Some(ref mut blocks) = self.blocks {
+                    blocks.drop_page(paddr);
+                }
+            }
+        }
+
+        // Code can only be executed from memory areas
+        let offset = paddr - self.last_fetch_addr;
+        let fetchable = self
+            .last_fetch_mem
+            .mem()
+            .map_or(false, |m| m.len() >= offset as usize + 4);
+        if !fetchable {
+            return Err(match self.cop0 {
+                Some(ref mut cop0) => cop0.address_error(addr, MemAccess::Fetch),
+                None => Exception::ADEL,
+            });
+        }
+        Ok(paddr)
+    }
+
+    // Iterate over the code from a physical address (returned by
+    // fetch_addr) up to the end of its page.
+    fn fetch_iter(&self, paddr: u32) -> impl Iterator<Item = u32> {
+        let offset = paddr - self.last_fetch_addr;
+        let words = (FETCH_PAGE_SIZE - (paddr & (FETCH_PAGE_SIZE - 1))) / 4;
+        self.last_fetch_mem
+            .iter_at(offset)
+            .unwrap()
+            .take(words as usize)
+    }
+
+    // Return the decoded block starting at the specified address, decoding
+    // it if it is not cached: it goes up to the end of the page, or to the
+    // delay slot of the first branch.
+    fn fetch_block(&mut self, addr: u32) -> Result<Rc<Block>, Exception> {
+        let paddr = self.fetch_addr(addr)?;
+        let generation = self.code_generation;
+        if let Some(block) = self.blocks.as_mut().unwrap().get(paddr, generation) {
+            return Ok(block);
+        }
+
+        let mut ops = Vec::new();
+        let mut last = false;
+        for opcode in self.fetch_iter(paddr) {
+            ops.push((opcode, self.decode.resolve(opcode)));
+            if last {
+                break;
+            }
+            last = ends_block(opcode);
+        }
+        let block = Block {
+            ops,
+            #[cfg(feature = "jit")]
+            jit: BlockJit::default(),
+        };
+        Ok(self.blocks.as_mut().unwrap().insert(paddr, block))
+    }
+
+    // Run the native code compiled for the block at the current PC, if it
+    // got hot enough, and return the number of instructions it executed.
+    // Native code only covers instructions that take one cycle with the
+    // simple timing, and that neither access memory nor raise exceptions;
+    // it is skipped whenever the interpreter would stop within it.
+    #[cfg(feature = "jit")]
+    fn run_native(&mut self, block: &Block) -> usize {
+        let jit = match self.jit {
+            Some(ref mut jit) => jit,
+            None => return 0,
+        };
+        if self.ctx.timing != Timing::Simple || self.ctx.stats.is_some() {
+            return 0;
+        }
+        let native = match block.jit.enter(jit, block.ops.iter().map(|&(op, _)| op)) {
+            Some(native) => native,
+            None => return 0,
+        };
+        let len = native.len();
+        let cycles = len as i64 * self.ctx.counter_factor;
+        if self.ctx.clock + cycles >= self.until || self.ctx.lines.single_step {
+            return 0;
+        }
+        native.run(&mut self.ctx.regs);
+        self.ctx.pc = self.ctx.pc.wrapping_add(len as u32 * 4);
+        self.ctx.clock += cycles;
+        len
+    }
+
+    #[cfg(not(feature = "jit"))]
+    fn run_native(&mut self, _block: &Block) -> usize {
+        0
+    }
+
+    // Raise an exception caused by the instruction fetch at the current PC.
+    // PC is moved past the faulting instruction, so that the exception is
+    // seen by Cop0 exactly as if it was raised by the instruction itself.
+    fn fetch_exception(&mut self, exc: Exception) {
+        self.ctx.pc = self.ctx.pc.wrapping_add(4);
+        self.exception(exc);
+    }
+
+    fn read<U: MemInt>(&mut self, vaddr: u64) -> Result<U, Exception> {
+        let paddr = self.translate_addr(vaddr, MemAccess::Read)? & !(U::SIZE as u32 - 1);
+        let val = self.bus.borrow().read::<U>(paddr);
+        self.trace_access(MemAccess::Read, vaddr, paddr, val);
+        Ok(val)
+    }
+
+    fn write<U: MemInt>(&mut self, vaddr: u64, val: U) -> Result<(), Exception> {
+        let paddr = self.translate_addr(vaddr, MemAccess::Write)? & !(U::SIZE as u32 - 1);
+        self.write_paddr(vaddr, paddr, val);
+        Ok(())
+    }
+
+    // Perform a store of the core, once its address is translated. All the
+    // core stores go through here.
+    fn write_paddr<U: MemInt>(&mut self, vaddr: u64, paddr: u32, val: U) {
+        self.bus.borrow().write::<U>(paddr, val);
+        self.trace_access(MemAccess::Write, vaddr, paddr, val);
+
+        // Self-modifying code: leave the tight loop, so that the following
+        // instructions are fetched again.
+        if self.code_watch.invalidate(paddr, U::SIZE as u32) {
+            self.ctx.tight_exit = true;
+        }
+    }
+
+    // Record a memory access into the result of step().
+    fn trace_access<U: MemInt>(&mut self, kind: MemAccess, vaddr: u64, paddr: u32, val: U) {
+        if let Some(ref mut step) = self.step {
+            step.accesses.push(StepAccess {
+                kind,
+                vaddr: vaddr as u32,
+                paddr,
+                size: U::SIZE,
+                val: val.into(),
+            });
+        }
+    }
+
+    /// Execute exactly one instruction, and describe what happened. A branch
+    /// and its delay slot are executed by two consecutive steps; pending
+    /// interrupts are taken (instead of executing an instruction) only
+    /// outside of delay slots.
+    pub fn step(&mut self) -> StepResult {
+        self.step = Some(StepResult {
+            pc: self.ctx.pc,
+            ..Default::default()
+        });
+        let (regs, hi, lo) = (self.ctx.regs, self.ctx.hi, self.ctx.lo);
+        self.step_one();
+
+        let mut step = self.step.take().unwrap();
+        step.regs = (0..32)
+            .filter(|&idx| self.ctx.regs[idx] != regs[idx])
+            .map(|idx| (idx, self.ctx.regs[idx]))
+            .collect();
+        if (self.ctx.hi, self.ctx.lo) != (hi, lo) {
+            step.hi_lo = Some((self.ctx.hi, self.ctx.lo));
+        }
+        step
+    }
+
+    fn step_one(&mut self) {
+        if self.ctx.lines.halt {
+            return;
+        }
+
+        let pc = self.ctx.pc;
+        let delay_slot = self.ctx.branch_pc.is_some();
+        if !delay_slot {
+            let pending_int = match self.cop0 {
+                Some(ref mut cop0) => {
+                    cop0.update(&self.ctx);
+                    cop0.pending_int()
+                }
+                None => false,
+            };
+            if pending_int {
+                self.exception(Exception::INT);
+                return;
+            }
+        } else {
+            self.ctx.delay_slot = Some(pc.wrapping_sub(4));
+        }
+
+        match self.fetch(pc).map(|mut iter| iter.next().unwrap()) {
+            Ok(op) => {
+                if let Some(ref mut step) = self.step {
+                    step.opcode = Some(op);
+                    step.delay_slot = delay_slot;
+                }
+                self.ctx.pc = match self.ctx.branch_pc.take() {
+                    Some(tgt) => tgt,
+                    None => pc.wrapping_add(4),
+                };
+                self.op(op);
+            }
+            Err(exc) => self.fetch_exception(exc),
+        }
+        self.ctx.delay_slot = None;
+
+        // A step over a branch likely not taken also skips its delay slot
+        self.skip_annulled();
+    }
+
+    // Skip the annulled delay slot of a branch likely, if any. The slot takes
+    // a cycle, but it is neither fetched nor executed.
+    fn skip_annulled(&mut self) -> bool {
+        if !self.ctx.annul {
+            return false;
+        }
+        self.ctx.annul = false;
+        self.ctx.pc = self.ctx.branch_pc.take().unwrap();
+        self.ctx.clock += self.ctx.counter_factor;
+        true
+    }
+
+    // Called after the branch at the specified address, and its delay slot,
+    // were executed. If the branch closed an idle loop, the clock is moved
+    // forward to the next event, or the next interrupt raised by COP0.
+    fn skip_idle(&mut self, branch: u32) {
+        let pc = self.ctx.pc;
+        if pc > branch || branch - pc >= idle::MAX_LOOP_LEN * 4 || self.ctx.lines.single_step {
+            return;
+        }
+        let known = self.idle.as_ref().map_or(true, |idle| idle.known(pc));
+        if !known {
+            let len = ((branch - pc) / 4 + 2) as usize;
+            let body: Vec<u32> = match self.fetch_addr(pc) {
+                Ok(paddr) => self.fetch_iter(paddr).take(len).collect(),
+                Err(_) => return,
+            };
+            // Loops crossing a page are not analyzed
+            let body: &[u32] = if body.len() == len { &body } else { &[] };
+            if let Some(ref mut idle) = self.idle {
+                idle.enter(pc, body);
+            }
+        }
+
+        let regs = self.ctx.regs;
+        let mut until = self.until;
+        if let Some(ref cop0) = self.cop0 {
+            if let Some(clock) = cop0.next_interrupt(&self.ctx) {
+                until = until.min(clock);
+            }
+        }
+        if let Some(ref mut idle) = self.idle {
+            if idle.iterate(&regs) && until > self.ctx.clock {
+                idle.skip(until - self.ctx.clock);
+                self.ctx.clock = until;
+            }
+        }
+    }
+
+    // Cached engine: execute the decoded block at the current PC, exactly as
+    // run() goes through the fetched code in its tight loop.
+    fn run_block(&mut self) {
+        let pc = self.ctx.pc;
+        let block = match self.fetch_block(pc) {
+            Ok(block) => block,
+            Err(exc) => return self.fetch_exception(exc),
+        };
+
+        self.ctx.tight_exit = false;
+        let native = self.run_native(&block);
+        let mut ops = block.ops[native..].iter();
+        while let Some(&(opcode, handler)) = ops.next() {
+            self.ctx.pc = self.ctx.pc.wrapping_add(4);
+            self.exec(opcode, handler);
+            if self.ctx.clock >= self.until || self.ctx.tight_exit || self.ctx.lines.single_step {
+                break;
+            }
+        }
+
+        if !self.skip_annulled() && self.ctx.branch_pc.is_some() {
+            let pc = self.ctx.pc;
+            self.ctx.delay_slot = Some(pc.wrapping_sub(4));
+            let (opcode, handler) = match ops.next() {
+                Some(&op) => op,
+                None => match self.fetch_block(pc) {
+                    Ok(block) => block.ops[0],
+                    Err(exc) => {
+                        self.fetch_exception(exc);
+                        self.ctx.delay_slot = None;
+                        return;
+                    }
+                },
+            };
+            self.ctx.pc = self.ctx.branch_pc.take().unwrap();
+            self.exec(opcode, handler);
+            self.ctx.delay_slot = None;
+            if self.idle.is_some() {
+                self.skip_idle(pc.wrapping_sub(4));
+            }
+        }
+    }
+
+    pub fn run(&mut self, until: i64) {
+        self.until = until;
+
+        // A branch executed by step() leaves its delay slot pending
+        if self.ctx.branch_pc.is_some() {
+            self.step_one();
+        }
+
+        while self.ctx.clock < self.until {
+            if self.ctx.lines.halt {
+                self.ctx.clock = self.until;
+                return;
+            }
+
+            let pending_int = match self.cop0 {
+                Some(ref mut cop0) => {
+                    cop0.update(&self.ctx);
+                    cop0.pending_int()
+                }
+                None => false,
+            };
+            if pending_int {
+                self.exception(Exception::INT);
+                continue;
+            }
+
+            if self.blocks.is_some() {
+                self.run_block();
+            } else {
+                let pc = self.ctx.pc;
+                let mut iter = match self.fetch(pc) {
+                    Ok(iter) => iter,
+                    Err(exc) => {
+                        self.fetch_exception(exc);
+                        continue;
+                    }
+                };
+
+                // Tight loop: go through continuous memory within the page, no
+                // branches, no IRQs
+                self.ctx.tight_exit = false;
+                while let Some(op) = iter.next() {
+                    self.ctx.pc = self.ctx.pc.wrapping_add(4);
+                    self.op(op);
+                    if self.ctx.clock >= self.until
+                        || self.ctx.tight_exit
+                        || self.ctx.lines.single_step
+                    {
+                        break;
+                    }
+                }
+
+                // The tight loop is left after each branch, so its delay slot is
+                // executed (or skipped, if annulled) here, before checking for
+                // interrupts.
+                if !self.skip_annulled() && self.ctx.branch_pc.is_some() {
+                    let pc = self.ctx.pc;
+                    self.ctx.delay_slot = Some(pc.wrapping_sub(4));
+                    let op = match iter.next() {
+                        Some(op) => op,
+                        None => match self.fetch(pc).map(|mut iter| iter.next().unwrap()) {
+                            Ok(op) => op,
+                            Err(exc) => {
+                                self.fetch_exception(exc);
+                                self.ctx.delay_slot = None;
+                                continue;
+                            }
+                        },
+                    };
+                    self.ctx.pc = self.ctx.branch_pc.take().unwrap();
+                    self.op(op);
+                    self.ctx.delay_slot = None;
+                    if self.idle.is_some() {
+                        self.skip_idle(pc.wrapping_sub(4));
+                    }
+                }
+            }
+
+            if self.ctx.lines.single_step {
+                match self.cop0 {
+                    Some(ref mut cop0) => cop0.single_step(&mut self.ctx),
+                    None => self.ctx.set_halt_line(true),
+                }
+            }
+        }
+    }
+}
+
+/*
+pub trait Executor<'a, 'c: 'a> {
+    type Ctx;
+
+    fn exec_begin(&'a mut self) -> (&'a mut Self, Self::Ctx);
+    fn exec_step(&mut self, ectx: &mut Self::Ctx) -> bool;
+    fn exec_finish(&mut self, ectx: Self::Ctx);
+}
+
+use emu::bus::MemIoRIterator;
+
+pub struct ExecContext<'c> {
+    iter: MemIoRIterator<'c, u32>,
+}
+
+impl<'a, 'c: 'a> Executor<'a, 'c> for Box<Cpu> {
+    type Ctx = ExecContext<'c>;
+
+    #[inline(always)]
+    fn exec_begin(&'a mut self) -> (&'a mut Self, ExecContext<'static>) {
+        let pc = self.ctx.pc;
+        let iter = self
+            .bus
+            .borrow()
+            .fetch_read::<u32>(pc & 0x1FFF_FFFC)
+            .iter()
+            .unwrap();
+        self.ctx.tight_exit = false;
+        (self, ExecContext { iter })
+    }
+
+    #[inline(always)]
+    fn exec_step(&mut self, ectx: &mut ExecContext) -> bool {
+        if let Some(op) = ectx.iter.next() {
+            self.ctx.pc = self.ctx.pc.wrapping_add(4);
+            self.op(op);
+            return !self.ctx.tight_exit;
+        }
+        return false;
+    }
+    #[inline(always)]
+    fn exec_finish(&mut self, mut ectx: ExecContext) {
+        if let Some(tgt) = self.ctx.branch_pc.take() {
+            let pc = self.ctx.pc;
+            let op = ectx.iter.next().unwrap_or_else(|| self.fetch(pc).read());
+            self.ctx.pc = tgt;
+            self.op(op);
+        }
+        // if let Some(ref mut cop0) = self.cop0 {
+        //     if cop0.pending_int() {
+        //         cop0.exception(&mut self.ctx, Exception::INT);
+        //     }
+        // }
+    }
+}
+
+pub fn run_two<'a, 'c: 'a, E1: Executor<'a, 'c>, E2: Executor<'a, 'c>>(
+    e1: &'a mut E1,
+    n1: usize,
+    e2: &'a mut E2,
+    n2: usize,
+) {
+    let (e1, mut ectx1) = e1.exec_begin();
+    let (e2, mut ectx2) = e2.exec_begin();
+
+    'iloop: loop {
+        for _ in 0..n1 {
+            if !e1.exec_step(&mut ectx1) {
+                break 'iloop;
+            }
+        }
+        for _ in 0..n2 {
+            if !e2.exec_step(&mut ectx2) {
+                break 'iloop;
+            }
+        }
+    }
+
+    e1.exec_finish(ectx1);
+    e2.exec_finish(ectx2);
+}
+*/
+
+impl sync::Subsystem for Box<Cpu> {
+    fn run(&mut self, until: i64) {
+        Cpu::run(self, until)
+    }
+
+    fn cycles(&self) -> i64 {
+        self.ctx.clock
+    }
+}
@@ -0,0 +1,302 @@
+// Minimal MIPS64 (VR4300) disassembler, used for logging and debugging.
+extern crate byteorder;
+use self::byteorder::{BigEndian, ByteOrder};
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+static REGS: [&'static str; 32] = [
+    "zr", "at", "v0", "v1", "a0", "a1", "a2", "a3", "t0", "t1", "t2", "t3", "t4", "t5", "t6", "t7",
+    "s0", "s1", "s2", "s3", "s4", "s5", "s6", "s7", "t8", "t9", "k0", "k1", "gp", "sp", "fp", "ra",
+];
+
+static SPECIAL: [&'static str; 64] = [
+    "sll", "", "srl", "sra", "sllv", "", "srlv", "srav", "jr", "jalr", "", "", "syscall", "break",
+    "", "sync", "mfhi", "mthi", "mflo", "mtlo", "dsllv", "", "dsrlv", "dsrav", "mult", "multu",
+    "div", "divu", "dmult", "dmultu", "ddiv", "ddivu", "add", "addu", "sub", "subu", "and", "or",
+    "xor", "nor", "", "", "slt", "sltu", "dadd", "daddu", "dsub", "dsubu", "tge", "tgeu", "tlt",
+    "tltu", "teq", "", "tne", "", "dsll", "", "dsrl", "dsra", "dsll32", "", "dsrl32", "dsra32",
+];
+
+static REGIMM: [&'static str; 32] = [
+    "bltz", "bgez", "bltzl", "bgezl", "", "", "", "", "tgei", "tgeiu", "tlti", "tltiu", "teqi", "",
+    "tnei", "", "bltzal", "bgezal", "bltzall", "bgezall", "", "", "", "", "", "", "", "", "", "",
+    "", "",
+];
+
+static OPS: [&'static str; 64] = [
+    "", "", "j", "jal", "beq", "bne", "blez", "bgtz", "addi", "addiu", "slti", "sltiu", "andi",
+    "ori", "xori", "lui", "", "", "", "", "beql", "bnel", "blezl", "bgtzl", "daddi", "daddiu",
+    "ldl", "ldr", "", "", "", "", "lb", "lh", "lwl", "lw", "lbu", "lhu", "lwr", "lwu", "sb", "sh",
+    "swl", "sw", "sdl", "sdr", "swr", "cache", "ll", "lwc1", "lwc2", "", "lld", "ldc1", "ldc2",
+    "ld", "sc", "swc1", "swc2", "", "scd", "sdc1", "sdc2", "sd",
+];
+
+static FPU: [&'static str; 64] = [
+    "add", "sub", "mul", "div", "sqrt", "abs", "mov", "neg", "round.l", "trunc.l", "ceil.l",
+    "floor.l", "round.w", "trunc.w", "ceil.w", "floor.w", "", "", "", "", "", "", "", "", "", "",
+    "", "", "", "", "", "", "cvt.s", "cvt.d", "", "", "cvt.w", "cvt.l", "", "", "", "", "", "", "",
+    "", "", "", "c.f", "c.un", "c.eq", "c.ueq", "c.olt", "c.ult", "c.ole", "c.ule", "c.sf",
+    "c.ngle", "c.seq", "c.ngl", "c.lt", "c.nge", "c.le", "c.ngt",
+];
+
+/// Conventional (ABI) name of a GPR, as used by the disassembler.
+pub fn reg_name(idx: usize) -> &'static str {
+    REGS[idx]
+}
+
+/// Index of a GPR from its name: either the ABI name (with the common
+/// aliases "zero" and "s8"), or its number ("r4"); an optional "$" prefix is
+/// accepted, as is the bare number ("$4").
+pub fn reg_index(name: &str) -> Option<usize> {
+    let name = name.trim_left_matches('$');
+    match name {
+        "zero" => return Some(0),
+        "s8" => return Some(30),
+        _ => {}
+    }
+    if let Some(idx) = REGS.iter().position(|&r| r == name) {
+        return Some(idx);
+    }
+    let num = if name.starts_with('r') {
+        &name[1..]
+    } else {
+        name
+    };
+    match num.parse::<usize>() {
+        Ok(idx) if idx < 32 => Some(idx),
+        _ => None,
+    }
+}
+
+/// Disassemble a single opcode. `pc` is the address of the opcode, and is
+/// used to compute branch targets. The mnemonic is always followed by at
+/// least one space.
+pub fn disasm(opcode: u32, pc: u32) -> String {
+    let op = opcode >> 26;
+    let rs = REGS[((opcode >> 21) & 0x1f) as usize];
+    let rt = REGS[((opcode >> 16) & 0x1f) as usize];
+    let rd = REGS[((opcode >> 11) & 0x1f) as usize];
+    let sa = (opcode >> 6) & 0x1f;
+    let imm = opcode & 0xffff;
+    let simm = imm as i16 as i32;
+    let btgt = pc.wrapping_add(4).wrapping_add((simm << 2) as u32);
+
+    match op {
+        0x00 => {
+            let func = opcode & 0x3f;
+            let name = SPECIAL[func as usize];
+            match func {
+                _ if opcode == 0 => "nop".into(),
+                0x00 | 0x02 | 0x03 | 0x38 | 0x3A | 0x3B | 0x3C | 0x3E | 0x3F => {
+                    format!("{:7} {},{},{}", name, rd, rt, sa)
+                }
+                0x04 | 0x06 | 0x07 | 0x14 | 0x16 | 0x17 => {
+                    format!("{:7} {},{},{}", name, rd, rt, rs)
+                }
+                0x08 => format!("{:7} {}", name, rs),
+                0x09 => format!("{:7} {},{}", name, rd, rs),
+                0x0C | 0x0D | 0x0F => name.into(),
+                0x10 | 0x12 => format!("{:7} {}", name, rd),
+                0x11 | 0x13 => format!("{:7} {}", name, rs),
+                0x18...0x1F | 0x30...0x36 => format!("{:7} {},{}", name, rs, rt),
+                _ if name != "" => format!("{:7} {},{},{}", name, rd, rs, rt),
+                _ => unknown(opcode),
+            }
+        }
+        0x01 => {
+            let name = REGIMM[((opcode >> 16) & 0x1f) as usize];
+            match (opcode >> 16) & 0x1f {
+                _ if name == "" => unknown(opcode),
+                0x08...0x0F => format!("{:7} {},{}", name, rs, simm),
+                _ => format!("{:7} {},0x{:08x}", name, rs, btgt),
+            }
+        }
+        0x02 | 0x03 => format!(
+            "{:7} 0x{:08x}",
+            OPS[op as usize],
+            (pc.wrapping_add(4) & 0xF000_0000) | ((opcode & 0x03FF_FFFF) << 2)
+        ),
+        0x04 | 0x05 | 0x14 | 0x15 => format!("{:7} {},{},0x{:08x}", OPS[op as usize], rs, rt, btgt),
+        0x06 | 0x07 | 0x16 | 0x17 => format!("{:7} {},0x{:08x}", OPS[op as usize], rs, btgt),
+        0x08...0x0B | 0x18 | 0x19 => format!("{:7} {},{},{}", OPS[op as usize], rt, rs, simm),
+        0x0C...0x0E => format!("{:7} {},{},0x{:x}", OPS[op as usize], rt, rs, imm),
+        0x0F => format!("{:7} {},0x{:x}", "lui", rt, imm),
+        0x10...0x13 => disasm_cop(opcode, pc),
+        0x2F => format!(
+            "{:7} 0x{:x},{}({})",
+            "cache",
+            (opcode >> 16) & 0x1f,
+            simm,
+            rs
+        ),
+        0x31 | 0x35 | 0x39 | 0x3D => format!(
+            "{:7} f{},{}({})",
+            OPS[op as usize],
+            (opcode >> 16) & 0x1f,
+            simm,
+            rs
+        ),
+        _ if OPS[op as usize] != "" => format!("{:7} {},{}({})", OPS[op as usize], rt, simm, rs),
+        _ => unknown(opcode),
+    }
+}
+
+fn disasm_cop(opcode: u32, pc: u32) -> String {
+    let cop = (opcode >> 26) & 3;
+    let fmt = (opcode >> 21) & 0x1f;
+    let rt = REGS[((opcode >> 16) & 0x1f) as usize];
+    let rd = (opcode >> 11) & 0x1f;
+    let btgt = pc
+        .wrapping_add(4)
+        .wrapping_add(((opcode & 0xffff) as i16 as i32 as u32) << 2);
+
+    match fmt {
+        0x00 => format!("{:7} {},${}", format!("mfc{}", cop), rt, rd),
+        0x01 => format!("{:7} {},${}", format!("dmfc{}", cop), rt, rd),
+        0x02 => format!("{:7} {},${}", format!("cfc{}", cop), rt, rd),
+        0x04 => format!("{:7} {},${}", format!("mtc{}", cop), rt, rd),
+        0x05 => format!("{:7} {},${}", format!("dmtc{}", cop), rt, rd),
+        0x06 => format!("{:7} {},${}", format!("ctc{}", cop), rt, rd),
+        0x08 => {
+            let cond = ["f", "t", "fl", "tl"][((opcode >> 16) & 3) as usize];
+            format!("{:7} 0x{:08x}", format!("bc{}{}", cop, cond), btgt)
+        }
+        0x10...0x1F if cop == 0 => match opcode & 0x3f {
+            0x01 => "tlbr".into(),
+            0x02 => "tlbwi".into(),
+            0x06 => "tlbwr".into(),
+            0x08 => "tlbp".into(),
+            0x18 => "eret".into(),
+            _ => unknown(opcode),
+        },
+        0x10 | 0x11 | 0x14 | 0x15 if cop == 1 => {
+            let name = FPU[(opcode & 0x3f) as usize];
+            let fmt = ["s", "d", "", "", "w", "l"][(fmt & 7) as usize];
+            let ft = (opcode >> 16) & 0x1f;
+            let fs = (opcode >> 11) & 0x1f;
+            let fd = (opcode >> 6) & 0x1f;
+            match opcode & 0x3f {
+                _ if name == "" => unknown(opcode),
+                0x00...0x03 => format!("{:7} f{},f{},f{}", format!("{}.{}", name, fmt), fd, fs, ft),
+                0x30...0x3F => format!("{:7} f{},f{}", format!("{}.{}", name, fmt), fs, ft),
+                _ => format!("{:7} f{},f{}", format!("{}.{}", name, fmt), fd, fs),
+            }
+        }
+        _ => format!("{:7} 0x{:07x}", format!("cop{}", cop), opcode & 0x01FF_FFFF),
+    }
+}
+
+fn unknown(opcode: u32) -> String {
+    format!("{:7} 0x{:08x}", "???", opcode)
+}
+
+/// Return the target of a branch or jump opcode at `pc`, if it is known
+/// statically (that is, not a jump through a register).
+pub fn branch_target(opcode: u32, pc: u32) -> Option<u32> {
+    let btgt = pc
+        .wrapping_add(4)
+        .wrapping_add(((opcode & 0xffff) as i16 as i32 as u32) << 2);
+    match opcode >> 26 {
+        0x01 => match (opcode >> 16) & 0x1f {
+            0x00...0x03 | 0x10...0x13 => Some(btgt),
+            _ => None,
+        },
+        0x02 | 0x03 => Some((pc.wrapping_add(4) & 0xF000_0000) | ((opcode & 0x03FF_FFFF) << 2)),
+        0x04...0x07 | 0x14...0x17 => Some(btgt),
+        0x10...0x13 if (opcode >> 21) & 0x1f == 0x08 => Some(btgt),
+        _ => None,
+    }
+}
+
+/// Parse a list of symbols, as produced by `nm` (`<addr> [<type>] <name>`).
+/// Lines that do not start with an hexadecimal address are ignored, so that
+/// simple linker map files can be used as well.
+pub fn parse_symbols(text: &str) -> BTreeMap<u32, String> {
+    let mut symbols = BTreeMap::new();
+    for line in text.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 2 {
+            continue;
+        }
+        let addr = fields[0].trim_left_matches("0x");
+        if let Ok(addr) = u64::from_str_radix(addr, 16) {
+            symbols.insert(addr as u32, fields[fields.len() - 1].to_string());
+        }
+    }
+    symbols
+}
+
+/// Disassemble a block of big-endian code loaded at `base`. Each line shows
+/// the address, opcode and instruction; symbols and the targets of branches
+/// within the block get a label, and branches are annotated with the name
+/// of their target.
+pub fn disasm_listing(code: &[u8], base: u32, symbols: &BTreeMap<u32, String>) -> String {
+    let ops: Vec<(u32, u32)> = code
+        .chunks(4)
+        .filter(|w| w.len() == 4)
+        .enumerate()
+        .map(|(idx, w)| (base.wrapping_add(idx as u32 * 4), BigEndian::read_u32(w)))
+        .collect();
+    let targets: BTreeSet<u32> = ops
+        .iter()
+        .filter_map(|&(pc, op)| branch_target(op, pc))
+        .collect();
+    let label = |addr: u32| match symbols.get(&addr) {
+        Some(name) => name.clone(),
+        None => format!("loc_{:08x}", addr),
+    };
+
+    let mut out = String::new();
+    for &(pc, op) in &ops {
+        if symbols.contains_key(&pc) || targets.contains(&pc) {
+            writeln!(out, "{}:", label(pc)).unwrap();
+        }
+        let mut line = format!("{:08x}:  {:08x}  {}", pc, op, disasm(op, pc));
+        if let Some(tgt) = branch_target(op, pc) {
+            let end = base.wrapping_add(ops.len() as u32 * 4);
+            if symbols.contains_key(&tgt) || (tgt >= base && tgt < end) {
+                line = format!("{:48}; {}", line.trim_right(), label(tgt));
+            }
+        }
+        writeln!(out, "{}", line.trim_right()).unwrap();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listing() {
+        let symbols = parse_symbols(
+            "80000400 T main\n\
+             0x80001000 t helper\n\
+             garbage\n",
+        );
+        assert_eq!(symbols.len(), 2);
+        assert_eq!(symbols[&0x8000_1000], "helper");
+
+        let code = [
+            0x0C00_0400u32, // jal 0x80001000
+            0x0000_0000,    // nop
+            0x1000_FFFE,    // b 0x80000404
+            0x0000_0000,    // nop
+        ];
+        let mut bytes = vec![0u8; 16];
+        for (idx, op) in code.iter().enumerate() {
+            BigEndian::write_u32(&mut bytes[idx * 4..], *op);
+        }
+        assert_eq!(
+            disasm_listing(&bytes, 0x8000_0400, &symbols),
+            "main:\n\
+             80000400:  0c000400  jal     0x80001000         ; helper\n\
+             loc_80000404:\n\
+             80000404:  00000000  nop\n\
+             80000408:  1000fffe  beq     zr,zr,0x80000404   ; loc_80000404\n\
+             8000040c:  00000000  nop\n"
+        );
+    }
+}
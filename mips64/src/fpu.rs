@@ -0,0 +1,649 @@
+extern crate num_traits;
+
+use self::num_traits::Float;
+use super::cpu::{Cop, CpuBus, CpuContext, Exception};
+use alloc::boxed::Box;
+use core::marker::PhantomData;
+use core::mem;
+use core::num::FpCategory;
+use slog;
+
+pub struct Fpu {
+    regs: [u64; 32],
+    fir: u64,
+    fccr: u64,
+    fexr: u64,
+    fenr: u64,
+    fcsr: u64,
+
+    logger: slog::Logger,
+}
+
+// Implementation/revision of the VR4300 FPU, as read from FCR0
+const FIR: u64 = 0x0B00;
+
+// Writable bits of FCR31: condition bit, flush denormals, cause/enable/flag
+// bits of exceptions, rounding mode.
+const FCSR_RWMASK: u64 = 0x0183_FFFF;
+const FCSR_CC: u64 = 1 << 23;
+const FCSR_FS: u64 = 1 << 24;
+
+// FPU exceptions, in the order of their bits in the flag (FCR31 bits 2-6),
+// enable (bits 7-11) and cause (bits 12-17) fields. Unimplemented operation
+// only exists as a cause, and cannot be disabled.
+const FPE_INEXACT: u64 = 1 << 0;
+const FPE_UNDERFLOW: u64 = 1 << 1;
+const FPE_OVERFLOW: u64 = 1 << 2;
+const FPE_DIVZERO: u64 = 1 << 3;
+const FPE_INVALID: u64 = 1 << 4;
+const FPE_UNIMPL: u64 = 1 << 5;
+
+const FCSR_FLAGS_SHIFT: u64 = 2;
+const FCSR_ENABLES_SHIFT: u64 = 7;
+const FCSR_CAUSE_SHIFT: u64 = 12;
+const FCSR_CAUSE_MASK: u64 = 0x3F << FCSR_CAUSE_SHIFT;
+
+trait FloatRawConvert {
+    fn from_u64bits(v: u64) -> Self;
+    fn to_u64bits(self) -> u64;
+    fn bankers_round(self) -> Self;
+    fn as_f32(self) -> f32;
+    fn as_f64(self) -> f64;
+    // NaN produced by invalid operations (MIPS uses a clear MSB of the
+    // mantissa for quiet NaNs).
+    fn default_nan() -> Self;
+    // Next representable value towards +inf (up) or -inf.
+    fn ulp_step(self, up: bool) -> Self;
+}
+
+impl FloatRawConvert for f32 {
+    fn from_u64bits(v: u64) -> Self {
+        f32::from_bits(v as u32)
+    }
+    fn to_u64bits(self) -> u64 {
+        self.to_bits() as u64
+    }
+    fn bankers_round(self) -> Self {
+        let y = self.round();
+        if (self - y).abs() == 0.5 {
+            (self * 0.5).round() * 2.0
+        } else {
+            y
+        }
+    }
+    fn as_f32(self) -> f32 {
+        self
+    }
+    fn as_f64(self) -> f64 {
+        self as f64
+    }
+    fn default_nan() -> Self {
+        f32::from_bits(0x7FBF_FFFF)
+    }
+    fn ulp_step(self, up: bool) -> Self {
+        if self.is_nan() || (self.is_infinite() && (self > 0.0) == up) {
+            self
+        } else if self == 0.0 {
+            let min = f32::from_bits(1);
+            if up {
+                min
+            } else {
+                -min
+            }
+        } else if (self > 0.0) == up {
+            f32::from_bits(self.to_bits() + 1)
+        } else {
+            f32::from_bits(self.to_bits() - 1)
+        }
+    }
+}
+
+impl FloatRawConvert for f64 {
+    fn from_u64bits(v: u64) -> Self {
+        f64::from_bits(v)
+    }
+    fn to_u64bits(self) -> u64 {
+        self.to_bits()
+    }
+    fn bankers_round(self) -> Self {
+        let y = self.round();
+        if (self - y).abs() == 0.5 {
+            (self * 0.5).round() * 2.0
+        } else {
+            y
+        }
+    }
+    fn as_f32(self) -> f32 {
+        self as f32
+    }
+    fn as_f64(self) -> f64 {
+        self
+    }
+    fn default_nan() -> Self {
+        f64::from_bits(0x7FF7_FFFF_FFFF_FFFF)
+    }
+    fn ulp_step(self, up: bool) -> Self {
+        if self.is_nan() || (self.is_infinite() && (self > 0.0) == up) {
+            self
+        } else if self == 0.0 {
+            let min = f64::from_bits(1);
+            if up {
+                min
+            } else {
+                -min
+            }
+        } else if (self > 0.0) == up {
+            f64::from_bits(self.to_bits() + 1)
+        } else {
+            f64::from_bits(self.to_bits() - 1)
+        }
+    }
+}
+
+// Operands that the VR4300 does not handle in hardware (denormals and NaNs):
+// they raise an unimplemented operation exception, so that the OS can
+// emulate the instruction.
+fn unimplemented_operand<F: Float>(v: F) -> bool {
+    v.is_nan() || v.classify() == FpCategory::Subnormal
+}
+
+// Sign of the difference between an exact result and its rounded value, as
+// a float of the result format.
+fn err_sign<F: Float>(diff: f64) -> F {
+    if diff > 0.0 {
+        F::one()
+    } else if diff < 0.0 {
+        -F::one()
+    } else {
+        F::zero()
+    }
+}
+
+// Error-free addition: returns the sum rounded to nearest, and the rounding
+// error (exact sum - rounded sum).
+fn two_sum<F: Float>(a: F, b: F) -> (F, F) {
+    let s = a + b;
+    let bb = s - a;
+    (s, (a - (s - bb)) + (b - bb))
+}
+
+struct Fop<'a, F: Float + FloatRawConvert> {
+    opcode: u32,
+    fpu: &'a mut Fpu,
+    cpu: &'a mut CpuContext,
+    phantom: PhantomData<F>,
+}
+
+impl<'a, F: Float + FloatRawConvert> Fop<'a, F> {
+    fn func(&self) -> u32 {
+        self.opcode & 0x3f
+    }
+    fn cc(&self) -> usize {
+        ((self.opcode >> 8) & 7) as usize
+    }
+    fn rs(&self) -> usize {
+        ((self.opcode >> 11) & 0x1f) as usize
+    }
+    fn rt(&self) -> usize {
+        ((self.opcode >> 16) & 0x1f) as usize
+    }
+    fn rd(&self) -> usize {
+        ((self.opcode >> 6) & 0x1f) as usize
+    }
+    fn wide(&self) -> bool {
+        mem::size_of::<F>() == 8
+    }
+    fn fs(&self) -> F {
+        F::from_u64bits(self.fpu.fpr(self.cpu.fr, self.rs(), self.wide()))
+    }
+    fn ft(&self) -> F {
+        F::from_u64bits(self.fpu.fpr(self.cpu.fr, self.rt(), self.wide()))
+    }
+    // Round fs to an integral value, using the rounding mode selected in FCSR
+    // (used by CVT.W/CVT.L).
+    fn fs_rounded(&self) -> F {
+        let fs = self.fs();
+        match self.fpu.fcsr & 3 {
+            0 => fs.bankers_round(),
+            1 => fs.trunc(),
+            2 => fs.ceil(),
+            _ => fs.floor(),
+        }
+    }
+    fn set_fd(&mut self, v: F) {
+        let wide = self.wide();
+        self.set_fd_bits(v.to_u64bits(), wide);
+    }
+    fn set_fd_bits(&mut self, v: u64, wide: bool) {
+        let (fr, rd) = (self.cpu.fr, self.rd());
+        self.fpu.set_fpr(fr, rd, wide, v);
+    }
+    // Complete an arithmetic operation on the specified operands, whose
+    // result (rounded to nearest) is res, and err its rounding error: fd is
+    // written unless an exception is raised.
+    fn set_fd_rounded(&mut self, res: F, err: F, ops: &[F]) {
+        let unimpl = ops.iter().any(|&v| unimplemented_operand(v));
+        let inf = ops.iter().any(|v| v.is_infinite());
+        if let Some(v) = self.fpu.round(self.cpu, res, err, unimpl, inf) {
+            self.set_fd(v);
+        }
+    }
+    // Complete a conversion of fs into another floating-point format.
+    fn set_fd_cvt<G: Float + FloatRawConvert>(&mut self, res: G, err: G) {
+        let fs = self.fs();
+        let (unimpl, inf) = (unimplemented_operand(fs), fs.is_infinite());
+        if let Some(v) = self.fpu.round(self.cpu, res, err, unimpl, inf) {
+            self.set_fd_bits(v.to_u64bits(), mem::size_of::<G>() == 8);
+        }
+    }
+}
+
+// Conversion to fixed-point: out of range (or NaN) values raise an
+// unimplemented operation exception on the VR4300; the result is inexact if
+// the value was rounded.
+macro_rules! approx {
+    ($op:ident, $val:expr, $size:ident) => {{
+        let fs = $op.fs();
+        let val = $val;
+        match (unimplemented_operand(fs), val.$size()) {
+            (false, Some(v)) => {
+                let exc = if val != fs { FPE_INEXACT } else { 0 };
+                if !$op.fpu.fp_exceptions($op.cpu, exc) {
+                    $op.set_fd_bits(v as u64, mem::size_of_val(&v) == 8);
+                }
+            }
+            _ => {
+                $op.fpu.fp_exceptions($op.cpu, FPE_UNIMPL);
+            }
+        }
+    }};
+}
+
+macro_rules! cond {
+    ($op:ident, $cond:expr) => {{
+        let cond = $cond;
+        let cc = $op.cc();
+        $op.fpu.set_cc(cc, cond);
+    }};
+}
+
+impl Fpu {
+    pub fn new(logger: slog::Logger) -> Box<Fpu> {
+        Box::new(Fpu {
+            regs: [0u64; 32],
+            fir: FIR,
+            fccr: 0,
+            fexr: 0,
+            fenr: 0,
+            fcsr: 0,
+            logger,
+        })
+    }
+
+    // Access a FPR as seen by instructions, with a 32-bit (only the low
+    // bits of val are written) or 64-bit (wide) access. With Status.FR set,
+    // there are 32 64-bit registers. Otherwise, there are 16 of them: odd
+    // registers name the upper half of the preceding even register in 32-bit
+    // accesses, and are not valid in 64-bit accesses (the even one is used).
+    fn fpr(&self, fr: bool, idx: usize, wide: bool) -> u64 {
+        match (fr, wide) {
+            (true, true) => self.regs[idx],
+            (true, false) => self.regs[idx] & 0xFFFF_FFFF,
+            (false, true) => self.regs[idx & !1],
+            (false, false) => (self.regs[idx & !1] >> ((idx & 1) * 32)) & 0xFFFF_FFFF,
+        }
+    }
+
+    fn set_fpr(&mut self, fr: bool, idx: usize, wide: bool, val: u64) {
+        let (idx, shift) = if fr {
+            (idx, 0)
+        } else {
+            (idx & !1, (idx & 1) * 32)
+        };
+        if wide {
+            self.regs[idx] = val;
+        } else {
+            let mask = 0xFFFF_FFFFu64 << shift;
+            self.regs[idx] = (self.regs[idx] & !mask) | ((val << shift) & mask);
+        }
+    }
+
+    fn set_cc(&mut self, cc: usize, val: bool) {
+        if cc > 8 {
+            panic!("invalid cc code");
+        }
+        self.fccr = (self.fccr & !(1 << cc)) | ((val as u64) << cc);
+        let mut cc2 = cc + 23;
+        if cc > 0 {
+            cc2 += 1;
+        }
+        self.fcsr = (self.fcsr & !(1 << cc2)) | ((val as u64) << cc2);
+    }
+
+    // Record the exceptions raised by an operation in FCR31: the cause bits
+    // are replaced, and the flag bits accumulate the exceptions that do not
+    // trap. Returns true if an enabled exception was raised: the core traps,
+    // and the destination register must not be written.
+    fn fp_exceptions(&mut self, cpu: &mut CpuContext, exc: u64) -> bool {
+        self.fcsr = (self.fcsr & !FCSR_CAUSE_MASK) | (exc << FCSR_CAUSE_SHIFT);
+        let enabled = ((self.fcsr >> FCSR_ENABLES_SHIFT) & 0x1F) | FPE_UNIMPL;
+        if exc & enabled != 0 {
+            cpu.trigger_exception(Exception::FPE);
+            return true;
+        }
+        self.fcsr |= (exc & 0x1F) << FCSR_FLAGS_SHIFT;
+        false
+    }
+
+    // Apply a directed rounding mode to a result rounded to nearest, given
+    // the sign of its rounding error (exact - rounded).
+    fn round_directed<G: Float + FloatRawConvert>(&self, res: G, err: G) -> G {
+        let zero = G::zero();
+        match self.fcsr & 3 {
+            1 if res > zero && err < zero => res.ulp_step(false), // RZ
+            1 if res < zero && err > zero => res.ulp_step(true),  // RZ
+            2 if err > zero => res.ulp_step(true),                // RP
+            3 if err < zero => res.ulp_step(false),               // RM
+            _ => res,
+        }
+    }
+
+    // Round the result of an operation (computed by the host, rounded to
+    // nearest) according to FCR31, detecting the exceptions it raises. unimpl
+    // reports operands that cannot be handled in hardware, and inf infinite
+    // operands (which produce infinite results without overflowing). Returns
+    // the value to write in the destination register, if any.
+    fn round<G: Float + FloatRawConvert>(
+        &mut self,
+        cpu: &mut CpuContext,
+        res: G,
+        err: G,
+        unimpl: bool,
+        inf: bool,
+    ) -> Option<G> {
+        let mut res = res;
+        let mut exc = 0;
+        if unimpl {
+            exc = FPE_UNIMPL;
+        } else if res.is_nan() {
+            exc = FPE_INVALID;
+            res = G::default_nan();
+        } else if res.is_infinite() {
+            if !inf {
+                // Directed rounding modes clamp to the largest finite value
+                // on overflow, when rounding towards zero.
+                exc = FPE_OVERFLOW | FPE_INEXACT;
+                let max = G::max_value();
+                res = match (self.fcsr & 3, res > G::zero()) {
+                    (1, true) | (3, true) => max,
+                    (1, false) | (2, false) => -max,
+                    _ => res,
+                };
+            }
+        } else {
+            // The error is not representable (NaN) only for exact results
+            // involving infinities.
+            if !err.is_nan() && err != G::zero() {
+                exc = FPE_INEXACT;
+                res = self.round_directed(res, err);
+            }
+            let tiny = res.classify() == FpCategory::Subnormal || (res.is_zero() && exc != 0);
+            if tiny {
+                // Denormal results are flushed to zero if FS is set;
+                // otherwise, they are left to the OS to handle.
+                if self.fcsr & FCSR_FS != 0 {
+                    exc |= FPE_UNDERFLOW | FPE_INEXACT;
+                    res = if res.is_sign_negative() {
+                        -G::zero()
+                    } else {
+                        G::zero()
+                    };
+                } else {
+                    exc |= FPE_UNIMPL;
+                }
+            }
+        }
+        if self.fp_exceptions(cpu, exc) {
+            None
+        } else {
+            Some(res)
+        }
+    }
+
+    // CVT.S.fmt / CVT.D.fmt with a fixed-point source (W or L format)
+    fn cvt_fixed(&mut self, cpu: &mut CpuContext, opcode: u32, val: i64) {
+        let fd = ((opcode >> 6) & 0x1f) as usize;
+        let fr = cpu.fr;
+        match opcode & 0x3f {
+            0x20 => {
+                // CVT.S
+                let res = val as f32;
+                let err = err_sign((val as i128 - res as i128) as f64);
+                if let Some(v) = self.round(cpu, res, err, false, false) {
+                    self.set_fpr(fr, fd, false, v.to_u64bits());
+                }
+            }
+            0x21 => {
+                // CVT.D
+                let res = val as f64;
+                let err = err_sign((val as i128 - res as i128) as f64);
+                if let Some(v) = self.round(cpu, res, err, false, false) {
+                    self.set_fpr(fr, fd, true, v.to_u64bits());
+                }
+            }
+            _ => cpu.unimplemented(&self.logger, opcode),
+        }
+    }
+
+    fn get_cc(&mut self, cc: usize) -> bool {
+        if cc > 8 {
+            panic!("invalid cc code");
+        }
+        (self.fccr & (1 << cc)) != 0
+    }
+
+    fn fop<M: Float + FloatRawConvert>(&mut self, cpu: &mut CpuContext, opcode: u32) {
+        let mut op = Fop::<M> {
+            opcode,
+            fpu: self,
+            cpu,
+            phantom: PhantomData,
+        };
+        // Arithmetic operations are computed by the host rounding to
+        // nearest; their rounding error (obtained with error-free
+        // transformations) is then used to apply the rounding mode in FCR31.
+        match op.func() {
+            0x00 => {
+                // ADD.fmt
+                let (a, b) = (op.fs(), op.ft());
+                let (v, err) = two_sum(a, b);
+                op.set_fd_rounded(v, err, &[a, b])
+            }
+            0x01 => {
+                // SUB.fmt
+                let (a, b) = (op.fs(), op.ft());
+                let (v, err) = two_sum(a, -b);
+                op.set_fd_rounded(v, err, &[a, b])
+            }
+            0x02 => {
+                // MUL.fmt
+                let (a, b) = (op.fs(), op.ft());
+                let v = a * b;
+                op.set_fd_rounded(v, a.mul_add(b, -v), &[a, b])
+            }
+            0x03 => {
+                // DIV.fmt
+                let (a, b) = (op.fs(), op.ft());
+                let v = a / b;
+                if b.is_zero() && a.is_normal() {
+                    if !op.fpu.fp_exceptions(op.cpu, FPE_DIVZERO) {
+                        op.set_fd(v)
+                    }
+                } else {
+                    // a - v*b has the sign of the error if b is positive
+                    let rem = (-v).mul_add(b, a);
+                    let err = if b < M::zero() { -rem } else { rem };
+                    op.set_fd_rounded(v, err, &[a, b])
+                }
+            }
+            0x04 => {
+                // SQRT.fmt
+                let a = op.fs();
+                let v = a.sqrt();
+                op.set_fd_rounded(v, (-v).mul_add(v, a), &[a])
+            }
+            0x05 => {
+                // ABS.fmt
+                let v = op.fs().abs();
+                op.set_fd(v)
+            }
+            0x06 => {
+                // MOV.fmt
+                let v = op.fs();
+                op.set_fd(v)
+            }
+            0x07 => {
+                // NEG.fmt
+                let v = op.fs().neg();
+                op.set_fd(v)
+            }
+            0x08 => approx!(op, op.fs().bankers_round(), to_i64), // ROUND.L.fmt
+            0x09 => approx!(op, op.fs().trunc(), to_i64),         // TRUNC.L.fmt
+            0x0A => approx!(op, op.fs().ceil(), to_i64),          // CEIL.L.fmt
+            0x0B => approx!(op, op.fs().floor(), to_i64),         // FLOOR.L.fmt
+            0x0C => approx!(op, op.fs().bankers_round(), to_i32), // ROUND.W.fmt
+            0x0D => approx!(op, op.fs().trunc(), to_i32),         // TRUNC.W.fmt
+            0x0E => approx!(op, op.fs().ceil(), to_i32),          // CEIL.W.fmt
+            0x0F => approx!(op, op.fs().floor(), to_i32),         // FLOOR.W.fmt
+
+            0x20 => {
+                // CVT.S.fmt
+                let fs = op.fs();
+                let v = fs.as_f32();
+                op.set_fd_cvt(v, err_sign(fs.as_f64() - v as f64))
+            }
+            0x21 => {
+                // CVT.D.fmt
+                let v = op.fs().as_f64();
+                op.set_fd_cvt(v, 0.0)
+            }
+            0x24 => approx!(op, op.fs_rounded(), to_i32), // CVT.W.fmt
+            0x25 => approx!(op, op.fs_rounded(), to_i64), // CVT.L.fmt
+
+            0x30...0x3F => {
+                // C.cond.fmt: the low bits of the function select which
+                // relations satisfy the condition (unordered, equal, less
+                // than). Signaling compares raise an invalid operation
+                // exception on NaNs.
+                let (fs, ft) = (op.fs(), op.ft());
+                let cond = op.func();
+                let nan = fs.is_nan() || ft.is_nan();
+                let res = if nan {
+                    cond & 1 != 0
+                } else {
+                    (cond & 2 != 0 && fs == ft) || (cond & 4 != 0 && fs < ft)
+                };
+                let exc = if nan && cond & 8 != 0 { FPE_INVALID } else { 0 };
+                if !op.fpu.fp_exceptions(op.cpu, exc) {
+                    cond!(op, res)
+                }
+            }
+
+            _ => op.cpu.unimplemented(&op.fpu.logger, op.opcode),
+        }
+    }
+}
+
+impl Cop for Fpu {
+    fn reg(&self, idx: usize) -> u128 {
+        self.regs[idx] as u128
+    }
+    fn set_reg(&mut self, idx: usize, val: u128) {
+        self.regs[idx] = val as u64;
+    }
+
+    // Loads and stores access the registers in the layout selected by
+    // Status.FR, like moves.
+
+    fn lwc(&mut self, op: u32, ctx: &CpuContext, paddr: u32, bus: &CpuBus) {
+        let ft = ((op >> 16) & 0x1f) as usize;
+        let val = bus.read::<u32>(paddr & !3) as u64;
+        self.set_fpr(ctx.fr, ft, false, val);
+    }
+
+    fn ldc(&mut self, op: u32, ctx: &CpuContext, paddr: u32, bus: &CpuBus) {
+        let ft = ((op >> 16) & 0x1f) as usize;
+        let val = bus.read::<u64>(paddr & !7);
+        self.set_fpr(ctx.fr, ft, true, val);
+    }
+
+    fn swc(&mut self, op: u32, ctx: &CpuContext, paddr: u32, bus: &CpuBus) {
+        let ft = ((op >> 16) & 0x1f) as usize;
+        let val = self.fpr(ctx.fr, ft, false) as u32;
+        bus.write::<u32>(paddr & !3, val);
+    }
+
+    fn sdc(&mut self, op: u32, ctx: &CpuContext, paddr: u32, bus: &CpuBus) {
+        let ft = ((op >> 16) & 0x1f) as usize;
+        let val = self.fpr(ctx.fr, ft, true);
+        bus.write::<u64>(paddr & !7, val);
+    }
+
+    fn op(&mut self, cpu: &mut CpuContext, opcode: u32, _bus: &mut CpuBus) {
+        let fmt = (opcode >> 21) & 0x1F;
+        let rt = ((opcode >> 16) & 0x1f) as usize;
+        let fs = ((opcode >> 11) & 0x1f) as usize;
+        match fmt {
+            0 => cpu.regs[rt] = self.fpr(cpu.fr, fs, false) as u32 as i32 as i64 as u64, // MFC1
+            1 => cpu.regs[rt] = self.fpr(cpu.fr, fs, true),                              // DMFC1
+            2 => {
+                // CFC1
+                cpu.regs[rt] = match fs {
+                    0 => self.fir,
+                    31 => self.fcsr as u32 as i32 as i64 as u64,
+                    _ => {
+                        warn!(self.logger, "unimplemented FPU control reg read"; "reg" => fs);
+                        0
+                    }
+                }
+            }
+            4 => self.set_fpr(cpu.fr, fs, false, cpu.regs[rt]), // MTC1
+            5 => self.set_fpr(cpu.fr, fs, true, cpu.regs[rt]),  // DMTC1
+            6 => match fs {
+                // CTC1
+                31 => {
+                    self.fcsr = cpu.regs[rt] & FCSR_RWMASK;
+                    self.fccr = (self.fccr & !1) | (self.fcsr & FCSR_CC != 0) as u64;
+                    // Writing a cause bit whose exception is enabled traps
+                    let cause = (self.fcsr & FCSR_CAUSE_MASK) >> FCSR_CAUSE_SHIFT;
+                    let enabled = ((self.fcsr >> FCSR_ENABLES_SHIFT) & 0x1F) | FPE_UNIMPL;
+                    if cause & enabled != 0 {
+                        cpu.trigger_exception(Exception::FPE);
+                    }
+                }
+                _ => warn!(self.logger, "unimplemented FPU control reg write"; "reg" => fs),
+            },
+            8 => {
+                let tgt = cpu
+                    .pc
+                    .wrapping_add((((opcode & 0xffff) as i16 as i32) << 2) as u32);
+                let cc = ((opcode >> 18) & 7) as usize;
+                let nd = opcode & (1 << 17) != 0;
+                let tf = opcode & (1 << 16) != 0;
+                let cond = self.get_cc(cc) == tf;
+                cpu.branch(cond, tgt, nd);
+            }
+            16 => self.fop::<f32>(cpu, opcode),
+            17 => self.fop::<f64>(cpu, opcode),
+            20 => {
+                let val = self.fpr(cpu.fr, fs, false) as u32 as i32 as i64;
+                self.cvt_fixed(cpu, opcode, val)
+            }
+            21 => {
+                let val = self.fpr(cpu.fr, fs, true) as i64;
+                self.cvt_fixed(cpu, opcode, val)
+            }
+            _ => cpu.unimplemented(&self.logger, opcode),
+        }
+    }
+}
@@ -0,0 +1,115 @@
+// Idle loops are made of at most this many instructions, including the
+// delay slot of the branch that closes them.
+pub(crate) const MAX_LOOP_LEN: u32 = 8;
+
+/// IdleLoop detects idle loops: short loops that only read memory and
+/// compute on registers (eg: polling a flag, or a branch to self). Once an
+/// iteration of such a loop leaves the GPRs unchanged, so will the following
+/// ones, until memory is written by a device or an interrupt is raised, which
+/// only happens at the next event: the core can then skip ahead to it, rather
+/// than spinning through the loop.
+pub(crate) struct IdleLoop {
+    // Start of the current loop, and whether its body qualifies
+    pc: u32,
+    idle: bool,
+    // GPRs at the end of the last iteration of the loop
+    regs: Option<[u64; 32]>,
+    // Cycles skipped so far
+    skipped: u64,
+}
+
+impl IdleLoop {
+    pub fn new() -> IdleLoop {
+        IdleLoop {
+            pc: 0xFFFF_FFFF,
+            idle: false,
+            regs: None,
+            skipped: 0,
+        }
+    }
+
+    /// Whether the loop starting at the specified address is the current
+    /// one (which was already analyzed).
+    pub fn known(&self, pc: u32) -> bool {
+        self.pc == pc
+    }
+
+    /// Start tracking the loop starting at the specified address, made of
+    /// the specified opcodes (none if they are unknown).
+    pub fn enter(&mut self, pc: u32, body: &[u32]) {
+        self.pc = pc;
+        self.idle = !body.is_empty() && body.iter().all(|&op| idle_op(op));
+        self.regs = None;
+    }
+
+    /// Account for an iteration of the current loop, which left the GPRs in
+    /// the specified state. Returns true if the loop is idle.
+    pub fn iterate(&mut self, regs: &[u64; 32]) -> bool {
+        if !self.idle {
+            return false;
+        }
+        if self.regs.as_ref() == Some(regs) {
+            return true;
+        }
+        self.regs = Some(*regs);
+        false
+    }
+
+    pub fn skip(&mut self, cycles: i64) {
+        self.skipped += cycles as u64;
+    }
+
+    /// Number of cycles skipped in idle loops.
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+}
+
+// Instructions allowed in idle loops: loads, ALU operations that cannot
+// trap, and branches without link. Their result only depends on the GPRs
+// and on memory.
+fn idle_op(op: u32) -> bool {
+    match op >> 26 {
+        0x00 => match op & 0x3f {
+            0x00 | 0x02...0x04 | 0x06 | 0x07 | 0x0F | 0x14 | 0x16 | 0x17 => true,
+            0x21 | 0x23...0x27 | 0x2A | 0x2B | 0x2D | 0x2F => true,
+            0x38 | 0x3A...0x3C | 0x3E | 0x3F => true,
+            _ => false,
+        },
+        0x01 => (op >> 16) & 0x1f <= 0x03,
+        0x02 | 0x04...0x07 | 0x14...0x17 => true,
+        0x09...0x0F | 0x19 => true,
+        0x1A | 0x1B | 0x20...0x27 | 0x37 => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn poll_loop() {
+        // loop: lw t0,0(a0) ; beq t0,zero,loop ; nop
+        let body = [0x8C88_0000, 0x1100_FFFE, 0x0000_0000];
+        let mut idle = IdleLoop::new();
+        idle.enter(0x8000_0100, &body);
+        assert!(idle.known(0x8000_0100));
+
+        let mut regs = [0u64; 32];
+        assert!(!idle.iterate(&regs));
+        assert!(idle.iterate(&regs));
+        regs[8] = 1;
+        assert!(!idle.iterate(&regs));
+        assert!(idle.iterate(&regs));
+
+        // A store, or a counter that traps on overflow, might change what
+        // the next iteration does
+        idle.enter(0x8000_0200, &[0xAC88_0000, 0x1100_FFFE, 0x0000_0000]);
+        assert!(!idle.iterate(&regs));
+        assert!(!idle.iterate(&regs));
+        idle.enter(0x8000_0300, &[0x2108_0001, 0x1100_FFFE, 0x0000_0000]);
+        assert!(!idle.iterate(&regs));
+        assert!(!idle.iterate(&regs));
+    }
+}
@@ -0,0 +1,306 @@
+extern crate cranelift_codegen;
+extern crate cranelift_frontend;
+extern crate cranelift_jit;
+extern crate cranelift_module;
+
+use self::cranelift_codegen::ir::condcodes::IntCC;
+use self::cranelift_codegen::ir::{types, AbiParam, InstBuilder, MemFlags, Value};
+use self::cranelift_codegen::Context;
+use self::cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
+use self::cranelift_jit::{JITBuilder, JITModule};
+use self::cranelift_module::{default_libcall_names, Module};
+use std::cell::Cell;
+use std::mem::{self, ManuallyDrop};
+
+// Number of times a block is entered before it is compiled.
+const HOT_THRESHOLD: u32 = 64;
+
+// Calling native code is only worth it for at least this many instructions.
+const MIN_NATIVE_LEN: usize = 2;
+
+// Size of the generated code after which all of it is freed.
+const CODE_CACHE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Native code compiled from the first instructions of a block. It takes a
+/// pointer to the GPRs, and updates them in place.
+#[derive(Copy, Clone)]
+pub(crate) struct NativeBlock {
+    func: extern "C" fn(*mut u64),
+    len: usize,
+    generation: u64,
+}
+
+impl NativeBlock {
+    /// Number of instructions executed by the native code.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn run(&self, regs: &mut [u64; 32]) {
+        (self.func)(regs.as_mut_ptr());
+    }
+}
+
+/// BlockJit tracks how many times a decoded block was entered, and holds
+/// its native code once it is compiled.
+#[derive(Default)]
+pub(crate) struct BlockJit {
+    hits: Cell<u32>,
+    native: Cell<Option<NativeBlock>>,
+}
+
+impl BlockJit {
+    /// Account for an execution of the block made of the specified opcodes,
+    /// compiling it when it gets hot. Returns its native code, if any. Code
+    /// freed by the compiler since it was compiled is compiled again.
+    pub fn enter<I: Iterator<Item = u32>>(&self, jit: &mut Jit, opcodes: I) -> Option<NativeBlock> {
+        let hits = self.hits.get();
+        if hits < HOT_THRESHOLD {
+            self.hits.set(hits + 1);
+            if hits + 1 < HOT_THRESHOLD {
+                return None;
+            }
+        } else {
+            match self.native.get() {
+                Some(native) if native.generation != jit.generation => {}
+                native => return native,
+            }
+        }
+        let opcodes: Vec<u32> = opcodes.collect();
+        self.native.set(jit.compile(&opcodes));
+        self.native.get()
+    }
+}
+
+/// Jit compiles the hot blocks of the cached engine to native code, through
+/// cranelift. Only the instructions that neither access memory nor raise
+/// exceptions are compiled: the longest prefix of a block made of them runs
+/// natively, and the interpreter takes over from the first other one (which
+/// always includes the final branch).
+///
+/// The code of blocks invalidated by writes can't be freed individually, so
+/// it accumulates: once the generated code exceeds CODE_CACHE_SIZE, all of
+/// it is freed, and the blocks still in use are compiled again when they are
+/// next entered.
+pub(crate) struct Jit {
+    module: ManuallyDrop<JITModule>,
+    ctx: Context,
+    fctx: FunctionBuilderContext,
+    code_size: usize,
+    generation: u64,
+}
+
+impl Jit {
+    pub fn new() -> Jit {
+        let module = Jit::new_module();
+        let ctx = module.make_context();
+        Jit {
+            module: ManuallyDrop::new(module),
+            ctx,
+            fctx: FunctionBuilderContext::new(),
+            code_size: 0,
+            generation: 0,
+        }
+    }
+
+    fn new_module() -> JITModule {
+        let builder = JITBuilder::new(default_libcall_names()).expect("unsupported host");
+        JITModule::new(builder)
+    }
+
+    // Free all the generated code. The native blocks compiled so far must
+    // not be run anymore: they are recognized by their generation.
+    fn flush(&mut self) {
+        let module = mem::replace(&mut *self.module, Jit::new_module());
+        unsafe { module.free_memory() };
+        self.code_size = 0;
+        self.generation += 1;
+    }
+
+    /// Compile the longest prefix of a block made of supported instructions.
+    /// Returns None if it is too short to be worth it.
+    pub fn compile(&mut self, opcodes: &[u32]) -> Option<NativeBlock> {
+        let len = opcodes.iter().take_while(|&&op| supported(op)).count();
+        if len < MIN_NATIVE_LEN {
+            return None;
+        }
+
+        if self.code_size >= CODE_CACHE_SIZE {
+            self.flush();
+        }
+
+        let ptr = self.module.target_config().pointer_type();
+        self.ctx.func.signature.params.push(AbiParam::new(ptr));
+        {
+            let mut b = FunctionBuilder::new(&mut self.ctx.func, &mut self.fctx);
+            let block = b.create_block();
+            b.append_block_params_for_function_params(block);
+            b.switch_to_block(block);
+            b.seal_block(block);
+            let regs = b.block_params(block)[0];
+            for &op in &opcodes[..len] {
+                emit(&mut b, regs, op);
+            }
+            b.ins().return_(&[]);
+            b.finalize();
+        }
+
+        let id = self
+            .module
+            .declare_anonymous_function(&self.ctx.func.signature);
+        let res = id.and_then(|id| self.module.define_function(id, &mut self.ctx).map(|_| id));
+        if let Some(code) = self.ctx.compiled_code() {
+            self.code_size += code.code_info().total_size as usize;
+        }
+        self.module.clear_context(&mut self.ctx);
+        let id = res.ok()?;
+        self.module.finalize_definitions().ok()?;
+        let code = self.module.get_finalized_function(id);
+        Some(NativeBlock {
+            func: unsafe { mem::transmute::<*const u8, extern "C" fn(*mut u64)>(code) },
+            len,
+            generation: self.generation,
+        })
+    }
+}
+
+impl Drop for Jit {
+    fn drop(&mut self) {
+        // JITModule leaks its code when dropped
+        let module = unsafe { ManuallyDrop::take(&mut self.module) };
+        unsafe { module.free_memory() };
+    }
+}
+
+// Instructions compiled to native code: ALU operations without overflow
+// traps, which take a single cycle.
+fn supported(op: u32) -> bool {
+    match op >> 26 {
+        0x00 => match op & 0x3f {
+            0x00 | 0x02 | 0x03 | 0x21 | 0x23 | 0x24...0x27 | 0x2A | 0x2B | 0x2D | 0x2F => true,
+            _ => false,
+        },
+        0x09...0x0F | 0x19 => true,
+        _ => false,
+    }
+}
+
+fn load(b: &mut FunctionBuilder, regs: Value, idx: u32) -> Value {
+    b.ins()
+        .load(types::I64, MemFlags::new(), regs, idx as i32 * 8)
+}
+
+// Sign-extend the low 32 bits of a value, as done by 32-bit operations.
+fn sx32(b: &mut FunctionBuilder, val: Value) -> Value {
+    let val = b.ins().ireduce(types::I32, val);
+    b.ins().sextend(types::I64, val)
+}
+
+// Emit the code of an instruction, with the same semantics as its handler
+// in the interpreter.
+fn emit(b: &mut FunctionBuilder, regs: Value, op: u32) {
+    let (rs, rt, rd) = ((op >> 21) & 0x1f, (op >> 16) & 0x1f, (op >> 11) & 0x1f);
+    let sa = ((op >> 6) & 0x1f) as i64;
+    let (imm, simm) = (op as u16 as i64, op as i16 as i64);
+
+    let (dst, val) = if op >> 26 == 0 {
+        let s = load(b, regs, rs);
+        let t = load(b, regs, rt);
+        let val = match op & 0x3f {
+            0x00 => {
+                let val = b.ins().ishl_imm(t, sa);
+                sx32(b, val)
+            }
+            0x02 | 0x03 => {
+                let t = b.ins().ireduce(types::I32, t);
+                let val = if op & 0x3f == 0x02 {
+                    b.ins().ushr_imm(t, sa)
+                } else {
+                    b.ins().sshr_imm(t, sa)
+                };
+                b.ins().sextend(types::I64, val)
+            }
+            0x21 => {
+                let val = b.ins().iadd(s, t);
+                sx32(b, val)
+            }
+            0x23 => {
+                let val = b.ins().isub(s, t);
+                sx32(b, val)
+            }
+            0x24 => b.ins().band(s, t),
+            0x25 => b.ins().bor(s, t),
+            0x26 => b.ins().bxor(s, t),
+            0x27 => {
+                let val = b.ins().bor(s, t);
+                b.ins().bnot(val)
+            }
+            0x2A | 0x2B => {
+                let cc = if op & 0x3f == 0x2A {
+                    IntCC::SignedLessThan
+                } else {
+                    IntCC::UnsignedLessThan
+                };
+                let cmp = b.ins().icmp(cc, s, t);
+                b.ins().uextend(types::I64, cmp)
+            }
+            0x2D => b.ins().iadd(s, t),
+            0x2F => b.ins().isub(s, t),
+            _ => unreachable!(),
+        };
+        (rd, val)
+    } else {
+        let s = load(b, regs, rs);
+        let val = match op >> 26 {
+            0x09 => {
+                let val = b.ins().iadd_imm(s, simm);
+                sx32(b, val)
+            }
+            0x0A | 0x0B => {
+                let cc = if op >> 26 == 0x0A {
+                    IntCC::SignedLessThan
+                } else {
+                    IntCC::UnsignedLessThan
+                };
+                let cmp = b.ins().icmp_imm(cc, s, simm);
+                b.ins().uextend(types::I64, cmp)
+            }
+            0x0C => b.ins().band_imm(s, imm),
+            0x0D => b.ins().bor_imm(s, imm),
+            0x0E => b.ins().bxor_imm(s, imm),
+            0x0F => b.ins().iconst(types::I64, (simm << 16) as i32 as i64),
+            0x19 => b.ins().iadd_imm(s, simm),
+            _ => unreachable!(),
+        };
+        (rt, val)
+    };
+    if dst != 0 {
+        b.ins().store(MemFlags::new(), val, regs, dst as i32 * 8);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flush_recompiles() {
+        // addiu r1,r1,1 ; addiu r2,r2,2
+        let ops = [0x2421_0001, 0x2442_0002];
+        let mut jit = Jit::new();
+        let block = BlockJit::default();
+        for _ in 1..HOT_THRESHOLD {
+            assert!(block.enter(&mut jit, ops.iter().cloned()).is_none());
+        }
+        let native = block.enter(&mut jit, ops.iter().cloned()).unwrap();
+        assert_eq!(native.len(), 2);
+
+        // Blocks compiled before a flush are compiled again
+        jit.flush();
+        let native = block.enter(&mut jit, ops.iter().cloned()).unwrap();
+        assert_eq!(native.generation, jit.generation);
+        let mut regs = [0u64; 32];
+        native.run(&mut regs);
+        assert_eq!((regs[1], regs[2]), (1, 2));
+    }
+}
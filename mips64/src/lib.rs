@@ -0,0 +1,55 @@
+//! MIPS64 core, as found in the NEC VR4300: CPU, standard coprocessors
+//! (COP0 with TLB and caches, FPU), and disassembler. It has no dependency
+//! on a specific machine: the CPU accesses memory and devices through an
+//! emu::bus::Bus, on which the host maps its own memories and devices, and
+//! custom coprocessors can be installed through the Cop and Cop0 traits.
+//!
+//! Without the default `std` feature, the crate is no_std and only needs
+//! alloc.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(feature = "std")]
+extern crate core;
+#[macro_use]
+extern crate alloc;
+
+#[macro_use]
+extern crate slog;
+
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+
+extern crate emu;
+extern crate num_traits;
+
+mod blocks;
+mod cache;
+mod codewatch;
+mod cp0;
+mod cpu;
+mod disasm;
+mod fpu;
+mod idle;
+#[cfg(feature = "jit")]
+mod jit;
+mod opstats;
+mod segment;
+mod stall;
+mod state;
+mod timing;
+mod tlb;
+
+pub use self::codewatch::CodeWatch;
+pub use self::cp0::Cp0;
+pub use self::cpu::{
+    Cop, Cop0, Cpu, CpuBus, CpuContext, Exception, MemAccess, StepAccess, StepResult,
+};
+pub use self::disasm::{branch_target, disasm, disasm_listing, parse_symbols, reg_index, reg_name};
+pub use self::fpu::Fpu;
+pub use self::opstats::{opcode_kind, OpcodeStats};
+pub use self::segment::{AddrSpace, Mode, Segment};
+pub use self::stall::BusStall;
+pub use self::state::CpuState;
+pub use self::timing::Timing;
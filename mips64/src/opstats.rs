@@ -0,0 +1,113 @@
+use super::disasm::disasm;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// Mask away the operands of an opcode, keeping only the fields that
+/// identify the instruction (including the function field of SPECIAL,
+/// REGIMM and coprocessor instructions).
+pub fn opcode_kind(opcode: u32) -> u32 {
+    match opcode >> 26 {
+        0x00 => opcode & 0xFC00_003F,
+        0x01 => opcode & 0xFC1F_0000,
+        0x10...0x13 => opcode & 0xFFE0_003F,
+        _ => opcode & 0xFC00_0000,
+    }
+}
+
+fn mnemonic(kind: u32) -> String {
+    // An all-zero SLL would be disassembled as a NOP
+    if kind == 0 {
+        return "sll".into();
+    }
+    let dis = disasm(kind, 0);
+    dis.split_whitespace().next().unwrap_or("???").into()
+}
+
+/// OpcodeStats counts how many times each kind of instruction was executed,
+/// and how many times unimplemented instructions were requested. It helps
+/// prioritizing the work on the interpreter using real games.
+#[derive(Clone, Debug, Default)]
+pub struct OpcodeStats {
+    executed: BTreeMap<u32, u64>,
+    unimplemented: BTreeMap<u32, u64>,
+}
+
+impl OpcodeStats {
+    pub fn record(&mut self, opcode: u32) {
+        *self.executed.entry(opcode_kind(opcode)).or_insert(0) += 1;
+    }
+
+    pub fn record_unimplemented(&mut self, opcode: u32) {
+        *self.unimplemented.entry(opcode_kind(opcode)).or_insert(0) += 1;
+    }
+
+    /// Number of executions of the same kind of instruction as opcode.
+    pub fn count(&self, opcode: u32) -> u64 {
+        *self.executed.get(&opcode_kind(opcode)).unwrap_or(&0)
+    }
+
+    pub fn total(&self) -> u64 {
+        self.executed.values().sum()
+    }
+
+    // Sort by decreasing count (and by kind, for a stable output)
+    fn sorted(counts: &BTreeMap<u32, u64>) -> Vec<(u32, u64)> {
+        let mut v: Vec<(u32, u64)> = counts.iter().map(|(&k, &c)| (k, c)).collect();
+        v.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        v
+    }
+
+    /// Unimplemented instructions that were requested, as (mnemonic, count),
+    /// most requested first.
+    pub fn unimplemented(&self) -> Vec<(String, u64)> {
+        OpcodeStats::sorted(&self.unimplemented)
+            .into_iter()
+            .map(|(kind, count)| (mnemonic(kind), count))
+            .collect()
+    }
+
+    /// Textual report: executed instructions, then the unimplemented ones.
+    pub fn report(&self) -> String {
+        let mut out = format!(
+            "executed: {} opcodes, {} kinds\n",
+            self.total(),
+            self.executed.len()
+        );
+        for (kind, count) in OpcodeStats::sorted(&self.executed) {
+            out += &format!("{:>12} {:08x} {}\n", count, kind, mnemonic(kind));
+        }
+        out += &format!("unimplemented: {} kinds\n", self.unimplemented.len());
+        for (kind, count) in OpcodeStats::sorted(&self.unimplemented) {
+            out += &format!("{:>12} {:08x} {}\n", count, kind, mnemonic(kind));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    fn opcode_stats() {
+        let mut stats = OpcodeStats::default();
+        stats.record(0x2401_0001); // addiu at,zr,1
+        stats.record(0x2402_0005); // addiu v0,zr,5
+        stats.record(0x0000_0000); // nop
+        stats.record(0x4600_1080); // add.s
+        stats.record_unimplemented(0x4600_1080);
+
+        assert_eq!(stats.count(0x2403_FFFF), 2);
+        assert_eq!(stats.count(0x0041_1821), 0); // addu
+        assert_eq!(stats.total(), 4);
+        assert_eq!(stats.unimplemented(), vec![("add.s".to_string(), 1)]);
+
+        let report = stats.report();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], "executed: 4 opcodes, 3 kinds");
+        assert_eq!(lines[1], "           2 24000000 addiu");
+        assert_eq!(lines[4], "unimplemented: 1 kinds");
+    }
+}
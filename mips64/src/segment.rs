@@ -0,0 +1,174 @@
+/// Operating mode of the CPU, which selects the segments of the virtual
+/// address space that can be accessed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Kernel,
+    Supervisor,
+    User,
+}
+
+/// Segment of the virtual address space an address belongs to, and how it
+/// is translated into a physical address.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Segment {
+    /// Mapped through the TLB (KUSEG, KSSEG, KSEG3, and their 64-bit
+    /// counterparts)
+    Mapped,
+    /// Directly mapped to a physical address (KSEG0, KSEG1, XKPHYS, and
+    /// KUSEG while ERL is set)
+    Unmapped { paddr: u32, cached: bool },
+}
+
+/// AddrSpace decodes virtual addresses into segments, according to the
+/// operating mode and the addressing mode (32-bit or 64-bit, selected by the
+/// KX/SX/UX bits of Status) of the CPU.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct AddrSpace {
+    pub mode: Mode,
+    // 64-bit addressing enabled for the current mode
+    pub wide: bool,
+    // Error level: KUSEG is unmapped and uncached
+    pub erl: bool,
+}
+
+// Cache algorithm in XKPHYS addresses (bits 61-59) meaning uncached.
+const XKPHYS_UNCACHED: u64 = 2;
+
+impl AddrSpace {
+    /// Effective virtual address: in 32-bit mode, only the low 32 bits of
+    /// the address are used (sign extended), like in the 32-bit
+    /// compatibility segments of the 64-bit address space.
+    pub fn effective(&self, vaddr: u64) -> u64 {
+        if self.wide {
+            vaddr
+        } else {
+            vaddr as i32 as i64 as u64
+        }
+    }
+
+    /// Decode a virtual address. Returns None if the address is not
+    /// accessible in the current mode (address error).
+    pub fn decode(&self, vaddr: u64) -> Option<Segment> {
+        let vaddr = self.effective(vaddr);
+
+        // Sign-extended 32-bit addresses never fall in the 64-bit segments,
+        // so these need no check on the addressing mode.
+        match self.mode {
+            Mode::User => match vaddr {
+                0...0x0000_00FF_FFFF_FFFF => Some(Segment::Mapped),
+                _ => None,
+            },
+            Mode::Supervisor => match vaddr {
+                0...0x0000_00FF_FFFF_FFFF
+                | 0x4000_0000_0000_0000...0x4000_00FF_FFFF_FFFF
+                | 0xFFFF_FFFF_C000_0000...0xFFFF_FFFF_DFFF_FFFF => Some(Segment::Mapped),
+                _ => None,
+            },
+            Mode::Kernel => match vaddr {
+                0...0x0000_00FF_FFFF_FFFF if self.erl => Some(Segment::Unmapped {
+                    paddr: vaddr as u32,
+                    cached: false,
+                }),
+                0...0x0000_00FF_FFFF_FFFF
+                | 0x4000_0000_0000_0000...0x4000_00FF_FFFF_FFFF
+                | 0xC000_0000_0000_0000...0xC000_00FF_7FFF_FFFF => Some(Segment::Mapped),
+                // XKPHYS: physical addresses are 32-bit on the VR4300
+                0x8000_0000_0000_0000...0xBFFF_FFFF_FFFF_FFFF
+                    if vaddr & 0x07FF_FFFF_0000_0000 == 0 =>
+                {
+                    Some(Segment::Unmapped {
+                        paddr: vaddr as u32,
+                        cached: (vaddr >> 59) & 7 != XKPHYS_UNCACHED,
+                    })
+                }
+                // KSEG0
+                0xFFFF_FFFF_8000_0000...0xFFFF_FFFF_9FFF_FFFF => Some(Segment::Unmapped {
+                    paddr: vaddr as u32 & 0x1FFF_FFFF,
+                    cached: true,
+                }),
+                // KSEG1
+                0xFFFF_FFFF_A000_0000...0xFFFF_FFFF_BFFF_FFFF => Some(Segment::Unmapped {
+                    paddr: vaddr as u32 & 0x1FFF_FFFF,
+                    cached: false,
+                }),
+                // KSSEG, KSEG3
+                0xFFFF_FFFF_C000_0000...0xFFFF_FFFF_FFFF_FFFF => Some(Segment::Mapped),
+                _ => None,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_segments() {
+        let kernel = AddrSpace {
+            mode: Mode::Kernel,
+            wide: false,
+            erl: false,
+        };
+        let kseg0 = Segment::Unmapped {
+            paddr: 0x0000_0400,
+            cached: true,
+        };
+        assert_eq!(kernel.decode(0xFFFF_FFFF_8000_0400), Some(kseg0));
+        // Upper bits are ignored in 32-bit mode
+        assert_eq!(kernel.decode(0x0000_0000_8000_0400), Some(kseg0));
+        assert_eq!(
+            kernel.decode(0xFFFF_FFFF_A400_0040),
+            Some(Segment::Unmapped {
+                paddr: 0x0400_0040,
+                cached: false,
+            })
+        );
+        assert_eq!(kernel.decode(0x0040_0000), Some(Segment::Mapped));
+        assert_eq!(kernel.decode(0xFFFF_FFFF_C000_0000), Some(Segment::Mapped));
+
+        let xkphys = AddrSpace {
+            wide: true,
+            ..kernel
+        };
+        assert_eq!(
+            xkphys.decode(0x9000_0000_0400_0040),
+            Some(Segment::Unmapped {
+                paddr: 0x0400_0040,
+                cached: false,
+            })
+        );
+        assert_eq!(xkphys.decode(0x9000_0001_0000_0000), None);
+        assert_eq!(xkphys.decode(0x0000_0000_8000_0400), Some(Segment::Mapped));
+        assert_eq!(kernel.decode(0x0000_0000_8000_0400), Some(kseg0));
+        assert_eq!(xkphys.decode(0x0000_0100_0000_0000), None);
+
+        let erl = AddrSpace {
+            erl: true,
+            ..kernel
+        };
+        assert_eq!(
+            erl.decode(0x0040_0000),
+            Some(Segment::Unmapped {
+                paddr: 0x0040_0000,
+                cached: false,
+            })
+        );
+
+        let user = AddrSpace {
+            mode: Mode::User,
+            ..kernel
+        };
+        assert_eq!(user.decode(0x0040_0000), Some(Segment::Mapped));
+        assert_eq!(user.decode(0xFFFF_FFFF_8000_0400), None);
+        let supervisor = AddrSpace {
+            mode: Mode::Supervisor,
+            ..kernel
+        };
+        assert_eq!(
+            supervisor.decode(0xFFFF_FFFF_C000_0000),
+            Some(Segment::Mapped)
+        );
+        assert_eq!(supervisor.decode(0xFFFF_FFFF_E000_0000), None);
+    }
+}
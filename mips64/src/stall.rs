@@ -0,0 +1,67 @@
+use alloc::rc::Rc;
+use core::cell::Cell;
+use emu::sync::convert_cycles;
+
+#[derive(Debug)]
+struct Stall {
+    // Cycles the CPU still has to wait, and the frequency of its clock
+    cycles: Cell<i64>,
+    freq: Cell<i64>,
+}
+
+/// BusStall is a shared handle through which the devices mapped on the bus
+/// stall the CPU: an access that keeps the CPU waiting (eg: a read from the
+/// cartridge, which goes through the slow PI bus) adds the time it takes,
+/// and the CPU charges it to the instruction that made the access. It is
+/// held by the CPU itself and by the devices that stall it.
+#[derive(Clone, Debug)]
+pub struct BusStall(Rc<Stall>);
+
+impl BusStall {
+    /// Create a handle for a CPU running at the specified frequency (in Hz).
+    pub fn new(freq: i64) -> BusStall {
+        BusStall(Rc::new(Stall {
+            cycles: Cell::new(0),
+            freq: Cell::new(freq),
+        }))
+    }
+
+    /// Change the frequency of the CPU clock.
+    pub fn set_frequency(&self, freq: i64) {
+        self.0.freq.set(freq);
+    }
+
+    /// Stall the CPU for the specified number of cycles of a clock running
+    /// at `freq` Hz.
+    pub fn stall(&self, cycles: i64, freq: i64) {
+        let cycles = convert_cycles(cycles, freq, self.0.freq.get());
+        self.0.cycles.set(self.0.cycles.get() + cycles);
+    }
+
+    /// Return the CPU cycles of the stalls added since the last call.
+    pub fn take(&self) -> i64 {
+        self.0.cycles.replace(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stall_cycles() {
+        let cpu = BusStall::new(93_750_000);
+        let dev = cpu.clone();
+        assert_eq!(cpu.take(), 0);
+
+        // RCP cycles are converted to CPU cycles, and accumulated
+        dev.stall(100, 62_500_000);
+        dev.stall(2, 62_500_000);
+        assert_eq!(cpu.take(), 150 + 3);
+        assert_eq!(cpu.take(), 0);
+
+        cpu.set_frequency(62_500_000);
+        dev.stall(100, 62_500_000);
+        assert_eq!(cpu.take(), 100);
+    }
+}
@@ -0,0 +1,78 @@
+use super::disasm::{reg_index, reg_name};
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Snapshot of the architectural state of the core, as returned by
+/// Cpu::state(). It is detached from the core, so it can be kept around
+/// (eg: to compare it with a later state) and printed as a register dump.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CpuState {
+    /// Address of the next instruction to execute.
+    pub pc: u32,
+    /// Target of the pending branch, if the next instruction is in its
+    /// delay slot.
+    pub branch_pc: Option<u32>,
+    pub regs: [u64; 32],
+    pub hi: u64,
+    pub lo: u64,
+    pub clock: i64,
+    /// Status of the halt and single-step lines.
+    pub halt: bool,
+    pub single_step: bool,
+    /// COP0 registers, as (name, value), in the order reported by
+    /// Cop0::dump_regs().
+    pub cop0: Vec<(&'static str, u64)>,
+}
+
+impl CpuState {
+    /// Value of a register by name: a GPR (see reg_index()), "hi", "lo",
+    /// "pc", or a COP0 register (case-insensitive, eg: "status").
+    pub fn reg(&self, name: &str) -> Option<u64> {
+        match name {
+            "hi" => return Some(self.hi),
+            "lo" => return Some(self.lo),
+            "pc" => return Some(self.pc as u64),
+            _ => {}
+        }
+        if let Some(idx) = reg_index(name) {
+            return Some(self.regs[idx]);
+        }
+        self.cop0
+            .iter()
+            .find(|&&(cname, _)| cname.eq_ignore_ascii_case(name))
+            .map(|&(_, val)| val)
+    }
+}
+
+impl fmt::Display for CpuState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "pc:{:08x}", self.pc)?;
+        if let Some(tgt) = self.branch_pc {
+            write!(f, " (delay slot, branch to {:08x})", tgt)?;
+        }
+        write!(f, " clock:{}", self.clock)?;
+        if self.halt {
+            write!(f, " halted")?;
+        }
+        if self.single_step {
+            write!(f, " single-step")?;
+        }
+        writeln!(f)?;
+
+        for (idx, val) in self.regs.iter().enumerate() {
+            let sep = if idx % 4 == 3 { "\n" } else { "  " };
+            write!(f, "{}:{:016x}{}", reg_name(idx), val, sep)?;
+        }
+        writeln!(f, "hi:{:016x}  lo:{:016x}", self.hi, self.lo)?;
+
+        for (idx, &(name, val)) in self.cop0.iter().enumerate() {
+            let sep = if idx % 4 == 3 || idx == self.cop0.len() - 1 {
+                "\n"
+            } else {
+                "  "
+            };
+            write!(f, "{:>8}:{:016x}{}", name, val, sep)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,113 @@
+/// Accuracy profile of the CPU timing, which decides how many cycles each
+/// instruction takes.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Timing {
+    /// Every instruction takes a single cycle.
+    Simple,
+    /// Approximation of the VR4300 pipeline: multiplications, divisions and
+    /// FPU operations take multiple cycles, and using the result of a load
+    /// in the following instruction stalls for one cycle. Branches take no
+    /// additional cycle, as their latency is hidden by the delay slot.
+    Accurate,
+}
+
+impl Default for Timing {
+    fn default() -> Timing {
+        Timing::Simple
+    }
+}
+
+impl Timing {
+    pub fn parse(s: &str) -> Option<Timing> {
+        match s {
+            "simple" => Some(Timing::Simple),
+            "accurate" => Some(Timing::Accurate),
+            _ => None,
+        }
+    }
+
+    /// Cycles taken by an instruction, without interlocks.
+    pub fn cycles(self, opcode: u32) -> i64 {
+        if self == Timing::Simple {
+            return 1;
+        }
+        match opcode >> 26 {
+            0x00 => match opcode & 0x3F {
+                0x18 | 0x19 => 5,  // MULT, MULTU
+                0x1A | 0x1B => 37, // DIV, DIVU
+                0x1C | 0x1D => 8,  // DMULT, DMULTU
+                0x1E | 0x1F => 69, // DDIV, DDIVU
+                _ => 1,
+            },
+            0x11 => fpu_cycles(opcode),
+            _ => 1,
+        }
+    }
+}
+
+fn fpu_cycles(opcode: u32) -> i64 {
+    let fmt = (opcode >> 21) & 0x1F;
+    match (fmt, opcode & 0x3F) {
+        (16...17, 0x00...0x01) => 3, // ADD, SUB
+        (16, 0x02) => 5,             // MUL.S
+        (17, 0x02) => 8,             // MUL.D
+        (16, 0x03...0x04) => 29,     // DIV.S, SQRT.S
+        (17, 0x03...0x04) => 58,     // DIV.D, SQRT.D
+        (16...17, 0x08...0x0F) => 5, // ROUND, TRUNC, CEIL, FLOOR
+        (16...21, 0x20...0x25) => 5, // CVT
+        _ => 1,
+    }
+}
+
+/// GPR loaded by an instruction, or 0 if it is not a load.
+pub fn load_target(opcode: u32) -> usize {
+    match opcode >> 26 {
+        0x1A | 0x1B | 0x20...0x27 | 0x30 | 0x34 | 0x37 => (opcode >> 16) as usize & 0x1F,
+        _ => 0,
+    }
+}
+
+/// Whether an instruction reads the specified GPR (other than r0).
+pub fn reads_reg(opcode: u32, reg: usize) -> bool {
+    let rs = (opcode >> 21) as usize & 0x1F;
+    let rt = (opcode >> 16) as usize & 0x1F;
+    let (rs_used, rt_used) = match opcode >> 26 {
+        0x00 => (true, true),                             // SPECIAL
+        0x02 | 0x03 => (false, false),                    // J, JAL
+        0x04 | 0x05 | 0x14 | 0x15 => (true, true),        // BEQ, BNE, BEQL, BNEL
+        0x10...0x13 => (false, rs >= 4 && rs <= 6),       // MTCz, DMTCz, CTCz
+        0x1A | 0x1B | 0x22 | 0x26 => (true, true),        // LDL, LDR, LWL, LWR
+        0x28...0x2E | 0x38 | 0x3C | 0x3F => (true, true), // Stores
+        _ => (true, false),
+    };
+    reg != 0 && ((rs_used && rs == reg) || (rt_used && rt == reg))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn instruction_cycles() {
+        let mult = 0x0022_0018; // MULT r1, r2
+        let ddiv = 0x0022_001E; // DDIV r1, r2
+        let div_d = 0x4622_0803; // DIV.D f0, f1, f2
+        assert_eq!(Timing::Simple.cycles(mult), 1);
+        assert_eq!(Timing::Accurate.cycles(mult), 5);
+        assert_eq!(Timing::Accurate.cycles(ddiv), 69);
+        assert_eq!(Timing::Accurate.cycles(div_d), 58);
+        assert_eq!(Timing::Accurate.cycles(0), 1);
+
+        let lw = 0x8C22_0000; // LW r2, 0(r1)
+        let sw = 0xAC62_0000; // SW r2, 0(r3)
+        let addiu = 0x2443_0001; // ADDIU r3, r2, 1
+        assert_eq!(load_target(lw), 2);
+        assert_eq!(load_target(sw), 0);
+        assert!(reads_reg(sw, 2) && reads_reg(sw, 3));
+        assert!(reads_reg(addiu, 2) && !reads_reg(addiu, 3));
+        assert!(!reads_reg(lw, 2));
+        assert_eq!(Timing::parse("accurate"), Some(Timing::Accurate));
+        assert_eq!(Timing::parse("fast"), None);
+    }
+}
@@ -0,0 +1,138 @@
+/// Number of entries in the VR4300 TLB.
+pub const TLB_ENTRIES: usize = 32;
+
+// EntryLo flags
+const ENTRYLO_G: u32 = 1 << 0;
+const ENTRYLO_V: u32 = 1 << 1;
+const ENTRYLO_D: u32 = 1 << 2;
+
+/// TlbEntry maps a pair of consecutive virtual pages (even and odd) to two
+/// physical pages. Fields use the same layout as the COP0 registers they are
+/// loaded from (PageMask, EntryHi, EntryLo0/EntryLo1).
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct TlbEntry {
+    pub page_mask: u32,
+    pub entry_hi: u64,
+    pub entry_lo: [u32; 2],
+}
+
+impl TlbEntry {
+    fn global(&self) -> bool {
+        // The G bit is the AND of the G bits of both EntryLo
+        self.entry_lo[0] & self.entry_lo[1] & ENTRYLO_G != 0
+    }
+
+    fn asid(&self) -> u8 {
+        self.entry_hi as u8
+    }
+
+    // Mask of the virtual address bits covered by the page pair.
+    fn offset_mask(&self) -> u32 {
+        self.page_mask | 0x1FFF
+    }
+
+    fn matches(&self, vaddr: u32, asid: u8) -> bool {
+        let mask = !self.offset_mask();
+        vaddr & mask == self.entry_hi as u32 & mask && (self.global() || self.asid() == asid)
+    }
+}
+
+/// Reason of a failed TLB translation.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TlbError {
+    /// No entry matches the address (TLB refill)
+    Miss,
+    /// The matching page is not valid
+    Invalid,
+    /// Write to a page which is not dirty (ie: write-protected)
+    Modified,
+}
+
+pub struct Tlb {
+    entries: [TlbEntry; TLB_ENTRIES],
+}
+
+impl Tlb {
+    pub fn new() -> Tlb {
+        Tlb {
+            entries: [TlbEntry::default(); TLB_ENTRIES],
+        }
+    }
+
+    pub fn entry(&self, idx: usize) -> TlbEntry {
+        self.entries[idx % TLB_ENTRIES]
+    }
+
+    pub fn set_entry(&mut self, idx: usize, mut e: TlbEntry) {
+        // VPN2 bits covered by the page mask are not stored
+        e.entry_hi &= !(e.page_mask as u64);
+        self.entries[idx % TLB_ENTRIES] = e;
+    }
+
+    /// Search the entry matching the VPN2 and ASID in the specified EntryHi
+    /// value (as done by TLBP).
+    pub fn probe(&self, entry_hi: u64) -> Option<usize> {
+        self.entries
+            .iter()
+            .position(|e| e.matches(entry_hi as u32, entry_hi as u8))
+    }
+
+    /// Translate a virtual address in a mapped segment, for the specified ASID.
+    pub fn translate(&self, vaddr: u32, asid: u8, write: bool) -> Result<u32, TlbError> {
+        let e = match self.entries.iter().find(|e| e.matches(vaddr, asid)) {
+            Some(e) => e,
+            None => return Err(TlbError::Miss),
+        };
+
+        // The lowest bit of the VPN selects the even or odd page
+        let page_size = (e.offset_mask() >> 1) + 1;
+        let lo = e.entry_lo[(vaddr & page_size != 0) as usize];
+        if lo & ENTRYLO_V == 0 {
+            return Err(TlbError::Invalid);
+        }
+        if write && lo & ENTRYLO_D == 0 {
+            return Err(TlbError::Modified);
+        }
+        let pfn = ((lo >> 6) & 0xF_FFFF) << 12;
+        Ok((pfn & !(page_size - 1)) | (vaddr & (page_size - 1)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tlb_translate() {
+        let mut tlb = Tlb::new();
+        // 16KB pages at 0x0040_0000 (ASID 5) -> 0x0010_0000 (valid, dirty)
+        // and 0x0020_0000 (valid, clean)
+        tlb.set_entry(
+            3,
+            TlbEntry {
+                page_mask: 0x6000,
+                entry_hi: 0x0040_0005,
+                entry_lo: [(0x100 << 6) | 0x6, (0x200 << 6) | 0x2],
+            },
+        );
+        assert_eq!(tlb.translate(0x0040_1234, 5, true), Ok(0x0010_1234));
+        assert_eq!(tlb.translate(0x0040_5678, 5, false), Ok(0x0020_1678));
+        assert_eq!(tlb.translate(0x0040_5678, 5, true), Err(TlbError::Modified));
+        assert_eq!(tlb.translate(0x0040_1234, 6, false), Err(TlbError::Miss));
+        assert_eq!(tlb.translate(0x0040_8000, 5, false), Err(TlbError::Miss));
+        assert_eq!(tlb.probe(0x0040_6005), Some(3));
+        assert_eq!(tlb.probe(0x0040_0006), None);
+
+        // Global entries ignore the ASID; the page can be invalid
+        tlb.set_entry(
+            0,
+            TlbEntry {
+                page_mask: 0,
+                entry_hi: 0x1000_2000,
+                entry_lo: [0x1, 0x3],
+            },
+        );
+        assert_eq!(tlb.translate(0x1000_2010, 9, false), Err(TlbError::Invalid));
+        assert_eq!(tlb.translate(0x1000_3010, 9, false), Ok(0x10));
+    }
+}
@@ -0,0 +1,1828 @@
+#[macro_use]
+extern crate slog;
+
+extern crate byteorder;
+extern crate emu;
+extern crate mips64;
+
+use byteorder::BigEndian;
+use emu::bus::be::{Bus, Mem};
+use mips64::{
+    CodeWatch, Cop, Cp0, Cpu, CpuBus, CpuContext, Exception, Fpu, MemAccess, StepAccess, Timing,
+};
+use slog::Discard;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+const RAM_SIZE: usize = 1024 * 1024;
+
+struct TestCpu {
+    cpu: Cpu,
+    bus: Rc<RefCell<Box<Bus>>>,
+    ram: Mem,
+}
+
+fn make_cpu() -> TestCpu {
+    let logger = slog::Logger::root(Discard, o!());
+    let bus = Rc::new(RefCell::new(Bus::new(logger.new(o!()))));
+    let ram = Mem::new(RAM_SIZE, Default::default());
+    bus.borrow_mut()
+        .map_mem(0x0000_0000, RAM_SIZE as u32 - 1, &ram)
+        .unwrap();
+
+    let mut cpu = Cpu::new(logger.new(o!()), bus.clone());
+    cpu.set_cop0(Cp0::new(logger.new(o!())));
+    TestCpu { cpu, bus, ram }
+}
+
+impl TestCpu {
+    // Load a program at the specified (KSEG0) address, and run it for
+    // the specified number of cycles.
+    fn run(&mut self, pc: u32, insn: &[u32], cycles: i64) {
+        for (idx, op) in insn.iter().enumerate() {
+            self.ram
+                .write::<BigEndian, u32>((pc & 0x1FFF_FFFF) + idx as u32 * 4, *op);
+        }
+        self.cpu.ctx_mut().set_pc(pc);
+        let until = self.cpu.ctx().clock + cycles;
+        self.cpu.run(until);
+    }
+
+    // Map additional memory at the specified physical address.
+    fn map_mem(&self, addr: u32, mem: &Mem) {
+        self.bus
+            .borrow_mut()
+            .map_mem(addr, addr + mem.len() as u32 - 1, mem)
+            .unwrap();
+    }
+
+    fn reg(&self, idx: usize) -> u64 {
+        self.cpu.ctx().regs[idx]
+    }
+
+    fn set_reg(&mut self, idx: usize, val: u64) {
+        self.cpu.ctx_mut().regs[idx] = val;
+    }
+}
+
+fn itype(op: u32, rs: u32, rt: u32, imm: i16) -> u32 {
+    (op << 26) | (rs << 21) | (rt << 16) | (imm as u16 as u32)
+}
+
+fn lw(rt: u32, off: i16, base: u32) -> u32 {
+    itype(0x23, base, rt, off)
+}
+fn sw(rt: u32, off: i16, base: u32) -> u32 {
+    itype(0x2B, base, rt, off)
+}
+fn addi(rt: u32, rs: u32, imm: i16) -> u32 {
+    itype(0x08, rs, rt, imm)
+}
+fn addiu(rt: u32, rs: u32, imm: i16) -> u32 {
+    itype(0x09, rs, rt, imm)
+}
+fn beq(rs: u32, rt: u32, off: i16) -> u32 {
+    itype(0x04, rs, rt, off)
+}
+const NOP: u32 = 0;
+
+#[test]
+fn ea_negative_offset() {
+    let mut t = make_cpu();
+    t.ram.write::<BigEndian, u32>(0x1000, 0x1234_5678);
+    t.set_reg(1, 0x8000_1010);
+    t.run(0x8000_0000, &[lw(2, -0x10, 1)], 1);
+    assert_eq!(t.reg(2), 0x1234_5678);
+}
+
+#[test]
+fn ea_wraps_around() {
+    let mut t = make_cpu();
+    tlb_map(&mut t, 0, 0x0000_0000, 0x0000_0000, TLB_DIRTY);
+    t.ram.write::<BigEndian, u32>(0x10, 0xCAFE_BABE);
+    t.set_reg(1, 0xFFFF_FFF0);
+    t.set_reg(3, 0x0BAD_F00D);
+    t.run(0x8000_0000, &[lw(2, 0x20, 1), sw(3, 0x24, 1)], 2);
+    assert_eq!(t.reg(2), 0xFFFF_FFFF_CAFE_BABE);
+    assert_eq!(t.ram.read::<BigEndian, u32>(0x14), 0x0BAD_F00D);
+}
+
+#[test]
+fn addiu_wraps_around() {
+    let mut t = make_cpu();
+    t.set_reg(1, 0x7FFF_FFFF);
+    t.set_reg(3, 0);
+    t.run(0x8000_0000, &[addiu(2, 1, 1), addiu(4, 3, -1)], 2);
+    assert_eq!(t.reg(2), 0xFFFF_FFFF_8000_0000);
+    assert_eq!(t.reg(4), 0xFFFF_FFFF_FFFF_FFFF);
+}
+
+#[test]
+fn branch_backward() {
+    let mut t = make_cpu();
+    t.set_reg(1, 0);
+    // loop: addiu r1,r1,1 ; beq r0,r0,loop ; nop
+    t.run(0x8000_0100, &[addiu(1, 1, 1), beq(0, 0, -2), NOP], 3 * 4);
+    assert_eq!(t.reg(1), 4);
+    assert_eq!(t.cpu.ctx().get_pc(), 0x8000_0100);
+}
+
+#[test]
+fn accurate_timing() {
+    let mut t = make_cpu();
+    t.cpu.set_timing(Timing::Accurate);
+    t.set_reg(1, 0xFFFF_FFFF_8000_1000);
+    let mult = 0x0022_0018; // MULT r1, r2
+
+    // MULT takes 5 cycles; the ADDIU using the result of the LW stalls
+    // for one cycle, while the one after it does not.
+    let clock = t.cpu.ctx().clock;
+    let prog = [mult, lw(2, 0, 1), addiu(3, 2, 1), addiu(4, 2, 1)];
+    t.run(0x8000_0000, &prog, 9);
+    assert_eq!(t.cpu.ctx().clock, clock + 9);
+    assert_eq!(t.cpu.ctx().get_pc(), 0x8000_0010);
+
+    t.cpu.set_timing(Timing::Simple);
+    let clock = t.cpu.ctx().clock;
+    t.run(0x8000_0000, &prog, 4);
+    assert_eq!(t.cpu.ctx().clock, clock + 4);
+    assert_eq!(t.cpu.ctx().get_pc(), 0x8000_0010);
+}
+
+#[test]
+fn self_modifying_code() {
+    let mut t = make_cpu();
+    let watch = CodeWatch::new();
+    t.cpu.set_code_watch(watch.clone());
+
+    // The store rewrites the instruction that follows it
+    t.set_reg(1, 0xFFFF_FFFF_8000_0100);
+    t.set_reg(2, addiu(3, 3, 7) as u64);
+    t.run(0x8000_0100, &[sw(2, 8, 1), NOP, addiu(3, 3, 1)], 3);
+    assert_eq!(t.reg(3), 7);
+
+    // Fetched pages are tracked, so that DMAs can invalidate them
+    assert!(watch.invalidate(0x0000_0104, 4));
+    assert!(!watch.invalidate(0x0000_2000, 0x100));
+    assert_eq!(watch.generation(), 2);
+}
+
+#[test]
+fn branch_likely_annulled() {
+    // BEQL, BNEL, BLEZL, BGTZL, with a value of r1 that makes them not taken
+    let cases = [(0x14, 1), (0x15, 0), (0x16, 1), (0x17, 0)];
+    for &(op, r1) in cases.iter() {
+        let mut t = make_cpu();
+        t.set_reg(1, r1);
+        let clock = t.cpu.ctx().clock;
+        let prog = [itype(op, 1, 0, 10), addiu(2, 2, 1), addiu(3, 3, 1)];
+        t.run(0x8000_0000, &prog, 3);
+        assert_eq!((t.reg(2), t.reg(3)), (0, 1), "opcode {:x}", op);
+        assert_eq!(t.cpu.ctx().clock, clock + 3);
+        assert_eq!(t.cpu.ctx().get_pc(), 0x8000_000C);
+
+        // Taken: the delay slot is executed
+        t.set_reg(1, r1 ^ 1);
+        t.set_reg(2, 0);
+        t.set_reg(3, 0);
+        let prog = [
+            itype(op, 1, 0, 2),
+            addiu(2, 2, 1),
+            addiu(3, 3, 1),
+            addiu(4, 4, 1),
+        ];
+        t.run(0x8000_0000, &prog, 3);
+        assert_eq!((t.reg(2), t.reg(3), t.reg(4)), (1, 0, 1), "opcode {:x}", op);
+    }
+
+    // Run ending right after the branch: the slot is skipped before
+    // returning, taking its cycle
+    let mut t = make_cpu();
+    t.set_reg(1, 1);
+    let clock = t.cpu.ctx().clock;
+    t.run(
+        0x8000_0000,
+        &[itype(0x14, 1, 0, 10), addiu(2, 2, 1), addiu(3, 3, 1)],
+        1,
+    );
+    assert_eq!(t.cpu.ctx().get_pc(), 0x8000_0008);
+    assert_eq!(t.cpu.ctx().clock, clock + 2);
+    t.cpu.run(clock + 3);
+    assert_eq!((t.reg(2), t.reg(3)), (0, 1));
+
+    // Branch at the end of memory: the annulled slot is not fetched, so the
+    // fetch exception is raised by the next instruction, not the slot
+    let mut t = make_cpu();
+    t.set_reg(1, 1);
+    t.ram.write::<BigEndian, u32>(0x180, mfc0(5, 14));
+    let end = 0x8000_0000 + RAM_SIZE as u32;
+    t.run(end - 4, &[itype(0x14, 1, 0, 10)], 4);
+    assert_eq!(t.cpu.exception_stats().get(&Exception::ADEL), Some(&1));
+    assert_eq!(t.reg(5) as u32, end + 4);
+}
+
+#[test]
+fn lenient_unimplemented_opcode() {
+    let mut t = make_cpu();
+    t.cpu.set_lenient(true);
+    t.set_reg(1, 0);
+    // reserved opcode (0x3B), then addiu r1,r1,1
+    t.run(0x8000_0000, &[0xEC00_0000, addiu(1, 1, 1)], 2);
+    assert_eq!(t.reg(1), 1);
+}
+
+#[test]
+fn r0_hardwired() {
+    let mut t = make_cpu();
+    t.set_reg(1, 0xFFFF_FFFF_8000_1000);
+    t.ram.write::<BigEndian, u32>(0x1000, 0x1234_5678);
+    // addiu r0,r0,5 ; lw r0,0(r1) ; addu r2,r0,r0
+    t.run(0x8000_0000, &[addiu(0, 0, 5), lw(0, 0, 1), 0x0000_1021], 3);
+    assert_eq!(t.reg(0), 0);
+    assert_eq!(t.reg(2), 0);
+}
+
+#[test]
+fn div_by_zero_and_overflow() {
+    let mut t = make_cpu();
+    t.set_reg(1, 0xFFFF_FFFF_FFFF_FFF9); // -7
+    t.set_reg(2, 0);
+    t.set_reg(3, 0x7FFF_FFFF);
+    t.set_reg(4, 1);
+    t.set_reg(5, 0x1234);
+    // div r1,r2 ; add r5,r3,r4 (overflow: r5 is unchanged, exception)
+    t.run(0x8000_0100, &[0x0022_001A, 0x0064_2820], 2);
+    assert_eq!(t.cpu.ctx().lo, 1);
+    assert_eq!(t.cpu.ctx().hi, 0xFFFF_FFFF_FFFF_FFF9);
+    assert_eq!(t.reg(5), 0x1234);
+    assert_eq!(t.cpu.ctx().get_pc(), 0x8000_0180);
+}
+
+// Divisions by zero do not trap: LO is all ones (or 1, for signed divisions
+// of negative numbers), and HI is the dividend. Overflowing signed
+// divisions wrap around.
+#[test]
+fn div_results() {
+    let special = |rs: u32, rt: u32, func: u32| (rs << 21) | (rt << 16) | func;
+    let (div, divu, ddiv, ddivu) = (0x1A, 0x1B, 0x1E, 0x1F);
+    let tests: [(u32, u64, u64, u64, u64); 8] = [
+        (div, 7, 0, !0, 7),
+        (div, 0xFFFF_FFFF_8000_0000, 0, 1, 0xFFFF_FFFF_8000_0000),
+        (divu, 0x8000_0000, 0, !0, 0xFFFF_FFFF_8000_0000),
+        (ddiv, 0xFFFF_FFFF_FFFF_FFF9, 0, 1, 0xFFFF_FFFF_FFFF_FFF9),
+        (ddiv, 7, 0, !0, 7),
+        (ddivu, 0x8000_0000_0000_0007, 0, !0, 0x8000_0000_0000_0007),
+        (div, 0xFFFF_FFFF_8000_0000, !0, 0xFFFF_FFFF_8000_0000, 0),
+        (ddiv, 0x8000_0000_0000_0000, !0, 0x8000_0000_0000_0000, 0),
+    ];
+    for &(func, rs, rt, lo, hi) in tests.iter() {
+        let mut t = make_cpu();
+        t.set_reg(1, rs);
+        t.set_reg(2, rt);
+        t.run(0x8000_0000, &[special(1, 2, func)], 1);
+        assert_eq!(
+            (t.cpu.ctx().lo, t.cpu.ctx().hi),
+            (lo, hi),
+            "func={:x} rs={:x} rt={:x}",
+            func,
+            rs,
+            rt
+        );
+    }
+}
+
+#[test]
+fn overflow_exception() {
+    let mut t = make_cpu();
+    t.set_reg(1, 0x7FFF_FFFF); // INT_MAX
+    t.set_reg(3, 0xFFFF_FFFF_8000_0000); // INT_MIN
+    t.set_reg(4, 1);
+    t.set_reg(5, 0x7FFF_FFFF_FFFF_FFFF);
+    t.set_reg(2, 0x1234);
+
+    // addi v0,at,1: the destination is not modified
+    t.run(0x8000_0100, &[addi(2, 1, 1)], 1);
+    assert_eq!(t.reg(2), 0x1234);
+    assert_eq!(t.cpu.ctx().get_pc(), 0x8000_0180);
+
+    // sub v0,v1,a0 ; dadd v0,a1,a0
+    t.run(0x8000_0100, &[0x0064_1022], 1);
+    t.run(0x8000_0100, &[0x00A4_102C], 1);
+    assert_eq!(t.reg(2), 0x1234);
+    assert_eq!(t.cpu.exception_stats().get(&Exception::OV), Some(&3));
+
+    // No overflow
+    t.run(0x8000_0100, &[addi(2, 1, -1)], 1);
+    assert_eq!(t.reg(2), 0x7FFF_FFFE);
+    assert_eq!(t.cpu.exception_stats().get(&Exception::OV), Some(&3));
+}
+
+// An overflow in a branch delay slot restarts from the branch: EPC points
+// to it, and Cause.BD is set.
+#[test]
+fn overflow_in_delay_slot() {
+    let mut t = make_cpu();
+    let handler = [mfc0(5, 14), mfc0(6, 13)];
+    for (idx, op) in handler.iter().enumerate() {
+        t.ram.write::<BigEndian, u32>(0x180 + idx as u32 * 4, *op);
+    }
+    t.set_reg(1, 0x7FFF_FFFF);
+    t.set_reg(4, 1);
+    t.set_reg(2, 0x1234);
+
+    // beq zero,zero,+4 ; add v0,at,a0
+    t.run(0x8000_0100, &[beq(0, 0, 4), 0x0024_1020], 4);
+    assert_eq!(t.cpu.exception_stats().get(&Exception::OV), Some(&1));
+    assert_eq!(t.reg(2), 0x1234);
+    assert_eq!(t.reg(5), 0xFFFF_FFFF_8000_0100);
+    assert_eq!(t.reg(6) & (1 << 31), 1 << 31);
+    assert_eq!((t.reg(6) >> 2) & 0x1F, 0x0C);
+    assert_eq!(t.cpu.ctx().get_pc(), 0x8000_0188);
+}
+
+// Breakpoints in the delay slot of a jump also point EPC to the jump;
+// Cause.BD is cleared again by the next exception outside a delay slot.
+#[test]
+fn break_in_delay_slot() {
+    let mut t = make_cpu();
+    let handler = [mfc0(5, 14), mfc0(6, 13)];
+    for (idx, op) in handler.iter().enumerate() {
+        t.ram.write::<BigEndian, u32>(0x180 + idx as u32 * 4, *op);
+    }
+
+    // j 0x80000220 ; break
+    let j = (0x02 << 26) | ((0x8000_0220u32 >> 2) & 0x03FF_FFFF);
+    t.run(0x8000_0200, &[j, 0x0000_000D], 4);
+    assert_eq!(t.cpu.exception_stats().get(&Exception::BP), Some(&1));
+    assert_eq!(t.reg(5), 0xFFFF_FFFF_8000_0200);
+    assert_eq!(t.reg(6) & (1 << 31), 1 << 31);
+    assert_eq!((t.reg(6) >> 2) & 0x1F, 0x09);
+
+    // Clear EXL, then raise a syscall outside of a delay slot
+    t.run(0x8000_0300, &[mtc0(0, 12), 0x0000_000C], 4);
+    assert_eq!(t.reg(5), 0xFFFF_FFFF_8000_0304);
+    assert_eq!(t.reg(6) & (1 << 31), 0);
+    assert_eq!((t.reg(6) >> 2) & 0x1F, 0x08);
+}
+
+// Traps compare full 64-bit registers; the immediate forms sign-extend the
+// immediate, even for unsigned compares.
+#[test]
+fn trap_instructions() {
+    let special = |rs: u32, rt: u32, func: u32| (rs << 21) | (rt << 16) | func;
+    let tests = [
+        (special(1, 2, 0x30), false),     // tge at,v0
+        (special(1, 2, 0x31), true),      // tgeu at,v0
+        (special(1, 2, 0x32), true),      // tlt at,v0
+        (special(1, 2, 0x33), false),     // tltu at,v0
+        (special(1, 1, 0x34), true),      // teq at,at
+        (special(1, 1, 0x36), false),     // tne at,at
+        (itype(0x01, 1, 0x08, -1), true), // tgei at,-1
+        (itype(0x01, 1, 0x09, -1), true), // tgeiu at,-1
+        (itype(0x01, 1, 0x0A, 0), true),  // tlti at,0
+        (itype(0x01, 2, 0x0B, -1), true), // tltiu v0,-1
+        (itype(0x01, 2, 0x0C, 1), true),  // teqi v0,1
+        (itype(0x01, 2, 0x0E, 1), false), // tnei v0,1
+    ];
+
+    let mut t = make_cpu();
+    let mut traps = 0;
+    for &(op, taken) in tests.iter() {
+        t.set_reg(1, 0xFFFF_FFFF_FFFF_FFFF);
+        t.set_reg(2, 1);
+        t.run(0x8000_0100, &[op], 1);
+        if taken {
+            traps += 1;
+        }
+        let pc = if taken { 0x8000_0180 } else { 0x8000_0104 };
+        assert_eq!(t.cpu.ctx().get_pc(), pc, "op {:08x}", op);
+        let stats = t.cpu.exception_stats();
+        assert_eq!(stats.get(&Exception::TR).cloned().unwrap_or(0), traps);
+    }
+}
+
+// Unaligned word accesses, for each alignment, with the word 0x11223344 in
+// memory and 0xAABBCCDD in the register (big-endian, as on the VR4300).
+#[test]
+fn unaligned_word_access() {
+    let lwl: [u32; 4] = [0x1122_3344, 0x2233_44DD, 0x3344_CCDD, 0x44BB_CCDD];
+    let lwr: [u32; 4] = [0xAABB_CC11, 0xAABB_1122, 0xAA11_2233, 0x1122_3344];
+    let swl: [u32; 4] = [0xAABB_CCDD, 0x11AA_BBCC, 0x1122_AABB, 0x1122_33AA];
+    let swr: [u32; 4] = [0xDD22_3344, 0xCCDD_3344, 0xBBCC_DD44, 0xAABB_CCDD];
+
+    for k in 0..4 {
+        let mut t = make_cpu();
+        for addr in &[0x1000, 0x1004, 0x1008] {
+            t.ram.write::<BigEndian, u32>(*addr, 0x1122_3344);
+        }
+        t.set_reg(1, 0x8000_1000 + k as u64);
+        for idx in 2..6 {
+            t.set_reg(idx, 0xFFFF_FFFF_AABB_CCDD);
+        }
+        t.run(
+            0x8000_0000,
+            &[
+                itype(0x22, 1, 2, 0), // lwl v0,0(at)
+                itype(0x26, 1, 3, 0), // lwr v1,0(at)
+                itype(0x2A, 1, 4, 4), // swl a0,4(at)
+                itype(0x2E, 1, 5, 8), // swr a1,8(at)
+            ],
+            4,
+        );
+
+        // Loads sign-extend the merged word
+        assert_eq!(t.reg(2), lwl[k] as i32 as u64, "lwl, offset {}", k);
+        assert_eq!(t.reg(3), lwr[k] as i32 as u64, "lwr, offset {}", k);
+        assert_eq!(
+            t.ram.read::<BigEndian, u32>(0x1004),
+            swl[k],
+            "swl, offset {}",
+            k
+        );
+        assert_eq!(
+            t.ram.read::<BigEndian, u32>(0x1008),
+            swr[k],
+            "swr, offset {}",
+            k
+        );
+    }
+}
+
+// Unaligned doubleword accesses, for each alignment, with the doubleword
+// 0x1122334455667788 in memory and 0xAABBCCDDEEFF0099 in the register.
+#[test]
+fn unaligned_doubleword_access() {
+    let ldl = [
+        0x1122_3344_5566_7788,
+        0x2233_4455_6677_8899,
+        0x3344_5566_7788_0099,
+        0x4455_6677_88FF_0099,
+        0x5566_7788_EEFF_0099,
+        0x6677_88DD_EEFF_0099,
+        0x7788_CCDD_EEFF_0099,
+        0x88BB_CCDD_EEFF_0099,
+    ];
+    let ldr = [
+        0xAABB_CCDD_EEFF_0011,
+        0xAABB_CCDD_EEFF_1122,
+        0xAABB_CCDD_EE11_2233,
+        0xAABB_CCDD_1122_3344,
+        0xAABB_CC11_2233_4455,
+        0xAABB_1122_3344_5566,
+        0xAA11_2233_4455_6677,
+        0x1122_3344_5566_7788,
+    ];
+    let sdl = [
+        0xAABB_CCDD_EEFF_0099,
+        0x11AA_BBCC_DDEE_FF00,
+        0x1122_AABB_CCDD_EEFF,
+        0x1122_33AA_BBCC_DDEE,
+        0x1122_3344_AABB_CCDD,
+        0x1122_3344_55AA_BBCC,
+        0x1122_3344_5566_AABB,
+        0x1122_3344_5566_77AA,
+    ];
+    let sdr = [
+        0x9922_3344_5566_7788,
+        0x0099_3344_5566_7788,
+        0xFF00_9944_5566_7788,
+        0xEEFF_0099_5566_7788,
+        0xDDEE_FF00_9966_7788,
+        0xCCDD_EEFF_0099_7788,
+        0xBBCC_DDEE_FF00_9988,
+        0xAABB_CCDD_EEFF_0099,
+    ];
+
+    for k in 0..8 {
+        let mut t = make_cpu();
+        for addr in &[0x1000, 0x1008, 0x1010] {
+            t.ram.write::<BigEndian, u64>(*addr, 0x1122_3344_5566_7788);
+        }
+        t.set_reg(1, 0x8000_1000 + k as u64);
+        for idx in 2..6 {
+            t.set_reg(idx, 0xAABB_CCDD_EEFF_0099);
+        }
+        t.run(
+            0x8000_0000,
+            &[
+                itype(0x1A, 1, 2, 0),  // ldl v0,0(at)
+                itype(0x1B, 1, 3, 0),  // ldr v1,0(at)
+                itype(0x2C, 1, 4, 8),  // sdl a0,8(at)
+                itype(0x2D, 1, 5, 16), // sdr a1,16(at)
+            ],
+            4,
+        );
+
+        assert_eq!(t.reg(2), ldl[k], "ldl, offset {}", k);
+        assert_eq!(t.reg(3), ldr[k], "ldr, offset {}", k);
+        assert_eq!(
+            t.ram.read::<BigEndian, u64>(0x1008),
+            sdl[k],
+            "sdl, offset {}",
+            k
+        );
+        assert_eq!(
+            t.ram.read::<BigEndian, u64>(0x1010),
+            sdr[k],
+            "sdr, offset {}",
+            k
+        );
+    }
+}
+
+// Set-on-less-than compares full 64-bit registers; the immediate is always
+// sign-extended, even by SLTIU (which then compares as unsigned).
+#[test]
+fn set_on_less_than() {
+    let rtype = |rs: u32, rt: u32, rd: u32, func: u32| (rs << 21) | (rt << 16) | (rd << 11) | func;
+    let vectors: &[(u32, u64, u64, i16, u64)] = &[
+        // (opcode, rs, rt, imm, expected)
+        // SLT
+        (0x2A, 1, 2, 0, 1),
+        (0x2A, 0xFFFF_FFFF_FFFF_FFFF, 0, 0, 1),
+        (0x2A, 0x0000_0001_0000_0000, 0x7FFF_FFFF, 0, 0),
+        (0x2A, 0x8000_0000_0000_0000, 0x7FFF_FFFF_FFFF_FFFF, 0, 1),
+        // SLTU
+        (0x2B, 0xFFFF_FFFF_FFFF_FFFF, 0, 0, 0),
+        (0x2B, 0x0000_0000_FFFF_FFFF, 0x0000_0001_0000_0000, 0, 1),
+        (0x2B, 0x0000_0001_0000_0000, 0x0000_0000_FFFF_FFFF, 0, 0),
+        // SLTI
+        (0x0A, 0xFFFF_FFFF_FFFF_FFFE, 0, -1, 1),
+        (0x0A, 0x0000_0001_0000_0000, 0, 1, 0),
+        (0x0A, 0x0000_0000_8000_0000, 0, 0, 0),
+        // SLTIU: -1 is compared as 0xFFFF_FFFF_FFFF_FFFF
+        (0x0B, 0x0000_0000_FFFF_FFFF, 0, -1, 1),
+        (0x0B, 0xFFFF_FFFF_FFFF_FFFE, 0, -1, 1),
+        (0x0B, 0xFFFF_FFFF_FFFF_FFFF, 0, -1, 0),
+        (0x0B, 0x0000_0001_0000_0000, 0, 1, 0),
+        (0x0B, 0x0000_0000_0000_7FFF, 0, 0x7FFF, 0),
+    ];
+
+    for &(op, rs, rt, imm, exp) in vectors {
+        let mut t = make_cpu();
+        t.set_reg(1, rs);
+        t.set_reg(2, rt);
+        t.set_reg(3, 0xDEAD);
+        let insn = if op >= 0x2A {
+            rtype(1, 2, 3, op)
+        } else {
+            itype(op, 1, 3, imm)
+        };
+        t.run(0x8000_0000, &[insn], 1);
+        assert_eq!(
+            t.reg(3),
+            exp,
+            "op={:x} rs={:x} rt={:x} imm={}",
+            op,
+            rs,
+            rt,
+            imm
+        );
+    }
+}
+
+// Jump and branch targets are relative to the address of the delay slot,
+// which matters when the jump is the last instruction of a 256MB region.
+#[test]
+fn jump_target_region() {
+    let mut t = make_cpu();
+    let lo = Mem::new(0x1000, Default::default());
+    let hi = Mem::new(0x1000, Default::default());
+    t.map_mem(0x0FFF_F000, &lo);
+    t.map_mem(0x1000_0000, &hi);
+    hi.write::<BigEndian, u32>(0x000, addiu(1, 0, 1)); // delay slot at 0x9000_0000
+    hi.write::<BigEndian, u32>(0x004, addiu(3, 0, 3));
+    hi.write::<BigEndian, u32>(0x400, addiu(2, 0, 2));
+
+    // j 0x9000_0400
+    lo.write::<BigEndian, u32>(0xFFC, 0x0800_0100);
+    t.run(0x8FFF_FFFC, &[], 3);
+    assert_eq!(t.reg(1), 1);
+    assert_eq!(t.reg(2), 2);
+    assert_eq!(t.cpu.ctx().get_pc(), 0x9000_0404);
+
+    // beq zr,zr,+1: the target is the address of the delay slot + 4
+    lo.write::<BigEndian, u32>(0xFFC, beq(0, 0, 1));
+    t.run(0x8FFF_FFFC, &[], 3);
+    assert_eq!(t.reg(3), 3);
+    assert_eq!(t.cpu.ctx().get_pc(), 0x9000_0008);
+}
+
+// A jump to an address that can't be fetched faults on the fetch at the
+// target, after the delay slot: misaligned, null (a TLB miss in KUSEG), or
+// not backed by memory.
+#[test]
+fn jump_to_bad_target() {
+    for &(tgt, exc, vector) in &[
+        (0x8000_0202u32, Exception::ADEL, 0x180),
+        (0x0000_0000, Exception::TLBL, 0x000),
+        (0xB000_0000, Exception::ADEL, 0x180),
+    ] {
+        let mut t = make_cpu();
+        let handler = [mfc0(5, 8), mfc0(6, 14), mfc0(7, 13)];
+        for (idx, op) in handler.iter().enumerate() {
+            t.ram.write::<BigEndian, u32>(vector + idx as u32 * 4, *op);
+        }
+
+        // jr at ; addiu v0,v0,1
+        t.set_reg(1, tgt as i32 as u64);
+        t.run(0x8000_1000, &[0x0020_0008, addiu(2, 2, 1)], 6);
+        assert_eq!(t.cpu.exception_stats().get(&exc), Some(&1), "{:x}", tgt);
+        assert_eq!(t.reg(2), 1);
+        assert_eq!(t.reg(5), tgt as i32 as u64);
+        assert_eq!(t.reg(6), tgt as i32 as u64);
+        assert_eq!(t.reg(7) & (1 << 31), 0);
+    }
+}
+
+#[test]
+fn single_step() {
+    let mut t = make_cpu();
+    t.cpu.ctx_mut().set_single_step_line(true);
+    let prog = [addiu(1, 0, 1), addiu(2, 0, 2), beq(0, 0, 2), addiu(3, 0, 3)];
+    t.run(0x8000_0000, &prog, 10);
+    assert_eq!(t.reg(1), 1);
+    assert_eq!(t.reg(2), 0);
+    assert_eq!(t.cpu.ctx().get_pc(), 0x8000_0004);
+    assert!(t.cpu.ctx().halted());
+
+    // While halted, time flows but nothing is executed
+    let until = t.cpu.ctx().clock + 10;
+    t.cpu.run(until);
+    assert_eq!(t.cpu.ctx().clock, until);
+    assert_eq!(t.reg(2), 0);
+
+    // Each release of the halt line executes one more step; a branch is
+    // executed together with its delay slot.
+    t.cpu.ctx_mut().set_halt_line(false);
+    t.cpu.run(until + 10);
+    assert_eq!(t.reg(2), 2);
+    t.cpu.ctx_mut().set_halt_line(false);
+    t.cpu.run(until + 20);
+    assert_eq!(t.reg(3), 3);
+    assert_eq!(t.cpu.ctx().get_pc(), 0x8000_0014);
+}
+
+// step() executes a single instruction (a delay slot is a separate step),
+// and reports its memory accesses, the registers it changed, and exceptions.
+#[test]
+fn step_instructions() {
+    let mut t = make_cpu();
+    let prog = [
+        lw(2, 0x1000, 1),
+        beq(0, 0, 2),
+        sw(2, 0x1010, 1),
+        NOP,
+        0x0000_000C,
+    ];
+    for (idx, op) in prog.iter().enumerate() {
+        t.ram.write::<BigEndian, u32>(0x100 + idx as u32 * 4, *op);
+    }
+    t.ram.write::<BigEndian, u32>(0x1000, 0x1234_5678);
+    t.set_reg(1, 0xFFFF_FFFF_8000_0000);
+    t.cpu.ctx_mut().set_pc(0x8000_0100);
+
+    let step = t.cpu.step();
+    assert_eq!(step.pc, 0x8000_0100);
+    assert_eq!(step.opcode, Some(prog[0]));
+    assert_eq!(
+        step.accesses,
+        vec![StepAccess {
+            kind: MemAccess::Read,
+            vaddr: 0x8000_1000,
+            paddr: 0x1000,
+            size: 4,
+            val: 0x1234_5678,
+        }]
+    );
+    assert_eq!(step.regs, vec![(2, 0x1234_5678)]);
+    assert_eq!(step.hi_lo, None);
+
+    let step = t.cpu.step();
+    assert_eq!((step.pc, step.delay_slot), (0x8000_0104, false));
+    assert!(step.accesses.is_empty());
+    assert!(step.regs.is_empty());
+    assert_eq!(t.cpu.ctx().get_pc(), 0x8000_0108);
+
+    let step = t.cpu.step();
+    assert_eq!((step.pc, step.delay_slot), (0x8000_0108, true));
+    assert_eq!(step.accesses[0].kind, MemAccess::Write);
+    assert_eq!(step.accesses[0].paddr, 0x1010);
+    assert_eq!(t.ram.read::<BigEndian, u32>(0x1010), 0x1234_5678);
+    assert_eq!(t.cpu.ctx().get_pc(), 0x8000_0110);
+
+    let step = t.cpu.step();
+    assert_eq!(step.exception, Some(Exception::SYS));
+    assert_eq!(t.cpu.ctx().get_pc(), 0x8000_0180);
+
+    // MULT r3, r4
+    t.ram.write::<BigEndian, u32>(0x200, 0x0064_0018);
+    t.set_reg(3, 3);
+    t.set_reg(4, 5);
+    t.cpu.ctx_mut().set_pc(0x8000_0200);
+    let step = t.cpu.step();
+    assert!(step.regs.is_empty());
+    assert_eq!(step.hi_lo, Some((0, 15)));
+
+    // run() completes a delay slot left pending by step()
+    t.set_reg(2, 0xABCD);
+    t.cpu.ctx_mut().set_pc(0x8000_0104);
+    t.cpu.step();
+    let until = t.cpu.ctx().clock + 1;
+    t.cpu.run(until);
+    assert_eq!(t.ram.read::<BigEndian, u32>(0x1010), 0xABCD);
+    assert_eq!(t.cpu.ctx().get_pc(), 0x8000_0110);
+
+    // Partial stores report the merged word or doubleword
+    t.ram.write::<BigEndian, u32>(0x1020, 0x1122_3344);
+    // swl v0,0x1021(at) ; sdr v0,0x1030(at)
+    t.ram.write::<BigEndian, u32>(0x300, itype(0x2A, 1, 2, 0x1021));
+    t.ram.write::<BigEndian, u32>(0x304, itype(0x2D, 1, 2, 0x1030));
+    t.set_reg(2, 0xAABB_CCDD);
+    t.cpu.ctx_mut().set_pc(0x8000_0300);
+    let step = t.cpu.step();
+    assert_eq!(
+        step.accesses,
+        vec![StepAccess {
+            kind: MemAccess::Write,
+            vaddr: 0x8000_1021,
+            paddr: 0x1020,
+            size: 4,
+            val: 0x11AA_BBCC,
+        }]
+    );
+    let step = t.cpu.step();
+    assert_eq!(
+        step.accesses,
+        vec![StepAccess {
+            kind: MemAccess::Write,
+            vaddr: 0x8000_1030,
+            paddr: 0x1030,
+            size: 8,
+            val: 0xDD00_0000_0000_0000,
+        }]
+    );
+}
+
+#[test]
+fn block_cache() {
+    // loop: addiu r1,r1,1 ; addiu r2,r2,-1 ; beq r0,r0,loop ; addiu r3,r3,2
+    let prog = [
+        addiu(1, 1, 1),
+        addiu(2, 2, -1),
+        beq(0, 0, -3),
+        addiu(3, 3, 2),
+    ];
+    let mut states = Vec::new();
+    for &cached in &[false, true] {
+        let mut t = make_cpu();
+        t.cpu.set_block_cache(cached);
+        t.run(0x8000_0100, &prog, 4 * 10 + 2);
+        states.push(t.cpu.state());
+    }
+    assert_eq!(states[0], states[1]);
+    assert_eq!(states[1].regs[1], 11);
+    assert_eq!(states[1].pc, 0x8000_0108);
+
+    // Blocks are decoded once, and dropped when their code is written
+    let mut t = make_cpu();
+    let watch = CodeWatch::new();
+    t.cpu.set_code_watch(watch.clone());
+    t.cpu.set_block_cache(true);
+    t.run(0x8000_0100, &prog, 4 * 10);
+    assert_eq!(t.reg(1), 10);
+    assert_eq!(t.cpu.cached_blocks(), 1);
+    t.ram.write::<BigEndian, u32>(0x100, addiu(1, 1, 5));
+    assert!(watch.invalidate(0x100, 4));
+    let until = t.cpu.ctx().clock + 4;
+    t.cpu.run(until);
+    assert_eq!(t.reg(1), 15);
+    assert_eq!(t.cpu.cached_blocks(), 1);
+
+    // Including writes by the core itself
+    t.set_reg(4, 0xFFFF_FFFF_8000_0200);
+    t.set_reg(5, addiu(6, 6, 7) as u64);
+    t.run(0x8000_0200, &[sw(5, 8, 4), NOP, addiu(6, 6, 1)], 3);
+    assert_eq!(t.reg(6), 7);
+}
+
+// Every kind of store drops the blocks it overwrites: partial stores, SC and
+// coprocessor stores, like SW above. Each program overwrites the final
+// addiu r6,r6,1, decoded together with the store, with addiu r6,r6,7.
+#[test]
+fn block_cache_other_stores() {
+    let progs = [
+        // swl a1,12(a0)
+        vec![NOP, itype(0x2A, 4, 5, 12), NOP, addiu(6, 6, 1)],
+        // ll a3,12(a0) ; sc a1,12(a0)
+        vec![
+            itype(0x30, 4, 7, 12),
+            itype(0x38, 4, 5, 12),
+            NOP,
+            addiu(6, 6, 1),
+        ],
+        // dmtc1 a1,f0 ; sdc1 f0,8(a0)
+        vec![cop1(5, 5, 0), itype(0x3D, 4, 0, 8), addiu(6, 6, 1), NOP],
+    ];
+    for (idx, prog) in progs.iter().enumerate() {
+        let mut t = make_fpu_cpu();
+        let watch = CodeWatch::new();
+        t.cpu.set_code_watch(watch.clone());
+        t.cpu.set_block_cache(true);
+
+        let new = addiu(6, 6, 7) as u64;
+        t.set_reg(4, 0xFFFF_FFFF_8000_0200);
+        t.set_reg(5, if idx == 2 { new << 32 } else { new });
+        t.run(0x8000_0200, prog, 4);
+        assert_eq!(t.reg(6), 7, "program {}", idx);
+        assert_eq!(t.cpu.exception_stats().len(), 0, "program {}", idx);
+    }
+}
+
+#[test]
+#[cfg(feature = "jit")]
+fn jit_block() {
+    // loop: addiu r1,r1,1 ; sll r2,r1,3 ; subu r3,r2,r1 ; slt r4,r3,r2 ;
+    //       xori r5,r4,0xFFFF ; beq r0,r0,loop ; lui r6,0x8000
+    let prog = [
+        addiu(1, 1, 1),
+        0x0001_10C0,
+        0x0041_1823,
+        0x0062_202A,
+        itype(0x0E, 4, 5, -1),
+        beq(0, 0, -6),
+        itype(0x0F, 0, 6, -0x8000),
+    ];
+    let mut states = Vec::new();
+    for &jit in &[false, true] {
+        let mut t = make_cpu();
+        t.cpu.set_jit(jit);
+        t.set_reg(1, 0x7FFF_FF00);
+        t.run(0x8000_0100, &prog, 7 * 200 + 3);
+        states.push(t.cpu.state());
+    }
+    assert_eq!(states[0], states[1]);
+    assert_eq!(states[1].regs[1], 0x7FFF_FF00 + 201);
+}
+
+#[test]
+#[cfg(feature = "jit")]
+fn jit_matches_interpreter() {
+    // Instructions compiled to native code
+    const SPECIAL: [u32; 13] = [
+        0x00, 0x02, 0x03, 0x21, 0x23, 0x24, 0x25, 0x26, 0x27, 0x2A, 0x2B, 0x2D, 0x2F,
+    ];
+    const PRIMARY: [u32; 8] = [0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E, 0x0F, 0x19];
+
+    let mut seed = 0x2545_F491_4F6C_DD1Du64;
+    let mut rand = move || {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        seed
+    };
+
+    // Loops of random instructions with random operands (including r0),
+    // run long enough to be compiled, on random register values.
+    for _ in 0..16 {
+        let mut prog = Vec::new();
+        for _ in 0..24 {
+            let r = rand();
+            let op = if r & 1 == 0 {
+                (r >> 8) as u32 & 0x03FF_FFC0 | SPECIAL[(r >> 40) as usize % SPECIAL.len()]
+            } else {
+                (r >> 8) as u32 & 0x03FF_FFFF | PRIMARY[(r >> 40) as usize % PRIMARY.len()] << 26
+            };
+            prog.push(op);
+        }
+        let len = prog.len() as i16;
+        prog.push(beq(0, 0, -len - 1));
+        prog.push(NOP);
+
+        let regs: Vec<u64> = (0..32).map(|_| rand()).collect();
+        let mut states = Vec::new();
+        for &jit in &[false, true] {
+            let mut t = make_cpu();
+            t.cpu.set_jit(jit);
+            for idx in 1..32 {
+                t.set_reg(idx, regs[idx]);
+            }
+            t.run(0x8000_0100, &prog, prog.len() as i64 * 100);
+            states.push(t.cpu.state());
+        }
+        assert_eq!(states[0], states[1], "program: {:08x?}", prog);
+    }
+}
+
+#[test]
+fn idle_skip() {
+    // loop: lw r8,0(r4) ; beq r8,r0,loop ; nop
+    let prog = [lw(8, 0, 4), beq(8, 0, -2), NOP];
+    for &cached in &[false, true] {
+        let mut t = make_cpu();
+        t.cpu.set_block_cache(cached);
+        t.cpu.set_idle_skip(true);
+        t.set_reg(4, 0xFFFF_FFFF_8000_0200);
+        t.run(0x8000_0100, &prog, 1000);
+        assert_eq!(t.cpu.ctx().clock, 1000);
+        assert_eq!(t.cpu.ctx().get_pc(), 0x8000_0100);
+        assert!(t.cpu.idle_cycles() >= 990);
+
+        // The loop exits once memory changes
+        t.ram.write::<BigEndian, u32>(0x200, 1);
+        t.cpu.run(1003);
+        assert_eq!(t.reg(8), 1);
+        assert_eq!(t.cpu.ctx().get_pc(), 0x8000_010C);
+    }
+
+    // Loops that change the registers are not idle
+    let mut t = make_cpu();
+    t.cpu.set_idle_skip(true);
+    t.run(0x8000_0100, &[addiu(1, 1, 1), beq(0, 0, -2), NOP], 300);
+    assert_eq!(t.reg(1), 100);
+    assert_eq!(t.cpu.idle_cycles(), 0);
+}
+
+#[test]
+fn state_snapshot() {
+    let mut t = make_cpu();
+    t.set_reg(4, 0x1234);
+    t.set_reg(29, 0xFFFF_FFFF_8000_4000);
+    t.cpu.ctx_mut().hi = 7;
+    t.ram.write::<BigEndian, u32>(0, beq(0, 0, 4));
+    t.cpu.ctx_mut().set_pc(0x8000_0000);
+    t.cpu.step();
+
+    assert_eq!(t.cpu.ctx().reg_by_name("a0"), Some(0x1234));
+    assert_eq!(t.cpu.ctx().reg_by_name("$sp"), Some(0xFFFF_FFFF_8000_4000));
+    assert_eq!(t.cpu.ctx().reg_by_name("r4"), Some(0x1234));
+    assert_eq!(t.cpu.ctx().reg_by_name("x0"), None);
+    assert_eq!(mips64::reg_index("zero"), Some(0));
+    assert_eq!(mips64::reg_index("ra"), Some(31));
+    assert_eq!(mips64::reg_name(30), "fp");
+
+    let state = t.cpu.state();
+    assert_eq!(state.pc, 0x8000_0004);
+    assert_eq!(state.branch_pc, Some(0x8000_0014));
+    assert_eq!(state.reg("a0"), Some(0x1234));
+    assert_eq!(state.reg("hi"), Some(7));
+    assert_eq!(state.reg("pc"), Some(0x8000_0004));
+    assert_eq!(state.cop0[10].0, "Status");
+    assert_eq!(state.reg("status"), Some(state.cop0[10].1));
+    assert_eq!(state.reg("prid"), Some(0x0B22));
+    assert!(!state.halt);
+
+    let dump = state.to_string();
+    let lines: Vec<&str> = dump.lines().collect();
+    assert_eq!(lines.len(), 1 + 8 + 1 + 5);
+    assert!(lines[0].starts_with("pc:80000004 (delay slot, branch to 80000014)"));
+    assert!(lines[2].starts_with("a0:0000000000001234  a1:"));
+    assert!(lines[8].contains("sp:ffffffff80004000"));
+    assert_eq!(lines[9], "hi:0000000000000007  lo:0000000000000000");
+    assert!(lines[13].contains("    PRId:0000000000000b22"));
+}
+
+fn mtc0(rt: u32, rd: u32) -> u32 {
+    0x4080_0000 | (rt << 16) | (rd << 11)
+}
+fn mfc0(rt: u32, rd: u32) -> u32 {
+    0x4000_0000 | (rt << 16) | (rd << 11)
+}
+
+#[test]
+fn software_interrupt() {
+    let mut t = make_cpu();
+    t.set_reg(1, 0x0000_0001); // Status: IE, all lines masked
+    t.set_reg(2, 0xFFFF_FF00); // Cause: only IP0/IP1 are writable
+    t.set_reg(3, 0x0000_0101); // Status: IE, IM0
+    t.run(
+        0x8000_0000,
+        &[mtc0(1, 12), mtc0(2, 13), mfc0(4, 13), NOP, mtc0(3, 12), NOP],
+        5,
+    );
+
+    // The masked interrupt is pending, but not taken
+    assert_eq!(t.reg(4), 0x300);
+    assert_eq!(t.cpu.exception_stats().get(&Exception::INT), None);
+
+    // Enabling IM0 fires the interrupt before the next instruction. The
+    // handler reads EPC (the interrupted instruction), and clears the
+    // software interrupts, lowering the lines.
+    let handler = [mfc0(5, 14), mtc0(0, 13), mfc0(4, 13)];
+    for (idx, op) in handler.iter().enumerate() {
+        t.ram.write::<BigEndian, u32>(0x180 + idx as u32 * 4, *op);
+    }
+    let until = t.cpu.ctx().clock + 3;
+    t.cpu.run(until);
+    assert_eq!(t.cpu.exception_stats().get(&Exception::INT), Some(&1));
+    assert_eq!(t.reg(5), 0xFFFF_FFFF_8000_0014);
+    assert_eq!(t.reg(4), 0);
+    assert_eq!(t.cpu.ctx().get_pc(), 0x8000_018C);
+}
+
+// Interrupts are not taken while EXL or ERL are set, even if enabled and
+// unmasked: they stay pending until both are cleared.
+#[test]
+fn interrupt_gating() {
+    let mut t = make_cpu();
+    t.ram.write::<BigEndian, u32>(0x180, mfc0(5, 14));
+    t.set_reg(1, 0x0000_0103); // Status: IE, EXL, IM0
+    t.set_reg(2, 0x0000_0100); // Cause: IP0
+    t.set_reg(3, 0x0000_0105); // Status: IE, ERL, IM0
+    t.set_reg(4, 0x0000_0101); // Status: IE, IM0
+    t.run(
+        0x8000_0000,
+        &[
+            mtc0(1, 12),
+            mtc0(2, 13),
+            NOP,
+            mtc0(3, 12),
+            NOP,
+            mtc0(4, 12),
+            NOP,
+        ],
+        7,
+    );
+    assert_eq!(t.cpu.exception_stats().get(&Exception::INT), Some(&1));
+    assert_eq!(t.reg(5), 0xFFFF_FFFF_8000_0018);
+    assert_eq!(t.cpu.ctx().get_pc(), 0x8000_0184);
+}
+
+// The reset button raises the pre-NMI interrupt on IP4, then the NMI, which
+// restarts from the boot vector at error level, in soft reset state.
+#[test]
+fn pre_nmi_and_nmi() {
+    let mut t = make_cpu();
+    t.ram.write::<BigEndian, u32>(0x180, mfc0(5, 13));
+    t.set_reg(1, 0x0000_1001); // Status: IE, IM4
+    t.run(0x8000_0000, &[mtc0(1, 12), NOP], 1);
+    t.cpu.set_int_line(4, true);
+    let until = t.cpu.ctx().clock + 1;
+    t.cpu.run(until);
+    assert_eq!(t.cpu.exception_stats().get(&Exception::INT), Some(&1));
+    assert_eq!(t.reg(5) & 0xFF00, 0x1000);
+
+    t.cpu.set_int_line(4, false);
+    t.cpu.nmi();
+    assert_eq!(t.cpu.ctx().get_pc(), 0xBFC0_0000);
+    t.run(0x8000_0100, &[mfc0(2, 12), mfc0(3, 30), mfc0(4, 13)], 3);
+    assert_eq!(t.reg(2) & 0x0070_0004, 0x0050_0004); // BEV, SR, ERL; TS clear
+    assert_eq!(t.reg(3), 0xFFFF_FFFF_8000_0184);
+    assert_eq!(t.reg(4) & 0xFF00, 0);
+}
+
+// An exception raised while handling another one (EXL set) does not
+// overwrite EPC; ERET returns to EPC, and clears EXL.
+#[test]
+fn nested_exception_and_eret() {
+    let mut t = make_cpu();
+    let teqi = |rs: u32, imm: i16| itype(0x01, rs, 0x0C, imm);
+    let handler = [
+        addiu(6, 6, 1),
+        teqi(6, 1), // traps on first entry only
+        mfc0(5, 14),
+        addiu(5, 5, 4),
+        mtc0(5, 14),
+        0x4200_0018, // eret
+    ];
+    for (idx, op) in handler.iter().enumerate() {
+        t.ram.write::<BigEndian, u32>(0x180 + idx as u32 * 4, *op);
+    }
+    t.run(
+        0x8000_0000,
+        &[NOP, 0x0000_000C, addiu(7, 0, 7), mfc0(8, 12)],
+        12,
+    );
+    assert_eq!(t.cpu.exception_stats().get(&Exception::SYS), Some(&1));
+    assert_eq!(t.cpu.exception_stats().get(&Exception::TR), Some(&1));
+    assert_eq!(t.reg(6), 2);
+    assert_eq!(t.reg(5), 0xFFFF_FFFF_8000_0008);
+    assert_eq!(t.reg(7), 7);
+    assert_eq!(t.reg(8) & 0x2, 0);
+    assert_eq!(t.cpu.ctx().get_pc(), 0x8000_0010);
+}
+
+// Resets jump to the boot ROM at error level (ERL), saving the PC into
+// ErrorEPC; ERET returns there, clearing ERL only.
+#[test]
+fn reset_and_error_level() {
+    let mut t = make_cpu();
+    t.cpu.ctx_mut().set_pc(0x8000_0040);
+    t.cpu.reset();
+    assert_eq!(t.cpu.ctx().get_pc(), 0xBFC0_0000);
+
+    t.set_reg(1, 0x0040_0006); // BEV, ERL, EXL
+    t.run(
+        0x8000_0100,
+        &[mfc0(2, 12), mfc0(3, 30), mtc0(1, 12), 0x4200_0018],
+        4,
+    );
+    assert_eq!(t.reg(2), 0x0040_0004);
+    assert_eq!(t.reg(3), 0xFFFF_FFFF_8000_0040);
+    assert_eq!(t.cpu.ctx().get_pc(), 0x8000_0040);
+    t.run(0x8000_0100, &[mfc0(2, 12)], 1);
+    assert_eq!(t.reg(2), 0x0040_0002);
+}
+
+// SYSCALL jumps to the general exception vector, with EPC pointing to the
+// instruction itself and the Syscall ExcCode in Cause.
+#[test]
+fn syscall_exception() {
+    let mut t = make_cpu();
+    let handler = [mfc0(2, 14), mfc0(3, 13)];
+    for (idx, op) in handler.iter().enumerate() {
+        t.ram.write::<BigEndian, u32>(0x180 + idx as u32 * 4, *op);
+    }
+    t.run(0x8000_0000, &[NOP, 0x0000_000C], 4);
+    assert_eq!(t.cpu.exception_stats().get(&Exception::SYS), Some(&1));
+    assert_eq!(t.reg(2), 0xFFFF_FFFF_8000_0004);
+    assert_eq!((t.reg(3) >> 2) & 0x1F, 8);
+    assert_eq!(t.cpu.ctx().get_pc(), 0x8000_0188);
+}
+
+#[test]
+fn cp0_registers() {
+    let mut t = make_cpu();
+    t.set_reg(1, 0x1234_5678_9ABC_DEF0);
+    t.run(
+        0x8000_0000,
+        &[
+            mfc0(2, 15),                          // PRId
+            mfc0(3, 16),                          // Config
+            0x40A0_0000 | (1 << 16) | (14 << 11), // dmtc0 at,epc
+            0x4020_0000 | (4 << 16) | (14 << 11), // dmfc0 a0,epc
+            mfc0(5, 14),
+        ],
+        5,
+    );
+    assert_eq!(t.reg(2), 0x0B22);
+    assert_eq!(t.reg(3), 0x7006_E463);
+    assert_eq!(t.reg(4), 0x1234_5678_9ABC_DEF0);
+    assert_eq!(t.reg(5), 0xFFFF_FFFF_9ABC_DEF0);
+}
+
+#[test]
+fn cp0_timer_interrupt() {
+    let mut t = make_cpu();
+    t.set_reg(1, 20); // Compare
+    t.set_reg(2, 0x8001); // Status: IE, IM7
+
+    // Exception handler: read Cause, acknowledge the timer, return
+    let handler = [mfc0(5, 13), mtc0(1, 11), 0x4200_0018];
+    for (idx, op) in handler.iter().enumerate() {
+        t.ram.write::<BigEndian, u32>(0x180 + idx as u32 * 4, *op);
+    }
+
+    // Count is incremented every other cycle: run one cycle at a time,
+    // so that the interrupt is checked after each instruction.
+    t.run(0x8000_0000, &[mtc0(0, 9), mtc0(1, 11), mtc0(2, 12)], 3);
+    for _ in 0..60 {
+        let until = t.cpu.ctx().clock + 1;
+        t.cpu.run(until);
+    }
+    assert_eq!(t.cpu.exception_stats().get(&Exception::INT), Some(&1));
+    assert_eq!(t.reg(5) & 0x8000, 0x8000);
+    let pc = t.cpu.ctx().get_pc();
+    assert!(pc > 0x8000_0040 && pc < 0x8000_0180, "pc={:x}", pc);
+}
+
+// Count runs at half the CPU clock, and raises IP7 when it reaches Compare,
+// also when wrapping around. Writing Compare lowers IP7.
+#[test]
+fn cp0_count_wraparound() {
+    let mut t = make_cpu();
+    t.set_reg(1, 0xFFFF_FFFF_FFFF_FFFE); // Count
+    t.set_reg(2, 1); // Compare
+    let mut prog = vec![mtc0(1, 9), mtc0(2, 11)];
+    prog.extend_from_slice(&[NOP; 8]);
+    prog.extend_from_slice(&[mfc0(3, 9), mfc0(4, 13), mtc0(2, 11), mfc0(5, 13)]);
+    t.run(0x8000_0000, &prog, prog.len() as i64);
+
+    // Count is read 10 cycles after being set: 5 increments.
+    assert_eq!(t.reg(3), 3);
+    assert_eq!(t.reg(4) & 0x8000, 0x8000);
+    assert_eq!(t.reg(5) & 0x8000, 0);
+    assert_eq!(t.cpu.exception_stats().get(&Exception::INT), None);
+}
+
+// The counter factor makes each instruction take more cycles: fewer
+// instructions run in the same time (eg: a VI frame), but Count advances
+// by the same amount.
+#[test]
+fn cp0_count_counter_factor() {
+    const FRAME: i64 = 1200;
+    for &cf in &[1, 2, 4] {
+        let mut t = make_cpu();
+        t.cpu.set_counter_factor(cf);
+        // loop: addiu r2,r2,1 ; beq r0,r0,loop ; mfc0 r3,count
+        let prog = [addiu(2, 2, 1), beq(0, 0, -2), mfc0(3, 9)];
+        t.run(0x8000_0000, &prog, FRAME);
+        assert_eq!(t.reg(2), FRAME as u64 / (3 * cf as u64), "cf={}", cf);
+        assert_eq!(t.reg(3), FRAME as u64 / 2, "cf={}", cf);
+    }
+}
+
+const TLB_VALID: u32 = 0x3; // valid, global
+const TLB_DIRTY: u32 = 0x7; // valid, global, writable
+const TLBWI: u32 = 0x4200_0002;
+const TLBP: u32 = 0x4200_0008;
+
+// Map a pair of 4KB pages (vaddr must be 8KB aligned) to consecutive
+// physical pages, through the specified TLB entry.
+fn tlb_map(t: &mut TestCpu, idx: u64, vaddr: u32, paddr: u32, flags: u32) {
+    let lo = |paddr: u32| ((paddr >> 12) << 6 | flags) as u64;
+    t.set_reg(1, idx);
+    t.set_reg(2, vaddr as u64);
+    t.set_reg(3, lo(paddr));
+    t.set_reg(4, lo(paddr + 0x1000));
+    t.run(
+        0x8000_F000,
+        &[
+            mtc0(1, 0),
+            mtc0(2, 10),
+            mtc0(3, 2),
+            mtc0(4, 3),
+            mtc0(0, 5),
+            TLBWI,
+        ],
+        6,
+    );
+}
+
+#[test]
+fn tlb_mapping() {
+    let mut t = make_cpu();
+    tlb_map(&mut t, 5, 0x0040_0000, 0x0002_0000, TLB_DIRTY);
+    t.ram.write::<BigEndian, u32>(0x2_1008, 0x1234_5678);
+
+    t.set_reg(1, 0x0040_0000);
+    t.set_reg(3, 0xCAFE_BABE);
+    t.run(0x8000_0000, &[lw(2, 0x1008, 1), sw(3, 0x10, 1)], 2);
+    assert_eq!(t.reg(2), 0x1234_5678);
+    assert_eq!(t.ram.read::<BigEndian, u32>(0x2_0010), 0xCAFE_BABE);
+
+    // TLBP finds the entry from EntryHi
+    t.set_reg(1, 0x0040_1000);
+    t.run(0x8000_0000, &[mtc0(1, 10), TLBP, mfc0(4, 0)], 3);
+    assert_eq!(t.reg(4), 5);
+
+    // Stores to a clean page raise a TLB modification exception, and
+    // leave memory untouched.
+    tlb_map(&mut t, 6, 0x0060_0000, 0x0003_0000, TLB_VALID);
+    t.set_reg(1, 0x0060_0000);
+    t.run(0x8000_1000, &[sw(3, 0x20, 1)], 1);
+    assert_eq!(t.cpu.exception_stats().get(&Exception::MOD), Some(&1));
+    assert_eq!(t.ram.read::<BigEndian, u32>(0x3_0020), 0);
+    assert_eq!(t.cpu.ctx().get_pc(), 0x8000_0180);
+}
+
+#[test]
+fn tlb_miss() {
+    let mut t = make_cpu();
+
+    // Refill handler: read BadVAddr, Context and EntryHi
+    let handler = [mfc0(5, 8), mfc0(6, 4), mfc0(7, 10)];
+    for (idx, op) in handler.iter().enumerate() {
+        t.ram.write::<BigEndian, u32>(idx as u32 * 4, *op);
+    }
+
+    t.set_reg(1, 0x0070_3000);
+    t.set_reg(2, 0xAB);
+    t.run(0x8000_1000, &[mtc0(2, 10), lw(2, 0x10, 1)], 5);
+    assert_eq!(t.cpu.exception_stats().get(&Exception::TLBL), Some(&1));
+    assert_eq!(t.reg(2), 0xAB); // destination is not modified
+    assert_eq!(t.reg(5), 0x0070_3010);
+    assert_eq!(t.reg(6), 0x0070_2000 >> 9);
+    assert_eq!(t.reg(7), 0x0070_20AB);
+    assert_eq!(t.cpu.ctx().get_pc(), 0x8000_000C);
+}
+
+// Instruction fetches from an unmapped KUSEG page raise TLBL through the
+// refill vector, with EPC and BadVAddr at the fetched address; in a delay
+// slot, EPC points to the branch and Cause.BD is set.
+#[test]
+fn fetch_tlb_miss() {
+    let mut t = make_cpu();
+    let handler = [mfc0(5, 8), mfc0(6, 14), mfc0(7, 13)];
+    for (idx, op) in handler.iter().enumerate() {
+        t.ram.write::<BigEndian, u32>(idx as u32 * 4, *op);
+    }
+
+    t.cpu.ctx_mut().set_pc(0x0070_3000);
+    let until = t.cpu.ctx().clock + 3;
+    t.cpu.run(until);
+    assert_eq!(t.cpu.exception_stats().get(&Exception::TLBL), Some(&1));
+    assert_eq!(t.reg(5), 0x0070_3000);
+    assert_eq!(t.reg(6), 0x0070_3000);
+    assert_eq!(t.reg(7) & (1 << 31), 0);
+    assert_eq!((t.reg(7) >> 2) & 0x1F, 0x02);
+    assert_eq!(t.cpu.ctx().get_pc(), 0x8000_000C);
+
+    // The delay slot of a branch at the end of a mapped page is in the
+    // next, unmapped one.
+    let mut t = make_cpu();
+    for (idx, op) in handler.iter().enumerate() {
+        t.ram.write::<BigEndian, u32>(idx as u32 * 4, *op);
+    }
+    tlb_map(&mut t, 0, 0x0040_0000, 0x0002_0000, TLB_DIRTY);
+    t.ram.write::<BigEndian, u32>(0x2_1FFC, beq(0, 0, -4));
+    t.cpu.ctx_mut().set_pc(0x0040_1FFC);
+    let until = t.cpu.ctx().clock + 5;
+    t.cpu.run(until);
+    assert_eq!(t.cpu.exception_stats().get(&Exception::TLBL), Some(&1));
+    assert_eq!(t.reg(5), 0x0040_2000);
+    assert_eq!(t.reg(6), 0x0040_1FFC);
+    assert_eq!(t.reg(7) & (1 << 31), 1 << 31);
+    assert_eq!((t.reg(7) >> 2) & 0x1F, 0x02);
+}
+
+// XKPHYS is only reachable with 64-bit addressing (KX), and user mode
+// cannot access the kernel segments.
+#[test]
+fn fetch_across_pages() {
+    let mut t = make_cpu();
+    tlb_map(&mut t, 0, 0x0040_0000, 0x0002_0000, TLB_DIRTY);
+    tlb_map(&mut t, 1, 0x0040_2000, 0x0005_0000, TLB_DIRTY);
+
+    // Execution continues into the next virtual page, not into the
+    // physical memory that follows.
+    t.ram.write::<BigEndian, u32>(0x2_1FFC, addiu(2, 2, 1));
+    t.ram.write::<BigEndian, u32>(0x2_2000, addiu(4, 4, 1));
+    t.ram.write::<BigEndian, u32>(0x5_0000, addiu(3, 3, 1));
+    t.set_reg(2, 0);
+    t.set_reg(3, 0);
+    t.set_reg(4, 0);
+    t.cpu.ctx_mut().set_pc(0x0040_1FFC);
+    let until = t.cpu.ctx().clock + 2;
+    t.cpu.run(until);
+    assert_eq!((t.reg(2), t.reg(3), t.reg(4)), (1, 1, 0));
+    assert_eq!(t.cpu.ctx().get_pc(), 0x0040_2004);
+
+    // Branches within the page, in both directions
+    t.set_reg(1, 0);
+    let prog = [addiu(1, 1, 1), beq(0, 0, 2), NOP, NOP, beq(0, 0, -5), NOP];
+    t.run(0x8000_0FE0, &prog, 10);
+    assert_eq!(t.reg(1), 2);
+    assert_eq!(t.cpu.ctx().get_pc(), 0x8000_0FE0);
+}
+
+#[test]
+fn address_segments() {
+    let mut t = make_cpu();
+    t.ram.write::<BigEndian, u32>(0x1000, 0x1234_5678);
+
+    // XKPHYS, uncached
+    t.set_reg(1, 0x9000_0000_0000_1000);
+    t.set_reg(3, 0x80);
+    t.run(0x8000_0000, &[mtc0(3, 12), lw(2, 0, 1)], 2);
+    assert_eq!(t.reg(2), 0x1234_5678);
+
+    // In 32-bit mode, only the low 32 bits are used (KSEG0)
+    t.set_reg(1, 0x9000_0000_8000_1000);
+    t.set_reg(2, 0);
+    t.run(0x8000_0000, &[mtc0(0, 12), lw(2, 0, 1)], 2);
+    assert_eq!(t.reg(2), 0x1234_5678);
+
+    // Return to user code in KUSEG (mapped by the TLB), which then accesses
+    // KSEG0.
+    tlb_map(&mut t, 0, 0x0000_0000, 0x0000_0000, TLB_DIRTY);
+    t.ram.write::<BigEndian, u32>(0x180, mfc0(5, 8));
+    t.ram.write::<BigEndian, u32>(0x800, lw(2, 0, 1));
+    t.set_reg(1, 0xFFFF_FFFF_8000_1000);
+    t.set_reg(3, 0x12); // EXL, KSU=user
+    t.set_reg(4, 0x800);
+    t.run(0x8000_0000, &[mtc0(4, 14), mtc0(3, 12), 0x4200_0018], 5);
+    assert_eq!(t.cpu.exception_stats().get(&Exception::ADEL), Some(&1));
+    assert_eq!(t.reg(5), 0xFFFF_FFFF_8000_1000);
+    assert_eq!(t.cpu.ctx().get_pc(), 0x8000_0184);
+}
+
+// COP0 instructions in user mode raise a coprocessor unusable exception,
+// unless CU0 is set.
+#[test]
+fn cop0_unusable_in_user_mode() {
+    let mut t = make_cpu();
+    tlb_map(&mut t, 0, 0x0000_0000, 0x0000_0000, TLB_DIRTY);
+    t.ram.write::<BigEndian, u32>(0x180, mfc0(5, 13));
+    t.ram.write::<BigEndian, u32>(0x800, mfc0(2, 12));
+    t.set_reg(2, 0);
+    t.set_reg(3, 0x12); // EXL, KSU=user
+    t.set_reg(4, 0x800);
+    t.run(0x8000_0000, &[mtc0(4, 14), mtc0(3, 12), 0x4200_0018], 5);
+    assert_eq!(t.cpu.exception_stats().get(&Exception::CPU), Some(&1));
+    assert_eq!((t.reg(5) >> 2) & 0x1F, 0x0B);
+    assert_eq!((t.reg(5) >> 28) & 3, 0);
+    assert_eq!(t.reg(2), 0);
+
+    // With CU0 set, user code can access COP0
+    t.set_reg(3, 0x1000_0012);
+    t.run(0x8000_0000, &[mtc0(4, 14), mtc0(3, 12), 0x4200_0018], 4);
+    assert_eq!(t.reg(2), 0x1000_0010);
+    assert_eq!(t.cpu.ctx().get_pc(), 0x0000_0804);
+}
+
+#[test]
+fn watch_exception() {
+    let mut t = make_cpu();
+    t.ram.write::<BigEndian, u32>(0x180, mfc0(5, 13));
+    t.ram.write::<BigEndian, u32>(0x1000, 0x1234_5678);
+    t.set_reg(1, 0x8000_1000);
+    t.set_reg(2, 0xCAFE_BABE);
+    t.set_reg(3, 0x1001); // Watch stores to 0x1000..0x1007
+
+    // Loads are not watched; the store raises the exception, and does not
+    // complete
+    t.run(0x8000_0000, &[mtc0(3, 18), lw(6, 4, 1), sw(2, 0, 1)], 4);
+    assert_eq!(t.cpu.exception_stats().get(&Exception::WATCH), Some(&1));
+    assert_eq!((t.reg(5) >> 2) & 0x1F, 0x17);
+    assert_eq!(t.ram.read::<BigEndian, u32>(0x1000), 0x1234_5678);
+
+    // Watch exceptions are not raised while EXL is set
+    t.run(0x8000_0000, &[sw(2, 0, 1)], 1);
+    assert_eq!(t.cpu.exception_stats().get(&Exception::WATCH), Some(&1));
+    assert_eq!(t.ram.read::<BigEndian, u32>(0x1000), 0xCAFE_BABE);
+
+    // Nor while ERL is set
+    t.set_reg(2, 0xDEAD_BEEF);
+    t.set_reg(4, 0x4); // ERL
+    t.run(0x8000_0000, &[mtc0(4, 12), sw(2, 0, 1)], 2);
+    assert_eq!(t.cpu.exception_stats().get(&Exception::WATCH), Some(&1));
+    assert_eq!(t.ram.read::<BigEndian, u32>(0x1000), 0xDEAD_BEEF);
+
+    // And again once both are clear
+    t.run(0x8000_0000, &[mtc0(0, 12), sw(2, 0, 1)], 2);
+    assert_eq!(t.cpu.exception_stats().get(&Exception::WATCH), Some(&2));
+}
+
+#[test]
+fn unimplemented_cop0_regs() {
+    let mut t = make_cpu();
+    t.set_reg(5, 0x1234);
+
+    // Accesses are ignored, and each register is reported once
+    t.run(0x8000_0000, &[mfc0(5, 7), mfc0(5, 7), mtc0(5, 21)], 3);
+    assert_eq!(t.reg(5), 0x1234);
+    assert_eq!(t.cpu.unimplemented_cop0_regs(), vec![(7, false), (21, true)]);
+}
+
+fn cache(op: u32, off: i16, base: u32) -> u32 {
+    itype(0x2F, base, op, off)
+}
+
+#[test]
+fn cache_ops() {
+    let mut t = make_cpu();
+    t.set_reg(1, 0x8000_1230);
+    t.set_reg(3, 0x180); // Tag 0x1000, valid
+
+    // Index Store Tag / Index Load Tag (data cache)
+    t.run(
+        0x8000_0000,
+        &[
+            mtc0(3, 28),
+            cache(0x09, 0, 1),
+            mtc0(0, 28),
+            cache(0x05, 0, 1),
+            mfc0(5, 28),
+        ],
+        5,
+    );
+    assert_eq!(t.reg(5), 0x180);
+
+    // Hit Invalidate
+    t.run(
+        0x8000_0000,
+        &[cache(0x11, 0, 1), cache(0x05, 0, 1), mfc0(5, 28)],
+        3,
+    );
+    assert_eq!(t.reg(5), 0);
+
+    // Cached loads fill the line, stores make it dirty, and Hit Write Back
+    // cleans it
+    t.run(
+        0x8000_0000,
+        &[
+            lw(7, 0, 1),
+            sw(7, 4, 1),
+            cache(0x05, 0, 1),
+            mfc0(5, 28),
+            cache(0x19, 0, 1),
+            cache(0x05, 0, 1),
+            mfc0(6, 28),
+        ],
+        7,
+    );
+    assert_eq!(t.reg(5), 0x1C0);
+    assert_eq!(t.reg(6), 0x180);
+
+    // Uncached accesses do not touch the cache
+    t.set_reg(2, 0xA000_1230);
+    t.run(
+        0x8000_0000,
+        &[
+            cache(0x11, 0, 1),
+            lw(7, 0, 2),
+            cache(0x05, 0, 1),
+            mfc0(5, 28),
+        ],
+        4,
+    );
+    assert_eq!(t.reg(5), 0);
+}
+
+// A COP2 whose only instruction stores rt at the virtual address in rd,
+// then loads rt from the next word.
+struct StoreCop;
+
+impl Cop for StoreCop {
+    fn reg(&self, _idx: usize) -> u128 {
+        0
+    }
+    fn set_reg(&mut self, _idx: usize, _val: u128) {}
+
+    fn op(&mut self, cpu: &mut CpuContext, opcode: u32, bus: &mut CpuBus) {
+        let rt = ((opcode >> 16) & 0x1f) as usize;
+        let rd = ((opcode >> 11) & 0x1f) as usize;
+        let paddr = bus.translate_addr(cpu.regs[rd], MemAccess::Write).unwrap();
+        bus.write::<u32>(paddr, cpu.regs[rt] as u32);
+        cpu.regs[rt] = bus.read::<u32>(paddr + 4) as u64;
+    }
+}
+
+#[test]
+fn cop_bus_access() {
+    let mut t = make_cpu();
+    t.cpu.set_cop2(Box::new(StoreCop));
+    tlb_map(&mut t, 2, 0x0040_0000, 0x0002_0000, TLB_DIRTY);
+    t.ram.write::<BigEndian, u32>(0x2_0104, 0x1234_5678);
+
+    // Virtual addresses are translated through the TLB
+    t.set_reg(1, 0x0040_0100);
+    t.set_reg(2, 0xCAFE_BABE);
+    t.run(0x8000_0000, &[0x4800_0000 | (2 << 16) | (1 << 11)], 1);
+    assert_eq!(t.ram.read::<BigEndian, u32>(0x2_0100), 0xCAFE_BABE);
+    assert_eq!(t.reg(2), 0x1234_5678);
+}
+
+fn cop1(fmt: u32, rt: u32, fs: u32) -> u32 {
+    0x4400_0000 | (fmt << 21) | (rt << 16) | (fs << 11)
+}
+fn fop(fmt: u32, func: u32, fd: u32, fs: u32, ft: u32) -> u32 {
+    cop1(fmt, ft, fs) | (fd << 6) | func
+}
+const FMT_S: u32 = 16;
+const FMT_D: u32 = 17;
+const FMT_W: u32 = 20;
+
+// Status.CU1: COP1 usable
+const STATUS_CU1: u64 = 0x2000_0000;
+// Status.FR: 32 64-bit FPU registers
+const STATUS_FR: u64 = 0x0400_0000;
+
+// A CPU with the FPU installed and usable (Status.CU1 set).
+fn make_fpu_cpu() -> TestCpu {
+    let logger = slog::Logger::root(Discard, o!());
+    let mut t = make_cpu();
+    let mut cop0 = Cp0::new(logger.new(o!()));
+    cop0.set_reg(12, STATUS_CU1 as u128);
+    t.cpu.set_cop0(cop0);
+    t.cpu.set_cop1(Fpu::new(logger));
+    t
+}
+
+#[test]
+fn fpu_ops() {
+    let mut t = make_fpu_cpu();
+    t.set_reg(31, STATUS_CU1 | STATUS_FR);
+    t.set_reg(1, 0x4040_0000); // 3.0
+    t.set_reg(2, 0x3F00_0000); // 0.5
+    t.set_reg(6, 1); // FCSR: round towards zero
+    t.set_reg(11, 0x7FC0_0000); // NaN
+    let prog = [
+        mtc0(31, 12),               // mtc0 ra,status
+        cop1(4, 1, 1),              // mtc1 at,f1
+        cop1(4, 2, 2),              // mtc1 v0,f2
+        fop(FMT_S, 0x00, 3, 1, 2),  // add.s f3,f1,f2
+        cop1(0, 3, 3),              // mfc1 v1,f3
+        fop(FMT_S, 0x21, 4, 3, 0),  // cvt.d.s f4,f3
+        cop1(1, 4, 4),              // dmfc1 a0,f4
+        fop(FMT_S, 0x24, 5, 3, 0),  // cvt.w.s f5,f3 (nearest even)
+        cop1(0, 5, 5),              // mfc1 a1,f5
+        cop1(6, 6, 31),             // ctc1 a2,fcr31
+        fop(FMT_D, 0x24, 6, 4, 0),  // cvt.w.d f6,f4 (towards zero)
+        cop1(0, 7, 6),              // mfc1 a3,f6
+        fop(FMT_S, 0x3C, 0, 2, 1),  // c.lt.s f2,f1
+        cop1(2, 8, 31),             // cfc1 t0,fcr31
+        fop(FMT_S, 0x06, 9, 1, 0),  // mov.s f9,f1
+        fop(FMT_S, 0x07, 10, 9, 0), // neg.s f10,f9
+        cop1(0, 9, 10),             // mfc1 t1,f10
+        fop(FMT_W, 0x20, 12, 5, 0), // cvt.s.w f12,f5
+        cop1(0, 10, 12),            // mfc1 t2,f12
+        cop1(4, 11, 13),            // mtc1 t3,f13
+        fop(FMT_S, 0x32, 0, 13, 1), // c.eq.s f13,f1
+        cop1(2, 12, 31),            // cfc1 t4,fcr31
+        fop(FMT_S, 0x33, 0, 13, 1), // c.ueq.s f13,f1
+        cop1(2, 13, 31),            // cfc1 t5,fcr31
+    ];
+    t.run(0x8000_0000, &prog, prog.len() as i64);
+
+    assert_eq!(t.reg(3), 0x4060_0000); // 3.5
+    assert_eq!(t.reg(4), 0x400C_0000_0000_0000);
+    assert_eq!(t.reg(5), 4);
+    assert_eq!(t.reg(7), 3);
+    assert_eq!(t.reg(8), 0x0080_0005); // inexact flag set by cvt.w.d
+    assert_eq!(t.reg(9), 0xFFFF_FFFF_C040_0000); // -3.0
+    assert_eq!(t.reg(10), 0x4080_0000); // 4.0
+    assert_eq!(t.reg(12), 0x0000_0005);
+    assert_eq!(t.reg(13), 0x0080_0005);
+}
+
+#[test]
+fn fpu_rounding_and_exceptions() {
+    let mut t = make_fpu_cpu();
+    t.set_reg(31, STATUS_CU1 | STATUS_FR);
+    t.set_reg(1, 0x3F80_0000); // 1.0
+    t.set_reg(2, 0x4040_0000); // 3.0
+    t.set_reg(10, 1); // RZ
+    t.set_reg(11, 2); // RP
+    t.set_reg(12, 3); // RM
+    t.set_reg(13, 0x7F7F_FFFF); // largest single
+    t.set_reg(14, 0x0100_0400); // FS, divide-by-zero enabled
+    let prog = [
+        mtc0(31, 12),                 // mtc0 ra,status
+        cop1(4, 1, 1),                // mtc1 at,f1
+        cop1(4, 2, 2),                // mtc1 v0,f2
+        cop1(6, 10, 31),              // ctc1 t2,fcr31
+        fop(FMT_S, 0x03, 3, 1, 2),    // div.s f3,f1,f2
+        cop1(0, 3, 3),                // mfc1 v1,f3
+        cop1(6, 11, 31),              // ctc1 t3,fcr31
+        fop(FMT_S, 0x03, 4, 1, 2),    // div.s f4,f1,f2
+        cop1(0, 4, 4),                // mfc1 a0,f4
+        cop1(6, 12, 31),              // ctc1 t4,fcr31
+        fop(FMT_S, 0x07, 5, 1, 0),    // neg.s f5,f1
+        fop(FMT_S, 0x03, 6, 5, 2),    // div.s f6,f5,f2
+        cop1(0, 5, 6),                // mfc1 a1,f6
+        cop1(2, 6, 31),               // cfc1 a2,fcr31
+        cop1(6, 0, 31),               // ctc1 zero,fcr31
+        cop1(4, 0, 8),                // mtc1 zero,f8
+        fop(FMT_S, 0x03, 9, 8, 8),    // div.s f9,f8,f8
+        cop1(0, 7, 9),                // mfc1 a3,f9
+        cop1(2, 8, 31),               // cfc1 t0,fcr31
+        cop1(6, 10, 31),              // ctc1 t2,fcr31
+        cop1(4, 13, 10),              // mtc1 t5,f10
+        fop(FMT_S, 0x00, 11, 10, 10), // add.s f11,f10,f10
+        cop1(0, 9, 11),               // mfc1 t1,f11
+        cop1(6, 14, 31),              // ctc1 t6,fcr31
+        fop(FMT_S, 0x03, 12, 1, 8),   // div.s f12,f1,f8
+    ];
+    t.run(0x8000_0000, &prog, prog.len() as i64);
+
+    assert_eq!(t.reg(3), 0x3EAA_AAAA); // 1/3 towards zero
+    assert_eq!(t.reg(4), 0x3EAA_AAAB); // 1/3 towards +inf
+    assert_eq!(t.reg(5), 0xFFFF_FFFF_BEAA_AAAB); // -1/3 towards -inf
+    assert_eq!(t.reg(6), 0x0000_1007); // inexact cause and flag
+    assert_eq!(t.reg(7), 0x7FBF_FFFF); // 0/0: default NaN
+    assert_eq!(t.reg(8), 0x0001_0040); // invalid cause and flag
+    assert_eq!(t.reg(9), 0x7F7F_FFFF); // overflow clamped towards zero
+
+    // 1/0 with divide-by-zero enabled traps
+    assert_eq!(t.cpu.exception_stats().get(&Exception::FPE), Some(&1));
+    assert_eq!(t.cpu.ctx().get_pc(), 0x8000_0180);
+}
+
+// BC1F/BC1T/BC1FL/BC1TL
+fn bc1(likely: bool, tf: bool, off: i16) -> u32 {
+    cop1(8, ((likely as u32) << 1) | tf as u32, 0) | off as u16 as u32
+}
+
+#[test]
+fn fpu_branches() {
+    let mut t = make_fpu_cpu();
+    t.set_reg(31, STATUS_CU1 | STATUS_FR);
+    t.set_reg(1, 0x4040_0000); // 3.0
+    let prog = [
+        mtc0(31, 12),              // mtc0 ra,status
+        cop1(4, 1, 1),             // mtc1 at,f1
+        fop(FMT_S, 0x32, 0, 1, 1), // c.eq.s f1,f1
+        bc1(false, false, 2),      // bc1f (not taken)
+        addiu(2, 0, 1),            // delay slot: executed
+        bc1(false, true, 2),       // bc1t (taken)
+        addiu(3, 0, 1),            // delay slot: executed
+        addiu(4, 0, 1),            // skipped
+        bc1(true, false, 2),       // bc1fl (not taken)
+        addiu(5, 0, 1),            // delay slot: nullified
+        bc1(true, true, 2),        // bc1tl (taken)
+        addiu(6, 0, 1),            // delay slot: executed
+        addiu(7, 0, 1),            // skipped
+        addiu(8, 0, 1),
+    ];
+    t.run(0x8000_0000, &prog, 12);
+
+    let regs: Vec<u64> = (2..9).map(|r| t.reg(r)).collect();
+    assert_eq!(regs, [1, 1, 0, 0, 1, 0, 1]);
+    assert_eq!(t.cpu.ctx().get_pc(), 0x8000_0038);
+}
+
+#[test]
+fn fpu_fr_banking() {
+    let mut t = make_fpu_cpu();
+    t.set_reg(1, 0x1111_1111);
+    t.set_reg(2, 0x2222_2222);
+    t.set_reg(5, 0x8000_1000);
+    t.set_reg(7, STATUS_CU1 | STATUS_FR);
+    let prog = [
+        // FR=0: odd registers are the upper half of even ones
+        cop1(4, 1, 0),        // mtc1 at,f0
+        cop1(4, 2, 1),        // mtc1 v0,f1
+        cop1(1, 3, 0),        // dmfc1 v1,f0
+        cop1(1, 4, 1),        // dmfc1 a0,f1
+        itype(0x39, 5, 1, 0), // swc1 f1,0(a1)
+        lw(6, 0, 5),          // lw a2,0(a1)
+        // FR=1: 32 independent registers
+        mtc0(7, 12),   // mtc0 a3,status
+        cop1(4, 1, 1), // mtc1 at,f1
+        cop1(1, 8, 1), // dmfc1 t0,f1
+        cop1(1, 9, 0), // dmfc1 t1,f0
+    ];
+    t.run(0x8000_0000, &prog, prog.len() as i64);
+
+    assert_eq!(t.reg(3), 0x2222_2222_1111_1111);
+    assert_eq!(t.reg(4), 0x2222_2222_1111_1111);
+    assert_eq!(t.reg(6), 0x2222_2222);
+    assert_eq!(t.reg(8), 0x1111_1111);
+    assert_eq!(t.reg(9), 0x2222_2222_1111_1111);
+}
+
+// FPU instructions, including loads/stores and control register moves,
+// raise a Coprocessor Unusable exception (CE=1) if Status.CU1 is clear.
+#[test]
+fn fpu_unusable() {
+    let mut t = make_cpu();
+    t.cpu.set_cop1(Fpu::new(slog::Logger::root(Discard, o!())));
+    t.ram.write::<BigEndian, u32>(0x180, mfc0(5, 13)); // handler: mfc0 a1,cause
+    t.set_reg(1, 0x8000_1000);
+    t.set_reg(2, 0x0100_0000);
+    let ops = [
+        cop1(6, 2, 31),            // ctc1 v0,fcr31
+        itype(0x31, 1, 2, 0),      // lwc1 f2,0(at)
+        fop(FMT_S, 0x00, 3, 1, 2), // add.s f3,f1,f2
+    ];
+    for (idx, op) in ops.iter().enumerate() {
+        t.set_reg(5, 0);
+        t.run(0x8000_0000, &[*op], 2);
+        let cause = t.reg(5);
+        assert_eq!((cause >> 2) & 0x1F, 11, "op {}: ExcCode", idx);
+        assert_eq!((cause >> 28) & 3, 1, "op {}: CE", idx);
+        assert_eq!(
+            t.cpu.exception_stats().get(&Exception::CPU),
+            Some(&(idx as u64 + 1))
+        );
+    }
+}
+
+// LL/SC: SC only stores (and sets rt to 1) if no exception, nor ERET, was
+// executed since LL.
+#[test]
+fn load_linked_store_conditional() {
+    let mut t = make_cpu();
+    t.set_reg(1, 0xFFFF_FFFF_8000_1000);
+    t.set_reg(2, 0x1234_5678);
+    t.set_reg(6, 0xFFFF_FFFF_8000_0100); // EPC
+    t.ram.write::<BigEndian, u32>(0x1000, 0xCAFE_BABE);
+    t.ram.write::<BigEndian, u32>(0x100, addiu(5, 0, 0x55));
+    t.ram.write::<BigEndian, u32>(0x104, itype(0x38, 1, 5, 0)); // sc a1,0(at)
+    t.run(
+        0x8000_0000,
+        &[
+            itype(0x30, 1, 3, 0), // ll v1,0(at)
+            itype(0x38, 1, 2, 0), // sc v0,0(at)
+            mfc0(7, 17),          // LLAddr
+            itype(0x30, 1, 4, 0), // ll a0,0(at)
+            mtc0(6, 14),
+            0x4200_0018, // eret
+        ],
+        8,
+    );
+    assert_eq!(t.reg(3), 0xFFFF_FFFF_CAFE_BABE);
+    assert_eq!(t.reg(2), 1);
+    assert_eq!(t.reg(4), 0x1234_5678);
+    assert_eq!(t.reg(7), 0x100);
+    assert_eq!(t.reg(5), 0);
+    assert_eq!(t.ram.read::<BigEndian, u32>(0x1000), 0x1234_5678);
+
+    // An exception between LL and SC breaks the link as well
+    t.set_reg(2, 0x0000_1111_2222_3333);
+    t.ram.write::<BigEndian, u32>(0x180, itype(0x3C, 1, 2, 0)); // scd v0,0(at)
+    t.run(
+        0x8000_0200,
+        &[
+            itype(0x34, 1, 3, 0), // lld v1,0(at)
+            0x0000_000D,          // break
+        ],
+        3,
+    );
+    assert_eq!(t.cpu.exception_stats().get(&Exception::BP), Some(&1));
+    assert_eq!(t.reg(3), 0x1234_5678_0000_0000);
+    assert_eq!(t.reg(2), 0);
+    assert_eq!(t.ram.read::<BigEndian, u32>(0x1000), 0x1234_5678);
+}
@@ -1,7 +1,13 @@
 extern crate emu;
 extern crate slog;
-use emu::bus::be::Reg32;
+use emu::bus::be::{Bus, Reg32};
 use emu::int::Numerics;
+use interrupts::{Interrupt, InterruptLog};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// Frequency of the video clock, from which the DAC sample rate is derived.
+const VI_NTSC_CLOCK: u32 = 48_681_812;
 
 #[derive(DeviceBE)]
 pub struct Ai {
@@ -11,7 +17,7 @@ pub struct Ai {
 
     // [14:0] transfer length (v1.0) - Bottom 3 bits are ignored
     // [17:0] transfer length (v2.0) - Bottom 3 bits are ignored
-    #[reg(bank = 0, offset = 0x04, rwmask = 0x3FFFF)]
+    #[reg(bank = 0, offset = 0x04, rwmask = 0x3FFFF, wcb)]
     length: Reg32,
 
     // (W): [0] DMA enable - if LSB == 1, DMA is enabled
@@ -38,10 +44,16 @@ pub struct Ai {
     bit_rate: Reg32,
 
     logger: slog::Logger,
+    bus: Rc<RefCell<Box<Bus>>>,
+
+    // Samples fetched through DMA, not yet consumed by the audio output
+    samples: Vec<i16>,
+
+    ints: InterruptLog,
 }
 
 impl Ai {
-    pub fn new(logger: slog::Logger) -> Ai {
+    pub fn new(logger: slog::Logger, bus: Rc<RefCell<Box<Bus>>>) -> Ai {
         Ai {
             dram_address: Reg32::default(),
             length: Reg32::default(),
@@ -50,7 +62,47 @@ impl Ai {
             dac_sample_period: Reg32::default(),
             bit_rate: Reg32::default(),
             logger,
+            bus,
+            samples: Vec::new(),
+            ints: InterruptLog::new(),
+        }
+    }
+
+    pub fn set_interrupt_log(&mut self, ints: InterruptLog) {
+        self.ints = ints;
+    }
+
+    // Current DAC sample rate (0 if the DAC was not configured yet).
+    pub fn frequency(&self) -> u32 {
+        match self.dac_sample_period.get() {
+            0 => 0,
+            period => VI_NTSC_CLOCK / (period + 1),
+        }
+    }
+
+    // Move all the samples produced so far into the output buffer,
+    // and return their sample rate.
+    pub fn take_samples(&mut self, out: &mut Vec<i16>) -> u32 {
+        out.extend(self.samples.drain(..));
+        self.frequency()
+    }
+
+    fn cb_write_length(&mut self, _old: u32, len: u32) {
+        if self.control.get() & 1 == 0 {
+            return;
+        }
+
+        // Each 32-bit word in RDRAM is a stereo sample (left in the
+        // upper half). The DMA is completed immediately.
+        let bus = self.bus.borrow();
+        let addr = self.dram_address.get() & !7;
+        let len = len & !7;
+        for off in (0..len).step_by(4) {
+            let word = bus.read::<u32>(addr.wrapping_add(off));
+            self.samples.push((word >> 16) as i16);
+            self.samples.push(word as i16);
         }
+        self.ints.raise(Interrupt::Ai);
     }
 
     fn cb_write_status(&self, _old: u32, new: u32) {
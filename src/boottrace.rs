@@ -0,0 +1,140 @@
+use super::cartridge::CicModel;
+use super::interrupts::{Interrupt, InterruptStats};
+use slog;
+
+/// Milestones of the boot process of a game, in their usual order.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Milestone {
+    CicDetected,
+    Ipl3Copied,
+    EntryPoint,
+    FirstViInterrupt,
+    FirstPadPoll,
+    FirstAiDma,
+}
+
+impl Milestone {
+    pub const ALL: [Milestone; 6] = [
+        Milestone::CicDetected,
+        Milestone::Ipl3Copied,
+        Milestone::EntryPoint,
+        Milestone::FirstViInterrupt,
+        Milestone::FirstPadPoll,
+        Milestone::FirstAiDma,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Milestone::CicDetected => "CIC detected",
+            Milestone::Ipl3Copied => "IPL3 copied",
+            Milestone::EntryPoint => "entry point reached",
+            Milestone::FirstViInterrupt => "first VI interrupt",
+            Milestone::FirstPadPoll => "first controller poll",
+            Milestone::FirstAiDma => "first AI DMA",
+        }
+    }
+}
+
+/// BootTrace logs when each boot milestone is reached, with its CPU cycle
+/// timestamp, so that a game stuck on a black screen shows how far it got.
+/// Milestones are checked at each scanline, so timestamps are accurate to a
+/// line.
+pub struct BootTrace {
+    logger: slog::Logger,
+    // Boot code (IPL3) from the ROM, copied into DMEM by the PIF
+    ipl3: Vec<u8>,
+    reached: Vec<(Milestone, i64)>,
+}
+
+impl BootTrace {
+    pub fn new(logger: slog::Logger, header: &[u8], cic: Option<CicModel>) -> BootTrace {
+        let mut trace = BootTrace {
+            logger,
+            ipl3: header[0x40..0x1000].to_vec(),
+            reached: Vec::new(),
+        };
+        match cic {
+            Some(cic) => {
+                info!(trace.logger, "CIC model"; o!("cic" => cic as u32));
+                trace.reach(Milestone::CicDetected, 0);
+            }
+            None => warn!(trace.logger, "unknown CIC model"),
+        }
+        trace
+    }
+
+    fn reach(&mut self, m: Milestone, clock: i64) {
+        if self.reached(m).is_none() {
+            info!(self.logger, "boot milestone"; o!("milestone" => m.name(), "clock" => clock));
+            self.reached.push((m, clock));
+        }
+    }
+
+    /// CPU clock at which the milestone was reached, if it was.
+    pub fn reached(&self, m: Milestone) -> Option<i64> {
+        self.reached.iter().find(|&&(r, _)| r == m).map(|&(_, c)| c)
+    }
+
+    /// Milestones that were not reached yet.
+    pub fn missing(&self) -> Vec<Milestone> {
+        Milestone::ALL
+            .iter()
+            .cloned()
+            .filter(|&m| self.reached(m).is_none())
+            .collect()
+    }
+
+    // Check for new milestones, given the state of the machine. The entry
+    // point is reached when the CPU first runs code from RDRAM (IPL3 runs
+    // from DMEM, and jumps to the game).
+    pub(crate) fn check(&mut self, clock: i64, pc: u32, dmem: &[u8], ints: &InterruptStats) {
+        if self.reached(Milestone::Ipl3Copied).is_none() && dmem[0x40..0x1000] == self.ipl3[..] {
+            self.reach(Milestone::Ipl3Copied, clock);
+        }
+        if (pc >> 29 == 4 || pc >> 29 == 5) && pc & 0x1FFF_FFFF < 0x0080_0000 {
+            self.reach(Milestone::EntryPoint, clock);
+        }
+        let firsts = [
+            (Interrupt::Vi, Milestone::FirstViInterrupt),
+            (Interrupt::Si, Milestone::FirstPadPoll),
+            (Interrupt::Ai, Milestone::FirstAiDma),
+        ];
+        for &(int, m) in firsts.iter() {
+            if ints.total(int) != 0 {
+                self.reach(m, clock);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn boot_trace() {
+        let logger = slog::Logger::root(slog::Discard, o!());
+        let mut rom = vec![0u8; 0x1000];
+        rom[0x40] = 0x3C;
+        let mut trace = BootTrace::new(logger, &rom, Some(CicModel::Cic6102));
+        let mut dmem = vec![0u8; 0x1000];
+        let mut ints = InterruptStats::default();
+        assert_eq!(trace.reached(Milestone::CicDetected), Some(0));
+
+        trace.check(100, 0xBFC0_0000, &dmem, &ints);
+        assert_eq!(trace.missing().len(), 5);
+
+        dmem.copy_from_slice(&rom);
+        trace.check(200, 0xA400_0040, &dmem, &ints);
+        ints.raise(Interrupt::Vi);
+        trace.check(300, 0x8000_0400, &dmem, &ints);
+        trace.check(400, 0x8000_0400, &dmem, &ints);
+        assert_eq!(trace.reached(Milestone::Ipl3Copied), Some(200));
+        assert_eq!(trace.reached(Milestone::EntryPoint), Some(300));
+        assert_eq!(trace.reached(Milestone::FirstViInterrupt), Some(300));
+        assert_eq!(
+            trace.missing(),
+            vec![Milestone::FirstPadPoll, Milestone::FirstAiDma]
+        );
+    }
+}
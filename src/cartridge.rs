@@ -2,6 +2,7 @@ extern crate crc;
 extern crate emu;
 
 use self::crc::crc32;
+use byteorder::BigEndian;
 use emu::bus::be::{Mem, MemFlags};
 use errors::*;
 use std::fs::File;
@@ -13,6 +14,7 @@ pub struct Cartridge {
     rom: Mem,
 }
 
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum CicModel {
     Cic6101 = 6101,
     Cic6102 = 6102,
@@ -37,6 +39,61 @@ pub fn romswap(rom: Vec<u8>) -> Vec<u8> {
     }
 }
 
+// Read the game code (media type, cartridge ID and region) from the header
+// of a ROM file, without loading the whole ROM.
+pub fn read_game_code(romfn: &str) -> Result<String> {
+    let mut header = vec![0u8; 0x40];
+    File::open(romfn)?.read_exact(&mut header)?;
+    let header = romswap(header);
+    Ok(header[0x3B..0x3F].iter().map(|&c| c as char).collect())
+}
+
+// Read a whole ROM file, in big-endian order.
+pub fn read_rom(romfn: &str) -> Result<Vec<u8>> {
+    let mut contents = vec![];
+    File::open(romfn)?.read_to_end(&mut contents)?;
+    if contents.len() < 0x1000 {
+        bail!("ROM file too small");
+    }
+    Ok(romswap(contents))
+}
+
+/// Kind of save memory found on a cartridge.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SaveType {
+    Unknown,
+    Eeprom4k,
+    Eeprom16k,
+    Sram,
+    FlashRam,
+}
+
+impl SaveType {
+    // The save type is not stored in the ROM header, so it is looked up by
+    // cartridge ID (the game code without media type and region).
+    pub fn from_game_code(code: &str) -> SaveType {
+        match code.get(1..3) {
+            Some("SM") | Some("KT") | Some("FX") => SaveType::Eeprom4k,
+            Some("YS") => SaveType::Eeprom16k,
+            Some("ZL") => SaveType::Sram,
+            Some("ZS") | Some("MQ") => SaveType::FlashRam,
+            _ => SaveType::Unknown,
+        }
+    }
+
+    /// Size of the save memory, in bytes.
+    pub fn size(&self) -> Option<usize> {
+        match *self {
+            SaveType::Eeprom4k => Some(0x200),
+            SaveType::Eeprom16k => Some(0x800),
+            SaveType::Sram => Some(0x8000),
+            SaveType::FlashRam => Some(0x2_0000),
+            SaveType::Unknown => None,
+        }
+    }
+}
+
 impl Cartridge {
     pub fn new(romfn: &str) -> Result<Cartridge> {
         let mut file = File::open(romfn)?;
@@ -53,6 +110,22 @@ impl Cartridge {
         })
     }
 
+    // Header and boot code (IPL3) of the ROM.
+    pub fn header(&self) -> Vec<u8> {
+        self.rom.buf()[..0x1000].to_vec()
+    }
+
+    // Read a 32-bit word of the ROM, which is mirrored over the whole
+    // cartridge space.
+    pub fn read_u32(&self, off: u32) -> u32 {
+        self.rom.read::<BigEndian, u32>(off)
+    }
+
+    pub fn game_code(&self) -> String {
+        let rom = self.rom.buf();
+        rom[0x3B..0x3F].iter().map(|&c| c as char).collect()
+    }
+
     // Detect the CIC model by checksumming the header of the ROM.
     pub fn detect_cic_model(&self) -> Result<CicModel> {
         let rom = self.rom.buf();
@@ -2,10 +2,11 @@ extern crate bit_field;
 extern crate byteorder;
 extern crate emu;
 extern crate slog;
-use super::rdp::Rdp;
+use super::rdp::{HiresCache, Rdp, RdpCapture, TexturePack};
 use emu::bus::be::{Bus, MemIoR, Reg32, RegDeref, RegRef};
 use emu::int::Numerics;
 use emu::sync;
+use interrupts::InterruptLog;
 use std::cell::RefCell;
 use std::rc::Rc;
 
@@ -80,6 +81,38 @@ impl Dp {
         }
     }
 
+    pub fn set_texture_pack(&mut self, texpack: TexturePack) {
+        self.gfx.set_texture_pack(texpack);
+    }
+
+    pub fn set_resolution_scale(&mut self, scale: usize) {
+        self.gfx.set_resolution_scale(scale);
+    }
+
+    pub fn set_dither(&mut self, enabled: bool) {
+        self.gfx.set_dither(enabled);
+    }
+
+    pub fn set_interrupt_log(&mut self, ints: InterruptLog) {
+        self.gfx.set_interrupt_log(ints);
+    }
+
+    pub fn hires_cache(&self) -> Rc<RefCell<HiresCache>> {
+        self.gfx.hires_cache()
+    }
+
+    pub fn start_rdp_capture(&mut self) {
+        self.gfx.start_capture();
+    }
+
+    pub fn take_rdp_capture(&mut self) -> Option<RdpCapture> {
+        self.gfx.take_capture()
+    }
+
+    pub fn tmem_mut(&mut self) -> &mut [u8] {
+        self.gfx.tmem_mut()
+    }
+
     fn cmd_status_ref(&self) -> RegRef<StatusFlags> {
         self.cmd_status.as_ref::<StatusFlags>()
     }
@@ -0,0 +1,92 @@
+use super::{musyx, OsTask, TaskHandler};
+use emu::bus::be::Bus;
+use emu::int::Numerics;
+use errors::*;
+
+/// Family of the audio microcode run by an audio task. Audio microcodes
+/// differ in their command lists (ABI), so they need different HLE
+/// implementations.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AudioAbi {
+    // Nintendo's standard ABIs, identified by a signature word.
+    Standard(u32),
+    // MusyX (Factor 5), v1: Rogue Squadron, Resident Evil 2, Hydro Thunder...
+    MusyxV1,
+    // MusyX v2: Indiana Jones, Battle for Naboo.
+    MusyxV2,
+}
+
+impl AudioAbi {
+    /// Identify the microcode family from a few well-known words of the
+    /// microcode data section.
+    pub fn detect(task: &OsTask, bus: &Bus) -> AudioAbi {
+        let data = task.ucode_data & 0x00FF_FFFF;
+        let word = |off: u32| bus.read::<u32>(data + off);
+        if word(0) == 0x0000_0001 {
+            if word(0x30) == 0xF000_0F00 {
+                AudioAbi::Standard(word(0x28))
+            } else {
+                match word(0x10) {
+                    0x0001_0010 => AudioAbi::MusyxV2,
+                    v => AudioAbi::Standard(v),
+                }
+            }
+        } else {
+            match word(0x10) {
+                0x0000_0001 => AudioAbi::MusyxV1,
+                v => AudioAbi::Standard(v),
+            }
+        }
+    }
+}
+
+/// AudioTasks dispatches audio tasks according to the microcode family.
+pub struct AudioTasks;
+
+impl TaskHandler for AudioTasks {
+    fn name(&self) -> &'static str {
+        "audio"
+    }
+
+    fn run(&mut self, task: &OsTask, bus: &Bus) -> Result<()> {
+        match AudioAbi::detect(task, bus) {
+            AudioAbi::MusyxV1 => musyx::run_v1(task, bus),
+            AudioAbi::MusyxV2 => musyx::run_v2(task, bus),
+            AudioAbi::Standard(sig) => bail!("audio ABI {} HLE not implemented", sig.hex()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use emu::bus::be::Mem;
+    use slog;
+
+    #[test]
+    fn audio_abi() {
+        let logger = slog::Logger::root(slog::Discard, o!());
+        let mut bus = Bus::new(logger);
+        let ram = Mem::new(0x10000, Default::default());
+        bus.map_mem(0x0000_0000, 0xFFFF, &ram).unwrap();
+        let task = OsTask {
+            ty: 2,
+            ucode_data: 0x8000_1000,
+            ..Default::default()
+        };
+
+        bus.write::<u32>(0x1010, 0x0000_0001);
+        assert_eq!(AudioAbi::detect(&task, &bus), AudioAbi::MusyxV1);
+
+        bus.write::<u32>(0x1000, 0x0000_0001);
+        bus.write::<u32>(0x1010, 0x0001_0010);
+        assert_eq!(AudioAbi::detect(&task, &bus), AudioAbi::MusyxV2);
+
+        bus.write::<u32>(0x1028, 0x1E24_138C);
+        bus.write::<u32>(0x1030, 0xF000_0F00);
+        assert_eq!(
+            AudioAbi::detect(&task, &bus),
+            AudioAbi::Standard(0x1E24_138C)
+        );
+    }
+}
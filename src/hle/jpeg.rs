@@ -0,0 +1,250 @@
+use super::{OsTask, TaskHandler};
+use emu::bus::be::Bus;
+use errors::*;
+use std::f32::consts::{FRAC_1_SQRT_2, PI};
+
+const SUBBLOCK_SIZE: usize = 64;
+
+// Pointers in the task data are sometimes virtual (KSEG0) addresses.
+const RDRAM_MASK: u32 = 0x00FF_FFFF;
+
+// Position in the zigzag sequence of each coefficient of a subblock
+const ZIGZAG_TABLE: [usize; SUBBLOCK_SIZE] = [
+    0, 1, 5, 6, 14, 15, 27, 28, //
+    2, 4, 7, 13, 16, 26, 29, 42, //
+    3, 8, 12, 17, 25, 30, 41, 43, //
+    9, 11, 18, 24, 31, 40, 44, 53, //
+    10, 19, 23, 32, 39, 45, 52, 54, //
+    20, 22, 33, 38, 46, 51, 55, 60, //
+    21, 34, 37, 47, 50, 56, 59, 61, //
+    35, 36, 48, 49, 57, 58, 62, 63, //
+];
+
+/// Pixel format produced by the JPEG microcode. The version used by Pokémon
+/// Snap outputs RGBA 5551 pixels, while others output YUV pixels (UYVY),
+/// that are later converted by the CPU.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum JpegOutput {
+    Rgba5551,
+    Yuv,
+}
+
+/// JpegDecoder emulates the standard JPEG decompression microcode. The
+/// macroblocks (already Huffman-decoded by the CPU) are dequantized and
+/// transformed, and the resulting tiles are written back in place in RDRAM.
+pub struct JpegDecoder {
+    output: JpegOutput,
+    idct: [[f32; 8]; 8],
+}
+
+fn clamp_s16(x: i32) -> i16 {
+    x.max(-0x8000).min(0x7FFF) as i16
+}
+
+fn clamp_s12(x: i16) -> i16 {
+    x.max(-0x800).min(0x7F0)
+}
+
+fn clamp_u8(x: i16) -> u32 {
+    x.max(0).min(0xFF) as u32
+}
+
+// Components are computed with 4 bits of extra precision: keep the top 5 bits.
+fn clamp_rgba_component(x: f32) -> u16 {
+    (x as i16).max(0).min(0xFF0) as u16 & 0xF80
+}
+
+fn rgba(y: i16, u: i16, v: i16) -> u16 {
+    let (y, u, v) = (y as f32 + 2048.0, u as f32, v as f32);
+    let r = clamp_rgba_component(y + 1.4025 * v);
+    let g = clamp_rgba_component(y - 0.3443 * u - 0.7144 * v);
+    let b = clamp_rgba_component(y + 1.7729 * u);
+    (r << 4) | (g >> 1) | (b >> 6) | 1
+}
+
+fn uyvy(y1: i16, y2: i16, u: i16, v: i16) -> u32 {
+    clamp_u8(u) << 24 | clamp_u8(y1) << 16 | clamp_u8(v) << 8 | clamp_u8(y2)
+}
+
+impl JpegDecoder {
+    pub fn new(output: JpegOutput) -> JpegDecoder {
+        let mut idct = [[0f32; 8]; 8];
+        for (x, row) in idct.iter_mut().enumerate() {
+            for (u, c) in row.iter_mut().enumerate() {
+                let cu = if u == 0 { FRAC_1_SQRT_2 } else { 1.0 };
+                *c = cu / 2.0 * ((2 * x + 1) as f32 * u as f32 * PI / 16.0).cos();
+            }
+        }
+        JpegDecoder { output, idct }
+    }
+
+    // 2D inverse DCT, computed as 1D transforms over rows and then columns.
+    fn inverse_dct(&self, src: &[i16], dst: &mut [i16]) {
+        let mut rows = [0f32; SUBBLOCK_SIZE];
+        for i in 0..8 {
+            for x in 0..8 {
+                rows[i * 8 + x] = (0..8)
+                    .map(|u| self.idct[x][u] * src[i * 8 + u] as f32)
+                    .sum::<f32>();
+            }
+        }
+        for i in 0..8 {
+            for y in 0..8 {
+                let v = (0..8)
+                    .map(|u| self.idct[y][u] * rows[u * 8 + i])
+                    .sum::<f32>();
+                dst[y * 8 + i] = v as i16;
+            }
+        }
+    }
+
+    fn decode_macroblock(&self, mb: &mut [i16], qtables: &[[i16; SUBBLOCK_SIZE]; 3]) {
+        let count = mb.len() / SUBBLOCK_SIZE;
+        for (sb, block) in mb.chunks_mut(SUBBLOCK_SIZE).enumerate() {
+            // The last two subblocks are U and V, the others are Y
+            let chroma = count - sb <= 2;
+            let qtable = &qtables[if chroma { 3 - (count - sb) } else { 0 }];
+
+            let mut tmp = [0i16; SUBBLOCK_SIZE];
+            for (i, t) in tmp.iter_mut().enumerate() {
+                let z = ZIGZAG_TABLE[i];
+                *t = clamp_s16(block[z] as i32 * qtable[z] as i32) << 4;
+            }
+            self.inverse_dct(&tmp, block);
+
+            if self.output == JpegOutput::Yuv {
+                for v in block.iter_mut() {
+                    *v = if chroma {
+                        ((clamp_s12(*v) as i32 * 0xE00) >> 16) as i16 + 0x80
+                    } else {
+                        (((clamp_s12(*v) + 0x800) as u32 * 0xDB0) >> 16) as i16 + 0x10
+                    };
+                }
+            }
+        }
+    }
+
+    // Write a line of 16 pixels. The luma samples are taken from two
+    // horizontally adjacent subblocks; the chroma samples are shared by two
+    // pixels, and V follows U.
+    fn emit_line(&self, bus: &Bus, y: &[i16], u: &[i16], addr: u32) {
+        let (y2, v) = (&y[SUBBLOCK_SIZE..], &u[SUBBLOCK_SIZE..]);
+        for i in 0..8 {
+            let (ya, yb) = if i < 4 {
+                (y[i * 2], y[i * 2 + 1])
+            } else {
+                (y2[i * 2 - 8], y2[i * 2 - 7])
+            };
+            match self.output {
+                JpegOutput::Rgba5551 => {
+                    bus.write::<u16>(addr + i as u32 * 4, rgba(ya, u[i], v[i]));
+                    bus.write::<u16>(addr + i as u32 * 4 + 2, rgba(yb, u[i], v[i]));
+                }
+                JpegOutput::Yuv => bus.write::<u32>(addr + i as u32 * 4, uyvy(ya, yb, u[i], v[i])),
+            }
+        }
+    }
+
+    // Write the decoded macroblock as a tile: 16x8 pixels for 4:2:2 (mode 0,
+    // 2 luma subblocks), or 16x16 pixels for 4:2:0 (mode 2, 4 luma subblocks).
+    fn emit_tile(&self, bus: &Bus, mb: &[i16], mode: u32, mut addr: u32) {
+        let mut y_offset = 0;
+        let mut u_offset = (mode as usize + 2) * SUBBLOCK_SIZE;
+        for i in 0..8 {
+            if mode == 0 {
+                self.emit_line(bus, &mb[y_offset..], &mb[u_offset..], addr);
+                y_offset += 8;
+                addr += 32;
+            } else {
+                self.emit_line(bus, &mb[y_offset..], &mb[u_offset..], addr);
+                self.emit_line(bus, &mb[y_offset + 8..], &mb[u_offset..], addr + 32);
+                // After the first 8 lines, continue with the bottom subblocks
+                y_offset += if i == 3 { SUBBLOCK_SIZE + 16 } else { 16 };
+                addr += 64;
+            }
+            u_offset += 8;
+        }
+    }
+}
+
+impl TaskHandler for JpegDecoder {
+    fn name(&self) -> &'static str {
+        "jpeg"
+    }
+
+    fn run(&mut self, task: &OsTask, bus: &Bus) -> Result<()> {
+        if task.flags & 1 != 0 {
+            bail!("yielded JPEG tasks are not supported");
+        }
+
+        let read = |addr: u32| bus.read::<u32>(addr & RDRAM_MASK);
+        let mut addr = read(task.data_ptr) & RDRAM_MASK;
+        let count = read(task.data_ptr + 4);
+        let mode = read(task.data_ptr + 8);
+        if mode != 0 && mode != 2 {
+            bail!("invalid JPEG mode: {}", mode);
+        }
+
+        let mut qtables = [[0i16; SUBBLOCK_SIZE]; 3];
+        for (idx, qtable) in qtables.iter_mut().enumerate() {
+            let qaddr = read(task.data_ptr + 12 + idx as u32 * 4) & RDRAM_MASK;
+            for (i, q) in qtable.iter_mut().enumerate() {
+                *q = bus.read::<u16>(qaddr + i as u32 * 2) as i16;
+            }
+        }
+
+        let mut mb = vec![0i16; (mode as usize + 4) * SUBBLOCK_SIZE];
+        for _ in 0..count {
+            for (i, v) in mb.iter_mut().enumerate() {
+                *v = bus.read::<u16>(addr + i as u32 * 2) as i16;
+            }
+            self.decode_macroblock(&mut mb, &qtables);
+            self.emit_tile(bus, &mb, mode, addr);
+            addr += mb.len() as u32 * 2;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use emu::bus::be::Mem;
+    use slog;
+
+    #[test]
+    fn jpeg_decode_rgba() {
+        let logger = slog::Logger::root(slog::Discard, o!());
+        let mut bus = Bus::new(logger);
+        let ram = Mem::new(0x10000, Default::default());
+        bus.map_mem(0x0000_0000, 0xFFFF, &ram).unwrap();
+
+        // Task data: macroblocks at 0x1000, 1 macroblock, mode 0, and the
+        // same quantization table (all ones) for Y, U and V.
+        for (idx, v) in [0x8000_1000u32, 1, 0, 0x800, 0x800, 0x800].iter().enumerate() {
+            bus.write::<u32>(0x100 + idx as u32 * 4, *v);
+        }
+        for i in 0..SUBBLOCK_SIZE as u32 {
+            bus.write::<u16>(0x800 + i * 2, 1);
+        }
+        // Only the DC coefficient of the first Y subblock is set
+        bus.write::<u16>(0x1000, 0x120);
+
+        let task = OsTask {
+            ty: 4,
+            data_ptr: 0x100,
+            ..Default::default()
+        };
+        let mut jpeg = JpegDecoder::new(JpegOutput::Rgba5551);
+        jpeg.run(&task, &bus).unwrap();
+
+        // Left half of the tile is lighter than the (grey) right half
+        for line in 0..8 {
+            let addr = 0x1000 + line * 32;
+            assert_eq!(bus.read::<u16>(addr), 0xA529);
+            assert_eq!(bus.read::<u16>(addr + 14), 0xA529);
+            assert_eq!(bus.read::<u16>(addr + 16), 0x8421);
+            assert_eq!(bus.read::<u16>(addr + 30), 0x8421);
+        }
+    }
+}
@@ -0,0 +1,302 @@
+extern crate crc;
+
+use self::crc::crc32;
+use byteorder::{BigEndian, ByteOrder};
+use emu::bus::be::Bus;
+use emu::int::Numerics;
+use errors::*;
+use slog;
+use std::collections::{HashMap, HashSet};
+
+mod audio;
+mod jpeg;
+mod musyx;
+pub use self::audio::{AudioAbi, AudioTasks};
+pub use self::jpeg::{JpegDecoder, JpegOutput};
+
+/// Address of the OSTask structure in DMEM, where libultra stores the
+/// description of the task before kicking the RSP.
+pub const OSTASK_DMEM_ADDR: usize = 0xFC0;
+pub const OSTASK_SIZE: usize = 0x40;
+
+/// Kind of task, as specified in the OSTask structure.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum TaskType {
+    Gfx,
+    Audio,
+    Video,
+    Jpeg,
+    Unknown(u32),
+}
+
+impl TaskType {
+    pub fn from_u32(t: u32) -> TaskType {
+        match t {
+            1 => TaskType::Gfx,
+            2 => TaskType::Audio,
+            3 => TaskType::Video,
+            4 => TaskType::Jpeg,
+            t => TaskType::Unknown(t),
+        }
+    }
+}
+
+/// OSTask is the task descriptor (OSTask_t in libultra). All pointers are
+/// physical RDRAM addresses.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OsTask {
+    pub ty: u32,
+    pub flags: u32,
+    pub ucode_boot: u32,
+    pub ucode_boot_size: u32,
+    pub ucode: u32,
+    pub ucode_size: u32,
+    pub ucode_data: u32,
+    pub ucode_data_size: u32,
+    pub dram_stack: u32,
+    pub dram_stack_size: u32,
+    pub output_buff: u32,
+    pub output_buff_size: u32,
+    pub data_ptr: u32,
+    pub data_size: u32,
+    pub yield_data_ptr: u32,
+    pub yield_data_size: u32,
+}
+
+impl OsTask {
+    /// Parse the OSTask structure from the contents of DMEM.
+    pub fn parse(dmem: &[u8]) -> OsTask {
+        let buf = &dmem[OSTASK_DMEM_ADDR..OSTASK_DMEM_ADDR + OSTASK_SIZE];
+        let f = |idx: usize| BigEndian::read_u32(&buf[idx * 4..]);
+        OsTask {
+            ty: f(0),
+            flags: f(1),
+            ucode_boot: f(2),
+            ucode_boot_size: f(3),
+            ucode: f(4),
+            ucode_size: f(5),
+            ucode_data: f(6),
+            ucode_data_size: f(7),
+            dram_stack: f(8),
+            dram_stack_size: f(9),
+            output_buff: f(10),
+            output_buff_size: f(11),
+            data_ptr: f(12),
+            data_size: f(13),
+            yield_data_ptr: f(14),
+            yield_data_size: f(15),
+        }
+    }
+
+    pub fn task_type(&self) -> TaskType {
+        TaskType::from_u32(self.ty)
+    }
+
+    // The RSP is also used without the OS (eg: by test ROMs). Only consider
+    // DMEM to contain a task if it looks like a sensible one.
+    fn is_valid(&self) -> bool {
+        match self.task_type() {
+            TaskType::Unknown(_) => false,
+            _ => self.ucode != 0 && self.ucode_boot != 0,
+        }
+    }
+}
+
+/// Microcode identifies the microcode run by a task: a checksum of its text
+/// section, and the name embedded in its data section, if any.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Microcode {
+    pub hash: u32,
+    pub name: Option<String>,
+}
+
+// Most microcodes embed a signature like "RSP Gfx ucode F3DEX 1.23 ..." in
+// their data section.
+fn ucode_name(data: &[u8]) -> Option<String> {
+    let sig = b"RSP ";
+    let start = data.windows(sig.len()).position(|w| w == sig)?;
+    let name: String = data[start..]
+        .iter()
+        .take_while(|&&c| c >= 0x20 && c < 0x7F)
+        .map(|&c| c as char)
+        .collect();
+    Some(name.trim_right().to_owned())
+}
+
+// Return a slice of RDRAM, if the range is entirely mapped to memory.
+fn rdram<'a>(bus: &'a Bus, addr: u32, size: u32) -> Option<&'a [u8]> {
+    bus.fetch_read::<u8>(addr & 0x1FFF_FFFF)
+        .mem()
+        .and_then(|mem| mem.get(..size as usize))
+}
+
+impl Microcode {
+    pub fn identify(task: &OsTask, bus: &Bus) -> Microcode {
+        // The text section is loaded in IMEM, so it cannot be bigger than 4K.
+        let text_size = task.ucode_size.min(0x1000);
+        let data_size = task.ucode_data_size.min(0x1000);
+        Microcode {
+            hash: rdram(bus, task.ucode, text_size).map_or(0, crc32::checksum_ieee),
+            name: rdram(bus, task.ucode_data, data_size).and_then(ucode_name),
+        }
+    }
+}
+
+/// HleConfig selects which kinds of tasks are emulated at high level. The
+/// other tasks are run on the RSP core (LLE).
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct HleConfig {
+    pub gfx: bool,
+    pub audio: bool,
+    pub jpeg: bool,
+}
+
+impl HleConfig {
+    /// Parse a configuration: "none", "all", or a comma-separated list of
+    /// task kinds (eg: "audio,jpeg").
+    pub fn parse(s: &str) -> Result<HleConfig> {
+        let mut cfg = HleConfig::default();
+        match s {
+            "none" => {}
+            "all" => {
+                cfg.gfx = true;
+                cfg.audio = true;
+                cfg.jpeg = true;
+            }
+            s => {
+                for kind in s.split(',') {
+                    match kind {
+                        "gfx" => cfg.gfx = true,
+                        "audio" => cfg.audio = true,
+                        "jpeg" => cfg.jpeg = true,
+                        _ => bail!("invalid HLE task kind: {}", kind),
+                    }
+                }
+            }
+        }
+        Ok(cfg)
+    }
+
+    pub fn enabled(&self, ty: TaskType) -> bool {
+        match ty {
+            TaskType::Gfx => self.gfx,
+            TaskType::Audio => self.audio,
+            TaskType::Jpeg => self.jpeg,
+            _ => false,
+        }
+    }
+}
+
+/// A TaskHandler emulates the execution of a whole task, accessing RDRAM
+/// through the main bus.
+pub trait TaskHandler {
+    fn name(&self) -> &'static str;
+    fn run(&mut self, task: &OsTask, bus: &Bus) -> Result<()>;
+}
+
+/// SpTasks inspects the tasks started on the RSP, and dispatches them to
+/// the registered HLE handlers, according to the configuration.
+pub struct SpTasks {
+    config: HleConfig,
+    handlers: HashMap<TaskType, Box<TaskHandler>>,
+    seen: HashSet<u32>,           // microcodes already logged
+    fallbacks: HashSet<TaskType>, // tasks without a handler, already logged
+    failures: HashSet<String>,    // HLE errors, already logged
+    logger: slog::Logger,
+}
+
+impl SpTasks {
+    pub fn new(logger: slog::Logger) -> SpTasks {
+        SpTasks {
+            config: HleConfig::default(),
+            handlers: HashMap::new(),
+            seen: HashSet::new(),
+            fallbacks: HashSet::new(),
+            failures: HashSet::new(),
+            logger,
+        }
+    }
+
+    pub fn set_config(&mut self, config: HleConfig) {
+        self.config = config;
+    }
+
+    pub fn register(&mut self, ty: TaskType, handler: Box<TaskHandler>) {
+        self.handlers.insert(ty, handler);
+    }
+
+    /// Called when the RSP is started, with the task found in DMEM: returns
+    /// true if the task was completed through HLE, or false if the RSP core
+    /// must run it.
+    pub fn dispatch(&mut self, task: OsTask, bus: &Bus) -> bool {
+        if !task.is_valid() {
+            return false;
+        }
+        let ty = task.task_type();
+        let ucode = Microcode::identify(&task, bus);
+
+        if self.seen.insert(ucode.hash) {
+            match ucode.name {
+                Some(ref name) => {
+                    info!(self.logger, "SP task microcode"; o!("type" => format!("{:?}", ty), "hash" => ucode.hash.hex(), "name" => name.clone()))
+                }
+                None => {
+                    warn!(self.logger, "unknown SP task microcode"; o!("type" => format!("{:?}", ty), "hash" => ucode.hash.hex(), "ucode" => task.ucode.hex()))
+                }
+            }
+        }
+
+        if !self.config.enabled(ty) {
+            return false;
+        }
+        let handler = match self.handlers.get_mut(&ty) {
+            Some(handler) => handler,
+            None => {
+                if self.fallbacks.insert(ty) {
+                    warn!(self.logger, "HLE not available for task, using LLE"; o!("type" => format!("{:?}", ty)));
+                }
+                return false;
+            }
+        };
+        match handler.run(&task, bus) {
+            Ok(()) => true,
+            Err(err) => {
+                if self.failures.insert(err.to_string()) {
+                    error!(self.logger, "HLE task failed, using LLE"; o!("handler" => handler.name(), "err" => err.to_string()));
+                }
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ostask() {
+        let mut dmem = vec![0u8; 0x1000];
+        for idx in 0..16 {
+            BigEndian::write_u32(&mut dmem[OSTASK_DMEM_ADDR + idx * 4..], idx as u32 + 1);
+        }
+        let task = OsTask::parse(&dmem);
+        assert_eq!(task.task_type(), TaskType::Gfx);
+        assert_eq!(task.ucode, 5);
+        assert_eq!(task.yield_data_size, 16);
+        assert!(task.is_valid());
+
+        let data = b"\x00\x01RSP SW Version: 2.0D, 04-01-96\x00\x02";
+        assert_eq!(
+            ucode_name(data),
+            Some("RSP SW Version: 2.0D, 04-01-96".into())
+        );
+        assert_eq!(ucode_name(b"\x00\x00"), None);
+
+        let cfg = HleConfig::parse("audio,jpeg").unwrap();
+        assert!(!cfg.enabled(TaskType::Gfx));
+        assert!(cfg.enabled(TaskType::Jpeg));
+        assert_eq!(HleConfig::parse("none").unwrap(), HleConfig::default());
+        assert!(HleConfig::parse("video").is_err());
+    }
+}
@@ -0,0 +1,766 @@
+// MusyX audio microcode (Factor 5).
+//
+// Unlike Nintendo's ABIs, MusyX tasks do not run command lists: the task data
+// is an array of subframe descriptors (SFD), each describing the voices and
+// the effects to mix into a subframe of 192 stereo samples. The mixer works
+// on four internal subframes: left, right, and two auxiliary buses (CC0 and
+// E50) that feed the surround and delay effects of the following subframes.
+use super::OsTask;
+use emu::bus::be::Bus;
+use errors::*;
+
+const SUBFRAME_SIZE: usize = 192;
+const MAX_VOICES: u32 = 32;
+const SAMPLE_BUFFER_SIZE: usize = 0x200;
+
+// Pointers in the task data are sometimes virtual (KSEG0) addresses.
+const RDRAM_MASK: u32 = 0x00FF_FFFF;
+
+// Subframe descriptor
+const SFD_SFX_INDEX: u32 = 0x02;
+const SFD_VOICE_BITMASK: u32 = 0x04;
+const SFD_STATE_PTR: u32 = 0x08;
+const SFD_SFX_PTR: u32 = 0x0C;
+const SFD_VOICES: u32 = 0x10;
+const SFD2_10_PTR: u32 = 0x10;
+const SFD2_15_BITMASK: u32 = 0x15;
+const SFD2_16_BITMASK: u32 = 0x16;
+const SFD2_18_PTR: u32 = 0x18;
+const SFD2_1C_PTR: u32 = 0x1C;
+const SFD2_20_PTR: u32 = 0x20;
+const SFD2_24_PTR: u32 = 0x24;
+const SFD2_VOICES: u32 = 0x28;
+
+// Voice descriptor
+const VOICE_ENV_BEGIN: u32 = 0x00;
+const VOICE_ENV_STEP: u32 = 0x10;
+const VOICE_PITCH_Q16: u32 = 0x20;
+const VOICE_PITCH_SHIFT: u32 = 0x22;
+const VOICE_CATSRC_0: u32 = 0x24;
+const VOICE_CATSRC_1: u32 = 0x30;
+const VOICE_ADPCM_FRAMES: u32 = 0x3C;
+const VOICE_SKIP_SAMPLES: u32 = 0x3E;
+const VOICE_PCM16_COUNT: u32 = 0x40;
+const VOICE_PCM16_SEGMENT: u32 = 0x42;
+const VOICE_ADPCM_TABLE_PTR: u32 = 0x40;
+const VOICE_INTERLEAVED_PTR: u32 = 0x44;
+const VOICE_END_POINT: u32 = 0x48;
+const VOICE_RESTART_POINT: u32 = 0x4A;
+const VOICE_START_OFFSET: u32 = 0x4E;
+const VOICE_SIZE: u32 = 0x50;
+
+// Concatenation of two RDRAM buffers, the source of a voice segment
+const CATSRC_PTR1: u32 = 0x00;
+const CATSRC_PTR2: u32 = 0x04;
+const CATSRC_SIZE1: u32 = 0x08;
+const CATSRC_SIZE2: u32 = 0x0A;
+
+// Mixer state, kept in RDRAM between tasks
+const STATE_LAST_SAMPLE: u32 = 0x000;
+const STATE_BASE_VOL: u32 = 0x100;
+const STATE_CC0: u32 = 0x110;
+const STATE_LAST4_V1: u32 = 0x290;
+const STATE_LAST4_V2: u32 = 0x110;
+
+// Delay effect (SFX) descriptor
+const SFX_CBUFFER_PTR: u32 = 0x00;
+const SFX_CBUFFER_LENGTH: u32 = 0x04;
+const SFX_TAP_COUNT: u32 = 0x08;
+const SFX_FIR4_HGAIN: u32 = 0x0A;
+const SFX_TAP_DELAYS: u32 = 0x0C;
+const SFX_TAP_GAINS: u32 = 0x2C;
+const SFX_GAINS: u32 = 0x3C;
+const SFX_FIR4_HCOEFFS: u32 = 0x40;
+
+// Internal subframes
+const LEFT: usize = 0;
+const RIGHT: usize = 1;
+const CC0: usize = 2;
+const E50: usize = 3;
+
+// Coefficients of the 4-tap resampling filter, for 64 phases. The table is
+// symmetric: the second half is the first one reversed.
+const RESAMPLE_LUT_HALF: [[i16; 4]; 32] = [
+    [0x0C39, 0x66AD, 0x0D46, -0x0021],
+    [0x0B39, 0x6696, 0x0E5F, -0x0028],
+    [0x0A44, 0x6669, 0x0F83, -0x0030],
+    [0x095A, 0x6626, 0x10B4, -0x0038],
+    [0x087D, 0x65CD, 0x11F0, -0x0041],
+    [0x07AB, 0x655E, 0x1338, -0x004A],
+    [0x06E4, 0x64D9, 0x148C, -0x0054],
+    [0x0628, 0x643F, 0x15EB, -0x005F],
+    [0x0577, 0x638F, 0x1756, -0x006A],
+    [0x04D1, 0x62CB, 0x18CB, -0x0076],
+    [0x0435, 0x61F3, 0x1A4C, -0x0082],
+    [0x03A4, 0x6106, 0x1BD7, -0x008F],
+    [0x031C, 0x6007, 0x1D6C, -0x009C],
+    [0x029F, 0x5EF5, 0x1F0B, -0x00AA],
+    [0x022A, 0x5DD0, 0x20B3, -0x00B8],
+    [0x01BE, 0x5C9A, 0x2264, -0x00C6],
+    [0x015B, 0x5B53, 0x241E, -0x00D4],
+    [0x0101, 0x59FC, 0x25E0, -0x00E2],
+    [0x00AE, 0x5896, 0x27A9, -0x00F0],
+    [0x0063, 0x5720, 0x297A, -0x00FE],
+    [0x001F, 0x559D, 0x2B50, -0x010C],
+    [-0x001E, 0x540D, 0x2D2C, -0x0118],
+    [-0x0054, 0x5270, 0x2F0D, -0x0125],
+    [-0x0084, 0x50C7, 0x30F3, -0x0130],
+    [-0x00AD, 0x4F14, 0x32DC, -0x013A],
+    [-0x00D2, 0x4D57, 0x34C8, -0x0143],
+    [-0x00F1, 0x4B91, 0x36B6, -0x014A],
+    [-0x010B, 0x49C2, 0x38A5, -0x0150],
+    [-0x0121, 0x47ED, 0x3A95, -0x0154],
+    [-0x0132, 0x4611, 0x3C85, -0x0155],
+    [-0x0140, 0x4430, 0x3E74, -0x0154],
+    [-0x014A, 0x424A, 0x4060, -0x0151],
+];
+
+fn resample_coeffs(phase: usize) -> [i16; 4] {
+    if phase < 32 {
+        RESAMPLE_LUT_HALF[phase]
+    } else {
+        let c = RESAMPLE_LUT_HALF[63 - phase];
+        [c[3], c[2], c[1], c[0]]
+    }
+}
+
+fn clamp_s16(x: i32) -> i16 {
+    x.max(-0x8000).min(0x7FFF) as i16
+}
+
+// Accessors to RDRAM, through the main bus.
+struct Dram<'a>(&'a Bus);
+
+impl<'a> Dram<'a> {
+    fn u8(&self, addr: u32) -> u8 {
+        self.0.read::<u8>(addr & RDRAM_MASK)
+    }
+
+    fn u16(&self, addr: u32) -> u16 {
+        self.0.read::<u16>(addr & RDRAM_MASK)
+    }
+
+    fn i16(&self, addr: u32) -> i16 {
+        self.u16(addr) as i16
+    }
+
+    fn u32(&self, addr: u32) -> u32 {
+        self.0.read::<u32>(addr & RDRAM_MASK)
+    }
+
+    fn write_i16(&self, addr: u32, val: i16) {
+        self.0.write::<u16>(addr & RDRAM_MASK, val as u16);
+    }
+
+    fn load_i16(&self, dst: &mut [i16], addr: u32) {
+        for (i, v) in dst.iter_mut().enumerate() {
+            *v = self.i16(addr + i as u32 * 2);
+        }
+    }
+
+    fn store_i16(&self, src: &[i16], addr: u32) {
+        for (i, v) in src.iter().enumerate() {
+            self.write_i16(addr + i as u32 * 2, *v);
+        }
+    }
+
+    // Load the concatenation of the two buffers described by a CATSRC
+    // structure (sizes are in bytes).
+    fn load_cat8(&self, dst: &mut [u8], catsrc: u32) -> Result<()> {
+        let mut pos = 0;
+        for &(ptr, size) in &[(CATSRC_PTR1, CATSRC_SIZE1), (CATSRC_PTR2, CATSRC_SIZE2)] {
+            let (addr, count) = (self.u32(catsrc + ptr), self.u16(catsrc + size) as usize);
+            if pos + count > dst.len() {
+                bail!("MusyX: voice segment too big ({} bytes)", pos + count);
+            }
+            for (i, v) in dst[pos..pos + count].iter_mut().enumerate() {
+                *v = self.u8(addr + i as u32);
+            }
+            pos += count;
+        }
+        Ok(())
+    }
+
+    fn load_cat16(&self, dst: &mut [i16], catsrc: u32) -> Result<()> {
+        let mut pos = 0;
+        for &(ptr, size) in &[(CATSRC_PTR1, CATSRC_SIZE1), (CATSRC_PTR2, CATSRC_SIZE2)] {
+            let (addr, count) = (self.u32(catsrc + ptr), self.u16(catsrc + size) as usize / 2);
+            if pos + count > dst.len() {
+                bail!("MusyX: voice segment too big ({} samples)", pos + count);
+            }
+            self.load_i16(&mut dst[pos..pos + count], addr);
+            pos += count;
+        }
+        Ok(())
+    }
+}
+
+// Decoded samples of a voice: the segment being played ends at the end of
+// the buffer, and an optional second segment (the loop) is at the start.
+struct VoiceSamples {
+    buf: [i16; SAMPLE_BUFFER_SIZE],
+    segbase: usize,
+    offset: usize,
+}
+
+impl VoiceSamples {
+    fn get(&self, idx: usize) -> i16 {
+        self.buf.get(idx).cloned().unwrap_or(0)
+    }
+}
+
+fn load_samples_pcm16(dram: &Dram, voice: u32) -> Result<VoiceSamples> {
+    let skip = dram.u8(voice + VOICE_SKIP_SAMPLES) as usize;
+    let count = (dram.u16(voice + VOICE_PCM16_COUNT) as usize + skip + 3) & !3;
+    if count > SAMPLE_BUFFER_SIZE {
+        bail!("MusyX: too many PCM16 samples ({})", count);
+    }
+
+    let mut s = VoiceSamples {
+        buf: [0; SAMPLE_BUFFER_SIZE],
+        segbase: SAMPLE_BUFFER_SIZE - count,
+        offset: skip,
+    };
+    dram.load_cat16(&mut s.buf[s.segbase..], voice + VOICE_CATSRC_0)?;
+    if dram.u16(voice + VOICE_PCM16_SEGMENT) != 0 {
+        let segbase = s.segbase;
+        dram.load_cat16(&mut s.buf[..segbase], voice + VOICE_CATSRC_1)?;
+    }
+    Ok(s)
+}
+
+fn load_samples_adpcm(dram: &Dram, voice: u32) -> Result<VoiceSamples> {
+    // Compressed frames are 9 bytes for 16 samples, at most 0x400 bytes of
+    // decoded samples.
+    let mut buf = [0u8; SAMPLE_BUFFER_SIZE * 2 * 5 / 16];
+    let mut table = [0i16; 256];
+    dram.load_i16(&mut table[..128], dram.u32(voice + VOICE_ADPCM_TABLE_PTR));
+
+    let frames = [
+        dram.u8(voice + VOICE_ADPCM_FRAMES),
+        dram.u8(voice + VOICE_ADPCM_FRAMES + 1),
+    ];
+    let skip = [
+        dram.u8(voice + VOICE_SKIP_SAMPLES),
+        dram.u8(voice + VOICE_SKIP_SAMPLES + 1),
+    ];
+    let count = frames[0] as usize * 32;
+    if count > SAMPLE_BUFFER_SIZE || frames[1] as usize * 32 > SAMPLE_BUFFER_SIZE - count {
+        bail!("MusyX: too many ADPCM frames ({}+{})", frames[0], frames[1]);
+    }
+
+    let mut s = VoiceSamples {
+        buf: [0; SAMPLE_BUFFER_SIZE],
+        segbase: SAMPLE_BUFFER_SIZE - count,
+        offset: skip[0] as usize & 0x1F,
+    };
+    dram.load_cat8(&mut buf, voice + VOICE_CATSRC_0)?;
+    adpcm_decode_frames(&mut s.buf[s.segbase..], &buf, &table, frames[0], skip[0]);
+    if frames[1] != 0 {
+        dram.load_cat8(&mut buf, voice + VOICE_CATSRC_1)?;
+        adpcm_decode_frames(&mut s.buf, &buf, &table, frames[1], skip[1]);
+    }
+    Ok(s)
+}
+
+// Decode ADPCM frames of 32 samples. Each frame starts with two raw samples
+// (4 bytes), followed by 15 bytes of nibbles, after a byte selecting the
+// codebook and the scale. Headers and nibbles of pairs of frames are
+// grouped in 40 byte blocks: 8 bytes of headers, then 2x16 bytes of nibbles.
+fn adpcm_decode_frames(dst: &mut [i16], src: &[u8], table: &[i16; 256], count: u8, skip: u8) {
+    let byte = |idx: usize| src.get(idx).cloned().unwrap_or(0);
+    let (mut hdr, mut nibbles) = (0, 8);
+    let mut jump_gap = false;
+    if skip >= 32 {
+        jump_gap = true;
+        hdr += 4;
+        nibbles += 16;
+    }
+
+    for out in dst.chunks_mut(32).take(count as usize) {
+        let c = byte(nibbles);
+        let book = &table[(c & 0xF0) as usize..][..16];
+        let rshift = c & 0x0F;
+
+        let mut frame = [0i16; 32];
+        frame[0] = ((byte(hdr) as u16) << 8 | byte(hdr + 1) as u16) as i16;
+        frame[1] = ((byte(hdr + 2) as u16) << 8 | byte(hdr + 3) as u16) as i16;
+        for i in 1..16 {
+            let b = byte(nibbles + i) as u16;
+            frame[i * 2] = (((b & 0xF0) << 8) as i16) >> rshift;
+            frame[i * 2 + 1] = (((b & 0x0F) << 12) as i16) >> rshift;
+        }
+
+        out[0] = frame[0];
+        out[1] = frame[1];
+        for &(start, len) in &[(2, 6), (8, 8), (16, 8), (24, 8)] {
+            let (l1, l2) = (out[start - 2] as i64, out[start - 1] as i64);
+            let src = &frame[start..start + len];
+            for i in 0..len {
+                let mut accu = (src[i] as i64) << 11;
+                accu += book[i] as i64 * l1 + book[8 + i] as i64 * l2;
+                accu += (0..i)
+                    .map(|j| book[8 + j] as i64 * src[i - 1 - j] as i64)
+                    .sum::<i64>();
+                out[start + i] = (accu >> 11).max(-0x8000).min(0x7FFF) as i16;
+            }
+        }
+
+        if jump_gap {
+            hdr += 32;
+            nibbles += 8;
+        }
+        jump_gap = !jump_gap;
+        hdr += 4;
+        nibbles += 16;
+    }
+}
+
+// Apply a gain (Q1.15) to a subframe, and mix it into another one.
+fn mix_subframes(dst: &mut [i16], src: &[i16], gain: i16) {
+    for (d, s) in dst.iter_mut().zip(src) {
+        *d = clamp_s16(*d as i32 + ((gain as i32 * *s as i32) >> 15));
+    }
+}
+
+struct Mixer {
+    sub: [[i16; SUBFRAME_SIZE]; 4],
+    base_vol: [i32; 4],
+    // Last 4 samples of the previous delayed subframe, for the FIR filter
+    last4: [i16; 4],
+}
+
+impl Mixer {
+    fn new() -> Mixer {
+        Mixer {
+            sub: [[0; SUBFRAME_SIZE]; 4],
+            base_vol: [0; 4],
+            last4: [0; 4],
+        }
+    }
+
+    // Base volumes are 32-bit, stored as the 4 high halves followed by the
+    // 4 low halves.
+    fn load_base_vol(&mut self, dram: &Dram, addr: u32) {
+        for (k, v) in self.base_vol.iter_mut().enumerate() {
+            let k = k as u32 * 2;
+            *v = ((dram.u16(addr + k) as u32) << 16 | dram.u16(addr + 8 + k) as u32) as i32;
+        }
+    }
+
+    fn save_base_vol(&self, dram: &Dram, addr: u32) {
+        for (k, v) in self.base_vol.iter().enumerate() {
+            let k = k as u32 * 2;
+            dram.write_i16(addr + k, (*v >> 16) as i16);
+            dram.write_i16(addr + 8 + k, *v as i16);
+        }
+    }
+
+    // Base volumes follow the last samples of the active voices (and of
+    // other sources in v2), with a decay of about 3% per subframe.
+    fn update_base_vol(&mut self, dram: &Dram, voices: u32, last: u32, others: u8, ptr: u32) {
+        let sources = (0..MAX_VOICES)
+            .filter(|i| voices & (1 << i) != 0)
+            .map(|i| last + i * 8)
+            .chain(
+                (0..4)
+                    .filter(|i| others & (1 << i) != 0)
+                    .map(|i| ptr + i * 8),
+            );
+        for addr in sources {
+            for (k, v) in self.base_vol.iter_mut().enumerate() {
+                *v = v.wrapping_add(dram.i16(addr + k as u32 * 2) as i32);
+            }
+        }
+        for v in self.base_vol.iter_mut() {
+            *v = ((*v as i64 * 0xF850) >> 16) as i32;
+        }
+    }
+
+    // v1 starts from the base volumes, and from the CC0 subframe left by the
+    // previous subframe, that is mixed in opposite phase into left/right.
+    fn init_subframes_v1(&mut self) {
+        let base_cc0 = clamp_s16(self.base_vol[2]) as i32;
+        let base_e50 = clamp_s16(self.base_vol[3]);
+        for i in 0..SUBFRAME_SIZE {
+            let cc0 = self.sub[CC0][i] as i32;
+            self.sub[LEFT][i] = clamp_s16(cc0 + base_cc0);
+            self.sub[RIGHT][i] = clamp_s16(-cc0 - base_cc0);
+            self.sub[CC0][i] = 0;
+            self.sub[E50][i] = base_e50;
+        }
+    }
+
+    fn init_subframes_v2(&mut self) {
+        for (sub, v) in self.sub.iter_mut().zip(&self.base_vol) {
+            *sub = [clamp_s16(*v); SUBFRAME_SIZE];
+        }
+    }
+
+    // Mix the voices into the internal subframes, until the voice that holds
+    // the address of the output. Returns that address.
+    fn voice_stage(&mut self, dram: &Dram, mut voice: u32, last: u32) -> Result<u32> {
+        // The stage is skipped if the first voice has no samples
+        if dram.u16(voice + VOICE_CATSRC_0 + CATSRC_SIZE1) == 0 {
+            return Ok(dram.u32(voice + VOICE_INTERLEAVED_PTR));
+        }
+        for i in 0..MAX_VOICES {
+            let samples = if dram.u8(voice + VOICE_ADPCM_FRAMES) == 0 {
+                load_samples_pcm16(dram, voice)?
+            } else {
+                load_samples_adpcm(dram, voice)?
+            };
+            self.mix_voice(dram, voice, &samples, last + i * 8);
+
+            let output = dram.u32(voice + VOICE_INTERLEAVED_PTR);
+            if output != 0 {
+                return Ok(output);
+            }
+            voice += VOICE_SIZE;
+        }
+        bail!("MusyX: no output address in voices");
+    }
+
+    // Resample a voice, and mix it into each subframe with its envelope.
+    fn mix_voice(&mut self, dram: &Dram, voice: u32, s: &VoiceSamples, last: u32) {
+        let end_point = dram.u16(voice + VOICE_END_POINT) as usize;
+        let restart_point = dram.u16(voice + VOICE_RESTART_POINT) as usize;
+        let start = dram.u16(voice + VOICE_START_OFFSET) as usize;
+
+        let mut sample = s.segbase + s.offset + start;
+        let sample_end = s.segbase + end_point;
+        // Bit 15 of the restart point selects the second segment
+        let restart_base = if restart_point & 0x8000 != 0 {
+            0
+        } else {
+            s.segbase
+        };
+        let sample_restart = restart_base + (restart_point & 0x7FFF);
+
+        // Pitch is Q4.12, the position in the samples is Q16.16
+        let mut pitch_accu = dram.u16(voice + VOICE_PITCH_Q16) as u32;
+        let pitch_step = (dram.u16(voice + VOICE_PITCH_SHIFT) as u32) << 4;
+
+        let mut env = [0i32; 4];
+        let mut env_step = [0i32; 4];
+        for k in 0..4 {
+            env[k] = dram.u32(voice + VOICE_ENV_BEGIN + k as u32 * 4) as i32;
+            env_step[k] = dram.u32(voice + VOICE_ENV_STEP + k as u32 * 4) as i32;
+        }
+
+        let mut v4 = [0i16; 4];
+        for i in 0..SUBFRAME_SIZE {
+            let coeffs = resample_coeffs(((pitch_accu & 0xFC00) >> 10) as usize);
+            sample += (pitch_accu >> 16) as usize;
+            pitch_accu = (pitch_accu & 0xFFFF) + pitch_step;
+            if sample >= sample_end {
+                sample = sample_restart + (sample - sample_end);
+            }
+
+            let mut v = 0i32;
+            for (j, c) in coeffs.iter().enumerate() {
+                v = clamp_s16(v + ((s.get(sample + j) as i32 * *c as i32) >> 15)) as i32;
+            }
+
+            for k in 0..4 {
+                let accu = (v * (env[k] >> 16)) >> 15;
+                v4[k] = clamp_s16(accu);
+                self.sub[k][i] = clamp_s16(accu + self.sub[k][i] as i32);
+                env[k] = env[k].wrapping_add(env_step[k]);
+            }
+        }
+
+        // The last resampled sample feeds the base volumes
+        dram.store_i16(&v4, last);
+    }
+
+    // Delay effects: up to 8 taps are read from a circular buffer, and mixed
+    // into the main subframes. The E50 subframe, filtered by a 4-tap FIR on
+    // the delayed signal, is then written back into the circular buffer.
+    fn sfx_stage(&mut self, dram: &Dram, sfx: u32, idx: u16, v2: bool) {
+        if sfx == 0 {
+            return;
+        }
+        let cbuffer_ptr = dram.u32(sfx + SFX_CBUFFER_PTR);
+        let cbuffer_length = dram.u32(sfx + SFX_CBUFFER_LENGTH) as i64;
+        let tap_count = dram.u16(sfx + SFX_TAP_COUNT).min(8) as u32;
+        let fir4_hgain = dram.i16(sfx + SFX_FIR4_HGAIN) as i32;
+        let gains = [
+            dram.u16(sfx + SFX_GAINS) as i32,
+            dram.u16(sfx + SFX_GAINS + 2) as i32,
+        ];
+        let pos = idx as i64 * SUBFRAME_SIZE as i64;
+
+        // The delayed subframe, preceded by the last samples of the
+        // previous one.
+        let mut buf = [0i16; SUBFRAME_SIZE + 4];
+        buf[..4].copy_from_slice(&self.last4);
+        for i in 0..tap_count {
+            let mut dpos = pos - dram.u32(sfx + SFX_TAP_DELAYS + i * 4) as i64;
+            if dpos <= 0 {
+                dpos = (dpos + cbuffer_length).max(0);
+            }
+            let dlength = (cbuffer_length - dpos).max(0).min(SUBFRAME_SIZE as i64) as usize;
+
+            let mut delayed = [0i16; SUBFRAME_SIZE];
+            dram.load_i16(&mut delayed[..dlength], cbuffer_ptr + dpos as u32 * 2);
+            dram.load_i16(&mut delayed[dlength..], cbuffer_ptr);
+
+            mix_subframes(
+                &mut buf[4..],
+                &delayed,
+                dram.i16(sfx + SFX_TAP_GAINS + i * 2),
+            );
+        }
+
+        let subframe = &buf[4..];
+        for (i, v) in subframe.iter().map(|&v| v as i32).enumerate() {
+            let (main, cc0) = if v2 {
+                ((v * gains[0]) >> 16, Some((v * gains[1]) >> 16))
+            } else {
+                (v, None)
+            };
+            self.sub[LEFT][i] = clamp_s16(self.sub[LEFT][i] as i32 + main);
+            self.sub[RIGHT][i] = clamp_s16(self.sub[RIGHT][i] as i32 + main);
+            if let Some(cc0) = cc0 {
+                self.sub[CC0][i] = clamp_s16(self.sub[CC0][i] as i32 + cc0);
+            }
+        }
+
+        let mut h = [0i32; 4];
+        for (k, h) in h.iter_mut().enumerate() {
+            *h = (fir4_hgain * dram.i16(sfx + SFX_FIR4_HCOEFFS + k as u32 * 2) as i32) >> 15;
+        }
+        for i in 0..SUBFRAME_SIZE {
+            let v = (0..4).map(|k| h[k] * buf[i + 1 + k] as i32).sum::<i32>() >> 15;
+            self.sub[E50][i] = clamp_s16(self.sub[E50][i] as i32 + v);
+        }
+        self.last4.copy_from_slice(&buf[SUBFRAME_SIZE..]);
+        dram.store_i16(&self.sub[E50], cbuffer_ptr + pos as u32 * 2);
+    }
+
+    // v1 output: left/right subframes plus the base volumes, interleaved.
+    fn interleave_v1(&self, dram: &Dram, output: u32) {
+        let base_left = clamp_s16(self.base_vol[0]) as i32;
+        let base_right = clamp_s16(self.base_vol[1]) as i32;
+        for i in 0..SUBFRAME_SIZE {
+            let addr = output + i as u32 * 4;
+            dram.write_i16(addr, clamp_s16(self.sub[LEFT][i] as i32 + base_left));
+            dram.write_i16(addr + 2, clamp_s16(self.sub[RIGHT][i] as i32 + base_right));
+        }
+    }
+
+    // v2 output: a subframe (in opposite phase on the right channel), plus
+    // up to 8 other subframes with their gains (Q6.10). Their sum is also
+    // written back for the next task.
+    fn interleave_v2(&mut self, dram: &Dram, mask: u16, mut ptr: u32, sum: u32, output: u32) {
+        let mut subframe = [0i16; SUBFRAME_SIZE];
+        for i in 0..SUBFRAME_SIZE {
+            let v = dram.i16(sum + i as u32 * 2);
+            self.sub[LEFT][i] = v;
+            self.sub[RIGHT][i] = clamp_s16(-(v as i32));
+        }
+        for k in 0..8 {
+            if mask & (1 << k) != 0 {
+                let addr = dram.u32(ptr);
+                let gain = dram.i16(ptr + 4) as i32;
+                for i in 0..SUBFRAME_SIZE {
+                    let v = (dram.i16(addr + i as u32 * 2) as i32 * gain) >> 10;
+                    self.sub[LEFT][i] = clamp_s16(self.sub[LEFT][i] as i32 + v);
+                    self.sub[RIGHT][i] = clamp_s16(self.sub[RIGHT][i] as i32 + v);
+                    subframe[i] = clamp_s16(subframe[i] as i32 + v);
+                }
+            }
+            ptr += 8;
+        }
+
+        for i in 0..SUBFRAME_SIZE {
+            let addr = output + i as u32 * 4;
+            dram.write_i16(addr, self.sub[LEFT][i]);
+            dram.write_i16(addr + 2, self.sub[RIGHT][i]);
+        }
+        dram.store_i16(&subframe, sum);
+    }
+}
+
+/// Run a MusyX v1 task. The mixer state is loaded from the first SFD, and
+/// written back to the last one.
+pub fn run_v1(task: &OsTask, bus: &Bus) -> Result<()> {
+    let dram = Dram(bus);
+    let mut sfd = task.data_ptr;
+    let mut state = dram.u32(sfd + SFD_STATE_PTR);
+
+    let mut mixer = Mixer::new();
+    mixer.load_base_vol(&dram, state + STATE_BASE_VOL);
+    dram.load_i16(&mut mixer.sub[CC0], state + STATE_CC0);
+    dram.load_i16(&mut mixer.last4, state + STATE_LAST4_V1);
+
+    for n in 0..task.data_size.max(1) {
+        if n != 0 {
+            sfd += SFD_VOICES + MAX_VOICES * VOICE_SIZE;
+            state = dram.u32(sfd + SFD_STATE_PTR);
+        }
+        let voices = dram.u32(sfd + SFD_VOICE_BITMASK);
+        let last = state + STATE_LAST_SAMPLE;
+
+        mixer.update_base_vol(&dram, voices, last, 0, 0);
+        mixer.init_subframes_v1();
+        let output = mixer.voice_stage(&dram, sfd + SFD_VOICES, last)?;
+        let sfx = dram.u32(sfd + SFD_SFX_PTR);
+        mixer.sfx_stage(&dram, sfx, dram.u16(sfd + SFD_SFX_INDEX), false);
+        mixer.interleave_v1(&dram, output);
+    }
+
+    mixer.save_base_vol(&dram, state + STATE_BASE_VOL);
+    dram.store_i16(&mixer.sub[CC0], state + STATE_CC0);
+    dram.store_i16(&mixer.last4, state + STATE_LAST4_V1);
+    Ok(())
+}
+
+/// Run a MusyX v2 task. Each SFD has its own state, and writes its left,
+/// right and CC0 subframes; the interleaved output is optional.
+pub fn run_v2(task: &OsTask, bus: &Bus) -> Result<()> {
+    let dram = Dram(bus);
+    let mut sfd = task.data_ptr;
+
+    for n in 0..task.data_size.max(1) {
+        if n != 0 {
+            sfd += SFD2_VOICES + MAX_VOICES * VOICE_SIZE;
+        }
+        if dram.u32(sfd + SFD2_10_PTR) != 0 {
+            bail!("MusyX: unsupported SFD field at 0x10");
+        }
+        let voices = dram.u32(sfd + SFD_VOICE_BITMASK);
+        let state = dram.u32(sfd + SFD_STATE_PTR);
+        let last = state + STATE_LAST_SAMPLE;
+
+        let mut mixer = Mixer::new();
+        mixer.load_base_vol(&dram, state + STATE_BASE_VOL);
+        dram.load_i16(&mut mixer.last4, state + STATE_LAST4_V2);
+
+        let others = dram.u8(sfd + SFD2_15_BITMASK);
+        mixer.update_base_vol(&dram, voices, last, others, dram.u32(sfd + SFD2_24_PTR));
+        mixer.init_subframes_v2();
+        let output = mixer.voice_stage(&dram, sfd + SFD2_VOICES, last)?;
+        let sfx = dram.u32(sfd + SFD_SFX_PTR);
+        mixer.sfx_stage(&dram, sfx, dram.u16(sfd + SFD_SFX_INDEX), true);
+
+        for (k, sub) in mixer.sub[..3].iter().enumerate() {
+            dram.store_i16(sub, output + (k * SUBFRAME_SIZE * 2) as u32);
+        }
+        mixer.save_base_vol(&dram, state + STATE_BASE_VOL);
+        dram.store_i16(&mixer.last4, state + STATE_LAST4_V2);
+
+        let mask = dram.u16(sfd + SFD2_16_BITMASK);
+        if mask != 0 {
+            let (ptr, sum) = (dram.u32(sfd + SFD2_18_PTR), dram.u32(sfd + SFD2_1C_PTR));
+            mixer.interleave_v2(&dram, mask, ptr, sum, dram.u32(sfd + SFD2_20_PTR));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use emu::bus::be::Mem;
+    use slog;
+
+    #[test]
+    fn resample_lut() {
+        // Each phase has unity gain (within rounding)
+        for phase in 0..64 {
+            let sum: i32 = resample_coeffs(phase).iter().map(|&c| c as i32).sum();
+            assert!((sum - 0x8000).abs() < 0x20, "phase {}", phase);
+        }
+        assert_eq!(resample_coeffs(63), [-0x0021, 0x0D46, 0x66AD, 0x0C39]);
+    }
+
+    #[test]
+    fn adpcm_frame() {
+        // Header 0x0100/0x0200, codebook 0 (no prediction), and nibbles
+        // 1,2 (shifted right by 12) for the following samples.
+        let mut src = [0u8; 40];
+        src[..4].copy_from_slice(&[0x01, 0x00, 0x02, 0x00]);
+        src[8] = 0x0C;
+        for b in src[9..24].iter_mut() {
+            *b = 0x12;
+        }
+        let mut dst = [0i16; 32];
+        adpcm_decode_frames(&mut dst, &src, &[0; 256], 1, 0);
+        assert_eq!(dst[..4], [0x100, 0x200, 1, 2]);
+        assert_eq!(dst[30..], [1, 2]);
+
+        // With a codebook, each group of samples is predicted from the last
+        // decoded sample, and from the residuals within the group.
+        let mut table = [0i16; 256];
+        table[8] = 0x800; // 1.0 * previous sample
+        adpcm_decode_frames(&mut dst, &src, &table, 1, 0);
+        assert_eq!(dst[..5], [0x100, 0x200, 0x201, 3, 3]);
+    }
+
+    // A single PCM16 voice playing a constant signal at the original pitch,
+    // with envelopes of 1/2 on the left and 1/4 on the right.
+    fn setup_voice(bus: &Bus, voice: u32) {
+        for i in 0..196 {
+            bus.write::<u16>(0x2000 + i * 2, 0x1000);
+        }
+        let words: [(u32, u32); 6] = [
+            (VOICE_ENV_BEGIN, 0x4000_0000),
+            (VOICE_ENV_BEGIN + 4, 0x2000_0000),
+            (VOICE_PITCH_Q16, 0x0000_1000),
+            (VOICE_CATSRC_0 + CATSRC_PTR1, 0x8000_2000),
+            (VOICE_CATSRC_0 + CATSRC_SIZE1, 196 * 2 << 16),
+            (VOICE_INTERLEAVED_PTR, 0x8000_3000),
+        ];
+        for &(off, val) in &words {
+            bus.write::<u32>(voice + off, val);
+        }
+        bus.write::<u16>(voice + VOICE_PCM16_COUNT, 196);
+        bus.write::<u16>(voice + VOICE_END_POINT, 196);
+    }
+
+    #[test]
+    fn musyx_voice() {
+        let logger = slog::Logger::root(slog::Discard, o!());
+        let mut bus = Bus::new(logger);
+        let ram = Mem::new(0x10000, Default::default());
+        bus.map_mem(0x0000_0000, 0xFFFF, &ram).unwrap();
+        let task = OsTask {
+            ty: 2,
+            data_ptr: 0x1000,
+            data_size: 1,
+            ..Default::default()
+        };
+
+        // v1: interleaved output
+        bus.write::<u32>(0x1000 + SFD_VOICE_BITMASK, 1);
+        bus.write::<u32>(0x1000 + SFD_STATE_PTR, 0x8000_4000);
+        setup_voice(&bus, 0x1000 + SFD_VOICES);
+        run_v1(&task, &bus).unwrap();
+        for i in &[0, 191] {
+            assert_eq!(bus.read::<u16>(0x3000 + i * 4), 2047);
+            assert_eq!(bus.read::<u16>(0x3000 + i * 4 + 2), 1023);
+        }
+        assert_eq!(bus.read::<u32>(0x4000), 2047 << 16 | 1023);
+
+        // Base volumes follow the last samples of the previous subframe
+        run_v1(&task, &bus).unwrap();
+        // (with a decay of 3%).
+        assert_eq!(bus.read::<u16>(0x4100), 0);
+        assert_eq!(bus.read::<u16>(0x4108), 1985);
+        assert_eq!(bus.read::<u16>(0x3000), 2047 + 1985);
+        assert_eq!(bus.read::<u16>(0x3002), 1023 + 992);
+
+        // v2: separate left, right and CC0 subframes
+        for i in 0..0x1000 {
+            bus.write::<u32>(0x1000 + i * 4, 0);
+        }
+        bus.write::<u32>(0x1000 + SFD_STATE_PTR, 0x8000_4000);
+        setup_voice(&bus, 0x1000 + SFD2_VOICES);
+        run_v2(&task, &bus).unwrap();
+        assert_eq!(bus.read::<u16>(0x3000 + 191 * 2), 2047);
+        assert_eq!(bus.read::<u16>(0x3000 + 384 + 191 * 2), 1023);
+        assert_eq!(bus.read::<u16>(0x3000 + 768), 0);
+    }
+}
@@ -0,0 +1,107 @@
+use super::cartridge::SaveType;
+use super::errors::*;
+use serde_json;
+
+/// MachineInfo describes the emulator build and the configuration of the
+/// emulated machine. It is meant to be attached to bug reports, so that the
+/// exact setup can be reproduced.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MachineInfo {
+    pub version: String,
+    pub build: String,
+    pub features: Vec<String>,
+    pub renderer: String,
+    pub resolution_scale: usize,
+    pub counter_factor: u32,
+    // CPU clock frequency, in Hz
+    pub cpu_clock: i64,
+    pub lenient: bool,
+    pub game_code: String,
+    pub cic: Option<u32>,
+    pub save_type: SaveType,
+}
+
+impl MachineInfo {
+    pub fn version() -> &'static str {
+        env!("CARGO_PKG_VERSION")
+    }
+
+    // Build features that affect emulation.
+    pub fn features() -> Vec<String> {
+        let mut features = vec![];
+        if cfg!(target_feature = "sse4.1") {
+            features.push("sse4.1".into());
+        }
+        if cfg!(target_feature = "avx2") {
+            features.push("avx2".into());
+        }
+        if cfg!(feature = "jit") {
+            features.push("jit".into());
+        }
+        if cfg!(debug_assertions) {
+            features.push("debug-assertions".into());
+        }
+        features
+    }
+
+    pub fn build() -> &'static str {
+        if cfg!(debug_assertions) {
+            "debug"
+        } else {
+            "release"
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// One-line summary, suitable for logs and status bars.
+    pub fn summary(&self) -> String {
+        format!(
+            "r64emu {} ({}) - {} renderer x{}{} - {} CIC-{} save:{:?}",
+            self.version,
+            self.build,
+            self.renderer,
+            self.resolution_scale,
+            if self.lenient { " lenient" } else { "" },
+            self.game_code,
+            self.cic.map_or("unknown".into(), |c| c.to_string()),
+            self.save_type,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn machine_info() {
+        let info = MachineInfo {
+            version: MachineInfo::version().into(),
+            build: "release".into(),
+            features: vec![],
+            renderer: "software".into(),
+            resolution_scale: 2,
+            counter_factor: 1,
+            cpu_clock: 93_744_000,
+            lenient: true,
+            game_code: "NSME".into(),
+            cic: Some(6102),
+            save_type: SaveType::from_game_code("NSME"),
+        };
+        assert_eq!(
+            info.summary(),
+            format!(
+                "r64emu {} (release) - software renderer x2 lenient - NSME CIC-6102 save:Eeprom4k",
+                MachineInfo::version()
+            )
+        );
+
+        let json = info.to_json().unwrap();
+        assert!(json.contains(r#""save_type": "eeprom4k""#));
+        assert_eq!(serde_json::from_str::<MachineInfo>(&json).unwrap(), info);
+        assert_eq!(SaveType::from_game_code("NXXE"), SaveType::Unknown);
+    }
+}
@@ -0,0 +1,215 @@
+use std::cell::{Ref, RefCell};
+use std::fmt;
+use std::rc::Rc;
+
+/// Interrupt sources handled by the MI (MIPS Interface).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Interrupt {
+    Sp = 0,
+    Si = 1,
+    Ai = 2,
+    Vi = 3,
+    Pi = 4,
+    Dp = 5,
+}
+
+const NUM_INTERRUPTS: usize = 6;
+
+impl Interrupt {
+    pub const ALL: [Interrupt; NUM_INTERRUPTS] = [
+        Interrupt::Sp,
+        Interrupt::Si,
+        Interrupt::Ai,
+        Interrupt::Vi,
+        Interrupt::Pi,
+        Interrupt::Dp,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Interrupt::Sp => "SP",
+            Interrupt::Si => "SI",
+            Interrupt::Ai => "AI",
+            Interrupt::Vi => "VI",
+            Interrupt::Pi => "PI",
+            Interrupt::Dp => "DP",
+        }
+    }
+}
+
+/// Interrupts raised during a single frame.
+#[derive(Clone, Debug, Default)]
+pub struct FrameInterrupts {
+    counts: [u32; NUM_INTERRUPTS],
+    // Each interrupt, in order, with the line at which it was raised
+    lines: Vec<(usize, Interrupt)>,
+}
+
+impl FrameInterrupts {
+    pub fn count(&self, int: Interrupt) -> u32 {
+        self.counts[int as usize]
+    }
+
+    pub fn lines(&self) -> &[(usize, Interrupt)] {
+        &self.lines
+    }
+
+    /// Number of times each interrupt was raised at the specified line.
+    pub fn line_counts(&self, line: usize) -> [u32; NUM_INTERRUPTS] {
+        let mut counts = [0; NUM_INTERRUPTS];
+        for &(_, int) in self.lines.iter().filter(|&&(l, _)| l == line) {
+            counts[int as usize] += 1;
+        }
+        counts
+    }
+}
+
+impl fmt::Display for FrameInterrupts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (idx, int) in Interrupt::ALL.iter().enumerate() {
+            if idx != 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{}:{}", int.name(), self.count(*int))?;
+        }
+        Ok(())
+    }
+}
+
+// Number of interrupts of the same kind within a frame above which we
+// consider the game to be stuck in an interrupt storm. Even the busiest
+// sources (SP tasks, PI DMAs) are raised a few dozen times per frame.
+const STORM_THRESHOLD: u32 = 1000;
+
+/// InterruptStats keeps track of the interrupts raised by the devices, frame
+/// by frame. It helps diagnosing games stuck in interrupt storms, or waiting
+/// for interrupts that are never raised. It also records which interrupts
+/// are pending, until the game acknowledges them to the device that raised
+/// them (as reported by MI_INTR).
+#[derive(Debug, Default)]
+pub struct InterruptStats {
+    line: usize,
+    pending: u8,
+    frames: u64,
+    current: FrameInterrupts,
+    last: FrameInterrupts,
+    totals: [u64; NUM_INTERRUPTS],
+}
+
+impl InterruptStats {
+    pub fn raise(&mut self, int: Interrupt) {
+        self.pending |= 1 << int as usize;
+        self.current.counts[int as usize] += 1;
+        self.current.lines.push((self.line, int));
+        self.totals[int as usize] += 1;
+    }
+
+    pub fn ack(&mut self, int: Interrupt) {
+        self.pending &= !(1 << int as usize);
+    }
+
+    /// Whether the interrupt was raised, and not acknowledged yet.
+    pub fn pending(&self, int: Interrupt) -> bool {
+        self.pending & (1 << int as usize) != 0
+    }
+
+    pub fn set_line(&mut self, line: usize) {
+        self.line = line;
+    }
+
+    pub fn end_frame(&mut self) {
+        self.last = ::std::mem::replace(&mut self.current, FrameInterrupts::default());
+        self.frames += 1;
+    }
+
+    /// Interrupts raised during the last completed frame.
+    pub fn last_frame(&self) -> &FrameInterrupts {
+        &self.last
+    }
+
+    pub fn total(&self, int: Interrupt) -> u64 {
+        self.totals[int as usize]
+    }
+
+    /// Average number of interrupts per frame, since the beginning.
+    pub fn rate(&self, int: Interrupt) -> f64 {
+        match self.frames {
+            0 => 0.0,
+            n => self.total(int) as f64 / n as f64,
+        }
+    }
+
+    /// Interrupts that were raised abnormally often in the last frame.
+    pub fn storms(&self) -> Vec<Interrupt> {
+        Interrupt::ALL
+            .iter()
+            .cloned()
+            .filter(|&int| self.last.count(int) > STORM_THRESHOLD)
+            .collect()
+    }
+}
+
+/// InterruptLog is a shared handle to the interrupt statistics, held by all
+/// the devices that raise interrupts.
+#[derive(Clone, Debug, Default)]
+pub struct InterruptLog(Rc<RefCell<InterruptStats>>);
+
+impl InterruptLog {
+    pub fn new() -> InterruptLog {
+        InterruptLog::default()
+    }
+
+    pub fn raise(&self, int: Interrupt) {
+        self.0.borrow_mut().raise(int);
+    }
+
+    pub fn ack(&self, int: Interrupt) {
+        self.0.borrow_mut().ack(int);
+    }
+
+    pub fn set_line(&self, line: usize) {
+        self.0.borrow_mut().set_line(line);
+    }
+
+    pub fn end_frame(&self) {
+        self.0.borrow_mut().end_frame();
+    }
+
+    pub fn stats(&self) -> Ref<InterruptStats> {
+        self.0.borrow()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interrupt_stats() {
+        let log = InterruptLog::new();
+        let dev = log.clone();
+
+        for frame in 0..2 {
+            log.set_line(10);
+            dev.raise(Interrupt::Pi);
+            dev.raise(Interrupt::Pi);
+            log.set_line(512);
+            dev.raise(Interrupt::Vi);
+            if frame == 1 {
+                dev.raise(Interrupt::Sp);
+            }
+            log.end_frame();
+        }
+
+        let stats = log.stats();
+        let last = stats.last_frame();
+        assert_eq!(last.count(Interrupt::Pi), 2);
+        assert_eq!(last.count(Interrupt::Si), 0);
+        assert_eq!(last.line_counts(10), [0, 0, 0, 0, 2, 0]);
+        assert_eq!(last.lines()[2], (512, Interrupt::Vi));
+        assert_eq!(last.to_string(), "SP:1 SI:0 AI:0 VI:1 PI:2 DP:0");
+        assert_eq!(stats.total(Interrupt::Pi), 4);
+        assert_eq!(stats.rate(Interrupt::Sp), 0.5);
+        assert!(stats.storms().is_empty());
+    }
+}
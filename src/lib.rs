@@ -8,6 +8,7 @@ extern crate slog;
 extern crate emu_derive;
 extern crate byteorder;
 extern crate emu;
+pub extern crate mips64;
 
 extern crate packed_simd;
 
@@ -17,27 +18,66 @@ extern crate bitflags;
 #[macro_use]
 extern crate error_chain;
 
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+
 pub mod errors {
     error_chain!{
         foreign_links {
             Io(::std::io::Error) #[cfg(unix)];
+            Json(::serde_json::Error);
+            Hw(::emu::hw::Error);
+        }
+
+        errors {
+            RomLoad(path: String) {
+                description("cannot load ROM")
+                display("cannot load ROM: {}", path)
+            }
+            BusMapping(err: String) {
+                description("invalid bus mapping")
+                display("invalid bus mapping: {}", err)
+            }
+            Frontend(what: &'static str, err: ::emu::hw::Error) {
+                description("cannot initialize frontend")
+                display("cannot initialize {}: {}", what, err)
+            }
         }
     }
+
+    // Wrap the errors returned by the bus when mapping devices and memories.
+    pub fn bus_error(err: &str) -> ErrorKind {
+        ErrorKind::BusMapping(err.into())
+    }
 }
 
 mod rdp;
 mod vops;
 
 pub mod ai;
+pub mod boottrace;
 pub mod cartridge;
 pub mod dp;
-pub mod mips64;
+pub mod hle;
+pub mod info;
+pub mod interrupts;
+pub mod logfile;
+pub mod mempak;
+pub mod memview;
+pub mod monitor;
 pub mod pi;
+pub mod report;
 pub mod ri;
+pub mod save;
+pub mod settings;
 pub mod si;
 pub mod sp;
 pub mod spvector;
 pub mod vi;
+pub mod watchdog;
 
 mod n64;
 pub use n64::N64;
+pub use rdp::{RdpCapture, TexturePack};
@@ -0,0 +1,156 @@
+use errors::*;
+use slog;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Configuration of the log file: where the log goes, the minimum level of
+/// the messages that are written, and how the file is rotated.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogConfig {
+    pub path: PathBuf,
+    pub level: slog::Level,
+    // Maximum size of the log file in bytes, before it is rotated
+    pub max_size: u64,
+    // Number of rotated files that are kept (file.1 ... file.N)
+    pub keep: usize,
+}
+
+impl LogConfig {
+    /// Parse a log file configuration: the file name, optionally followed by
+    /// a comma-separated list of options (level=<level>, max-size=<MB>,
+    /// keep=<n>).
+    pub fn parse(s: &str) -> Result<LogConfig> {
+        let mut parts = s.split(',');
+        let path = parts.next().unwrap();
+        if path.is_empty() {
+            bail!("missing log file name");
+        }
+        let mut cfg = LogConfig {
+            path: PathBuf::from(path),
+            level: slog::Level::Info,
+            max_size: 16 << 20,
+            keep: 4,
+        };
+        for opt in parts {
+            let mut kv = opt.splitn(2, '=');
+            let (key, val) = (kv.next().unwrap(), kv.next().unwrap_or(""));
+            match key {
+                "level" => {
+                    cfg.level = match val.parse::<slog::Level>() {
+                        Ok(level) => level,
+                        Err(_) => bail!("invalid log level: {}", val),
+                    }
+                }
+                "max-size" => match val.parse::<u64>() {
+                    Ok(mb) if mb > 0 => cfg.max_size = mb << 20,
+                    _ => bail!("invalid log file size: {}", val),
+                },
+                "keep" => cfg.keep = val.parse().chain_err(|| "invalid number of log files")?,
+                _ => bail!("unknown log option: {}", opt),
+            }
+        }
+        Ok(cfg)
+    }
+}
+
+/// RotatingFile is a log file that is rotated when it grows over a maximum
+/// size: the current file is renamed to file.1 (shifting the older ones up
+/// to file.N, and discarding the oldest), and a new file is started. This
+/// bounds the disk space used by long sessions, while keeping the latest
+/// messages.
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    keep: usize,
+}
+
+impl RotatingFile {
+    pub fn new(path: &Path, max_size: u64, keep: usize) -> io::Result<RotatingFile> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            file,
+            size,
+            max_size,
+            keep,
+        })
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                if self.rotated(n).exists() {
+                    fs::rename(self.rotated(n), self.rotated(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+        self.file = File::create(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    // Messages are written one at a time by the log decorator, so rotating
+    // before a write never splits a message across files.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let n = self.file.write(buf)?;
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn rotate() {
+        let path = env::temp_dir().join("r64emu_rotate_test.log");
+        let _ = fs::remove_file(&path);
+        let mut log = RotatingFile::new(&path, 10, 2).unwrap();
+        for _ in 0..4 {
+            log.write_all(b"123456\n").unwrap();
+        }
+        log.flush().unwrap();
+
+        let read = |p: &Path| fs::read_to_string(p).unwrap();
+        assert_eq!(read(&path), "123456\n");
+        assert_eq!(read(&log.rotated(1)), "123456\n");
+        assert_eq!(read(&log.rotated(2)), "123456\n");
+        assert!(!log.rotated(3).exists());
+        for n in 1..3 {
+            fs::remove_file(log.rotated(n)).unwrap();
+        }
+        fs::remove_file(&path).unwrap();
+
+        let cfg = LogConfig::parse("r64.log,level=debug,max-size=2").unwrap();
+        assert_eq!(cfg.level, slog::Level::Debug);
+        assert_eq!(cfg.max_size, 2 << 20);
+        assert_eq!(cfg.keep, 4);
+        assert!(LogConfig::parse("r64.log,level=loud").is_err());
+        assert!(LogConfig::parse(",keep=1").is_err());
+    }
+}
@@ -9,11 +9,29 @@ extern crate slog_term;
 extern crate emu;
 extern crate r64emu;
 
+use emu::gfx::{OwnedGfxBufferLE, Rgb888};
 use emu::hw;
+use emu::hw::OutputProducer;
+use emu::sync;
+use r64emu::cartridge;
+use r64emu::cartridge::SaveType;
 use r64emu::errors::*;
-use r64emu::N64;
+use r64emu::hle::HleConfig;
+use r64emu::logfile::{LogConfig, RotatingFile};
+use r64emu::mempak::Mempak;
+use r64emu::mips64;
+use r64emu::monitor::{MachineConfig, Monitor};
+use r64emu::report::{BatchConfig, CompatReport};
+use r64emu::save::SaveFormat;
+use r64emu::settings::{GameSettings, GameSettingsDb};
+use r64emu::{RdpCapture, TexturePack, N64};
 use slog::Drain;
 use std::env;
+use std::fs;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 fn module_and_line(record: &slog::Record) -> String {
     format!("{}:{}", record.module(), record.line())
@@ -34,34 +52,745 @@ fn log_build_async() -> slog::Logger {
     slog::Logger::root(drain, o!("module" => slog::FnValue(module_and_line)))
 }
 
+// Log into a file, rotated when it grows too large, filtering the messages
+// below the configured level.
+fn log_build_file(cfg: &LogConfig) -> Result<slog::Logger> {
+    let file = RotatingFile::new(&cfg.path, cfg.max_size, cfg.keep)
+        .chain_err(|| "cannot open log file")?;
+    let decorator = slog_term::PlainSyncDecorator::new(file);
+    let drain = slog_term::FullFormat::new(decorator).build();
+    let drain = drain.filter_level(cfg.level).fuse();
+    Ok(slog::Logger::root(
+        drain,
+        o!("module" => slog::FnValue(module_and_line)),
+    ))
+}
+
+const USAGE: &str = "Usage: r64emu [options] <rom>
+       r64emu --batch [--report=<frames>] [--jobs=<n>] [--timeout=<secs>] [--watchdog=<n>] <romdir>
+       r64emu --mempak=list|export:<n>:<file>|import:<file>|delete:<n> <pakfile>
+       r64emu --convert-save=<from>:<to> <rom> <infile> <outfile>
+       r64emu --rdp-replay=<png> <capture>
+       r64emu disasm <rom> --range <start>..<end> [--symbols <file>] [--output <file>]
+
+Options:
+    --lenient                       skip unimplemented opcodes
+    --info                          print the emulator and machine info as JSON
+    --dump-memmap=text|json         print the memory map of the main bus
+    --report=<frames>               run headless and print a JSON report
+    --audit=record|verify:<file>    run headless for the --report frames (default: 60),
+                                    recording the schedule of all events into <file>,
+                                    or verifying that it matches the one in <file>
+    --monitor=<opt>;...             run headless for the --report frames (default: 60),
+                                    in lockstep with a second machine with different
+                                    options (hle=<kind>,..., [no-]yuv-framebuffer),
+                                    and report the first frame that differs
+    --log-file=<file>[,<opt>...]    write the log into a file rather than the terminal,
+                                    with options: level=<level> (default: info),
+                                    max-size=<MB> (default: 16), rotating the file when
+                                    it grows larger, and keep=<n> (default: 4) old files
+    --video=sdl|null|png:<dir>      video output
+    --audio=sdl|null|wav:<file>|cpal
+                                    audio output (cpal in builds with the cpal feature)
+    --input=live|movie:<file>|tcp:<addr>
+                                    controllers input
+    --record=<file>                 record controllers input as a movie
+    --hotkeys=<hotkey>=<key>,...    rebind hotkeys (quit, savestate, loadstate,
+                                    screenshot, pause, fastforward, fullscreen, reset,
+                                    speedup, speeddown)
+    --pad-profiles=<file>           load game controller profiles (one per line:
+                                    guid,name,a:a,b:x,...,x:leftx,y:lefty)
+    --passthrough=<dev>[,<ports>]   use real controllers (and paks) through a
+                                    raphnet N64-to-USB adapter (eg: /dev/hidraw0)
+                                    for the first <ports> ports (default: 1)
+    --pak=<file>                    insert a controller pak image into the first
+                                    controller (saved back on exit); images dropped
+                                    on the window are swapped in while running
+    --input-display[=<opt>,...]     draw the controllers as read by the game on the
+                                    screen, with options: top-left, top-right,
+                                    bottom-left (default), bottom-right, scale=<n>
+    --dump-textures=<dir>           dump the textures used for drawing as PNG files
+    --texture-pack=<dir>            load replacement textures from a directory
+    --game-settings=<file>          load per-game settings (JSON, keyed by game code)
+    --widescreen[=<w>:<h>]          stretch the output to a wider aspect (default: 16:9)
+    --resolution-scale=<n>          render at <n> times the native resolution (1, 2, 4)
+    --counter-factor=<n>            CPU cycles taken by each instruction (1, 2, 3), to
+                                    fix the speed of some games
+    --cpu-clock=<MHz>               run the CPU at the specified frequency, rather than
+                                    93.75 MHz (overclocking makes some games smoother)
+    --cpu-timing=<profile>          cycles taken by each instruction: simple (one cycle
+                                    each, the default) or accurate (approximate VR4300
+                                    timing, slower but closer to the real hardware)
+    --block-cache                   run the CPU with the cached engine, which decodes
+                                    basic blocks once (faster on hot loops)
+    --jit                           compile hot CPU code to native code (in builds with
+                                    the jit feature; otherwise, same as --block-cache)
+    --idle-skip                     skip the loops in which the CPU waits for an event
+                                    or interrupt, saving host CPU time
+    --yuv-framebuffer               display 16-bit framebuffers as YUV (FMV games)
+    --no-dither                     disable the dithering of 16-bit color
+    --boot-trace                    log the boot milestones (CIC, IPL3, entry point, first
+                                    VI/SI interrupts, first AI DMA) with their timestamps,
+                                    and report the missing ones on exit
+    --watchdog=<n>                  stop the emulation when the game makes no progress
+                                    (no framebuffer change, no interrupt) for <n>
+                                    million CPU cycles, dumping the machine state
+    --opcode-stats=<file>           count the executed opcodes, and write a report
+                                    (including unimplemented ones) on exit
+    --rdp-capture=<frame>:<file>    capture the RDP commands of a frame, with their
+                                    inputs (RDP state, TMEM, RDRAM), into a file
+    --rdp-replay=<png>              rasterize a RDP capture without the rest of the
+                                    machine, and save the color image
+    --frame-skip=off|auto[:<max>]|<n>/<m>
+                                    skip frames automatically when too slow (at most
+                                    <max> in a row), or always <n> frames out of <m>
+    --present=fixed|swap            present every frame, or only when the game swaps
+                                    framebuffers (smoother for variable frame rates)
+    --limit-speed                   do not run faster than real time
+    --sync=video|audio              pace emulation with the video timer (default), or
+                                    with the audio output, which avoids crackling sound
+                                    when the display refresh rate does not match
+    --speed=<n>%|unlimited          run at a percentage of the real speed (25%-400%),
+                                    adjustable with the speedup/speeddown hotkeys
+    --threads=<hint>,...            scheduling hints: raise the emulation thread
+                                    priority (high-priority), pin the emulation or
+                                    UI thread to a core (emu-core=<n>, ui-core=<n>)
+    --hle=none|all|<kind>,...       emulate RSP tasks at high level (gfx, audio, jpeg)
+                                    rather than running their microcode (default: none)
+    --convert-save=<from>:<to>      convert a save file of the game between formats
+                                    (native, pj64, mupen)
+
+Commands:
+    disasm                          disassemble a range of ROM offsets (the boot code
+                                    at 0x1000 is loaded at the entry point), labelled
+                                    with the symbols of a file in nm format, into the
+                                    output file or stdout";
+
 quick_main!(run);
 
+// Run the emulation for the specified number of frames, and either record
+// the exact schedule of events, or verify it against a recorded one.
+fn run_audit(romfn: &str, frames: usize, mode: &str) -> Result<()> {
+    let mut n64 = N64::new(slog::Logger::root(slog::Discard, o!()), romfn)?;
+    n64.setup_cic()?;
+    n64.set_lenient(true);
+
+    let (verify, path) = match mode {
+        m if m.starts_with("record:") => (false, Path::new(&m["record:".len()..])),
+        m if m.starts_with("verify:") => (true, Path::new(&m["verify:".len()..])),
+        _ => bail!("invalid audit mode: {}", mode),
+    };
+    if verify {
+        let file = File::open(path).chain_err(|| "cannot open schedule trace")?;
+        n64.verify_schedule(sync::EventTrace::read(BufReader::new(file))?);
+    } else {
+        n64.record_schedule();
+    }
+
+    let mut screen = OwnedGfxBufferLE::<Rgb888>::new(640, 480);
+    for _ in 0..frames {
+        n64.render_frame(&mut screen.buf_mut());
+    }
+
+    if verify {
+        match n64.schedule_divergence() {
+            Some(div) => bail!("schedule diverged: {}", div),
+            None => println!("schedule verified: {} frames", frames),
+        }
+    } else {
+        let trace = n64.take_schedule().unwrap();
+        trace.write(BufWriter::new(File::create(path)?))?;
+        println!("schedule recorded: {} entries", trace.entries.len());
+    }
+    Ok(())
+}
+
+// Run the emulation on two machine configurations in lockstep, and report
+// the first frame where their outputs differ.
+fn run_monitor(romfn: &str, frames: usize, configs: [MachineConfig; 2]) -> Result<()> {
+    let logger = slog::Logger::root(slog::Discard, o!());
+    let mut monitor = Monitor::new(logger, romfn, configs)?;
+    match monitor.run(frames) {
+        Some(div) => bail!("output diverged at {}", div),
+        None => println!("output matched: {} frames", monitor.frames_run()),
+    }
+    Ok(())
+}
+
+// Manage the notes stored in a controller pak image.
+fn run_mempak(pakfn: &str, cmd: &str) -> Result<()> {
+    let data = fs::read(pakfn).chain_err(|| "cannot open controller pak")?;
+    let mut pak = Mempak::new(data)?;
+    let mut parts = cmd.splitn(2, ':');
+    let (op, arg) = (parts.next().unwrap(), parts.next().unwrap_or(""));
+    let note_index = |s: &str| s.parse::<usize>().chain_err(|| "invalid note number");
+    match op {
+        "list" => {
+            for note in pak.notes()? {
+                println!("{}", note);
+            }
+            println!("{} free pages", pak.free_pages());
+            return Ok(());
+        }
+        "export" => {
+            let mut parts = arg.splitn(2, ':');
+            let note = pak.export(note_index(parts.next().unwrap())?)?;
+            match parts.next() {
+                Some(path) => fs::write(path, note)?,
+                None => bail!("missing note file name"),
+            }
+            return Ok(());
+        }
+        "import" => {
+            let note = fs::read(arg).chain_err(|| "cannot open note file")?;
+            println!("imported as note {}", pak.import(&note)?);
+        }
+        "delete" => pak.delete(note_index(arg)?)?,
+        _ => bail!("invalid controller pak command: {}", cmd),
+    }
+    fs::write(pakfn, pak.data())?;
+    Ok(())
+}
+
+// Convert a save file between the formats used by different emulators. The
+// kind of save memory is detected from the game.
+fn run_convert_save(romfn: &str, infn: &str, outfn: &str, formats: &str) -> Result<()> {
+    let mut parts = formats.splitn(2, ':');
+    let from = SaveFormat::parse(parts.next().unwrap())?;
+    let to = SaveFormat::parse(parts.next().unwrap_or(""))?;
+
+    let game_code = cartridge::read_game_code(romfn).chain_err(|| "cannot open rom file")?;
+    let ty = SaveType::from_game_code(&game_code);
+    if ty == SaveType::Unknown {
+        bail!("unknown save type for game {}", game_code);
+    }
+
+    let data = fs::read(infn).chain_err(|| "cannot open save file")?;
+    let save = to.export(ty, &from.import(ty, &data)?)?;
+    fs::write(outfn, save)?;
+    println!("converted {:?} save: {} bytes", ty, data.len());
+    Ok(())
+}
+
+// Rasterize a RDP capture, and save the resulting color image.
+fn run_rdp_replay(capfn: &str, pngfn: &str) -> Result<()> {
+    let file = File::open(capfn).chain_err(|| "cannot open RDP capture")?;
+    let cap = RdpCapture::load(&mut BufReader::new(file))?;
+    let image = cap.replay(slog::Logger::root(slog::Discard, o!()))?;
+    let (width, height) = (image.width(), image.height());
+    hw::save_png(Path::new(pngfn), &image.buf(), width, height)?;
+    println!("replayed {} RDP commands: {}x{}", cap.len(), width, height);
+    Ok(())
+}
+
+// Disassemble a range of the ROM, with labels for branch targets and
+// symbols. The range is in ROM offsets, where the code at 0x1000 is loaded
+// at the entry point found in the header. Options follow the command, and
+// take their value as the next argument.
+fn run_disasm(args: &[String]) -> Result<()> {
+    let mut romfn = None;
+    let mut range = None;
+    let mut symfn = None;
+    let mut outfn = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--range" => range = args.next(),
+            "--symbols" => symfn = args.next(),
+            "--output" => outfn = args.next(),
+            a if a.starts_with("--") => bail!("unknown disasm option: {}", a),
+            _ if romfn.is_none() => romfn = Some(arg),
+            _ => bail!(USAGE),
+        }
+    }
+    let (romfn, range) = match (romfn, range) {
+        (Some(romfn), Some(range)) => (romfn, range),
+        _ => bail!(USAGE),
+    };
+
+    let parse = |s: &str| u32::from_str_radix(s.trim_left_matches("0x"), 16);
+    let mut parts = range.splitn(2, "..");
+    let start = parse(parts.next().unwrap()).chain_err(|| "invalid disassembly start")?;
+    let end = parse(parts.next().unwrap_or("")).chain_err(|| "invalid disassembly end")?;
+
+    let rom = cartridge::read_rom(romfn).chain_err(|| "cannot open rom file")?;
+    if start < 0x1000 || start >= end || end as usize > rom.len() {
+        bail!("invalid disassembly range: {}", range);
+    }
+    let symbols = match symfn {
+        Some(path) => mips64::parse_symbols(
+            &fs::read_to_string(path).chain_err(|| "cannot open symbols file")?,
+        ),
+        None => Default::default(),
+    };
+    let entry =
+        (rom[8] as u32) << 24 | (rom[9] as u32) << 16 | (rom[10] as u32) << 8 | rom[11] as u32;
+    let base = entry.wrapping_add(start - 0x1000);
+    let listing = mips64::disasm_listing(&rom[start as usize..end as usize], base, &symbols);
+    match outfn {
+        Some(path) => fs::write(path, listing)?,
+        None => print!("{}", listing),
+    }
+    Ok(())
+}
+
 fn run() -> Result<()> {
-    let logger = log_build_sync();
+    let args: Vec<String> = env::args().collect();
+
+    // Commands, with their own options
+    match args.get(1).map(|a| a.as_str()) {
+        Some("disasm") => return run_disasm(&args[2..]),
+        _ => {}
+    }
+
+    let (flags, args): (Vec<String>, Vec<String>) =
+        args.into_iter().skip(1).partition(|a| a.starts_with("--"));
+
+    let mut lenient = false;
+    let mut info = false;
+    let mut audit = None;
+    let mut monitor = None;
+    let mut mempak = None;
+    let mut convert_save = None;
+    let mut dump_memmap = None;
+    let mut report_frames = None;
+    let mut batch = false;
+    let mut jobs = 4;
+    let mut timeout = 60;
+    let mut log_file = None;
+    let mut video = String::from("sdl");
+    let mut audio = String::from("sdl");
+    let mut input = String::from("live");
+    let mut record = None;
+    let mut hotkeys = hw::HotkeyTable::default();
+    let mut pad_profiles = hw::PadProfiles::default();
+    let mut passthrough = None;
+    let mut pak = None;
+    let mut input_display = None;
+    let mut texpack = TexturePack::new();
+    let mut settings_db = GameSettingsDb::new();
+    let mut widescreen = None;
+    let mut hle = HleConfig::default();
+    let mut yuv_framebuffer = false;
+    let mut no_dither = false;
+    let mut opcode_stats = None;
+    let mut rdp_capture = None;
+    let mut rdp_replay = None;
+    let mut boot_trace = false;
+    let mut watchdog = None;
+    let mut resolution_scale = None;
+    let mut counter_factor = None;
+    let mut cpu_timing = None;
+    let mut block_cache = false;
+    let mut jit = false;
+    let mut idle_skip = false;
+    let mut cpu_clock = None;
+    let mut frame_skip = hw::FrameSkip::Off;
+    let mut present = hw::PresentMode::Fixed;
+    let mut sync_mode = hw::SyncMode::Video;
+    let mut limit_speed = false;
+    let mut speed = hw::Speed::default();
+    let mut threads = hw::ThreadHints::default();
+    for flag in flags {
+        match flag.as_str() {
+            "--lenient" => lenient = true,
+            "--block-cache" => block_cache = true,
+            "--jit" => jit = true,
+            "--idle-skip" => idle_skip = true,
+            "--info" => info = true,
+            "--batch" => batch = true,
+            f if f.starts_with("--report=") => {
+                report_frames = Some(
+                    f["--report=".len()..]
+                        .parse::<usize>()
+                        .chain_err(|| "invalid number of frames")?,
+                )
+            }
+            f if f.starts_with("--dump-memmap=") => {
+                dump_memmap = Some(f["--dump-memmap=".len()..].to_string())
+            }
+            f if f.starts_with("--audit=") => audit = Some(f["--audit=".len()..].to_string()),
+            f if f.starts_with("--monitor=") => monitor = Some(f["--monitor=".len()..].to_string()),
+            f if f.starts_with("--mempak=") => mempak = Some(f["--mempak=".len()..].to_string()),
+            f if f.starts_with("--convert-save=") => {
+                convert_save = Some(f["--convert-save=".len()..].to_string())
+            }
+            f if f.starts_with("--log-file=") => {
+                log_file = Some(LogConfig::parse(&f["--log-file=".len()..])?)
+            }
+            f if f.starts_with("--video=") => video = f["--video=".len()..].into(),
+            f if f.starts_with("--audio=") => audio = f["--audio=".len()..].into(),
+            f if f.starts_with("--input=") => input = f["--input=".len()..].into(),
+            f if f.starts_with("--record=") => record = Some(f["--record=".len()..].to_string()),
+            f if f.starts_with("--hotkeys=") => hotkeys.parse(&f["--hotkeys=".len()..])?,
+            f if f.starts_with("--pad-profiles=") => {
+                pad_profiles.load(Path::new(&f["--pad-profiles=".len()..]))?
+            }
+            f if f.starts_with("--passthrough=") => {
+                let mut parts = f["--passthrough=".len()..].splitn(2, ',');
+                let dev = parts.next().unwrap().to_string();
+                let ports = match parts.next() {
+                    Some(n) => n.parse::<usize>().chain_err(|| "invalid number of ports")?,
+                    None => 1,
+                };
+                if ports == 0 || ports > hw::MAX_PADS {
+                    bail!("invalid number of passthrough ports: {}", ports);
+                }
+                passthrough = Some((dev, ports));
+            }
+            f if f.starts_with("--pak=") => pak = Some(PathBuf::from(&f["--pak=".len()..])),
+            "--input-display" => input_display = Some(hw::InputDisplay::default()),
+            f if f.starts_with("--input-display=") => {
+                input_display = Some(hw::InputDisplay::parse(&f["--input-display=".len()..])?)
+            }
+            f if f.starts_with("--dump-textures=") => {
+                texpack = texpack.dump_to(PathBuf::from(&f["--dump-textures=".len()..]))
+            }
+            f if f.starts_with("--texture-pack=") => {
+                texpack = texpack.replace_from(PathBuf::from(&f["--texture-pack=".len()..]))
+            }
+            f if f.starts_with("--game-settings=") => {
+                settings_db = GameSettingsDb::load(Path::new(&f["--game-settings=".len()..]))?
+            }
+            "--widescreen" => widescreen = Some((16, 9)),
+            "--yuv-framebuffer" => yuv_framebuffer = true,
+            "--no-dither" => no_dither = true,
+            "--boot-trace" => boot_trace = true,
+            f if f.starts_with("--watchdog=") => {
+                let mcycles = f["--watchdog=".len()..]
+                    .parse::<i64>()
+                    .chain_err(|| "invalid watchdog cycles")?;
+                watchdog = Some(mcycles * 1_000_000);
+            }
+            f if f.starts_with("--opcode-stats=") => {
+                opcode_stats = Some(PathBuf::from(&f["--opcode-stats=".len()..]))
+            }
+            f if f.starts_with("--rdp-capture=") => {
+                let mut parts = f["--rdp-capture=".len()..].splitn(2, ':');
+                let frame = parts
+                    .next()
+                    .unwrap()
+                    .parse::<u64>()
+                    .chain_err(|| "invalid capture frame")?;
+                let path = match parts.next() {
+                    Some(path) => PathBuf::from(path),
+                    None => bail!("missing RDP capture file"),
+                };
+                rdp_capture = Some((frame, path));
+            }
+            f if f.starts_with("--rdp-replay=") => {
+                rdp_replay = Some(f["--rdp-replay=".len()..].to_string())
+            }
+            f if f.starts_with("--widescreen=") => {
+                widescreen = Some(GameSettings::parse_aspect(&f["--widescreen=".len()..])?)
+            }
+            f if f.starts_with("--resolution-scale=") => {
+                resolution_scale = Some(GameSettings::parse_resolution_scale(
+                    &f["--resolution-scale=".len()..],
+                )?)
+            }
+            f if f.starts_with("--counter-factor=") => {
+                counter_factor = Some(GameSettings::parse_counter_factor(
+                    &f["--counter-factor=".len()..],
+                )?)
+            }
+            f if f.starts_with("--cpu-clock=") => {
+                let mhz = f["--cpu-clock=".len()..]
+                    .parse::<f64>()
+                    .chain_err(|| "invalid CPU clock")?;
+                if !(mhz > 0.0) {
+                    bail!("invalid CPU clock: {}", mhz);
+                }
+                cpu_clock = Some((mhz * 1_000_000.0) as i64);
+            }
+            f if f.starts_with("--cpu-timing=") => {
+                cpu_timing = Some(GameSettings::parse_cpu_timing(&f["--cpu-timing=".len()..])?)
+            }
+            f if f.starts_with("--hle=") => hle = HleConfig::parse(&f["--hle=".len()..])?,
+            f if f.starts_with("--frame-skip=") => {
+                frame_skip = hw::FrameSkip::parse(&f["--frame-skip=".len()..])?
+            }
+            f if f.starts_with("--present=") => {
+                present = hw::PresentMode::parse(&f["--present=".len()..])?
+            }
+            "--limit-speed" => limit_speed = true,
+            f if f.starts_with("--sync=") => {
+                sync_mode = hw::SyncMode::parse(&f["--sync=".len()..])?;
+                // Audio is only played when the speed is limited
+                limit_speed |= sync_mode == hw::SyncMode::Audio;
+            }
+            f if f.starts_with("--speed=") => {
+                speed = hw::Speed::parse(&f["--speed=".len()..])?;
+                limit_speed = true;
+            }
+            f if f.starts_with("--threads=") => {
+                threads = hw::ThreadHints::parse(&f["--threads=".len()..])?
+            }
+            f if f.starts_with("--jobs=") => {
+                jobs = f["--jobs=".len()..]
+                    .parse::<usize>()
+                    .chain_err(|| "invalid number of jobs")?
+            }
+            f if f.starts_with("--timeout=") => {
+                timeout = f["--timeout=".len()..]
+                    .parse::<u64>()
+                    .chain_err(|| "invalid timeout")?
+            }
+            _ => bail!("unknown option: {}", flag),
+        }
+    }
+
+    if args.len() < 1 {
+        bail!(USAGE);
+    }
+
+    // Controller pak management, then exit
+    if let Some(cmd) = mempak {
+        return run_mempak(&args[0], &cmd);
+    }
+
+    // Save file conversion, then exit
+    if let Some(formats) = convert_save {
+        if args.len() < 3 {
+            bail!(USAGE);
+        }
+        return run_convert_save(&args[0], &args[1], &args[2], &formats);
+    }
+
+    // RDP capture replay, then exit
+    if let Some(png) = rdp_replay {
+        return run_rdp_replay(&args[0], &png);
+    }
+
+    // Batch run: run the compatibility report over a whole directory of ROMs
+    if batch {
+        let reports = CompatReport::run_batch(
+            Path::new(&args[0]),
+            &BatchConfig {
+                frames: report_frames.unwrap_or(300),
+                jobs,
+                timeout: Duration::from_secs(timeout),
+                watchdog,
+            },
+        )?;
+        print!("{}", CompatReport::summary_table(&reports));
+        return Ok(());
+    }
+
+    // Memory map of the main bus, then exit
+    if let Some(format) = dump_memmap {
+        let n64 = N64::new(slog::Logger::root(slog::Discard, o!()), &args[0])?;
+        let map = n64.memory_map();
+        match format.as_str() {
+            "text" => print!("{}", map.to_text()),
+            "json" => print!("{}", map.to_json()),
+            _ => bail!("unknown memory map format: {}", format),
+        }
+        return Ok(());
+    }
+
+    // Headless run: determinism audit of the scheduler
+    if let Some(audit) = audit {
+        return run_audit(&args[0], report_frames.unwrap_or(60), &audit);
+    }
+
+    // Headless run: compare two machine configurations
+    if let Some(monitor) = monitor {
+        let base = MachineConfig {
+            hle,
+            yuv_framebuffer,
+        };
+        let other = MachineConfig::parse(base, &monitor)?;
+        return run_monitor(&args[0], report_frames.unwrap_or(60), [base, other]);
+    }
+
+    // Headless run: emit a JSON compatibility report on stdout
+    if let Some(frames) = report_frames {
+        let logger = slog::Logger::root(slog::Discard, o!());
+        let report = CompatReport::run(logger, &args[0], frames, watchdog)?;
+        println!("{}", report.to_json()?);
+        return Ok(());
+    }
+
+    let logger = match log_file {
+        Some(cfg) => log_build_file(&cfg)?,
+        None => log_build_sync(),
+    };
     crit!(logger, "Hello World!");
 
-    let args: Vec<String> = env::args().collect();
+    // Per-game settings; options on the command line take precedence
+    let game_code = cartridge::read_game_code(&args[0]).chain_err(|| "cannot open rom file")?;
+    let mut settings = settings_db.get(&game_code);
+    if widescreen.is_some() {
+        settings.widescreen = widescreen;
+    }
+    if resolution_scale.is_some() {
+        settings.resolution_scale = resolution_scale;
+    }
+    if counter_factor.is_some() {
+        settings.counter_factor = counter_factor;
+    }
+    if cpu_timing.is_some() {
+        settings.cpu_timing = cpu_timing;
+    }
+    if yuv_framebuffer {
+        settings.yuv_framebuffer = true;
+    }
+    if no_dither {
+        settings.no_dither = true;
+    }
+    let (width, height) = settings.screen_size(settings.screen_height());
+    let resolution_scale = settings.resolution_scale();
+    let counter_factor = settings.counter_factor();
+    let cpu_timing = settings.cpu_timing();
+    let yuv_framebuffer = settings.yuv_framebuffer;
+    let dither = !settings.no_dither;
 
-    if args.len() < 2 {
-        bail!("Usage: r64emu [rom]");
+    // Machine info (useful in bug reports), then exit
+    if info {
+        let mut n64 = N64::new(slog::Logger::root(slog::Discard, o!()), &args[0])?;
+        n64.set_lenient(lenient);
+        n64.set_resolution_scale(resolution_scale);
+        n64.set_counter_factor(counter_factor);
+        if let Some(freq) = cpu_clock {
+            n64.set_cpu_clock(freq);
+        }
+        println!("{}", n64.info().to_json()?);
+        return Ok(());
     }
 
     let mut out = hw::Output::new(hw::OutputConfig {
         window_title: "R64EMU - Nintendo 64 Emulator".into(),
-        width: 640,
-        height: 480,
+        width: width as isize,
+        height: height as isize,
         fps: 60,
-        enforce_speed: false,
+        enforce_speed: limit_speed,
+        speed,
+        frame_skip,
+        present,
+        sync: sync_mode,
+        hotkeys,
+        pad_profiles,
+        threads,
     })?;
-    out.enable_video()?;
+    match video.as_str() {
+        "sdl" => out
+            .enable_video()
+            .map_err(|e| ErrorKind::Frontend("video", e))?,
+        "null" => out.set_video(Box::new(hw::NullVideo)),
+        v if v.starts_with("png:") => {
+            let dir = PathBuf::from(&v["png:".len()..]);
+            out.set_video(Box::new(hw::PngVideo::new(out.config(), dir)))
+        }
+        _ => bail!("unknown video backend: {}", video),
+    }
+    match audio.as_str() {
+        "sdl" => out
+            .enable_audio()
+            .map_err(|e| ErrorKind::Frontend("audio", e))?,
+        "null" => out.set_audio(Box::new(hw::NullAudio)),
+        a if a.starts_with("wav:") => {
+            let wav = hw::WavAudio::new(Path::new(&a["wav:".len()..]))
+                .map_err(|e| ErrorKind::Frontend("audio", e))?;
+            out.set_audio(Box::new(wav))
+        }
+        #[cfg(feature = "cpal")]
+        "cpal" => {
+            let cpal = hw::CpalAudio::new().map_err(|e| ErrorKind::Frontend("audio", e))?;
+            out.set_audio(Box::new(cpal))
+        }
+        #[cfg(not(feature = "cpal"))]
+        "cpal" => bail!("cpal audio requires a build with the cpal feature"),
+        _ => bail!("unknown audio backend: {}", audio),
+    }
+
+    let source: Box<hw::InputSource> = match input.as_str() {
+        "live" => Box::new(out.live_input()),
+        i if i.starts_with("movie:") => Box::new(
+            hw::MoviePlayer::new(Path::new(&i["movie:".len()..]))
+                .chain_err(|| "cannot open movie file")?,
+        ),
+        i if i.starts_with("tcp:") => {
+            Box::new(hw::TcpInput::new(&i["tcp:".len()..]).chain_err(|| "cannot listen for input")?)
+        }
+        _ => bail!("unknown input source: {}", input),
+    };
+    let source: Box<hw::InputSource> = match record {
+        Some(fn_) => Box::new(
+            hw::MovieRecorder::new(source, Path::new(&fn_))
+                .chain_err(|| "cannot create movie file")?,
+        ),
+        None => source,
+    };
+
+    let passthrough: Option<Box<hw::JoybusDevice>> = match passthrough {
+        Some((dev, ports)) => Some(Box::new(
+            hw::RaphnetAdapter::open(Path::new(&dev), ports)
+                .chain_err(|| "cannot open passthrough adapter")?,
+        )),
+        None => None,
+    };
+
+    // Ctrl-C shuts down the emulator as if the window was closed, so that
+    // the machine and the output backends can flush their files.
+    hw::install_signal_handlers();
 
     let logger1 = logger.clone();
-    let romfn = args[1].clone();
+    let logger2 = logger.clone();
+    let romfn = args[0].clone();
+    // The producer is created in the emulation thread: its errors are sent
+    // back as a single line, including all their causes.
     out.run(move || {
-        let n64 = Box::new(N64::new(logger1, &romfn).unwrap());
-        n64.setup_cic().unwrap();
+        let setup = || -> Result<Box<N64>> {
+            let mut n64 = Box::new(N64::new(logger1, &romfn)?);
+            n64.setup_cic()?;
+            if let Some(ref pak) = pak {
+                n64.insert_pak(0, pak)?;
+            }
+            Ok(n64)
+        };
+        let mut n64 = setup().map_err(|e| {
+            let causes: Vec<String> = e.iter().map(|c| c.to_string()).collect();
+            hw::Error::Producer(causes.join(": "))
+        })?;
+        n64.set_lenient(lenient);
+        n64.set_texture_pack(texpack);
+        n64.set_resolution_scale(resolution_scale);
+        n64.set_counter_factor(counter_factor);
+        if let Some(freq) = cpu_clock {
+            n64.set_cpu_clock(freq);
+        }
+        n64.set_cpu_timing(cpu_timing);
+        n64.set_block_cache(block_cache);
+        if jit {
+            n64.set_jit(true);
+        }
+        n64.set_idle_skip(idle_skip);
+        n64.set_hle(hle);
+        n64.set_yuv_framebuffer(yuv_framebuffer);
+        n64.set_dither(dither);
+        if let Some(report) = opcode_stats {
+            n64.set_opcode_stats(report);
+        }
+        if let Some((frame, path)) = rdp_capture {
+            n64.set_rdp_capture(frame, path);
+        }
+        if boot_trace {
+            n64.set_boot_trace();
+        }
+        if let Some(cycles) = watchdog {
+            n64.set_watchdog(cycles);
+        }
+        info!(logger2, "machine info"; o!("info" => n64.info().summary()));
+        n64.set_input(source);
+        if let Some(dev) = passthrough {
+            n64.set_passthrough(dev);
+        }
+        if let Some(disp) = input_display {
+            n64.set_input_display(disp);
+        }
         Ok(n64)
-    });
+    })?;
 
     Ok(())
 }
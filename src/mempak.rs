@@ -0,0 +1,326 @@
+use super::errors::*;
+use std::fmt;
+
+/// Size of a controller pak image: 128 pages of 256 bytes.
+pub const MEMPAK_SIZE: usize = 0x8000;
+const PAGE_SIZE: usize = 0x100;
+const NUM_PAGES: usize = MEMPAK_SIZE / PAGE_SIZE;
+
+// Filesystem layout: ID area, index table (and its backup copy), note table.
+// Pages from FIRST_DATA_PAGE onwards hold the notes data.
+const INDEX_PAGE: usize = 1;
+const INDEX_BACKUP_PAGE: usize = 2;
+const NOTE_TABLE_OFFSET: usize = 3 * PAGE_SIZE;
+const FIRST_DATA_PAGE: u8 = 5;
+
+/// Number of entries in the note table.
+pub const MAX_NOTES: usize = 16;
+const NOTE_ENTRY_SIZE: usize = 32;
+
+// Special values in the index table
+const INDEX_END: u8 = 0x01;
+const INDEX_FREE: u8 = 0x03;
+
+// N64 font, as used in note names: digits, letters, then some punctuation.
+const FONT_PUNCT: &[u8] = b"!\"#'*+,-./:=?@";
+
+fn decode_char(c: u8) -> char {
+    match c {
+        0x0F => ' ',
+        0x10...0x19 => (b'0' + c - 0x10) as char,
+        0x1A...0x33 => (b'A' + c - 0x1A) as char,
+        0x34...0x41 => FONT_PUNCT[(c - 0x34) as usize] as char,
+        _ => '~',
+    }
+}
+
+fn decode_name(name: &[u8]) -> String {
+    name.iter()
+        .take_while(|&&c| c != 0)
+        .map(|&c| decode_char(c))
+        .collect::<String>()
+        .trim_right()
+        .to_owned()
+}
+
+/// A note (save file of a game) stored in the controller pak.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Note {
+    /// Position in the note table
+    pub index: usize,
+    pub game_code: [u8; 4],
+    pub publisher: [u8; 2],
+    pub name: String,
+    pub extension: String,
+    /// Data pages, in order
+    pub pages: Vec<u8>,
+}
+
+impl fmt::Display for Note {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let code: String = self
+            .game_code
+            .iter()
+            .chain(self.publisher.iter())
+            .map(|&c| {
+                if c >= 0x20 && c < 0x7F {
+                    c as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        write!(f, "{:2} {} {}", self.index, code, self.name)?;
+        if !self.extension.is_empty() {
+            write!(f, ".{}", self.extension)?;
+        }
+        write!(f, " ({} pages)", self.pages.len())
+    }
+}
+
+/// Mempak is a controller pak image, giving access to the notes stored in its
+/// filesystem.
+pub struct Mempak {
+    data: Vec<u8>,
+}
+
+impl Mempak {
+    /// Parse a controller pak image, validating its index table. If the
+    /// main copy of the table is corrupted, the backup copy is used.
+    pub fn new(data: Vec<u8>) -> Result<Mempak> {
+        if data.len() != MEMPAK_SIZE {
+            bail!("invalid controller pak size: {} bytes", data.len());
+        }
+        let mut pak = Mempak { data };
+        if !pak.index_valid(INDEX_PAGE) {
+            if !pak.index_valid(INDEX_BACKUP_PAGE) {
+                bail!("corrupted controller pak index table");
+            }
+            let (main, backup) = pak.data.split_at_mut(INDEX_BACKUP_PAGE * PAGE_SIZE);
+            main[INDEX_PAGE * PAGE_SIZE..].copy_from_slice(&backup[..PAGE_SIZE]);
+        }
+        Ok(pak)
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    // Each index table entry is 2 bytes: bank (always 0) and page. The
+    // checksum covers the entries of the data pages.
+    fn index_checksum(&self, page: usize) -> u8 {
+        let table = &self.data[page * PAGE_SIZE..(page + 1) * PAGE_SIZE];
+        table[FIRST_DATA_PAGE as usize * 2..]
+            .iter()
+            .fold(0u8, |sum, &b| sum.wrapping_add(b))
+    }
+
+    fn index_valid(&self, page: usize) -> bool {
+        self.data[page * PAGE_SIZE + 1] == self.index_checksum(page)
+    }
+
+    fn next_page(&self, page: u8) -> u8 {
+        self.data[INDEX_PAGE * PAGE_SIZE + page as usize * 2 + 1]
+    }
+
+    fn set_next_page(&mut self, page: u8, next: u8) {
+        let off = INDEX_PAGE * PAGE_SIZE + page as usize * 2;
+        self.data[off] = 0;
+        self.data[off + 1] = next;
+    }
+
+    // Update the checksum, and the backup copy of the index table.
+    fn commit_index(&mut self) {
+        let sum = self.index_checksum(INDEX_PAGE);
+        self.data[INDEX_PAGE * PAGE_SIZE + 1] = sum;
+        let (main, backup) = self.data.split_at_mut(INDEX_BACKUP_PAGE * PAGE_SIZE);
+        backup[..PAGE_SIZE].copy_from_slice(&main[INDEX_PAGE * PAGE_SIZE..]);
+    }
+
+    fn entry(&self, index: usize) -> &[u8] {
+        let off = NOTE_TABLE_OFFSET + index * NOTE_ENTRY_SIZE;
+        &self.data[off..off + NOTE_ENTRY_SIZE]
+    }
+
+    fn entry_used(entry: &[u8]) -> bool {
+        entry[0..4] != [0; 4] && entry[7] >= FIRST_DATA_PAGE
+    }
+
+    // Follow the chain of pages of a note, starting from the specified page.
+    fn chain(&self, start: u8) -> Result<Vec<u8>> {
+        let mut pages = vec![];
+        let mut page = start;
+        loop {
+            if page < FIRST_DATA_PAGE || page as usize >= NUM_PAGES || pages.contains(&page) {
+                bail!("invalid page chain at page {}", page);
+            }
+            pages.push(page);
+            match self.next_page(page) {
+                INDEX_END => return Ok(pages),
+                next => page = next,
+            }
+        }
+    }
+
+    /// Return the notes stored in the pak.
+    pub fn notes(&self) -> Result<Vec<Note>> {
+        let mut notes = vec![];
+        for index in 0..MAX_NOTES {
+            let entry = self.entry(index);
+            if !Mempak::entry_used(entry) {
+                continue;
+            }
+            let mut game_code = [0u8; 4];
+            let mut publisher = [0u8; 2];
+            game_code.copy_from_slice(&entry[0..4]);
+            publisher.copy_from_slice(&entry[4..6]);
+            notes.push(Note {
+                index,
+                game_code,
+                publisher,
+                name: decode_name(&entry[0x10..0x20]),
+                extension: decode_name(&entry[0x0C..0x10]),
+                pages: self
+                    .chain(entry[7])
+                    .chain_err(|| format!("invalid note {}", index))?,
+            });
+        }
+        Ok(notes)
+    }
+
+    pub fn free_pages(&self) -> usize {
+        (FIRST_DATA_PAGE..NUM_PAGES as u8)
+            .filter(|&p| self.next_page(p) == INDEX_FREE)
+            .count()
+    }
+
+    fn note(&self, index: usize) -> Result<Note> {
+        match self.notes()?.into_iter().find(|n| n.index == index) {
+            Some(note) => Ok(note),
+            None => bail!("note {} not found", index),
+        }
+    }
+
+    /// Export a note as a standalone file: its note table entry, followed by
+    /// the contents of its pages.
+    pub fn export(&self, index: usize) -> Result<Vec<u8>> {
+        let note = self.note(index)?;
+        let mut out = self.entry(index).to_vec();
+        for &page in &note.pages {
+            let off = page as usize * PAGE_SIZE;
+            out.extend_from_slice(&self.data[off..off + PAGE_SIZE]);
+        }
+        Ok(out)
+    }
+
+    /// Import a note previously exported, returning its position in the
+    /// note table.
+    pub fn import(&mut self, note: &[u8]) -> Result<usize> {
+        if note.len() <= NOTE_ENTRY_SIZE || (note.len() - NOTE_ENTRY_SIZE) % PAGE_SIZE != 0 {
+            bail!("invalid note file size: {} bytes", note.len());
+        }
+        let (entry, data) = note.split_at(NOTE_ENTRY_SIZE);
+        let npages = data.len() / PAGE_SIZE;
+
+        let index = match (0..MAX_NOTES).find(|&i| !Mempak::entry_used(self.entry(i))) {
+            Some(index) => index,
+            None => bail!("controller pak note table is full"),
+        };
+        let free: Vec<u8> = (FIRST_DATA_PAGE..NUM_PAGES as u8)
+            .filter(|&p| self.next_page(p) == INDEX_FREE)
+            .take(npages)
+            .collect();
+        if free.len() < npages {
+            bail!(
+                "not enough free pages in controller pak: {} needed, {} available",
+                npages,
+                free.len()
+            );
+        }
+
+        for (i, &page) in free.iter().enumerate() {
+            let off = page as usize * PAGE_SIZE;
+            self.data[off..off + PAGE_SIZE]
+                .copy_from_slice(&data[i * PAGE_SIZE..(i + 1) * PAGE_SIZE]);
+            let next = free.get(i + 1).cloned().unwrap_or(INDEX_END);
+            self.set_next_page(page, next);
+        }
+        self.commit_index();
+
+        let off = NOTE_TABLE_OFFSET + index * NOTE_ENTRY_SIZE;
+        self.data[off..off + NOTE_ENTRY_SIZE].copy_from_slice(entry);
+        self.data[off + 6] = 0;
+        self.data[off + 7] = free[0];
+        Ok(index)
+    }
+
+    /// Delete a note, freeing its pages.
+    pub fn delete(&mut self, index: usize) -> Result<()> {
+        let note = self.note(index)?;
+        for &page in &note.pages {
+            self.set_next_page(page, INDEX_FREE);
+        }
+        self.commit_index();
+
+        let off = NOTE_TABLE_OFFSET + index * NOTE_ENTRY_SIZE;
+        for b in &mut self.data[off..off + NOTE_ENTRY_SIZE] {
+            *b = 0;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An empty filesystem: all data pages free, no notes.
+    fn blank() -> Vec<u8> {
+        let mut data = vec![0u8; MEMPAK_SIZE];
+        for page in FIRST_DATA_PAGE as usize..NUM_PAGES {
+            data[PAGE_SIZE + page * 2 + 1] = INDEX_FREE;
+        }
+        let mut pak = Mempak { data };
+        pak.commit_index();
+        pak.data
+    }
+
+    #[test]
+    fn mempak_notes() {
+        let mut pak = Mempak::new(blank()).unwrap();
+        assert_eq!(pak.free_pages(), 123);
+        assert!(pak.notes().unwrap().is_empty());
+
+        // "NSME01" "MARIO64" with two pages of data
+        let mut note = vec![0u8; NOTE_ENTRY_SIZE + 2 * PAGE_SIZE];
+        note[0..6].copy_from_slice(b"NSME01");
+        note[0x10..0x17].copy_from_slice(&[0x26, 0x1A, 0x2B, 0x22, 0x28, 0x16, 0x14]);
+        note[NOTE_ENTRY_SIZE + PAGE_SIZE] = 0xAA;
+        assert_eq!(pak.import(&note).unwrap(), 0);
+        assert_eq!(pak.import(&note).unwrap(), 1);
+        pak.delete(0).unwrap();
+
+        let mut pak = Mempak::new(pak.data().to_vec()).unwrap();
+        let notes = pak.notes().unwrap();
+        assert_eq!(notes.len(), 1);
+        assert_eq!(notes[0].to_string(), " 1 NSME01 MARIO64 (2 pages)");
+        assert_eq!(notes[0].pages, vec![7, 8]);
+        assert_eq!(pak.free_pages(), 121);
+        assert_eq!(
+            pak.export(1).unwrap()[NOTE_ENTRY_SIZE..],
+            note[NOTE_ENTRY_SIZE..]
+        );
+        assert!(pak.export(0).is_err());
+
+        // Reuses the first free entry and pages
+        assert_eq!(pak.import(&note).unwrap(), 0);
+        assert_eq!(pak.notes().unwrap()[0].pages, vec![5, 6]);
+
+        // The backup copy of the index table is used if the main one is
+        // corrupted.
+        let mut data = pak.data().to_vec();
+        data[PAGE_SIZE + 20] ^= 0xFF;
+        let pak = Mempak::new(data).unwrap();
+        assert_eq!(pak.free_pages(), 119);
+    }
+}
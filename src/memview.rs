@@ -0,0 +1,173 @@
+use super::errors::*;
+use super::N64;
+use std::fmt::Write;
+
+// Bytes shown on each line of the hex view.
+const LINE_SIZE: usize = 16;
+
+/// Memory areas that can be inspected and edited while a game is running.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MemRegion {
+    Rdram,
+    Dmem,
+    Imem,
+    Tmem,
+}
+
+impl MemRegion {
+    pub fn parse(s: &str) -> Result<MemRegion> {
+        match s {
+            "rdram" | "ram" => Ok(MemRegion::Rdram),
+            "dmem" => Ok(MemRegion::Dmem),
+            "imem" => Ok(MemRegion::Imem),
+            "tmem" => Ok(MemRegion::Tmem),
+            _ => bail!("unknown memory region: {}", s),
+        }
+    }
+
+    /// Size of the region, in bytes.
+    pub fn size(&self) -> usize {
+        match *self {
+            MemRegion::Rdram => 0x40_0000,
+            MemRegion::Dmem | MemRegion::Imem | MemRegion::Tmem => 0x1000,
+        }
+    }
+}
+
+/// MemView is a hex view over a window of a memory region. Each refresh
+/// records which bytes changed since the previous one, so that a frontend
+/// can highlight them.
+pub struct MemView {
+    region: MemRegion,
+    addr: u32,
+    lines: usize,
+    data: Vec<u8>,
+    changed: Vec<bool>,
+}
+
+impl MemView {
+    pub fn new(region: MemRegion, lines: usize) -> MemView {
+        MemView {
+            region,
+            addr: 0,
+            lines,
+            data: Vec::new(),
+            changed: Vec::new(),
+        }
+    }
+
+    pub fn region(&self) -> MemRegion {
+        self.region
+    }
+
+    /// First address shown by the view.
+    pub fn addr(&self) -> u32 {
+        self.addr
+    }
+
+    // Number of bytes shown by the view, clamped to the end of the region.
+    fn len(&self) -> usize {
+        (self.lines * LINE_SIZE).min(self.region.size() - self.addr as usize)
+    }
+
+    /// Move the view so that it starts at the line containing the specified
+    /// address. Nothing is highlighted on the next refresh.
+    pub fn goto(&mut self, addr: u32) -> Result<()> {
+        if addr as usize >= self.region.size() {
+            bail!("address out of {:?}: {:x}", self.region, addr);
+        }
+        self.addr = addr & !(LINE_SIZE as u32 - 1);
+        self.data.clear();
+        self.changed.clear();
+        Ok(())
+    }
+
+    /// Fetch the current contents of the view from the machine.
+    pub fn refresh(&mut self, n64: &N64) -> Result<()> {
+        let data = n64.read_memory(self.region, self.addr, self.len())?;
+        self.update(data);
+        Ok(())
+    }
+
+    fn update(&mut self, data: Vec<u8>) {
+        self.changed = if self.data.len() == data.len() {
+            self.data.iter().zip(&data).map(|(a, b)| a != b).collect()
+        } else {
+            vec![false; data.len()]
+        };
+        self.data = data;
+    }
+
+    /// Contents of the view, as of the last refresh.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Whether the byte at the specified address changed in the last refresh.
+    pub fn changed(&self, addr: u32) -> bool {
+        let idx = addr.wrapping_sub(self.addr) as usize;
+        idx < self.changed.len() && self.changed[idx]
+    }
+
+    /// Render the view as text: one line per 16 bytes, followed by their
+    /// ASCII representation. Changed bytes are prefixed by '*'.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for (line, bytes) in self.data.chunks(LINE_SIZE).enumerate() {
+            let addr = self.addr as usize + line * LINE_SIZE;
+            write!(out, "{:08x}:", addr).unwrap();
+            for (idx, b) in bytes.iter().enumerate() {
+                let mark = if self.changed[line * LINE_SIZE + idx] {
+                    '*'
+                } else {
+                    ' '
+                };
+                write!(out, "{}{:02x}", mark, b).unwrap();
+            }
+            let ascii: String = bytes
+                .iter()
+                .map(|&b| {
+                    if b >= 0x20 && b < 0x7F {
+                        b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            writeln!(out, "  {}", ascii).unwrap();
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memview() {
+        let mut view = MemView::new(MemRegion::Dmem, 2);
+        assert!(view.goto(0x1000).is_err());
+        view.goto(0xFF8).unwrap();
+        assert_eq!(view.addr(), 0xFF0);
+        assert_eq!(view.len(), 16);
+
+        view.goto(0x123).unwrap();
+        assert_eq!(view.addr(), 0x120);
+        let mut data: Vec<u8> = (0x40..0x60).collect();
+        view.update(data.clone());
+        assert!(!view.changed(0x120));
+
+        data[3] = 0;
+        view.update(data);
+        assert!(view.changed(0x123));
+        assert!(!view.changed(0x124));
+        assert!(!view.changed(0x200));
+        assert_eq!(
+            view.render(),
+            "00000120: 40 41 42*00 44 45 46 47 48 49 4a 4b 4c 4d 4e 4f  @AB.DEFGHIJKLMNO\n\
+             00000130: 50 51 52 53 54 55 56 57 58 59 5a 5b 5c 5d 5e 5f  PQRSTUVWXYZ[\\]^_\n"
+        );
+        assert_eq!(MemRegion::parse("tmem").unwrap(), MemRegion::Tmem);
+    }
+}
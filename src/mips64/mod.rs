@@ -1,9 +0,0 @@
-extern crate num;
-
-mod cp0;
-mod cpu;
-mod fpu;
-
-pub use self::cp0::Cp0;
-pub use self::cpu::{Cop, Cop0, Cpu, CpuContext, Exception};
-pub use self::fpu::Fpu;
@@ -0,0 +1,146 @@
+extern crate crc;
+
+use self::crc::crc32;
+use super::errors::*;
+use super::hle::HleConfig;
+use super::N64;
+use emu::gfx::{OwnedGfxBufferLE, Rgb888};
+use emu::hw::OutputProducer;
+use emu::int::Numerics;
+use slog;
+use std::fmt;
+
+/// MachineConfig is the set of accuracy-related options that can differ
+/// between the two machines run by the monitor.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct MachineConfig {
+    pub hle: HleConfig,
+    pub yuv_framebuffer: bool,
+}
+
+impl MachineConfig {
+    /// Parse a configuration as a list of options separated by ';', on top
+    /// of a base configuration (eg: "hle=audio,jpeg;yuv-framebuffer").
+    pub fn parse(base: MachineConfig, s: &str) -> Result<MachineConfig> {
+        let mut cfg = base;
+        for opt in s.split(';').filter(|opt| !opt.is_empty()) {
+            match opt {
+                o if o.starts_with("hle=") => cfg.hle = HleConfig::parse(&o["hle=".len()..])?,
+                "yuv-framebuffer" => cfg.yuv_framebuffer = true,
+                "no-yuv-framebuffer" => cfg.yuv_framebuffer = false,
+                _ => bail!("invalid monitor option: {}", opt),
+            }
+        }
+        Ok(cfg)
+    }
+
+    fn build(&self, logger: slog::Logger, romfn: &str) -> Result<N64> {
+        let mut n64 = N64::new(logger, romfn)?;
+        n64.setup_cic()?;
+        n64.set_lenient(true);
+        n64.set_hle(self.hle);
+        n64.set_yuv_framebuffer(self.yuv_framebuffer);
+        Ok(n64)
+    }
+}
+
+/// First frame whose output differs between the two machines.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Divergence {
+    pub frame: usize,
+    pub hashes: [u32; 2],
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "frame {}: {} != {}",
+            self.frame,
+            self.hashes[0].hex(),
+            self.hashes[1].hex()
+        )
+    }
+}
+
+/// Monitor runs the same ROM on two machine configurations in lockstep,
+/// comparing the hash of each frame, to find out whether (and when) an
+/// accuracy option affects the emulation of a game.
+pub struct Monitor {
+    machines: [N64; 2],
+    screens: [OwnedGfxBufferLE<Rgb888>; 2],
+    frames_run: usize,
+}
+
+impl Monitor {
+    pub fn new(logger: slog::Logger, romfn: &str, configs: [MachineConfig; 2]) -> Result<Monitor> {
+        Ok(Monitor {
+            machines: [
+                configs[0].build(logger.new(o!("machine" => 0)), romfn)?,
+                configs[1].build(logger.new(o!("machine" => 1)), romfn)?,
+            ],
+            screens: [
+                OwnedGfxBufferLE::new(640, 480),
+                OwnedGfxBufferLE::new(640, 480),
+            ],
+            frames_run: 0,
+        })
+    }
+
+    /// Emulate one frame on both machines, and return their frame hashes.
+    pub fn step(&mut self) -> [u32; 2] {
+        let mut hashes = [0u32; 2];
+        for (idx, n64) in self.machines.iter_mut().enumerate() {
+            let screen = &mut self.screens[idx];
+            n64.render_frame(&mut screen.buf_mut());
+            hashes[idx] = crc32::checksum_ieee(screen.buf().raw().0);
+        }
+        self.frames_run += 1;
+        hashes
+    }
+
+    /// Run up to the specified number of frames, stopping at the first
+    /// frame that differs.
+    pub fn run(&mut self, frames: usize) -> Option<Divergence> {
+        for _ in 0..frames {
+            let hashes = self.step();
+            if hashes[0] != hashes[1] {
+                return Some(Divergence {
+                    frame: self.frames_run - 1,
+                    hashes,
+                });
+            }
+        }
+        None
+    }
+
+    pub fn frames_run(&self) -> usize {
+        self.frames_run
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn machine_config() {
+        let base = MachineConfig::parse(MachineConfig::default(), "yuv-framebuffer").unwrap();
+        assert!(base.yuv_framebuffer);
+        assert_eq!(base.hle, HleConfig::default());
+
+        let cfg = MachineConfig::parse(base, "hle=audio,jpeg").unwrap();
+        assert!(cfg.yuv_framebuffer);
+        assert!(cfg.hle.audio && cfg.hle.jpeg && !cfg.hle.gfx);
+
+        let cfg = MachineConfig::parse(cfg, "hle=none;no-yuv-framebuffer").unwrap();
+        assert_eq!(cfg, MachineConfig::default());
+        assert!(MachineConfig::parse(base, "cache").is_err());
+
+        let div = Divergence {
+            frame: 12,
+            hashes: [0x1234, 0xABCD],
+        };
+        assert_eq!(div.to_string(), "frame 12: 0x00001234 != 0x0000abcd");
+    }
+}
@@ -1,21 +1,44 @@
 use emu::bus::be::{Bus, DevPtr, Mem};
+use emu::bus::MemoryMap;
 use emu::gfx::{GfxBufferMutLE, Rgb888};
 use emu::hw;
 use emu::sync;
 use slog;
-use std::cell::RefCell;
+use std::cell::{Ref, RefCell};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 
 use super::ai::Ai;
-use super::cartridge::{Cartridge, CicModel};
+use super::boottrace::BootTrace;
+use super::cartridge::{Cartridge, CicModel, SaveType};
 use super::dp::Dp;
 use super::errors::*;
+use super::hle::HleConfig;
+use super::info::MachineInfo;
+use super::interrupts::{InterruptLog, InterruptStats};
+use super::memview::MemRegion;
 use super::mips64;
-use super::pi::Pi;
+use super::pi::{self, Pi};
+use super::rdp::TexturePack;
 use super::ri::Ri;
-use super::si::Si;
+use super::si::{ControllerPak, Si};
 use super::sp::Sp;
 use super::vi::Vi;
+use super::watchdog::Watchdog;
+
+// Main clock of the machine (TODO: guessed), the timeline on which all the
+// subsystems are synchronized. The CPU runs at half of it (93.75 MHz on the
+// real hardware), and the RCP (RSP and RDP) at a third (62.5 MHz).
+const MAIN_CLOCK: i64 = 187488000;
+const CPU_CLOCK: i64 = MAIN_CLOCK / 2;
+pub(crate) const RCP_CLOCK: i64 = MAIN_CLOCK / 3;
+
+// Pressing the reset button asserts the pre-NMI interrupt (IP4), giving the
+// game about half a second to shut down cleanly before the NMI.
+const PRE_NMI_LINE: usize = 4;
+const PRE_NMI_FRAMES: u32 = 30;
 
 pub struct N64 {
     logger: slog::Logger,
@@ -31,6 +54,25 @@ pub struct N64 {
     vi: DevPtr<Vi>,
     ai: DevPtr<Ai>,
     ri: DevPtr<Ri>,
+    code: mips64::CodeWatch,
+    stall: mips64::BusStall,
+
+    input: Option<Box<hw::InputSource>>,
+    frame: u64,
+    ints: InterruptLog,
+
+    lenient: bool,
+    resolution_scale: usize,
+    counter_factor: u32,
+    cpu_clock: i64,
+    cpu_sub: usize,
+    opcode_report_path: Option<PathBuf>,
+    rdp_capture: Option<(u64, PathBuf)>,
+    boot_trace: Option<BootTrace>,
+    watchdog: Option<Watchdog>,
+    input_display: Option<hw::InputDisplay>,
+    // Frames left until the NMI, after the reset button was pressed
+    pending_nmi: Option<u32>,
 }
 
 impl N64 {
@@ -40,18 +82,45 @@ impl N64 {
             logger.new(o!()),
             bus.clone(),
         ))));
-        let cart = DevPtr::new(Cartridge::new(romfn).chain_err(|| "cannot open rom file")?);
-        let pi = DevPtr::new(
+        let cart =
+            DevPtr::new(Cartridge::new(romfn).chain_err(|| ErrorKind::RomLoad(romfn.into()))?);
+        let mut pi = DevPtr::new(
             Pi::new(logger.new(o!()), bus.clone(), "bios/pifdata.bin")
                 .chain_err(|| "cannot open BIOS file")?,
         );
-        let sp = Sp::new(logger.new(o!()), bus.clone())?;
-        let si = DevPtr::new(Si::new(logger.new(o!())));
-        let dp = DevPtr::new(Dp::new(logger.new(o!()), bus.clone()));
-        let vi = DevPtr::new(Vi::new(logger.new(o!()), bus.clone()));
-        let ai = DevPtr::new(Ai::new(logger.new(o!())));
+        let mut sp = Sp::new(logger.new(o!()), bus.clone())?;
+        let mut si = DevPtr::new(Si::new(logger.new(o!()), bus.clone()));
+        let mut dp = DevPtr::new(Dp::new(logger.new(o!()), bus.clone()));
+        let mut vi = DevPtr::new(Vi::new(logger.new(o!()), bus.clone()));
+        let mut ai = DevPtr::new(Ai::new(logger.new(o!()), bus.clone()));
         let ri = DevPtr::new(Ri::new(logger.new(o!())));
 
+        // The VI displays the high-resolution framebuffers drawn by the RDP,
+        // when internal resolution scaling is enabled.
+        vi.borrow_mut().set_hires_cache(dp.borrow().hires_cache());
+
+        // All devices that raise interrupts share the same statistics.
+        let ints = InterruptLog::new();
+        sp.borrow_mut().set_interrupt_log(ints.clone());
+        si.borrow_mut().set_interrupt_log(ints.clone());
+        ai.borrow_mut().set_interrupt_log(ints.clone());
+        vi.borrow_mut().set_interrupt_log(ints.clone());
+        pi.borrow_mut().set_interrupt_log(ints.clone());
+        dp.borrow_mut().set_interrupt_log(ints.clone());
+
+        // Stores and DMAs into RDRAM invalidate the code fetched by the CPU.
+        let code = mips64::CodeWatch::new();
+        cpu.borrow_mut().set_code_watch(code.clone());
+        pi.borrow_mut().set_code_watch(code.clone());
+        si.borrow_mut().set_code_watch(code.clone());
+        sp.borrow_mut().set_code_watch(code.clone());
+
+        // Reads from the cartridge stall the CPU for the time they take on
+        // the PI bus.
+        let stall = mips64::BusStall::new(CPU_CLOCK);
+        cpu.borrow_mut().set_bus_stall(stall.clone());
+        pi.borrow_mut().set_bus_stall(stall.clone());
+
         {
             // Install CPU coprocessors
             //   COP0 -> standard MIPS64 CP0
@@ -64,22 +133,20 @@ impl N64 {
         {
             // Configure main bus
             let mut bus = bus.borrow_mut();
-            bus.map_device(0x0000_0000, &ri, 0)?;
-            bus.map_device(0x03F0_0000, &ri, 1)?;
-            bus.map_device(0x0400_0000, &sp, 0)?;
-            bus.map_device(0x0404_0000, &sp, 1)?;
-            bus.map_device(0x0408_0000, &sp, 2)?;
-            bus.map_device(0x0410_0000, &dp, 0)?;
-            bus.map_device(0x0440_0000, &vi, 0)?;
-            bus.map_device(0x0450_0000, &ai, 0)?;
-            bus.map_device(0x0460_0000, &pi, 0)?;
-            bus.map_device(0x0470_0000, &ri, 2)?;
-            bus.map_device(0x0480_0000, &si, 0)?;
-            bus.map_device(0x1000_0000, &cart, 0)?;
-            bus.map_device(0x1FC0_0000, &pi, 1)?;
-        }
-
-        const MAIN_CLOCK: i64 = 187488000; // TODO: guessed
+            bus.map_device(0x0000_0000, &ri, 0).map_err(bus_error)?;
+            bus.map_device(0x03F0_0000, &ri, 1).map_err(bus_error)?;
+            bus.map_device(0x0400_0000, &sp, 0).map_err(bus_error)?;
+            bus.map_device(0x0404_0000, &sp, 1).map_err(bus_error)?;
+            bus.map_device(0x0408_0000, &sp, 2).map_err(bus_error)?;
+            bus.map_device(0x0410_0000, &dp, 0).map_err(bus_error)?;
+            bus.map_device(0x0440_0000, &vi, 0).map_err(bus_error)?;
+            bus.map_device(0x0450_0000, &ai, 0).map_err(bus_error)?;
+            bus.map_device(0x0460_0000, &pi, 0).map_err(bus_error)?;
+            bus.map_device(0x0470_0000, &ri, 2).map_err(bus_error)?;
+            bus.map_device(0x0480_0000, &si, 0).map_err(bus_error)?;
+            Pi::map_cartridge(&pi, &mut bus, 0x1000_0000, &cart).map_err(bus_error)?;
+            bus.map_device(0x1FC0_0000, &pi, 1).map_err(bus_error)?;
+        }
 
         let mut sync = sync::Sync::new(sync::Config {
             main_clock: MAIN_CLOCK,
@@ -89,9 +156,10 @@ impl N64 {
             hsyncs: vec![0], // sync at the beginning of each line
             vsyncs: vec![],
         });
-        sync.register(cpu.clone(), MAIN_CLOCK / 2);
-        sync.register(sp.borrow().core_cpu.clone(), MAIN_CLOCK / 3);
-        sync.register(dp.clone().unwrap(), MAIN_CLOCK / 3);
+        let cpu_sub = sync.register(cpu.clone(), CPU_CLOCK);
+        sync.register(sp.borrow().core_cpu.clone(), RCP_CLOCK);
+        sync.register(dp.clone().unwrap(), RCP_CLOCK);
+        pi.borrow_mut().set_timers(sync.timers());
 
         return Ok(N64 {
             logger,
@@ -106,6 +174,22 @@ impl N64 {
             vi,
             ai,
             ri,
+            code,
+            stall,
+            input: None,
+            frame: 0,
+            ints,
+            lenient: false,
+            resolution_scale: 1,
+            counter_factor: 1,
+            cpu_clock: CPU_CLOCK,
+            cpu_sub,
+            opcode_report_path: None,
+            rdp_capture: None,
+            boot_trace: None,
+            watchdog: None,
+            input_display: None,
+            pending_nmi: None,
         });
     }
 
@@ -123,22 +207,445 @@ impl N64 {
         self.bus.borrow().write::<u32>(0x1FC0_07E4, seed << 8);
         Ok(())
     }
-}
 
-impl hw::OutputProducer for N64 {
-    fn render_frame(&mut self, screen: &mut GfxBufferMutLE<Rgb888>) {
+    pub fn cpu(&self) -> &Rc<RefCell<Box<mips64::Cpu>>> {
+        &self.cpu
+    }
+
+    pub fn sp(&self) -> &DevPtr<Sp> {
+        &self.sp
+    }
+
+    pub fn bus(&self) -> &Rc<RefCell<Box<Bus>>> {
+        &self.bus
+    }
+
+    // Select the source of the controllers state, polled once per frame.
+    pub fn set_input(&mut self, input: Box<hw::InputSource>) {
+        self.input = Some(input);
+    }
+
+    // Forward the joybus commands for the ports covered by the device to
+    // real controllers, bypassing the emulated controllers and paks.
+    pub fn set_passthrough(&mut self, dev: Box<hw::JoybusDevice>) {
+        self.si.borrow_mut().set_passthrough(dev);
+    }
+
+    // Insert a controller pak image into the controller on the specified
+    // port, even while the machine runs. The pak it replaces is saved first.
+    pub fn insert_pak(&mut self, port: usize, path: &Path) -> Result<()> {
+        if port >= hw::MAX_PADS {
+            bail!("invalid controller port: {}", port + 1);
+        }
+        let pak = ControllerPak::load(path)?;
+        if let Some(mut old) = self.si.borrow_mut().insert_pak(port, Some(pak)) {
+            old.save()?;
+        }
+        Ok(())
+    }
+
+    // Draw the controllers on the screen, as read by the game.
+    pub fn set_input_display(&mut self, disp: hw::InputDisplay) {
+        self.input_display = Some(disp);
+    }
+
+    // Select the RSP tasks that are emulated at high level.
+    pub fn set_hle(&mut self, config: HleConfig) {
+        self.sp.borrow_mut().set_hle(config);
+    }
+
+    // Configure texture dumping and replacement.
+    pub fn set_texture_pack(&mut self, texpack: TexturePack) {
+        self.dp.borrow_mut().set_texture_pack(texpack);
+    }
+
+    // Render at a multiple of the native resolution (1 = native).
+    pub fn set_resolution_scale(&mut self, scale: usize) {
+        self.resolution_scale = scale;
+        self.dp.borrow_mut().set_resolution_scale(scale);
+    }
+
+    // Set the CPU cycles taken by each instruction: the CPU runs fewer
+    // instructions per frame (and thus between VI interrupts), while COP0
+    // Count keeps advancing at the same rate.
+    pub fn set_counter_factor(&mut self, factor: u32) {
+        self.counter_factor = factor.max(1);
+        self.cpu.borrow_mut().set_counter_factor(self.counter_factor);
+    }
+
+    // Set the CPU clock frequency (in Hz), to overclock or underclock the
+    // CPU with respect to the rest of the machine.
+    pub fn set_cpu_clock(&mut self, freq: i64) {
+        self.cpu_clock = freq.max(1);
+        self.sync.set_frequency(self.cpu_sub, self.cpu_clock);
+        self.stall.set_frequency(self.cpu_clock);
+    }
+
+    // Select the timing profile of the main CPU: how many cycles each
+    // instruction takes.
+    pub fn set_cpu_timing(&mut self, timing: mips64::Timing) {
+        self.cpu.borrow_mut().set_timing(timing);
+    }
+
+    // Enable the RDP dithering of 16-bit color. Disabling it gives the
+    // undithered look that some users prefer.
+    pub fn set_dither(&mut self, enabled: bool) {
+        self.dp.borrow_mut().set_dither(enabled);
+    }
+
+    // Display 16-bit framebuffers as YUV frames (FMV hack).
+    pub fn set_yuv_framebuffer(&mut self, yuv: bool) {
+        self.vi.borrow_mut().set_yuv_framebuffer(yuv);
+    }
+
+    // Enable lenient mode on all CPUs: unimplemented opcodes are logged
+    // (once) and skipped, rather than aborting emulation.
+    pub fn set_lenient(&mut self, lenient: bool) {
+        self.lenient = lenient;
+        self.cpu.borrow_mut().set_lenient(lenient);
+        self.sp.borrow().core_cpu.borrow_mut().set_lenient(lenient);
+    }
+
+    // Run the main CPU with the cached engine, which executes basic blocks
+    // decoded once, rather than decoding each instruction again.
+    pub fn set_block_cache(&mut self, enable: bool) {
+        self.cpu.borrow_mut().set_block_cache(enable);
+    }
+
+    // Compile the hot code of the main CPU to native code. Builds without
+    // the "jit" feature run the cached engine instead.
+    pub fn set_jit(&mut self, enable: bool) {
+        let mut cpu = self.cpu.borrow_mut();
+        #[cfg(feature = "jit")]
+        cpu.set_jit(enable);
+        #[cfg(not(feature = "jit"))]
+        cpu.set_block_cache(enable);
+    }
+
+    // Skip the loops in which the main CPU waits for an event (eg: polling
+    // a device register, or spinning until an interrupt), advancing its
+    // clock straight to the event.
+    pub fn set_idle_skip(&mut self, enable: bool) {
+        self.cpu.borrow_mut().set_idle_skip(enable);
+    }
+
+    // Count the opcodes executed by both CPUs, and write a report to the
+    // specified file when emulation finishes.
+    pub fn set_opcode_stats(&mut self, report: PathBuf) {
+        self.cpu.borrow_mut().set_opcode_stats(true);
+        self.sp.borrow().core_cpu.borrow_mut().set_opcode_stats(true);
+        self.opcode_report_path = Some(report);
+    }
+
+    // Capture the RDP commands of the specified frame (counting from 1) into
+    // a file, which can be replayed without the rest of the machine.
+    pub fn set_rdp_capture(&mut self, frame: u64, path: PathBuf) {
+        self.rdp_capture = Some((frame, path));
+    }
+
+    fn save_rdp_capture(&mut self, path: &PathBuf) -> Result<()> {
+        let cap = match self.dp.borrow_mut().take_rdp_capture() {
+            Some(cap) => cap,
+            None => return Ok(()),
+        };
+        let mut file = io::BufWriter::new(fs::File::create(path)?);
+        cap.save(&mut file)?;
+        info!(self.logger, "RDP capture saved"; o!("path" => path.display().to_string(), "commands" => cap.len()));
+        Ok(())
+    }
+
+    // Log the milestones of the boot process as they are reached.
+    pub fn set_boot_trace(&mut self) {
+        let cart = self.cart.borrow();
+        let cic = cart.detect_cic_model().ok();
+        self.boot_trace = Some(BootTrace::new(self.logger.new(o!()), &cart.header(), cic));
+    }
+
+    pub fn boot_trace(&self) -> Option<&BootTrace> {
+        self.boot_trace.as_ref()
+    }
+
+    // Stop the emulation when the game makes no progress (no framebuffer
+    // change, no interrupt taken) for the specified number of CPU cycles.
+    pub fn set_watchdog(&mut self, cycles: i64) {
+        self.watchdog = Some(Watchdog::new(cycles));
+    }
+
+    // Whether the watchdog stopped the emulation.
+    pub fn hung(&self) -> bool {
+        self.watchdog.as_ref().map_or(false, |wd| wd.tripped())
+    }
+
+    // Report of the opcodes executed so far, if counting is enabled.
+    pub fn opcode_report(&self) -> Option<String> {
+        let cpu = self.cpu.borrow();
+        let sp = self.sp.borrow();
+        let rsp = sp.core_cpu.borrow();
+        match (cpu.ctx().opcode_stats(), rsp.ctx().opcode_stats()) {
+            (Some(cpu), Some(rsp)) => Some(format!("CPU {}\nRSP {}", cpu.report(), rsp.report())),
+            _ => None,
+        }
+    }
+
+    // Describe the emulator build and the configuration of the machine.
+    pub fn info(&self) -> MachineInfo {
+        let cart = self.cart.borrow();
+        let game_code = cart.game_code();
+        MachineInfo {
+            version: MachineInfo::version().into(),
+            build: MachineInfo::build().into(),
+            features: MachineInfo::features(),
+            renderer: "software".into(),
+            resolution_scale: self.resolution_scale,
+            counter_factor: self.counter_factor,
+            cpu_clock: self.cpu_clock,
+            lenient: self.lenient,
+            cic: cart.detect_cic_model().ok().map(|cic| cic as u32),
+            save_type: SaveType::from_game_code(&game_code),
+            game_code,
+        }
+    }
+
+    // Emulate a whole frame, without scanning it out.
+    fn run_frame(&mut self) {
+        if self.hung() {
+            return;
+        }
+        self.check_reset();
+        if let Some(ref mut input) = self.input {
+            self.si.borrow_mut().set_pads(input.poll(self.frame));
+        }
+        self.frame += 1;
+
+        let capture = match self.rdp_capture {
+            Some((frame, ref path)) if frame == self.frame => Some(path.clone()),
+            _ => None,
+        };
+        if capture.is_some() {
+            self.dp.borrow_mut().start_rdp_capture();
+        }
+
         let mut vi = self.vi.clone();
-        self.sync.run_frame(move |evt| match evt {
+        let (cpu, sp, ints) = (self.cpu.clone(), self.sp.clone(), self.ints.clone());
+        let mut pi = self.pi.clone();
+        let logger = self.logger.clone();
+        let mut boot = self.boot_trace.take();
+        self.sync.run_frame(|evt| match evt {
             sync::Event::HSync(x, y) if x == 0 => {
                 vi.borrow_mut().set_line(y);
+                if let Some(ref mut boot) = boot {
+                    let cpu = cpu.borrow();
+                    let (clock, pc) = (cpu.ctx().clock, cpu.ctx().get_pc());
+                    boot.check(clock, pc, &sp.borrow().dmem.buf(), &ints.stats());
+                }
+            }
+            sync::Event::Timer(pi::DMA_TIMER) => pi.borrow_mut().dma_done(),
+            sync::Event::Timer(pi::IO_TIMER) => pi.borrow_mut().io_done(),
+            sync::Event::Timer(pi::PIF_TIMER) => {
+                error!(logger, "boot process not terminated in time, PIF reset");
+                pi.borrow_mut().pif_reset();
+                cpu.borrow_mut().reset();
             }
             _ => panic!("unexpected sync event: {:?}", evt),
         });
+        self.boot_trace = boot;
+
+        if let Some(path) = capture {
+            if let Err(err) = self.save_rdp_capture(&path) {
+                error!(self.logger, "cannot save RDP capture"; o!("path" => path.display().to_string(), "err" => err.to_string()));
+            }
+        }
+
+        self.ints.end_frame();
+        {
+            let stats = self.ints.stats();
+            for int in stats.storms() {
+                warn!(self.logger, "interrupt storm"; o!("int" => int.name(), "frame" => self.frame, "count" => stats.last_frame().count(int)));
+            }
+        }
+        self.check_watchdog();
+    }
+
+    // Press the reset button: the game is notified through the pre-NMI
+    // interrupt, then the NMI restarts the machine from the boot code.
+    pub fn reset_button(&mut self) {
+        if self.pending_nmi.is_none() {
+            info!(self.logger, "reset button pressed");
+            self.cpu.borrow_mut().set_int_line(PRE_NMI_LINE, true);
+            self.pending_nmi = Some(PRE_NMI_FRAMES);
+        }
+    }
+
+    fn check_reset(&mut self) {
+        self.pending_nmi = match self.pending_nmi {
+            Some(0) => {
+                let mut cpu = self.cpu.borrow_mut();
+                cpu.set_int_line(PRE_NMI_LINE, false);
+                cpu.nmi();
+                self.pi.borrow_mut().pif_reset();
+                None
+            }
+            Some(frames) => Some(frames - 1),
+            None => None,
+        };
+    }
+
+    // Feed the watchdog, and dump the state of the machine if it trips.
+    fn check_watchdog(&mut self) {
+        let cpu = self.cpu.borrow();
+        let clock = cpu.ctx().clock;
+        let ints = cpu.exception_stats().get(&mips64::Exception::INT).cloned();
+        let origin = self.vi.borrow().origin();
+        let wd = match self.watchdog {
+            Some(ref mut wd) => wd,
+            None => return,
+        };
+        if !wd.check(clock, origin, ints.unwrap_or(0)) {
+            return;
+        }
+
+        let pc = cpu.ctx().get_pc();
+        let opcode = self.bus.borrow().read::<u32>(pc & 0x1FFF_FFFF);
+        error!(self.logger, "watchdog: no progress, emulation stopped";
+            o!("frame" => self.frame, "idle_cycles" => wd.idle_cycles(clock),
+               "pc" => format!("{:x}", pc), "disasm" => mips64::disasm(opcode, pc),
+               "ints" => self.ints.stats().last_frame().to_string(),
+               "origin" => format!("{:x}", origin)));
+        for (exc, count) in cpu.exception_stats() {
+            error!(self.logger, "watchdog: exceptions"; o!("exc" => format!("{:?}", exc), "count" => *count));
+        }
+        for line in cpu.state().to_string().lines() {
+            error!(self.logger, "watchdog: {}", line);
+        }
+    }
+
+    // Determinism audit: record the exact schedule of the emulation, or
+    // verify that it matches a previously recorded one.
+    pub fn record_schedule(&mut self) {
+        self.sync.record_trace();
+    }
+
+    pub fn verify_schedule(&mut self, trace: sync::EventTrace) {
+        self.sync.verify_trace(trace);
+    }
+
+    pub fn take_schedule(&mut self) -> Option<sync::EventTrace> {
+        self.sync.take_trace()
+    }
+
+    pub fn schedule_divergence(&self) -> Option<&sync::Divergence> {
+        self.sync.divergence()
+    }
+
+    // Current memory map of the main bus.
+    pub fn memory_map(&self) -> MemoryMap {
+        self.bus.borrow().memory_map()
+    }
+
+    // Statistics on the interrupts raised by the devices.
+    pub fn interrupt_stats(&self) -> Ref<InterruptStats> {
+        self.ints.stats()
+    }
 
+    // Run a function over the contents of a memory region.
+    fn with_memory<R, F: FnOnce(&mut [u8]) -> R>(&self, region: MemRegion, f: F) -> R {
+        match region {
+            MemRegion::Rdram => f(&mut self.ri.borrow().rdram.buf()),
+            MemRegion::Dmem => f(&mut self.sp.borrow().dmem.buf()),
+            MemRegion::Imem => f(&mut self.sp.borrow().imem.buf()),
+            MemRegion::Tmem => f(self.dp.clone().borrow_mut().tmem_mut()),
+        }
+    }
+
+    /// Read the contents of a memory region, for debugging.
+    pub fn read_memory(&self, region: MemRegion, addr: u32, len: usize) -> Result<Vec<u8>> {
+        if addr as usize + len > region.size() {
+            bail!("address out of {:?}: {:x}+{:x}", region, addr, len);
+        }
+        Ok(self.with_memory(region, |mem| mem[addr as usize..][..len].to_vec()))
+    }
+
+    /// Modify the contents of a memory region, for debugging.
+    pub fn write_memory(&self, region: MemRegion, addr: u32, data: &[u8]) -> Result<()> {
+        if addr as usize + data.len() > region.size() {
+            bail!("address out of {:?}: {:x}+{:x}", region, addr, data.len());
+        }
+        self.with_memory(region, |mem| {
+            mem[addr as usize..][..data.len()].copy_from_slice(data)
+        });
+
+        // The CPU (or the RSP) might have fetched the modified code already.
+        let len = data.len() as u32;
+        match region {
+            MemRegion::Rdram => {
+                self.code.invalidate(addr, len);
+            }
+            MemRegion::Dmem => self.sp.borrow().invalidate_code(addr, len),
+            MemRegion::Imem => self.sp.borrow().invalidate_code(0x1000 + addr, len),
+            MemRegion::Tmem => {}
+        }
+        Ok(())
+    }
+}
+
+impl hw::OutputProducer for N64 {
+    fn render_frame(&mut self, screen: &mut GfxBufferMutLE<Rgb888>) {
+        self.run_frame();
         self.vi.borrow().draw_frame(screen);
+        if let Some(ref disp) = self.input_display {
+            disp.draw(screen, &self.si.borrow().read_pads());
+        }
+    }
+
+    fn skip_frame(&mut self, _screen: &mut GfxBufferMutLE<Rgb888>) {
+        self.run_frame();
+    }
+
+    fn swapped(&mut self) -> bool {
+        self.vi.borrow().take_swap()
+    }
+
+    fn render_audio(&mut self, samples: &mut Vec<i16>) -> u32 {
+        self.ai.borrow_mut().take_samples(samples)
+    }
+
+    fn hotkey(&mut self, hk: hw::Hotkey) {
+        if hk == hw::Hotkey::Reset {
+            self.reset_button();
+            return;
+        }
+        warn!(self.logger, "hotkey not supported yet"; o!("hotkey" => format!("{:?}", hk)));
+    }
+
+    // Files dropped on the window are controller pak images, which are
+    // swapped into the first controller.
+    fn load_file(&mut self, path: &Path) {
+        match self.insert_pak(0, path) {
+            Ok(()) => {
+                info!(self.logger, "controller pak inserted"; o!("path" => path.display().to_string()));
+            }
+            Err(err) => {
+                error!(self.logger, "cannot insert controller pak"; o!("path" => path.display().to_string(), "err" => err.to_string()));
+            }
+        }
     }
 
     fn finish(&mut self) {
         info!(self.logger, "finish"; o!("pc" => format!("{:x}", self.cpu.borrow().ctx().get_pc())));
+        if let (Some(path), Some(report)) = (self.opcode_report_path.as_ref(), self.opcode_report()) {
+            if let Err(err) = fs::write(path, report) {
+                error!(self.logger, "cannot write opcode report"; o!("path" => path.display().to_string(), "err" => err.to_string()));
+            }
+        }
+        if let Some(ref boot) = self.boot_trace {
+            for m in boot.missing() {
+                warn!(self.logger, "boot milestone not reached"; o!("milestone" => m.name()));
+            }
+        }
+        for pak in self.si.borrow_mut().paks_mut() {
+            if let Err(err) = pak.save() {
+                error!(self.logger, "cannot save controller pak"; o!("path" => pak.path().display().to_string(), "err" => err.to_string()));
+            }
+        }
     }
 }
@@ -1,13 +1,55 @@
 extern crate emu;
 extern crate slog;
-use emu::bus::be::{Bus, Mem, MemFlags, Reg32};
+use cartridge::Cartridge;
+use emu::bus::be::{Bus, DevPtr, Mem, MemFlags, Reg32};
 use emu::int::Numerics;
+use emu::sync::Timers;
 use errors::*;
+use interrupts::{Interrupt, InterruptLog};
+use mips64::{BusStall, CodeWatch};
+use n64::RCP_CLOCK;
 use std::cell::RefCell;
 use std::fs::File;
 use std::io::Read;
 use std::rc::Rc;
 
+/// Id of the timer that completes PI DMA transfers.
+pub const DMA_TIMER: usize = 0;
+
+/// Id of the timer that completes PI IO writes.
+pub const IO_TIMER: usize = 1;
+
+/// Id of the timer that expires if the boot code does not complete the
+/// PIF handshake in time.
+pub const PIF_TIMER: usize = 2;
+
+/// Size of the cartridge space (domain 1, address 2).
+pub const CART_SIZE: u32 = 0x0FC0_0000;
+
+// Timings of cartridge domain 1 (in RCP cycles), as configured by retail
+// games: each 16-bit word takes the R/W strobe pulse plus its release, and
+// each page adds the device latency.
+const DOM1_LATENCY: i64 = 0x40 + 1;
+const DOM1_PULSE_WIDTH: i64 = 0x12 + 1;
+const DOM1_RELEASE: i64 = 0x03 + 1;
+const DOM1_PAGE_SIZE: i64 = 1 << (0x07 + 2);
+
+// Commands of the PIF, written by the boot code into the last byte of PIF
+// RAM: the checksum is acquired (and acknowledged) once IPL2 runs, the PIF
+// ROM is then locked out, and IPL3 must terminate the boot process within
+// about 5 seconds, or the PIF resets the console.
+const PIF_CMD_TERMINATE: u32 = 0x08;
+const PIF_CMD_LOCKOUT: u32 = 0x10;
+const PIF_CMD_CHECKSUM: u32 = 0x20;
+const PIF_CHECKSUM_ACK: u32 = 0x80;
+const PIF_TIMEOUT_SECS: i64 = 5;
+
+// Physical address of the PIF ROM on the main bus.
+const PIF_ROM: u32 = 0x1FC0_0000;
+
+const STATUS_DMA_BUSY: u32 = 1 << 0;
+const STATUS_IO_BUSY: u32 = 1 << 1;
+
 #[derive(DeviceBE)]
 pub struct Pi {
     #[mem(bank = 1, offset = 0x0, vsize = 0x7C0)]
@@ -75,6 +117,22 @@ pub struct Pi {
 
     logger: slog::Logger,
     bus: Rc<RefCell<Box<Bus>>>,
+    ints: InterruptLog,
+    code: CodeWatch,
+    timers: Timers,
+    stall: BusStall,
+
+    // Cartridge, as accessed by DMA and PI IO, and its physical address
+    cart: Option<DevPtr<Cartridge>>,
+    cart_base: u32,
+    // Last word transferred on the PI bus by PI IO, returned by reads while
+    // the bus is busy
+    io_latch: u32,
+
+    // Contents of the PIF ROM, restored on reset after a lockout, and
+    // whether the boot code terminated the boot process
+    pif_rom: Vec<u8>,
+    boot_done: bool,
 }
 
 impl Pi {
@@ -85,6 +143,15 @@ impl Pi {
         Ok(Pi {
             logger,
             bus,
+            ints: InterruptLog::new(),
+            code: CodeWatch::new(),
+            timers: Timers::default(),
+            stall: BusStall::new(RCP_CLOCK),
+            cart: None,
+            cart_base: 0,
+            io_latch: 0,
+            pif_rom: contents.clone(),
+            boot_done: false,
             rom: Mem::from_buffer(contents, MemFlags::READACCESS),
             ram: Mem::default(),
             magic: Reg32::default(),
@@ -104,16 +171,157 @@ impl Pi {
         })
     }
 
+    pub fn set_interrupt_log(&mut self, ints: InterruptLog) {
+        self.ints = ints;
+    }
+
+    pub fn set_code_watch(&mut self, code: CodeWatch) {
+        self.code = code;
+    }
+
+    pub fn set_timers(&mut self, timers: Timers) {
+        self.timers = timers;
+    }
+
+    pub fn set_bus_stall(&mut self, stall: BusStall) {
+        self.stall = stall;
+    }
+
+    /// Map the cartridge at the specified physical address. The CPU accesses
+    /// it through PI IO: it is not mapped as memory, and code cannot be
+    /// executed from it.
+    pub fn map_cartridge(
+        pi: &DevPtr<Pi>,
+        bus: &mut Bus,
+        base: u32,
+        cart: &DevPtr<Cartridge>,
+    ) -> ::std::result::Result<(), &'static str> {
+        {
+            let mut pi = pi.clone();
+            let mut pi = pi.borrow_mut();
+            pi.cart = Some(cart.clone());
+            pi.cart_base = base;
+        }
+        let rpi = Rc::downgrade(&pi.clone().unwrap());
+        let wpi = rpi.clone();
+        bus.map_io_named(
+            base,
+            base + CART_SIZE - 1,
+            "cart",
+            Rc::new(move |addr, size| {
+                let pi = rpi.upgrade().unwrap();
+                let val = pi.borrow_mut().io_read(addr, size);
+                val
+            }),
+            Rc::new(move |addr, size, val| {
+                let pi = wpi.upgrade().unwrap();
+                pi.borrow_mut().io_write(addr, size, val);
+            }),
+        )
+    }
+
+    fn busy(&self) -> bool {
+        self.dma_status.get() & (STATUS_DMA_BUSY | STATUS_IO_BUSY) != 0
+    }
+
+    fn cart_read(&self, addr: u32) -> u32 {
+        match self.cart {
+            Some(ref cart) => cart.borrow().read_u32(addr - self.cart_base),
+            None => 0,
+        }
+    }
+
+    // CPU read from the cartridge through PI IO. The bus transfers 32-bit
+    // words: smaller reads get their part of the word, and 64-bit reads are
+    // split in two words. Each transfer stalls the CPU until it completes.
+    // While a DMA or a write is in progress, the bus returns the last word
+    // it transferred instead.
+    fn io_read(&mut self, addr: u32, size: usize) -> u64 {
+        if size == 8 {
+            let hi = self.io_read(addr, 4);
+            return hi << 32 | self.io_read(addr + 4, 4);
+        }
+        if !self.busy() {
+            self.io_latch = self.cart_read(addr & !3);
+            self.stall.stall(Pi::dma_cycles(4), RCP_CLOCK);
+        }
+        let shift = (4 - size - (addr & 3) as usize) * 8;
+        (self.io_latch >> shift) as u64
+    }
+
+    // CPU write to the cartridge through PI IO: the word is latched and sent
+    // to the cartridge (the ROM ignores it), keeping the bus busy for the
+    // time of the transfer. Writes while the bus is busy are dropped.
+    fn io_write(&mut self, _addr: u32, size: usize, val: u64) {
+        if self.busy() {
+            return;
+        }
+        self.io_latch = if size == 8 {
+            (val >> 32) as u32
+        } else {
+            val as u32
+        };
+        self.dma_status.set(self.dma_status.get() | STATUS_IO_BUSY);
+        self.timers.schedule(IO_TIMER, Pi::dma_cycles(4), RCP_CLOCK);
+    }
+
+    /// Complete the PI IO write in progress (see IO_TIMER).
+    pub fn io_done(&mut self) {
+        self.dma_status.set(self.dma_status.get() & !STATUS_IO_BUSY);
+    }
+
+    // Number of RCP cycles taken by a DMA transfer from the cartridge.
+    fn dma_cycles(len: u32) -> i64 {
+        let len = len as i64;
+        let pages = (len + DOM1_PAGE_SIZE - 1) / DOM1_PAGE_SIZE;
+        pages * DOM1_LATENCY + (len + 1) / 2 * (DOM1_PULSE_WIDTH + DOM1_RELEASE)
+    }
+
+    /// Complete the DMA transfer in progress, once the time it takes on the
+    /// cartridge bus has elapsed (see DMA_TIMER).
+    pub fn dma_done(&mut self) {
+        self.dma_status
+            .set(self.dma_status.get() & !STATUS_DMA_BUSY);
+        self.ints.raise(Interrupt::Pi);
+    }
+
     fn cb_read_magic(&self, val: u32) -> u32 {
         info!(self.logger, "read magic"; o!("val" => format!("{:x}", val)));
         val
     }
 
     fn cb_write_magic(&mut self, _old: u32, new: u32) {
-        if new & 0x20 != 0 {
+        if new & PIF_CMD_CHECKSUM != 0 {
             info!(self.logger, "magic: unlock boot");
-            self.magic.set(self.magic.get() | 0x80);
+            self.magic.set(self.magic.get() | PIF_CHECKSUM_ACK);
+            if !self.boot_done && !self.timers.pending(PIF_TIMER) {
+                self.timers
+                    .schedule(PIF_TIMER, PIF_TIMEOUT_SECS * RCP_CLOCK, RCP_CLOCK);
+            }
+        }
+        if new & PIF_CMD_LOCKOUT != 0 {
+            info!(self.logger, "magic: lock out PIF ROM");
+            for b in self.rom.buf().iter_mut() {
+                *b = 0;
+            }
+            self.code.invalidate(PIF_ROM, self.pif_rom.len() as u32);
         }
+        if new & PIF_CMD_TERMINATE != 0 && !self.boot_done {
+            info!(self.logger, "magic: boot terminated");
+            self.boot_done = true;
+            self.timers.cancel(PIF_TIMER);
+        }
+    }
+
+    /// Reset the PIF, as done by the reset button or by a boot timeout (see
+    /// PIF_TIMER): the PIF ROM is readable again, and the boot code must go
+    /// through the handshake again.
+    pub fn pif_reset(&mut self) {
+        self.rom.buf().copy_from_slice(&self.pif_rom);
+        self.code.invalidate(PIF_ROM, self.pif_rom.len() as u32);
+        self.magic.set(0);
+        self.boot_done = false;
+        self.timers.cancel(PIF_TIMER);
     }
 
     fn cb_write_dma_status(&mut self, _old: u32, new: u32) {
@@ -123,24 +331,92 @@ impl Pi {
     fn cb_write_dma_wr_len(&mut self, _old: u32, val: u32) {
         let mut raddr = self.dma_rom_addr.get();
         let mut waddr = self.dma_ram_addr.get();
+        self.code.invalidate(waddr, val + 1);
         info!(self.logger, "DMA xfer"; o!(
             "src" => raddr.hex(),
             "dst" => waddr.hex(),
             "len" => val+1));
 
+        // DMA reads the cartridge directly, not through PI IO
         let bus = self.bus.borrow();
         let mut i = 0;
         while i < val + 1 {
-            bus.write::<u32>(waddr, bus.read::<u32>(raddr));
+            let data = if raddr.wrapping_sub(self.cart_base) < CART_SIZE {
+                self.cart_read(raddr)
+            } else {
+                bus.read::<u32>(raddr)
+            };
+            bus.write::<u32>(waddr, data);
             raddr = raddr + 4;
             waddr = waddr + 4;
             i += 4;
         }
         self.dma_rom_addr.set(raddr);
         self.dma_ram_addr.set(waddr);
+
+        // Data is copied immediately, but the transfer is reported as busy
+        // until it would have completed.
+        self.dma_status.set(self.dma_status.get() | STATUS_DMA_BUSY);
+        self.timers
+            .schedule(DMA_TIMER, Pi::dma_cycles(val + 1), RCP_CLOCK);
     }
 
     fn cb_write_dma_rd_len(&mut self, _old: u32, _new: u32) {
         unimplemented!()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn io_latch() {
+        let logger = slog::Logger::root(slog::Discard, o!());
+        let dir = env::temp_dir();
+        let piffn = dir.join("r64emu_pi_test.pif");
+        let romfn = dir.join("r64emu_pi_test.z64");
+        fs::write(&piffn, vec![0u8; 0x800]).unwrap();
+        let mut rom = vec![0u8; 0x1000];
+        rom[0] = 0x80;
+        rom[0x10..0x18].copy_from_slice(&[0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88]);
+        fs::write(&romfn, rom).unwrap();
+
+        let bus = Rc::new(RefCell::new(Bus::new(logger.clone())));
+        let pi = DevPtr::new(Pi::new(logger, bus.clone(), piffn.to_str().unwrap()).unwrap());
+        let cart = DevPtr::new(Cartridge::new(romfn.to_str().unwrap()).unwrap());
+        Pi::map_cartridge(&pi, &mut bus.borrow_mut(), 0x1000_0000, &cart).unwrap();
+        fs::remove_file(piffn).unwrap();
+        fs::remove_file(romfn).unwrap();
+
+        let stall = BusStall::new(RCP_CLOCK);
+        let mut pi = pi.clone();
+        let mut pi = pi.borrow_mut();
+        pi.set_bus_stall(stall.clone());
+
+        // Each word read stalls the CPU for the transfer
+        assert_eq!(pi.io_read(0x1000_0010, 4), 0x1122_3344);
+        assert_eq!(stall.take(), Pi::dma_cycles(4));
+        assert_eq!(pi.io_read(0x1000_0016, 2) as u16, 0x7788);
+        assert_eq!(pi.io_read(0x1000_0010, 8), 0x1122_3344_5566_7788);
+        assert_eq!(stall.take(), 3 * Pi::dma_cycles(4));
+
+        // During a write, reads return the written word
+        pi.io_write(0x1000_0000, 4, 0xDEAD_BEEF);
+        assert_eq!(pi.io_read(0x1000_0010, 4), 0xDEAD_BEEF);
+        assert_eq!(pi.io_read(0x1000_0012, 2) as u16, 0xBEEF);
+        assert_eq!(stall.take(), 0);
+        pi.io_done();
+        assert_eq!(pi.io_read(0x1000_0010, 4), 0x1122_3344);
+
+        // During a DMA, reads return the last word read through PI IO
+        pi.dma_rom_addr.set(0x1000_0000);
+        pi.dma_ram_addr.set(0x0000_0000);
+        pi.cb_write_dma_wr_len(0, 0x7F);
+        assert_eq!(pi.io_read(0x1000_0014, 4), 0x1122_3344);
+        pi.dma_done();
+        assert_eq!(pi.io_read(0x1000_0014, 4), 0x5566_7788);
+    }
+}
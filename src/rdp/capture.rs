@@ -0,0 +1,208 @@
+extern crate byteorder;
+extern crate emu;
+extern crate slog;
+use self::byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use super::Rdp;
+use emu::bus::be::{Bus, Mem};
+use emu::gfx::*;
+use errors::*;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::rc::Rc;
+
+const MAGIC: &[u8; 8] = b"R64RDPC1";
+
+// RDRAM is captured in pages, the first time the RDP references them.
+const PAGE_SIZE: u32 = 0x1000;
+
+const TMEM_SIZE: usize = 4096;
+
+// Size of RDRAM in the replay machine.
+const RDRAM_SIZE: usize = 0x40_0000;
+
+/// RdpCapture holds a sequence of RDP commands (typically one frame's worth),
+/// together with all their inputs: the RDP state and TMEM contents when the
+/// capture started, and the RDRAM pages referenced by the commands (as they
+/// were before the commands modified them). It can be saved into a
+/// standalone file, and replayed without the rest of the machine.
+pub struct RdpCapture {
+    // Last state commands (Set Color Image, Set Tile, etc.) issued before
+    // the capture, in issue order.
+    pub(crate) state: Vec<u64>,
+    pub(crate) tmem: Vec<u8>,
+    pub(crate) ram: BTreeMap<u32, Vec<u8>>,
+    pub(crate) cmds: Vec<u64>,
+}
+
+impl RdpCapture {
+    pub(crate) fn new(state: Vec<u64>, tmem: &[u8]) -> RdpCapture {
+        RdpCapture {
+            state,
+            tmem: tmem.to_vec(),
+            ram: BTreeMap::new(),
+            cmds: Vec::new(),
+        }
+    }
+
+    /// Number of captured commands (in 64-bit words).
+    pub fn len(&self) -> usize {
+        self.cmds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cmds.is_empty()
+    }
+
+    // Snapshot the RDRAM pages covering the specified range, unless they
+    // were already captured.
+    pub(crate) fn add_ram(&mut self, bus: &Bus, addr: u32, len: usize) {
+        if len == 0 {
+            return;
+        }
+        let first = addr / PAGE_SIZE;
+        let last = (addr + len as u32 - 1) / PAGE_SIZE;
+        for page in first..=last {
+            let paddr = page * PAGE_SIZE;
+            if self.ram.contains_key(&paddr) {
+                continue;
+            }
+            if let Some(mem) = bus.fetch_read::<u8>(paddr).mem() {
+                let mut data = mem[..mem.len().min(PAGE_SIZE as usize)].to_vec();
+                data.resize(PAGE_SIZE as usize, 0);
+                self.ram.insert(paddr, data);
+            }
+        }
+    }
+
+    pub fn save<W: Write>(&self, w: &mut W) -> Result<()> {
+        w.write_all(MAGIC)?;
+        w.write_u32::<LittleEndian>(self.state.len() as u32)?;
+        for cmd in &self.state {
+            w.write_u64::<LittleEndian>(*cmd)?;
+        }
+        w.write_all(&self.tmem)?;
+        w.write_u32::<LittleEndian>(self.ram.len() as u32)?;
+        for (addr, data) in &self.ram {
+            w.write_u32::<LittleEndian>(*addr)?;
+            w.write_all(data)?;
+        }
+        w.write_u32::<LittleEndian>(self.cmds.len() as u32)?;
+        for cmd in &self.cmds {
+            w.write_u64::<LittleEndian>(*cmd)?;
+        }
+        Ok(())
+    }
+
+    pub fn load<R: Read>(r: &mut R) -> Result<RdpCapture> {
+        let mut magic = [0u8; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            bail!("not a RDP capture file");
+        }
+
+        let read_cmds = |r: &mut R| -> Result<Vec<u64>> {
+            let n = r.read_u32::<LittleEndian>()?;
+            let mut cmds = Vec::new();
+            for _ in 0..n {
+                cmds.push(r.read_u64::<LittleEndian>()?);
+            }
+            Ok(cmds)
+        };
+
+        let state = read_cmds(r)?;
+        let mut tmem = vec![0u8; TMEM_SIZE];
+        r.read_exact(&mut tmem)?;
+        let mut ram = BTreeMap::new();
+        for _ in 0..r.read_u32::<LittleEndian>()? {
+            let addr = r.read_u32::<LittleEndian>()?;
+            if addr % PAGE_SIZE != 0 || addr as usize >= RDRAM_SIZE {
+                bail!("invalid RDRAM page in RDP capture: {:x}", addr);
+            }
+            let mut data = vec![0u8; PAGE_SIZE as usize];
+            r.read_exact(&mut data)?;
+            ram.insert(addr, data);
+        }
+        let cmds = read_cmds(r)?;
+
+        Ok(RdpCapture {
+            state,
+            tmem,
+            ram,
+            cmds,
+        })
+    }
+
+    /// Rasterize the captured commands on a standalone RDP, whose RDRAM only
+    /// contains the captured pages, and return the final color image.
+    pub fn replay(&self, logger: slog::Logger) -> Result<OwnedGfxBufferLE<Rgb888>> {
+        let bus = Rc::new(RefCell::new(Bus::new(logger.new(o!()))));
+        let ram = Mem::new(RDRAM_SIZE, Default::default());
+        bus.borrow_mut()
+            .map_mem(0, RDRAM_SIZE as u32 - 1, &ram)
+            .map_err(bus_error)?;
+        for (addr, data) in &self.ram {
+            ram.buf()[*addr as usize..][..data.len()].copy_from_slice(data);
+        }
+
+        let mut rdp = Rdp::new(logger, bus.clone());
+        rdp.replay(self);
+
+        // The color image is as tall as the framebuffers drawn by the RDP.
+        let (addr, width, bpp) = rdp.color_image();
+        let (height, pitch) = (240, width * bpp / 8);
+        let mem = ram.buf();
+        let src = &mem[addr as usize % RDRAM_SIZE..];
+        Ok(match bpp {
+            32 => OwnedGfxBufferLE::<Rgb888>::from_buf(&GfxBufferLE::<Rgb888>::new(
+                src, width, height, pitch,
+            )?),
+            _ => OwnedGfxBufferLE::<Rgb888>::from_buf(&GfxBufferLE::<Rgb555>::new(
+                src, width, height, pitch,
+            )?),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use slog;
+    use std::io::Cursor;
+
+    #[test]
+    fn capture_and_replay() {
+        let logger = slog::Logger::root(slog::Discard, o!());
+        let bus = Rc::new(RefCell::new(Bus::new(logger.new(o!()))));
+        let ram = Mem::new(RDRAM_SIZE, Default::default());
+        bus.borrow_mut()
+            .map_mem(0, RDRAM_SIZE as u32 - 1, &ram)
+            .unwrap();
+        let mut rdp = Rdp::new(logger.clone(), bus.clone());
+
+        // The color image (16-bit, 320 pixels wide, at 0x10000) and the fill
+        // mode are set before the capture starts.
+        rdp.op(0x3F10_013F_0001_0000);
+        rdp.op(0x2F30_0000_0000_0000);
+        rdp.start_capture();
+        rdp.op(0x3700_0000_F801_F801); // Set Fill Color
+        rdp.op(0x360B_C03C_0000_0000); // Fill Rectangle (0,0)-(47,15)
+        rdp.op(0x2900_0000_0000_0000); // Full Sync
+        let cap = rdp.take_capture().unwrap();
+        assert_eq!(cap.len(), 3);
+        assert_eq!(cap.state.len(), 2);
+
+        let mut file = Vec::new();
+        cap.save(&mut file).unwrap();
+        let cap = RdpCapture::load(&mut Cursor::new(file)).unwrap();
+        assert_eq!(cap.ram.len(), 38); // 320*240*2 bytes, starting at 0x10000
+        assert!(cap.ram.values().all(|p| p.iter().all(|&b| b == 0)));
+
+        let image = cap.replay(logger).unwrap();
+        let expected = OwnedGfxBufferLE::<Rgb888>::from_buf(
+            &GfxBufferLE::<Rgb555>::new(&ram.buf()[0x10000..], 320, 240, 640).unwrap(),
+        );
+        assert!(image.buf().raw().0 == expected.buf().raw().0);
+        assert!(image.buf().raw().0.iter().any(|&b| b != 0));
+    }
+}
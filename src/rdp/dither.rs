@@ -0,0 +1,155 @@
+// Dither
+//
+// When writing 16-bit color, the RDP truncates each 8-bit component to 5
+// bits. To hide the banding of gradients, a dither value (0..7) is selected
+// for each pixel, and the component is rounded up to the next multiple of 8
+// when its three discarded bits are greater than it.
+
+extern crate bit_field;
+extern crate emu;
+
+use self::bit_field::BitField;
+use emu::gfx::{Color, Rgba8888};
+
+// 4x4 dither matrices, indexed by the low bits of the pixel coordinates.
+const MAGIC_SQUARE: [u8; 16] = [0, 6, 1, 7, 4, 2, 5, 3, 3, 5, 2, 4, 7, 1, 6, 0];
+const BAYER: [u8; 16] = [0, 4, 1, 5, 4, 0, 5, 1, 3, 7, 2, 6, 7, 3, 6, 2];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) enum RgbDither {
+    MagicSquare,
+    Bayer,
+    Noise,
+    Disabled,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) enum AlphaDither {
+    Pattern,
+    InvPattern,
+    Noise,
+    Disabled,
+}
+
+pub(crate) struct Dither {
+    rgb: RgbDither,
+    alpha: AlphaDither,
+    // Cleared by the user to get the undithered look (per-game setting).
+    enabled: bool,
+    noise: u32,
+}
+
+impl Dither {
+    pub(crate) fn new() -> Dither {
+        Dither {
+            rgb: RgbDither::Disabled,
+            alpha: AlphaDither::Disabled,
+            enabled: true,
+            noise: 0x1234_5678,
+        }
+    }
+
+    pub(crate) fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub(crate) fn set_other_modes(&mut self, modes: u64) {
+        self.rgb = match modes.get_bits(38..40) {
+            0 => RgbDither::MagicSquare,
+            1 => RgbDither::Bayer,
+            2 => RgbDither::Noise,
+            _ => RgbDither::Disabled,
+        };
+        self.alpha = match modes.get_bits(36..38) {
+            0 => AlphaDither::Pattern,
+            1 => AlphaDither::InvPattern,
+            2 => AlphaDither::Noise,
+            _ => AlphaDither::Disabled,
+        };
+    }
+
+    // Pseudo-random dither value (xorshift), replacing the RDP noise
+    // generator.
+    fn next_noise(&mut self) -> u8 {
+        self.noise ^= self.noise << 13;
+        self.noise ^= self.noise >> 17;
+        self.noise ^= self.noise << 5;
+        (self.noise & 7) as u8
+    }
+
+    fn round(c: i32, dith: u8) -> i32 {
+        if (c & 7) > dith as i32 {
+            ((c & 0xF8) + 8).min(0xFF)
+        } else {
+            c
+        }
+    }
+
+    /// Dither a color that is about to be written into a 16-bit framebuffer
+    /// at the specified coordinates. Alpha uses the same pattern as RGB (or
+    /// Bayer, if RGB is not dithered with a pattern).
+    pub(crate) fn apply(&mut self, c: Color<Rgba8888>, x: usize, y: usize) -> Color<Rgba8888> {
+        if !self.enabled {
+            return c;
+        }
+        let idx = (y & 3) * 4 + (x & 3);
+        let (r, g, b, a) = c.components();
+        let (r, g, b) = match self.rgb {
+            RgbDither::MagicSquare => {
+                let d = MAGIC_SQUARE[idx];
+                (Self::round(r, d), Self::round(g, d), Self::round(b, d))
+            }
+            RgbDither::Bayer => {
+                let d = BAYER[idx];
+                (Self::round(r, d), Self::round(g, d), Self::round(b, d))
+            }
+            RgbDither::Noise => (
+                Self::round(r, self.next_noise()),
+                Self::round(g, self.next_noise()),
+                Self::round(b, self.next_noise()),
+            ),
+            RgbDither::Disabled => (r, g, b),
+        };
+        let pattern = match self.rgb {
+            RgbDither::MagicSquare => MAGIC_SQUARE[idx],
+            _ => BAYER[idx],
+        };
+        let a = match self.alpha {
+            AlphaDither::Pattern => Self::round(a, pattern),
+            AlphaDither::InvPattern => Self::round(a, !pattern & 7),
+            AlphaDither::Noise => {
+                let d = self.next_noise();
+                Self::round(a, d)
+            }
+            AlphaDither::Disabled => a,
+        };
+        Color::new_clamped(r, g, b, a)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dither() {
+        let mut d = Dither::new();
+        let c = Color::<Rgba8888>::new_clamped(0x13, 0x10, 0xFD, 0x85);
+
+        // Magic square, alpha with inverted pattern
+        d.set_other_modes(1 << 36);
+        assert_eq!(d.apply(c, 0, 0).components(), (0x18, 0x10, 0xFF, 0x85));
+        assert_eq!(d.apply(c, 1, 0).components(), (0x13, 0x10, 0xFD, 0x88));
+        assert_eq!(d.apply(c, 3, 1).components(), (0x13, 0x10, 0xFF, 0x88));
+
+        // Bayer, no alpha dithering
+        d.set_other_modes(1 << 38 | 3 << 36);
+        assert_eq!(d.apply(c, 1, 0).components(), (0x13, 0x10, 0xFF, 0x85));
+        assert_eq!(d.apply(c, 0, 3).components(), (0x13, 0x10, 0xFD, 0x85));
+
+        // Disabled by the user
+        d.set_other_modes(0);
+        d.set_enabled(false);
+        assert_eq!(d.apply(c, 0, 0).components(), c.components());
+    }
+}
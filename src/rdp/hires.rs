@@ -0,0 +1,301 @@
+extern crate emu;
+
+use self::emu::bus::be::Bus;
+
+/// A high-resolution copy of a framebuffer in RDRAM, used when rendering at
+/// an internal resolution higher than the native one. Drawing only happens
+/// in the copy, which is written back to RDRAM lazily, when the framebuffer
+/// is read (see HiresCache::flush).
+pub struct HiresFramebuffer {
+    dram_addr: u32,
+    width: usize,
+    height: usize,
+    bpp: usize,
+    scale: usize,
+
+    // Framebuffer at high resolution (width*scale x height*scale)
+    mem: Vec<u8>,
+    // Contents of the framebuffer in RDRAM, as last written back. It is used
+    // to detect whether the CPU modified the framebuffer behind our back.
+    lowres: Vec<u8>,
+    // Set when the copy was drawn into since the last write-back
+    dirty: bool,
+}
+
+impl HiresFramebuffer {
+    fn new(dram_addr: u32, width: usize, height: usize, bpp: usize, scale: usize) -> Self {
+        HiresFramebuffer {
+            dram_addr,
+            width,
+            height,
+            bpp,
+            scale,
+            mem: vec![0u8; width * height * bpp / 8 * scale * scale],
+            lowres: Vec::new(),
+            dirty: false,
+        }
+    }
+
+    fn pitch(&self) -> usize {
+        self.width * self.bpp / 8
+    }
+
+    // Whether the framebuffer overlaps the specified range of RDRAM.
+    fn overlaps(&self, addr: u32, len: usize) -> bool {
+        let begin = self.dram_addr as u64;
+        let end = begin + (self.pitch() * self.height) as u64;
+        begin < addr as u64 + len as u64 && (addr as u64) < end
+    }
+
+    // Copy a native pixel into its block of high-resolution pixels.
+    fn upscale_pixel(&mut self, x: usize, y: usize, px: &[u8]) {
+        let (bytes, pitch, scale) = (self.bpp / 8, self.pitch() * self.scale, self.scale);
+        for line in self.mem[y * scale * pitch..].chunks_mut(pitch).take(scale) {
+            for dst in line[x * scale * bytes..].chunks_mut(bytes).take(scale) {
+                dst.copy_from_slice(px);
+            }
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width * self.scale
+    }
+
+    pub fn height(&self) -> usize {
+        self.height * self.scale
+    }
+
+    /// Return the high-resolution framebuffer for drawing, in the same format
+    /// expected by the rasterizer (memory, width, height, pitch).
+    pub fn buffer(&mut self) -> (&mut [u8], usize, usize, usize) {
+        let (w, h, p) = (self.width(), self.height(), self.pitch() * self.scale);
+        self.dirty = true;
+        (&mut self.mem, w, h, p)
+    }
+
+    pub fn raw(&self) -> (&[u8], usize) {
+        (&self.mem, self.pitch() * self.scale)
+    }
+
+    /// Check whether the framebuffer in RDRAM still contains what was last
+    /// written back from the high-resolution copy.
+    pub fn is_current(&self, rdram: &[u8]) -> bool {
+        let size = self.lowres.len();
+        size > 0 && rdram.len() >= size && &rdram[..size] == &self.lowres[..]
+    }
+
+    /// Refresh the high-resolution copy from RDRAM, if the CPU modified it
+    /// since the last write-back. While the copy is dirty, this is deferred
+    /// to the next write-back, which merges the changes of both sides.
+    pub fn sync_from(&mut self, rdram: &[u8]) {
+        if self.dirty || self.is_current(rdram) {
+            return;
+        }
+        let (bytes, pitch, scale) = (self.bpp / 8, self.pitch(), self.scale);
+        let size = (pitch * self.height).min(rdram.len());
+        for (y, line) in self.mem.chunks_mut(pitch * scale).enumerate() {
+            let src = &rdram[(y / scale * pitch).min(size)..size];
+            for (x, px) in line.chunks_mut(bytes).enumerate() {
+                let sx = x / scale * bytes;
+                if sx + bytes <= src.len() {
+                    px.copy_from_slice(&src[sx..sx + bytes]);
+                }
+            }
+        }
+        self.lowres = rdram[..size].to_vec();
+    }
+
+    /// Downsample the high-resolution copy into RDRAM, so that games that
+    /// read back the framebuffer see the expected contents. Each native pixel
+    /// takes the value of the top-left pixel of its block, which keeps the
+    /// operation independent of the pixel format. Pixels modified by the CPU
+    /// since the last write-back are kept, and copied into the high-resolution
+    /// copy instead.
+    pub fn write_back(&mut self, rdram: &mut [u8]) {
+        let (bytes, pitch, scale) = (self.bpp / 8, self.pitch(), self.scale);
+        let size = (pitch * self.height).min(rdram.len());
+        let lowres = ::std::mem::replace(&mut self.lowres, Vec::new());
+        for (y, line) in rdram[..size].chunks_mut(pitch).enumerate() {
+            for (x, px) in line.chunks_mut(bytes).enumerate() {
+                let off = y * pitch + x * bytes;
+                if lowres.len() >= off + px.len() && lowres[off..off + px.len()] != px[..] {
+                    self.upscale_pixel(x, y, px);
+                } else {
+                    let sx = (y * scale * pitch + x * bytes) * scale;
+                    px.copy_from_slice(&self.mem[sx..sx + px.len()]);
+                }
+            }
+        }
+        self.lowres = rdram[..size].to_vec();
+        self.dirty = false;
+    }
+}
+
+/// HiresCache holds the high-resolution copies of the framebuffers drawn by
+/// the RDP. It is shared with the VI, that displays them in place of the
+/// native framebuffers.
+#[derive(Default)]
+pub struct HiresCache {
+    fbs: Vec<HiresFramebuffer>,
+    // Address of the depth buffer (Set Z Image). It is cleared through the
+    // color image like any framebuffer, so it gets a high-resolution copy
+    // too, which is never displayed, and kept over the color buffers.
+    zbuf: Option<u32>,
+}
+
+// Games use at most triple buffering, plus some offscreen buffers and the
+// depth buffer.
+const MAX_FRAMEBUFFERS: usize = 5;
+
+impl HiresCache {
+    pub fn new() -> HiresCache {
+        HiresCache::default()
+    }
+
+    pub fn set_depth_buffer(&mut self, dram_addr: u32) {
+        self.zbuf = Some(dram_addr);
+    }
+
+    /// Return the high-resolution copy of the specified framebuffer,
+    /// creating it if needed. Copies that are replaced or evicted are
+    /// written back first.
+    pub fn framebuffer(
+        &mut self,
+        bus: &Bus,
+        dram_addr: u32,
+        width: usize,
+        height: usize,
+        bpp: usize,
+        scale: usize,
+    ) -> &mut HiresFramebuffer {
+        let found = self.fbs.iter().position(|fb| {
+            (fb.dram_addr, fb.width, fb.height, fb.bpp, fb.scale)
+                == (dram_addr, width, height, bpp, scale)
+        });
+        let idx = match found {
+            Some(idx) => idx,
+            None => {
+                if let Some(idx) = self.fbs.iter().position(|fb| fb.dram_addr == dram_addr) {
+                    HiresCache::write_back(bus, &mut self.fbs.remove(idx));
+                }
+                if self.fbs.len() >= MAX_FRAMEBUFFERS {
+                    let zbuf = self.zbuf;
+                    let idx = self
+                        .fbs
+                        .iter()
+                        .position(|fb| Some(fb.dram_addr) != zbuf)
+                        .unwrap_or(0);
+                    HiresCache::write_back(bus, &mut self.fbs.remove(idx));
+                }
+                self.fbs.push(HiresFramebuffer::new(
+                    dram_addr, width, height, bpp, scale,
+                ));
+                self.fbs.len() - 1
+            }
+        };
+        &mut self.fbs[idx]
+    }
+
+    fn write_back(bus: &Bus, fb: &mut HiresFramebuffer) {
+        if fb.dirty {
+            if let Some(rdram) = bus.fetch_write::<u8>(fb.dram_addr).mem() {
+                fb.write_back(rdram);
+            }
+        }
+    }
+
+    /// Write back the framebuffers drawn since their last write-back that
+    /// overlap the specified range of RDRAM, before it is read (by the VI,
+    /// the RDP itself, or the CPU after a full sync).
+    pub fn flush(&mut self, bus: &Bus, addr: u32, len: usize) {
+        for fb in self.fbs.iter_mut().filter(|fb| fb.overlaps(addr, len)) {
+            HiresCache::write_back(bus, fb);
+        }
+    }
+
+    /// Find the high-resolution copy of the framebuffer at the specified
+    /// address, if it is still current. The depth buffer is never displayed.
+    pub fn find(&self, dram_addr: u32, rdram: &[u8]) -> Option<&HiresFramebuffer> {
+        self.fbs.iter().find(|fb| {
+            fb.dram_addr == dram_addr && Some(dram_addr) != self.zbuf && fb.is_current(rdram)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use emu::bus::be::{Mem, MemFlags};
+    use slog;
+
+    #[test]
+    fn hires_roundtrip() {
+        let mut rdram: Vec<u8> = (0..4 * 2 * 2).map(|v| v as u8).collect();
+        let mut fb = HiresFramebuffer::new(0x100, 4, 2, 16, 2);
+        fb.sync_from(&rdram);
+        assert!(fb.is_current(&rdram));
+        {
+            let (mem, w, h, pitch) = fb.buffer();
+            assert_eq!((w, h, pitch), (8, 4, 16));
+            // Each native pixel is replicated in a 2x2 block
+            assert_eq!(&mem[0..8], &[0, 1, 0, 1, 2, 3, 2, 3]);
+            assert_eq!(&mem[16..24], &[0, 1, 0, 1, 2, 3, 2, 3]);
+            assert_eq!(&mem[32..36], &[8, 9, 8, 9]);
+
+            // Draw at high resolution
+            mem[2] = 0xAA;
+            mem[4] = 0xBB;
+        }
+
+        // While the copy is dirty, changes by the CPU are not synced, but
+        // merged at write-back
+        rdram[6] = 0xCC;
+        fb.sync_from(&rdram);
+        fb.write_back(&mut rdram);
+        assert_eq!(&rdram[0..8], &[0, 1, 0xBB, 3, 4, 5, 0xCC, 7]);
+        assert!(fb.is_current(&rdram));
+        let (mem, _) = fb.raw();
+        assert_eq!(&mem[12..16], &[0xCC, 7, 0xCC, 7]);
+        assert_eq!(&mem[28..32], &[0xCC, 7, 0xCC, 7]);
+    }
+
+    #[test]
+    fn lazy_write_back() {
+        let logger = slog::Logger::root(slog::Discard, o!());
+        let ram = Mem::new(0x1000, MemFlags::default());
+        let mut bus = Bus::new(logger);
+        bus.map_mem(0x0000_0000, 0x0000_0FFF, &ram).unwrap();
+
+        let mut cache = HiresCache::new();
+        {
+            let fb = cache.framebuffer(&bus, 0x100, 4, 2, 16, 2);
+            fb.sync_from(bus.fetch_read::<u8>(0x100).mem().unwrap());
+            fb.buffer().0[0] = 0xAA;
+        }
+
+        // Drawing is not written back until the framebuffer is read
+        assert_eq!(bus.read::<u8>(0x100), 0);
+        cache.flush(&bus, 0x200, 0x10);
+        assert_eq!(bus.read::<u8>(0x100), 0);
+        cache.flush(&bus, 0x0F0, 0x11);
+        assert_eq!(bus.read::<u8>(0x100), 0xAA);
+        assert!(cache.find(0x100, bus.fetch_read::<u8>(0x100).mem().unwrap()).is_some());
+
+        // The CPU modifies the framebuffer: the high-resolution copy is stale
+        bus.write::<u8>(0x101, 0xFF);
+        assert!(cache.find(0x100, bus.fetch_read::<u8>(0x100).mem().unwrap()).is_none());
+        assert!(cache.find(0x200, bus.fetch_read::<u8>(0x200).mem().unwrap()).is_none());
+
+        // The depth buffer is not evicted by color buffers, and is never
+        // displayed
+        cache.set_depth_buffer(0x800);
+        cache.framebuffer(&bus, 0x800, 4, 2, 16, 2).buffer().0[0] = 0x55;
+        for idx in 0..MAX_FRAMEBUFFERS as u32 {
+            cache.framebuffer(&bus, 0x100 + idx * 0x20, 4, 2, 16, 2);
+        }
+        assert_eq!(bus.read::<u8>(0x800), 0);
+        cache.flush(&bus, 0x800, 1);
+        assert_eq!(bus.read::<u8>(0x800), 0x55);
+        assert!(cache.find(0x800, bus.fetch_read::<u8>(0x800).mem().unwrap()).is_none());
+    }
+}
@@ -106,10 +106,17 @@ impl DpColorFormat {
 }
 
 mod bl;
+mod capture;
 mod cc;
+mod dither;
+mod hires;
 mod pipeline;
 mod raster;
 mod rdp;
+mod texpack;
 
+pub use self::capture::RdpCapture;
+pub use self::hires::HiresCache;
 pub use self::pipeline::PixelPipeline;
 pub use self::rdp::Rdp;
+pub use self::texpack::TexturePack;
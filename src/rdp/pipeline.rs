@@ -1,12 +1,14 @@
 extern crate emu;
 use super::bl::Blender;
 use super::cc::Combiner;
+use super::dither::Dither;
 use super::MultiColor;
 use emu::gfx::{Color, Rgba8888};
 
 pub struct PixelPipeline {
     cc: Combiner,
     bl: Blender,
+    dither: Dither,
 }
 
 impl PixelPipeline {
@@ -14,6 +16,7 @@ impl PixelPipeline {
         PixelPipeline {
             cc: Combiner::new(),
             bl: Blender::new(),
+            dither: Dither::new(),
         }
     }
 
@@ -25,6 +28,12 @@ impl PixelPipeline {
         return blended;
     }
 
+    // Dither a pixel written into a 16-bit framebuffer.
+    #[inline(always)]
+    pub fn dither(&mut self, c: Color<Rgba8888>, x: usize, y: usize) -> Color<Rgba8888> {
+        self.dither.apply(c, x, y)
+    }
+
     pub fn set_combine_mode(&mut self, mode: u64) {
         self.cc.set_mode(mode);
     }
@@ -39,6 +48,10 @@ impl PixelPipeline {
     }
     pub fn set_other_modes(&mut self, modes: u64) {
         self.bl.set_other_modes(modes);
+        self.dither.set_other_modes(modes);
+    }
+    pub fn set_dither(&mut self, enabled: bool) {
+        self.dither.set_enabled(enabled);
     }
 
     pub fn fmt_combiner(&self) -> String {
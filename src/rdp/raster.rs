@@ -38,6 +38,7 @@ pub fn fill_rect_pp<'a, 'b, CF1, CF2, FP1, O1>(
     dr: Rect<FP1>,
     color: Color<CF2>,
     pp: &mut PixelPipeline,
+    dither: bool,
 ) where
     CF1: ColorFormat,
     CF2: ColorFormat,
@@ -60,7 +61,11 @@ pub fn fill_rect_pp<'a, 'b, CF1, CF2, FP1, O1>(
                     cres
                 ));
             }
-            dst.set(didx, cres.get_color(0));
+            let mut c = cres.get_color::<Rgba8888>(0);
+            if dither {
+                c = pp.dither(c, didx, dy.to_usize().unwrap());
+            }
+            dst.set(didx, c.cconv());
         }
     }
 }
@@ -214,6 +219,15 @@ impl<FPXY: FixedPoint, FPST: FixedPoint> RenderState<FPXY, FPST> {
             DpColorFormat::Intensity if self.src_bpp == 8 => {
                 self.draw_rect_slopes2::<CF1, I8, BigEndian>(dst, dr, src, st, dsdt)
             }
+            DpColorFormat::IntensityAlpha if self.src_bpp == 4 => {
+                self.draw_rect_slopes2::<CF1, Ia31, BigEndian>(dst, dr, src, st, dsdt)
+            }
+            DpColorFormat::IntensityAlpha if self.src_bpp == 8 => {
+                self.draw_rect_slopes2::<CF1, Ia44, BigEndian>(dst, dr, src, st, dsdt)
+            }
+            DpColorFormat::IntensityAlpha if self.src_bpp == 16 => {
+                self.draw_rect_slopes2::<CF1, Ia88, BigEndian>(dst, dr, src, st, dsdt)
+            }
             _ => panic!(
                 "unimplemented src color format: {:?}/{}",
                 self.src_cf, self.src_bpp
@@ -221,6 +235,43 @@ impl<FPXY: FixedPoint, FPST: FixedPoint> RenderState<FPXY, FPST> {
         }
     }
 
+    #[inline]
+    fn draw_rect_slopes_rgba1<CF1: ColorFormat>(
+        &self,
+        dst: (&mut [u8], usize, usize, usize),
+        dr: Rect<FPXY>,
+        src: &GfxBufferLE<Rgba8888>,
+        st: Point<FPST>,
+        dsdt: Point<FPST>,
+    ) {
+        let mut dst = GfxBufferMut::<CF1, LittleEndian>::new(dst.0, dst.1, dst.2, dst.3).unwrap();
+        draw_rect_slopes(&mut dst, dr, src, st, dsdt);
+    }
+
+    // Like draw_rect_slopes, but sample a texture already decoded to RGBA
+    // (eg: a replacement texture) instead of TMEM.
+    pub fn draw_rect_slopes_rgba(
+        &self,
+        dst: (&mut [u8], usize, usize, usize),
+        dr: Rect<FPXY>,
+        src: &GfxBufferLE<Rgba8888>,
+        st: Point<FPST>,
+        dsdt: Point<FPST>,
+    ) {
+        match self.dst_cf {
+            DpColorFormat::Rgba if self.dst_bpp == 32 => {
+                self.draw_rect_slopes_rgba1::<Rgb888>(dst, dr, src, st, dsdt)
+            }
+            DpColorFormat::Rgba if self.dst_bpp == 16 => {
+                self.draw_rect_slopes_rgba1::<Rgb555>(dst, dr, src, st, dsdt)
+            }
+            _ => panic!(
+                "unimplemented dst color format: {:?}/{}",
+                self.dst_cf, self.dst_bpp
+            ),
+        }
+    }
+
     pub fn draw_rect_slopes(
         &self,
         dst: (&mut [u8], usize, usize, usize),
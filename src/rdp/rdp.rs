@@ -5,14 +5,19 @@ extern crate slog;
 use self::bit_field::BitField;
 use self::byteorder::{BigEndian, LittleEndian};
 use self::emu::bus::be::Bus;
+use super::capture::RdpCapture;
+use super::hires::HiresCache;
 use super::pipeline::PixelPipeline;
 use super::raster::{draw_rect, fill_rect, fill_rect_pp, DpRenderState};
+use super::texpack::{TexturePack, TmemTexture};
 use super::{CycleMode, DpColorFormat};
 use emu::fp::formats::*;
 use emu::fp::Q;
 use emu::gfx::*;
 use emu::int::Numerics;
+use interrupts::{Interrupt, InterruptLog};
 use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::marker::PhantomData;
 use std::rc::Rc;
 
@@ -57,9 +62,31 @@ pub struct Rdp {
     cycle_mode: CycleMode,
 
     pipeline: PixelPipeline,
+    texpack: TexturePack,
+
+    // Internal resolution scaling
+    scale: usize,
+    hires: Rc<RefCell<HiresCache>>,
+
+    ints: InterruptLog,
 
     cmdbuf: [u64; 16],
     cmdlen: usize,
+
+    // Last state command of each kind (with its sequence number), needed to
+    // start a capture from the current state.
+    state: BTreeMap<u32, (u64, u64)>,
+    state_seq: u64,
+    capture: Option<RdpCapture>,
+}
+
+// Rectangle of a Load Tile command, in 10.2 texel coordinates.
+fn tile_rect(cmd: u64) -> Rect<U30F2> {
+    let s0 = cmd.get_bits(44..56) as u32;
+    let t0 = cmd.get_bits(32..44) as u32;
+    let s1 = cmd.get_bits(12..24) as u32;
+    let t1 = cmd.get_bits(0..12) as u32;
+    Rect::<U30F2>::from_bits(s0, t0, s1, t1)
 }
 
 impl Rdp {
@@ -77,8 +104,106 @@ impl Rdp {
             fill_color: 0,
             cycle_mode: CycleMode::One,
             pipeline: PixelPipeline::new(),
+            texpack: TexturePack::new(),
+            scale: 1,
+            hires: Rc::new(RefCell::new(HiresCache::new())),
+            ints: InterruptLog::new(),
             cmdbuf: [0u64; 16],
             cmdlen: 0,
+            state: BTreeMap::new(),
+            state_seq: 0,
+            capture: None,
+        }
+    }
+
+    pub fn set_texture_pack(&mut self, texpack: TexturePack) {
+        self.texpack = texpack;
+    }
+
+    // Set the internal resolution scale (1 = native resolution).
+    pub fn set_resolution_scale(&mut self, scale: usize) {
+        self.scale = scale.max(1);
+    }
+
+    // Enable dithering of 16-bit color (when selected by the other modes).
+    pub fn set_dither(&mut self, enabled: bool) {
+        self.pipeline.set_dither(enabled);
+    }
+
+    pub fn set_interrupt_log(&mut self, ints: InterruptLog) {
+        self.ints = ints;
+    }
+
+    pub fn hires_cache(&self) -> Rc<RefCell<HiresCache>> {
+        self.hires.clone()
+    }
+
+    // Start capturing the commands, together with the current state, TMEM,
+    // and the RDRAM they reference.
+    pub fn start_capture(&mut self) {
+        let mut state: Vec<(u64, u64)> = self.state.values().cloned().collect();
+        state.sort();
+        let state = state.into_iter().map(|(_, cmd)| cmd).collect();
+        let mut cap = RdpCapture::new(state, &self.tmem);
+        // A command might be partially received
+        cap.cmds.extend_from_slice(&self.cmdbuf[..self.cmdlen]);
+        self.capture = Some(cap);
+    }
+
+    pub fn take_capture(&mut self) -> Option<RdpCapture> {
+        self.capture.take()
+    }
+
+    pub fn tmem_mut(&mut self) -> &mut [u8] {
+        &mut self.tmem
+    }
+
+    // Rasterize a capture: restore the state and TMEM, then run the commands.
+    pub(crate) fn replay(&mut self, cap: &RdpCapture) {
+        for &cmd in &cap.state {
+            match cmd.get_bits(56..62) {
+                // Only restore the tile rect: TMEM contents are restored below
+                0x34 => self.tiles[cmd.get_bits(24..27) as usize].rect = tile_rect(cmd),
+                _ => self.op(cmd),
+            }
+        }
+        self.tmem.copy_from_slice(&cap.tmem);
+        for &cmd in &cap.cmds {
+            self.op(cmd);
+        }
+    }
+
+    // Address, width and bpp of the current color image.
+    pub(crate) fn color_image(&self) -> (u32, usize, usize) {
+        (self.fb.dram_addr, self.fb.width, self.fb.bpp)
+    }
+
+    fn track_state(&mut self, op: u64, cmd: u64) {
+        let key = match op {
+            0x2D | 0x2F | 0x37 | 0x39 | 0x3C | 0x3D | 0x3E | 0x3F => op as u32,
+            0x34 | 0x35 => (op as u32) << 8 | cmd.get_bits(24..27) as u32,
+            _ => return,
+        };
+        self.state_seq += 1;
+        self.state.insert(key, (self.state_seq, cmd));
+    }
+
+    // Make a range of RDRAM current before the RDP reads it: write back the
+    // high-resolution framebuffers drawn since, and snapshot the range while
+    // capturing.
+    fn read_ram(&mut self, addr: u32, len: usize) {
+        if self.scale > 1 {
+            self.hires
+                .borrow_mut()
+                .flush(&self.main_bus.borrow(), addr, len);
+        }
+        self.capture_ram(addr, len);
+    }
+
+    // While capturing, snapshot the RDRAM range read or written by a command.
+    fn capture_ram(&mut self, addr: u32, len: usize) {
+        if let Some(ref mut cap) = self.capture {
+            cap.add_ram(&self.main_bus.borrow(), addr, len);
         }
     }
 
@@ -101,11 +226,52 @@ impl Rdp {
         (fb_mem, 320, 240, self.fb.pitch())
     }
 
+    // Run a drawing operation on the current color image. With internal
+    // resolution scaling, the operation draws into the high-resolution copy
+    // of the framebuffer (and receives the scale to adjust its coordinates);
+    // the result is downsampled back into RDRAM only when it is read: at
+    // full sync, when scanned out by the VI, or when loaded as a texture.
+    fn draw<F>(&mut self, f: F)
+    where
+        F: FnOnce(&mut Rdp, (&mut [u8], usize, usize, usize), usize),
+    {
+        if self.capture.is_some() {
+            let (addr, len) = (self.fb.dram_addr, self.fb.pitch() * 240);
+            self.read_ram(addr, len);
+        }
+        let fb = self.framebuffer();
+        let scale = self.scale;
+        if scale == 1 {
+            f(self, fb, 1);
+            return;
+        }
+
+        let hires = self.hires.clone();
+        let mut hires = hires.borrow_mut();
+        let bus = self.main_bus.clone();
+        let hfb = hires.framebuffer(
+            &bus.borrow(),
+            self.fb.dram_addr,
+            fb.1,
+            fb.2,
+            self.fb.bpp,
+            scale,
+        );
+        hfb.sync_from(fb.0);
+        f(self, hfb.buffer(), scale);
+    }
+
     pub fn op(&mut self, cmd: u64) {
+        if let Some(ref mut cap) = self.capture {
+            cap.cmds.push(cmd);
+        }
         self.cmdbuf[self.cmdlen] = cmd;
         self.cmdlen += 1;
 
         let op = self.cmdbuf[0].get_bits(56..62);
+        if self.cmdlen == 1 {
+            self.track_state(op, cmd);
+        }
         match op {
             0x2D => {
                 // Set Scissor
@@ -136,6 +302,25 @@ impl Rdp {
                 }
                 self.cmdlen = 0;
             }
+            0x3E => {
+                // Set Z Image
+                let addr = cmd.get_bits(0..26) as u32;
+                info!(self.logger, "DP: Set Z Image"; "addr" => addr.hex());
+                self.hires.borrow_mut().set_depth_buffer(addr);
+                self.cmdlen = 0;
+            }
+            0x29 => {
+                // Full Sync: all drawing is complete, raise the DP interrupt.
+                // The CPU can now read the framebuffers.
+                info!(self.logger, "DP: Full Sync");
+                if self.scale > 1 {
+                    self.hires
+                        .borrow_mut()
+                        .flush(&self.main_bus.borrow(), 0, 1 << 32);
+                }
+                self.ints.raise(Interrupt::Dp);
+                self.cmdlen = 0;
+            }
             0x28 => {
                 // Sync Tile
                 info!(self.logger, "DP: Sync Tile");
@@ -165,7 +350,7 @@ impl Rdp {
                 let y1 = self.cmdbuf[0].get_bits(32..44) as u32;
                 let x0 = self.cmdbuf[0].get_bits(12..24) as u32;
                 let y0 = self.cmdbuf[0].get_bits(0..12) as u32;
-                let mut rect = Rect::<U30F2>::from_bits(x0, y0, x1, y1);
+                let rect = Rect::<U30F2>::from_bits(x0, y0, x1, y1);
 
                 let s = Q::<I6F10>::from_bits(self.cmdbuf[1].get_bits(48..64) as i16);
                 let t = Q::<I6F10>::from_bits(self.cmdbuf[1].get_bits(32..48) as i16);
@@ -179,22 +364,18 @@ impl Rdp {
                 let tmem_addr = self.tiles[tile].tmem_addr as usize;
                 let tmem_pitch = self.tiles[tile].pitch;
                 let tex_rect = self.tiles[tile].rect;
-                let src = (
-                    &self.tmem[tmem_addr..],
-                    tex_rect.width().floor() as usize + 1,
-                    tex_rect.height().floor() as usize + 1,
-                    tmem_pitch,
-                );
-
-                let fb_writer = self.main_bus.borrow().fetch_write::<u8>(self.fb.dram_addr);
-                let mut fb_mem = fb_writer.mem().unwrap();
-                let dst = (fb_mem, 320, 240, self.fb.pitch());
 
-                // FIXME: draw_rect_slopes() use inclusive rectangles... maybe we need clipping?
-                let w = rect.width() - 1;
-                let h = rect.height() - 1;
-                rect.set_width(w);
-                rect.set_height(h);
+                let repl = self.texpack.texture_used(
+                    &self.logger,
+                    &TmemTexture {
+                        mem: &self.tmem[tmem_addr..],
+                        width: tex_rect.width().floor() as usize + 1,
+                        height: tex_rect.height().floor() as usize + 1,
+                        pitch: tmem_pitch,
+                        color_format: self.tiles[tile].color_format,
+                        bpp: self.tiles[tile].bpp,
+                    },
+                );
 
                 let state = DpRenderState {
                     dst_cf: self.fb.color_format,
@@ -203,18 +384,44 @@ impl Rdp {
                     src_bpp: self.tiles[tile].bpp,
                     phantom: PhantomData,
                 };
-                state.draw_rect_slopes(dst, rect, src, ptex.cast(), slope.cast());
+
+                self.draw(|rdp, dst, scale| {
+                    let src = (
+                        &rdp.tmem[tmem_addr..],
+                        tex_rect.width().floor() as usize + 1,
+                        tex_rect.height().floor() as usize + 1,
+                        tmem_pitch,
+                    );
+
+                    // FIXME: draw_rect_slopes() use inclusive rectangles... maybe we need clipping?
+                    let mut rect = rect.scale(scale as u32);
+                    let w = rect.width() - 1;
+                    let h = rect.height() - 1;
+                    rect.set_width(w);
+                    rect.set_height(h);
+
+                    let slope = Point::new(slope.x / scale as i16, slope.y / scale as i16);
+                    match repl {
+                        // A replacement texture covers the same texture
+                        // space at a higher resolution: scale the texture
+                        // coordinates by its factor.
+                        Some((ref img, factor)) => state.draw_rect_slopes_rgba(
+                            dst,
+                            rect,
+                            &img.buf(),
+                            ptex.cast::<I22F10>().scale(factor as i32),
+                            slope.cast::<I22F10>().scale(factor as i32),
+                        ),
+                        None => state.draw_rect_slopes(dst, rect, src, ptex.cast(), slope.cast()),
+                    }
+                });
 
                 self.cmdlen = 0;
             }
             0x34 => {
                 // Load Tile
                 let tile = cmd.get_bits(24..27) as usize;
-                let s0 = cmd.get_bits(44..56) as u32;
-                let t0 = cmd.get_bits(32..44) as u32;
-                let s1 = cmd.get_bits(12..24) as u32;
-                let t1 = cmd.get_bits(0..12) as u32;
-                let mut rect = Rect::<U30F2>::from_bits(s0, t0, s1, t1);
+                let mut rect = tile_rect(cmd);
                 info!(self.logger, "DP: Load Tile"; "idx" => tile, "rect" => ?rect);
 
                 // Load_Tile also updates the internal tile rect
@@ -222,6 +429,11 @@ impl Rdp {
 
                 let tmem_addr = self.tiles[tile].tmem_addr as usize;
                 let tmem_pitch = self.tiles[tile].pitch;
+                let (addr, len) = (
+                    self.tex.dram_addr,
+                    self.tex.pitch() * (rect.c1.y.floor() as usize + 1),
+                );
+                self.read_ram(addr, len);
                 let tex_reader = self.main_bus.borrow().fetch_read::<u8>(self.tex.dram_addr);
                 let tex_mem = tex_reader.mem().unwrap();
                 let width = rect.width().floor() as usize + 1;
@@ -305,7 +517,7 @@ impl Rdp {
                 let y1 = cmd.get_bits(32..44) as u32;
                 let x0 = cmd.get_bits(12..24) as u32;
                 let y0 = cmd.get_bits(0..12) as u32;
-                let mut rect = Rect::<U30F2>::from_bits(x0, y0, x1, y1);
+                let rect = Rect::<U30F2>::from_bits(x0, y0, x1, y1);
                 info!(self.logger, "DP: Fill Rectangle"; "rect" => ?rect);
 
                 match self.cycle_mode {
@@ -314,38 +526,52 @@ impl Rdp {
                         // as RGBA8888, but we need to convert the rect coordinates to adjust them
                         // to a fake 32-bit resolution.
                         let bppconv = 32 / self.fb.bpp as u32;
-
-                        rect.c0.x /= bppconv;
-                        rect.c0.y /= bppconv;
-                        rect.c1.x = ((rect.c1.x + 1) / bppconv) - 1;
-                        rect.c1.y = ((rect.c1.y + 1) / bppconv) - 1;
-
-                        if rect.truncate().cast::<U30F2>() != rect {
-                            panic!("Coordinates in DP Fill Rectangle were not 32-bit aligned");
-                        }
-
-                        let fb = self.framebuffer();
-                        let mut dst = GfxBufferMut::<Rgba8888, BigEndian>::new(
-                            fb.0,
-                            fb.1 / bppconv as usize,
-                            fb.2,
-                            fb.3,
-                        ).unwrap();
                         let color = Color::<Rgba8888>::from_bits(self.fill_color);
-                        fill_rect(&mut dst, rect, color);
+
+                        self.draw(|_, fb, scale| {
+                            // The rectangle is inclusive: scale so that it covers
+                            // whole blocks of high-resolution pixels.
+                            let mut rect = rect;
+                            if scale > 1 {
+                                let scale = scale as u32;
+                                rect.c0 = rect.c0.scale(scale);
+                                rect.c1 = (rect.c1 + Point::from_int(1, 1)).scale(scale)
+                                    - Point::from_int(1, 1);
+                            }
+
+                            rect.c0.x /= bppconv;
+                            rect.c0.y /= bppconv;
+                            rect.c1.x = ((rect.c1.x + 1) / bppconv) - 1;
+                            rect.c1.y = ((rect.c1.y + 1) / bppconv) - 1;
+
+                            if rect.truncate().cast::<U30F2>() != rect {
+                                panic!("Coordinates in DP Fill Rectangle were not 32-bit aligned");
+                            }
+
+                            let mut dst = GfxBufferMut::<Rgba8888, BigEndian>::new(
+                                fb.0,
+                                fb.1 / bppconv as usize,
+                                fb.2,
+                                fb.3,
+                            )
+                            .unwrap();
+                            fill_rect(&mut dst, rect, color);
+                        });
                     }
                     CycleMode::One => {
-                        let fb = self.framebuffer();
-                        let mut dst = GfxBufferMut::<Rgba8888, LittleEndian>::new(
-                            fb.0, fb.1, fb.2, fb.3,
-                        ).unwrap();
-
                         if rect.truncate().cast::<U30F2>() != rect {
                             panic!("Coordinates in DP Fill Rectangle were not 32-bit aligned");
                         }
 
                         let color = Color::<Abgr8888>::from_bits(self.fill_color); // FIXME: this is probably not correct
-                        fill_rect_pp(&mut dst, rect, color, &mut self.pipeline);
+                        let dither = self.fb.bpp == 16;
+                        self.draw(|rdp, fb, scale| {
+                            let mut dst =
+                                GfxBufferMut::<Rgba8888, LittleEndian>::new(fb.0, fb.1, fb.2, fb.3)
+                                    .unwrap();
+                            let rect = rect.scale(scale as u32);
+                            fill_rect_pp(&mut dst, rect, color, &mut rdp.pipeline, dither);
+                        });
                     }
                     _ => unimplemented!(),
                 }
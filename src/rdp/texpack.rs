@@ -0,0 +1,263 @@
+extern crate byteorder;
+extern crate crc;
+extern crate emu;
+extern crate slog;
+use self::byteorder::BigEndian;
+use self::crc::crc64;
+use super::DpColorFormat;
+use emu::gfx::*;
+use emu::hw;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// A texture as found in TMEM when it is used for drawing.
+pub(crate) struct TmemTexture<'a> {
+    pub(crate) mem: &'a [u8],
+    pub(crate) width: usize,
+    pub(crate) height: usize,
+    pub(crate) pitch: usize,
+    pub(crate) color_format: DpColorFormat,
+    pub(crate) bpp: usize,
+}
+
+impl<'a> TmemTexture<'a> {
+    // Hash of the texture contents. Only the visible part of each line is
+    // hashed (padding in TMEM is not significant), together with the format,
+    // so that the same data interpreted differently is a different texture.
+    fn hash(&self) -> Option<u64> {
+        let linesize = (self.width * self.bpp + 7) / 8;
+        if self.height == 0 || self.mem.len() < (self.height - 1) * self.pitch + linesize {
+            return None;
+        }
+        let mut data = Vec::with_capacity(linesize * self.height + 8);
+        for y in 0..self.height {
+            data.extend_from_slice(&self.mem[y * self.pitch..][..linesize]);
+        }
+        data.push(self.color_format as u8);
+        data.push(self.bpp as u8);
+        Some(crc64::checksum_ecma(&data))
+    }
+
+    fn decode2<CF: ColorFormat>(&self) -> Option<OwnedGfxBufferLE<Rgba8888>> {
+        GfxBuffer::<CF, BigEndian>::new(self.mem, self.width, self.height, self.pitch)
+            .ok()
+            .map(|src| OwnedGfxBufferLE::<Rgba8888>::from_buf(&src))
+    }
+
+    // Convert the texture to RGBA, for the formats supported by the rasterizer.
+    fn decode(&self) -> Option<OwnedGfxBufferLE<Rgba8888>> {
+        match (self.color_format, self.bpp) {
+            (DpColorFormat::Intensity, 4) => self.decode2::<I4>(),
+            (DpColorFormat::Intensity, 8) => self.decode2::<I8>(),
+            (DpColorFormat::IntensityAlpha, 4) => self.decode2::<Ia31>(),
+            (DpColorFormat::IntensityAlpha, 8) => self.decode2::<Ia44>(),
+            (DpColorFormat::IntensityAlpha, 16) => self.decode2::<Ia88>(),
+            _ => None,
+        }
+    }
+}
+
+/// TexturePack implements texture dumping and replacement ("HD texture
+/// packs"). Textures are identified by the hash of their TMEM contents, and
+/// stored as "<hash>.png" files.
+///
+/// Replacements are usually at a higher resolution than the original
+/// textures: their size must be an integer multiple of the original size,
+/// so that the rasterizer can sample them with scaled texture coordinates.
+#[derive(Default)]
+pub struct TexturePack {
+    dump_dir: Option<PathBuf>,
+    dumped: HashSet<u64>,
+
+    replace_dir: Option<PathBuf>,
+    replacements: HashMap<u64, Option<(Arc<OwnedGfxBufferLE<Rgba8888>>, usize)>>,
+}
+
+impl TexturePack {
+    pub fn new() -> TexturePack {
+        TexturePack::default()
+    }
+
+    /// Dump all textures used for drawing into the specified directory.
+    pub fn dump_to(mut self, dir: PathBuf) -> TexturePack {
+        self.dump_dir = Some(dir);
+        self
+    }
+
+    /// Look for replacement textures in the specified directory.
+    pub fn replace_from(mut self, dir: PathBuf) -> TexturePack {
+        self.replace_dir = Some(dir);
+        self
+    }
+
+    fn filename(hash: u64) -> String {
+        format!("{:016x}.png", hash)
+    }
+
+    fn dump(&mut self, logger: &slog::Logger, hash: u64, tex: &TmemTexture) {
+        let dir = match self.dump_dir {
+            Some(ref dir) if !self.dumped.contains(&hash) => dir,
+            _ => return,
+        };
+        self.dumped.insert(hash);
+
+        let image = match tex.decode() {
+            Some(image) => image,
+            None => {
+                warn!(logger, "cannot dump texture: unsupported format or size"; "format" => ?tex.color_format, "bpp" => tex.bpp);
+                return;
+            }
+        };
+        let path = dir.join(TexturePack::filename(hash));
+        let res = fs::create_dir_all(dir)
+            .map_err(|e| hw::Error::File(dir.to_path_buf(), e))
+            .and_then(|_| hw::save_png_rgba(&path, &image.buf()));
+        if let Err(e) = res {
+            error!(logger, "error dumping texture"; "err" => %e);
+        }
+    }
+
+    // Load the replacement of a texture, and return it together with its
+    // scale factor. Replacements that are not an integer multiple of the
+    // original size are discarded.
+    fn replacement(
+        &mut self,
+        logger: &slog::Logger,
+        hash: u64,
+        tex: &TmemTexture,
+    ) -> Option<(Arc<OwnedGfxBufferLE<Rgba8888>>, usize)> {
+        let dir = match self.replace_dir {
+            Some(ref dir) => dir,
+            None => return None,
+        };
+        self.replacements
+            .entry(hash)
+            .or_insert_with(|| {
+                let path = dir.join(TexturePack::filename(hash));
+                if !path.exists() {
+                    return None;
+                }
+                match hw::load_png_rgba(&path) {
+                    Ok(image) => {
+                        let scale = image.width() / tex.width;
+                        if scale == 0
+                            || image.width() != tex.width * scale
+                            || image.height() != tex.height * scale
+                        {
+                            warn!(logger, "replacement texture is not an integer multiple of the original size"; "file" => TexturePack::filename(hash), "width" => image.width(), "height" => image.height());
+                            return None;
+                        }
+                        info!(logger, "loaded replacement texture"; "file" => TexturePack::filename(hash), "scale" => scale);
+                        Some((Arc::new(image), scale))
+                    }
+                    Err(e) => {
+                        error!(logger, "error loading replacement texture"; "err" => %e);
+                        None
+                    }
+                }
+            })
+            .clone()
+    }
+
+    /// Hook called by the RDP each time a texture in TMEM is used for
+    /// drawing. Returns the replacement texture, if any, and its scale
+    /// factor over the original texture.
+    pub(crate) fn texture_used(
+        &mut self,
+        logger: &slog::Logger,
+        tex: &TmemTexture,
+    ) -> Option<(Arc<OwnedGfxBufferLE<Rgba8888>>, usize)> {
+        if self.dump_dir.is_none() && self.replace_dir.is_none() {
+            return None;
+        }
+        let hash = match tex.hash() {
+            Some(hash) => hash,
+            None => return None,
+        };
+        self.dump(logger, hash, tex);
+        self.replacement(logger, hash, tex)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn tex(mem: &[u8], pitch: usize, color_format: DpColorFormat) -> TmemTexture {
+        TmemTexture {
+            mem,
+            width: 8,
+            height: 4,
+            pitch,
+            color_format,
+            bpp: 8,
+        }
+    }
+
+    #[test]
+    fn texture_hash() {
+        let mut mem = vec![0u8; 16 * 4];
+        for (i, b) in mem.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+
+        let h1 = tex(&mem, 16, DpColorFormat::Intensity).hash().unwrap();
+        assert_eq!(Some(h1), tex(&mem, 16, DpColorFormat::Intensity).hash());
+        assert!(Some(h1) != tex(&mem, 16, DpColorFormat::IntensityAlpha).hash());
+        assert_eq!(None, tex(&mem[..50], 16, DpColorFormat::Intensity).hash());
+
+        // Padding bytes do not affect the hash
+        let mut mem2 = mem.clone();
+        mem2[12] = 0xFF;
+        assert_eq!(Some(h1), tex(&mem2, 16, DpColorFormat::Intensity).hash());
+        mem2[2] = 0xFF;
+        assert!(Some(h1) != tex(&mem2, 16, DpColorFormat::Intensity).hash());
+
+        let img = tex(&mem, 16, DpColorFormat::Intensity).decode().unwrap();
+        assert_eq!((img.width(), img.height()), (8, 4));
+        assert_eq!(
+            img.buf().line(1).get(2),
+            Color::<Rgba8888>::new_clamped(18, 18, 18, 255)
+        );
+    }
+
+    #[test]
+    fn texture_replacement() {
+        let logger = slog::Logger::root(slog::Discard, o!());
+        let dir = env::temp_dir().join("r64emu_texpack_test");
+        fs::create_dir_all(&dir).unwrap();
+        let mem = vec![0x80u8; 16 * 4];
+        let mem2 = vec![0x40u8; 16 * 4];
+        let hash = tex(&mem, 16, DpColorFormat::Intensity).hash().unwrap();
+        let hash2 = tex(&mem2, 16, DpColorFormat::Intensity).hash().unwrap();
+
+        // An 8x4 texture, replaced at twice its size
+        let mut img = OwnedGfxBufferLE::<Rgba8888>::new(16, 8);
+        img.buf_mut().line(3).set(5, Color::new_clamped(1, 2, 3, 4));
+        let path = dir.join(TexturePack::filename(hash));
+        hw::save_png_rgba(&path, &img.buf()).unwrap();
+        // A replacement that is not an integer multiple of the original size
+        let path2 = dir.join(TexturePack::filename(hash2));
+        hw::save_png_rgba(&path2, &OwnedGfxBufferLE::<Rgba8888>::new(12, 8).buf()).unwrap();
+
+        let mut pack = TexturePack::new().replace_from(dir.clone());
+        let (repl, scale) = pack
+            .texture_used(&logger, &tex(&mem, 16, DpColorFormat::Intensity))
+            .unwrap();
+        assert_eq!(scale, 2);
+        assert_eq!((repl.width(), repl.height()), (16, 8));
+        assert_eq!(
+            repl.buf().line(3).get(5),
+            Color::<Rgba8888>::new_clamped(1, 2, 3, 4)
+        );
+        assert!(pack
+            .texture_used(&logger, &tex(&mem2, 16, DpColorFormat::Intensity))
+            .is_none());
+
+        fs::remove_file(path).unwrap();
+        fs::remove_file(path2).unwrap();
+    }
+}
@@ -0,0 +1,409 @@
+extern crate crc;
+
+use self::crc::{crc32, Hasher32};
+use super::errors::*;
+use super::mips64::{disasm, Cpu};
+use super::N64;
+use emu::gfx::{OwnedGfxBufferLE, Rgb888};
+use emu::hw::OutputProducer;
+use emu::int::Numerics;
+use serde_json;
+use slog;
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io::Read;
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportStatus {
+    // All requested frames were emulated
+    Ok,
+    // Emulation aborted because of a panic
+    Crashed,
+    // Emulation did not complete within the allotted time (batch runs)
+    Timeout,
+    // Emulation stopped by the watchdog, as the game made no progress
+    Hung,
+    // The emulator could not be run at all (eg: invalid ROM)
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UnimplementedOp {
+    pub cpu: String,
+    pub pc: String,
+    pub opcode: String,
+    pub disasm: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UnhandledReg {
+    pub cpu: String,
+    pub reg: String,
+    pub write: bool,
+}
+
+/// CompatReport is a machine-readable summary of how far the emulation of a
+/// ROM went, used to build compatibility lists across ROM sets.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CompatReport {
+    pub rom: String,
+    pub status: ReportStatus,
+    pub error: Option<String>,
+    pub frames: usize,
+    pub frames_run: usize,
+    pub unimplemented_ops: Vec<UnimplementedOp>,
+    pub unmapped_reads: Vec<String>,
+    pub unmapped_writes: Vec<String>,
+    // Registers accessed by the game that are not implemented, and whose
+    // accesses are ignored in lenient mode.
+    #[serde(default)]
+    pub unhandled_regs: Vec<UnhandledReg>,
+    pub exceptions: BTreeMap<String, u64>,
+    pub frame_hash: Option<String>,
+    // Hash of all the audio samples produced by the AI, used to catch audio
+    // regressions like frame_hash does for video.
+    #[serde(default)]
+    pub audio_hash: Option<String>,
+}
+
+fn panic_message(payload: &Box<Any + Send>) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "unknown panic".into()
+    }
+}
+
+// Hash audio samples as 16-bit little-endian values, so that the hash does
+// not depend on the host.
+fn hash_samples(digest: &mut crc32::Digest, samples: &[i16]) {
+    for s in samples {
+        digest.write(&[*s as u8, (*s >> 8) as u8]);
+    }
+}
+
+fn unimplemented_ops(name: &str, cpu: &Cpu) -> Vec<UnimplementedOp> {
+    cpu.ctx()
+        .unimplemented_ops()
+        .iter()
+        .map(|&(pc, opcode)| UnimplementedOp {
+            cpu: name.into(),
+            pc: pc.hex(),
+            opcode: opcode.hex(),
+            disasm: disasm(opcode, pc),
+        })
+        .collect()
+}
+
+impl CompatReport {
+    /// Boot the specified ROM and emulate the requested number of frames
+    /// in lenient mode, collecting a report of what was hit along the way.
+    /// If a watchdog is specified (in CPU cycles), the emulation stops early
+    /// when the game hangs.
+    pub fn run(
+        logger: slog::Logger,
+        romfn: &str,
+        frames: usize,
+        watchdog: Option<i64>,
+    ) -> Result<CompatReport> {
+        let mut n64 = N64::new(logger, romfn)?;
+        n64.setup_cic()?;
+        n64.set_lenient(true);
+        if let Some(cycles) = watchdog {
+            n64.set_watchdog(cycles);
+        }
+
+        let mut screen = OwnedGfxBufferLE::<Rgb888>::new(640, 480);
+        let mut audio = crc32::Digest::new(crc32::IEEE);
+        let mut samples = Vec::new();
+        let mut frames_run = 0;
+        let res = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            for _ in 0..frames {
+                n64.render_frame(&mut screen.buf_mut());
+                n64.render_audio(&mut samples);
+                hash_samples(&mut audio, &samples);
+                samples.clear();
+                if n64.hung() {
+                    break;
+                }
+                frames_run += 1;
+            }
+        }));
+
+        let (status, error) = match res {
+            Ok(_) if n64.hung() => (
+                ReportStatus::Hung,
+                Some(format!("no progress after frame {}", frames_run)),
+            ),
+            Ok(_) => (ReportStatus::Ok, None),
+            Err(payload) => (ReportStatus::Crashed, Some(panic_message(&payload))),
+        };
+
+        let mut unimpl = unimplemented_ops("cpu", &n64.cpu().borrow());
+        unimpl.extend(unimplemented_ops(
+            "rsp",
+            &n64.sp().borrow().core_cpu.borrow(),
+        ));
+
+        let (mut unmapped_reads, mut unmapped_writes) = (Vec::new(), Vec::new());
+        for (addr, write) in n64.bus().borrow().unmapped_accesses() {
+            if write {
+                unmapped_writes.push(addr.hex());
+            } else {
+                unmapped_reads.push(addr.hex());
+            }
+        }
+
+        let unhandled_regs = n64
+            .cpu()
+            .borrow()
+            .unimplemented_cop0_regs()
+            .iter()
+            .map(|&(reg, write)| UnhandledReg {
+                cpu: "cpu".into(),
+                reg: format!("cop0:{}", reg),
+                write,
+            })
+            .collect();
+
+        let exceptions = n64
+            .cpu()
+            .borrow()
+            .exception_stats()
+            .iter()
+            .map(|(exc, count)| (format!("{:?}", exc), *count))
+            .collect();
+
+        let (frame_hash, audio_hash) = if frames_run > 0 {
+            (
+                Some(crc32::checksum_ieee(screen.buf().raw().0).hex()),
+                Some(audio.sum32().hex()),
+            )
+        } else {
+            (None, None)
+        };
+
+        Ok(CompatReport {
+            rom: romfn.into(),
+            status,
+            error,
+            frames,
+            frames_run,
+            unimplemented_ops: unimpl,
+            unmapped_reads,
+            unmapped_writes,
+            unhandled_regs,
+            exceptions,
+            frame_hash,
+            audio_hash,
+        })
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<CompatReport> {
+        Ok(serde_json::from_str(json)?)
+    }
+}
+
+pub struct BatchConfig {
+    pub frames: usize,         // number of frames to emulate for each ROM
+    pub jobs: usize,           // number of parallel worker processes
+    pub timeout: Duration,     // maximum running time for each ROM
+    pub watchdog: Option<i64>, // cycles without progress before stopping
+}
+
+struct Worker {
+    rom: PathBuf,
+    child: Child,
+    started: Instant,
+    output: Option<thread::JoinHandle<String>>,
+}
+
+impl Worker {
+    // Spawn a new instance of the emulator, running the report of a single ROM.
+    fn spawn(rom: PathBuf, cfg: &BatchConfig) -> Result<Worker> {
+        let mut cmd = Command::new(env::current_exe()?);
+        cmd.arg(format!("--report={}", cfg.frames));
+        if let Some(cycles) = cfg.watchdog {
+            cmd.arg(format!("--watchdog={}", cycles / 1_000_000));
+        }
+        let mut child = cmd
+            .arg(&rom)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .chain_err(|| "cannot spawn worker process")?;
+
+        // Drain stdout in a separate thread, so that the child never blocks
+        // on a full pipe.
+        let mut stdout = child.stdout.take().unwrap();
+        let output = thread::spawn(move || {
+            let mut out = String::new();
+            let _ = stdout.read_to_string(&mut out);
+            out
+        });
+
+        Ok(Worker {
+            rom,
+            child,
+            started: Instant::now(),
+            output: Some(output),
+        })
+    }
+
+    // Check if the worker has finished, and return its report.
+    fn poll(&mut self, cfg: &BatchConfig) -> Result<Option<CompatReport>> {
+        let status = match self.child.try_wait()? {
+            Some(status) => status,
+            None if self.started.elapsed() > cfg.timeout => {
+                self.child.kill()?;
+                self.child.wait()?;
+                return Ok(Some(CompatReport::failed(
+                    &self.rom,
+                    cfg.frames,
+                    ReportStatus::Timeout,
+                    format!("timeout after {}s", cfg.timeout.as_secs()),
+                )));
+            }
+            None => return Ok(None),
+        };
+
+        let output = self
+            .output
+            .take()
+            .and_then(|h| h.join().ok())
+            .unwrap_or_default();
+        Ok(Some(match CompatReport::from_json(&output) {
+            Ok(report) => report,
+            Err(_) => CompatReport::failed(
+                &self.rom,
+                cfg.frames,
+                ReportStatus::Failed,
+                format!("worker exited with {}", status),
+            ),
+        }))
+    }
+}
+
+fn is_rom(path: &Path) -> bool {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) => ["z64", "n64", "v64"].contains(&ext.to_lowercase().as_str()),
+        None => false,
+    }
+}
+
+impl CompatReport {
+    fn failed(rom: &Path, frames: usize, status: ReportStatus, error: String) -> CompatReport {
+        CompatReport {
+            rom: rom.to_string_lossy().into_owned(),
+            status,
+            error: Some(error),
+            frames,
+            frames_run: 0,
+            unimplemented_ops: Vec::new(),
+            unmapped_reads: Vec::new(),
+            unmapped_writes: Vec::new(),
+            unhandled_regs: Vec::new(),
+            exceptions: BTreeMap::new(),
+            frame_hash: None,
+            audio_hash: None,
+        }
+    }
+
+    /// Run the compatibility report on all the ROMs found in the specified
+    /// directory. Each ROM is run in a separate worker process, so that
+    /// crashes and timeouts do not affect the rest of the batch.
+    pub fn run_batch(dir: &Path, cfg: &BatchConfig) -> Result<Vec<CompatReport>> {
+        let mut roms = Vec::new();
+        for entry in fs::read_dir(dir).chain_err(|| "cannot read ROM directory")? {
+            let path = entry?.path();
+            if path.is_file() && is_rom(&path) {
+                roms.push(path);
+            }
+        }
+        roms.sort();
+        roms.reverse();
+
+        let mut reports = Vec::new();
+        let mut workers: Vec<Worker> = Vec::new();
+        while !roms.is_empty() || !workers.is_empty() {
+            while workers.len() < cfg.jobs.max(1) {
+                match roms.pop() {
+                    Some(rom) => workers.push(Worker::spawn(rom, cfg)?),
+                    None => break,
+                }
+            }
+
+            let mut idx = 0;
+            while idx < workers.len() {
+                match workers[idx].poll(cfg)? {
+                    Some(report) => {
+                        reports.push(report);
+                        workers.remove(idx);
+                    }
+                    None => idx += 1,
+                }
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        reports.sort_by(|a, b| a.rom.cmp(&b.rom));
+        Ok(reports)
+    }
+
+    /// Format a list of reports as a plain-text summary table.
+    pub fn summary_table(reports: &[CompatReport]) -> String {
+        let width = reports
+            .iter()
+            .map(|r| r.rom.len())
+            .max()
+            .unwrap_or(0)
+            .max(3);
+
+        let mut out = format!(
+            "{:w$}  {:8}  {:>6}  {:>6}  {:>8}  {:10}  {:10}\n",
+            "ROM",
+            "STATUS",
+            "FRAMES",
+            "UNIMPL",
+            "UNMAPPED",
+            "HASH",
+            "AUDIO",
+            w = width
+        );
+        for r in reports {
+            out += &format!(
+                "{:w$}  {:8}  {:>6}  {:>6}  {:>8}  {:10}  {:10}\n",
+                r.rom,
+                format!("{:?}", r.status).to_lowercase(),
+                r.frames_run,
+                r.unimplemented_ops.len(),
+                r.unmapped_reads.len() + r.unmapped_writes.len(),
+                r.frame_hash.as_ref().map(|s| s.as_str()).unwrap_or("-"),
+                r.audio_hash.as_ref().map(|s| s.as_str()).unwrap_or("-"),
+                w = width
+            );
+        }
+
+        let ok = reports
+            .iter()
+            .filter(|r| r.status == ReportStatus::Ok)
+            .count();
+        out += &format!("\n{}/{} ROMs completed\n", ok, reports.len());
+        out
+    }
+}
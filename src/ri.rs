@@ -12,7 +12,7 @@ pub struct Ri {
         offset = 0x0000_0000,
         vsize = 0x03F0_0000
     )]
-    rdram: Mem,
+    pub rdram: Mem,
 
     #[reg(bank = 1, offset = 0x00)]
     reg_rdram_config: Reg32,
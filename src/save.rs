@@ -0,0 +1,161 @@
+use super::cartridge::SaveType;
+use super::errors::*;
+
+// Layout of the mupen64plus .srm file, which holds all the save memories
+// of a game: EEPROM, 4 controller paks, SRAM and FlashRAM.
+const SRM_EEPROM_OFFSET: usize = 0;
+const SRM_EEPROM_SIZE: usize = 0x800;
+const SRM_SRAM_OFFSET: usize = 0x2_0800;
+const SRM_FLASHRAM_OFFSET: usize = 0x2_8800;
+const SRM_SIZE: usize = 0x4_8800;
+
+// Project64 always writes EEPROM files as 2KB, whatever the chip size.
+const PJ64_EEPROM_SIZE: usize = 0x800;
+
+fn is_eeprom(ty: SaveType) -> bool {
+    ty == SaveType::Eeprom4k || ty == SaveType::Eeprom16k
+}
+
+/// File format of save memories used by different emulators. The native
+/// format is the contents of the memory in its byte order (big-endian), with
+/// its exact size.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SaveFormat {
+    Native,
+    // Project64 (.eep, .sra, .fla): SRAM and FlashRAM are stored as
+    // little-endian 32-bit words.
+    Project64,
+    // mupen64plus (.srm): all memories in a single file, in native order.
+    Mupen64Plus,
+}
+
+impl SaveFormat {
+    pub fn parse(s: &str) -> Result<SaveFormat> {
+        match s {
+            "native" | "r64" => Ok(SaveFormat::Native),
+            "pj64" | "project64" => Ok(SaveFormat::Project64),
+            "mupen" | "mupen64plus" => Ok(SaveFormat::Mupen64Plus),
+            _ => bail!("unknown save format: {}", s),
+        }
+    }
+
+    /// Convert a save file in this format into the native format.
+    pub fn import(&self, ty: SaveType, data: &[u8]) -> Result<Vec<u8>> {
+        let size = match ty.size() {
+            Some(size) => size,
+            None => bail!("unknown save type"),
+        };
+        let out = match *self {
+            SaveFormat::Native => data.to_vec(),
+            SaveFormat::Project64 if is_eeprom(ty) => data[..size.min(data.len())].to_vec(),
+            SaveFormat::Project64 => swap_words(data),
+            SaveFormat::Mupen64Plus => {
+                if data.len() != SRM_SIZE {
+                    bail!("invalid mupen64plus save size: {} bytes", data.len());
+                }
+                let off = srm_offset(ty);
+                data[off..off + size].to_vec()
+            }
+        };
+        if out.len() != size {
+            bail!(
+                "invalid save size for {:?}: {} bytes (expected {})",
+                ty,
+                data.len(),
+                size
+            );
+        }
+        Ok(out)
+    }
+
+    /// Convert a save file in the native format into this format.
+    pub fn export(&self, ty: SaveType, data: &[u8]) -> Result<Vec<u8>> {
+        if ty.size() != Some(data.len()) {
+            bail!("invalid save size for {:?}: {} bytes", ty, data.len());
+        }
+        Ok(match *self {
+            SaveFormat::Native => data.to_vec(),
+            SaveFormat::Project64 if is_eeprom(ty) => {
+                let mut out = data.to_vec();
+                out.resize(PJ64_EEPROM_SIZE, 0);
+                out
+            }
+            SaveFormat::Project64 => swap_words(data),
+            SaveFormat::Mupen64Plus => {
+                // The other memories are left erased (EEPROM and FlashRAM
+                // are erased to 0xFF).
+                let mut out = vec![0u8; SRM_SIZE];
+                for b in &mut out[SRM_EEPROM_OFFSET..SRM_EEPROM_OFFSET + SRM_EEPROM_SIZE] {
+                    *b = 0xFF;
+                }
+                for b in &mut out[SRM_FLASHRAM_OFFSET..] {
+                    *b = 0xFF;
+                }
+                let off = srm_offset(ty);
+                out[off..off + data.len()].copy_from_slice(data);
+                out
+            }
+        })
+    }
+}
+
+fn srm_offset(ty: SaveType) -> usize {
+    match ty {
+        SaveType::Sram => SRM_SRAM_OFFSET,
+        SaveType::FlashRam => SRM_FLASHRAM_OFFSET,
+        _ => SRM_EEPROM_OFFSET,
+    }
+}
+
+fn swap_words(data: &[u8]) -> Vec<u8> {
+    data.chunks(4)
+        .flat_map(|w| w.iter().rev().cloned())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_conversion() {
+        let sram: Vec<u8> = (0..0x8000).map(|i| i as u8).collect();
+        let pj64 = SaveFormat::Project64.export(SaveType::Sram, &sram).unwrap();
+        assert_eq!(pj64[0..8], [3, 2, 1, 0, 7, 6, 5, 4]);
+        assert_eq!(
+            SaveFormat::Project64.import(SaveType::Sram, &pj64).unwrap(),
+            sram
+        );
+
+        let srm = SaveFormat::Mupen64Plus
+            .export(SaveType::Sram, &sram)
+            .unwrap();
+        assert_eq!(srm.len(), SRM_SIZE);
+        assert_eq!(srm[SRM_SRAM_OFFSET + 1], 1);
+        assert_eq!(srm[SRM_EEPROM_SIZE - 1], 0xFF);
+        assert_eq!(srm[SRM_FLASHRAM_OFFSET], 0xFF);
+        assert_eq!(
+            SaveFormat::Mupen64Plus
+                .import(SaveType::Sram, &srm)
+                .unwrap(),
+            sram
+        );
+
+        // 4K EEPROM: padded to 2KB by Project64
+        let eeprom = vec![0x5A; 0x200];
+        let pj64 = SaveFormat::Project64
+            .export(SaveType::Eeprom4k, &eeprom)
+            .unwrap();
+        assert_eq!(pj64.len(), PJ64_EEPROM_SIZE);
+        assert_eq!(
+            SaveFormat::Project64
+                .import(SaveType::Eeprom4k, &pj64)
+                .unwrap(),
+            eeprom
+        );
+        assert!(SaveFormat::Native
+            .import(SaveType::Eeprom16k, &eeprom)
+            .is_err());
+        assert!(SaveFormat::parse("zsnes").is_err());
+    }
+}
@@ -0,0 +1,180 @@
+use super::errors::*;
+use mips64::Timing;
+use serde_json;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::Path;
+
+/// GameSettings holds the options that can be tuned per game, mostly hacks
+/// that improve the output but are not compatible with every game.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct GameSettings {
+    // Widescreen hack: display aspect ratio (eg: [16, 9]). Without a hardware
+    // renderer the projection cannot be changed, so the VI output is
+    // stretched to the requested aspect instead.
+    pub widescreen: Option<(u32, u32)>,
+
+    // Internal resolution scale of the software rasterizer (1, 2 or 4).
+    // Games that read back the framebuffer might misbehave when scaled.
+    pub resolution_scale: Option<usize>,
+
+    // FMV hack: the 16-bit framebuffer holds YUV frames written by the RSP,
+    // rather than RGBA 5551 pixels.
+    pub yuv_framebuffer: bool,
+
+    // Counter factor: CPU cycles taken by each instruction (1, 2 or 3).
+    // Higher values run fewer instructions between VI interrupts, which
+    // fixes the speed of games that rely on a slower CPU.
+    pub counter_factor: Option<u32>,
+
+    // CPU timing profile: "accurate" approximates the cycles taken by each
+    // instruction on the VR4300, for timing-sensitive games.
+    pub cpu_timing: Option<Timing>,
+
+    // Disable the RDP dithering of 16-bit color, for users who prefer the
+    // smooth (but banded) look.
+    pub no_dither: bool,
+}
+
+impl GameSettings {
+    /// Size of the output screen for the specified height, taking the
+    /// widescreen setting into account.
+    pub fn screen_size(&self, height: usize) -> (usize, usize) {
+        match self.widescreen {
+            Some((w, h)) if w > 0 && h > 0 => {
+                // Keep the width even, as required by most video encoders
+                let width = (height * w as usize / h as usize + 1) & !1;
+                (width, height)
+            }
+            _ => (height * 4 / 3, height),
+        }
+    }
+
+    /// Internal resolution scale (1 = native resolution).
+    pub fn resolution_scale(&self) -> usize {
+        self.resolution_scale.unwrap_or(1)
+    }
+
+    /// Counter factor (1 = one instruction per CPU cycle).
+    pub fn counter_factor(&self) -> u32 {
+        self.counter_factor.unwrap_or(1)
+    }
+
+    /// CPU timing profile (simple = one cycle per instruction).
+    pub fn cpu_timing(&self) -> Timing {
+        self.cpu_timing.unwrap_or_default()
+    }
+
+    /// Output screen height: the native 240 lines are doubled at least, and
+    /// more if the internal resolution is higher.
+    pub fn screen_height(&self) -> usize {
+        240 * self.resolution_scale().max(2)
+    }
+
+    /// Parse an internal resolution scale.
+    pub fn parse_resolution_scale(s: &str) -> Result<usize> {
+        match s.parse::<usize>() {
+            Ok(n) if n == 1 || n == 2 || n == 4 => Ok(n),
+            _ => bail!("invalid resolution scale (must be 1, 2 or 4): {}", s),
+        }
+    }
+
+    /// Parse a counter factor.
+    pub fn parse_counter_factor(s: &str) -> Result<u32> {
+        match s.parse::<u32>() {
+            Ok(n) if n >= 1 && n <= 3 => Ok(n),
+            _ => bail!("invalid counter factor (must be 1, 2 or 3): {}", s),
+        }
+    }
+
+    /// Parse a CPU timing profile.
+    pub fn parse_cpu_timing(s: &str) -> Result<Timing> {
+        match Timing::parse(s) {
+            Some(timing) => Ok(timing),
+            None => bail!("invalid CPU timing (must be simple or accurate): {}", s),
+        }
+    }
+
+    /// Parse a widescreen aspect ratio in the form "16:9".
+    pub fn parse_aspect(s: &str) -> Result<(u32, u32)> {
+        let mut parts = s.splitn(2, ':');
+        let w = parts.next().unwrap().parse::<u32>();
+        let h = parts.next().unwrap_or("").parse::<u32>();
+        match (w, h) {
+            (Ok(w), Ok(h)) if w > 0 && h > 0 => Ok((w, h)),
+            _ => bail!("invalid aspect ratio: {}", s),
+        }
+    }
+}
+
+/// Database of per-game settings, keyed by the game code found in the ROM
+/// header (eg: "NSME" for Super Mario 64, USA). It is stored as a JSON object.
+#[derive(Debug, Default)]
+pub struct GameSettingsDb {
+    games: BTreeMap<String, GameSettings>,
+}
+
+impl GameSettingsDb {
+    pub fn new() -> GameSettingsDb {
+        GameSettingsDb::default()
+    }
+
+    pub fn parse(json: &str) -> Result<GameSettingsDb> {
+        Ok(GameSettingsDb {
+            games: serde_json::from_str(json)?,
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<GameSettingsDb> {
+        let file = File::open(path).chain_err(|| "cannot open game settings")?;
+        Ok(GameSettingsDb {
+            games: serde_json::from_reader(file)?,
+        })
+    }
+
+    /// Return the settings for a game; unknown games use the defaults.
+    pub fn get(&self, code: &str) -> GameSettings {
+        self.games.get(code).cloned().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn game_settings() {
+        let db = GameSettingsDb::parse(
+            r#"{"NSME": {"widescreen": [16, 9]}, "NZLE": {}, "NFXE": {"resolution_scale": 4}, "NPNE": {"yuv_framebuffer": true}, "NGEE": {"counter_factor": 2, "cpu_timing": "accurate"}, "NMQE": {"no_dither": true}}"#,
+        )
+        .unwrap();
+        let sm64 = db.get("NSME");
+        assert_eq!(sm64.widescreen, Some((16, 9)));
+        assert_eq!(sm64.screen_size(480), (854, 480));
+        assert_eq!(db.get("NZLE"), GameSettings::default());
+        assert_eq!(db.get("XXXX").screen_size(480), (640, 480));
+        assert_eq!(sm64.resolution_scale(), 1);
+        assert_eq!(db.get("NFXE").resolution_scale(), 4);
+        assert_eq!(db.get("NFXE").screen_height(), 960);
+        assert_eq!(db.get("XXXX").screen_height(), 480);
+        assert!(db.get("NPNE").yuv_framebuffer);
+        assert!(!sm64.yuv_framebuffer);
+        assert_eq!(sm64.counter_factor(), 1);
+        assert_eq!(db.get("NGEE").counter_factor(), 2);
+        assert_eq!(db.get("NGEE").cpu_timing(), Timing::Accurate);
+        assert_eq!(sm64.cpu_timing(), Timing::Simple);
+        assert!(db.get("NMQE").no_dither);
+        assert!(!sm64.no_dither);
+
+        assert_eq!(GameSettings::parse_aspect("21:9").unwrap(), (21, 9));
+        assert!(GameSettings::parse_aspect("16").is_err());
+        assert!(GameSettings::parse_aspect("0:9").is_err());
+        assert_eq!(GameSettings::parse_resolution_scale("2").unwrap(), 2);
+        assert!(GameSettings::parse_resolution_scale("3").is_err());
+        assert_eq!(GameSettings::parse_counter_factor("3").unwrap(), 3);
+        assert!(GameSettings::parse_counter_factor("0").is_err());
+        assert!(GameSettings::parse_cpu_timing("exact").is_err());
+        assert!(GameSettingsDb::parse(r#"{"NSME": {"widescreen": "yes"}}"#).is_err());
+    }
+}
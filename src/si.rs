@@ -1,25 +1,408 @@
+extern crate byteorder;
 extern crate emu;
 extern crate slog;
-use emu::bus::be::Reg32;
+use self::byteorder::{BigEndian, ByteOrder};
+use emu::bus::be::{Bus, Reg32};
+use emu::hw::{JoybusDevice, PadPorts, PadState, MAX_PADS};
 use emu::int::Numerics;
+use errors::*;
+use interrupts::{Interrupt, InterruptLog};
+use mempak::MEMPAK_SIZE;
+use mips64::CodeWatch;
+use std::cell::RefCell;
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+// Address of the PIF RAM (64 bytes) on the main bus.
+const PIF_RAM: u32 = 0x1FC0_07C0;
+
+// Controller pak commands, which transfer blocks of 32 bytes.
+const PAK_READ: u8 = 0x02;
+const PAK_WRITE: u8 = 0x03;
+const PAK_BLOCK: usize = 32;
+
+/// ControllerPak is a controller pak inserted into an emulated controller,
+/// along with the image file it was loaded from, to which it is saved back.
+pub struct ControllerPak {
+    path: PathBuf,
+    data: Vec<u8>,
+    dirty: bool,
+}
+
+impl ControllerPak {
+    /// Load a controller pak image. Unformatted images are accepted, as
+    /// games can format them.
+    pub fn load(path: &Path) -> Result<ControllerPak> {
+        let data = fs::read(path)
+            .chain_err(|| format!("cannot read controller pak: {}", path.display()))?;
+        if data.len() != MEMPAK_SIZE {
+            bail!("invalid controller pak size: {} bytes", data.len());
+        }
+        Ok(ControllerPak {
+            path: path.to_path_buf(),
+            data,
+            dirty: false,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Write the image back to its file, if the game modified it.
+    pub fn save(&mut self) -> Result<()> {
+        if self.dirty {
+            fs::write(&self.path, &self.data)
+                .chain_err(|| format!("cannot write controller pak: {}", self.path.display()))?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+}
+
+// CRC of the data blocks of controller pak transfers (polynomial 0x85,
+// with an extra zero byte shifted in).
+fn pak_crc(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for i in 0..data.len() + 1 {
+        for bit in (0..8).rev() {
+            let xor = if crc & 0x80 != 0 { 0x85 } else { 0 };
+            crc <<= 1;
+            if i < data.len() && data[i] & (1 << bit) != 0 {
+                crc |= 1;
+            }
+            crc ^= xor;
+        }
+    }
+    crc
+}
 
 #[derive(DeviceBE)]
 pub struct Si {
+    // [23:0] starting RDRAM address
+    #[reg(bank = 0, offset = 0x00, rwmask = 0x00FF_FFFF)]
+    dram_addr: Reg32,
+
+    // (W): [31:0] PIF address; start a 64-byte DMA from PIF RAM to RDRAM
+    #[reg(bank = 0, offset = 0x04, writeonly, wcb)]
+    pif_addr_rd64b: Reg32,
+
+    // (W): [31:0] PIF address; start a 64-byte DMA from RDRAM to PIF RAM
+    #[reg(bank = 0, offset = 0x10, writeonly, wcb)]
+    pif_addr_wr64b: Reg32,
+
     #[reg(bank = 0, offset = 0x18, rwmask = 0, wcb)]
     status: Reg32,
 
     logger: slog::Logger,
+    bus: Rc<RefCell<Box<Bus>>>,
+
+    // State of the controllers, as last provided by the input source, and
+    // as last read by the game
+    pads: PadPorts,
+    read_pads: PadPorts,
+
+    // Real controllers accessed through an adapter; they replace the emulated
+    // controllers on the ports that they cover.
+    passthrough: Option<Box<JoybusDevice>>,
+
+    // Controller paks inserted into the emulated controllers, and whether
+    // each slot changed since the game last checked its status
+    paks: Vec<Option<ControllerPak>>,
+    pak_swapped: [bool; MAX_PADS],
+
+    ints: InterruptLog,
+    code: CodeWatch,
 }
 
 impl Si {
-    pub fn new(logger: slog::Logger) -> Si {
+    pub fn new(logger: slog::Logger, bus: Rc<RefCell<Box<Bus>>>) -> Si {
         Si {
+            dram_addr: Reg32::default(),
+            pif_addr_rd64b: Reg32::default(),
+            pif_addr_wr64b: Reg32::default(),
             status: Reg32::default(),
             logger,
+            bus,
+            pads: PadPorts::default(),
+            read_pads: PadPorts::default(),
+            passthrough: None,
+            paks: (0..MAX_PADS).map(|_| None).collect(),
+            pak_swapped: [false; MAX_PADS],
+            ints: InterruptLog::new(),
+            code: CodeWatch::new(),
         }
     }
 
+    pub fn set_pads(&mut self, pads: PadPorts) {
+        self.pads = pads;
+    }
+
+    /// State of the controllers as last read by the game through the
+    /// joybus (None on the ports where it found no controller). Unlike the
+    /// state from the input source, it includes the passthrough ports.
+    pub fn read_pads(&self) -> PadPorts {
+        self.read_pads
+    }
+
+    pub fn set_passthrough(&mut self, dev: Box<JoybusDevice>) {
+        self.passthrough = Some(dev);
+    }
+
+    /// Insert a controller pak into the controller on the specified port
+    /// (or remove it), while the machine runs, returning the previous one.
+    /// The game sees the swap as if it was done by hand: the slot is
+    /// reported empty once, before the new pak shows up.
+    pub fn insert_pak(&mut self, port: usize, pak: Option<ControllerPak>) -> Option<ControllerPak> {
+        self.pak_swapped[port] = true;
+        mem::replace(&mut self.paks[port], pak)
+    }
+
+    /// Iterate over the inserted controller paks (eg: to save them).
+    pub fn paks_mut<'a>(&'a mut self) -> impl Iterator<Item = &'a mut ControllerPak> + 'a {
+        self.paks.iter_mut().filter_map(|pak| pak.as_mut())
+    }
+
+    pub fn set_interrupt_log(&mut self, ints: InterruptLog) {
+        self.ints = ints;
+    }
+
+    pub fn set_code_watch(&mut self, code: CodeWatch) {
+        self.code = code;
+    }
+
     fn cb_write_status(&self, _old: u32, new: u32) {
         error!(self.logger, "write SI status reg"; o!("val" => new.hex()));
     }
+
+    fn cb_write_pif_addr_rd64b(&mut self, _old: u32, _new: u32) {
+        let mut ram = [0u8; 64];
+        for i in 0..16 {
+            let val = self.bus.borrow().read::<u32>(PIF_RAM + i as u32 * 4);
+            BigEndian::write_u32(&mut ram[i * 4..], val);
+        }
+        let orig = ram;
+
+        self.joybus(&mut ram);
+
+        let bus = self.bus.borrow();
+        let dst = self.dram_addr.get() & !3;
+        for i in 0..16 {
+            let val = BigEndian::read_u32(&ram[i * 4..]);
+            if val != BigEndian::read_u32(&orig[i * 4..]) {
+                bus.write::<u32>(PIF_RAM + i as u32 * 4, val);
+            }
+            bus.write::<u32>(dst + i as u32 * 4, val);
+        }
+        self.code.invalidate(dst, 64);
+        self.ints.raise(Interrupt::Si);
+    }
+
+    fn cb_write_pif_addr_wr64b(&mut self, _old: u32, _new: u32) {
+        let bus = self.bus.borrow();
+        let src = self.dram_addr.get() & !3;
+        for i in 0..16 {
+            bus.write::<u32>(PIF_RAM + i * 4, bus.read::<u32>(src + i * 4));
+        }
+        self.ints.raise(Interrupt::Si);
+    }
+
+    // Execute the joybus commands found in PIF RAM. Each channel (one per
+    // controller port) is described by a TX length, a RX length, the command
+    // bytes and the space for the reply.
+    fn joybus(&mut self, ram: &mut [u8; 64]) {
+        let mut chan = 0;
+        let mut idx = 0;
+        while idx < 63 {
+            let tx = ram[idx];
+            match tx {
+                0xFE => break,
+                0xFD | 0xFF => {
+                    idx += 1;
+                    continue;
+                }
+                0x00 => {
+                    chan += 1;
+                    idx += 1;
+                    continue;
+                }
+                _ => {}
+            }
+
+            let rxidx = idx + 1;
+            if ram[rxidx] == 0xFE {
+                break;
+            }
+            let tx = (tx & 0x3F) as usize;
+            let rx = (ram[rxidx] & 0x3F) as usize;
+            let cmd = rxidx + 1;
+            let resp = cmd + tx;
+            if tx == 0 || resp + rx > 63 {
+                break;
+            }
+
+            let req = ram[cmd..resp].to_vec();
+            if !self.joybus_command(chan, &req, &mut ram[resp..resp + rx]) {
+                // Signal "no device" to the caller
+                ram[rxidx] |= 0x80;
+            }
+            chan += 1;
+            idx = resp + rx;
+        }
+    }
+
+    fn joybus_command(&mut self, chan: usize, req: &[u8], resp: &mut [u8]) -> bool {
+        // Ports covered by a passthrough device receive the raw command,
+        // including controller pak accesses.
+        if let Some(ref mut dev) = self.passthrough {
+            if chan < dev.channels() {
+                return match dev.command(chan, req, resp) {
+                    Ok(present) => {
+                        if req[0] == 0x01 && chan < MAX_PADS {
+                            self.read_pads[chan] = if present && resp.len() >= 4 {
+                                Some(PadState::from_bytes(resp))
+                            } else {
+                                None
+                            };
+                        }
+                        present
+                    }
+                    Err(e) => {
+                        error!(self.logger, "passthrough error"; o!("chan" => chan, "err" => e.to_string()));
+                        false
+                    }
+                };
+            }
+        }
+
+        let pad = match self.pads.get(chan).and_then(|p| *p) {
+            Some(pad) if chan < MAX_PADS => pad,
+            _ => {
+                if let Some(read) = self.read_pads.get_mut(chan) {
+                    *read = None;
+                }
+                return false;
+            }
+        };
+
+        match req[0] {
+            // Info / reset: standard controller, and status of the pak slot:
+            // pak inserted (0x01), or no pak (0x02)
+            0x00 | 0xFF if resp.len() >= 3 => {
+                let present = self.paks[chan].is_some() && !self.pak_swapped[chan];
+                self.pak_swapped[chan] = false;
+                resp[..3].copy_from_slice(&[0x05, 0x00, if present { 0x01 } else { 0x02 }]);
+                true
+            }
+            // Read buttons
+            0x01 if resp.len() >= 4 => {
+                resp[..4].copy_from_slice(&pad.to_bytes());
+                self.read_pads[chan] = Some(pad);
+                true
+            }
+            PAK_READ if req.len() >= 3 && resp.len() > PAK_BLOCK => {
+                self.pak_read(chan, pak_addr(req), resp)
+            }
+            PAK_WRITE if req.len() >= 3 + PAK_BLOCK && !resp.is_empty() => {
+                self.pak_write(chan, pak_addr(req), &req[3..3 + PAK_BLOCK], resp)
+            }
+            cmd => {
+                warn!(self.logger, "unsupported joybus command"; o!("chan" => chan, "cmd" => cmd.hex()));
+                false
+            }
+        }
+    }
+
+    // Read a block of the controller pak, followed by its CRC. Addresses
+    // past the image (where accessories like the rumble pak are detected)
+    // read as zero.
+    fn pak_read(&mut self, chan: usize, addr: usize, resp: &mut [u8]) -> bool {
+        let pak = match self.paks[chan] {
+            Some(ref pak) => pak,
+            None => return false,
+        };
+        let (data, crc) = resp.split_at_mut(PAK_BLOCK);
+        if addr + PAK_BLOCK <= pak.data.len() {
+            data.copy_from_slice(&pak.data[addr..addr + PAK_BLOCK]);
+        } else {
+            for b in data.iter_mut() {
+                *b = 0;
+            }
+        }
+        crc[0] = pak_crc(data);
+        true
+    }
+
+    // Write a block of the controller pak, replying with its CRC.
+    fn pak_write(&mut self, chan: usize, addr: usize, block: &[u8], resp: &mut [u8]) -> bool {
+        let pak = match self.paks[chan] {
+            Some(ref mut pak) => pak,
+            None => return false,
+        };
+        if addr + PAK_BLOCK <= pak.data.len() {
+            pak.data[addr..addr + PAK_BLOCK].copy_from_slice(block);
+            pak.dirty = true;
+        }
+        resp[0] = pak_crc(block);
+        true
+    }
+}
+
+// Address of a controller pak command, without its 5-bit CRC.
+fn pak_addr(req: &[u8]) -> usize {
+    ((req[1] as usize) << 8 | req[2] as usize) & !0x1F
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn joybus_controller() {
+        let logger = slog::Logger::root(slog::Discard, o!());
+        let bus = Rc::new(RefCell::new(Bus::new(logger.clone())));
+        let mut si = Si::new(logger, bus);
+        si.set_pads([Some(PadState::default()), None, None, None]);
+        let mut resp = [0u8; PAK_BLOCK + 1];
+
+        // Buttons read by the game are reported for the input display
+        assert_eq!(si.read_pads()[0], None);
+        assert!(si.joybus_command(0, &[0x01], &mut resp[..4]));
+        assert!(!si.joybus_command(1, &[0x01], &mut resp[..4]));
+        assert_eq!(si.read_pads()[0], Some(PadState::default()));
+        assert_eq!(si.read_pads()[1], None);
+
+        // No pak: reads fail, and the slot is reported empty
+        assert!(!si.joybus_command(0, &[PAK_READ, 0x00, 0x00], &mut resp));
+        assert!(si.joybus_command(0, &[0x00], &mut resp[..3]));
+        assert_eq!(&resp[..3], &[0x05, 0x00, 0x02]);
+
+        let pak = ControllerPak {
+            path: PathBuf::new(),
+            data: vec![0; MEMPAK_SIZE],
+            dirty: false,
+        };
+        assert!(si.insert_pak(0, Some(pak)).is_none());
+
+        // A pak inserted at runtime is first seen as removed
+        si.joybus_command(0, &[0x00], &mut resp[..3]);
+        assert_eq!(resp[2], 0x02);
+        si.joybus_command(0, &[0x00], &mut resp[..3]);
+        assert_eq!(resp[2], 0x01);
+
+        let mut req = vec![PAK_WRITE, 0x01, 0x15];
+        req.extend((0..PAK_BLOCK as u8).map(|b| b + 1));
+        assert!(si.joybus_command(0, &req, &mut resp[..1]));
+        assert_eq!(resp[0], pak_crc(&req[3..]));
+        assert!(si.joybus_command(0, &[PAK_READ, 0x01, 0x00], &mut resp));
+        assert_eq!(&resp[..PAK_BLOCK], &req[3..]);
+        assert_eq!(resp[PAK_BLOCK], pak_crc(&req[3..]));
+        assert_eq!(pak_crc(&[0; PAK_BLOCK]), 0);
+
+        let pak = si.insert_pak(0, None).unwrap();
+        assert!(pak.dirty);
+        assert_eq!(pak.data[0x100], 1);
+    }
 }
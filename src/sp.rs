@@ -5,6 +5,8 @@ use super::spvector::SpVector;
 use emu::bus::be::{Bus, DevPtr, Mem, Reg32};
 use emu::int::Numerics;
 use errors::*;
+use hle::{AudioTasks, HleConfig, JpegDecoder, JpegOutput, OsTask, SpTasks, TaskType};
+use interrupts::{Interrupt, InterruptLog};
 use mips64;
 use std::cell::RefCell;
 use std::rc::Rc;
@@ -55,7 +57,13 @@ pub struct Sp {
     #[reg(bank = 1, offset = 0x0C, wcb)]
     reg_dma_wr_len: Reg32,
 
-    #[reg(bank = 1, offset = 0x10, init = 0x1, wcb)]
+    #[reg(
+        bank = 1,
+        offset = 0x10,
+        init = 0x1,
+        wcb,
+        fields = "halt:0 broke:1 dma_busy:2 dma_full:3 io_full:4 sstep:5 intbreak:6 sig:7-14"
+    )]
     reg_status: Reg32,
 
     #[reg(bank = 1, offset = 0x18, init = 0, rwmask = 0x1, readonly)]
@@ -64,6 +72,13 @@ pub struct Sp {
     logger: slog::Logger,
 
     main_bus: Rc<RefCell<Box<Bus>>>,
+    ints: InterruptLog,
+    tasks: SpTasks,
+
+    // Code fetched by the main CPU (invalidated by DMAs into RDRAM) and by
+    // the RSP core (invalidated by DMAs into IMEM)
+    code: mips64::CodeWatch,
+    core_code: mips64::CodeWatch,
 }
 
 impl Sp {
@@ -75,9 +90,21 @@ impl Sp {
             bus.clone(),
         ))));
 
+        // HLE handlers for the standard microcodes
+        let mut tasks = SpTasks::new(logger.new(o!()));
+        tasks.register(TaskType::Audio, Box::new(AudioTasks));
+        tasks.register(
+            TaskType::Jpeg,
+            Box::new(JpegDecoder::new(JpegOutput::Rgba5551)),
+        );
+
         let sp = DevPtr::new(Sp {
+            tasks,
             logger,
             main_bus,
+            ints: InterruptLog::new(),
+            code: mips64::CodeWatch::new(),
+            core_code: mips64::CodeWatch::new(),
             dmem: Mem::default(),
             imem: Mem::default(),
             reg_status: Reg32::default(),
@@ -100,6 +127,7 @@ impl Sp {
             let mut cpu = spb.core_cpu.borrow_mut();
             cpu.set_cop0(SpCop0::new(&sp));
             cpu.set_cop2(SpVector::new(&sp, spb.logger.new(o!())));
+            cpu.set_code_watch(spb.core_code.clone());
 
             let ctx = cpu.ctx_mut();
             ctx.set_halt_line(true);
@@ -110,12 +138,42 @@ impl Sp {
             // Configure RSP internal core bus
             let spb = sp.borrow();
             let mut bus = spb.core_bus.borrow_mut();
-            bus.map_device(0x0000_0000, &sp, 0)?;
+            bus.map_device(0x0000_0000, &sp, 0).map_err(bus_error)?;
         }
 
         Ok(sp)
     }
 
+    pub fn set_interrupt_log(&mut self, ints: InterruptLog) {
+        self.ints = ints;
+    }
+
+    pub fn set_code_watch(&mut self, code: mips64::CodeWatch) {
+        self.code = code;
+    }
+
+    // Invalidate the code fetched from DMEM/IMEM by both the CPU and the RSP
+    // core, after a write that bypassed the bus (e.g. from the debugger).
+    // addr is relative to the start of DMEM.
+    pub fn invalidate_code(&self, addr: u32, len: u32) {
+        self.code.invalidate(0x0400_0000 + addr, len);
+        self.core_code.invalidate(addr & 0x1FFF, len);
+    }
+
+    // Select the tasks that are emulated at high level, rather than by
+    // running their microcode on the RSP core.
+    pub fn set_hle(&mut self, config: HleConfig) {
+        self.tasks.set_config(config);
+    }
+
+    // Try to run the task found in DMEM through HLE. Returns true if the
+    // task was completed, so that the RSP core does not need to start.
+    fn run_hle_task(&mut self) -> bool {
+        let task = OsTask::parse(&self.dmem.buf());
+        let bus = self.main_bus.borrow();
+        self.tasks.dispatch(task, &bus)
+    }
+
     fn get_status(&self) -> StatusFlags {
         StatusFlags::from_bits(self.reg_status.get()).unwrap()
     }
@@ -137,7 +195,7 @@ impl Sp {
             error!(self.logger, "clear RSP Interrupt not implemented");
         }
         if new & (1 << 4) != 0 {
-            error!(self.logger, "set RSP Interrupt not implemented");
+            self.ints.raise(Interrupt::Sp);
         }
         if new & (1 << 5) != 0 {
             status.remove(StatusFlags::SINGLESTEP);
@@ -200,6 +258,20 @@ impl Sp {
             status.insert(StatusFlags::SIG7);
         }
 
+        // The RSP is being started: check if the task can be run through HLE.
+        // If so, the RSP stays halted, and it signals the completion of the
+        // task as the microcode would (SIG2 is "task done" in libultra).
+        if self.get_status().contains(StatusFlags::HALT)
+            && !status.contains(StatusFlags::HALT)
+            && !status.contains(StatusFlags::SINGLESTEP)
+            && self.run_hle_task()
+        {
+            status.insert(StatusFlags::HALT | StatusFlags::BROKE | StatusFlags::SIG2);
+            if status.contains(StatusFlags::INTBREAK) {
+                self.ints.raise(Interrupt::Sp);
+            }
+        }
+
         info!(self.logger, "write status reg"; o!("status" => format!("{:?}", status)));
         let mut cpu = self.core_cpu.borrow_mut();
         self.set_status(status, cpu.ctx_mut());
@@ -214,17 +286,23 @@ impl Sp {
             if status.contains(StatusFlags::HALT) {
                 ctx.set_halt_line(true);
                 if status.contains(StatusFlags::INTBREAK) {
-                    // FIXME: generate interrupt on break
+                    self.ints.raise(Interrupt::Sp);
                 }
             } else {
-                // Releasing HALT causes a reset.
+                // Releasing HALT causes a reset (but not while single-stepping,
+                // where each release executes the next instruction).
                 // FIXME: I would love to call reset() here but borrowing
                 // rules doesn't allow me re-entrancy into CPU.
                 //cpu.reset();
-                ctx.set_pc(0x1000);
+                if !status.contains(StatusFlags::SINGLESTEP) {
+                    ctx.set_pc(0x1000);
+                }
                 ctx.set_halt_line(false);
             }
         }
+        if changed.contains(StatusFlags::SINGLESTEP) {
+            ctx.set_single_step_line(status.contains(StatusFlags::SINGLESTEP));
+        }
     }
 
     fn dma_xfer(
@@ -270,6 +348,8 @@ impl Sp {
             skip,
             0,
         );
+        self.core_code
+            .invalidate(self.reg_dma_rsp_addr.get() & 0x1FFF, (width * count) as u32);
     }
 
     fn cb_write_reg_dma_wr_len(&self, _old: u32, val: u32) {
@@ -293,6 +373,10 @@ impl Sp {
             0,
             skip,
         );
+        self.code.invalidate(
+            self.reg_dma_rdram_addr.get(),
+            ((width + skip) * count) as u32,
+        );
     }
 
     fn cb_write_reg_rsp_pc(&self, _old: u32, val: u32) {
@@ -336,6 +420,15 @@ impl mips64::Cop0 for SpCop0 {
             _ => unimplemented!(),
         }
     }
+
+    // In single-step mode, the RSP halts after each instruction, and waits
+    // for the CPU to release HALT.
+    fn single_step(&mut self, ctx: &mut mips64::CpuContext) {
+        let mut sp = self.sp.borrow_mut();
+        let mut status = sp.get_status();
+        status.insert(StatusFlags::HALT);
+        sp.set_status(status, ctx);
+    }
 }
 
 impl mips64::Cop for SpCop0 {
@@ -346,7 +439,7 @@ impl mips64::Cop for SpCop0 {
         panic!("unsupported COP0 reg access in RSP")
     }
 
-    fn op(&mut self, _cpu: &mut mips64::CpuContext, _opcode: u32) {
+    fn op(&mut self, _cpu: &mut mips64::CpuContext, _opcode: u32, _bus: &mut mips64::CpuBus) {
         panic!("unsupported COP0 opcode in RSP")
     }
 }
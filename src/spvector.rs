@@ -6,13 +6,11 @@ use super::vops::sse41 as vops;
 
 use super::sp::Sp;
 use byteorder::{ByteOrder, LittleEndian};
-use emu::bus::be::{Bus, DevPtr};
+use emu::bus::be::DevPtr;
 use emu::int::Numerics;
-use mips64::{Cop, CpuContext};
+use mips64::{Cop, CpuBus, CpuContext};
 use slog;
 use std::arch::x86_64::*;
-use std::cell::RefCell;
-use std::rc::Rc;
 
 // Vector registers as array of u8.
 // We define a separate structure for this array to be able
@@ -255,7 +253,7 @@ impl SpVector {
                     op.setvd(res);
                     op.setaccum(0, res);
                 }
-                _ => panic!("unimplemented COP2 VU opcode={}", op.func().hex()),
+                _ => cpu.unimplemented(&op.spv.logger, op.op),
             }
         } else {
             match op.e() {
@@ -263,9 +261,9 @@ impl SpVector {
                     0 => cpu.regs[op.rt()] = op.spv.vco() as u64,
                     1 => cpu.regs[op.rt()] = op.spv.vcc() as u64,
                     2 => cpu.regs[op.rt()] = op.spv.vce() as u64,
-                    _ => panic!("unimplement COP2 CTC2 reg:{}", op.rs()),
+                    _ => cpu.unimplemented(&op.spv.logger, op.op),
                 },
-                _ => panic!("unimplemented COP2 non-VU opcode={:x}", op.e()),
+                _ => cpu.unimplemented(&op.spv.logger, op.op),
             }
         }
     }
@@ -301,18 +299,18 @@ impl Cop for SpVector {
         }
     }
 
-    fn op(&mut self, cpu: &mut CpuContext, op: u32) {
+    fn op(&mut self, cpu: &mut CpuContext, op: u32, _bus: &mut CpuBus) {
         unsafe { self.uop(cpu, op) }
     }
 
-    fn lwc(&mut self, op: u32, ctx: &CpuContext, _bus: &Rc<RefCell<Box<Bus>>>) {
+    fn lwc(&mut self, op: u32, ctx: &CpuContext, _paddr: u32, _bus: &CpuBus) {
         let sp = self.sp.borrow();
         let dmem = sp.dmem.buf();
         let (base, vt, op, _element, offset) = SpVector::oploadstore(op, ctx);
         match op {
             0x04 => {
                 // LQV
-                let ea = ((base + (offset << 4)) & 0xFFF) as usize;
+                let ea = (base.wrapping_add(offset << 4) & 0xFFF) as usize;
                 let ea_end = (ea & !0xF) + 0x10;
                 for (m, r) in dmem[ea..ea_end]
                     .iter()
@@ -324,14 +322,14 @@ impl Cop for SpVector {
             _ => panic!("unimplemented VU load opcode={}", op.hex()),
         }
     }
-    fn swc(&mut self, op: u32, ctx: &CpuContext, _bus: &Rc<RefCell<Box<Bus>>>) {
+    fn swc(&mut self, op: u32, ctx: &CpuContext, _paddr: u32, _bus: &CpuBus) {
         let sp = self.sp.borrow();
         let mut dmem = sp.dmem.buf();
         let (base, vt, op, _element, offset) = SpVector::oploadstore(op, ctx);
         match op {
             0x04 => {
                 // SQV
-                let ea = ((base + (offset << 4)) & 0xFFF) as usize;
+                let ea = (base.wrapping_add(offset << 4) & 0xFFF) as usize;
                 let ea_end = (ea & !0xF) + 0x10;
                 for (m, r) in dmem[ea..ea_end]
                     .iter_mut()
@@ -344,10 +342,10 @@ impl Cop for SpVector {
         }
     }
 
-    fn ldc(&mut self, _op: u32, _ctx: &CpuContext, _bus: &Rc<RefCell<Box<Bus>>>) {
+    fn ldc(&mut self, _op: u32, _ctx: &CpuContext, _paddr: u32, _bus: &CpuBus) {
         unimplemented!()
     }
-    fn sdc(&mut self, _op: u32, _ctx: &CpuContext, _bus: &Rc<RefCell<Box<Bus>>>) {
+    fn sdc(&mut self, _op: u32, _ctx: &CpuContext, _paddr: u32, _bus: &CpuBus) {
         unimplemented!()
     }
 }
@@ -1,10 +1,13 @@
 extern crate byteorder;
 extern crate emu;
 extern crate slog;
+use self::byteorder::LittleEndian;
+use super::rdp::HiresCache;
 use emu::bus::be::{Bus, Reg32};
 use emu::gfx::*;
 use emu::int::Numerics;
-use std::cell::RefCell;
+use interrupts::{Interrupt, InterruptLog};
+use std::cell::{Cell, RefCell};
 use std::rc::Rc;
 
 #[derive(DeviceBE)]
@@ -28,11 +31,16 @@ pub struct Vi {
     //     3: neither (replicate pixels, no interpolate)
     // [11] reserved - diagnostics only
     // [15:12] reserved
-    #[reg(offset = 0x00, rwmask = 0xFFFF)]
+    #[reg(
+        offset = 0x00,
+        rwmask = 0xFFFF,
+        fields = "type:0-1 gamma_dither:2 gamma:3 divot:4 serrate:6 aa_mode:8-9"
+    )]
     status: Reg32,
 
     // [23:0] frame buffer origin in bytes
-    #[reg(offset = 0x04, rwmask = 0xFFFFFF)]
+    //       - Writes of a different address are buffer swaps
+    #[reg(offset = 0x04, rwmask = 0xFFFFFF, wcb)]
     origin: Reg32,
 
     // [11:0] frame buffer line width in pixels
@@ -74,12 +82,12 @@ pub struct Vi {
 
     // [9:0] end of active video in screen pixels
     // [25:16] start of active video in screen pixels
-    #[reg(offset = 0x24, rwmask = 0x3FFFFFF)]
+    #[reg(offset = 0x24, rwmask = 0x3FFFFFF, fields = "end:0-9 start:16-25")]
     horizontal_video: Reg32,
 
     // [9:0] end of active video in screen half-lines
     // [25:16] start of active video in screen half-lines
-    #[reg(offset = 0x28, rwmask = 0x3FFFFFF)]
+    #[reg(offset = 0x28, rwmask = 0x3FFFFFF, fields = "end:0-9 start:16-25")]
     vertical_video: Reg32,
 
     // [9:0] end of color burst enable in half-lines
@@ -99,6 +107,11 @@ pub struct Vi {
 
     logger: slog::Logger,
     bus: Rc<RefCell<Box<Bus>>>,
+    hires: Option<Rc<RefCell<HiresCache>>>,
+    ints: InterruptLog,
+    yuv: bool,
+    field: Cell<u32>,
+    swapped: Cell<bool>,
 }
 
 impl Vi {
@@ -120,87 +133,363 @@ impl Vi {
             y_scale: Reg32::default(),
             logger,
             bus,
+            hires: None,
+            ints: InterruptLog::new(),
+            yuv: false,
+            field: Cell::new(0),
+            swapped: Cell::new(false),
         }
     }
 
+    pub fn set_interrupt_log(&mut self, ints: InterruptLog) {
+        self.ints = ints;
+    }
+
+    pub fn set_hires_cache(&mut self, hires: Rc<RefCell<HiresCache>>) {
+        self.hires = Some(hires);
+    }
+
+    // Treat 16-bit framebuffers as YUV. The VI itself only displays RGB, but
+    // some games play FMVs by having the RSP write decoded YUV frames, that
+    // are displayed after a conversion step that we skip.
+    pub fn set_yuv_framebuffer(&mut self, yuv: bool) {
+        self.yuv = yuv;
+    }
+
+    // Update V_CURRENT at the beginning of each line (y counts half-lines).
+    // V_CURRENT changes every two half-lines: its lsb holds the field
+    // number, which alternates every frame in interlaced mode (serrate).
+    // The interrupt is raised once per field, ignoring the field bit of
+    // V_INTR.
     pub fn set_line(&self, y: usize) {
-        self.current_line.set(y as u32);
+        if y == 0 {
+            let serrate = self.status.get() & (1 << 6) != 0;
+            let field = if serrate { self.field.get() ^ 1 } else { 0 };
+            self.field.set(field);
+        }
+        let half_line = (y as u32 & !1) | self.field.get();
+        self.current_line.set(half_line);
+        self.ints.set_line(y);
+        if y & 1 == 0 && half_line & !1 == self.vertical_interrupt.get() & !1 {
+            self.ints.raise(Interrupt::Vi);
+        }
+    }
+
+    fn cb_write_origin(&self, old: u32, new: u32) {
+        if old != new {
+            self.swapped.set(true);
+        }
     }
 
-    fn cb_write_current_line(&self, _old: u32, new: u32) {
-        error!(self.logger, "write VI current line"; o!("val" => new.hex()));
+    pub fn origin(&self) -> u32 {
+        self.origin.get()
     }
 
+    // Return true if the game changed the framebuffer origin since the last
+    // call.
+    pub fn take_swap(&self) -> bool {
+        self.swapped.replace(false)
+    }
+
+    // Writing V_CURRENT acknowledges the VI interrupt.
+    fn cb_write_current_line(&self, _old: u32, _new: u32) {
+        self.ints.ack(Interrupt::Vi);
+    }
+
+    // Scan out the framebuffer at the VI origin. Anything that can't be
+    // displayed (display disabled, origin outside RDRAM, framebuffer
+    // overflowing the memory) results in a black screen: games sometimes
+    // program a bogus origin for a frame while reconfiguring the VI.
     pub fn draw_frame(&self, screen: &mut GfxBufferMutLE<Rgb888>) {
         let bpp = self.status.get() & 3;
 
         // display disable -> clear screen
         if bpp == 0 || bpp == 1 {
-            let black = Color::<Rgb888>::new_clamped(0, 0, 0, 0);
-            for y in 0..480 {
-                let mut line = screen.line(y);
-                for x in 0..640 {
-                    line.set(x, black);
+            Vi::clear(screen);
+            return;
+        }
+
+        let stride = self.width.get() as usize;
+        let (width, height) = self.visible_size();
+        info!(self.logger, "draw frame"; o!("origin" => self.origin.get().hex(),
+            "width" => width, "height" => height, "stride" => stride));
+        if width == 0 || height == 0 {
+            Vi::clear(screen);
+            return;
+        }
+
+        // Write back what the RDP drew at a higher resolution before reading
+        // the framebuffer.
+        if let Some(ref hires) = self.hires {
+            let len = stride * height * if bpp == 3 { 4 } else { 2 };
+            hires
+                .borrow_mut()
+                .flush(&self.bus.borrow(), self.origin.get(), len);
+        }
+        let memio = self.bus.borrow().fetch_read::<u8>(self.origin.get());
+        let src = match memio.mem() {
+            Some(src) => src,
+            None => {
+                warn!(self.logger, "VI origin outside memory"; o!("origin" => self.origin.get().hex()));
+                Vi::clear(screen);
+                return;
+            }
+        };
+
+        if self.yuv && bpp == 2 {
+            match Vi::convert_yuv(src, stride, height) {
+                Some(fb) => Vi::draw_stretched(screen, &fb.buf()),
+                None => {
+                    error!(self.logger, "invalid YUV framebuffer"; o!("width" => stride));
+                    Vi::clear(screen);
                 }
             }
             return;
         }
 
-        info!(self.logger, "draw frame"; o!("origin" => self.origin.get().hex()));
-        let memio = self.bus.borrow().fetch_read::<u8>(self.origin.get());
-        let src = memio.mem().unwrap();
-
-        match self.width.get() {
-            640 => {
-                let src = GfxBufferLE::<Rgb888>::new(src, 640, 480, 640 * 4).unwrap();
-                for y in 0..480 {
-                    let mut dst = screen.line(y);
-                    let src = src.line(y);
-                    for x in 0..640 {
-                        dst.set(x, src.get(x));
+        // If the RDP rendered this framebuffer at a higher resolution, and
+        // it was not modified since, display the high-resolution copy.
+        if stride == 320 {
+            if let Some(ref hires) = self.hires {
+                if let Some(fb) = hires.borrow().find(self.origin.get(), src) {
+                    let (mem, pitch) = fb.raw();
+                    let (w, h) = (fb.width(), fb.height());
+                    let drawn = match bpp {
+                        3 => GfxBufferLE::<Rgb888>::new(mem, w, h, pitch)
+                            .map(|src| Vi::draw_stretched(screen, &src))
+                            .is_ok(),
+                        _ => GfxBufferLE::<Rgb555>::new(mem, w, h, pitch)
+                            .map(|src| Vi::draw_stretched(screen, &src))
+                            .is_ok(),
+                    };
+                    if drawn {
+                        return;
                     }
                 }
             }
+        }
 
-            320 => {
-                match bpp {
-                    // 32-bit
-                    3 => {
-                        let src = GfxBufferLE::<Rgb888>::new(src, 320, 240, 320 * 4).unwrap();
-                        for y in 0..240 {
-                            let (mut dst1, mut dst2) = screen.lines(y * 2, y * 2 + 1);
-                            let src = src.line(y);
-                            for x in 0..320 {
-                                let px = src.get(x);
-                                dst1.set(x * 2, px);
-                                dst1.set(x * 2 + 1, px);
-                                dst2.set(x * 2, px);
-                                dst2.set(x * 2 + 1, px);
-                            }
-                        }
-                    }
-                    // 16-bit
-                    2 => {
-                        let src = GfxBufferLE::<Rgb555>::new(src, 320, 240, 320 * 2).unwrap();
-                        for y in 0..240 {
-                            let (mut dst1, mut dst2) = screen.lines(y * 2, y * 2 + 1);
-                            let src = src.line(y);
-                            for x in 0..320 {
-                                let px = src.get(x).cconv();
-                                dst1.set(x * 2, px);
-                                dst1.set(x * 2 + 1, px);
-                                dst2.set(x * 2, px);
-                                dst2.set(x * 2 + 1, px);
-                            }
-                        }
-                    }
-                    _ => unimplemented!(),
+        match bpp {
+            3 => self.draw_framebuffer::<Rgb888>(screen, src, width, height, stride * 4),
+            _ => self.draw_framebuffer::<Rgb555>(screen, src, width, height, stride * 2),
+        }
+    }
+
+    fn clear(screen: &mut GfxBufferMutLE<Rgb888>) {
+        screen.fill(Color::<Rgb888>::new_clamped(0, 0, 0, 0));
+    }
+
+    // Size in pixels of the visible part of the framebuffer: the active video
+    // area of the screen (H_VIDEO, and V_VIDEO in half-lines), resampled by
+    // the scale factors (X_SCALE/Y_SCALE, in 2.10 format). The width is
+    // bounded by the framebuffer line width. If the VI is not fully
+    // programmed, a 4:3 picture as wide as the framebuffer is assumed.
+    fn visible_size(&self) -> (usize, usize) {
+        let stride = self.width.get() as usize;
+        let (hv, vv) = (self.horizontal_video.get(), self.vertical_video.get());
+        let hlen = (hv & 0x3FF).saturating_sub((hv >> 16) & 0x3FF);
+        let vlen = (vv & 0x3FF).saturating_sub((vv >> 16) & 0x3FF) / 2;
+        let width = (hlen * (self.x_scale.get() & 0xFFF) / 1024) as usize;
+        let height = (vlen * (self.y_scale.get() & 0xFFF) / 1024) as usize;
+        if width == 0 || height == 0 {
+            (stride, stride * 3 / 4)
+        } else {
+            (width.min(stride), height)
+        }
+    }
+
+    // Scan out a framebuffer of any size, with the specified pitch in bytes.
+    // On a standard 640x480 screen, the standard resolutions are copied (or
+    // doubled); anything else (eg: unusual widths like 292 or 424, or a
+    // widescreen output) is stretched to cover the screen.
+    fn draw_framebuffer<CF: ColorFormat>(
+        &self,
+        screen: &mut GfxBufferMutLE<Rgb888>,
+        src: &[u8],
+        width: usize,
+        height: usize,
+        pitch: usize,
+    ) {
+        let fb = match GfxBufferLE::<CF>::new(src, width, height, pitch) {
+            Ok(fb) => fb,
+            Err(err) => {
+                error!(self.logger, "invalid framebuffer"; o!("err" => err));
+                Vi::clear(screen);
+                return;
+            }
+        };
+        let standard = (screen.width(), screen.height()) == (640, 480);
+        match (width, height) {
+            (640, 480) if standard => {
+                for (mut dst, src) in screen.iter_lines_mut().zip(fb.iter_lines()) {
+                    dst.convert_from(&src);
                 }
             }
+            (320, 240) if standard => Vi::draw_scaled2x(screen, fb.iter_lines()),
+            _ => Vi::draw_stretched(screen, &fb),
+        }
+    }
+
+    // Convert a YUV framebuffer into RGB. Pixels are stored in pairs sharing
+    // the chroma samples (UYVY), so that each pixel takes 16 bits.
+    fn convert_yuv(src: &[u8], width: usize, height: usize) -> Option<OwnedGfxBufferLE<Rgb888>> {
+        if width == 0 || width % 2 != 0 || src.len() < width * height * 2 {
+            return None;
+        }
+        let mut fb = OwnedGfxBufferLE::<Rgb888>::new(width, height);
+        {
+            let mut buf = fb.buf_mut();
+            for (mut dst, src) in buf.iter_lines_mut().zip(src.chunks(width * 2)) {
+                for (x, p) in src.chunks(4).enumerate() {
+                    let (u, y0, v, y1) = (p[0], p[1], p[2], p[3]);
+                    dst.set2(x * 2, Color::from_yuv(y0, u, v), Color::from_yuv(y1, u, v));
+                }
+            }
+        }
+        Some(fb)
+    }
+
+    // Draw a low-resolution framebuffer, doubling each pixel horizontally
+    // and each line vertically.
+    fn draw_scaled2x<CF: ColorFormat>(
+        screen: &mut GfxBufferMutLE<Rgb888>,
+        src: GfxLines<CF, LittleEndian>,
+    ) {
+        let mut dst = screen.iter_lines_mut();
+        for src in src {
+            let mut dst1 = match dst.next() {
+                Some(line) => line,
+                None => break,
+            };
+            dst1.convert_from_scaled(&src, 2);
+            if let Some(mut dst2) = dst.next() {
+                dst2.copy_from(&dst1.as_line());
+            }
+        }
+    }
+
+    // Draw a framebuffer of any size, stretching it to cover the whole screen.
+    fn draw_stretched<CF: ColorFormat>(
+        screen: &mut GfxBufferMutLE<Rgb888>,
+        src: &GfxBufferLE<CF>,
+    ) {
+        let (sh, dh) = (src.height(), screen.height());
+        for (y, mut dst) in screen.iter_lines_mut().enumerate() {
+            dst.convert_from_stretched(&src.line(y * sh / dh));
+        }
+    }
+}
 
-            _ => {
-                error!(self.logger, "unsupported screen width"; o!("width" => self.width.get()));
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use emu::bus::be::{DevPtr, Mem, MemFlags};
+
+    #[test]
+    fn visible_size() {
+        let logger = slog::Logger::root(slog::Discard, o!());
+        let bus = Rc::new(RefCell::new(Bus::new(logger.clone())));
+        let vi = Vi::new(logger, bus);
+
+        // Not programmed: 4:3 picture
+        vi.width.set(320);
+        assert_eq!(vi.visible_size(), (320, 240));
+
+        // NTSC, 320 pixels resampled over the active area (237 lines)
+        vi.horizontal_video.set(0x006C_02EC);
+        vi.vertical_video.set(0x0025_01FF);
+        vi.x_scale.set(0x200);
+        vi.y_scale.set(0x400);
+        assert_eq!(vi.visible_size(), (320, 237));
+
+        // Unusual width, bounded by the framebuffer line width
+        vi.width.set(292);
+        vi.x_scale.set(0x1D3);
+        assert_eq!(vi.visible_size(), (291, 237));
+        vi.x_scale.set(0x400);
+        assert_eq!(vi.visible_size(), (292, 237));
+    }
+    #[test]
+    fn current_line_and_ack() {
+        let logger = slog::Logger::root(slog::Discard, o!());
+        let bus = Rc::new(RefCell::new(Bus::new(logger.clone())));
+        let mut vi = DevPtr::new(Vi::new(logger, bus.clone()));
+        bus.borrow_mut().map_device(0x0440_0000, &vi, 0).unwrap();
+        let ints = InterruptLog::new();
+        vi.borrow_mut().set_interrupt_log(ints.clone());
+
+        // Progressive: the field bit is always clear
+        bus.borrow().write::<u32>(0x0440_000C, 0x200);
+        for y in 0..0x203 {
+            vi.borrow().set_line(y);
+        }
+        assert_eq!(bus.borrow().read::<u32>(0x0440_0010), 0x202);
+        assert_eq!(ints.stats().total(Interrupt::Vi), 1);
+        assert!(ints.stats().pending(Interrupt::Vi));
+
+        // The game acknowledges the interrupt by writing V_CURRENT
+        bus.borrow().write::<u32>(0x0440_0010, 0);
+        assert!(!ints.stats().pending(Interrupt::Vi));
+        assert_eq!(bus.borrow().read::<u32>(0x0440_0010), 0x202);
+
+        // Interlaced: the field bit alternates every frame
+        bus.borrow().write::<u32>(0x0440_0000, 0x42);
+        vi.borrow().set_line(0);
+        vi.borrow().set_line(5);
+        assert_eq!(bus.borrow().read::<u32>(0x0440_0010), 0x5);
+        vi.borrow().set_line(0);
+        vi.borrow().set_line(5);
+        assert_eq!(bus.borrow().read::<u32>(0x0440_0010), 0x4);
+
+        // The interrupt is raised once per field, whatever the field bit
+        for v_intr in &[0x200, 0x201] {
+            bus.borrow().write::<u32>(0x0440_000C, *v_intr);
+            let total = ints.stats().total(Interrupt::Vi);
+            for _ in 0..4 {
+                for y in 0..0x20D {
+                    vi.borrow().set_line(y);
+                }
             }
+            assert_eq!(ints.stats().total(Interrupt::Vi), total + 4);
+        }
+    }
+
+    #[test]
+    fn bogus_origin() {
+        let logger = slog::Logger::root(slog::Discard, o!());
+        let ram = Mem::new(0x1000, MemFlags::default());
+        let bus = Rc::new(RefCell::new(Bus::new(logger.clone())));
+        bus.borrow_mut()
+            .map_mem(0x0000_0000, 0x0000_0FFF, &ram)
+            .unwrap();
+        let vi = Vi::new(logger, bus.clone());
+        vi.status.set(3);
+        vi.width.set(16);
+        for addr in (0..0x1000).step_by(4) {
+            bus.borrow().write::<u32>(addr, 0xFFFF_FFFF);
         }
+
+        let mut screen = OwnedGfxBufferLE::<Rgb888>::new(64, 48);
+        let mut check = |origin: u32, expected: (i32, i32, i32)| {
+            screen
+                .buf_mut()
+                .fill(Color::new_clamped(0x80, 0x80, 0x80, 0));
+            vi.origin.set(origin);
+            vi.draw_frame(&mut screen.buf_mut());
+            let buf = screen.buf();
+            for y in 0..48 {
+                for x in 0..64 {
+                    let (r, g, b, _) = buf.line(y).get(x).components();
+                    assert_eq!((r, g, b), expected, "origin {:x}", origin);
+                }
+            }
+        };
+
+        // A valid framebuffer in RAM
+        check(0x0000_0000, (0xFF, 0xFF, 0xFF));
+        // Origin outside memory: black
+        check(0x0450_0000, (0, 0, 0));
+        // Framebuffer overflowing the end of RAM: black
+        check(0x0000_0F00, (0, 0, 0));
     }
 }
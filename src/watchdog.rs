@@ -0,0 +1,69 @@
+/// Watchdog detects a machine that stopped making progress: a game that
+/// neither changes the displayed framebuffer, nor takes any interrupt, for a
+/// long time is most likely stuck in a loop. This avoids spinning forever in
+/// headless runs.
+#[derive(Clone, Debug)]
+pub struct Watchdog {
+    limit: i64,
+    // Progress indicators (VI origin, interrupts taken by the CPU) at the
+    // last check, and CPU clock at which they last changed.
+    origin: u32,
+    ints: u64,
+    since: i64,
+    tripped: bool,
+}
+
+impl Watchdog {
+    /// Create a watchdog tripping after `limit` CPU cycles without progress.
+    pub fn new(limit: i64) -> Watchdog {
+        Watchdog {
+            limit,
+            origin: 0,
+            ints: 0,
+            since: 0,
+            tripped: false,
+        }
+    }
+
+    /// Check the progress indicators of the machine. Return true the first
+    /// time the watchdog trips.
+    pub fn check(&mut self, clock: i64, origin: u32, ints: u64) -> bool {
+        if origin != self.origin || ints != self.ints {
+            self.origin = origin;
+            self.ints = ints;
+            self.since = clock;
+            return false;
+        }
+        if self.tripped || clock - self.since < self.limit {
+            return false;
+        }
+        self.tripped = true;
+        true
+    }
+
+    pub fn tripped(&self) -> bool {
+        self.tripped
+    }
+
+    /// Number of cycles elapsed without progress, as of the last check.
+    pub fn idle_cycles(&self, clock: i64) -> i64 {
+        clock - self.since
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn watchdog() {
+        let mut wd = Watchdog::new(1000);
+        assert!(!wd.check(500, 0x1000, 0));
+        assert!(!wd.check(900, 0x1000, 1));
+        assert!(!wd.check(1800, 0x1000, 1));
+        assert!(wd.check(1900, 0x1000, 1));
+        assert!(wd.tripped());
+        assert_eq!(wd.idle_cycles(1900), 1000);
+        assert!(!wd.check(5000, 0x1000, 1));
+    }
+}
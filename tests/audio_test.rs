@@ -0,0 +1,338 @@
+#[macro_use]
+extern crate slog;
+
+extern crate crc;
+extern crate emu;
+extern crate r64emu;
+
+use crc::crc32::{self, Hasher32};
+use emu::bus::be::{Bus, Mem};
+use emu::bus::DevPtr;
+use emu::int::Numerics;
+use r64emu::ai::Ai;
+use r64emu::hle::{AudioTasks, OsTask, TaskHandler};
+use r64emu::report::CompatReport;
+use slog::Discard;
+use std::cell::RefCell;
+use std::env;
+use std::fs;
+use std::rc::Rc;
+
+static ROMS_PATH: &'static str = "roms/tests";
+static GOLDEN_PATH: &'static str = "tests/audio_golden.txt";
+
+const FPS: usize = 60;
+
+// Emulate each input listed in the golden file, and compare the hash of its
+// audio output with the recorded one. With R64_BLESS_AUDIO set, the golden
+// file is rewritten with the current hashes instead.
+#[test]
+fn golden_audio() {
+    let bless = env::var_os("R64_BLESS_AUDIO").is_some();
+    let golden = fs::read_to_string(GOLDEN_PATH).unwrap();
+
+    let mut out = String::new();
+    let mut failed = Vec::new();
+    for line in golden.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if line.starts_with('#') || fields.is_empty() {
+            out += line;
+            out += "\n";
+            continue;
+        }
+        if fields.len() != 3 {
+            panic!("invalid golden entry: {}", line);
+        }
+        let (input, secs) = (fields[0], fields[1].parse::<usize>().unwrap());
+
+        let hash = if input.starts_with("synth/") {
+            synth_audio_hash(&input["synth/".len()..], secs * FPS)
+        } else {
+            let logger = slog::Logger::root(Discard, o!());
+            let romfn = format!("{}/{}", ROMS_PATH, input);
+            let report = CompatReport::run(logger, &romfn, secs * FPS, None).unwrap();
+            report.audio_hash.unwrap_or("-".into())
+        };
+        if hash != fields[2] {
+            failed.push(format!("{}: {} (expected {})", input, hash, fields[2]));
+        }
+        out += &format!("{} {} {}\n", input, secs, hash);
+    }
+
+    if bless {
+        fs::write(GOLDEN_PATH, out).unwrap();
+    } else {
+        assert!(
+            failed.is_empty(),
+            "audio output differs:\n{}",
+            failed.join("\n")
+        );
+    }
+}
+
+// Synthetic inputs feed the AI from RDRAM, without a ROM: either a waveform
+// written directly, or the output of MusyX tasks run through HLE. Each frame
+// produces one buffer, that is played through an AI DMA.
+fn synth_audio_hash(name: &str, frames: usize) -> String {
+    let mut synth = Synth::new();
+    let mut digest = crc32::Digest::new(crc32::IEEE);
+    let mut samples = Vec::new();
+    for frame in 0..frames {
+        let (addr, len) = match name {
+            "ai-dma" => synth.waveform(frame),
+            "musyx-v1" => synth.musyx(frame, false),
+            "musyx-v2" => synth.musyx(frame, true),
+            _ => panic!("unknown synthetic input: {}", name),
+        };
+        synth.play(addr, len);
+        synth.ai.borrow_mut().take_samples(&mut samples);
+        // Same hash as the compatibility reports: 16-bit little-endian
+        for s in &samples {
+            digest.write(&[*s as u8, (*s >> 8) as u8]);
+        }
+        samples.clear();
+    }
+    digest.sum32().hex()
+}
+
+const AI_BASE: u32 = 0x0450_0000;
+const AI_DAC_PERIOD_32KHZ: u32 = 48_681_812 / 32_000 - 1;
+
+// RDRAM layout of the synthetic inputs
+const UCODE_DATA: u32 = 0x0000_1000;
+const TASK_DATA: u32 = 0x0001_0000;
+const MIXER_STATE: u32 = 0x0002_0000;
+const WAVE_SAW: u32 = 0x0003_0000;
+const WAVE_TRIANGLE: u32 = 0x0003_1000;
+const SFX: u32 = 0x0004_0000;
+const SFX_CBUFFER: u32 = 0x0004_1000;
+const OUTPUT: u32 = 0x0005_0000;
+const V2_SUBFRAMES: u32 = 0x0006_0000;
+const V2_INTERLEAVE: u32 = 0x0006_4000;
+const V2_SUM: u32 = 0x0006_5000;
+
+// Layout of the MusyX structures (see src/hle/musyx.rs)
+const SFD_SFX_INDEX: u32 = 0x02;
+const SFD_VOICE_BITMASK: u32 = 0x04;
+const SFD_STATE_PTR: u32 = 0x08;
+const SFD_SFX_PTR: u32 = 0x0C;
+const SFD_VOICES: u32 = 0x10;
+const SFD2_16_BITMASK: u32 = 0x16;
+const SFD2_18_PTR: u32 = 0x18;
+const SFD2_1C_PTR: u32 = 0x1C;
+const SFD2_20_PTR: u32 = 0x20;
+const SFD2_VOICES: u32 = 0x28;
+const VOICE_ENV_BEGIN: u32 = 0x00;
+const VOICE_ENV_STEP: u32 = 0x10;
+const VOICE_PITCH_Q16: u32 = 0x20;
+const VOICE_PITCH_SHIFT: u32 = 0x22;
+const VOICE_CATSRC_0: u32 = 0x24;
+const VOICE_PCM16_COUNT: u32 = 0x40;
+const VOICE_INTERLEAVED_PTR: u32 = 0x44;
+const VOICE_END_POINT: u32 = 0x48;
+const VOICE_SIZE: u32 = 0x50;
+const SFX_CBUFFER_LENGTH: u32 = 0x04;
+const SFX_TAP_COUNT: u32 = 0x08;
+const SFX_FIR4_HGAIN: u32 = 0x0A;
+const SFX_TAP_DELAYS: u32 = 0x0C;
+const SFX_TAP_GAINS: u32 = 0x2C;
+const SFX_GAINS: u32 = 0x3C;
+const SFX_FIR4_HCOEFFS: u32 = 0x40;
+
+const SUBFRAME_SIZE: u32 = 192;
+const SUBFRAMES_PER_TASK: u32 = 4;
+const WAVE_PERIOD: u32 = 256;
+// Samples loaded for each voice: enough for a subframe at the highest
+// pitch, plus the taps of the resampling filter.
+const VOICE_SAMPLES: u32 = 248;
+
+struct Synth {
+    bus: Rc<RefCell<Box<Bus>>>,
+    ai: DevPtr<Ai>,
+    // Position of each voice within its waveform (Q16.16)
+    voice_pos: [u32; 2],
+}
+
+impl Synth {
+    fn new() -> Synth {
+        let logger = slog::Logger::root(Discard, o!());
+        let bus = Rc::new(RefCell::new(Bus::new(logger.clone())));
+        let ram = Mem::new(0x10_0000, Default::default());
+        let ai = DevPtr::new(Ai::new(logger, bus.clone()));
+        {
+            let mut bus = bus.borrow_mut();
+            bus.map_mem(0x0000_0000, 0x000F_FFFF, &ram).unwrap();
+            bus.map_device(AI_BASE, &ai, 0).unwrap();
+        }
+
+        let synth = Synth {
+            bus,
+            ai,
+            voice_pos: [0; 2],
+        };
+        {
+            let bus = synth.bus.borrow();
+            bus.write::<u32>(AI_BASE + 0x10, AI_DAC_PERIOD_32KHZ);
+            bus.write::<u32>(AI_BASE + 0x08, 1);
+            for i in 0..WAVE_PERIOD * 4 {
+                let phase = (i % WAVE_PERIOD) as i32;
+                let saw = phase * 0x80 - 0x4000;
+                let triangle = (phase - 0x80).abs() * 0x60 - 0x1800;
+                bus.write::<u16>(WAVE_SAW + i * 2, saw as u16);
+                bus.write::<u16>(WAVE_TRIANGLE + i * 2, triangle as u16);
+            }
+        }
+        synth
+    }
+
+    // Play a buffer of interleaved stereo samples through an AI DMA.
+    fn play(&self, addr: u32, len: u32) {
+        let bus = self.bus.borrow();
+        bus.write::<u32>(AI_BASE, addr);
+        bus.write::<u32>(AI_BASE + 0x04, len);
+    }
+
+    // A triangle on the left and a sawtooth on the right, whose periods
+    // change every frame. Frames alternate between two buffers.
+    fn waveform(&self, frame: usize) -> (u32, u32) {
+        let bus = self.bus.borrow();
+        let count = 32_000 / FPS as u32 / 2 * 2;
+        let addr = OUTPUT + (frame as u32 & 1) * 0x1000;
+        let (pl, pr) = (64 + frame as u32 % 16 * 8, 100 + frame as u32 % 8 * 12);
+        for i in 0..count {
+            let t = (frame as u32 * count + i) as i32;
+            let (l, r) = ((t % pl as i32) * 2, (t % pr as i32));
+            let left = ((l - pl as i32).abs() * 0x4000 / pl as i32) - 0x2000;
+            let right = r * 0x4000 / pr as i32 - 0x2000;
+            bus.write::<u32>(addr + i * 4, (left as u32) << 16 | right as u16 as u32);
+        }
+        (addr, count * 4)
+    }
+
+    // One MusyX task per frame, with a subframe descriptor per subframe.
+    // Two voices are mixed into each subframe: a sawtooth, pitched up with
+    // envelopes ramping over the second, that also feeds the delay effect,
+    // and a triangle pitched down, that also feeds the surround bus (CC0).
+    fn musyx(&mut self, frame: usize, v2: bool) -> (u32, u32) {
+        {
+            let bus = self.bus.clone();
+            let bus = bus.borrow();
+            let (sfd_size, voices) = if v2 {
+                (SFD2_VOICES + 32 * VOICE_SIZE, SFD2_VOICES)
+            } else {
+                (SFD_VOICES + 32 * VOICE_SIZE, SFD_VOICES)
+            };
+            for n in 0..SUBFRAMES_PER_TASK {
+                let sfd = TASK_DATA + n * sfd_size;
+                let subframe = frame as u32 * SUBFRAMES_PER_TASK + n;
+                let output = OUTPUT + n * SUBFRAME_SIZE * 4;
+                bus.write::<u32>(sfd + SFD_VOICE_BITMASK, 0b11);
+                bus.write::<u32>(sfd + SFD_STATE_PTR, 0x8000_0000 | MIXER_STATE);
+                bus.write::<u32>(sfd + SFD_SFX_PTR, 0x8000_0000 | SFX);
+                bus.write::<u16>(sfd + SFD_SFX_INDEX, (subframe % 8) as u16);
+
+                let ramp = subframe * 0x0004_0000;
+                self.voice(
+                    &bus,
+                    sfd + voices,
+                    0,
+                    WAVE_SAW,
+                    0x1234,
+                    [0x1000_0000 + ramp, 0x4000_0000 - ramp, 0, 0x2000_0000],
+                    [0x0000_4000, -0x0000_4000i32 as u32, 0, 0],
+                    0,
+                );
+                let output = if v2 {
+                    V2_SUBFRAMES + n * SUBFRAME_SIZE * 2 * 3
+                } else {
+                    output
+                };
+                self.voice(
+                    &bus,
+                    sfd + voices + VOICE_SIZE,
+                    1,
+                    WAVE_TRIANGLE,
+                    0x0B6D,
+                    [0x3000_0000, 0x3000_0000, 0x2000_0000, 0],
+                    [0; 4],
+                    output,
+                );
+
+                if v2 {
+                    // Interleave the left and CC0 subframes, with gains of
+                    // 1.0 and 0.5 (Q6.10).
+                    let ptrs = V2_INTERLEAVE + n * 0x40;
+                    bus.write::<u32>(ptrs, output);
+                    bus.write::<u16>(ptrs + 4, 0x400);
+                    bus.write::<u32>(ptrs + 8, output + SUBFRAME_SIZE * 2 * 2);
+                    bus.write::<u16>(ptrs + 12, 0x200);
+                    bus.write::<u16>(sfd + SFD2_16_BITMASK, 0b11);
+                    bus.write::<u32>(sfd + SFD2_18_PTR, ptrs);
+                    bus.write::<u32>(sfd + SFD2_1C_PTR, V2_SUM);
+                    bus.write::<u32>(sfd + SFD2_20_PTR, OUTPUT + n * SUBFRAME_SIZE * 4);
+                }
+            }
+
+            // Delay effect: two taps, 3 and 5 subframes back in a circular
+            // buffer of 8 subframes.
+            bus.write::<u32>(SFX, SFX_CBUFFER);
+            bus.write::<u32>(SFX + SFX_CBUFFER_LENGTH, SUBFRAME_SIZE * 8);
+            bus.write::<u16>(SFX + SFX_TAP_COUNT, 2);
+            bus.write::<u16>(SFX + SFX_FIR4_HGAIN, 0x4000);
+            bus.write::<u32>(SFX + SFX_TAP_DELAYS, SUBFRAME_SIZE * 3);
+            bus.write::<u32>(SFX + SFX_TAP_DELAYS + 4, SUBFRAME_SIZE * 5);
+            bus.write::<u16>(SFX + SFX_TAP_GAINS, 0x3000);
+            bus.write::<u16>(SFX + SFX_TAP_GAINS + 2, 0x1800);
+            bus.write::<u16>(SFX + SFX_GAINS, 0x8000);
+            bus.write::<u16>(SFX + SFX_GAINS + 2, 0x4000);
+            for (k, c) in [0x0800u16, 0x3800, 0x3800, 0x0800].iter().enumerate() {
+                bus.write::<u16>(SFX + SFX_FIR4_HCOEFFS + k as u32 * 2, *c);
+            }
+
+            // The signature of the microcode data selects the ABI
+            let (word0, word10) = if v2 { (1, 0x0001_0010) } else { (0, 1) };
+            bus.write::<u32>(UCODE_DATA, word0);
+            bus.write::<u32>(UCODE_DATA + 0x10, word10);
+        }
+
+        let task = OsTask {
+            ty: 2,
+            ucode_data: UCODE_DATA,
+            data_ptr: TASK_DATA,
+            data_size: SUBFRAMES_PER_TASK,
+            ..Default::default()
+        };
+        AudioTasks.run(&task, &self.bus.borrow()).unwrap();
+        (OUTPUT, SUBFRAMES_PER_TASK * SUBFRAME_SIZE * 4)
+    }
+
+    // Setup a PCM16 voice for the next subframe, playing a waveform from the
+    // current position of the voice (pitch is Q4.12).
+    fn voice(
+        &mut self,
+        bus: &Bus,
+        voice: u32,
+        idx: usize,
+        wave: u32,
+        pitch: u16,
+        env: [u32; 4],
+        env_step: [u32; 4],
+        output: u32,
+    ) {
+        let pos = self.voice_pos[idx];
+        for k in 0..4 {
+            bus.write::<u32>(voice + VOICE_ENV_BEGIN + k * 4, env[k as usize]);
+            bus.write::<u32>(voice + VOICE_ENV_STEP + k * 4, env_step[k as usize]);
+        }
+        bus.write::<u16>(voice + VOICE_PITCH_Q16, pos as u16);
+        bus.write::<u16>(voice + VOICE_PITCH_SHIFT, pitch);
+        bus.write::<u32>(voice + VOICE_CATSRC_0, wave + (pos >> 16) * 2);
+        bus.write::<u16>(voice + VOICE_CATSRC_0 + 0x08, VOICE_SAMPLES as u16 * 2);
+        bus.write::<u16>(voice + VOICE_PCM16_COUNT, VOICE_SAMPLES as u16);
+        bus.write::<u16>(voice + VOICE_END_POINT, VOICE_SAMPLES as u16);
+        bus.write::<u32>(voice + VOICE_INTERLEAVED_PTR, output);
+
+        let pos = pos + SUBFRAME_SIZE * ((pitch as u32) << 4);
+        self.voice_pos[idx] = pos % (WAVE_PERIOD << 16);
+    }
+}